@@ -0,0 +1,72 @@
+#!/usr/bin/env bash
+# Builds the programs listed in user_space/initramfs.manifest and packs them into
+# initramfs.tar (ustar), which run-uefi.sh and qemu-runner.sh boot with:
+#
+#   /bin/<name>   one static ELF per manifest line
+#   /etc/init     path of the program the kernel starts at boot
+#   /lib/modules/<name>.ko   one kernel module per user_space/modules/<name>.S
+#
+# Rust programs need the musl target: rustup target add x86_64-unknown-linux-musl
+set -e
+
+ROOT="$(cd "$(dirname "$0")" && pwd)"
+SRC="$ROOT/user_space"
+OUT="$ROOT/target/initramfs"
+MANIFEST="$SRC/initramfs.manifest"
+
+rm -rf "$OUT"
+mkdir -p "$OUT/root/bin" "$OUT/root/etc" "$OUT/root/lib/modules" "$OUT/obj"
+
+INIT=
+while read -r source flag rest; do
+    case "$source" in
+        '' | '#'*) continue ;;
+    esac
+    if [ -n "$rest" ] || { [ -n "$flag" ] && [ "$flag" != init ]; }; then
+        echo "[!] $MANIFEST: bad line: $source $flag $rest"
+        exit 1
+    fi
+
+    name="${source%.*}"
+    echo "[*] Building /bin/$name..."
+    case "$source" in
+        *.rs)
+            # Built from outside the repo so rust-toolchain.toml does not apply
+            (cd / && rustc --edition 2021 -O --target x86_64-unknown-linux-musl \
+                -C target-feature=+crt-static "$SRC/$source" -o "$OUT/root/bin/$name")
+            ;;
+        *.S)
+            as "$SRC/$source" -o "$OUT/obj/$name.o"
+            ld -static "$OUT/obj/$name.o" -o "$OUT/root/bin/$name"
+            ;;
+        *)
+            echo "[!] $MANIFEST: $source: unknown source type"
+            exit 1
+            ;;
+    esac
+
+    if [ "$flag" = init ]; then
+        if [ -n "$INIT" ]; then
+            echo "[!] $MANIFEST: more than one init program"
+            exit 1
+        fi
+        INIT="/bin/$name"
+    fi
+done < "$MANIFEST"
+
+if [ -z "$INIT" ]; then
+    echo "[!] $MANIFEST: no init program, the kernel will start its shell"
+else
+    echo "$INIT" > "$OUT/root/etc/init"
+fi
+
+# Kernel modules are the relocatable objects themselves
+for source in "$SRC"/modules/*.S; do
+    [ -e "$source" ] || continue
+    name="$(basename "${source%.S}")"
+    echo "[*] Building /lib/modules/$name.ko..."
+    as "$source" -o "$OUT/root/lib/modules/$name.ko"
+done
+
+tar --format=ustar -cf "$ROOT/initramfs.tar" -C "$OUT/root" bin etc lib
+echo "[*] initramfs.tar: $(ls "$OUT/root/bin" | wc -l) programs, init ${INIT:-none}"
@@ -0,0 +1,621 @@
+// ACPI Module
+// This module walks the ACPI tables handed over by the bootloader (RSDP -> XSDT/RSDT -> FADT -> DSDT)
+// and extracts battery / AC adapter information for laptops running the OS bare-metal.
+// It also keeps what is needed to enter S3 (suspend-to-RAM): the PM1 control and status
+// registers and the FACS from the FADT, and the SLP_TYP values of the `\_S3_` package.
+//
+// We do not have a full AML interpreter. Instead we scan the AML byte code of the DSDT and SSDTs for:
+//   - Battery devices:    _HID EisaId("PNP0C0A") (Control Method Battery)
+//   - AC adapter devices: _HID "ACPI0003"
+//   - Static `Name(_BST, Package{...})` / `Name(_BIF, Package{...})` objects for charge level
+//   - Trivial `Method(_PSR) { Return (x) }` bodies for the AC state
+//   - A static `Name(_S3_, Package{...})` for the sleep type
+// Firmware that computes these values at runtime (e.g. via the Embedded Controller) will report
+// the device as present but with an unknown charge level / AC state.
+
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+// ============================================================================
+// 1. TABLE STRUCTURES
+// ============================================================================
+
+// Root System Description Pointer (ACPI 1.0 part + ACPI 2.0 extension)
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8], // "RSD PTR "
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8, // 0 = ACPI 1.0, 2 = ACPI 2.0+
+    rsdt_addr: u32,
+    // Fields below are only valid when revision >= 2
+    length: u32,
+    xsdt_addr: u64,
+    ext_checksum: u8,
+    reserved: [u8; 3],
+}
+
+// Common header of every System Description Table
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+const SDT_HEADER_SIZE: usize = core::mem::size_of::<SdtHeader>();
+
+// FADT offsets of the DSDT pointers
+const FADT_DSDT_OFFSET: usize = 40; // 32-bit DSDT physical address
+const FADT_X_DSDT_OFFSET: usize = 140; // 64-bit X_DSDT physical address (ACPI 2.0+)
+
+// FADT offsets of the fields used to enter sleep states
+const FADT_FIRMWARE_CTRL_OFFSET: usize = 36; // 32-bit FACS physical address
+const FADT_SMI_CMD_OFFSET: usize = 48; // port taking ACPI_ENABLE
+const FADT_ACPI_ENABLE_OFFSET: usize = 52;
+const FADT_PM1A_EVT_OFFSET: usize = 56; // PM1a event block (status register first)
+const FADT_PM1B_EVT_OFFSET: usize = 60;
+const FADT_PM1A_CNT_OFFSET: usize = 64; // PM1a control register
+const FADT_PM1B_CNT_OFFSET: usize = 68;
+const FADT_PM1_EVT_LEN_OFFSET: usize = 88; // status and enable registers, half each
+const FADT_X_FIRMWARE_CTRL_OFFSET: usize = 132; // 64-bit FACS physical address (ACPI 2.0+)
+
+// FACS offsets of the waking vectors
+const FACS_LENGTH_OFFSET: usize = 4;
+const FACS_WAKING_VECTOR_OFFSET: usize = 12; // 32-bit real mode vector
+const FACS_X_WAKING_VECTOR_OFFSET: usize = 24; // 64-bit vector (FACS version 1+)
+
+// PM1 register bits
+const PM1_SCI_EN: u16 = 1 << 0;
+const PM1_SLP_TYP_SHIFT: u16 = 10;
+const PM1_SLP_TYP_MASK: u16 = 0x7 << PM1_SLP_TYP_SHIFT;
+const PM1_SLP_EN: u16 = 1 << 13;
+const PM1_WAK_STS: u16 = 1 << 15;
+const PM1_RTC_STS: u16 = 1 << 10; // status register
+const PM1_RTC_EN: u16 = 1 << 10; // enable register
+
+// AML opcodes we understand
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_NAME_OP: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+const AML_WORD_PREFIX: u8 = 0x0B;
+const AML_DWORD_PREFIX: u8 = 0x0C;
+const AML_QWORD_PREFIX: u8 = 0x0E;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_METHOD_OP: u8 = 0x14;
+const AML_RETURN_OP: u8 = 0xA4;
+const AML_ONES_OP: u8 = 0xFF;
+
+// EisaId("PNP0C0A") encoded as a DWORD (bytes in memory order)
+const BATTERY_EISA_ID: [u8; 4] = [0x41, 0xD0, 0x0C, 0x0A];
+// String _HID of the AC adapter device
+const AC_ADAPTER_HID: &[u8] = b"ACPI0003";
+
+// _BST / _BIF values meaning "unknown"
+const ACPI_UNKNOWN: u64 = 0xFFFF_FFFF;
+
+// ============================================================================
+// 2. PUBLIC STATE
+// ============================================================================
+
+// Battery charge state reported by _BST[0]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatteryState {
+    Discharging,
+    Charging,
+    Critical,
+    Idle,
+}
+
+// Snapshot of the power status
+#[derive(Debug, Clone, Copy)]
+pub struct PowerStatus {
+    pub battery_present: bool,
+    pub ac_adapter_present: bool,
+    pub ac_online: Option<bool>,
+    pub charge_percent: Option<u8>,
+    pub battery_state: Option<BatteryState>,
+}
+
+impl PowerStatus {
+    const fn unknown() -> Self {
+        Self {
+            battery_present: false,
+            ac_adapter_present: false,
+            ac_online: None,
+            charge_percent: None,
+            battery_state: None,
+        }
+    }
+}
+
+// Short one-line rendering used by the status line
+impl fmt::Display for PowerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ac_online {
+            Some(true) => write!(f, "AC: online")?,
+            Some(false) => write!(f, "AC: offline")?,
+            None if self.ac_adapter_present => write!(f, "AC: unknown")?,
+            None => write!(f, "AC: none")?,
+        }
+
+        if !self.battery_present {
+            return write!(f, " | BAT: none");
+        }
+
+        match self.charge_percent {
+            Some(percent) => write!(f, " | BAT: {}%", percent)?,
+            None => write!(f, " | BAT: ?%")?,
+        }
+
+        match self.battery_state {
+            Some(BatteryState::Charging) => write!(f, " (charging)"),
+            Some(BatteryState::Discharging) => write!(f, " (discharging)"),
+            Some(BatteryState::Critical) => write!(f, " (critical)"),
+            Some(BatteryState::Idle) | None => Ok(()),
+        }
+    }
+}
+
+static POWER_STATUS: Mutex<PowerStatus> = Mutex::new(PowerStatus::unknown());
+
+// Registers and values used to enter S3
+#[derive(Debug, Clone, Copy)]
+struct SleepControl {
+    facs: Option<u64>, // virtual address
+    smi_cmd: u16,
+    acpi_enable: u8,
+    pm1a_evt: u16,
+    pm1b_evt: u16,
+    pm1_evt_len: u8,
+    pm1a_cnt: u16,
+    pm1b_cnt: u16,
+    s3: Option<[u16; 2]>, // SLP_TYPa, SLP_TYPb
+}
+
+static SLEEP_CONTROL: Mutex<Option<SleepControl>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepError {
+    // The firmware does not describe the sleep state (or what it needs)
+    Unsupported(&'static str),
+    // The sleep registers were written but the machine stayed awake
+    NotEntered,
+}
+
+// Get the last known power status
+pub fn power_status() -> PowerStatus {
+    *POWER_STATUS.lock()
+}
+
+// ============================================================================
+// 3. INITIALIZATION
+// ============================================================================
+
+// Walk the ACPI tables and fill in the power status
+pub fn init(rsdp_addr: u64, hhdm_offset: u64) {
+    if rsdp_addr == 0 {
+        log::warn!("No RSDP provided by bootloader");
+        return;
+    }
+
+    let rsdp = unsafe { core::ptr::read_unaligned((rsdp_addr + hhdm_offset) as *const Rsdp) };
+    if &rsdp.signature != b"RSD PTR " || !checksum_ok(rsdp_addr + hhdm_offset, 20) {
+        log::warn!("Invalid RSDP at {:#x}", rsdp_addr);
+        return;
+    }
+
+    let revision = rsdp.revision;
+    log::info!("RSDP revision {} at {:#x}", revision, rsdp_addr);
+
+    let mut status = PowerStatus::unknown();
+    let mut sleep = None;
+    let mut s3 = None;
+
+    // Prefer the XSDT (64-bit entries) if available
+    let (root_addr, entry_size) = if revision >= 2 && rsdp.xsdt_addr != 0 {
+        (rsdp.xsdt_addr, 8)
+    } else {
+        (rsdp.rsdt_addr as u64, 4)
+    };
+
+    let Some(root) = map_table(root_addr, hhdm_offset) else {
+        log::warn!("Invalid root table at {:#x}", root_addr);
+        return;
+    };
+
+    let entry_count = (root.len() - SDT_HEADER_SIZE) / entry_size;
+    for i in 0..entry_count {
+        let offset = SDT_HEADER_SIZE + i * entry_size;
+        let table_addr = if entry_size == 8 {
+            read_u64(root, offset)
+        } else {
+            read_u32(root, offset) as u64
+        };
+
+        let Some(table) = map_table(table_addr, hhdm_offset) else {
+            continue;
+        };
+
+        match &table[0..4] {
+            b"FACP" => {
+                // The DSDT is not listed in the XSDT, it is referenced by the FADT
+                let dsdt_addr = if table.len() >= FADT_X_DSDT_OFFSET + 8
+                    && read_u64(table, FADT_X_DSDT_OFFSET) != 0
+                {
+                    read_u64(table, FADT_X_DSDT_OFFSET)
+                } else {
+                    read_u32(table, FADT_DSDT_OFFSET) as u64
+                };
+
+                if let Some(dsdt) = map_table(dsdt_addr, hhdm_offset) {
+                    scan_aml(&dsdt[SDT_HEADER_SIZE..], &mut status);
+                    s3 = s3.or(find_sleep_type(&dsdt[SDT_HEADER_SIZE..], b"_S3_"));
+                }
+                sleep = Some(sleep_control(table, hhdm_offset));
+            }
+            b"SSDT" => {
+                scan_aml(&table[SDT_HEADER_SIZE..], &mut status);
+                s3 = s3.or(find_sleep_type(&table[SDT_HEADER_SIZE..], b"_S3_"));
+            }
+            _ => {}
+        }
+    }
+
+    log::info!("{}", status);
+    *POWER_STATUS.lock() = status;
+    if let Some(mut sleep) = sleep {
+        sleep.s3 = s3;
+        log::info!(
+            "ACPI sleep: S3 {}",
+            if sleep.s3.is_some() && sleep.facs.is_some() {
+                "available"
+            } else {
+                "not described"
+            }
+        );
+        *SLEEP_CONTROL.lock() = Some(sleep);
+    }
+}
+
+// Registers of the FADT used to enter sleep states
+fn sleep_control(fadt: &[u8], hhdm_offset: u64) -> SleepControl {
+    let port = |offset: usize| {
+        if fadt.len() >= offset + 4 {
+            read_u32(fadt, offset) as u16
+        } else {
+            0
+        }
+    };
+    let facs_addr = if fadt.len() >= FADT_X_FIRMWARE_CTRL_OFFSET + 8
+        && read_u64(fadt, FADT_X_FIRMWARE_CTRL_OFFSET) != 0
+    {
+        read_u64(fadt, FADT_X_FIRMWARE_CTRL_OFFSET)
+    } else {
+        read_u32(fadt, FADT_FIRMWARE_CTRL_OFFSET) as u64
+    };
+    // The FACS has no checksum, only its signature
+    let facs = (facs_addr != 0)
+        .then_some(facs_addr + hhdm_offset)
+        .filter(|&virt| unsafe { core::slice::from_raw_parts(virt as *const u8, 4) } == b"FACS");
+
+    SleepControl {
+        facs,
+        smi_cmd: port(FADT_SMI_CMD_OFFSET),
+        acpi_enable: fadt.get(FADT_ACPI_ENABLE_OFFSET).copied().unwrap_or(0),
+        pm1a_evt: port(FADT_PM1A_EVT_OFFSET),
+        pm1b_evt: port(FADT_PM1B_EVT_OFFSET),
+        pm1_evt_len: fadt.get(FADT_PM1_EVT_LEN_OFFSET).copied().unwrap_or(0),
+        pm1a_cnt: port(FADT_PM1A_CNT_OFFSET),
+        pm1b_cnt: port(FADT_PM1B_CNT_OFFSET),
+        s3: None,
+    }
+}
+
+// ============================================================================
+// 4. SLEEP STATES
+// ============================================================================
+
+// We cannot run _PTS / _WAK (no AML interpreter): entering S3 is writing the waking
+// vector and the PM1 control registers, which is enough on QEMU and on most firmware.
+
+fn sleep_control_for_s3() -> Result<(SleepControl, u64, [u16; 2]), SleepError> {
+    let sleep = SLEEP_CONTROL
+        .lock()
+        .ok_or(SleepError::Unsupported("no FADT"))?;
+    let facs = sleep.facs.ok_or(SleepError::Unsupported("no FACS"))?;
+    let s3 = sleep
+        .s3
+        .ok_or(SleepError::Unsupported("no \\_S3_ package"))?;
+    if sleep.pm1a_cnt == 0 {
+        return Err(SleepError::Unsupported("no PM1a control register"));
+    }
+    Ok((sleep, facs, s3))
+}
+
+// Switch to ACPI mode if needed, set the real mode waking vector (a page below
+// 1 MiB, entered at offset 0), clear the wake status and let the RTC alarm wake us
+pub fn prepare_s3(waking_vector: u32) -> Result<(), SleepError> {
+    let (sleep, facs, _) = sleep_control_for_s3()?;
+
+    let mut pm1a_cnt = Port::<u16>::new(sleep.pm1a_cnt);
+    if unsafe { pm1a_cnt.read() } & PM1_SCI_EN == 0 {
+        if sleep.smi_cmd == 0 || sleep.acpi_enable == 0 {
+            return Err(SleepError::Unsupported("cannot switch to ACPI mode"));
+        }
+        unsafe { Port::<u8>::new(sleep.smi_cmd).write(sleep.acpi_enable) };
+        if !(0..1_000_000).any(|_| unsafe { pm1a_cnt.read() } & PM1_SCI_EN != 0) {
+            return Err(SleepError::Unsupported("firmware did not enter ACPI mode"));
+        }
+    }
+
+    unsafe {
+        let length = core::ptr::read_unaligned((facs + FACS_LENGTH_OFFSET as u64) as *const u32);
+        core::ptr::write_volatile(
+            (facs + FACS_WAKING_VECTOR_OFFSET as u64) as *mut u32,
+            waking_vector,
+        );
+        // A 64-bit vector would take precedence over ours
+        if length as usize >= FACS_X_WAKING_VECTOR_OFFSET + 8 {
+            core::ptr::write_volatile((facs + FACS_X_WAKING_VECTOR_OFFSET as u64) as *mut u64, 0);
+        }
+    }
+
+    for evt in [sleep.pm1a_evt, sleep.pm1b_evt] {
+        if evt != 0 {
+            // Status bits are cleared by writing 1
+            unsafe { Port::<u16>::new(evt).write(PM1_WAK_STS | PM1_RTC_STS) };
+            if sleep.pm1_evt_len >= 4 {
+                let mut enable = Port::<u16>::new(evt + sleep.pm1_evt_len as u16 / 2);
+                unsafe {
+                    let bits = enable.read();
+                    enable.write(bits | PM1_RTC_EN);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Enter S3: on success the machine sleeps here and wakes at the waking vector
+// Returns only if it stayed awake
+pub fn enter_s3() -> SleepError {
+    let Ok((sleep, _, [typ_a, typ_b])) = sleep_control_for_s3() else {
+        return SleepError::NotEntered;
+    };
+
+    // Memory contents survive, caches do not
+    unsafe { core::arch::asm!("wbinvd", options(nostack)) };
+
+    // SLP_TYP first, then SLP_EN along with it
+    let registers = [(sleep.pm1a_cnt, typ_a), (sleep.pm1b_cnt, typ_b)];
+    for enable in [0, PM1_SLP_EN] {
+        for &(cnt, typ) in registers.iter().filter(|(cnt, _)| *cnt != 0) {
+            let mut port = Port::<u16>::new(cnt);
+            unsafe {
+                let value = port.read() & !(PM1_SLP_TYP_MASK | PM1_SLP_EN);
+                port.write(value | typ << PM1_SLP_TYP_SHIFT | enable);
+            }
+        }
+    }
+
+    // The chipset takes a moment to put the CPU to sleep
+    for _ in 0..10_000_000 {
+        core::hint::spin_loop();
+    }
+    SleepError::NotEntered
+}
+
+// ============================================================================
+// 5. HELPER FUNCTIONS
+// ============================================================================
+
+// Validate a table header and return the whole table as a byte slice
+fn map_table(phys_addr: u64, hhdm_offset: u64) -> Option<&'static [u8]> {
+    if phys_addr == 0 {
+        return None;
+    }
+
+    let virt = phys_addr + hhdm_offset;
+    let header = unsafe { core::ptr::read_unaligned(virt as *const SdtHeader) };
+    let length = header.length as usize;
+
+    if length < SDT_HEADER_SIZE || !checksum_ok(virt, length) {
+        return None;
+    }
+
+    Some(unsafe { core::slice::from_raw_parts(virt as *const u8, length) })
+}
+
+// ACPI checksums: all bytes of the structure must sum to zero (mod 256)
+fn checksum_ok(virt_addr: u64, length: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(virt_addr as *const u8, length) };
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+// Scan an AML byte stream for battery / AC adapter objects
+fn scan_aml(aml: &[u8], status: &mut PowerStatus) {
+    if contains(aml, &BATTERY_EISA_ID) {
+        status.battery_present = true;
+    }
+    if contains(aml, AC_ADAPTER_HID) {
+        status.ac_adapter_present = true;
+    }
+
+    let mut bst = None;
+    let mut last_full_capacity = None;
+
+    for pos in 0..aml.len().saturating_sub(5) {
+        match (aml[pos], &aml[pos + 1..pos + 5]) {
+            (AML_NAME_OP, b"_BST") => {
+                let mut values = [0u64; 4];
+                if parse_package(aml, pos + 5, &mut values) == Some(4) {
+                    bst = Some(values);
+                }
+            }
+            (AML_NAME_OP, b"_BIF") => {
+                // _BIF[2] = Last Full Charge Capacity
+                let mut values = [0u64; 3];
+                if parse_package(aml, pos + 5, &mut values) == Some(3) {
+                    last_full_capacity = Some(values[2]);
+                }
+            }
+            (AML_METHOD_OP, _) => {
+                if let Some(online) = parse_psr_method(aml, pos) {
+                    status.ac_online = Some(online);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // _BST = { State, Present Rate, Remaining Capacity, Present Voltage }
+    if let Some([state, _rate, remaining, _voltage]) = bst {
+        status.battery_state = Some(if state & 0x4 != 0 {
+            BatteryState::Critical
+        } else if state & 0x2 != 0 {
+            BatteryState::Charging
+        } else if state & 0x1 != 0 {
+            BatteryState::Discharging
+        } else {
+            BatteryState::Idle
+        });
+
+        if let Some(full) = last_full_capacity
+            && full != 0
+            && full != ACPI_UNKNOWN
+            && remaining != ACPI_UNKNOWN
+        {
+            status.charge_percent = Some((remaining.min(full) * 100 / full) as u8);
+        }
+    }
+}
+
+// SLP_TYPa / SLP_TYPb of a static `Name(_Sx_, Package{a, b, ..})`
+fn find_sleep_type(aml: &[u8], name: &[u8; 4]) -> Option<[u16; 2]> {
+    for pos in 0..aml.len().saturating_sub(6) {
+        if aml[pos] != AML_NAME_OP {
+            continue;
+        }
+        // The name may be written from the root: `\_S3_`
+        let name_pos = if aml[pos + 1] == b'\\' {
+            pos + 2
+        } else {
+            pos + 1
+        };
+        if aml.get(name_pos..name_pos + 4) != Some(name) {
+            continue;
+        }
+        let mut values = [0u64; 2];
+        if parse_package(aml, name_pos + 4, &mut values) == Some(2) {
+            return Some([values[0] as u16 & 0x7, values[1] as u16 & 0x7]);
+        }
+    }
+    None
+}
+
+// Recognize `Method(_PSR) { Return (<integer>) }`
+fn parse_psr_method(aml: &[u8], pos: usize) -> Option<bool> {
+    let (_, len_bytes) = parse_pkg_length(aml, pos + 1)?;
+    let name_pos = pos + 1 + len_bytes;
+    if aml.get(name_pos..name_pos + 4)? != b"_PSR" {
+        return None;
+    }
+
+    // Skip the name and the MethodFlags byte
+    let body = name_pos + 5;
+    if *aml.get(body)? != AML_RETURN_OP {
+        return None;
+    }
+
+    let (value, _) = parse_integer(aml, body + 1)?;
+    Some(value != 0)
+}
+
+// Parse `Package(n) { integers... }` starting at the PackageOp
+// Returns the number of leading integer elements stored into `out`
+fn parse_package(aml: &[u8], pos: usize, out: &mut [u64]) -> Option<usize> {
+    if *aml.get(pos)? != AML_PACKAGE_OP {
+        return None;
+    }
+
+    let (pkg_len, len_bytes) = parse_pkg_length(aml, pos + 1)?;
+    let end = pos + 1 + pkg_len;
+    let num_elements = *aml.get(pos + 1 + len_bytes)? as usize;
+    let mut cursor = pos + 2 + len_bytes;
+    let mut count = 0;
+
+    while count < out.len() && count < num_elements && cursor < end {
+        let (value, consumed) = parse_integer(aml, cursor)?;
+        out[count] = value;
+        count += 1;
+        cursor += consumed;
+    }
+
+    Some(count)
+}
+
+// Decode an AML PkgLength, returns (length, number of bytes used by the encoding)
+fn parse_pkg_length(aml: &[u8], pos: usize) -> Option<(usize, usize)> {
+    let lead = *aml.get(pos)?;
+    let follow = (lead >> 6) as usize;
+
+    if follow == 0 {
+        return Some(((lead & 0x3F) as usize, 1));
+    }
+
+    let mut length = (lead & 0x0F) as usize;
+    for i in 0..follow {
+        length |= (*aml.get(pos + 1 + i)? as usize) << (4 + 8 * i);
+    }
+
+    Some((length, follow + 1))
+}
+
+// Decode an AML integer constant, returns (value, number of bytes consumed)
+fn parse_integer(aml: &[u8], pos: usize) -> Option<(u64, usize)> {
+    let read = |size: usize| -> Option<u64> {
+        let bytes = aml.get(pos + 1..pos + 1 + size)?;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0u64, |acc, &b| (acc << 8) | b as u64),
+        )
+    };
+
+    match *aml.get(pos)? {
+        AML_ZERO_OP => Some((0, 1)),
+        AML_ONE_OP => Some((1, 1)),
+        AML_ONES_OP => Some((u64::MAX, 1)),
+        AML_BYTE_PREFIX => Some((read(1)?, 2)),
+        AML_WORD_PREFIX => Some((read(2)?, 3)),
+        AML_DWORD_PREFIX => Some((read(4)?, 5)),
+        AML_QWORD_PREFIX => Some((read(8)?, 9)),
+        _ => None,
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
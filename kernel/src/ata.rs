@@ -0,0 +1,360 @@
+// ATA (PIO) Disks
+// Drives on the legacy IDE channels (0x1F0 and 0x170) of a PCI IDE controller in
+// compatibility mode, as QEMU's `-drive` gives on the default machine. Transfers
+// are programmed I/O with polling: the driver turns the channel interrupts off and
+// waits on the status register, so it needs neither IRQs nor DMA.
+//
+// That makes it usable from the crash path: `write_block_polled` talks to the
+// ports directly, without the channel lock or the heap. Drives are registered as
+// block devices hda (primary master), hdb, hdc and hdd.
+
+use crate::block::{BlockDevice, BlockError};
+use crate::device::{BusType, Device, DeviceIdent, Driver, MatchId, ProbeError};
+use alloc::sync::Arc;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const SECTOR_SIZE: usize = 512;
+
+// Command block registers, from the channel's I/O base
+const REG_DATA: u16 = 0;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+const REG_STATUS: u16 = 7; // command on write
+
+// Device control register (alternate status on read)
+const CONTROL_NO_INTERRUPTS: u8 = 1 << 1;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_READ_SECTORS_EXT: u8 = 0x24;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_WRITE_SECTORS_EXT: u8 = 0x34;
+const CMD_FLUSH_CACHE: u8 = 0xE7;
+const CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+// IDENTIFY words
+const ID_LBA28_SECTORS: usize = 60;
+const ID_COMMAND_SETS: usize = 83;
+const ID_LBA48_SECTORS: usize = 100;
+const ID_LBA48_SUPPORTED: u16 = 1 << 10;
+
+// Status polls before a drive is given up on
+const POLL_LIMIT: u32 = 10_000_000;
+
+// PCI class of IDE controllers, and the programming interface bits telling a
+// channel runs in native mode (its ports in BARs, not the legacy ones)
+const CLASS_STORAGE: u8 = 0x01;
+const SUBCLASS_IDE: u8 = 0x01;
+const PROG_IF_NATIVE: [u8; 2] = [1 << 0, 1 << 2];
+
+#[derive(Clone, Copy)]
+struct Channel {
+    io: u16,
+    control: u16,
+}
+
+const CHANNELS: [Channel; 2] = [
+    Channel {
+        io: 0x1F0,
+        control: 0x3F6,
+    },
+    Channel {
+        io: 0x170,
+        control: 0x376,
+    },
+];
+
+// One command at a time per channel (master and slave share the registers)
+static CHANNEL_LOCKS: [Mutex<()>; 2] = [Mutex::new(()), Mutex::new(())];
+
+impl Channel {
+    fn read(self, reg: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io + reg).read() }
+    }
+
+    fn write(self, reg: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io + reg).write(value) }
+    }
+
+    // The drive needs 400 ns after a select to present its status
+    fn delay(self) {
+        for _ in 0..4 {
+            unsafe { Port::<u8>::new(self.control).read() };
+        }
+    }
+
+    fn wait_not_busy(self) -> Result<u8, BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.read(REG_STATUS);
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err(BlockError::Io)
+    }
+
+    // Wait until the drive wants the next sector transferred
+    fn wait_data(self) -> Result<(), BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.read(REG_STATUS);
+            if status & STATUS_BSY != 0 {
+                continue;
+            }
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err(BlockError::Io);
+            }
+            if status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+        }
+        Err(BlockError::Io)
+    }
+}
+
+pub struct AtaDisk {
+    index: usize, // channel * 2 + drive
+    sectors: u64,
+    lba48: bool,
+}
+
+impl AtaDisk {
+    fn channel(&self) -> Channel {
+        CHANNELS[self.index / 2]
+    }
+
+    fn slave(&self) -> u8 {
+        (self.index % 2) as u8
+    }
+
+    // Select the drive and program LBA and count for a one-sector command
+    fn setup(&self, lba: u64) {
+        let channel = self.channel();
+        if self.lba48 {
+            channel.write(REG_DRIVE, 0x40 | self.slave() << 4);
+            channel.delay();
+            // High bytes first, then the low ones
+            channel.write(REG_SECTOR_COUNT, 0);
+            channel.write(REG_LBA_LOW, (lba >> 24) as u8);
+            channel.write(REG_LBA_MID, (lba >> 32) as u8);
+            channel.write(REG_LBA_HIGH, (lba >> 40) as u8);
+        } else {
+            channel.write(
+                REG_DRIVE,
+                0xE0 | self.slave() << 4 | (lba >> 24) as u8 & 0x0F,
+            );
+            channel.delay();
+        }
+        channel.write(REG_SECTOR_COUNT, 1);
+        channel.write(REG_LBA_LOW, lba as u8);
+        channel.write(REG_LBA_MID, (lba >> 8) as u8);
+        channel.write(REG_LBA_HIGH, (lba >> 16) as u8);
+    }
+
+    fn check(&self, lba: u64, len: usize) -> Result<(), BlockError> {
+        if lba >= self.sectors || len != SECTOR_SIZE {
+            return Err(BlockError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    fn read_sector(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let channel = self.channel();
+        channel.wait_not_busy()?;
+        self.setup(lba);
+        channel.write(
+            REG_STATUS,
+            if self.lba48 {
+                CMD_READ_SECTORS_EXT
+            } else {
+                CMD_READ_SECTORS
+            },
+        );
+        channel.wait_data()?;
+        let mut data = Port::<u16>::new(channel.io + REG_DATA);
+        for word in buf.as_chunks_mut::<2>().0 {
+            *word = unsafe { data.read() }.to_le_bytes();
+        }
+        Ok(())
+    }
+
+    // The whole write, through the cache flush: on return the sector is on disk
+    fn write_sector(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let channel = self.channel();
+        channel.wait_not_busy()?;
+        self.setup(lba);
+        channel.write(
+            REG_STATUS,
+            if self.lba48 {
+                CMD_WRITE_SECTORS_EXT
+            } else {
+                CMD_WRITE_SECTORS
+            },
+        );
+        channel.wait_data()?;
+        let mut data = Port::<u16>::new(channel.io + REG_DATA);
+        for &word in buf.as_chunks::<2>().0 {
+            unsafe { data.write(u16::from_le_bytes(word)) };
+        }
+        channel.wait_not_busy()?;
+        channel.write(
+            REG_STATUS,
+            if self.lba48 {
+                CMD_FLUSH_CACHE_EXT
+            } else {
+                CMD_FLUSH_CACHE
+            },
+        );
+        let status = channel.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(BlockError::Io);
+        }
+        Ok(())
+    }
+
+    fn with_channel<R>(&self, f: impl FnOnce() -> R) -> R {
+        interrupts::without_interrupts(|| {
+            let _guard = CHANNEL_LOCKS[self.index / 2].lock();
+            f()
+        })
+    }
+}
+
+impl BlockDevice for AtaDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check(lba, buf.len())?;
+        self.with_channel(|| self.read_sector(lba, buf))
+    }
+
+    fn write_block(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.check(lba, buf.len())?;
+        self.with_channel(|| self.write_sector(lba, buf))
+    }
+
+    // The crash path: the channel lock may be held by the code that crashed
+    fn write_block_polled(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.check(lba, buf.len())?;
+        self.write_sector(lba, buf)
+    }
+}
+
+// IDENTIFY a drive; None if there is none or it is not an ATA disk (ATAPI)
+fn identify(index: usize) -> Option<AtaDisk> {
+    let channel = CHANNELS[index / 2];
+    // A floating bus reads 0xFF
+    if channel.read(REG_STATUS) == 0xFF {
+        return None;
+    }
+    unsafe { Port::<u8>::new(channel.control).write(CONTROL_NO_INTERRUPTS) };
+    channel.write(REG_DRIVE, 0xA0 | ((index % 2) as u8) << 4);
+    channel.delay();
+    for reg in [REG_SECTOR_COUNT, REG_LBA_LOW, REG_LBA_MID, REG_LBA_HIGH] {
+        channel.write(reg, 0);
+    }
+    channel.write(REG_STATUS, CMD_IDENTIFY);
+    if channel.read(REG_STATUS) == 0 {
+        return None;
+    }
+    channel.wait_not_busy().ok()?;
+    // ATAPI and SATA devices leave their signature here
+    if channel.read(REG_LBA_MID) != 0 || channel.read(REG_LBA_HIGH) != 0 {
+        return None;
+    }
+    channel.wait_data().ok()?;
+
+    let mut id = [0u16; 256];
+    let mut data = Port::<u16>::new(channel.io + REG_DATA);
+    for word in id.iter_mut() {
+        *word = unsafe { data.read() };
+    }
+
+    let lba48 = id[ID_COMMAND_SETS] & ID_LBA48_SUPPORTED != 0;
+    let sectors = if lba48 {
+        (0..4).fold(0u64, |acc, i| {
+            acc | (id[ID_LBA48_SECTORS + i] as u64) << (16 * i)
+        })
+    } else {
+        id[ID_LBA28_SECTORS] as u64 | (id[ID_LBA28_SECTORS + 1] as u64) << 16
+    };
+    (sectors > 0).then_some(AtaDisk {
+        index,
+        sectors,
+        lba48,
+    })
+}
+
+pub static DRIVER: Driver = Driver {
+    name: "ata-pio",
+    bus: BusType::Pci,
+    match_table: &[MatchId::PciClass {
+        class: CLASS_STORAGE,
+        subclass: SUBCLASS_IDE,
+    }],
+    probe,
+    remove,
+    suspend,
+    resume,
+};
+
+fn probe(device: &Device) -> Result<(), ProbeError> {
+    let DeviceIdent::Pci {
+        bus,
+        slot,
+        function,
+        ..
+    } = device.ident
+    else {
+        return Err(ProbeError::Unsupported);
+    };
+    let prog_if = (crate::pci::config_read_u32(bus, slot, function, 0x08) >> 8) as u8;
+
+    let mut found = false;
+    for index in 0..4 {
+        if prog_if & PROG_IF_NATIVE[index / 2] != 0 {
+            continue;
+        }
+        let Some(disk) = interrupts::without_interrupts(|| {
+            let _guard = CHANNEL_LOCKS[index / 2].lock();
+            identify(index)
+        }) else {
+            continue;
+        };
+        let name = alloc::format!("hd{}", (b'a' + index as u8) as char);
+        crate::block::register(&name, Arc::new(disk));
+        found = true;
+    }
+    if found {
+        Ok(())
+    } else {
+        Err(ProbeError::Unsupported)
+    }
+}
+
+// Nothing is in flight between calls
+fn remove(_device: &Device) {}
+
+fn suspend(_device: &Device) {}
+
+// The drives come back with their interrupts enabled
+fn resume(_device: &Device) {
+    for channel in CHANNELS {
+        unsafe { Port::<u8>::new(channel.control).write(CONTROL_NO_INTERRUPTS) };
+    }
+}
@@ -0,0 +1,36 @@
+// Backtrace
+// Walks the frame pointer chain (the kernel is built with force-frame-pointers):
+// every frame starts with the caller's RBP followed by the return address.
+// Used by the panic handler, so it only reads memory that looks like a kernel stack.
+
+// Frames collected at most
+pub const MAX_FRAMES: usize = 16;
+
+// Start of the higher half, where the kernel image and its stacks live
+const KERNEL_SPACE_START: u64 = 0xFFFF_8000_0000_0000;
+
+// Fill `frames` with return addresses, innermost first, and return their count
+#[inline(never)]
+pub fn collect(frames: &mut [u64]) -> usize {
+    let mut rbp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack));
+    }
+
+    let mut depth = 0;
+    while depth < frames.len() && rbp >= KERNEL_SPACE_START && rbp.is_multiple_of(8) {
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if ret == 0 {
+            break;
+        }
+        frames[depth] = ret;
+        depth += 1;
+
+        // The stack grows down, so callers' frames are at higher addresses
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    depth
+}
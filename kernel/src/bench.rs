@@ -0,0 +1,269 @@
+// Microbenchmarks
+// `bench [name]` in the shell runs the benchmarks below (or the named one) and
+// prints cycles per operation: minimum, median, mean and maximum over SAMPLES
+// samples, plus the median in nanoseconds once the TSC is calibrated.
+//
+// Each sample times `batch` operations between two serialized TSC reads (LFENCE
+// before RDTSC, RDTSCP then LFENCE after), so the timer's own cost is amortized and
+// earlier or later instructions cannot leak into the window. Samples run with
+// interrupts disabled, after WARMUP untimed samples that fill the caches and TLB.
+
+use crate::slab::SlabCache;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::naked_asm;
+use core::arch::x86_64::{__rdtscp, _mm_lfence, _rdtsc};
+use x86_64::PhysAddr;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{FrameDeallocator, PhysFrame};
+
+// Timed and untimed samples per benchmark
+const SAMPLES: usize = 64;
+const WARMUP: usize = 4;
+
+struct Benchmark {
+    name: &'static str,
+    // Operations timed together in one sample
+    batch: u32,
+    run: fn(u32),
+}
+
+static BENCHMARKS: &[Benchmark] = &[
+    Benchmark {
+        name: "frame_alloc_free",
+        batch: 4,
+        run: bench_frame_alloc_free,
+    },
+    Benchmark {
+        name: "heap_alloc_free",
+        batch: 64,
+        run: bench_heap_alloc_free,
+    },
+    Benchmark {
+        name: "slab_alloc_free",
+        batch: 64,
+        run: bench_slab_alloc_free,
+    },
+    Benchmark {
+        name: "context_switch",
+        batch: 256,
+        run: bench_context_switch,
+    },
+    #[cfg(feature = "userspace")]
+    Benchmark {
+        name: "syscall_dispatch",
+        batch: 256,
+        run: bench_syscall_dispatch,
+    },
+];
+
+// Cycles per operation over all samples
+struct Stats {
+    min: u64,
+    median: u64,
+    mean: u64,
+    max: u64,
+}
+
+impl Stats {
+    fn from_samples(mut samples: Vec<u64>) -> Self {
+        samples.sort_unstable();
+        Self {
+            min: samples[0],
+            median: samples[samples.len() / 2],
+            mean: samples.iter().sum::<u64>() / samples.len() as u64,
+            max: samples[samples.len() - 1],
+        }
+    }
+}
+
+// ============================================================================
+// 1. TIMING
+// ============================================================================
+
+#[inline(always)]
+fn timer_start() -> u64 {
+    unsafe {
+        _mm_lfence();
+        let tsc = _rdtsc();
+        _mm_lfence();
+        tsc
+    }
+}
+
+#[inline(always)]
+fn timer_stop() -> u64 {
+    unsafe {
+        let mut aux = 0;
+        let tsc = __rdtscp(&mut aux);
+        _mm_lfence();
+        tsc
+    }
+}
+
+// Cycles of an empty timed window, subtracted from every sample
+fn timer_overhead() -> u64 {
+    (0..SAMPLES)
+        .map(|_| {
+            let start = timer_start();
+            timer_stop() - start
+        })
+        .min()
+        .unwrap_or(0)
+}
+
+fn measure(bench: &Benchmark, overhead: u64) -> Stats {
+    for _ in 0..WARMUP {
+        (bench.run)(bench.batch);
+    }
+    let samples = (0..SAMPLES)
+        .map(|_| {
+            let cycles = interrupts::without_interrupts(|| {
+                let start = timer_start();
+                (bench.run)(bench.batch);
+                timer_stop() - start
+            });
+            cycles.saturating_sub(overhead) / bench.batch as u64
+        })
+        .collect();
+    Stats::from_samples(samples)
+}
+
+// ============================================================================
+// 2. BENCHMARKS
+// ============================================================================
+
+// A frame allocated and given back, so a run leaves the PMM as it was
+fn bench_frame_alloc_free(batch: u32) {
+    for _ in 0..batch {
+        if let Some(phys) = core::hint::black_box(crate::pmm::allocate_frame()) {
+            let frame = PhysFrame::containing_address(PhysAddr::new(phys));
+            unsafe { crate::pmm::KernelFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+fn bench_heap_alloc_free(batch: u32) {
+    for _ in 0..batch {
+        drop(core::hint::black_box(Box::new([0u64; 8])));
+    }
+}
+
+static BENCH_OBJECTS: SlabCache<[u64; 8]> = SlabCache::new("bench");
+
+// The same object as heap_alloc_free, from a slab cache
+fn bench_slab_alloc_free(batch: u32) {
+    for _ in 0..batch {
+        drop(core::hint::black_box(BENCH_OBJECTS.alloc([0u64; 8])));
+    }
+}
+
+// Switch to a second kernel stack and back: the register save/restore and stack
+// swap a scheduler would do, two switches per operation
+const PONG_STACK_SIZE: usize = 4096 * 2;
+
+#[repr(C, align(16))]
+struct PongStack([u8; PONG_STACK_SIZE]);
+
+static mut PONG_STACK: PongStack = PongStack([0; PONG_STACK_SIZE]);
+static mut MAIN_RSP: u64 = 0;
+static mut PONG_RSP: u64 = 0;
+
+// Push the callee-saved registers, store RSP to `*save`, load `load` and pop the
+// other context's registers
+#[unsafe(naked)]
+unsafe extern "C" fn switch_stack(save: *mut u64, load: u64) {
+    naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    );
+}
+
+extern "C" fn pong() -> ! {
+    loop {
+        unsafe { switch_stack(&raw mut PONG_RSP, MAIN_RSP) };
+    }
+}
+
+fn bench_context_switch(batch: u32) {
+    unsafe {
+        // Fresh pong context: six zeroed registers, then `pong` as return address
+        // and a null frame above it, leaving RSP as right after a call
+        let top = (&raw mut PONG_STACK as u64) + PONG_STACK_SIZE as u64;
+        let frame = (top - 8 * 8) as *mut u64;
+        frame.write_bytes(0, 8);
+        frame.add(6).write(pong as *const () as u64);
+        PONG_RSP = frame as u64;
+
+        for _ in 0..batch {
+            switch_stack(&raw mut MAIN_RSP, PONG_RSP);
+        }
+    }
+}
+
+// Linux madvise: MADV_NORMAL over an empty range does nothing
+#[cfg(feature = "userspace")]
+const SYS_MADVISE: u64 = 28;
+
+// The kernel side of a syscall (dispatch and return value) for a no-op syscall;
+// kernel code cannot SYSCALL into itself, so the ring transition is not included
+#[cfg(feature = "userspace")]
+fn bench_syscall_dispatch(batch: u32) {
+    for _ in 0..batch {
+        let nr = core::hint::black_box(SYS_MADVISE);
+        core::hint::black_box(crate::syscalls::dispatch(nr, 0, 0, 0, 0, 0, 0));
+    }
+}
+
+// ============================================================================
+// 3. ENTRY
+// ============================================================================
+
+pub fn names() -> impl Iterator<Item = &'static str> {
+    BENCHMARKS.iter().map(|bench| bench.name)
+}
+
+// Run every benchmark, or the one named; false if there is none by that name
+pub fn run(filter: Option<&str>) -> bool {
+    let selected: Vec<&Benchmark> = BENCHMARKS
+        .iter()
+        .filter(|bench| filter.is_none_or(|name| bench.name == name))
+        .collect();
+    if selected.is_empty() {
+        return false;
+    }
+
+    let overhead = timer_overhead();
+    let per_us = crate::delay::tsc_per_us();
+    println!("timer overhead: {} cycles", overhead);
+    println!(
+        "{:<18} {:>8} {:>8} {:>8} {:>8} {:>10}",
+        "benchmark", "min", "median", "mean", "max", "median ns"
+    );
+    for bench in selected {
+        let stats = measure(bench, overhead);
+        let ns = match (stats.median * 1000).checked_div(per_us) {
+            Some(ns) => alloc::format!("{}", ns),
+            None => String::from("-"),
+        };
+        println!(
+            "{:<18} {:>8} {:>8} {:>8} {:>8} {:>10}",
+            bench.name, stats.min, stats.median, stats.mean, stats.max, ns
+        );
+    }
+    true
+}
@@ -0,0 +1,412 @@
+// Block Device Layer
+// Defines the BlockDevice trait used by disk filesystems, an in-memory RamDisk,
+// and a BlockCache that keeps recently used blocks in RAM.
+//
+// The cache is write-back: writes only mark the cached block dirty, dirty blocks are
+// written to the device when they are evicted or when `sync()` is called.
+// When the cache reaches its capacity the least recently used block is evicted.
+// Under memory pressure the heap allocator calls `shrink_all()` to drop clean blocks.
+//
+// Block devices are registered by name (e.g. "ram0") so `mount` can find them by source.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::fs::FsError;
+
+// ============================================================================
+// 1. BLOCK DEVICE TRAIT
+// ============================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    OutOfRange,
+    ReadOnly,
+    Io,
+}
+
+impl From<BlockError> for FsError {
+    fn from(err: BlockError) -> Self {
+        match err {
+            BlockError::ReadOnly => FsError::ReadOnly,
+            BlockError::OutOfRange | BlockError::Io => FsError::Io,
+        }
+    }
+}
+
+pub trait BlockDevice: Send + Sync {
+    fn block_size(&self) -> usize;
+    fn block_count(&self) -> u64;
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+    fn write_block(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    // Write for the crash path: straight to the hardware, no locks, no heap, no
+    // interrupts (whatever holds them may be what crashed)
+    fn write_block_polled(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.write_block(lba, buf)
+    }
+
+    // Read `buf.len()` bytes starting at a byte offset (may span several blocks)
+    fn read_bytes(&self, offset: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let block_size = self.block_size() as u64;
+        let mut block = vec![0u8; block_size as usize];
+        let mut done = 0;
+
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let lba = pos / block_size;
+            let in_block = (pos % block_size) as usize;
+            let count = (buf.len() - done).min(block_size as usize - in_block);
+
+            self.read_block(lba, &mut block)?;
+            buf[done..done + count].copy_from_slice(&block[in_block..in_block + count]);
+            done += count;
+        }
+
+        Ok(())
+    }
+}
+
+// ============================================================================
+// 2. RAM DISK
+// ============================================================================
+
+// A block device backed by a physical memory region (e.g. a boot module)
+pub struct RamDisk {
+    base: u64, // Virtual address of the first byte
+    len: usize,
+    block_size: usize,
+    writable: bool,
+}
+
+impl RamDisk {
+    // Safety: the region must stay mapped and unused by anything else
+    pub unsafe fn new(base: u64, len: usize, block_size: usize, writable: bool) -> Self {
+        Self {
+            base,
+            len,
+            block_size,
+            writable,
+        }
+    }
+
+    fn block_range(&self, lba: u64, len: usize) -> Result<usize, BlockError> {
+        usize::try_from(lba)
+            .ok()
+            .and_then(|lba| lba.checked_mul(self.block_size))
+            .filter(|start| {
+                len == self.block_size && start.checked_add(len).is_some_and(|end| end <= self.len)
+            })
+            .ok_or(BlockError::OutOfRange)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.len / self.block_size) as u64
+    }
+
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let start = self.block_range(lba, buf.len())?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                (self.base + start as u64) as *const u8,
+                buf.as_mut_ptr(),
+                buf.len(),
+            );
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if !self.writable {
+            return Err(BlockError::ReadOnly);
+        }
+        let start = self.block_range(lba, buf.len())?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                buf.as_ptr(),
+                (self.base + start as u64) as *mut u8,
+                buf.len(),
+            );
+        }
+        Ok(())
+    }
+}
+
+// ============================================================================
+// 3. BLOCK CACHE
+// ============================================================================
+
+struct CachedBlock {
+    data: Vec<u8>,
+    dirty: bool,
+    last_used: u64, // Value of the access clock at the last access
+}
+
+struct CacheInner {
+    blocks: BTreeMap<u64, CachedBlock>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub cached: usize,
+    pub dirty: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+pub struct BlockCache {
+    device: Arc<dyn BlockDevice>,
+    capacity: usize, // Maximum number of cached blocks
+    inner: Mutex<CacheInner>,
+}
+
+// All caches, so memory pressure can shrink them
+static CACHES: Mutex<Vec<Weak<BlockCache>>> = Mutex::new(Vec::new());
+
+impl BlockCache {
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Arc<Self> {
+        let cache = Arc::new(Self {
+            device,
+            capacity: capacity.max(1),
+            inner: Mutex::new(CacheInner {
+                blocks: BTreeMap::new(),
+                clock: 0,
+                hits: 0,
+                misses: 0,
+            }),
+        });
+
+        interrupts::without_interrupts(|| {
+            let mut caches = CACHES.lock();
+            caches.retain(|weak| weak.strong_count() > 0);
+            caches.push(Arc::downgrade(&cache));
+        });
+        cache
+    }
+
+    // Run `f` on the cached copy of a block, caching it (and evicting) if needed;
+    // a block `f` overwrites whole (`load` false) is not read from the device
+    fn with_block<R>(
+        &self,
+        lba: u64,
+        load: bool,
+        f: impl FnOnce(&mut CachedBlock) -> R,
+    ) -> Result<R, BlockError> {
+        let mut inner = self.inner.lock();
+        inner.clock += 1;
+        let now = inner.clock;
+
+        if inner.blocks.contains_key(&lba) {
+            inner.hits += 1;
+        } else {
+            inner.misses += 1;
+
+            if inner.blocks.len() >= self.capacity {
+                self.evict_lru(&mut inner)?;
+            }
+
+            let mut data = vec![0u8; self.device.block_size()];
+            if load {
+                self.device.read_block(lba, &mut data)?;
+            } else if lba >= self.device.block_count() {
+                return Err(BlockError::OutOfRange);
+            }
+            inner.blocks.insert(
+                lba,
+                CachedBlock {
+                    data,
+                    dirty: false,
+                    last_used: now,
+                },
+            );
+        }
+
+        let block = inner.blocks.get_mut(&lba).expect("block just cached");
+        block.last_used = now;
+        Ok(f(block))
+    }
+
+    // Evict the least recently used block, writing it back if dirty
+    // A block whose write-back fails stays cached (and dirty): it is the only copy
+    fn evict_lru(&self, inner: &mut CacheInner) -> Result<(), BlockError> {
+        let Some((&lba, block)) = inner.blocks.iter().min_by_key(|(_, b)| b.last_used) else {
+            return Ok(());
+        };
+
+        if block.dirty {
+            self.device.write_block(lba, &block.data)?;
+        }
+        inner.blocks.remove(&lba);
+        Ok(())
+    }
+
+    // Write every dirty block back to the device
+    pub fn sync(&self) -> Result<(), BlockError> {
+        let mut inner = self.inner.lock();
+        for (&lba, block) in inner.blocks.iter_mut().filter(|(_, b)| b.dirty) {
+            self.device.write_block(lba, &block.data)?;
+            block.dirty = false;
+        }
+        Ok(())
+    }
+
+    // Drop up to `count` least recently used clean blocks, returns how many were freed
+    // Used under memory pressure: never performs I/O or allocates, and gives up
+    // if the cache is currently locked (the allocation may come from inside it)
+    fn reclaim_clean(&self, count: usize) -> usize {
+        let Some(mut inner) = self.inner.try_lock() else {
+            return 0;
+        };
+
+        let mut freed = 0;
+        while freed < count {
+            let victim = inner
+                .blocks
+                .iter()
+                .filter(|(_, b)| !b.dirty)
+                .min_by_key(|(_, b)| b.last_used)
+                .map(|(&lba, _)| lba);
+
+            match victim {
+                Some(lba) => {
+                    inner.blocks.remove(&lba);
+                    freed += 1;
+                }
+                None => break,
+            }
+        }
+        freed
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock();
+        CacheStats {
+            cached: inner.blocks.len(),
+            dirty: inner.blocks.values().filter(|b| b.dirty).count(),
+            hits: inner.hits,
+            misses: inner.misses,
+        }
+    }
+}
+
+impl BlockDevice for BlockCache {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_block(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.with_block(lba, true, |block| buf.copy_from_slice(&block.data))
+    }
+
+    fn write_block(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if buf.len() != self.block_size() {
+            return Err(BlockError::OutOfRange);
+        }
+        self.with_block(lba, false, |block| {
+            block.data.copy_from_slice(buf);
+            block.dirty = true;
+        })
+    }
+
+    // Past the cache: a later read of the block through it may be stale
+    fn write_block_polled(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.device.write_block_polled(lba, buf)
+    }
+}
+
+// Reclaim clean cached blocks from every cache (called by the heap allocator when it runs out)
+// Returns the number of blocks freed
+pub fn shrink_all(blocks_per_cache: usize) -> usize {
+    let Some(caches) = CACHES.try_lock() else {
+        return 0;
+    };
+
+    caches
+        .iter()
+        .filter_map(|weak| weak.upgrade())
+        .map(|cache| cache.reclaim_clean(blocks_per_cache))
+        .sum()
+}
+
+fn live_caches() -> Vec<Arc<BlockCache>> {
+    interrupts::without_interrupts(|| {
+        CACHES
+            .lock()
+            .iter()
+            .filter_map(|weak| weak.upgrade())
+            .collect()
+    })
+}
+
+// Write back all dirty blocks of every cache (sync, umount)
+pub fn sync_all() {
+    for cache in live_caches() {
+        if let Err(err) = cache.sync() {
+            log::warn!("sync failed: {:?}", err);
+        }
+    }
+}
+
+// Totals over every cache
+pub fn stats_all() -> CacheStats {
+    live_caches().iter().map(|cache| cache.stats()).fold(
+        CacheStats {
+            cached: 0,
+            dirty: 0,
+            hits: 0,
+            misses: 0,
+        },
+        |total, stats| CacheStats {
+            cached: total.cached + stats.cached,
+            dirty: total.dirty + stats.dirty,
+            hits: total.hits + stats.hits,
+            misses: total.misses + stats.misses,
+        },
+    )
+}
+
+// ============================================================================
+// 4. DEVICE REGISTRY
+// ============================================================================
+
+static DEVICES: Mutex<BTreeMap<String, Arc<dyn BlockDevice>>> = Mutex::new(BTreeMap::new());
+
+// Make a block device available under a name (mount source "/dev/<name>" or "<name>")
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) {
+    log::info!(
+        "{}: {} blocks of {} bytes",
+        name,
+        device.block_count(),
+        device.block_size()
+    );
+    interrupts::without_interrupts(|| DEVICES.lock().insert(String::from(name), device));
+}
+
+// Names of the registered block devices
+pub fn names() -> Vec<String> {
+    interrupts::without_interrupts(|| DEVICES.lock().keys().cloned().collect())
+}
+
+// Find a block device by name
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    let name = name.strip_prefix("/dev/").unwrap_or(name);
+    interrupts::without_interrupts(|| DEVICES.lock().get(name).cloned())
+}
@@ -0,0 +1,79 @@
+// Capabilities
+// What the running program is allowed to do beyond plain files and memory. Each
+// privileged operation checks its capability and fails with EPERM without it:
+//
+//   raw_io  open device nodes (/dev/fb0, /dev/input/*)
+//   net     create sockets
+//   mount   mount and unmount filesystems
+//   module  load and unload kernel modules
+//
+// The set is chosen when the program is started (`run --caps <list>`, all of them
+// by default: this is a single-user system) and can only shrink afterwards: the
+// program drops capabilities with prctl(PR_CAPBSET_DROP), and nothing adds them
+// back until the next program starts.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+// Capability numbers, for prctl
+pub const CAP_RAW_IO: u32 = 0;
+pub const CAP_NET: u32 = 1;
+pub const CAP_MOUNT: u32 = 2;
+pub const CAP_MODULE: u32 = 3;
+
+pub const COUNT: u32 = 4;
+const NAMES: [&str; COUNT as usize] = ["raw_io", "net", "mount", "module"];
+
+pub const ALL: u32 = (1 << COUNT) - 1;
+
+// Capabilities of the running program, one bit per number
+static CAPS: AtomicU32 = AtomicU32::new(ALL);
+
+// A new program starts with `caps`
+pub fn reset(caps: u32) {
+    CAPS.store(caps & ALL, Ordering::Relaxed);
+}
+
+pub fn has(cap: u32) -> bool {
+    cap < COUNT && CAPS.load(Ordering::Relaxed) & (1 << cap) != 0
+}
+
+// Give up `cap` for the rest of the program; false for an unknown number
+pub fn drop(cap: u32) -> bool {
+    if cap >= COUNT {
+        return false;
+    }
+    CAPS.fetch_and(!(1 << cap), Ordering::Relaxed);
+    true
+}
+
+// A set from a comma-separated list of names ("all" and "none" too)
+pub fn parse(list: &str) -> Option<u32> {
+    match list {
+        "all" => return Some(ALL),
+        "none" => return Some(0),
+        _ => {}
+    }
+    list.split(',').try_fold(0, |caps, name| {
+        let cap = NAMES.iter().position(|&known| known == name)?;
+        Some(caps | 1 << cap)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn caps_parse_and_drop() {
+        assert_eq!(parse("net,mount"), Some(1 << CAP_NET | 1 << CAP_MOUNT));
+        assert_eq!(parse("none"), Some(0));
+        assert_eq!(parse("all"), Some(ALL));
+        assert_eq!(parse("net,root"), None);
+
+        reset(ALL);
+        assert!(drop(CAP_NET));
+        assert!(!has(CAP_NET) && has(CAP_MOUNT));
+        assert!(!drop(COUNT));
+        reset(ALL);
+    }
+}
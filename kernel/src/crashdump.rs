@@ -0,0 +1,692 @@
+// Crash Dump
+// On a panic or a fatal exception the kernel writes a dump of its state to serial,
+// one record per line between two markers, so a script can cut it out of a serial
+// log and parse it:
+//
+//   ==== CRASH DUMP BEGIN v1 ====
+//   reason panic                          or: exception <name>
+//   message <panic message>
+//   location <file>:<line>:<column>
+//   regs rip=0x.. rsp=0x.. rbp=0x.. rflags=0x.. cs=0x.. ss=0x.. error=0x..
+//   cregs cr0=0x.. cr2=0x.. cr3=0x.. cr4=0x..
+//   frame <n> 0x<addr> [symbol+0xoff]   kernel backtrace, innermost first
+//   stack 0x<addr> <8 words>            top of the faulting stack
+//   mem frames_free=.. frames_total=.. heap_used=.. heap_size=..
+//   module <name> addr=0x.. size=..     boot modules (initramfs, font, module=)
+//   kaslr slide=0x..                    kernel load address minus link address
+//   program <path> running              last user program
+//   klog <line>                         tail of the kernel log, oldest first
+//   ==== CRASH DUMP END ====
+//
+// Fields a locked subsystem cannot provide are left out, since its lock holder may
+// be the code that crashed. The dump is built in a static buffer (the heap may be
+// what broke) and, when a crash log region on a block device is selected, also
+// appended there through the device's polled write path (no locks, no heap, no
+// interrupts: the normal I/O stack may be what broke).
+//
+// Crash log region, `blocks` blocks from `lba`:
+//   block 0    "CATCLOG1", region size in blocks (u64), sequence number of the
+//              newest dump already reported at boot (u64), little endian
+//   slots      of DUMP_SIZE bytes, used round robin: "CATDUMP2", dump length
+//              (u64), sequence number (u64), then the dump text
+//
+// At boot the region at `crashlog=<device>:<lba>` on the command line is selected,
+// without the option the first block device starting with a region header, and
+// dumps newer than the last reported one are announced in the kernel log;
+// `dmesg --crash` prints the stored dumps, oldest first.
+//
+// /proc/crashdump: `<device> <lba> <blocks>` selects a region (formatting it if it
+// has no header yet), `off` disables it. A region not at LBA 0 is only found again
+// at boot through `crashlog=`.
+
+use crate::block::BlockDevice;
+use crate::fs::procfs::{self, ProcEntry};
+use crate::fs::{FsError, FsResult};
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+
+const DUMP_VERSION: u32 = 1;
+const LOG_MAGIC: &[u8; 8] = b"CATCLOG1";
+const DISK_MAGIC: &[u8; 8] = b"CATDUMP2";
+
+// Room for a dump on disk, a multiple of any block size up to 4 KiB
+const DUMP_SIZE: usize = 32 * 1024;
+// Disk header of a dump: magic, length and sequence number
+const HEADER_SIZE: usize = 24;
+
+// Kernel log bytes included, at most
+const KLOG_TAIL: usize = 8 * 1024;
+
+// Words of the faulting stack included
+const STACK_WORDS: usize = 32;
+
+// Register state at the crash
+pub struct Registers {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rflags: u64,
+    pub cs: u64,
+    pub ss: u64,
+    pub error_code: Option<u64>,
+}
+
+impl Registers {
+    // The caller's state (RIP is inside the caller)
+    #[inline(always)]
+    pub fn current() -> Self {
+        let (rip, rsp, rbp, rflags, cs, ss): (u64, u64, u64, u64, u64, u64);
+        unsafe {
+            core::arch::asm!(
+                "lea {rip}, [rip]",
+                "mov {rsp}, rsp",
+                "mov {rbp}, rbp",
+                "pushfq",
+                "pop {rflags}",
+                "mov {cs:r}, cs",
+                "mov {ss:r}, ss",
+                rip = out(reg) rip,
+                rsp = out(reg) rsp,
+                rbp = out(reg) rbp,
+                rflags = out(reg) rflags,
+                cs = out(reg) cs,
+                ss = out(reg) ss,
+            );
+        }
+        Self {
+            rip,
+            rsp,
+            rbp,
+            rflags,
+            cs,
+            ss,
+            error_code: None,
+        }
+    }
+
+    // State pushed by the CPU for an exception
+    pub fn from_exception(frame: &InterruptStackFrame, error_code: Option<u64>) -> Self {
+        Self {
+            rip: frame.instruction_pointer.as_u64(),
+            rsp: frame.stack_pointer.as_u64(),
+            // Not pushed by the CPU
+            rbp: 0,
+            rflags: frame.cpu_flags.bits(),
+            cs: frame.code_segment.0 as u64,
+            ss: frame.stack_segment.0 as u64,
+            error_code,
+        }
+    }
+}
+
+pub enum Reason<'a> {
+    Panic(&'a core::panic::PanicInfo<'a>),
+    Exception(&'a str),
+}
+
+// Files handed over by the bootloader
+static MODULES: Mutex<&'static [shared::BootModule]> = Mutex::new(&[]);
+
+static HHDM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// Set by the first dump: a crash while dumping must not start another one
+static DUMPING: AtomicBool = AtomicBool::new(false);
+
+struct DiskTarget {
+    name: String,
+    device: Arc<dyn BlockDevice>,
+    lba: u64,
+    blocks: u64,
+    slots: u64,
+    // Sequence number of the next dump, its slot is `next_seq % slots`
+    next_seq: u64,
+    // Newest dump announced at boot
+    reported: u64,
+}
+
+static DISK: Mutex<Option<DiskTarget>> = Mutex::new(None);
+
+static CRASHDUMP_ENTRY: ProcEntry = ProcEntry {
+    name: "crashdump",
+    read: read_config,
+    write: Some(write_config),
+};
+
+// ============================================================================
+// 1. DUMP BUFFER
+// ============================================================================
+
+struct DumpBuffer {
+    data: [u8; DUMP_SIZE],
+    len: usize,
+}
+
+// Only locked by the first dump
+static BUFFER: Mutex<DumpBuffer> = Mutex::new(DumpBuffer {
+    data: [0; DUMP_SIZE],
+    len: HEADER_SIZE,
+});
+
+// Output beyond the end of the buffer is dropped
+impl Write for DumpBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = s.len().min(DUMP_SIZE - self.len);
+        self.data[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+impl DumpBuffer {
+    fn text(&self) -> &str {
+        // Truncation may have cut a character in two
+        match core::str::from_utf8(&self.data[HEADER_SIZE..self.len]) {
+            Ok(text) => text,
+            Err(err) => unsafe {
+                core::str::from_utf8_unchecked(
+                    &self.data[HEADER_SIZE..HEADER_SIZE + err.valid_up_to()],
+                )
+            },
+        }
+    }
+}
+
+static KLOG_BUFFER: Mutex<[u8; KLOG_TAIL]> = Mutex::new([0; KLOG_TAIL]);
+
+// Log text with invalid UTF-8 (a character cut by the ring) replaced
+struct Utf8Lossy<'a>(&'a [u8]);
+
+impl fmt::Display for Utf8Lossy<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for chunk in self.0.utf8_chunks() {
+            f.write_str(chunk.valid())?;
+            if !chunk.invalid().is_empty() {
+                f.write_char(char::REPLACEMENT_CHARACTER)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// ============================================================================
+// 2. RECORDS
+// ============================================================================
+
+// Page table walk through the HHDM: may `addr` be read without faulting?
+fn is_mapped(addr: u64) -> bool {
+    let hhdm = HHDM_OFFSET.load(Ordering::Relaxed);
+    if hhdm == 0 {
+        return false;
+    }
+    let frame = crate::pml4::active_pml4(hhdm);
+    let mut table_phys = frame.start_address().as_u64();
+    for level in (1..=4).rev() {
+        let table = unsafe { &*((table_phys + hhdm) as *const PageTable) };
+        let index = (addr >> (12 + 9 * (level - 1))) & 0x1FF;
+        let entry = &table[index as usize];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return false;
+        }
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return true;
+        }
+        table_phys = entry.addr().as_u64();
+    }
+    false
+}
+
+fn write_stack(out: &mut DumpBuffer, rsp: u64) {
+    let start = rsp & !7;
+    for row in 0..STACK_WORDS / 8 {
+        let addr = start + row as u64 * 64;
+        // A row spans at most two pages: its first and last word cover both
+        if !is_mapped(addr) || !is_mapped(addr + 56) {
+            return;
+        }
+        let _ = write!(out, "stack {:#018x}", addr);
+        for word in 0..8 {
+            let value = unsafe { core::ptr::read_volatile((addr + word * 8) as *const u64) };
+            let _ = write!(out, " {:016x}", value);
+        }
+        let _ = writeln!(out);
+    }
+}
+
+fn write_klog(out: &mut DumpBuffer) {
+    let Some(mut buffer) = KLOG_BUFFER.try_lock() else {
+        return;
+    };
+    let len = crate::klog::copy_tail(&mut buffer[..]);
+    let mut tail = &buffer[..len];
+    // Skip the partial first line
+    if len == KLOG_TAIL
+        && let Some(newline) = tail.iter().position(|&byte| byte == b'\n')
+    {
+        tail = &tail[newline + 1..];
+    }
+    for line in tail
+        .split(|&byte| byte == b'\n')
+        .filter(|line| !line.is_empty())
+    {
+        let _ = writeln!(out, "klog {}", Utf8Lossy(line));
+    }
+}
+
+fn write_dump(out: &mut DumpBuffer, reason: &Reason, regs: &Registers, backtrace: &[u64]) {
+    let _ = writeln!(out, "==== CRASH DUMP BEGIN v{} ====", DUMP_VERSION);
+    match reason {
+        Reason::Panic(info) => {
+            let _ = writeln!(out, "reason panic");
+            let _ = writeln!(out, "message {}", info.message());
+            if let Some(location) = info.location() {
+                let _ = writeln!(
+                    out,
+                    "location {}:{}:{}",
+                    location.file(),
+                    location.line(),
+                    location.column()
+                );
+            }
+        }
+        Reason::Exception(name) => {
+            let _ = writeln!(out, "reason exception {}", name);
+        }
+    }
+
+    let _ = write!(
+        out,
+        "regs rip={:#x} rsp={:#x} rbp={:#x} rflags={:#x} cs={:#x} ss={:#x}",
+        regs.rip, regs.rsp, regs.rbp, regs.rflags, regs.cs, regs.ss
+    );
+    if let Some(code) = regs.error_code {
+        let _ = write!(out, " error={:#x}", code);
+    }
+    let _ = writeln!(out);
+    let _ = writeln!(
+        out,
+        "cregs cr0={:#x} cr2={:#x} cr3={:#x} cr4={:#x}",
+        Cr0::read_raw(),
+        Cr2::read_raw(),
+        Cr3::read_raw().0.start_address().as_u64(),
+        Cr4::read_raw()
+    );
+
+    for (i, addr) in backtrace.iter().enumerate() {
+        let _ = writeln!(out, "frame {} {}", i, crate::symbols::Frame(*addr));
+    }
+    write_stack(out, regs.rsp);
+
+    let _ = write!(out, "mem");
+    if let Some(frames) = crate::pmm::try_stats() {
+        let _ = write!(
+            out,
+            " frames_free={} frames_total={}",
+            frames.free, frames.total
+        );
+    }
+    if let Some(heap) = crate::heap_allocator::try_stats() {
+        let _ = write!(out, " heap_used={} heap_size={}", heap.used, heap.size);
+    }
+    let _ = writeln!(out);
+
+    if let Some(modules) = MODULES.try_lock() {
+        for module in modules.iter() {
+            let _ = writeln!(
+                out,
+                "module {} addr={:#x} size={}",
+                module.name(),
+                module.addr,
+                module.size
+            );
+        }
+    }
+    let _ = writeln!(out, "kaslr slide={:#x}", crate::symbols::slide());
+    crate::shell::with_program(|name, status| {
+        let _ = match status {
+            Some(status) => writeln!(out, "program {} exited({})", name, status),
+            None => writeln!(out, "program {} running", name),
+        };
+    });
+
+    write_klog(out);
+    let _ = writeln!(out, "==== CRASH DUMP END ====");
+}
+
+// ============================================================================
+// 3. DISK
+// ============================================================================
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+impl DiskTarget {
+    // Open the region, formatting it when `format` is set and it has no header
+    fn open(
+        name: &str,
+        device: Arc<dyn BlockDevice>,
+        lba: u64,
+        blocks: u64,
+        format: bool,
+    ) -> FsResult<Self> {
+        let block_size = device.block_size();
+        if block_size < HEADER_SIZE || !DUMP_SIZE.is_multiple_of(block_size) {
+            return Err(FsError::InvalidArgument);
+        }
+        let slot_blocks = (DUMP_SIZE / block_size) as u64;
+        if blocks <= slot_blocks || lba.saturating_add(blocks) > device.block_count() {
+            return Err(FsError::InvalidArgument);
+        }
+        let mut disk = DiskTarget {
+            name: String::from(name),
+            device,
+            lba,
+            blocks,
+            slots: (blocks - 1) / slot_blocks,
+            next_seq: 1,
+            reported: 0,
+        };
+
+        let mut block = alloc::vec![0u8; block_size];
+        disk.device.read_block(lba, &mut block)?;
+        if &block[..8] == LOG_MAGIC && read_u64(&block, 8) == blocks {
+            disk.reported = read_u64(&block, 16);
+            disk.next_seq = disk
+                .records()?
+                .iter()
+                .map(|&(seq, _)| seq + 1)
+                .max()
+                .unwrap_or(1);
+            return Ok(disk);
+        }
+        if !format {
+            return Err(FsError::NotFound);
+        }
+
+        // Empty slots, then the header
+        block.fill(0);
+        for slot in 0..disk.slots {
+            disk.device.write_block(disk.slot_lba(slot), &block)?;
+        }
+        disk.write_header()?;
+        Ok(disk)
+    }
+
+    fn slot_lba(&self, slot: u64) -> u64 {
+        self.lba + 1 + slot * (DUMP_SIZE / self.device.block_size()) as u64
+    }
+
+    fn write_header(&self) -> FsResult<()> {
+        let mut block = alloc::vec![0u8; self.device.block_size()];
+        block[..8].copy_from_slice(LOG_MAGIC);
+        block[8..16].copy_from_slice(&self.blocks.to_le_bytes());
+        block[16..24].copy_from_slice(&self.reported.to_le_bytes());
+        self.device.write_block(self.lba, &block)?;
+        Ok(())
+    }
+
+    // Sequence number and slot of each stored dump, oldest first
+    fn records(&self) -> FsResult<alloc::vec::Vec<(u64, u64)>> {
+        let mut header = [0u8; HEADER_SIZE];
+        let mut records = alloc::vec::Vec::new();
+        for slot in 0..self.slots {
+            let offset = self.slot_lba(slot) * self.device.block_size() as u64;
+            self.device.read_bytes(offset, &mut header)?;
+            if &header[..8] == DISK_MAGIC
+                && read_u64(&header, 8) as usize <= DUMP_SIZE - HEADER_SIZE
+            {
+                records.push((read_u64(&header, 16), slot));
+            }
+        }
+        records.sort_unstable();
+        Ok(records)
+    }
+
+    // The text of the dump in `slot`
+    fn read_record(&self, slot: u64) -> FsResult<String> {
+        let offset = self.slot_lba(slot) * self.device.block_size() as u64;
+        let mut header = [0u8; HEADER_SIZE];
+        self.device.read_bytes(offset, &mut header)?;
+        let mut text = alloc::vec![0u8; read_u64(&header, 8) as usize];
+        self.device
+            .read_bytes(offset + HEADER_SIZE as u64, &mut text)?;
+        Ok(alloc::format!("{}", Utf8Lossy(&text)))
+    }
+
+    // Append the dump in `buffer`: no locks, no heap
+    fn write_record(&mut self, buffer: &mut DumpBuffer) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let text_len = (buffer.len - HEADER_SIZE) as u64;
+        buffer.data[..8].copy_from_slice(DISK_MAGIC);
+        buffer.data[8..16].copy_from_slice(&text_len.to_le_bytes());
+        buffer.data[16..HEADER_SIZE].copy_from_slice(&seq.to_le_bytes());
+
+        let block_size = self.device.block_size();
+        let count = buffer.len.div_ceil(block_size);
+        buffer.data[buffer.len..count * block_size].fill(0);
+        let lba = self.slot_lba(seq % self.slots);
+        for i in 0..count {
+            let start = i * block_size;
+            if self
+                .device
+                .write_block_polled(lba + i as u64, &buffer.data[start..start + block_size])
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+fn write_disk(buffer: &mut DumpBuffer) {
+    let Some(mut disk) = DISK.try_lock() else {
+        return;
+    };
+    if let Some(disk) = disk.as_mut() {
+        disk.write_record(buffer);
+    }
+}
+
+// Open the region whose header is at `lba` of device `name`
+fn find_region(name: &str, lba: u64) -> Option<DiskTarget> {
+    let device = crate::block::get(name)?;
+    let mut block = alloc::vec![0u8; device.block_size()];
+    device.read_block(lba, &mut block).ok()?;
+    if &block[..8] != LOG_MAGIC {
+        return None;
+    }
+    DiskTarget::open(name, device, lba, read_u64(&block, 8), false).ok()
+}
+
+// Select the region named by `crashlog=<device>:<lba>` on the command line, else
+// the first block device with one at its start, and announce the dumps it got
+// since the last boot, once the block devices are registered
+pub fn init_disk() {
+    let found = match shared::cmdline::current().get("crashlog") {
+        Some(option) => {
+            let region = option
+                .split_once(':')
+                .and_then(|(name, lba)| Some((name, lba.parse().ok()?)))
+                .and_then(|(name, lba)| find_region(name, lba));
+            if region.is_none() {
+                log::warn!("Crash log: no region at crashlog={}", option);
+            }
+            region
+        }
+        None => crate::block::names()
+            .into_iter()
+            .find_map(|name| find_region(&name, 0)),
+    };
+    let Some(mut disk) = found else {
+        return;
+    };
+
+    let newest = disk.next_seq - 1;
+    if newest > disk.reported {
+        let new = disk
+            .records()
+            .map(|records| {
+                records
+                    .iter()
+                    .filter(|&&(seq, _)| seq > disk.reported)
+                    .count()
+            })
+            .unwrap_or(0);
+        log::warn!(
+            "Crash log on {}: {} dump(s) from a previous boot, see `dmesg --crash`",
+            disk.name,
+            new
+        );
+        disk.reported = newest;
+        if disk.write_header().is_err() {
+            log::warn!("Crash log on {}: cannot update the header", disk.name);
+        }
+    }
+    log::info!("Crash log: {} ({} slots)", disk.name, disk.slots);
+    interrupts::without_interrupts(|| *DISK.lock() = Some(disk));
+}
+
+// The dumps stored in the crash log region, oldest first
+pub fn stored() -> FsResult<String> {
+    let disk = interrupts::without_interrupts(|| {
+        DISK.lock().as_ref().map(|disk| {
+            (
+                disk.device.clone(),
+                disk.lba,
+                disk.blocks,
+                disk.name.clone(),
+            )
+        })
+    });
+    let (device, lba, blocks, name) = disk.ok_or(FsError::NoDevice)?;
+    // A second handle, so the crash path never waits for this read
+    let disk = DiskTarget::open(&name, device, lba, blocks, false)?;
+    let mut out = String::new();
+    for (_, slot) in disk.records()? {
+        out.push_str(&disk.read_record(slot)?);
+    }
+    Ok(out)
+}
+
+// ============================================================================
+// 4. ENTRY
+// ============================================================================
+
+// Remember the boot modules, called at boot
+pub fn init(boot_info: &shared::BootInfo) {
+    HHDM_OFFSET.store(boot_info.hhdm_offset, Ordering::Relaxed);
+    let modules = unsafe { boot_info.modules(boot_info.hhdm_offset) };
+    interrupts::without_interrupts(|| *MODULES.lock() = modules);
+}
+
+// Register /proc/crashdump (needs the heap)
+pub fn init_proc() {
+    procfs::register(&CRASHDUMP_ENTRY);
+}
+
+// Write the dump to serial (and the disk region), called with interrupts off
+pub fn dump(reason: Reason, regs: &Registers, backtrace: &[u64]) {
+    if DUMPING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let Some(mut buffer) = BUFFER.try_lock() else {
+        return;
+    };
+    write_dump(&mut buffer, &reason, regs, backtrace);
+    shared::serial::print_panic(format_args!("\n{}", buffer.text()));
+    write_disk(&mut buffer);
+}
+
+// Dump for a fatal exception, from its handler
+pub fn exception(name: &str, frame: &InterruptStackFrame, error_code: Option<u64>) {
+    let mut frames = [0u64; crate::backtrace::MAX_FRAMES];
+    let depth = crate::backtrace::collect(&mut frames);
+    dump(
+        Reason::Exception(name),
+        &Registers::from_exception(frame, error_code),
+        &frames[..depth],
+    );
+}
+
+fn read_config() -> String {
+    interrupts::without_interrupts(|| match &*DISK.lock() {
+        Some(disk) => alloc::format!("{} {} {}\n", disk.name, disk.lba, disk.blocks),
+        None => String::from("off\n"),
+    })
+}
+
+fn write_config(command: &str) -> FsResult<()> {
+    let words: alloc::vec::Vec<&str> = command.split_whitespace().collect();
+    let target = match words.as_slice() {
+        ["off"] => None,
+        [name, lba, blocks] => {
+            let device = crate::block::get(name).ok_or(FsError::NoDevice)?;
+            let lba: u64 = lba.parse().map_err(|_| FsError::InvalidArgument)?;
+            let blocks: u64 = blocks.parse().map_err(|_| FsError::InvalidArgument)?;
+            let disk = DiskTarget::open(name, device, lba, blocks, true)?;
+            if lba != 0 {
+                log::info!(
+                    "Crash log: add crashlog={}:{} to boot.cfg to use it after a reboot",
+                    name,
+                    lba
+                );
+            }
+            Some(disk)
+        }
+        _ => return Err(FsError::InvalidArgument),
+    };
+    interrupts::without_interrupts(|| *DISK.lock() = target);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+    use alloc::boxed::Box;
+
+    fn dump_of(text: &str) -> Box<DumpBuffer> {
+        let mut buffer: Box<DumpBuffer> = unsafe { Box::new_zeroed().assume_init() };
+        buffer.len = HEADER_SIZE;
+        let _ = buffer.write_str(text);
+        buffer
+    }
+
+    #[test_case]
+    fn crash_log_appends_round_robin() {
+        // Two slots after the header, from block 2 of a scratch disk
+        let slot_blocks = (DUMP_SIZE / 512) as u64;
+        let blocks = 1 + 2 * slot_blocks;
+        let memory = Box::leak(alloc::vec![0xAAu8; (blocks as usize + 2) * 512].into_boxed_slice());
+        let device: Arc<dyn BlockDevice> =
+            Arc::new(unsafe { RamDisk::new(memory.as_ptr() as u64, memory.len(), 512, true) });
+
+        assert!(DiskTarget::open("scratch", device.clone(), 2, blocks, false).is_err());
+        let mut disk = DiskTarget::open("scratch", device.clone(), 2, blocks, true).unwrap();
+        assert_eq!(disk.slots, 2);
+        assert_eq!(disk.records(), Ok(alloc::vec![]));
+
+        for text in ["first\n", "second\n", "third\n"] {
+            disk.write_record(&mut dump_of(text));
+        }
+
+        // Reopened as at the next boot: the oldest dump was overwritten
+        let disk = DiskTarget::open("scratch", device, 2, blocks, false).unwrap();
+        assert_eq!(disk.next_seq, 4);
+        let texts: alloc::vec::Vec<String> = disk
+            .records()
+            .unwrap()
+            .iter()
+            .map(|&(_, slot)| disk.read_record(slot).unwrap())
+            .collect();
+        assert_eq!(texts, ["second\n", "third\n"]);
+    }
+}
@@ -0,0 +1,140 @@
+// Delay Module
+// Calibrated busy-wait delays for device drivers (i8042, AHCI port reset, USB, ...)
+//
+// At boot we measure how many TSC cycles elapse during a fixed PIT countdown.
+// udelay()/mdelay() then spin on the TSC. A TSC that is not invariant (its rate
+// follows frequency changes, it may stop in deep C-states) is still calibrated,
+// for CPU time accounting and benchmarks, but the delays use PIT one-shot
+// countdowns instead; so they do if the TSC could not be calibrated.
+//
+// PIT channel 0 is used by the periodic system timer (see interrupts::init_timer),
+// so all one-shot countdowns here use channel 2 (the PC speaker channel),
+// whose output can be polled through port 0x61 without any interrupt.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+// PIT input frequency
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+// Maximum countdown value of the 16-bit PIT counter (~54.9 ms)
+const PIT_MAX_COUNT: u64 = 0xFFFF;
+
+// Calibration window (10 ms)
+const CALIBRATION_US: u64 = 10_000;
+
+// PIT ports
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const SPEAKER_PORT: u16 = 0x61;
+
+// Channel 2, access lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+const PIT_CH2_ONESHOT: u8 = 0b1011_0000;
+
+// Bits of port 0x61
+const SPEAKER_GATE2: u8 = 1 << 0; // Gate input of channel 2
+const SPEAKER_DATA: u8 = 1 << 1; // Speaker enable (must stay off)
+const SPEAKER_OUT2: u8 = 1 << 5; // Output of channel 2 (read only)
+
+// TSC cycles per microsecond (0 = not calibrated)
+static TSC_PER_US: AtomicU64 = AtomicU64::new(0);
+
+// Whether delays can spin on the TSC
+static TSC_INVARIANT: AtomicBool = AtomicBool::new(false);
+
+#[inline(always)]
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+// Start a one-shot countdown of `count` PIT ticks on channel 2
+fn pit_oneshot_start(count: u16) {
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut channel2 = Port::<u8>::new(PIT_CHANNEL2);
+    let mut speaker = Port::<u8>::new(SPEAKER_PORT);
+
+    unsafe {
+        // Gate low while programming, speaker off
+        let value = speaker.read() & !(SPEAKER_GATE2 | SPEAKER_DATA);
+        speaker.write(value);
+
+        command.write(PIT_CH2_ONESHOT);
+        channel2.write((count & 0xFF) as u8);
+        channel2.write((count >> 8) as u8);
+
+        // Raising the gate starts the countdown
+        speaker.write(value | SPEAKER_GATE2);
+    }
+}
+
+// Has the channel 2 countdown reached zero?
+fn pit_oneshot_done() -> bool {
+    let mut speaker = Port::<u8>::new(SPEAKER_PORT);
+    unsafe { speaker.read() & SPEAKER_OUT2 != 0 }
+}
+
+// Busy-wait for `ticks` PIT ticks using channel 2 one-shot countdowns
+fn pit_wait_ticks(mut ticks: u64) {
+    while ticks > 0 {
+        let chunk = ticks.min(PIT_MAX_COUNT);
+        pit_oneshot_start(chunk as u16);
+        while !pit_oneshot_done() {
+            core::hint::spin_loop();
+        }
+        ticks -= chunk;
+    }
+}
+
+// Busy-wait for the given number of microseconds using only the PIT
+pub fn pit_oneshot(us: u64) {
+    pit_wait_ticks((us * PIT_FREQUENCY).div_ceil(1_000_000));
+}
+
+// Measure the TSC frequency against a PIT one-shot countdown; the CPU features
+// (BootInfo::cpu_features) say whether delays may rely on it
+pub fn calibrate(features: u64) {
+    let ticks = CALIBRATION_US * PIT_FREQUENCY / 1_000_000;
+
+    pit_oneshot_start(ticks as u16);
+    let start = rdtsc();
+    while !pit_oneshot_done() {
+        core::hint::spin_loop();
+    }
+    let end = rdtsc();
+
+    let per_us = (end - start) / CALIBRATION_US;
+    TSC_PER_US.store(per_us, Ordering::Relaxed);
+
+    log::info!("TSC calibrated: {} MHz", per_us);
+
+    let invariant = features & shared::cpu::INVARIANT_TSC != 0;
+    TSC_INVARIANT.store(invariant, Ordering::Relaxed);
+    if !invariant {
+        log::warn!("TSC not invariant, delays use the PIT");
+    }
+}
+
+// TSC cycles per microsecond (0 if not calibrated)
+pub fn tsc_per_us() -> u64 {
+    TSC_PER_US.load(Ordering::Relaxed)
+}
+
+// Busy-wait for `us` microseconds
+pub fn udelay(us: u64) {
+    let per_us = tsc_per_us();
+    if per_us == 0 || !TSC_INVARIANT.load(Ordering::Relaxed) {
+        pit_oneshot(us);
+        return;
+    }
+
+    let start = rdtsc();
+    let cycles = us * per_us;
+    while rdtsc().wrapping_sub(start) < cycles {
+        core::hint::spin_loop();
+    }
+}
+
+// Busy-wait for `ms` milliseconds
+pub fn mdelay(ms: u64) {
+    udelay(ms * 1000);
+}
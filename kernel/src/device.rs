@@ -0,0 +1,530 @@
+// Device / Driver Model
+// Buses (platform, PCI, virtio) register devices into a global registry.
+// Drivers declare a match table; whenever a device or a driver is registered,
+// matching pairs are bound and the driver's probe callback is invoked.
+//
+// Device tree:
+//   platform  - legacy devices at fixed I/O ports (PIT, PIC, i8042 and its aux port,
+//               COM1-4, framebuffer)
+//   pci       - devices found by scanning PCI configuration space
+//   virtio    - virtio devices, children of their virtio-pci transport device
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// ============================================================================
+// 1. TYPES
+// ============================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusType {
+    Platform,
+    Pci,
+    Virtio,
+}
+
+impl BusType {
+    pub fn name(self) -> &'static str {
+        match self {
+            BusType::Platform => "platform",
+            BusType::Pci => "pci",
+            BusType::Virtio => "virtio",
+        }
+    }
+}
+
+// Bus-specific identification of a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceIdent {
+    Platform(&'static str),
+    Pci {
+        bus: u8,
+        slot: u8,
+        function: u8,
+        vendor_id: u16,
+        device_id: u16,
+        class: u8,
+        subclass: u8,
+    },
+    Virtio {
+        device_type: u16,
+    },
+}
+
+// An entry of a driver's match table
+#[derive(Debug, Clone, Copy)]
+pub enum MatchId {
+    Platform(&'static str),
+    PciVendor(u16),
+    PciClass { class: u8, subclass: u8 },
+    Virtio(u16),
+}
+
+impl MatchId {
+    fn matches(&self, ident: &DeviceIdent) -> bool {
+        match (*self, *ident) {
+            (MatchId::Platform(name), DeviceIdent::Platform(dev_name)) => name == dev_name,
+            (MatchId::PciVendor(vendor_id), DeviceIdent::Pci { vendor_id: v, .. }) => {
+                vendor_id == v
+            }
+            (
+                MatchId::PciClass { class, subclass },
+                DeviceIdent::Pci {
+                    class: c,
+                    subclass: s,
+                    ..
+                },
+            ) => class == c && subclass == s,
+            (MatchId::Virtio(device_type), DeviceIdent::Virtio { device_type: t }) => {
+                device_type == t
+            }
+            _ => false,
+        }
+    }
+}
+
+// Errors a driver may return from probe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeError {
+    Unsupported,
+    InitFailed,
+}
+
+// A registered device
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub id: usize,
+    pub name: String,
+    pub bus: BusType,
+    pub ident: DeviceIdent,
+    pub parent: Option<usize>,
+    pub driver: Option<&'static str>,
+}
+
+// A driver: match table + lifecycle callbacks
+pub struct Driver {
+    pub name: &'static str,
+    pub bus: BusType,
+    pub match_table: &'static [MatchId],
+    pub probe: fn(&Device) -> Result<(), ProbeError>,
+    pub remove: fn(&Device),
+    // Save state before suspend-to-RAM, restore it after the hardware was reset
+    pub suspend: fn(&Device),
+    pub resume: fn(&Device),
+}
+
+impl Driver {
+    fn matches(&self, device: &Device) -> bool {
+        self.bus == device.bus && self.match_table.iter().any(|m| m.matches(&device.ident))
+    }
+}
+
+impl fmt::Display for DeviceIdent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            DeviceIdent::Platform(name) => write!(f, "{}", name),
+            DeviceIdent::Pci {
+                bus,
+                slot,
+                function,
+                vendor_id,
+                device_id,
+                class,
+                subclass,
+            } => write!(
+                f,
+                "{:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}:{:02x}",
+                bus, slot, function, vendor_id, device_id, class, subclass
+            ),
+            DeviceIdent::Virtio { device_type } => write!(f, "virtio type {}", device_type),
+        }
+    }
+}
+
+// ============================================================================
+// 2. REGISTRY
+// ============================================================================
+
+struct Registry {
+    devices: Vec<Device>,
+    drivers: Vec<&'static Driver>,
+    next_id: usize,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    devices: Vec::new(),
+    drivers: Vec::new(),
+    next_id: 0,
+});
+
+// Run a closure with the registry locked (interrupts disabled to avoid deadlocks)
+fn with_registry<R>(f: impl FnOnce(&mut Registry) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut REGISTRY.lock()))
+}
+
+// Register a device on a bus and try to bind a driver to it
+// Returns the device id
+pub fn register_device(
+    name: String,
+    bus: BusType,
+    ident: DeviceIdent,
+    parent: Option<usize>,
+) -> usize {
+    let (device, drivers) = with_registry(|reg| {
+        let id = reg.next_id;
+        reg.next_id += 1;
+
+        let device = Device {
+            id,
+            name,
+            bus,
+            ident,
+            parent,
+            driver: None,
+        };
+        reg.devices.push(device.clone());
+
+        (device, reg.drivers.clone())
+    });
+
+    let id = device.id;
+    for driver in drivers {
+        if driver.matches(&device) && try_probe(driver, &device) {
+            break;
+        }
+    }
+
+    id
+}
+
+// Register a driver and try to bind it to every unbound matching device
+pub fn register_driver(driver: &'static Driver) {
+    let candidates: Vec<Device> = with_registry(|reg| {
+        reg.drivers.push(driver);
+        reg.devices
+            .iter()
+            .filter(|dev| dev.driver.is_none() && driver.matches(dev))
+            .cloned()
+            .collect()
+    });
+
+    for device in candidates {
+        try_probe(driver, &device);
+    }
+}
+
+// Remove a device (and its children), calling the bound driver's remove callback
+pub fn remove_device(id: usize) {
+    let children: Vec<usize> = with_registry(|reg| {
+        reg.devices
+            .iter()
+            .filter(|dev| dev.parent == Some(id))
+            .map(|dev| dev.id)
+            .collect()
+    });
+
+    for child in children {
+        remove_device(child);
+    }
+
+    let removed = with_registry(|reg| {
+        let index = reg.devices.iter().position(|dev| dev.id == id)?;
+        let device = reg.devices.remove(index);
+        let driver = reg
+            .drivers
+            .iter()
+            .find(|drv| Some(drv.name) == device.driver)
+            .copied();
+        Some((device, driver))
+    });
+
+    if let Some((device, Some(driver))) = removed {
+        (driver.remove)(&device);
+    }
+}
+
+// Quiesce the machine for another kernel: remove every device (drivers stop
+// their hardware in remove) and keep PCI functions from doing any more DMA
+pub fn shutdown() {
+    let devices = devices();
+    for device in devices.iter().filter(|dev| dev.parent.is_none()) {
+        remove_device(device.id);
+    }
+    for device in &devices {
+        if let DeviceIdent::Pci {
+            bus,
+            slot,
+            function,
+            ..
+        } = device.ident
+        {
+            crate::pci::disable_bus_master(bus, slot, function);
+        }
+    }
+}
+
+// Bound devices with their drivers, in registration order (parents first)
+fn bound_devices() -> Vec<(Device, &'static Driver)> {
+    with_registry(|reg| {
+        reg.devices
+            .iter()
+            .filter_map(|dev| {
+                let driver = reg
+                    .drivers
+                    .iter()
+                    .find(|drv| Some(drv.name) == dev.driver)?;
+                Some((dev.clone(), *driver))
+            })
+            .collect()
+    })
+}
+
+// Let every driver save its device state before suspend-to-RAM, children first
+pub fn suspend() {
+    for (device, driver) in bound_devices().iter().rev() {
+        (driver.suspend)(device);
+    }
+}
+
+// Let every driver bring its device back after resume, parents first
+pub fn resume() {
+    for (device, driver) in &bound_devices() {
+        (driver.resume)(device);
+    }
+}
+
+// Probe a device with a driver, the registry lock is NOT held during the callback
+// so drivers can register child devices from probe
+fn try_probe(driver: &'static Driver, device: &Device) -> bool {
+    match (driver.probe)(device) {
+        Ok(()) => {
+            with_registry(|reg| {
+                if let Some(dev) = reg.devices.iter_mut().find(|dev| dev.id == device.id) {
+                    dev.driver = Some(driver.name);
+                }
+            });
+            log::info!("{} bound to {}", device.name, driver.name);
+            true
+        }
+        Err(err) => {
+            log::warn!("{} probe of {} failed: {:?}", driver.name, device.name, err);
+            false
+        }
+    }
+}
+
+// Snapshot of all registered devices
+pub fn devices() -> Vec<Device> {
+    with_registry(|reg| reg.devices.clone())
+}
+
+// ============================================================================
+// 3. LSDEV
+// ============================================================================
+
+// Print the device tree grouped by bus (used by the `lsdev` shell command)
+pub fn lsdev() {
+    let devices = devices();
+
+    for bus in [BusType::Platform, BusType::Pci] {
+        println!("{}", bus.name());
+        for device in devices
+            .iter()
+            .filter(|dev| dev.bus == bus && dev.parent.is_none())
+        {
+            print_device(&devices, device, 1);
+        }
+    }
+}
+
+fn print_device(devices: &[Device], device: &Device, depth: usize) {
+    for _ in 0..depth {
+        print!("  ");
+    }
+    println!(
+        "[{}] {} ({}) driver: {}",
+        device.id,
+        device.name,
+        device.ident,
+        device.driver.unwrap_or("-")
+    );
+
+    for child in devices.iter().filter(|dev| dev.parent == Some(device.id)) {
+        print_device(devices, child, depth + 1);
+    }
+}
+
+// ============================================================================
+// 4. BUILT-IN DEVICES & DRIVERS
+// ============================================================================
+
+// Legacy devices which are always present on a PC
+// Serial ports are probed: one "serial8250" device per UART found, named ttyS<n>
+const PLATFORM_DEVICES: &[&str] = &["pit", "pic8259", "i8042", "psaux", "framebuffer"];
+
+// Platform drivers for the devices the kernel already drives directly
+static PIT_DRIVER: Driver = Driver {
+    name: "pit-timer",
+    bus: BusType::Platform,
+    match_table: &[MatchId::Platform("pit")],
+    probe: probe_builtin,
+    remove: remove_noop,
+    suspend: suspend_noop,
+    resume: resume_noop,
+};
+
+static PIC_DRIVER: Driver = Driver {
+    name: "pic8259",
+    bus: BusType::Platform,
+    match_table: &[MatchId::Platform("pic8259")],
+    probe: probe_builtin,
+    remove: remove_noop,
+    suspend: suspend_noop,
+    resume: resume_noop,
+};
+
+static KEYBOARD_DRIVER: Driver = Driver {
+    name: "i8042-keyboard",
+    bus: BusType::Platform,
+    match_table: &[MatchId::Platform("i8042")],
+    probe: probe_builtin,
+    remove: remove_noop,
+    suspend: suspend_noop,
+    resume: resume_noop,
+};
+
+static SERIAL_DRIVER: Driver = Driver {
+    name: "uart16550",
+    bus: BusType::Platform,
+    match_table: &[MatchId::Platform("serial8250")],
+    probe: probe_builtin,
+    remove: remove_noop,
+    suspend: suspend_noop,
+    resume: resume_noop,
+};
+
+static FRAMEBUFFER_DRIVER: Driver = Driver {
+    name: "gop-fb",
+    bus: BusType::Platform,
+    match_table: &[MatchId::Platform("framebuffer")],
+    probe: probe_builtin,
+    remove: remove_noop,
+    suspend: suspend_noop,
+    resume: resume_noop,
+};
+
+// virtio-pci transport: exposes the virtio device behind a PCI function on the virtio bus
+static VIRTIO_PCI_DRIVER: Driver = Driver {
+    name: "virtio-pci",
+    bus: BusType::Pci,
+    match_table: &[MatchId::PciVendor(crate::pci::VIRTIO_VENDOR_ID)],
+    probe: probe_virtio_pci,
+    remove: remove_noop,
+    suspend: suspend_pci,
+    resume: resume_pci,
+};
+
+// These devices are initialized early in `_start`, binding only records ownership
+fn probe_builtin(_device: &Device) -> Result<(), ProbeError> {
+    Ok(())
+}
+
+fn remove_noop(_device: &Device) {}
+
+// The kernel reprograms the built-in devices itself on resume (see suspend.rs)
+fn suspend_noop(_device: &Device) {}
+
+fn resume_noop(_device: &Device) {}
+
+// Configuration headers of PCI functions saved across suspend-to-RAM, by device id
+static PCI_HEADERS: Mutex<BTreeMap<usize, [u32; crate::pci::HEADER_DWORDS]>> =
+    Mutex::new(BTreeMap::new());
+
+fn suspend_pci(device: &Device) {
+    if let DeviceIdent::Pci {
+        bus,
+        slot,
+        function,
+        ..
+    } = device.ident
+    {
+        let header = crate::pci::save_header(bus, slot, function);
+        interrupts::without_interrupts(|| PCI_HEADERS.lock().insert(device.id, header));
+    }
+}
+
+fn resume_pci(device: &Device) {
+    let header = interrupts::without_interrupts(|| PCI_HEADERS.lock().remove(&device.id));
+    if let (
+        Some(header),
+        DeviceIdent::Pci {
+            bus,
+            slot,
+            function,
+            ..
+        },
+    ) = (header, device.ident)
+    {
+        crate::pci::restore_header(bus, slot, function, &header);
+    }
+}
+
+fn probe_virtio_pci(device: &Device) -> Result<(), ProbeError> {
+    let DeviceIdent::Pci { device_id, .. } = device.ident else {
+        return Err(ProbeError::Unsupported);
+    };
+
+    let device_type = crate::pci::virtio_device_type(device_id).ok_or(ProbeError::Unsupported)?;
+    let name = alloc::format!("virtio{}", device.id);
+    register_device(
+        name,
+        BusType::Virtio,
+        DeviceIdent::Virtio { device_type },
+        Some(device.id),
+    );
+    Ok(())
+}
+
+// Register built-in drivers, platform devices and enumerate the PCI bus
+// Must be called after the heap is initialized
+pub fn init() {
+    for driver in [
+        &PIT_DRIVER,
+        &PIC_DRIVER,
+        &KEYBOARD_DRIVER,
+        &SERIAL_DRIVER,
+        &FRAMEBUFFER_DRIVER,
+        &VIRTIO_PCI_DRIVER,
+        &crate::ata::DRIVER,
+        &crate::virtio_rng::DRIVER,
+        #[cfg(feature = "userspace")]
+        &crate::mouse::DRIVER,
+    ] {
+        register_driver(driver);
+    }
+
+    for &name in PLATFORM_DEVICES {
+        register_device(
+            String::from(name),
+            BusType::Platform,
+            DeviceIdent::Platform(name),
+            None,
+        );
+    }
+    for com in shared::serial::present_ports() {
+        register_device(
+            alloc::format!("ttyS{}", com.index()),
+            BusType::Platform,
+            DeviceIdent::Platform("serial8250"),
+            None,
+        );
+    }
+
+    crate::pci::enumerate();
+
+    log::info!("{} devices registered", devices().len());
+}
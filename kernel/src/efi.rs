@@ -0,0 +1,191 @@
+// UEFI Runtime Services
+// The bootloader hands over the firmware's runtime services in virtual mode, at
+// their HHDM addresses (SetVirtualAddressMap), so they can be called from any
+// address space. Three are used: GetTime, for a wall clock with a four-digit year
+// (rtc.rs), ResetSystem, for a firmware reboot or power off, and SetVariable, to
+// confirm a trial boot of kernel_new (shared::fallback).
+//
+// The firmware is not reentrant and may use SSE: calls are serialized, made with
+// interrupts off, and the caller's x87/SSE state is saved around them.
+
+use shared::BootInfo;
+use shared::fallback;
+use shared::once::Once;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// "RUNTSERV"
+const RUNTIME_SERVICES_SIGNATURE: u64 = 0x5652_4553_544e_5552;
+
+// EFI_TIME's time zone when the clock keeps local time of an unknown zone
+const UNSPECIFIED_TIMEZONE: i16 = 0x07FF;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    _pad1: u8,
+    pub nanosecond: u32,
+    pub timezone: i16, // Minutes from UTC: local time = UTC - timezone
+    pub daylight: u8,
+    _pad2: u8,
+}
+
+impl Time {
+    // Minutes to add to the time to get UTC (0 if the zone is unknown)
+    pub fn utc_offset_minutes(&self) -> i64 {
+        if self.timezone == UNSPECIFIED_TIMEZONE {
+            0
+        } else {
+            self.timezone as i64
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ResetType {
+    Cold = 0,
+    Shutdown = 2,
+}
+
+// EFI_RUNTIME_SERVICES up to ResetSystem
+#[repr(C)]
+struct RuntimeServices {
+    signature: u64,
+    revision: u32,
+    header_size: u32,
+    crc32: u32,
+    reserved: u32,
+    get_time: unsafe extern "efiapi" fn(time: *mut Time, capabilities: *mut u8) -> usize,
+    set_time: usize,
+    get_wakeup_time: usize,
+    set_wakeup_time: usize,
+    set_virtual_address_map: usize,
+    convert_pointer: usize,
+    get_variable: usize,
+    get_next_variable_name: usize,
+    set_variable: unsafe extern "efiapi" fn(
+        name: *const u16,
+        vendor: *const Guid,
+        attributes: u32,
+        data_size: usize,
+        data: *const u8,
+    ) -> usize,
+    get_next_high_monotonic_count: usize,
+    reset_system: unsafe extern "efiapi" fn(
+        reset_type: ResetType,
+        status: usize,
+        data_size: usize,
+        data: *const u8,
+    ) -> !,
+}
+
+// EFI_GUID, in its byte order
+#[repr(C, align(8))]
+struct Guid([u8; 16]);
+
+static RUNTIME: Once<Option<&'static RuntimeServices>> = Once::new();
+static CALL: Mutex<()> = Mutex::new(());
+
+// Pick up the runtime services table of the BootInfo, if the bootloader kept them
+pub fn init(boot_info: &BootInfo) {
+    let runtime = RUNTIME.call_once(|| {
+        if boot_info.efi_runtime_services == 0 {
+            return None;
+        }
+        let table = unsafe {
+            &*((boot_info.efi_runtime_services + boot_info.hhdm_offset) as *const RuntimeServices)
+        };
+        (table.signature == RUNTIME_SERVICES_SIGNATURE).then_some(table)
+    });
+    match runtime {
+        Some(table) => log::info!(
+            "EFI: runtime services revision {}.{}",
+            table.revision >> 16,
+            table.revision & 0xFFFF
+        ),
+        None => log::info!("EFI: no runtime services"),
+    }
+}
+
+pub fn available() -> bool {
+    matches!(RUNTIME.get(), Some(Some(_)))
+}
+
+// Run a firmware call: one at a time, interrupts off, x87/SSE state preserved
+fn call<T>(f: impl FnOnce(&'static RuntimeServices) -> T) -> Option<T> {
+    let table = (*RUNTIME.get()?)?;
+    #[repr(C, align(16))]
+    struct FxArea([u8; 512]);
+    let mut fx = FxArea([0; 512]);
+    let _guard = CALL.lock();
+    Some(interrupts::without_interrupts(|| unsafe {
+        core::arch::asm!("fxsave [{}]", in(reg) &mut fx, options(nostack));
+        let result = f(table);
+        core::arch::asm!("fxrstor [{}]", in(reg) &fx, options(nostack));
+        result
+    }))
+}
+
+// The firmware's clock
+pub fn get_time() -> Option<Time> {
+    call(|table| {
+        let mut time = Time::default();
+        let status = unsafe { (table.get_time)(&mut time, core::ptr::null_mut()) };
+        (status == 0).then_some(time)
+    })
+    .flatten()
+}
+
+// Reset or power off through the firmware; returns only if it cannot
+pub fn reset(kind: ResetType) {
+    call(|table| unsafe { (table.reset_system)(kind, 0, 0, core::ptr::null()) });
+}
+
+// Tell the bootloader this kernel came up: on a trial boot of kernel_new, delete
+// the variable that would make the next boot fall back to kernel_good
+pub fn confirm_boot(boot_info: &BootInfo) {
+    if boot_info.boot_trial == 0 {
+        return;
+    }
+    let mut name = [0u16; 32];
+    for (slot, unit) in name
+        .iter_mut()
+        .zip(fallback::PENDING_VARIABLE.encode_utf16())
+    {
+        *slot = unit;
+    }
+    let vendor = Guid(fallback::VENDOR_GUID);
+    // No data and no attributes: delete
+    let status = call(|table| unsafe {
+        (table.set_variable)(name.as_ptr(), &vendor, 0, 0, core::ptr::null())
+    });
+    match status {
+        Some(0) => log::info!("EFI: boot of kernel_new confirmed"),
+        Some(status) => log::warn!("EFI: confirming the boot failed (status {:#x})", status),
+        None => log::warn!("EFI: no runtime services, the boot of kernel_new stays unconfirmed"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn efi_time_is_sane() {
+        // Booted by uefi_boot the clock answers; without runtime services there is
+        // nothing to check
+        if let Some(time) = get_time() {
+            assert!(time.year >= 2000);
+            assert!((1..=12).contains(&time.month));
+            assert!((1..=31).contains(&time.day));
+            assert!(time.hour < 24 && time.minute < 60 && time.second < 60);
+        }
+    }
+}
@@ -1,6 +1,10 @@
 // ELF Loader Module
 // This module loads an ELF64 executable into user memory and prepares for user mode execution
 
+use crate::vma::{PROT_EXEC, PROT_READ, PROT_WRITE, Vma, VmaKind};
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::Mutex;
 use x86_64::VirtAddr;
 use x86_64::structures::paging::{
     FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB,
@@ -18,9 +22,47 @@ const USER_BASE_ADDR: u64 = 0x40_0000; // 4 MB - standard user space base for PI
 const USER_STACK_BOTTOM: u64 = 0x7FFF_FFFF_0000; // Top of user space
 const USER_STACK_SIZE: u64 = 16 * 4096; // 64 KB stack
 
-// Include the user ELF binary at compile time
-// Change this path to load a different program
-static USER_ELF_BYTES: &[u8] = include_bytes!("../../user_space/hello");
+// Program memory must lie in the lower canonical half, above the null page
+const USER_MIN_ADDR: u64 = 0x1000;
+const USER_MAX_ADDR: u64 = 0x0000_8000_0000_0000;
+
+// Where mmap places private mappings, demand paged
+pub const MMAP_START: u64 = 0x1000_0000_0000;
+pub const MMAP_END: u64 = 0x7000_0000_0000;
+
+// Thread-local storage: one slot per thread above the stack guard gap, each
+// holding the TLS block followed by the thread control block the TP points at
+const USER_TLS_BASE: u64 = 0x7FFF_0000_0000;
+const TLS_SLOT_SIZE: u64 = 256 * 4096; // 1 MB per thread
+const TLS_MAX_THREADS: usize = 64;
+const TCB_SIZE: u64 = 256;
+
+// Window for MAP_SHARED mappings (16 GiB), whose frames belong to shared memory objects
+pub const SHARED_MAP_START: u64 = 0x10_0000_0000;
+pub const SHARED_MAP_END: u64 = 0x14_0000_0000;
+
+// Regions the loader or mmap map themselves, which segments may not cover
+const RESERVED_REGIONS: &[Range<u64>] = &[
+    MMAP_START..MMAP_END,
+    SHARED_MAP_START..SHARED_MAP_END,
+    USER_TLS_BASE..USER_TLS_BASE + TLS_MAX_THREADS as u64 * TLS_SLOT_SIZE,
+    USER_STACK_BOTTOM - USER_STACK_SIZE..USER_STACK_BOTTOM,
+];
+
+/// PT_TLS segment of the loaded program: the initialization image, copied into
+/// every thread's TLS block
+struct TlsTemplate {
+    // .tdata (the rest of the block, .tbss, is zeroed)
+    image: Vec<u8>,
+    mem_size: u64,
+    align: u64,
+}
+
+static TLS_TEMPLATE: Mutex<Option<TlsTemplate>> = Mutex::new(None);
+
+// Pages of the loaded program's PT_GNU_RELRO, made read-only on its first syscall:
+// a PIE relocates its GOT itself before making any
+static PENDING_RELRO: Mutex<Option<Range<u64>>> = Mutex::new(None);
 
 // Global variable to store HHDM offset
 static mut HHDM_OFFSET: u64 = 0;
@@ -33,133 +75,186 @@ pub fn init_hhdm(offset: u64) {
 }
 
 /// Get the HHDM offset
-fn get_hhdm_offset() -> u64 {
+pub fn get_hhdm_offset() -> u64 {
     unsafe { HHDM_OFFSET }
 }
 
-/// Load the user ELF executable into memory
+/// Load an ELF executable image into user memory
 /// Returns the entry point virtual address
-pub fn load_user_elf(
+pub fn load_elf(
+    data: &[u8],
     mapper: &mut OffsetPageTable<'static>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> VirtAddr {
+) -> Result<VirtAddr, &'static str> {
     // Parse the ELF file
-    let elf = ElfFile::new(USER_ELF_BYTES).expect("Failed to parse ELF file");
+    let elf = ElfFile::new(data)?;
 
     // Verify this is a valid ELF64 executable
-    assert!(
-        elf.header.pt1.magic == [0x7F, b'E', b'L', b'F'],
-        "Invalid ELF magic"
-    );
+    if elf.header.pt1.magic != [0x7F, b'E', b'L', b'F'] {
+        return Err("Invalid ELF magic");
+    }
 
     // Determine if this is a PIE or a regular executable
     let is_pie = elf.header.pt2.type_().as_type() == header::Type::SharedObject;
 
     // For PIE, we add a base address. For EXEC, we use the addresses as-is.
     let base_addr = if is_pie {
-        println!(
-            "[ELF] PIE executable detected, loading at base {:#x}",
+        log::info!(
+            "PIE executable detected, loading at base {:#x}",
             USER_BASE_ADDR
         );
         USER_BASE_ADDR
     } else {
-        println!("[ELF] Static executable detected");
+        log::info!("Static executable detected");
         0
     };
 
-    // Load each LOAD segment into memory
+    // Nothing is mapped before the whole layout is known to be sane
+    let segments = validate_layout(&elf, base_addr)?;
+    // The previous program's memory goes back to the PMM
+    crate::vma::reset();
+    log::debug!(
+        "{} LOAD segments within {:#x}..{:#x}",
+        segments.len(),
+        segments
+            .iter()
+            .map(|segment| segment.start)
+            .min()
+            .unwrap_or(0),
+        segments
+            .iter()
+            .map(|segment| segment.end)
+            .max()
+            .unwrap_or(0)
+    );
+
+    // Load each LOAD segment into memory, remember the TLS template
+    let mut tls = None;
     for program_header in elf.program_iter() {
-        if let Ok(Type::Load) = program_header.get_type() {
-            load_segment(mapper, frame_allocator, &elf, &program_header, base_addr);
+        match program_header.get_type() {
+            Ok(Type::Load) => {
+                load_segment(mapper, frame_allocator, &elf, &program_header, base_addr)?
+            }
+            Ok(Type::Tls) => tls = Some(tls_template(&elf, &program_header)?),
+            _ => {}
         }
     }
+    *TLS_TEMPLATE.lock() = tls;
+    *PENDING_RELRO.lock() = relro_pages(&elf, base_addr);
 
-    // Pre-map a region for mmap pool (used by musl for signal stacks, etc.)
-    // This is a simple approach - a real OS would map on demand
-    const MMAP_POOL_START: u64 = 0x480000;
-    const MMAP_POOL_END: u64 = 0x500000; // 512KB pool
-
-    let pool_start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(MMAP_POOL_START));
-    let pool_end_page = Page::<Size4KiB>::containing_address(VirtAddr::new(MMAP_POOL_END - 1));
+    // The brk heap starts empty past the last segment and grows from there
+    let program_end = segments.iter().map(|segment| segment.end).max();
+    crate::vma::init_brk(program_end.unwrap_or(USER_MIN_ADDR).next_multiple_of(4096));
 
-    let pool_flags = PageTableFlags::PRESENT
-        | PageTableFlags::USER_ACCESSIBLE
-        | PageTableFlags::WRITABLE
-        | PageTableFlags::NO_EXECUTE;
+    // Process relocations for PIE executable
+    // We intentionally skip kernel-side relocations effectively letting musl handle it
+    // itself using the information provided in the Auxiliary Vector (AT_PHDR).
+    // If we were to apply relocations here, we would get double-relocation because
+    // musl also applies them by adding the base address.
+    if is_pie {
+        // process_relocations(mapper, &elf, base_addr, &segments);
+        log::debug!("Skipping kernel relocations - expecting user runtime self-relocation");
+    }
 
-    let hhdm = get_hhdm_offset();
+    // Calculate the actual entry point (checked by validate_layout)
+    let entry_offset = elf.header.pt2.entry_point();
+    let entry_point = VirtAddr::new(base_addr + entry_offset);
 
-    for page in Page::range_inclusive(pool_start_page, pool_end_page) {
-        if mapper.translate_page(page).is_ok() {
-            continue; // Already mapped
-        }
+    log::info!("Entry point at {:#x}", entry_point.as_u64());
 
-        let frame = frame_allocator
-            .allocate_frame()
-            .expect("Failed to allocate frame for mmap pool");
+    Ok(entry_point)
+}
 
-        unsafe {
-            mapper
-                .map_to(page, frame, pool_flags, frame_allocator)
-                .expect("Failed to map mmap pool page")
-                .flush();
+/// Check the address layout of the program before anything is mapped
+/// Returns the relocated address ranges of the LOAD segments
+///
+/// Every LOAD segment must lie in user space, outside the regions the loader
+/// maps itself and outside every other segment (they may share a page, not
+/// bytes), with its file data inside the image; the entry point must be in an
+/// executable segment, the TLS and DYNAMIC segments inside loaded memory.
+fn validate_layout(elf: &ElfFile, base_addr: u64) -> Result<Vec<Range<u64>>, &'static str> {
+    let mut segments: Vec<Range<u64>> = Vec::new();
+    let mut entry_ok = false;
+    let entry = base_addr
+        .checked_add(elf.header.pt2.entry_point())
+        .ok_or("Entry point out of range")?;
 
-            // Zero the page
-            let frame_ptr = (frame.start_address().as_u64() + hhdm) as *mut u8;
-            core::ptr::write_bytes(frame_ptr, 0, 4096);
+    for ph in elf.program_iter() {
+        if !matches!(ph.get_type(), Ok(Type::Load)) || ph.mem_size() == 0 {
+            continue;
         }
-    }
-
-    println!(
-        "[ELF] mmap pool pre-mapped: {:#x} - {:#x}",
-        MMAP_POOL_START, MMAP_POOL_END
-    );
-
-    // ALSO Pre-map a region for Heap (brk)
-    // Current sys_brk starts at 0x8000000
-    const HEAP_START: u64 = 0x8000000;
-    const HEAP_END: u64 = 0x8100000; // 1MB heap
+        let range = user_range(base_addr, ph.virtual_addr(), ph.mem_size())?;
 
-    let heap_start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(HEAP_START));
-    let heap_end_page = Page::<Size4KiB>::containing_address(VirtAddr::new(HEAP_END - 1));
-
-    for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        if mapper.translate_page(page).is_ok() {
-            continue;
+        if ph.file_size() > ph.mem_size() {
+            return Err("Segment file size exceeds its memory size");
+        }
+        let file_end = ph.offset().checked_add(ph.file_size());
+        if file_end.is_none_or(|end| end > elf.input.len() as u64) {
+            return Err("Segment data outside the file");
+        }
+        if RESERVED_REGIONS
+            .iter()
+            .any(|region| overlaps(region, &range))
+        {
+            return Err("Segment overlaps a reserved user region");
+        }
+        if segments.iter().any(|other| overlaps(other, &range)) {
+            return Err("Segments overlap");
         }
 
-        let frame = frame_allocator
-            .allocate_frame()
-            .expect("Failed to allocate frame for heap");
+        entry_ok |= ph.flags().is_execute() && range.contains(&entry);
+        segments.push(range);
+    }
 
-        unsafe {
-            mapper
-                .map_to(page, frame, pool_flags, frame_allocator) // Re-use pool flags (RW, USER)
-                .expect("Failed to map heap page")
-                .flush();
-            let frame_ptr = (frame.start_address().as_u64() + hhdm) as *mut u8;
-            core::ptr::write_bytes(frame_ptr, 0, 4096);
-        }
+    if segments.is_empty() {
+        return Err("No loadable segments");
+    }
+    if !entry_ok {
+        return Err("Entry point outside the executable segments");
     }
-    println!("[ELF] Heap pre-mapped: {:#x} - {:#x}", HEAP_START, HEAP_END);
 
-    // Process relocations for PIE executable
-    // We intentionally skip kernel-side relocations effectively letting musl handle it
-    // itself using the information provided in the Auxiliary Vector (AT_PHDR).
-    // If we were to apply relocations here, we would get double-relocation because
-    // musl also applies them by adding the base address.
-    if is_pie {
-        // process_relocations(mapper, &elf, base_addr);
-        println!("[ELF] Skipping kernel relocations - expecting user runtime self-relocation");
+    // Runtime data the program finds through its program headers
+    for ph in elf.program_iter() {
+        let runtime = matches!(
+            ph.get_type(),
+            Ok(Type::Tls) | Ok(Type::Dynamic) | Ok(Type::GnuRelro)
+        );
+        if runtime && ph.mem_size() != 0 {
+            let range = user_range(base_addr, ph.virtual_addr(), ph.mem_size())?;
+            if !within_segments(&segments, range.start, range.end - range.start) {
+                return Err("TLS, DYNAMIC or RELRO segment outside the loaded segments");
+            }
+        }
     }
 
-    // Calculate the actual entry point
-    let entry_offset = elf.header.pt2.entry_point();
-    let entry_point = VirtAddr::new(base_addr + entry_offset);
+    Ok(segments)
+}
 
-    println!("[ELF] Entry point at {:#x}", entry_point.as_u64());
+/// The relocated range `base + vaddr .. + size`, if it lies entirely in user space
+fn user_range(base_addr: u64, vaddr: u64, size: u64) -> Result<Range<u64>, &'static str> {
+    let start = base_addr
+        .checked_add(vaddr)
+        .ok_or("Segment address out of range")?;
+    let end = start
+        .checked_add(size)
+        .ok_or("Segment address out of range")?;
+    if start < USER_MIN_ADDR || end > USER_MAX_ADDR {
+        return Err("Segment outside user space");
+    }
+    Ok(start..end)
+}
 
-    entry_point
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// Whether `len` bytes at `addr` lie inside a single loaded segment
+fn within_segments(segments: &[Range<u64>], addr: u64, len: u64) -> bool {
+    addr.checked_add(len).is_some_and(|end| {
+        segments
+            .iter()
+            .any(|segment| segment.start <= addr && end <= segment.end)
+    })
 }
 
 /// Load a single program segment into memory
@@ -169,7 +264,7 @@ fn load_segment(
     elf: &ElfFile,
     ph: &ProgramHeader,
     base_addr: u64,
-) {
+) -> Result<(), &'static str> {
     // Get segment information
     let segment_vaddr = base_addr + ph.virtual_addr(); // Relocated virtual address
     let segment_memsz = ph.mem_size();
@@ -179,12 +274,14 @@ fn load_segment(
 
     // Skip empty segments
     if segment_memsz == 0 {
-        return;
+        return Ok(());
     }
 
-    println!(
-        "[ELF] Loading segment: vaddr={:#x}, memsz={:#x}, filesz={:#x}",
-        segment_vaddr, segment_memsz, segment_filesz
+    log::debug!(
+        "Loading segment: vaddr={:#x}, memsz={:#x}, filesz={:#x}",
+        segment_vaddr,
+        segment_memsz,
+        segment_filesz
     );
 
     // Calculate page-aligned boundaries
@@ -205,6 +302,28 @@ fn load_segment(
         page_flags |= PageTableFlags::NO_EXECUTE;
     }
 
+    // The segment's pages form an area, less a first page shared with the
+    // previous segment (it keeps that one's protection, as its PTE does)
+    let mut prot = 0;
+    if flags.is_read() {
+        prot |= PROT_READ;
+    }
+    if flags.is_write() {
+        prot |= PROT_WRITE;
+    }
+    if flags.is_execute() {
+        prot |= PROT_EXEC;
+    }
+    let mut area_start = start_page.start_address().as_u64();
+    if crate::vma::find(area_start).is_some() {
+        area_start += 4096;
+    }
+    let area_end = end_page.start_address().as_u64() + 4096;
+    if area_start < area_end {
+        crate::vma::map(Vma::new(area_start..area_end, prot, VmaKind::File))
+            .map_err(|_| "Segment over memory the kernel uses")?;
+    }
+
     // Get HHDM offset for physical-to-virtual translation
     let hhdm = get_hhdm_offset();
 
@@ -214,8 +333,8 @@ fn load_segment(
         if mapper.translate_page(page).is_ok() {
             // Page already mapped (could be a segment that overlaps previous one)
             // This can happen with segments that share a page
-            println!(
-                "[ELF]   Page {:#x} already mapped, skipping",
+            log::debug!(
+                "Page {:#x} already mapped, skipping",
                 page.start_address().as_u64()
             );
             continue;
@@ -226,8 +345,8 @@ fn load_segment(
             .allocate_frame()
             .expect("Failed to allocate frame for ELF segment");
 
-        println!(
-            "[ELF]   Mapping page {:#x} -> frame {:#x}, flags: {:?}",
+        log::debug!(
+            "Mapping page {:#x} -> frame {:#x}, flags: {:?}",
             page.start_address().as_u64(),
             frame.start_address().as_u64(),
             page_flags
@@ -279,10 +398,76 @@ fn load_segment(
             current_vaddr += bytes_in_page;
         }
     }
+
+    Ok(())
+}
+
+/// Copy the PT_TLS segment out of the ELF image
+fn tls_template(elf: &ElfFile, ph: &ProgramHeader) -> Result<TlsTemplate, &'static str> {
+    let offset = ph.offset() as usize;
+    let file_size = ph.file_size() as usize;
+    let image = elf
+        .input
+        .get(offset..offset + file_size)
+        .ok_or("PT_TLS outside the file")?;
+
+    // Alignment 0 and 1 both mean unaligned; the TP is at least 16-byte aligned
+    let align = ph.align().max(16);
+    if !align.is_power_of_two() || align > 4096 {
+        return Err("Unsupported PT_TLS alignment");
+    }
+    if ph.mem_size() < ph.file_size()
+        || ph.mem_size().next_multiple_of(align) + TCB_SIZE > TLS_SLOT_SIZE
+    {
+        return Err("PT_TLS segment too large");
+    }
+
+    log::debug!(
+        "TLS: filesz={:#x}, memsz={:#x}, align={:#x}",
+        file_size,
+        ph.mem_size(),
+        align
+    );
+    Ok(TlsTemplate {
+        image: Vec::from(image),
+        mem_size: ph.mem_size(),
+        align,
+    })
+}
+
+/// Whole pages covered by PT_GNU_RELRO, as ld.so computes them: the start is
+/// rounded down and the end too, so the page holding the following RW data
+/// stays writable (linkers align the end of RELRO to a page boundary)
+fn relro_pages(elf: &ElfFile, base_addr: u64) -> Option<Range<u64>> {
+    let ph = elf
+        .program_iter()
+        .find(|ph| matches!(ph.get_type(), Ok(Type::GnuRelro)))?;
+    let start = (base_addr + ph.virtual_addr()) & !0xFFF;
+    let end = (base_addr + ph.virtual_addr() + ph.mem_size()) & !0xFFF;
+    (start < end).then_some(start..end)
+}
+
+/// Make the RELRO pages of the running program read-only, once
+/// Called on every syscall: the first one comes after user-mode relocation
+pub fn apply_pending_relro() {
+    let Some(range) = PENDING_RELRO.lock().take() else {
+        return;
+    };
+
+    if let Err(err) = crate::vma::protect(range.clone(), PROT_READ) {
+        log::warn!("RELRO {:#x}..{:#x}: {:?}", range.start, range.end, err);
+        return;
+    }
+    log::debug!("RELRO {:#x}..{:#x} now read-only", range.start, range.end);
 }
 
 /// Process ELF relocations for PIE executables
-fn process_relocations(mapper: &mut OffsetPageTable<'static>, elf: &ElfFile, base_addr: u64) {
+fn process_relocations(
+    mapper: &mut OffsetPageTable<'static>,
+    elf: &ElfFile,
+    base_addr: u64,
+    segments: &[Range<u64>],
+) {
     use xmas_elf::sections::SectionData;
 
     let hhdm = get_hhdm_offset();
@@ -295,7 +480,7 @@ fn process_relocations(mapper: &mut OffsetPageTable<'static>, elf: &ElfFile, bas
         let section_name = section.get_name(elf).unwrap_or("<unknown>");
 
         if let Ok(SectionData::Rela64(rela_entries)) = section.get_data(elf) {
-            println!("[ELF] Processing relocation section: {}", section_name);
+            log::debug!("Processing relocation section: {}", section_name);
 
             for rela in rela_entries {
                 let r_type = rela.get_type();
@@ -306,8 +491,15 @@ fn process_relocations(mapper: &mut OffsetPageTable<'static>, elf: &ElfFile, bas
 
                 // R_X86_64_RELATIVE = 8
                 if r_type == 8 {
-                    // Calculate the relocated address
-                    let target_vaddr = base_addr + r_offset;
+                    // Calculate the relocated address, which must be program memory
+                    let target_vaddr = base_addr.wrapping_add(r_offset);
+                    if !within_segments(segments, target_vaddr, 8) {
+                        if skipped_relocs < 5 {
+                            log::warn!("Reloc target {:#x} outside the program", target_vaddr);
+                        }
+                        skipped_relocs += 1;
+                        continue;
+                    }
                     // r_addend is i64, base_addr is u64
                     // The relocation value is: base_addr + addend
                     let value = base_addr.wrapping_add(r_addend as u64);
@@ -326,9 +518,11 @@ fn process_relocations(mapper: &mut OffsetPageTable<'static>, elf: &ElfFile, bas
                     } else {
                         // Page not mapped! This is a problem
                         if skipped_relocs < 5 {
-                            println!(
-                                "[ELF] WARNING: Reloc target {:#x} not mapped! (offset={:#x}, addend={:#x})",
-                                target_vaddr, r_offset, r_addend
+                            log::warn!(
+                                "Reloc target {:#x} not mapped! (offset={:#x}, addend={:#x})",
+                                target_vaddr,
+                                r_offset,
+                                r_addend
                             );
                         }
                         skipped_relocs += 1;
@@ -336,9 +530,10 @@ fn process_relocations(mapper: &mut OffsetPageTable<'static>, elf: &ElfFile, bas
                 } else {
                     // Non-RELATIVE relocation - might need handling
                     if total_relocs <= 5 {
-                        println!(
-                            "[ELF] Non-RELATIVE reloc type {} at offset {:#x}",
-                            r_type, r_offset
+                        log::warn!(
+                            "Non-RELATIVE reloc type {} at offset {:#x}",
+                            r_type,
+                            r_offset
                         );
                     }
                 }
@@ -346,9 +541,11 @@ fn process_relocations(mapper: &mut OffsetPageTable<'static>, elf: &ElfFile, bas
         }
     }
 
-    println!(
-        "[ELF] Relocations: {} total, {} applied, {} skipped",
-        total_relocs, applied_relocs, skipped_relocs
+    log::debug!(
+        "Relocations: {} total, {} applied, {} skipped",
+        total_relocs,
+        applied_relocs,
+        skipped_relocs
     );
 }
 
@@ -366,9 +563,10 @@ fn relocate_dynamic_segment(mapper: &mut OffsetPageTable<'static>, elf: &ElfFile
             let dyn_vaddr = base_addr + ph.virtual_addr();
             let dyn_size = ph.mem_size() as usize;
 
-            println!(
-                "[ELF] Relocating DYNAMIC segment at {:#x}, size {}",
-                dyn_vaddr, dyn_size
+            log::debug!(
+                "Relocating DYNAMIC segment at {:#x}, size {}",
+                dyn_vaddr,
+                dyn_size
             );
 
             // Each dynamic entry is 16 bytes: d_tag (8) + d_val (8)
@@ -430,13 +628,14 @@ fn relocate_dynamic_segment(mapper: &mut OffsetPageTable<'static>, elf: &ElfFile
                 }
             }
 
-            println!("[ELF] DYNAMIC: {} entries relocated", relocated_count);
+            log::debug!("DYNAMIC: {} entries relocated", relocated_count);
             break; // Only one DYNAMIC segment
         }
     }
 }
 
-/// Setup the user stack with proper auxiliary vector
+/// Setup the user stack with proper auxiliary vector for the program `data`
+/// (just loaded by load_elf)
 /// Returns the stack pointer (top of stack)
 ///
 /// Stack layout (growing down, addresses decrease):
@@ -455,9 +654,9 @@ fn relocate_dynamic_segment(mapper: &mut OffsetPageTable<'static>, elf: &ElfFile
 /// (low address)
 /// ```
 pub fn setup_user_stack(
+    data: &[u8],
     mapper: &mut OffsetPageTable<'static>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-    _hhdm_offset: u64,
 ) -> VirtAddr {
     let hhdm = get_hhdm_offset();
 
@@ -468,6 +667,15 @@ pub fn setup_user_stack(
     let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(stack_start));
     let end_page = Page::<Size4KiB>::containing_address(VirtAddr::new(stack_end - 1));
 
+    // Mapped up front, the arguments go there
+    if let Err(err) = crate::vma::map(Vma::new(
+        stack_start..stack_end,
+        PROT_READ | PROT_WRITE,
+        VmaKind::Stack,
+    )) {
+        log::warn!("User stack: {:?}", err);
+    }
+
     // Stack is readable, writable, not executable
     let flags = PageTableFlags::PRESENT
         | PageTableFlags::USER_ACCESSIBLE
@@ -494,14 +702,14 @@ pub fn setup_user_stack(
         }
     }
 
-    // Parse ELF to get phdr info
-    let elf = xmas_elf::ElfFile::new(USER_ELF_BYTES).expect("Failed to parse ELF");
+    // Parse ELF to get phdr info (already validated by load_elf)
+    let elf = xmas_elf::ElfFile::new(data).expect("Failed to parse ELF");
     let phdr_offset = elf.header.pt2.ph_offset();
     let phnum = elf.header.pt2.ph_count() as u64;
     let phent = elf.header.pt2.ph_entry_size() as u64;
     let entry = elf.header.pt2.entry_point();
 
-    // Determine base address (same logic as load_user_elf)
+    // Determine base address (same logic as load_elf)
     let is_pie = elf.header.pt2.type_().as_type() == xmas_elf::header::Type::SharedObject;
     let base_addr = if is_pie { USER_BASE_ADDR } else { 0 };
 
@@ -600,14 +808,105 @@ pub fn setup_user_stack(
     write_to_stack(mapper, sp + offset, AT_NULL);
     write_to_stack(mapper, sp + offset + 8, 0);
 
-    println!(
-        "[STACK] User stack at {:#x}, AT_PHDR={:#x}, AT_ENTRY={:#x}",
-        sp, phdr_addr, entry_addr
+    log::debug!(
+        "User stack at {:#x}, AT_PHDR={:#x}, AT_ENTRY={:#x}",
+        sp,
+        phdr_addr,
+        entry_addr
     );
 
     VirtAddr::new(sp)
 }
 
+/// Allocate and initialize the TLS block of thread `thread` of the loaded program
+/// Returns the thread pointer to load into FS, None if the program has no PT_TLS
+///
+/// x86-64 uses TLS variant II: the block ends at the TP, which points at the thread
+/// control block whose first word is the TP itself (`mov %fs:0, %rax`).
+/// ```
+/// | .tdata | .tbss | padding | TCB (self pointer, zeroes) |
+/// ^ slot base, TP - align_up(memsz, align)  ^ TP
+/// ```
+pub fn setup_tls(
+    thread: usize,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<Option<VirtAddr>, &'static str> {
+    let template = TLS_TEMPLATE.lock();
+    let Some(template) = template.as_ref() else {
+        return Ok(None);
+    };
+    if thread >= TLS_MAX_THREADS {
+        return Err("Too many threads");
+    }
+
+    // The slot is page aligned, so the TP is aligned as the block requires
+    let block = USER_TLS_BASE + thread as u64 * TLS_SLOT_SIZE;
+    let tp = block + template.mem_size.next_multiple_of(template.align);
+
+    // Map the block and the TCB zeroed, reusing pages of a previous thread in
+    // the slot
+    let hhdm = get_hhdm_offset();
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE;
+    let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(block));
+    let end_page = Page::<Size4KiB>::containing_address(VirtAddr::new(tp + TCB_SIZE - 1));
+    let area = start_page.start_address().as_u64()..end_page.start_address().as_u64() + 4096;
+    // Already an area when a thread reuses the slot
+    if crate::vma::find(area.start).is_none() {
+        crate::vma::map(Vma::new(area, PROT_READ | PROT_WRITE, VmaKind::Anonymous))
+            .map_err(|_| "Bad TLS slot")?;
+    }
+    for page in Page::range_inclusive(start_page, end_page) {
+        let frame = match mapper.translate_page(page) {
+            Ok(frame) => frame,
+            Err(_) => {
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or("Out of memory for TLS")?;
+                unsafe {
+                    mapper
+                        .map_to(page, frame, flags, frame_allocator)
+                        .map_err(|_| "Failed to map TLS page")?
+                        .flush();
+                }
+                frame
+            }
+        };
+        unsafe {
+            core::ptr::write_bytes((frame.start_address().as_u64() + hhdm) as *mut u8, 0, 4096);
+        }
+    }
+
+    // .tdata, then the TCB self pointer
+    write_user_bytes(mapper, block, &template.image);
+    write_user_bytes(mapper, tp, &tp.to_ne_bytes());
+
+    log::debug!("TLS for thread {} at {:#x}, TP={:#x}", thread, block, tp);
+    Ok(Some(VirtAddr::new(tp)))
+}
+
+/// Copy `bytes` to mapped user memory at `addr`, through the HHDM
+fn write_user_bytes(mapper: &OffsetPageTable<'static>, addr: u64, bytes: &[u8]) {
+    let hhdm = get_hhdm_offset();
+    let mut copied = 0;
+    while copied < bytes.len() {
+        let vaddr = addr + copied as u64;
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(vaddr));
+        let page_offset = vaddr % 4096;
+        let count = core::cmp::min(4096 - page_offset as usize, bytes.len() - copied);
+
+        let frame = mapper.translate_page(page).expect("Page should be mapped");
+        let dest = (frame.start_address().as_u64() + hhdm + page_offset) as *mut u8;
+        unsafe {
+            core::ptr::copy_nonoverlapping(bytes[copied..].as_ptr(), dest, count);
+        }
+        copied += count;
+    }
+}
+
 /// Enter user mode and jump to the entry point
 /// This function never returns
 #[inline(never)]
@@ -617,10 +916,7 @@ pub unsafe fn enter_userspace(entry_point: VirtAddr, stack_pointer: VirtAddr) ->
     // User data segment at index 3, RPL 3 = (3 << 3) | 3 = 0x1B
     // User code segment at index 4, RPL 3 = (4 << 3) | 3 = 0x23
 
-    println!(
-        "[USERSPACE] Jumping to Ring 3 at {:#x}",
-        entry_point.as_u64()
-    );
+    log::info!("Jumping to Ring 3 at {:#x}", entry_point.as_u64());
 
     // Use iretq to enter user mode
     // Stack must be set up as: SS, RSP, RFLAGS, CS, RIP (in that order, pushed)
@@ -633,12 +929,16 @@ pub unsafe fn enter_userspace(entry_point: VirtAddr, stack_pointer: VirtAddr) ->
     let entry = entry_point.as_u64();
     let stack = stack_pointer.as_u64();
 
-    println!("[DEBUG] Entry: {:#x}, Stack: {:#x}", entry, stack);
-    println!(
-        "[DEBUG] USER_CODE_SEL: {:#x}, USER_DATA_SEL: {:#x}",
-        USER_CODE_SEL, USER_DATA_SEL
+    log::debug!("Entry: {:#x}, Stack: {:#x}", entry, stack);
+    log::debug!(
+        "USER_CODE_SEL: {:#x}, USER_DATA_SEL: {:#x}",
+        USER_CODE_SEL,
+        USER_DATA_SEL
     );
 
+    // The next SYSCALL must find this CPU's block behind SWAPGS
+    crate::syscalls::prepare_user_gs(VirtAddr::zero());
+
     unsafe {
         core::arch::asm!(
             // Build iretq stack frame:
@@ -692,3 +992,73 @@ pub unsafe fn enter_userspace(entry_point: VirtAddr, stack_pointer: VirtAddr) ->
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ELF64 executable with one LOAD segment per (vaddr, memsz, flags) and no data
+    fn elf_image(entry: u64, segments: &[(u64, u64, u32)]) -> Vec<u8> {
+        let mut image = Vec::new();
+        image.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        image.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+        image.extend_from_slice(&0x3Eu16.to_le_bytes()); // x86-64
+        image.extend_from_slice(&1u32.to_le_bytes());
+        image.extend_from_slice(&entry.to_le_bytes());
+        image.extend_from_slice(&64u64.to_le_bytes()); // program headers follow
+        image.extend_from_slice(&0u64.to_le_bytes());
+        image.extend_from_slice(&0u32.to_le_bytes());
+        for half in [64u16, 56, segments.len() as u16, 64, 0, 0] {
+            image.extend_from_slice(&half.to_le_bytes());
+        }
+        for &(vaddr, mem_size, flags) in segments {
+            image.extend_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+            image.extend_from_slice(&flags.to_le_bytes());
+            for word in [0, vaddr, vaddr, 0, mem_size, 0x1000u64] {
+                image.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        image
+    }
+
+    fn check(entry: u64, segments: &[(u64, u64, u32)]) -> Result<(), &'static str> {
+        let image = elf_image(entry, segments);
+        let elf = ElfFile::new(&image)?;
+        validate_layout(&elf, 0).map(|_| ())
+    }
+
+    const RX: u32 = 5;
+    const RW: u32 = 6;
+
+    #[test_case]
+    fn elf_layout_accepts_user_segments() {
+        assert_eq!(
+            check(0x401000, &[(0x401000, 0x1000, RX), (0x402000, 0x800, RW)]),
+            Ok(())
+        );
+    }
+
+    #[test_case]
+    fn elf_layout_rejects_bad_addresses() {
+        // Kernel half, null page, wrapping around
+        assert!(
+            check(
+                0xFFFF_8000_0000_0000,
+                &[(0xFFFF_8000_0000_0000, 0x1000, RX)]
+            )
+            .is_err()
+        );
+        assert!(check(0x0, &[(0x0, 0x1000, RX)]).is_err());
+        assert!(check(0x401000, &[(0x401000, u64::MAX, RX)]).is_err());
+        // Over the user stack, over each other, entry outside code
+        assert!(
+            check(
+                USER_STACK_BOTTOM - 0x1000,
+                &[(USER_STACK_BOTTOM - 0x1000, 0x1000, RX)]
+            )
+            .is_err()
+        );
+        assert!(check(0x401000, &[(0x401000, 0x2000, RX), (0x402000, 0x1000, RW)]).is_err());
+        assert!(check(0x402000, &[(0x401000, 0x1000, RX), (0x402000, 0x1000, RW)]).is_err());
+    }
+}
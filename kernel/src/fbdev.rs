@@ -0,0 +1,239 @@
+// Framebuffer Device
+// /dev/fb0, the screen as a Linux fbdev node: FBIOGET_VSCREENINFO and
+// FBIOGET_FSCREENINFO describe the mode (32 bits per pixel, `line_length` bytes
+// per row), read/write at an offset copy pixels, and mmap(MAP_SHARED) maps the
+// RAM back buffer so a program draws straight into it. The timer flush carries it
+// to the screen (see screen.rs); the console keeps drawing into the same buffer.
+//
+// The mode cannot be changed: FBIOPUT_VSCREENINFO accepts the current one only,
+// and FBIOPAN_DISPLAY only offset 0 (the virtual screen is the visible one).
+
+use crate::fs::{self, FileType, FsError, FsResult, Inode, Metadata};
+use alloc::sync::Arc;
+use shared::framebuffer::{FrameBufferInfo, PixelFormat};
+use x86_64::structures::paging::PhysFrame;
+
+// fbdev ioctls
+const FBIOGET_VSCREENINFO: u64 = 0x4600;
+const FBIOPUT_VSCREENINFO: u64 = 0x4601;
+const FBIOGET_FSCREENINFO: u64 = 0x4602;
+const FBIOPAN_DISPLAY: u64 = 0x4606;
+
+const FB_TYPE_PACKED_PIXELS: u32 = 0;
+const FB_VISUAL_TRUECOLOR: u32 = 2;
+
+const BITS_PER_PIXEL: u32 = 32;
+
+// struct fb_bitfield
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Bitfield {
+    offset: u32,
+    length: u32,
+    msb_right: u32,
+}
+
+// struct fb_var_screeninfo
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct VarScreenInfo {
+    xres: u32,
+    yres: u32,
+    xres_virtual: u32,
+    yres_virtual: u32,
+    xoffset: u32,
+    yoffset: u32,
+    bits_per_pixel: u32,
+    grayscale: u32,
+    red: Bitfield,
+    green: Bitfield,
+    blue: Bitfield,
+    transp: Bitfield,
+    nonstd: u32,
+    activate: u32,
+    height: u32, // mm, unknown
+    width: u32,
+    accel_flags: u32,
+    pixclock: u32,
+    left_margin: u32,
+    right_margin: u32,
+    upper_margin: u32,
+    lower_margin: u32,
+    hsync_len: u32,
+    vsync_len: u32,
+    sync: u32,
+    vmode: u32,
+    rotate: u32,
+    colorspace: u32,
+    reserved: [u32; 4],
+}
+
+// struct fb_fix_screeninfo
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct FixScreenInfo {
+    id: [u8; 16],
+    smem_start: u64,
+    smem_len: u32,
+    fb_type: u32,
+    type_aux: u32,
+    visual: u32,
+    xpanstep: u16,
+    ypanstep: u16,
+    ywrapstep: u16,
+    line_length: u32,
+    mmio_start: u64,
+    mmio_len: u32,
+    accel: u32,
+    capabilities: u16,
+    reserved: [u16; 2],
+}
+
+fn var_info(info: &FrameBufferInfo) -> VarScreenInfo {
+    let channel = |offset| Bitfield {
+        offset,
+        length: 8,
+        msb_right: 0,
+    };
+    // Byte 0 of a pixel is red for RGB, blue otherwise (see screen::convert_color)
+    let (red, blue) = match info.format {
+        PixelFormat::RGB => (0, 16),
+        _ => (16, 0),
+    };
+    VarScreenInfo {
+        xres: info.width as u32,
+        yres: info.height as u32,
+        xres_virtual: info.width as u32,
+        yres_virtual: info.height as u32,
+        bits_per_pixel: BITS_PER_PIXEL,
+        red: channel(red),
+        green: channel(8),
+        blue: channel(blue),
+        ..VarScreenInfo::default()
+    }
+}
+
+fn fix_info(info: &FrameBufferInfo) -> FixScreenInfo {
+    let mut id = [0u8; 16];
+    id[..5].copy_from_slice(b"efifb");
+    FixScreenInfo {
+        id,
+        smem_start: info.buffer_phys,
+        smem_len: crate::screen::size() as u32,
+        fb_type: FB_TYPE_PACKED_PIXELS,
+        visual: FB_VISUAL_TRUECOLOR,
+        line_length: info.stride as u32 * 4,
+        ..FixScreenInfo::default()
+    }
+}
+
+// Copy a structure from / to the user address of an ioctl
+fn read_arg<T: Copy>(arg: u64) -> FsResult<T> {
+    if arg == 0 {
+        return Err(FsError::BadAddress);
+    }
+    Ok(unsafe { (arg as *const T).read_unaligned() })
+}
+
+fn write_arg<T>(arg: u64, value: T) -> FsResult<i64> {
+    if arg == 0 {
+        return Err(FsError::BadAddress);
+    }
+    unsafe { (arg as *mut T).write_unaligned(value) };
+    Ok(0)
+}
+
+struct FrameBufferDevice;
+
+impl Inode for FrameBufferDevice {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino: 0,
+            file_type: FileType::CharDevice,
+            size: crate::screen::size() as u64,
+            mode: 0o660,
+            nlink: 1,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        Ok(crate::screen::read_pixels(offset as usize, buf))
+    }
+
+    // Nothing can be written past the end of the screen
+    fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<usize> {
+        match crate::screen::write_pixels(offset as usize, buf) {
+            0 if !buf.is_empty() => Err(FsError::InvalidArgument),
+            count => Ok(count),
+        }
+    }
+
+    // O_TRUNC on a device is ignored
+    fn truncate(&self, _size: u64) -> FsResult<()> {
+        Ok(())
+    }
+
+    fn ioctl(&self, request: u64, arg: u64) -> FsResult<i64> {
+        let info = crate::screen::info().ok_or(FsError::NoDevice)?;
+        match request {
+            FBIOGET_VSCREENINFO => write_arg(arg, var_info(&info)),
+            FBIOGET_FSCREENINFO => write_arg(arg, fix_info(&info)),
+            FBIOPUT_VSCREENINFO => {
+                let wanted: VarScreenInfo = read_arg(arg)?;
+                let current = var_info(&info);
+                let same = (wanted.xres, wanted.yres, wanted.bits_per_pixel)
+                    == (current.xres, current.yres, current.bits_per_pixel);
+                if !same || wanted.xoffset != 0 || wanted.yoffset != 0 {
+                    return Err(FsError::InvalidArgument);
+                }
+                write_arg(arg, current)
+            }
+            FBIOPAN_DISPLAY => {
+                let pan: VarScreenInfo = read_arg(arg)?;
+                if pan.xoffset != 0 || pan.yoffset != 0 {
+                    return Err(FsError::InvalidArgument);
+                }
+                Ok(0)
+            }
+            _ => Err(FsError::NotATty),
+        }
+    }
+
+    fn mmap_frame(&self, index: u64) -> Option<PhysFrame> {
+        crate::screen::map_back_buffer_page(index)
+    }
+}
+
+// Register /dev/fb0 if there is a screen
+pub fn init() {
+    if crate::screen::info().is_some() {
+        fs::devfs::register("fb0", Arc::new(FrameBufferDevice));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn fbdev_structure_layout() {
+        // The sizes of the Linux structures on x86_64
+        assert_eq!(core::mem::size_of::<VarScreenInfo>(), 160);
+        assert_eq!(core::mem::size_of::<FixScreenInfo>(), 80);
+        assert_eq!(core::mem::offset_of!(FixScreenInfo, line_length), 48);
+
+        let info = FrameBufferInfo {
+            buffer_base: 0,
+            buffer_phys: 0x8000_0000,
+            buffer_size: 800 * 600 * 4,
+            width: 800,
+            height: 600,
+            stride: 800,
+            format: PixelFormat::BGR,
+        };
+        let var = var_info(&info);
+        assert_eq!((var.xres, var.yres, var.bits_per_pixel), (800, 600, 32));
+        assert_eq!((var.red.offset, var.blue.offset), (16, 0));
+        assert_eq!(fix_info(&info).line_length, 3200);
+    }
+}
@@ -0,0 +1,350 @@
+// PSF Bitmap Fonts
+// Parser for PC Screen Font files, the format of the Linux console fonts. PSF2 is
+// the main format, the older PSF1 (8 pixels wide, 256/512 glyphs) is accepted too.
+// Two Spleen fonts (BSD-2-Clause, by Frederic Cambus) are built in: 8x16 for normal
+// resolutions and 16x32 for high resolutions. A font.psf loaded by the bootloader
+// replaces them.
+//
+// PSF2 layout:
+//   header (32 bytes): magic, version, header size, flags, glyph count,
+//                      bytes per glyph, height, width
+//   glyphs:            `height` rows of ceil(width / 8) bytes, MSB = leftmost pixel
+//   unicode table:     (flag 1) per glyph, the UTF-8 characters it represents,
+//                      0xFE starts a combining sequence, 0xFF ends the glyph's entry
+// PSF1 layout:
+//   header (4 bytes):  magic, mode (1 = 512 glyphs, 2/4 = unicode table), glyph height
+//   glyphs:            `height` bytes each
+//   unicode table:     as PSF2 but in UCS-2 words, 0xFFFE / 0xFFFF as separators
+//
+// Parsing allocates nothing: the console font is needed before the heap exists.
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xB5, 0x4A, 0x86];
+const PSF2_HEADER_LEN: usize = 32;
+const PSF2_HAS_UNICODE_TABLE: u32 = 1;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_HEADER_LEN: usize = 4;
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_MODE_HAS_TABLE: u8 = 0x02 | 0x04;
+const PSF1_SEQ_START: u16 = 0xFFFE;
+const PSF1_ENTRY_END: u16 = 0xFFFF;
+
+const UNICODE_SEQ_START: u8 = 0xFE;
+const UNICODE_ENTRY_END: u8 = 0xFF;
+
+// Latin-1 (below DIRECT_CHARS) and the box drawing / block elements range are
+// looked up in direct tables, other characters by scanning the unicode table
+const DIRECT_CHARS: usize = 256;
+const BOX_DRAWING_START: usize = 0x2500;
+const BOX_DRAWING_LEN: usize = 0xA0;
+const NO_GLYPH: u16 = u16::MAX;
+
+// Drawn for characters the font has no glyph for
+const REPLACEMENT_CHARS: [char; 2] = ['\u{FFFD}', '?'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    BadMagic,
+    Truncated,
+    InvalidSize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontSize {
+    Normal, // 8x16
+    Large,  // 16x32
+}
+
+static SPLEEN_8X16: &[u8] = spleen_font::FONT_8X16;
+static SPLEEN_16X32: &[u8] = spleen_font::FONT_16X32;
+
+// Encoding of the unicode table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TableFormat {
+    Utf8, // PSF2
+    Ucs2, // PSF1
+}
+
+pub struct PsfFont {
+    data: &'static [u8],
+    glyphs_offset: usize,
+    glyph_count: usize,
+    glyph_size: usize,
+    pub width: usize,
+    pub height: usize,
+    // Unicode table (empty without one: glyph index = character)
+    unicode: &'static [u8],
+    table_format: TableFormat,
+    direct: [u16; DIRECT_CHARS],
+    box_drawing: [u16; BOX_DRAWING_LEN],
+    replacement: u16,
+}
+
+enum Entry {
+    Char(char),
+    SequenceStart,
+    End,
+    Invalid,
+}
+
+fn next_utf8_entry(rest: &mut &'static [u8]) -> Entry {
+    let byte = rest[0];
+    let len = match byte {
+        UNICODE_ENTRY_END | UNICODE_SEQ_START | 0x00..=0x7F => 1,
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        _ => 4,
+    };
+    let (encoded, tail) = rest.split_at(len.min(rest.len()));
+    *rest = tail;
+
+    match byte {
+        UNICODE_ENTRY_END => Entry::End,
+        UNICODE_SEQ_START => Entry::SequenceStart,
+        _ => match core::str::from_utf8(encoded)
+            .ok()
+            .and_then(|s| s.chars().next())
+        {
+            Some(c) => Entry::Char(c),
+            None => Entry::Invalid,
+        },
+    }
+}
+
+fn next_ucs2_entry(rest: &mut &'static [u8]) -> Entry {
+    if rest.len() < 2 {
+        *rest = &[];
+        return Entry::Invalid;
+    }
+    let word = u16::from_le_bytes([rest[0], rest[1]]);
+    *rest = &rest[2..];
+
+    match word {
+        PSF1_ENTRY_END => Entry::End,
+        PSF1_SEQ_START => Entry::SequenceStart,
+        _ => char::from_u32(word as u32).map_or(Entry::Invalid, Entry::Char),
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+impl PsfFont {
+    pub fn parse(data: &'static [u8]) -> Result<Self, FontError> {
+        if data.len() >= PSF1_HEADER_LEN && data[0..2] == PSF1_MAGIC {
+            return Self::parse_psf1(data);
+        }
+        if data.len() < PSF2_HEADER_LEN {
+            return Err(FontError::Truncated);
+        }
+        if data[0..4] != PSF2_MAGIC {
+            return Err(FontError::BadMagic);
+        }
+
+        let header_size = read_u32(data, 8) as usize;
+        let flags = read_u32(data, 12);
+        let glyph_count = read_u32(data, 16) as usize;
+        let glyph_size = read_u32(data, 20) as usize;
+        let height = read_u32(data, 24) as usize;
+        let width = read_u32(data, 28) as usize;
+
+        if width == 0 || height == 0 || glyph_count == 0 || glyph_size < height * width.div_ceil(8)
+        {
+            return Err(FontError::InvalidSize);
+        }
+        let glyphs_end = glyph_count
+            .checked_mul(glyph_size)
+            .and_then(|len| len.checked_add(header_size))
+            .ok_or(FontError::InvalidSize)?;
+        if header_size < PSF2_HEADER_LEN || glyphs_end > data.len() {
+            return Err(FontError::Truncated);
+        }
+
+        let unicode = if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+            &data[glyphs_end..]
+        } else {
+            &[]
+        };
+
+        let mut font = Self {
+            data,
+            glyphs_offset: header_size,
+            glyph_count,
+            glyph_size,
+            width,
+            height,
+            unicode,
+            table_format: TableFormat::Utf8,
+            direct: [NO_GLYPH; DIRECT_CHARS],
+            box_drawing: [NO_GLYPH; BOX_DRAWING_LEN],
+            replacement: NO_GLYPH,
+        };
+        font.build_direct_table();
+        Ok(font)
+    }
+
+    fn parse_psf1(data: &'static [u8]) -> Result<Self, FontError> {
+        let mode = data[2];
+        let height = data[3] as usize;
+        let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        if height == 0 {
+            return Err(FontError::InvalidSize);
+        }
+
+        let glyphs_end = PSF1_HEADER_LEN + glyph_count * height;
+        if glyphs_end > data.len() {
+            return Err(FontError::Truncated);
+        }
+        let unicode = if mode & PSF1_MODE_HAS_TABLE != 0 {
+            &data[glyphs_end..]
+        } else {
+            &[]
+        };
+
+        let mut font = Self {
+            data,
+            glyphs_offset: PSF1_HEADER_LEN,
+            glyph_count,
+            glyph_size: height,
+            width: 8,
+            height,
+            unicode,
+            table_format: TableFormat::Ucs2,
+            direct: [NO_GLYPH; DIRECT_CHARS],
+            box_drawing: [NO_GLYPH; BOX_DRAWING_LEN],
+            replacement: NO_GLYPH,
+        };
+        font.build_direct_table();
+        Ok(font)
+    }
+
+    pub fn builtin(size: FontSize) -> Self {
+        let data = match size {
+            FontSize::Normal => SPLEEN_8X16,
+            FontSize::Large => SPLEEN_16X32,
+        };
+        Self::parse(data).expect("Built-in font is invalid")
+    }
+
+    fn build_direct_table(&mut self) {
+        if self.unicode.is_empty() {
+            for (c, slot) in self.direct.iter_mut().enumerate() {
+                if c < self.glyph_count {
+                    *slot = c as u16;
+                }
+            }
+        } else {
+            let mut direct = [NO_GLYPH; DIRECT_CHARS];
+            let mut box_drawing = [NO_GLYPH; BOX_DRAWING_LEN];
+            self.for_each_mapping(|c, glyph| {
+                let c = c as usize;
+                let slot = if c < DIRECT_CHARS {
+                    &mut direct[c]
+                } else if (BOX_DRAWING_START..BOX_DRAWING_START + BOX_DRAWING_LEN).contains(&c) {
+                    &mut box_drawing[c - BOX_DRAWING_START]
+                } else {
+                    return true;
+                };
+                if *slot == NO_GLYPH {
+                    *slot = glyph as u16;
+                }
+                true
+            });
+            self.direct = direct;
+            self.box_drawing = box_drawing;
+        }
+
+        self.replacement = REPLACEMENT_CHARS
+            .iter()
+            .find_map(|&c| self.glyph_index(c))
+            .map_or(NO_GLYPH, |index| index as u16);
+    }
+
+    // Call `f(char, glyph index)` for every single-character mapping of the unicode
+    // table until it returns false
+    fn for_each_mapping(&self, mut f: impl FnMut(char, usize) -> bool) {
+        let mut glyph = 0;
+        let mut in_sequence = false;
+        let mut rest = self.unicode;
+
+        while !rest.is_empty() && glyph < self.glyph_count {
+            let entry = match self.table_format {
+                TableFormat::Utf8 => next_utf8_entry(&mut rest),
+                TableFormat::Ucs2 => next_ucs2_entry(&mut rest),
+            };
+
+            match entry {
+                Entry::End => {
+                    glyph += 1;
+                    in_sequence = false;
+                }
+                Entry::SequenceStart => in_sequence = true,
+                // Combining sequences need several characters, skip them
+                Entry::Char(c) => {
+                    if !in_sequence && !f(c, glyph) {
+                        return;
+                    }
+                }
+                Entry::Invalid => {}
+            }
+        }
+    }
+
+    fn glyph_index(&self, c: char) -> Option<usize> {
+        let code = c as usize;
+        let direct = if code < DIRECT_CHARS {
+            Some(self.direct[code])
+        } else if !self.unicode.is_empty()
+            && (BOX_DRAWING_START..BOX_DRAWING_START + BOX_DRAWING_LEN).contains(&code)
+        {
+            Some(self.box_drawing[code - BOX_DRAWING_START])
+        } else {
+            None
+        };
+        if let Some(index) = direct {
+            return (index != NO_GLYPH).then_some(index as usize);
+        }
+
+        if self.unicode.is_empty() {
+            return (code < self.glyph_count).then_some(code);
+        }
+
+        let mut found = None;
+        self.for_each_mapping(|mapped, glyph| {
+            if mapped == c {
+                found = Some(glyph);
+            }
+            found.is_none()
+        });
+        found
+    }
+
+    fn glyph_at(&self, index: usize) -> &'static [u8] {
+        let start = self.glyphs_offset + index * self.glyph_size;
+        &self.data[start..start + self.glyph_size]
+    }
+
+    // Bitmap of a character: `height` rows of `bytes_per_row()` bytes
+    pub fn glyph(&self, c: char) -> Option<&'static [u8]> {
+        self.glyph_index(c).map(|index| self.glyph_at(index))
+    }
+
+    // Bitmap of a character, or of the replacement character (U+FFFD or '?') if the
+    // font has no glyph for it
+    pub fn glyph_or_replacement(&self, c: char) -> Option<&'static [u8]> {
+        self.glyph(c).or_else(|| match self.replacement {
+            NO_GLYPH => None,
+            index => Some(self.glyph_at(index as usize)),
+        })
+    }
+
+    pub fn bytes_per_row(&self) -> usize {
+        self.width.div_ceil(8)
+    }
+}
@@ -0,0 +1,67 @@
+// FPU / SSE Setup
+// Puts the x87 FPU and SSE in a known state at boot instead of relying on what
+// the firmware left, with the same control bits the bootloader sets
+// (shared::cpu::enable_fpu): x87 present and reporting errors natively, SSE with
+// #XM, and with XSAVE support the x87, SSE and (if present) AVX state components.
+//
+// The kernel target is soft-float, but user programs use SSE freely. Nothing ever
+// sets CR0.TS, so #NM only happens if some CPU state is wrong; the handler in
+// interrupts.rs clears a stray TS and treats anything else as fatal.
+
+use shared::cpu;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+
+// MXCSR reset value: all SIMD exceptions masked, round to nearest
+const MXCSR_DEFAULT: u32 = 0x1F80;
+
+// Set up the FPU of the running CPU, before any code may use x87 or SSE, from
+// the features the bootloader found
+pub fn init(features: u64) {
+    let missing = cpu::REQUIRED & !features;
+    if missing != 0 {
+        panic!("CPU without {}: unsupported", cpu::Names(missing));
+    }
+    cpu::enable_fpu(features);
+
+    // Default x87 control word and MXCSR
+    let mxcsr = MXCSR_DEFAULT;
+    unsafe {
+        core::arch::asm!("fninit", "ldmxcsr [{}]", in(reg) &mxcsr, options(nostack));
+    }
+
+    let xsave = features & cpu::XSAVE != 0;
+    log::info!(
+        "FPU: x87 + SSE enabled, XSAVE {}, AVX {}",
+        if xsave { "on" } else { "off" },
+        if xsave && features & cpu::AVX != 0 {
+            "on"
+        } else {
+            "off"
+        }
+    );
+}
+
+// #NM with CR0.TS set: clear it and let the instruction run again
+// False if TS was clear (the fault has another cause)
+pub fn clear_task_switched() -> bool {
+    if !Cr0::read().contains(Cr0Flags::TASK_SWITCHED) {
+        return false;
+    }
+    unsafe { core::arch::asm!("clts", options(nomem, nostack)) };
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86_64::registers::control::{Cr4, Cr4Flags};
+
+    #[test_case]
+    fn fpu_set_up_for_sse() {
+        let cr0 = Cr0::read();
+        assert!(!cr0.contains(Cr0Flags::EMULATE_COPROCESSOR));
+        assert!(cr0.contains(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR));
+        assert!(Cr4::read().contains(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+        assert!(!clear_task_switched());
+    }
+}
@@ -0,0 +1,153 @@
+// Boot Modules
+// The files the bootloader loaded for the kernel (BootInfo's module list), as a
+// flat read-only directory mounted at /boot: the initramfs image, the console
+// font and every `module=<path>` of boot.cfg, e.g. `insmod /boot/driver.ko`.
+// Contents are not copied, the inodes point into the LOADER_DATA pages the
+// bootloader read them into, which the PMM never hands out.
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsResult, Inode, InodeRef, Metadata};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use shared::BootModule;
+
+const ROOT_INO: u64 = 1;
+
+pub struct BootFile {
+    ino: u64,
+    name: String,
+    data: &'static [u8],
+}
+
+impl Inode for BootFile {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino: self.ino,
+            file_type: FileType::Regular,
+            size: self.data.len() as u64,
+            mode: 0o444,
+            nlink: 1,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let start = (offset as usize).min(self.data.len());
+        let count = buf.len().min(self.data.len() - start);
+        buf[..count].copy_from_slice(&self.data[start..start + count]);
+        Ok(count)
+    }
+}
+
+pub struct BootDir {
+    files: Vec<Arc<BootFile>>,
+}
+
+impl Inode for BootDir {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino: ROOT_INO,
+            file_type: FileType::Directory,
+            size: 0,
+            mode: 0o555,
+            nlink: 2,
+        }
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> FsResult<usize> {
+        Err(FsError::IsADirectory)
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<InodeRef> {
+        self.files
+            .iter()
+            .find(|file| file.name == name)
+            .map(|file| file.clone() as InodeRef)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn readdir(&self) -> FsResult<Vec<DirEntry>> {
+        Ok(self
+            .files
+            .iter()
+            .map(|file| DirEntry {
+                name: file.name.clone(),
+                ino: file.ino,
+                file_type: FileType::Regular,
+            })
+            .collect())
+    }
+}
+
+pub struct BootFs {
+    root: Arc<BootDir>,
+}
+
+impl BootFs {
+    // Files for `modules`, read through the HHDM; a later module of the same
+    // name hides an earlier one
+    pub fn new(modules: &[BootModule], hhdm_offset: u64) -> Self {
+        let mut files: Vec<Arc<BootFile>> = Vec::new();
+        for module in modules {
+            let data = unsafe {
+                core::slice::from_raw_parts(
+                    (module.addr + hhdm_offset) as *const u8,
+                    module.size as usize,
+                )
+            };
+            files.retain(|file| file.name != module.name());
+            files.push(Arc::new(BootFile {
+                ino: ROOT_INO + 1 + files.len() as u64,
+                name: String::from(module.name()),
+                data,
+            }));
+        }
+        Self {
+            root: Arc::new(BootDir { files }),
+        }
+    }
+}
+
+impl FileSystem for BootFs {
+    fn name(&self) -> &'static str {
+        "bootfs"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+}
+
+// Mount the modules at /boot (created on the writable root if missing)
+pub fn mount(modules: &[BootModule], hhdm_offset: u64) -> FsResult<()> {
+    if super::lookup("/boot").is_err() {
+        super::mkdir("/boot")?;
+    }
+    super::mount("/boot", Arc::new(BootFs::new(modules, hhdm_offset)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static DATA: [u8; 5] = *b"hello";
+
+    #[test_case]
+    fn bootfs_lists_and_reads_modules() {
+        // Through an HHDM offset of 0, the "physical" address is the static's
+        let modules = [
+            BootModule::new("a.bin", &DATA[..2]),
+            BootModule::new("b.bin", &DATA),
+            BootModule::new("a.bin", &DATA[1..]),
+        ];
+        let fs = BootFs::new(&modules, 0);
+        let root = fs.root();
+        assert_eq!(root.readdir().map(|entries| entries.len()), Ok(2));
+
+        let file = root.lookup("a.bin").expect("a.bin");
+        assert_eq!(file.metadata().size, 4);
+        let mut buf = [0u8; 8];
+        assert_eq!(file.read_at(1, &mut buf), Ok(3));
+        assert_eq!(&buf[..3], b"llo");
+        assert!(root.lookup("c.bin").is_err());
+    }
+}
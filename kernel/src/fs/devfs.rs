@@ -0,0 +1,169 @@
+// devfs
+// Device nodes mounted at /dev. Drivers register the inode of a node under a path
+// relative to /dev (e.g. "fb0"); the directories leading to it ("input" for
+// "input/event0") exist as long as something is registered below them, and
+// `mkdir` adds an empty one to mount another filesystem on (/dev/shm).
+// Nodes cannot be created, removed or renamed from user space.
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsResult, Inode, InodeRef, Metadata};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Registered paths, with the inode of a node or None for a directory
+static NODES: Mutex<Vec<(String, Option<InodeRef>)>> = Mutex::new(Vec::new());
+
+fn with_nodes<R>(f: impl FnOnce(&mut Vec<(String, Option<InodeRef>)>) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut NODES.lock()))
+}
+
+// Make `inode` available at /dev/<path>
+pub fn register(path: &str, inode: InodeRef) {
+    with_nodes(|nodes| nodes.push((String::from(path), Some(inode))));
+}
+
+// An empty directory at /dev/<path>
+pub fn mkdir(path: &str) {
+    with_nodes(|nodes| nodes.push((String::from(path), None)));
+}
+
+// Inode numbers: 1 for the root, 2 + index for registered paths; a directory
+// only implied by the nodes below it takes the number of the first of them
+fn find(path: &str) -> Option<(u64, Option<InodeRef>)> {
+    with_nodes(|nodes| {
+        nodes.iter().enumerate().find_map(|(i, (name, inode))| {
+            if name == path {
+                Some((2 + i as u64, inode.clone()))
+            } else if name.starts_with(path) && name.as_bytes().get(path.len()) == Some(&b'/') {
+                Some((2 + i as u64, None))
+            } else {
+                None
+            }
+        })
+    })
+}
+
+// A directory of devfs: the root ("") or a path below it
+struct DevDir {
+    path: String,
+    ino: u64,
+}
+
+impl Inode for DevDir {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino: self.ino,
+            file_type: FileType::Directory,
+            size: 0,
+            mode: 0o755,
+            nlink: 2,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<InodeRef> {
+        let path = if self.path.is_empty() {
+            String::from(name)
+        } else {
+            alloc::format!("{}/{}", self.path, name)
+        };
+        match find(&path).ok_or(FsError::NotFound)? {
+            (_, Some(inode)) => Ok(inode),
+            (ino, None) => Ok(Arc::new(DevDir { path, ino })),
+        }
+    }
+
+    fn readdir(&self) -> FsResult<Vec<DirEntry>> {
+        let prefix = if self.path.is_empty() {
+            String::new()
+        } else {
+            alloc::format!("{}/", self.path)
+        };
+        let mut entries: Vec<DirEntry> = Vec::new();
+        with_nodes(|nodes| {
+            for (i, (path, inode)) in nodes.iter().enumerate() {
+                let Some(rest) = path.strip_prefix(prefix.as_str()) else {
+                    continue;
+                };
+                let (name, file_type) = match rest.split_once('/') {
+                    Some((dir, _)) => (dir, FileType::Directory),
+                    None => (
+                        rest,
+                        inode
+                            .as_ref()
+                            .map_or(FileType::Directory, |inode| inode.metadata().file_type),
+                    ),
+                };
+                if name.is_empty() || entries.iter().any(|entry| entry.name == name) {
+                    continue;
+                }
+                entries.push(DirEntry {
+                    name: String::from(name),
+                    ino: 2 + i as u64,
+                    file_type,
+                });
+            }
+        });
+        Ok(entries)
+    }
+}
+
+pub struct DevFs;
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> InodeRef {
+        Arc::new(DevDir {
+            path: String::new(),
+            ino: 1,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Null;
+
+    impl Inode for Null {
+        fn metadata(&self) -> Metadata {
+            Metadata {
+                ino: 0,
+                file_type: FileType::CharDevice,
+                size: 0,
+                mode: 0o666,
+                nlink: 1,
+            }
+        }
+    }
+
+    #[test_case]
+    fn devfs_nested_nodes() {
+        register("devfs_test/null", Arc::new(Null));
+        let root = DevFs.root();
+        let dir = root.lookup("devfs_test").expect("directory");
+        assert_eq!(dir.metadata().file_type, FileType::Directory);
+        assert_eq!(
+            dir.lookup("null").map(|inode| inode.metadata().file_type),
+            Ok(FileType::CharDevice)
+        );
+        assert!(dir.lookup("zero").is_err());
+        let names: Vec<String> = dir
+            .readdir()
+            .expect("readdir")
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, ["null"]);
+        assert!(
+            root.readdir().expect("readdir").iter().any(|entry| {
+                entry.name == "devfs_test" && entry.file_type == FileType::Directory
+            })
+        );
+    }
+}
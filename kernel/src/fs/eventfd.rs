@@ -0,0 +1,143 @@
+// eventfd
+// A 64-bit counter behind a file descriptor, as on Linux: write(2) adds an
+// 8-byte value, read(2) returns the counter and resets it to zero (or, in
+// semaphore mode, returns 1 and decrements it). A read of a zero counter waits,
+// and so does a write that would take it past u64::MAX - 1, unless the file is
+// non-blocking (EAGAIN). poll reports POLLIN while the counter is non-zero and
+// POLLOUT while another 1 can be added.
+
+use super::{DirEntry, File, FileType, FsError, FsResult, Metadata, POLLIN, POLLOUT, SeekFrom};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Largest value the counter can hold
+const MAX_COUNT: u64 = u64::MAX - 1;
+
+pub struct EventFd {
+    count: Mutex<u64>,
+    semaphore: bool,
+    nonblocking: AtomicBool,
+}
+
+impl EventFd {
+    pub fn new(initial: u64, semaphore: bool, nonblocking: bool) -> Arc<Self> {
+        Arc::new(Self {
+            count: Mutex::new(initial),
+            semaphore,
+            nonblocking: AtomicBool::new(nonblocking),
+        })
+    }
+
+    fn with_count<R>(&self, f: impl FnOnce(&mut u64) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.count.lock()))
+    }
+
+    // Apply `op` to the counter, waiting for it to succeed unless non-blocking
+    fn wait(&self, mut op: impl FnMut(&mut u64) -> Option<u64>) -> FsResult<u64> {
+        if self.nonblocking.load(Ordering::Relaxed) {
+            return self.with_count(op).ok_or(FsError::WouldBlock);
+        }
+        Ok(crate::idle::wait_for(|| self.with_count(&mut op)))
+    }
+}
+
+impl File for EventFd {
+    fn read(&self, buf: &mut [u8]) -> FsResult<usize> {
+        if buf.len() < 8 {
+            return Err(FsError::InvalidArgument);
+        }
+        let value = self.wait(|count| match *count {
+            0 => None,
+            _ if self.semaphore => {
+                *count -= 1;
+                Some(1)
+            }
+            _ => Some(core::mem::take(count)),
+        })?;
+        buf[..8].copy_from_slice(&value.to_ne_bytes());
+        Ok(8)
+    }
+
+    fn write(&self, buf: &[u8]) -> FsResult<usize> {
+        let value = match buf.get(..8) {
+            Some(bytes) => u64::from_ne_bytes(bytes.try_into().unwrap()),
+            None => return Err(FsError::InvalidArgument),
+        };
+        if value == u64::MAX {
+            return Err(FsError::InvalidArgument);
+        }
+        self.wait(|count| {
+            let sum = count.checked_add(value).filter(|&sum| sum <= MAX_COUNT)?;
+            *count = sum;
+            Some(sum)
+        })?;
+        Ok(8)
+    }
+
+    fn seek(&self, _pos: SeekFrom) -> FsResult<u64> {
+        Err(FsError::InvalidArgument)
+    }
+
+    // An anonymous inode, like Linux's
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino: 0,
+            file_type: FileType::Regular,
+            size: 0,
+            mode: 0o600,
+            nlink: 1,
+        }
+    }
+
+    fn read_dir(&self, _max: usize) -> FsResult<Vec<DirEntry>> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn truncate(&self, _size: u64) -> FsResult<()> {
+        Err(FsError::InvalidArgument)
+    }
+
+    fn poll(&self) -> u16 {
+        let count = self.with_count(|count| *count);
+        let mut events = 0;
+        if count > 0 {
+            events |= POLLIN;
+        }
+        if count < MAX_COUNT {
+            events |= POLLOUT;
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn eventfd_counter_and_semaphore() {
+        let event = EventFd::new(3, false, true);
+        let mut buf = [0u8; 8];
+        assert_eq!(event.write(&2u64.to_ne_bytes()), Ok(8));
+        assert_eq!(event.poll(), POLLIN | POLLOUT);
+        assert_eq!(event.read(&mut buf), Ok(8));
+        assert_eq!(u64::from_ne_bytes(buf), 5);
+        assert_eq!(event.read(&mut buf), Err(FsError::WouldBlock));
+        assert_eq!(event.poll(), POLLOUT);
+
+        // Full: the writer would wait
+        assert_eq!(event.write(&MAX_COUNT.to_ne_bytes()), Ok(8));
+        assert_eq!(event.poll(), POLLIN);
+        assert_eq!(event.write(&1u64.to_ne_bytes()), Err(FsError::WouldBlock));
+
+        let semaphore = EventFd::new(2, true, true);
+        for _ in 0..2 {
+            assert_eq!(semaphore.read(&mut buf), Ok(8));
+            assert_eq!(u64::from_ne_bytes(buf), 1);
+        }
+        assert_eq!(semaphore.read(&mut buf), Err(FsError::WouldBlock));
+    }
+}
@@ -0,0 +1,65 @@
+// File Descriptor Table
+// Maps file descriptor numbers to open file descriptions.
+// Descriptors 0-2 are the console (stdin/stdout/stderr) and handled directly by the syscalls,
+// so VFS files start at descriptor 3.
+
+use super::{File, FsError, FsResult};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// First descriptor handed out for VFS files
+pub const FIRST_FILE_FD: usize = 3;
+
+// Maximum number of open descriptors
+pub const MAX_FDS: usize = 64;
+
+static FD_TABLE: Mutex<Vec<Option<Arc<dyn File>>>> = Mutex::new(Vec::new());
+
+fn with_table<R>(f: impl FnOnce(&mut Vec<Option<Arc<dyn File>>>) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut FD_TABLE.lock()))
+}
+
+// Install an open file and return the lowest free descriptor
+pub fn install(file: Arc<dyn File>) -> FsResult<usize> {
+    with_table(|table| {
+        if table.len() < FIRST_FILE_FD {
+            table.resize(FIRST_FILE_FD, None);
+        }
+
+        let slot = (FIRST_FILE_FD..table.len()).find(|&fd| table[fd].is_none());
+        match slot {
+            Some(fd) => {
+                table[fd] = Some(file);
+                Ok(fd)
+            }
+            None if table.len() < MAX_FDS => {
+                table.push(Some(file));
+                Ok(table.len() - 1)
+            }
+            None => Err(FsError::TooManyOpenFiles),
+        }
+    })
+}
+
+// Get the open file behind a descriptor
+pub fn get(fd: u64) -> FsResult<Arc<dyn File>> {
+    with_table(|table| {
+        table
+            .get(fd as usize)
+            .and_then(|slot| slot.clone())
+            .ok_or(FsError::BadFd)
+    })
+}
+
+// Close a descriptor
+pub fn close(fd: u64) -> FsResult<()> {
+    with_table(|table| {
+        table
+            .get_mut(fd as usize)
+            .and_then(|slot| slot.take())
+            .map(|_| ())
+            .ok_or(FsError::BadFd)
+    })
+}
@@ -0,0 +1,443 @@
+// ISO9660 Filesystem (read-only)
+// Reads CD images through a BlockDevice (2048-byte sectors) and a block cache.
+// Sizes from the image are checked against the device and sane limits before
+// anything is allocated for them: a corrupt image fails with EIO.
+// Rock Ridge (SUSP) extensions are used when present for long mixed-case names,
+// POSIX modes/inode numbers, symlinks and relocated deep directories.
+//
+// Directory record layout:
+//   0  length           2  extent LBA (LE u32)   10 data length (LE u32)
+//   25 flags           32  name length           33 name, then System Use area
+// System Use entries: signature[2] length[1] version[1] data...
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsResult, Inode, InodeRef, Metadata};
+use crate::block::{BlockCache, BlockDevice};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const SECTOR_SIZE: u64 = 2048;
+
+// Volume descriptors start at sector 16
+const DESCRIPTOR_START: u64 = 16;
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+
+// Number of sectors kept in the block cache
+const CACHE_BLOCKS: usize = 128;
+
+// Directory record flags
+const FLAG_DIRECTORY: u8 = 1 << 1;
+
+// Nesting limit for SUSP continuation areas (CE)
+const MAX_CONTINUATIONS: usize = 8;
+
+// Sizes read from the image, past which it is taken as corrupt: a directory
+// extent (tens of thousands of entries), a continuation area (one sector)
+const MAX_DIRECTORY_SIZE: u64 = 1024 * 1024;
+const MAX_CONTINUATION_SIZE: usize = SECTOR_SIZE as usize;
+
+// Rock Ridge NM / SL flags
+const RR_CONTINUE: u8 = 1 << 0;
+const RR_CURRENT: u8 = 1 << 1;
+const RR_PARENT: u8 = 1 << 2;
+const RR_ROOT: u8 = 1 << 3;
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    rock_ridge: bool,
+}
+
+impl Volume {
+    // `len` bytes at `offset`; EIO for a range past the end of the device
+    fn read(&self, offset: u64, len: usize) -> FsResult<Vec<u8>> {
+        let device_size = self
+            .device
+            .block_count()
+            .saturating_mul(self.device.block_size() as u64);
+        if offset
+            .checked_add(len as u64)
+            .is_none_or(|end| end > device_size)
+        {
+            return Err(FsError::Io);
+        }
+        let mut buf = vec![0u8; len];
+        self.device.read_bytes(offset, &mut buf)?;
+        Ok(buf)
+    }
+}
+
+// A parsed directory record
+struct Record {
+    name: String,
+    extent: u64,
+    size: u64,
+    is_dir: bool,
+    position: u64, // Byte offset of the record on the volume, fallback inode number
+    mode: Option<u32>,
+    nlink: Option<u32>,
+    ino: Option<u64>,
+    symlink: Option<String>,
+    child_link: Option<u64>, // CL: real location of a relocated directory
+    relocated: bool,         // RE: placeholder that should not be listed
+}
+
+impl Record {
+    fn parse(volume: &Volume, rec: &[u8], position: u64) -> FsResult<Self> {
+        if rec.len() < 34 {
+            return Err(FsError::Io);
+        }
+
+        let name_len = rec[32] as usize;
+        if 33 + name_len > rec.len() {
+            return Err(FsError::Io);
+        }
+
+        let mut record = Self {
+            name: plain_name(&rec[33..33 + name_len]),
+            extent: le_u32(&rec[2..6]) as u64,
+            size: le_u32(&rec[10..14]) as u64,
+            is_dir: rec[25] & FLAG_DIRECTORY != 0,
+            position,
+            mode: None,
+            nlink: None,
+            ino: None,
+            symlink: None,
+            child_link: None,
+            relocated: false,
+        };
+
+        if volume.rock_ridge {
+            // The System Use area starts after the name, padded to an even offset
+            let su_start = 33 + name_len + (1 - name_len % 2);
+            if su_start < rec.len() {
+                record.parse_susp(volume, &rec[su_start..], 0)?;
+            }
+        }
+
+        Ok(record)
+    }
+
+    // Apply the Rock Ridge entries of a System Use area
+    fn parse_susp(&mut self, volume: &Volume, area: &[u8], depth: usize) -> FsResult<()> {
+        let mut rr_name = String::new();
+        let mut has_rr_name = false;
+        // SL entries may continue a target started before a continuation area
+        let mut link = self.symlink.clone().unwrap_or_default();
+        let mut link_continues = false;
+        let mut continuation = None;
+        let mut pos = 0;
+
+        while pos + 4 <= area.len() {
+            let sig = &area[pos..pos + 2];
+            let len = area[pos + 2] as usize;
+            if len < 4 || pos + len > area.len() {
+                break;
+            }
+            let entry = &area[pos..pos + len];
+
+            match sig {
+                b"NM" if len >= 5 => {
+                    let flags = entry[4];
+                    if flags & (RR_CURRENT | RR_PARENT) == 0 {
+                        rr_name.push_str(&String::from_utf8_lossy(&entry[5..]));
+                        has_rr_name = true;
+                    }
+                }
+                b"PX" if len >= 36 => {
+                    self.mode = Some(le_u32(&entry[4..8]) & 0o7777);
+                    self.nlink = Some(le_u32(&entry[12..16]));
+                    if len >= 44 {
+                        self.ino = Some(le_u32(&entry[36..40]) as u64);
+                    }
+                }
+                b"SL" if len >= 5 => {
+                    let mut comp = 5;
+                    while comp + 2 <= len {
+                        let flags = entry[comp];
+                        let comp_len = entry[comp + 1] as usize;
+                        let content = &entry[(comp + 2).min(len)..(comp + 2 + comp_len).min(len)];
+
+                        if !link_continues && !link.is_empty() && !link.ends_with('/') {
+                            link.push('/');
+                        }
+                        if flags & RR_ROOT != 0 {
+                            link.push('/');
+                        } else if flags & RR_CURRENT != 0 {
+                            link.push('.');
+                        } else if flags & RR_PARENT != 0 {
+                            link.push_str("..");
+                        } else {
+                            link.push_str(&String::from_utf8_lossy(content));
+                        }
+
+                        link_continues = flags & RR_CONTINUE != 0;
+                        comp += 2 + comp_len;
+                    }
+                    self.symlink = Some(link.clone());
+                }
+                b"CL" if len >= 12 => self.child_link = Some(le_u32(&entry[4..8]) as u64),
+                b"RE" => self.relocated = true,
+                b"CE" if len >= 28 => {
+                    let block = le_u32(&entry[4..8]) as u64;
+                    let offset = le_u32(&entry[12..16]) as u64;
+                    let size = le_u32(&entry[20..24]) as usize;
+                    continuation = Some((block * SECTOR_SIZE + offset, size));
+                }
+                b"ST" => break,
+                _ => {}
+            }
+
+            pos += len;
+        }
+
+        if has_rr_name {
+            self.name = rr_name;
+        }
+
+        if let Some((offset, size)) = continuation
+            && depth < MAX_CONTINUATIONS
+        {
+            if size > MAX_CONTINUATION_SIZE {
+                return Err(FsError::Io);
+            }
+            let area = volume.read(offset, size)?;
+            self.parse_susp(volume, &area, depth + 1)?;
+        }
+
+        Ok(())
+    }
+}
+
+// ISO9660 name without Rock Ridge: strip the ";1" version and a trailing dot, lowercase
+fn plain_name(id: &[u8]) -> String {
+    let name = String::from_utf8_lossy(id);
+    let name = name.split(';').next().unwrap_or("");
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+// Read all records of a directory extent, without "." and ".."
+fn read_directory(volume: &Volume, extent: u64, size: u64) -> FsResult<Vec<Record>> {
+    if size > MAX_DIRECTORY_SIZE {
+        return Err(FsError::Io);
+    }
+    let base = extent * SECTOR_SIZE;
+    let data = volume.read(base, size as usize)?;
+    let mut records = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let len = data[pos] as usize;
+
+        // Records never cross a sector boundary, zero padding fills the rest
+        if len == 0 {
+            pos = (pos / SECTOR_SIZE as usize + 1) * SECTOR_SIZE as usize;
+            continue;
+        }
+        // Shorter than the fixed part: corrupt (and nothing to skip it by)
+        if len < 34 || pos + len > data.len() {
+            return Err(FsError::Io);
+        }
+
+        let rec = &data[pos..pos + len];
+        let is_dot = rec[32] == 1 && (rec[33] == 0 || rec[33] == 1);
+        if !is_dot {
+            let record = Record::parse(volume, rec, base + pos as u64)?;
+            if !record.relocated {
+                records.push(record);
+            }
+        }
+
+        pos += len;
+    }
+
+    Ok(records)
+}
+
+pub struct IsoNode {
+    volume: Arc<Volume>,
+    ino: u64,
+    extent: u64,
+    size: u64,
+    file_type: FileType,
+    mode: u32,
+    nlink: u32,
+    symlink: Option<String>,
+}
+
+impl IsoNode {
+    fn from_record(volume: &Arc<Volume>, record: Record) -> FsResult<Arc<Self>> {
+        let (mut extent, mut size) = (record.extent, record.size);
+
+        // Relocated directory: the real one lives at the child link, its "." record has the size
+        if let Some(child) = record.child_link {
+            let dot = volume.read(child * SECTOR_SIZE, 34)?;
+            extent = child;
+            size = le_u32(&dot[10..14]) as u64;
+        }
+
+        let file_type = if record.symlink.is_some() {
+            FileType::Symlink
+        } else if record.is_dir || record.child_link.is_some() {
+            FileType::Directory
+        } else {
+            FileType::Regular
+        };
+
+        let default_mode = if file_type == FileType::Directory {
+            0o555
+        } else {
+            0o444
+        };
+
+        Ok(Arc::new(Self {
+            volume: volume.clone(),
+            ino: record.ino.unwrap_or(record.position),
+            extent,
+            size,
+            file_type,
+            mode: record.mode.unwrap_or(default_mode),
+            nlink: record.nlink.unwrap_or(1),
+            symlink: record.symlink,
+        }))
+    }
+
+    fn records(&self) -> FsResult<Vec<Record>> {
+        if self.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        read_directory(&self.volume, self.extent, self.size)
+    }
+}
+
+impl Inode for IsoNode {
+    fn metadata(&self) -> Metadata {
+        let size = match &self.symlink {
+            Some(target) => target.len() as u64,
+            None => self.size,
+        };
+
+        Metadata {
+            ino: self.ino,
+            file_type: self.file_type,
+            size,
+            mode: self.mode,
+            nlink: self.nlink,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        if self.file_type != FileType::Regular {
+            return Err(FsError::IsADirectory);
+        }
+
+        let start = offset.min(self.size);
+        let count = buf.len().min((self.size - start) as usize);
+        self.volume
+            .device
+            .read_bytes(self.extent * SECTOR_SIZE + start, &mut buf[..count])?;
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<InodeRef> {
+        let record = self
+            .records()?
+            .into_iter()
+            .find(|r| r.name == name)
+            .ok_or(FsError::NotFound)?;
+        Ok(IsoNode::from_record(&self.volume, record)?)
+    }
+
+    fn readdir(&self) -> FsResult<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        for record in self.records()? {
+            let name = record.name.clone();
+            let node = IsoNode::from_record(&self.volume, record)?;
+            entries.push(DirEntry {
+                name,
+                ino: node.ino,
+                file_type: node.file_type,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn read_link(&self) -> FsResult<String> {
+        self.symlink.clone().ok_or(FsError::InvalidArgument)
+    }
+}
+
+pub struct Iso9660Fs {
+    root: Arc<IsoNode>,
+}
+
+// Check for an ISO9660 volume descriptor
+pub fn probe(device: &dyn BlockDevice) -> bool {
+    if device.block_size() as u64 != SECTOR_SIZE || device.block_count() <= DESCRIPTOR_START {
+        return false;
+    }
+
+    let mut sector = vec![0u8; SECTOR_SIZE as usize];
+    device.read_block(DESCRIPTOR_START, &mut sector).is_ok() && &sector[1..6] == b"CD001"
+}
+
+impl Iso9660Fs {
+    pub fn new(device: Arc<dyn BlockDevice>) -> FsResult<Self> {
+        if !probe(&*device) {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let cache: Arc<dyn BlockDevice> = BlockCache::new(device, CACHE_BLOCKS);
+
+        // Find the primary volume descriptor
+        let mut lba = DESCRIPTOR_START;
+        let pvd = loop {
+            let mut sector = vec![0u8; SECTOR_SIZE as usize];
+            cache.read_block(lba, &mut sector)?;
+            if &sector[1..6] != b"CD001" || sector[0] == DESCRIPTOR_TERMINATOR {
+                return Err(FsError::Io);
+            }
+            if sector[0] == DESCRIPTOR_PRIMARY {
+                break sector;
+            }
+            lba += 1;
+        };
+
+        let mut volume = Volume {
+            device: cache,
+            rock_ridge: false,
+        };
+
+        // Rock Ridge volumes start the root's "." System Use area with an SP entry (BE EF)
+        let root_record = Record::parse(&volume, &pvd[156..190], lba * SECTOR_SIZE + 156)?;
+        let dot = volume.read(root_record.extent * SECTOR_SIZE, 255)?;
+        let dot_len = dot[0] as usize;
+        volume.rock_ridge = dot_len >= 41 && &dot[34..36] == b"SP" && dot[38..40] == [0xBE, 0xEF];
+
+        let label = String::from_utf8_lossy(&pvd[40..72]);
+        log::info!(
+            "Volume '{}', Rock Ridge: {}",
+            label.trim_end(),
+            if volume.rock_ridge { "yes" } else { "no" }
+        );
+
+        Ok(Self {
+            root: IsoNode::from_record(&Arc::new(volume), root_record)?,
+        })
+    }
+}
+
+impl FileSystem for Iso9660Fs {
+    fn name(&self) -> &'static str {
+        "iso9660"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+}
@@ -0,0 +1,1024 @@
+// Virtual Filesystem (VFS) Module
+// This module defines the object model shared by all filesystems:
+//   - Inode:      a file, directory or symlink living inside a filesystem
+//   - FileSystem: a mountable filesystem instance exposing a root inode
+//   - File:       an open file description (inode + offset), referenced by file descriptors
+//   - DirEntry:   a single directory entry returned by readdir
+//
+// Filesystems are attached to the mount table at canonical absolute paths.
+// `resolve` walks a path component by component from the root: `.` is skipped,
+// `..` returns to the previous directory (crossing back over mount points),
+// symlinks are expanded (at most MAX_SYMLINKS per lookup), and a component whose
+// canonical path is a mount point continues in the mounted filesystem's root.
+// Relative paths start at the current working directory.
+//
+// Filesystem types that can be created by the mount syscall register an `FsType`
+// (e.g. "tmpfs"), so `mount -t <type>` can attach them at any existing directory.
+
+pub mod bootfs;
+#[cfg(feature = "userspace")]
+pub mod devfs;
+#[cfg(feature = "userspace")]
+pub mod eventfd;
+#[cfg(feature = "userspace")]
+pub mod fd;
+pub mod iso9660;
+pub mod overlay;
+pub mod procfs;
+pub mod ramfs;
+#[cfg(feature = "userspace")]
+pub mod shmfs;
+pub mod tar;
+#[cfg(feature = "userspace")]
+pub mod timerfd;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+#[cfg(feature = "userspace")]
+use x86_64::structures::paging::PhysFrame;
+
+// ============================================================================
+// 1. TYPES
+// ============================================================================
+
+pub type InodeRef = Arc<dyn Inode>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    #[cfg(feature = "userspace")]
+    CharDevice,
+    #[cfg(feature = "net")]
+    Socket,
+}
+
+#[cfg(feature = "userspace")]
+impl FileType {
+    // File type bits of st_mode
+    pub fn mode_bits(self) -> u32 {
+        match self {
+            FileType::Regular => 0o100000,
+            FileType::Directory => 0o040000,
+            FileType::Symlink => 0o120000,
+            FileType::CharDevice => 0o020000,
+            #[cfg(feature = "net")]
+            FileType::Socket => 0o140000,
+        }
+    }
+
+    // d_type value of linux_dirent64
+    pub fn dirent_type(self) -> u8 {
+        match self {
+            FileType::Regular => 8,
+            FileType::Directory => 4,
+            FileType::Symlink => 10,
+            FileType::CharDevice => 2,
+            #[cfg(feature = "net")]
+            FileType::Socket => 12,
+        }
+    }
+}
+
+// ino, mode and nlink are only reported to user space (stat, getdents)
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(not(feature = "userspace"), allow(dead_code))]
+pub struct Metadata {
+    pub ino: u64,
+    pub file_type: FileType,
+    pub size: u64,
+    pub mode: u32, // Permission bits only (e.g. 0o755)
+    pub nlink: u32,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "userspace"), allow(dead_code))]
+pub struct DirEntry {
+    pub name: String,
+    pub ino: u64,
+    pub file_type: FileType,
+}
+
+// Filesystem errors, converted to Linux errno values at the syscall boundary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    #[cfg(feature = "userspace")]
+    NotEmpty,
+    ReadOnly,
+    InvalidArgument,
+    NotSupported,
+    #[cfg(feature = "userspace")]
+    BadFd,
+    #[cfg(feature = "userspace")]
+    TooManyOpenFiles,
+    Busy,
+    NoDevice,
+    TooManyLinks,
+    #[cfg(feature = "userspace")]
+    WouldBlock,
+    #[cfg(feature = "userspace")]
+    NotATty,
+    #[cfg(feature = "userspace")]
+    BadAddress,
+    FileTooLarge,
+    NoSpace,
+    Io,
+}
+
+#[cfg(feature = "userspace")]
+impl FsError {
+    // Negative errno returned to user space
+    pub fn errno(self) -> i64 {
+        match self {
+            FsError::NotFound => -2,          // ENOENT
+            FsError::Io => -5,                // EIO
+            FsError::BadFd => -9,             // EBADF
+            FsError::WouldBlock => -11,       // EAGAIN
+            FsError::BadAddress => -14,       // EFAULT
+            FsError::Busy => -16,             // EBUSY
+            FsError::NoDevice => -19,         // ENODEV
+            FsError::AlreadyExists => -17,    // EEXIST
+            FsError::NotADirectory => -20,    // ENOTDIR
+            FsError::IsADirectory => -21,     // EISDIR
+            FsError::InvalidArgument => -22,  // EINVAL
+            FsError::TooManyOpenFiles => -24, // EMFILE
+            FsError::NotATty => -25,          // ENOTTY
+            FsError::FileTooLarge => -27,     // EFBIG
+            FsError::NoSpace => -28,          // ENOSPC
+            FsError::ReadOnly => -30,         // EROFS
+            FsError::NotEmpty => -39,         // ENOTEMPTY
+            FsError::TooManyLinks => -40,     // ELOOP
+            FsError::NotSupported => -95,     // EOPNOTSUPP
+        }
+    }
+}
+
+pub type FsResult<T> = Result<T, FsError>;
+
+// ============================================================================
+// 2. TRAITS
+// ============================================================================
+
+// A node of a filesystem
+// Every operation has a default implementation so filesystems only implement what they support
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> FsResult<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> FsResult<usize> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _size: u64) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    // Find a child by name (directories only)
+    fn lookup(&self, _name: &str) -> FsResult<InodeRef> {
+        Err(FsError::NotADirectory)
+    }
+
+    // List the children (directories only)
+    fn readdir(&self) -> FsResult<Vec<DirEntry>> {
+        Err(FsError::NotADirectory)
+    }
+
+    // Create a new child (directories only)
+    fn create(&self, _name: &str, _file_type: FileType) -> FsResult<InodeRef> {
+        Err(FsError::ReadOnly)
+    }
+
+    // Remove a child (directories only)
+    #[cfg(feature = "userspace")]
+    fn unlink(&self, _name: &str) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    // Target of a symlink
+    fn read_link(&self) -> FsResult<String> {
+        Err(FsError::InvalidArgument)
+    }
+
+    // The frames behind the file, for MAP_SHARED (shared memory objects only)
+    #[cfg(feature = "userspace")]
+    fn shared_memory(self: Arc<Self>) -> Option<Arc<shmfs::SharedMemory>> {
+        None
+    }
+
+    // Device-specific request (device nodes only); `arg` is a user address or value
+    #[cfg(feature = "userspace")]
+    fn ioctl(&self, _request: u64, _arg: u64) -> FsResult<i64> {
+        Err(FsError::NotATty)
+    }
+
+    // The frame behind page `index` of a device's memory, for MAP_SHARED
+    #[cfg(feature = "userspace")]
+    fn mmap_frame(&self, _index: u64) -> Option<PhysFrame> {
+        None
+    }
+
+    // An open file description of its own for each open (devices with per-reader
+    // state); None opens the inode as a plain file
+    #[cfg(feature = "userspace")]
+    fn open(&self, _flags: u32) -> Option<Arc<dyn File>> {
+        None
+    }
+}
+
+// A mountable filesystem instance
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn root(&self) -> InodeRef;
+}
+
+// An open file description
+pub trait File: Send + Sync {
+    fn read(&self, buf: &mut [u8]) -> FsResult<usize>;
+    #[cfg(feature = "userspace")]
+    fn write(&self, buf: &[u8]) -> FsResult<usize>;
+    fn seek(&self, pos: SeekFrom) -> FsResult<u64>;
+    #[cfg(feature = "userspace")]
+    fn metadata(&self) -> Metadata;
+    // Return directory entries starting at the current position and advance it
+    #[cfg(feature = "userspace")]
+    fn read_dir(&self, max: usize) -> FsResult<Vec<DirEntry>>;
+    #[cfg(feature = "userspace")]
+    fn truncate(&self, size: u64) -> FsResult<()>;
+
+    // The socket behind this descriptor, for the socket syscalls
+    #[cfg(feature = "net")]
+    fn as_socket(&self) -> Option<&crate::net::socket::SocketFile> {
+        None
+    }
+
+    // The shared memory object behind this descriptor, for MAP_SHARED
+    #[cfg(feature = "userspace")]
+    fn shared_memory(&self) -> Option<Arc<shmfs::SharedMemory>> {
+        None
+    }
+
+    // ioctl(2): ENOTTY for everything but device nodes
+    #[cfg(feature = "userspace")]
+    fn ioctl(&self, _request: u64, _arg: u64) -> FsResult<i64> {
+        Err(FsError::NotATty)
+    }
+
+    // Device memory behind the descriptor, for MAP_SHARED
+    #[cfg(feature = "userspace")]
+    fn mmap_frame(&self, _index: u64) -> Option<PhysFrame> {
+        None
+    }
+
+    // The timer behind this descriptor, for timerfd_settime / timerfd_gettime
+    #[cfg(feature = "userspace")]
+    fn as_timer(&self) -> Option<&timerfd::TimerFd> {
+        None
+    }
+
+    // Readiness for poll(2) as POLLIN | POLLOUT bits; files never make a reader
+    // or writer wait
+    #[cfg(feature = "userspace")]
+    fn poll(&self) -> u16 {
+        POLLIN | POLLOUT
+    }
+}
+
+// poll(2) readiness bits
+#[cfg(feature = "userspace")]
+pub const POLLIN: u16 = 0x1;
+#[cfg(feature = "userspace")]
+pub const POLLOUT: u16 = 0x4;
+
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    #[cfg(feature = "userspace")]
+    Start(u64),
+    #[cfg(feature = "userspace")]
+    Current(i64),
+    End(i64),
+}
+
+// Default File implementation: an inode plus a position
+pub struct InodeFile {
+    inode: InodeRef,
+    pos: Mutex<u64>,
+    // A directory's entries, read when a listing starts at position 0 and
+    // walked by the position from there
+    #[cfg(feature = "userspace")]
+    listing: Mutex<Option<Vec<DirEntry>>>,
+    #[cfg(feature = "userspace")]
+    writable: bool,
+}
+
+impl InodeFile {
+    #[cfg_attr(not(feature = "userspace"), allow(unused_variables))]
+    pub fn new(inode: InodeRef, writable: bool) -> Self {
+        Self {
+            inode,
+            pos: Mutex::new(0),
+            #[cfg(feature = "userspace")]
+            listing: Mutex::new(None),
+            #[cfg(feature = "userspace")]
+            writable,
+        }
+    }
+}
+
+impl File for InodeFile {
+    fn read(&self, buf: &mut [u8]) -> FsResult<usize> {
+        if self.inode.metadata().file_type == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let mut pos = self.pos.lock();
+        let count = self.inode.read_at(*pos, buf)?;
+        *pos += count as u64;
+        Ok(count)
+    }
+
+    #[cfg(feature = "userspace")]
+    fn write(&self, buf: &[u8]) -> FsResult<usize> {
+        if !self.writable {
+            return Err(FsError::BadFd);
+        }
+        let mut pos = self.pos.lock();
+        let count = self.inode.write_at(*pos, buf)?;
+        *pos += count as u64;
+        Ok(count)
+    }
+
+    fn seek(&self, target: SeekFrom) -> FsResult<u64> {
+        let mut pos = self.pos.lock();
+        let new_pos = match target {
+            #[cfg(feature = "userspace")]
+            SeekFrom::Start(offset) => Some(offset),
+            #[cfg(feature = "userspace")]
+            SeekFrom::Current(delta) => pos.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.inode.metadata().size.checked_add_signed(delta),
+        };
+        // Negative or past what an off_t holds
+        *pos = new_pos
+            .filter(|&new_pos| new_pos <= i64::MAX as u64)
+            .ok_or(FsError::InvalidArgument)?;
+        Ok(*pos)
+    }
+
+    #[cfg(feature = "userspace")]
+    fn metadata(&self) -> Metadata {
+        self.inode.metadata()
+    }
+
+    #[cfg(feature = "userspace")]
+    fn read_dir(&self, max: usize) -> FsResult<Vec<DirEntry>> {
+        let mut pos = self.pos.lock();
+        let mut listing = self.listing.lock();
+        // Rewinding reads the directory again, so it sees entries added since
+        if *pos == 0 || listing.is_none() {
+            *listing = Some(self.inode.readdir()?);
+        }
+        let entries = listing.as_deref().unwrap_or_default();
+        let start = (*pos as usize).min(entries.len());
+        let batch: Vec<DirEntry> = entries[start..].iter().take(max).cloned().collect();
+        *pos += batch.len() as u64;
+        Ok(batch)
+    }
+
+    #[cfg(feature = "userspace")]
+    fn truncate(&self, size: u64) -> FsResult<()> {
+        if !self.writable {
+            return Err(FsError::InvalidArgument);
+        }
+        self.inode.truncate(size)
+    }
+
+    #[cfg(feature = "userspace")]
+    fn shared_memory(&self) -> Option<Arc<shmfs::SharedMemory>> {
+        self.inode.clone().shared_memory()
+    }
+
+    #[cfg(feature = "userspace")]
+    fn ioctl(&self, request: u64, arg: u64) -> FsResult<i64> {
+        self.inode.ioctl(request, arg)
+    }
+
+    #[cfg(feature = "userspace")]
+    fn mmap_frame(&self, index: u64) -> Option<PhysFrame> {
+        self.inode.mmap_frame(index)
+    }
+}
+
+// ============================================================================
+// 3. MOUNT TABLE
+// ============================================================================
+
+struct Mount {
+    path: String, // Normalized absolute path, "/" for the root
+    fs: Arc<dyn FileSystem>,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+fn with_mounts<R>(f: impl FnOnce(&mut Vec<Mount>) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut MOUNTS.lock()))
+}
+
+// Attach a filesystem at a directory
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> FsResult<()> {
+    // The mount point must exist (except for the very first root mount)
+    let path = if with_mounts(|mounts| mounts.is_empty()) {
+        if !path.split('/').all(|c| c.is_empty()) {
+            return Err(FsError::NotFound);
+        }
+        String::from("/")
+    } else {
+        let target = resolve(path, true)?;
+        if target.inode.metadata().file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        target.path
+    };
+
+    with_mounts(|mounts| {
+        if mounts.iter().any(|m| m.path == path) {
+            return Err(FsError::Busy);
+        }
+        log::info!("Mounted {} at {}", fs.name(), path);
+        mounts.push(Mount { path, fs });
+        Ok(())
+    })
+}
+
+// umount2 flag: also detach the filesystems mounted below
+#[cfg(feature = "userspace")]
+pub const MNT_DETACH: u32 = 2;
+
+// Detach the filesystem mounted at `path`
+// Fails with Busy while other filesystems are mounted below it, unless MNT_DETACH
+// is given, in which case those are detached too
+#[cfg(feature = "userspace")]
+pub fn umount(path: &str, flags: u32) -> FsResult<()> {
+    let path = resolve(path, true)?.path;
+    // Nothing cached for the filesystem may be lost with it
+    crate::block::sync_all();
+
+    with_mounts(|mounts| {
+        if !mounts.iter().any(|m| m.path == path) {
+            return Err(FsError::InvalidArgument); // Not a mount point
+        }
+
+        let below = |m: &Mount| {
+            m.path != path
+                && (path == "/"
+                    || (m.path.starts_with(path.as_str())
+                        && m.path.as_bytes().get(path.len()) == Some(&b'/')))
+        };
+
+        if mounts.iter().any(below) {
+            if flags & MNT_DETACH == 0 {
+                return Err(FsError::Busy);
+            }
+            mounts.retain(|m| !below(m));
+        }
+
+        let index = mounts
+            .iter()
+            .position(|m| m.path == path)
+            .expect("mount point");
+        let mount = mounts.remove(index);
+        log::info!("Unmounted {} from {}", mount.fs.name(), mount.path);
+        Ok(())
+    })
+}
+
+// List the mount table as (mount point, filesystem name) pairs
+pub fn mounts() -> Vec<(String, &'static str)> {
+    with_mounts(|mounts| {
+        mounts
+            .iter()
+            .map(|m| (m.path.clone(), m.fs.name()))
+            .collect()
+    })
+}
+
+// /proc/mounts: one "<mount point> <filesystem>" line per mount
+fn read_mounts() -> String {
+    mounts()
+        .into_iter()
+        .map(|(path, name)| format!("{} {}\n", path, name))
+        .collect()
+}
+
+static MOUNTS_ENTRY: procfs::ProcEntry = procfs::ProcEntry {
+    name: "mounts",
+    read: read_mounts,
+    write: None,
+};
+
+// A filesystem type that can be instantiated by name (mount -t <name>)
+pub struct FsType {
+    pub name: &'static str,
+    // Create a new instance from a source string (device path, or ignored for memory filesystems)
+    pub create: fn(source: &str) -> FsResult<Arc<dyn FileSystem>>,
+}
+
+static FS_TYPES: Mutex<Vec<&'static FsType>> = Mutex::new(Vec::new());
+
+pub fn register_fs_type(fs_type: &'static FsType) {
+    interrupts::without_interrupts(|| FS_TYPES.lock().push(fs_type));
+}
+
+// Create a filesystem of the given type and mount it
+pub fn mount_type(source: &str, target: &str, fs_type: &str) -> FsResult<()> {
+    let create = interrupts::without_interrupts(|| {
+        FS_TYPES
+            .lock()
+            .iter()
+            .find(|t| t.name == fs_type)
+            .map(|t| t.create)
+    })
+    .ok_or(FsError::NoDevice)?;
+
+    mount(target, create(source)?)
+}
+
+// Root inode of the filesystem mounted exactly at a canonical path
+fn mount_root(path: &str) -> Option<InodeRef> {
+    with_mounts(|mounts| mounts.iter().find(|m| m.path == path).map(|m| m.fs.root()))
+}
+
+// ============================================================================
+// 4. PATH LOOKUP
+// ============================================================================
+
+// Maximum number of symlinks expanded while resolving a single path
+const MAX_SYMLINKS: usize = 40;
+
+// A resolved path: its canonical form (absolute, no `.`, `..` or symlinks) and inode
+pub struct Resolved {
+    pub path: String,
+    pub inode: InodeRef,
+}
+
+// Walk a path to its inode
+// `follow_last` selects whether a symlink in the final component is expanded (stat vs lstat)
+pub fn resolve(path: &str, follow_last: bool) -> FsResult<Resolved> {
+    let root = mount_root("/").ok_or(FsError::NotFound)?;
+
+    // Directories walked so far with their canonical paths, the last one is the current one
+    let mut walked: Vec<Resolved> = Vec::new();
+    // Components left to walk, reversed so the next one is at the end
+    let mut pending: Vec<String> = Vec::new();
+    let mut symlinks = 0;
+
+    let push_components = |pending: &mut Vec<String>, path: &str| {
+        let components = path.split('/').filter(|c| !c.is_empty()).rev();
+        pending.extend(components.map(String::from));
+    };
+
+    push_components(&mut pending, path);
+    if !path.starts_with('/') {
+        push_components(&mut pending, &cwd());
+    }
+
+    while let Some(component) = pending.pop() {
+        match component.as_str() {
+            "." => continue,
+            ".." => {
+                // `..` of the root is the root itself
+                walked.pop();
+                continue;
+            }
+            _ => {}
+        }
+
+        let (parent_path, parent) = match walked.last() {
+            Some(dir) => (dir.path.as_str(), &dir.inode),
+            None => ("", &root),
+        };
+        let child_path = format!("{}/{}", parent_path, component);
+
+        // A mount point is replaced by the root of the filesystem mounted on it
+        let inode = match mount_root(&child_path) {
+            Some(mounted) => mounted,
+            None => parent.lookup(&component)?,
+        };
+
+        let is_last = pending.is_empty();
+        if inode.metadata().file_type == FileType::Symlink && (follow_last || !is_last) {
+            symlinks += 1;
+            if symlinks > MAX_SYMLINKS {
+                return Err(FsError::TooManyLinks);
+            }
+
+            // Absolute targets restart at the root, relative ones at the link's directory
+            let target = inode.read_link()?;
+            if target.starts_with('/') {
+                walked.clear();
+            }
+            push_components(&mut pending, &target);
+            continue;
+        }
+
+        walked.push(Resolved {
+            path: child_path,
+            inode,
+        });
+    }
+
+    Ok(walked.pop().unwrap_or(Resolved {
+        path: String::from("/"),
+        inode: root,
+    }))
+}
+
+// Resolve a path to an inode, following symlinks
+pub fn lookup(path: &str) -> FsResult<InodeRef> {
+    Ok(resolve(path, true)?.inode)
+}
+
+// Split a path into (parent path, final component)
+pub fn split_parent(path: &str) -> FsResult<(&str, &str)> {
+    let trimmed = path.trim_end_matches('/');
+    let (parent, name) = match trimmed.rfind('/') {
+        Some(0) => ("/", &trimmed[1..]),
+        Some(index) => (&trimmed[..index], &trimmed[index + 1..]),
+        None => (".", trimmed),
+    };
+    if name.is_empty() {
+        return Err(FsError::InvalidArgument);
+    }
+    Ok((parent, name))
+}
+
+// Resolve the directory that contains the final component of `path`
+// Returns the parent directory and the (unresolved) final component
+fn resolve_parent(path: &str) -> FsResult<(Resolved, &str)> {
+    let (parent, name) = split_parent(path)?;
+    if name == "." || name == ".." {
+        return Err(FsError::InvalidArgument);
+    }
+    Ok((resolve(parent, true)?, name))
+}
+
+// Fail with Busy if `name` inside `parent` is a mount point
+#[cfg(feature = "userspace")]
+fn check_not_mounted(parent: &Resolved, name: &str) -> FsResult<()> {
+    let parent_path = if parent.path == "/" { "" } else { &parent.path };
+    match mount_root(&format!("{}/{}", parent_path, name)) {
+        Some(_) => Err(FsError::Busy),
+        None => Ok(()),
+    }
+}
+
+// Open flags (Linux values)
+pub const O_WRONLY: u32 = 0o1;
+pub const O_RDWR: u32 = 0o2;
+pub const O_CREAT: u32 = 0o100;
+pub const O_EXCL: u32 = 0o200;
+pub const O_TRUNC: u32 = 0o1000;
+pub const O_APPEND: u32 = 0o2000;
+#[cfg(feature = "userspace")]
+pub const O_NONBLOCK: u32 = 0o4000;
+pub const O_DIRECTORY: u32 = 0o200000;
+
+// Open a path and return an open file description
+pub fn open(path: &str, flags: u32) -> FsResult<Arc<dyn File>> {
+    let inode = match lookup(path) {
+        Ok(inode) => {
+            if flags & O_CREAT != 0 && flags & O_EXCL != 0 {
+                return Err(FsError::AlreadyExists);
+            }
+            inode
+        }
+        Err(FsError::NotFound) if flags & O_CREAT != 0 => {
+            let (parent, name) = resolve_parent(path)?;
+            parent.inode.create(name, FileType::Regular)?
+        }
+        Err(err) => return Err(err),
+    };
+
+    let file_type = inode.metadata().file_type;
+    if flags & O_DIRECTORY != 0 && file_type != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+
+    let writable = flags & (O_WRONLY | O_RDWR) != 0;
+    if writable && file_type == FileType::Directory {
+        return Err(FsError::IsADirectory);
+    }
+    if writable && flags & O_TRUNC != 0 {
+        inode.truncate(0)?;
+    }
+
+    #[cfg(feature = "userspace")]
+    if let Some(file) = inode.open(flags) {
+        return Ok(file);
+    }
+    let file = Arc::new(InodeFile::new(inode, writable));
+    if flags & O_APPEND != 0 {
+        file.seek(SeekFrom::End(0))?;
+    }
+    Ok(file)
+}
+
+// Metadata of a path (follows a final symlink)
+#[cfg(feature = "userspace")]
+pub fn stat(path: &str) -> FsResult<Metadata> {
+    Ok(lookup(path)?.metadata())
+}
+
+// Metadata of a path (does not follow a final symlink)
+#[cfg(feature = "userspace")]
+pub fn lstat(path: &str) -> FsResult<Metadata> {
+    Ok(resolve(path, false)?.inode.metadata())
+}
+
+// Whole contents of a file
+pub fn read_file(path: &str) -> FsResult<Vec<u8>> {
+    let file = open(path, 0)?;
+    let mut data = Vec::new();
+    let mut chunk = [0u8; 512];
+    loop {
+        let count = file.read(&mut chunk)?;
+        if count == 0 {
+            return Ok(data);
+        }
+        data.extend_from_slice(&chunk[..count]);
+    }
+}
+
+// Target of a symlink
+#[cfg(feature = "userspace")]
+pub fn readlink(path: &str) -> FsResult<String> {
+    resolve(path, false)?.inode.read_link()
+}
+
+// Create a directory
+pub fn mkdir(path: &str) -> FsResult<()> {
+    let (parent, name) = resolve_parent(path)?;
+    parent.inode.create(name, FileType::Directory)?;
+    Ok(())
+}
+
+// Remove a non-directory entry
+#[cfg(feature = "userspace")]
+pub fn unlink(path: &str) -> FsResult<()> {
+    let (parent, name) = resolve_parent(path)?;
+    if parent.inode.lookup(name)?.metadata().file_type == FileType::Directory {
+        return Err(FsError::IsADirectory);
+    }
+    parent.inode.unlink(name)
+}
+
+// Remove an empty directory
+#[cfg(feature = "userspace")]
+pub fn rmdir(path: &str) -> FsResult<()> {
+    let (parent, name) = resolve_parent(path)?;
+    if parent.inode.lookup(name)?.metadata().file_type != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    check_not_mounted(&parent, name)?;
+    parent.inode.unlink(name)
+}
+
+// ============================================================================
+// 5. WORKING DIRECTORY
+// ============================================================================
+
+// Canonical path of the current working directory (empty until the first chdir)
+// There is a single user process, so the working directory is global
+static CWD: Mutex<String> = Mutex::new(String::new());
+
+pub fn cwd() -> String {
+    let cwd = interrupts::without_interrupts(|| CWD.lock().clone());
+    if cwd.is_empty() {
+        String::from("/")
+    } else {
+        cwd
+    }
+}
+
+// Change the working directory
+#[cfg(feature = "userspace")]
+pub fn chdir(path: &str) -> FsResult<()> {
+    let target = resolve(path, true)?;
+    if target.inode.metadata().file_type != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    interrupts::without_interrupts(|| *CWD.lock() = target.path);
+    Ok(())
+}
+
+static TMPFS: FsType = FsType {
+    name: "tmpfs",
+    create: |_| Ok(Arc::new(ramfs::RamFs::new("tmpfs"))),
+};
+
+static RAMFS: FsType = FsType {
+    name: "ramfs",
+    create: |_| Ok(Arc::new(ramfs::RamFs::new("ramfs"))),
+};
+
+static ISO9660: FsType = FsType {
+    name: "iso9660",
+    create: |source| {
+        let device = crate::block::get(source).ok_or(FsError::NoDevice)?;
+        Ok(Arc::new(iso9660::Iso9660Fs::new(device)?))
+    },
+};
+
+// Mount the image loaded by the bootloader at "/"
+// The image is registered as block device "ram0"; ISO9660 images are recognized
+// by their volume descriptor, anything else is parsed as a tar archive
+pub fn mount_boot_image(phys_addr: u64, size: u64, hhdm_offset: u64) -> FsResult<()> {
+    let disk =
+        unsafe { crate::block::RamDisk::new(phys_addr + hhdm_offset, size as usize, 2048, false) };
+    let disk: Arc<dyn crate::block::BlockDevice> = Arc::new(disk);
+    crate::block::register("ram0", disk.clone());
+
+    if iso9660::probe(&*disk) {
+        mount("/", Arc::new(iso9660::Iso9660Fs::new(disk)?))
+    } else {
+        tar::mount_initramfs(phys_addr, size, hhdm_offset)
+    }
+}
+
+static OVERLAY: FsType = FsType {
+    name: "overlay",
+    // The source directory becomes the lower layer, a fresh tmpfs the upper one
+    create: |source| {
+        let upper = ramfs::RamFs::new("overlay-upper");
+        Ok(Arc::new(overlay::OverlayFs::new(
+            lookup(source)?,
+            upper.root(),
+        )))
+    },
+};
+
+// Make the root filesystem writable by layering a tmpfs over it
+pub fn overlay_root() -> FsResult<()> {
+    with_mounts(|mounts| {
+        let root = mounts
+            .iter_mut()
+            .find(|m| m.path == "/")
+            .ok_or(FsError::NotFound)?;
+
+        let upper = ramfs::RamFs::new("overlay-upper");
+        log::info!("Root {} overlaid with a writable tmpfs", root.fs.name());
+        root.fs = Arc::new(overlay::OverlayFs::new(root.fs.root(), upper.root()));
+        Ok(())
+    })
+}
+
+static PROC: FsType = FsType {
+    name: "proc",
+    create: |_| Ok(Arc::new(procfs::ProcFs)),
+};
+
+// Register the built-in filesystem types and mount a tmpfs at /tmp, device nodes at
+// /dev, shared memory at /dev/shm and procfs at /proc, with /proc/mounts
+// Without an initramfs, an empty ramfs becomes the root filesystem first
+pub fn init_tmpfs() {
+    register_fs_type(&TMPFS);
+    register_fs_type(&RAMFS);
+    register_fs_type(&ISO9660);
+    register_fs_type(&OVERLAY);
+    register_fs_type(&PROC);
+
+    if lookup("/").is_err() {
+        mount("/", Arc::new(ramfs::RamFs::new("rootfs"))).expect("Failed to mount rootfs");
+    }
+    if lookup("/tmp").is_err() {
+        let _ = mkdir("/tmp");
+    }
+
+    // A read-only root without a /tmp directory gets no tmpfs
+    if let Err(err) = mount_type("none", "/tmp", "tmpfs") {
+        log::warn!("tmpfs not mounted at /tmp: {:?}", err);
+    }
+
+    // Device nodes, and named shared memory objects (shm_open) below them
+    #[cfg(feature = "userspace")]
+    {
+        if lookup("/dev").is_err() {
+            let _ = mkdir("/dev");
+        }
+        devfs::mkdir("shm");
+        if let Err(err) = mount("/dev", Arc::new(devfs::DevFs)) {
+            log::warn!("devfs not mounted at /dev: {:?}", err);
+        }
+        if let Err(err) = mount("/dev/shm", Arc::new(shmfs::ShmFs)) {
+            log::warn!("shm not mounted at /dev/shm: {:?}", err);
+        }
+    }
+
+    if lookup("/proc").is_err() {
+        let _ = mkdir("/proc");
+    }
+    if let Err(err) = mount_type("none", "/proc", "proc") {
+        log::warn!("procfs not mounted at /proc: {:?}", err);
+    }
+    procfs::register(&MOUNTS_ENTRY);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn tmpfs_write_read_unlink() {
+        let file = open("/tmp/vfs_test", O_CREAT | O_RDWR | O_TRUNC).expect("create");
+        assert_eq!(file.write(b"hello vfs"), Ok(9));
+        assert_eq!(read_file("/tmp/vfs_test").as_deref(), Ok(&b"hello vfs"[..]));
+        assert_eq!(stat("/tmp/vfs_test").map(|m| m.size), Ok(9));
+
+        unlink("/tmp/vfs_test").expect("unlink");
+        assert_eq!(lookup("/tmp/vfs_test").err(), Some(FsError::NotFound));
+    }
+
+    #[test_case]
+    fn seeks_out_of_range_are_refused() {
+        let file = open("/tmp/vfs_seek", O_CREAT | O_RDWR | O_TRUNC).expect("create");
+        assert_eq!(
+            file.seek(SeekFrom::Start(i64::MAX as u64)),
+            Ok(i64::MAX as u64)
+        );
+        assert_eq!(
+            file.seek(SeekFrom::Current(1)),
+            Err(FsError::InvalidArgument)
+        );
+        assert_eq!(
+            file.seek(SeekFrom::Start(u64::MAX)),
+            Err(FsError::InvalidArgument)
+        );
+        assert_eq!(file.seek(SeekFrom::End(-1)), Err(FsError::InvalidArgument));
+        assert_eq!(file.seek(SeekFrom::End(i64::MAX)), Ok(i64::MAX as u64));
+        assert_eq!(
+            file.seek(SeekFrom::Current(i64::MIN)),
+            Err(FsError::InvalidArgument)
+        );
+        unlink("/tmp/vfs_seek").expect("unlink");
+    }
+
+    #[test_case]
+    fn resolve_dot_dot_and_directories() {
+        mkdir("/tmp/vfs_dir").expect("mkdir");
+        assert_eq!(
+            stat("/tmp/vfs_dir/../vfs_dir/.").map(|m| m.file_type),
+            Ok(FileType::Directory)
+        );
+        assert_eq!(
+            open("/tmp/vfs_dir", O_WRONLY).err(),
+            Some(FsError::IsADirectory)
+        );
+        rmdir("/tmp/vfs_dir").expect("rmdir");
+    }
+
+    #[test_case]
+    fn directory_listing_resumes_where_it_stopped() {
+        mkdir("/tmp/vfs_list").expect("mkdir");
+        for name in ["a", "b"] {
+            open(&format!("/tmp/vfs_list/{}", name), O_CREAT | O_RDWR).expect("create");
+        }
+        let names = |entries: Vec<DirEntry>| -> Vec<String> {
+            entries
+                .into_iter()
+                .map(|entry| entry.name)
+                .filter(|name| name != "." && name != "..")
+                .collect()
+        };
+        let dir = open("/tmp/vfs_list", O_DIRECTORY).expect("open");
+        let mut listed = names(dir.read_dir(usize::MAX).expect("read_dir"));
+        assert_eq!(listed.len(), 2);
+        // One at a time from a rewound position, like getdents with a small buffer
+        dir.seek(SeekFrom::Start(0)).expect("rewind");
+        let first = dir.read_dir(1).expect("read_dir");
+        open("/tmp/vfs_list/c", O_CREAT | O_RDWR).expect("create");
+        let mut rest = first;
+        while let Some(entry) = dir.read_dir(1).expect("read_dir").pop() {
+            rest.push(entry);
+        }
+        assert_eq!(names(rest), listed);
+        dir.seek(SeekFrom::Start(0)).expect("rewind");
+        listed.push(String::from("c"));
+        let mut again = names(dir.read_dir(usize::MAX).expect("read_dir"));
+        again.sort();
+        listed.sort();
+        assert_eq!(again, listed);
+        for name in ["a", "b", "c"] {
+            unlink(&format!("/tmp/vfs_list/{}", name)).expect("unlink");
+        }
+        rmdir("/tmp/vfs_list").expect("rmdir");
+    }
+}
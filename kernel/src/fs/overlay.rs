@@ -0,0 +1,284 @@
+// Overlay Filesystem
+// Combines a read-only lower layer (initramfs / ISO image) with a writable upper
+// layer (tmpfs) so the tree appears fully writable without modifying the base image.
+//
+//   - Lookups prefer the upper layer, directories present in both layers are merged
+//   - Writing to a lower-only file first copies it (and its parent directories) up
+//   - Removing a lower entry records a whiteout that hides it from the merged view,
+//     a directory re-created over a whiteout is opaque (hides the lower contents)
+//
+// Whiteouts live in the overlay nodes rather than the upper filesystem, they last as
+// long as the mount (the upper tmpfs does not survive a reboot either).
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsResult, Inode, InodeRef, Metadata};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+// Overlay inode numbers, stable for the lifetime of a node
+static NEXT_INO: AtomicU64 = AtomicU64::new(1);
+
+// Chunk size used when copying a file up
+const COPY_CHUNK: usize = 4096;
+
+pub struct OverlayNode {
+    ino: u64,
+    name: String,
+    this: Weak<OverlayNode>,
+    parent: Option<Arc<OverlayNode>>,
+    lower: Option<InodeRef>,
+    upper: Mutex<Option<InodeRef>>,
+    opaque: bool,
+    // Nodes already looked up, so copy-ups and whiteouts stay attached to one node
+    children: Mutex<BTreeMap<String, Arc<OverlayNode>>>,
+    whiteouts: Mutex<BTreeSet<String>>,
+}
+
+impl OverlayNode {
+    fn new(
+        name: &str,
+        parent: Option<Arc<OverlayNode>>,
+        lower: Option<InodeRef>,
+        upper: Option<InodeRef>,
+        opaque: bool,
+    ) -> Arc<Self> {
+        Arc::new_cyclic(|this| Self {
+            ino: NEXT_INO.fetch_add(1, Ordering::Relaxed),
+            name: String::from(name),
+            this: this.clone(),
+            parent,
+            lower,
+            upper: Mutex::new(upper),
+            opaque,
+            children: Mutex::new(BTreeMap::new()),
+            whiteouts: Mutex::new(BTreeSet::new()),
+        })
+    }
+
+    fn upper(&self) -> Option<InodeRef> {
+        self.upper.lock().clone()
+    }
+
+    // The layer that currently provides this node
+    fn top(&self) -> InodeRef {
+        self.upper()
+            .or_else(|| self.lower.clone())
+            .expect("overlay node without layers")
+    }
+
+    fn file_type(&self) -> FileType {
+        self.top().metadata().file_type
+    }
+
+    // Make sure this node exists in the upper layer, copying it up if needed
+    fn copy_up(&self) -> FsResult<InodeRef> {
+        if let Some(upper) = self.upper() {
+            return Ok(upper);
+        }
+
+        let lower = self.lower.clone().ok_or(FsError::Io)?;
+        let parent = self.parent.as_ref().ok_or(FsError::Io)?;
+        let parent_upper = parent.copy_up()?;
+
+        let file_type = lower.metadata().file_type;
+        let upper = match parent_upper.lookup(&self.name) {
+            Ok(existing) => existing,
+            Err(FsError::NotFound) => parent_upper.create(&self.name, file_type)?,
+            Err(err) => return Err(err),
+        };
+
+        if file_type == FileType::Regular {
+            let mut buf = vec![0u8; COPY_CHUNK];
+            let mut offset = 0;
+            loop {
+                let count = lower.read_at(offset, &mut buf)?;
+                if count == 0 {
+                    break;
+                }
+                upper.write_at(offset, &buf[..count])?;
+                offset += count as u64;
+            }
+        }
+
+        *self.upper.lock() = Some(upper.clone());
+        Ok(upper)
+    }
+
+    fn lookup_node(&self, name: &str) -> FsResult<Arc<OverlayNode>> {
+        if self.file_type() != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        if self.whiteouts.lock().contains(name) {
+            return Err(FsError::NotFound);
+        }
+        if let Some(child) = self.children.lock().get(name) {
+            return Ok(child.clone());
+        }
+
+        let upper = match self.upper() {
+            Some(dir) => optional(dir.lookup(name))?,
+            None => None,
+        };
+
+        // The lower entry is only visible if nothing hides it
+        let lower_visible = !self.opaque
+            && upper
+                .as_ref()
+                .is_none_or(|u| u.metadata().file_type == FileType::Directory);
+        let lower = match &self.lower {
+            Some(dir) if lower_visible => optional(dir.lookup(name))?,
+            _ => None,
+        };
+
+        // Only directories merge, an upper directory hides a lower file
+        let lower =
+            lower.filter(|l| upper.is_none() || l.metadata().file_type == FileType::Directory);
+
+        if upper.is_none() && lower.is_none() {
+            return Err(FsError::NotFound);
+        }
+
+        let child = OverlayNode::new(name, self.this.upgrade(), lower, upper, false);
+        self.children
+            .lock()
+            .insert(String::from(name), child.clone());
+        Ok(child)
+    }
+
+    // Names visible in the merged directory
+    fn merged_names(&self) -> FsResult<BTreeSet<String>> {
+        let mut names = BTreeSet::new();
+
+        if let Some(upper) = self.upper() {
+            names.extend(upper.readdir()?.into_iter().map(|e| e.name));
+        }
+        if let Some(lower) = &self.lower
+            && !self.opaque
+        {
+            names.extend(lower.readdir()?.into_iter().map(|e| e.name));
+        }
+
+        let whiteouts = self.whiteouts.lock();
+        names.retain(|name| !whiteouts.contains(name));
+        Ok(names)
+    }
+}
+
+// Turn NotFound into None
+fn optional(result: FsResult<InodeRef>) -> FsResult<Option<InodeRef>> {
+    match result {
+        Ok(inode) => Ok(Some(inode)),
+        Err(FsError::NotFound) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+impl Inode for OverlayNode {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino: self.ino,
+            ..self.top().metadata()
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        self.top().read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<usize> {
+        self.copy_up()?.write_at(offset, buf)
+    }
+
+    fn truncate(&self, size: u64) -> FsResult<()> {
+        self.copy_up()?.truncate(size)
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<InodeRef> {
+        Ok(self.lookup_node(name)?)
+    }
+
+    fn readdir(&self) -> FsResult<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        for name in self.merged_names()? {
+            let node = self.lookup_node(&name)?;
+            entries.push(DirEntry {
+                ino: node.ino,
+                file_type: node.file_type(),
+                name,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> FsResult<InodeRef> {
+        match self.lookup_node(name) {
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => {}
+            Err(err) => return Err(err),
+        }
+
+        let upper = self.copy_up()?.create(name, file_type)?;
+
+        // Re-creating a removed lower directory must not bring its old contents back
+        let opaque = self.whiteouts.lock().remove(name);
+        let child = OverlayNode::new(name, self.this.upgrade(), None, Some(upper), opaque);
+        self.children
+            .lock()
+            .insert(String::from(name), child.clone());
+        Ok(child)
+    }
+
+    #[cfg(feature = "userspace")]
+    fn unlink(&self, name: &str) -> FsResult<()> {
+        let node = self.lookup_node(name)?;
+        if node.file_type() == FileType::Directory && !node.merged_names()?.is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+
+        if node.upper().is_some() {
+            self.copy_up()?.unlink(name)?;
+        }
+
+        // Hide any lower entry of that name, including one the upper entry was covering
+        let in_lower = match &self.lower {
+            Some(dir) if !self.opaque => optional(dir.lookup(name))?.is_some(),
+            _ => false,
+        };
+        if in_lower {
+            self.whiteouts.lock().insert(String::from(name));
+        }
+
+        self.children.lock().remove(name);
+        Ok(())
+    }
+
+    fn read_link(&self) -> FsResult<String> {
+        self.top().read_link()
+    }
+}
+
+pub struct OverlayFs {
+    root: Arc<OverlayNode>,
+}
+
+impl OverlayFs {
+    pub fn new(lower: InodeRef, upper: InodeRef) -> Self {
+        Self {
+            root: OverlayNode::new("", None, Some(lower), Some(upper), false),
+        }
+    }
+}
+
+impl FileSystem for OverlayFs {
+    fn name(&self) -> &'static str {
+        "overlay"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+}
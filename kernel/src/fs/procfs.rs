@@ -0,0 +1,130 @@
+// procfs
+// A flat directory of generated files mounted at /proc. Subsystems register a
+// `ProcEntry` whose contents are produced on every read; entries with a `write`
+// handler accept commands (e.g. `echo serial > /proc/net_trace`).
+// Generated files report size 0, like Linux, and are read until EOF.
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsResult, Inode, InodeRef, Metadata};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub struct ProcEntry {
+    pub name: &'static str,
+    pub read: fn() -> String,
+    pub write: Option<fn(&str) -> FsResult<()>>,
+}
+
+static ENTRIES: Mutex<Vec<&'static ProcEntry>> = Mutex::new(Vec::new());
+
+pub fn register(entry: &'static ProcEntry) {
+    interrupts::without_interrupts(|| ENTRIES.lock().push(entry));
+}
+
+fn find(name: &str) -> Option<(usize, &'static ProcEntry)> {
+    interrupts::without_interrupts(|| {
+        ENTRIES
+            .lock()
+            .iter()
+            .enumerate()
+            .find(|(_, e)| e.name == name)
+            .map(|(i, e)| (i, *e))
+    })
+}
+
+// Inode numbers: 1 for the root, 2 + index for entries
+struct ProcRoot;
+
+struct ProcFile {
+    ino: u64,
+    entry: &'static ProcEntry,
+}
+
+impl Inode for ProcRoot {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino: 1,
+            file_type: FileType::Directory,
+            size: 0,
+            mode: 0o555,
+            nlink: 2,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<InodeRef> {
+        let (index, entry) = find(name).ok_or(FsError::NotFound)?;
+        Ok(Arc::new(ProcFile {
+            ino: 2 + index as u64,
+            entry,
+        }))
+    }
+
+    fn readdir(&self) -> FsResult<Vec<DirEntry>> {
+        Ok(interrupts::without_interrupts(|| {
+            ENTRIES
+                .lock()
+                .iter()
+                .enumerate()
+                .map(|(i, e)| DirEntry {
+                    name: String::from(e.name),
+                    ino: 2 + i as u64,
+                    file_type: FileType::Regular,
+                })
+                .collect()
+        }))
+    }
+}
+
+impl Inode for ProcFile {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino: self.ino,
+            file_type: FileType::Regular,
+            size: 0,
+            mode: if self.entry.write.is_some() {
+                0o644
+            } else {
+                0o444
+            },
+            nlink: 1,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let contents = (self.entry.read)();
+        let data = contents.as_bytes();
+        let start = (offset as usize).min(data.len());
+        let count = buf.len().min(data.len() - start);
+        buf[..count].copy_from_slice(&data[start..start + count]);
+        Ok(count)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> FsResult<usize> {
+        let write = self.entry.write.ok_or(FsError::ReadOnly)?;
+        let command = core::str::from_utf8(buf).map_err(|_| FsError::InvalidArgument)?;
+        write(command.trim())?;
+        Ok(buf.len())
+    }
+
+    // Opening with O_TRUNC (shell redirection) is accepted and ignored
+    fn truncate(&self, _size: u64) -> FsResult<()> {
+        match self.entry.write {
+            Some(_) => Ok(()),
+            None => Err(FsError::ReadOnly),
+        }
+    }
+}
+
+pub struct ProcFs;
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "proc"
+    }
+
+    fn root(&self) -> InodeRef {
+        Arc::new(ProcRoot)
+    }
+}
@@ -0,0 +1,190 @@
+// ramfs / tmpfs
+// A writable filesystem whose files and directories live entirely on the kernel heap.
+// Mounted at /tmp so user programs have somewhere to write before persistent storage exists.
+// Contents are lost on reboot. A file grows to MAX_FILE_SIZE at most (EFBIG past
+// it), and fails with ENOSPC when the heap cannot hold it.
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsResult, Inode, InodeRef, Metadata};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+// Largest file, a quarter of the most the heap grows to
+const MAX_FILE_SIZE: usize = crate::heap_allocator::KERNEL_HEAP_MAX / 4;
+
+// Inode numbers are unique across all ramfs instances
+static NEXT_INO: AtomicU64 = AtomicU64::new(1);
+
+enum RamKind {
+    File(Mutex<Vec<u8>>),
+    Directory(Mutex<BTreeMap<String, Arc<RamNode>>>),
+}
+
+pub struct RamNode {
+    ino: u64,
+    mode: u32,
+    kind: RamKind,
+}
+
+// Grow (with zeros) or shrink file contents, within MAX_FILE_SIZE and the heap
+fn resize(data: &mut Vec<u8>, size: u64) -> FsResult<()> {
+    if size > MAX_FILE_SIZE as u64 {
+        return Err(FsError::FileTooLarge);
+    }
+    let size = size as usize;
+    if size > data.len() {
+        data.try_reserve_exact(size - data.len())
+            .map_err(|_| FsError::NoSpace)?;
+    }
+    data.resize(size, 0);
+    Ok(())
+}
+
+impl RamNode {
+    fn new(file_type: FileType) -> FsResult<Arc<Self>> {
+        let (kind, mode) = match file_type {
+            FileType::Regular => (RamKind::File(Mutex::new(Vec::new())), 0o644),
+            FileType::Directory => (RamKind::Directory(Mutex::new(BTreeMap::new())), 0o755),
+            _ => return Err(FsError::NotSupported),
+        };
+
+        Ok(Arc::new(Self {
+            ino: NEXT_INO.fetch_add(1, Ordering::Relaxed),
+            mode,
+            kind,
+        }))
+    }
+
+    fn children(&self) -> FsResult<&Mutex<BTreeMap<String, Arc<RamNode>>>> {
+        match &self.kind {
+            RamKind::Directory(children) => Ok(children),
+            RamKind::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn data(&self) -> FsResult<&Mutex<Vec<u8>>> {
+        match &self.kind {
+            RamKind::File(data) => Ok(data),
+            RamKind::Directory(_) => Err(FsError::IsADirectory),
+        }
+    }
+}
+
+impl Inode for RamNode {
+    fn metadata(&self) -> Metadata {
+        let (file_type, size, nlink) = match &self.kind {
+            RamKind::File(data) => (FileType::Regular, data.lock().len() as u64, 1),
+            RamKind::Directory(children) => (FileType::Directory, 0, 2 + children.lock().len()),
+        };
+
+        Metadata {
+            ino: self.ino,
+            file_type,
+            size,
+            mode: self.mode,
+            nlink: nlink as u32,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let data = self.data()?.lock();
+        let start = (offset as usize).min(data.len());
+        let count = buf.len().min(data.len() - start);
+        buf[..count].copy_from_slice(&data[start..start + count]);
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<usize> {
+        let mut data = self.data()?.lock();
+        let end = offset.saturating_add(buf.len() as u64);
+
+        // Writing past the end fills the gap with zeros
+        if end > data.len() as u64 {
+            resize(&mut data, end)?;
+        }
+        data[offset as usize..end as usize].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> FsResult<()> {
+        resize(&mut self.data()?.lock(), size)
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<InodeRef> {
+        self.children()?
+            .lock()
+            .get(name)
+            .map(|node| node.clone() as InodeRef)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn readdir(&self) -> FsResult<Vec<DirEntry>> {
+        let children = self.children()?.lock();
+        Ok(children
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                ino: node.ino,
+                file_type: node.metadata().file_type,
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> FsResult<InodeRef> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let mut children = self.children()?.lock();
+        if children.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let node = RamNode::new(file_type)?;
+        children.insert(String::from(name), node.clone());
+        Ok(node)
+    }
+
+    #[cfg(feature = "userspace")]
+    fn unlink(&self, name: &str) -> FsResult<()> {
+        let mut children = self.children()?.lock();
+        let node = children.get(name).ok_or(FsError::NotFound)?;
+
+        // Directories must be empty before removal
+        if let RamKind::Directory(grandchildren) = &node.kind
+            && !grandchildren.lock().is_empty()
+        {
+            return Err(FsError::NotEmpty);
+        }
+
+        children.remove(name);
+        Ok(())
+    }
+}
+
+pub struct RamFs {
+    name: &'static str,
+    root: Arc<RamNode>,
+}
+
+impl RamFs {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            root: RamNode::new(FileType::Directory).expect("ramfs root"),
+        }
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+}
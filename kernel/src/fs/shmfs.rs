@@ -0,0 +1,371 @@
+// Shared memory objects
+// `SharedMemory` is memory kept in whole page frames, so several mappings can
+// show the very same frames: a store through one mapping is seen by every
+// other one, and by read/write on the object, without any copying. It backs
+// MAP_SHARED mappings (see syscalls::sys_mmap): an anonymous one gets an object
+// of its own, a file one maps the object of a file in /dev/shm.
+//
+// /dev/shm is a flat filesystem of such objects, where shm_open() creates its
+// named objects (glibc and musl open "/dev/shm/<name>"). Named objects outlive
+// the programs that used them, until unlinked. Mappings hold the object (see
+// `Mapping`), so its frames go back to the PMM once it is unlinked and no longer
+// mapped or open; frames cut off by shrinking a mapped object wait for that too,
+// so pages still mapped stay valid. An object holds at most a quarter of the
+// physical memory; growth that runs out of frames gives back those it took.
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsResult, Inode, InodeRef, Metadata};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::PhysAddr;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{FrameDeallocator, PhysFrame, Size4KiB};
+
+const PAGE_SIZE: u64 = 4096;
+
+// An object takes at most this share of the physical memory
+const MAX_SHARE_OF_MEMORY: u64 = 4;
+
+// Inode numbers: 1 for the root, objects from 2 on
+static NEXT_INO: AtomicU64 = AtomicU64::new(2);
+
+struct Pages {
+    frames: Vec<PhysFrame<Size4KiB>>,
+    // Frames cut off while mapped, freed with the object
+    retired: Vec<PhysFrame<Size4KiB>>,
+    size: u64,
+}
+
+pub struct SharedMemory {
+    ino: u64,
+    pages: Mutex<Pages>,
+    // Live `Mapping`s of the object
+    mappings: AtomicUsize,
+}
+
+fn frame_ptr(frame: PhysFrame<Size4KiB>) -> *mut u8 {
+    (frame.start_address().as_u64() + crate::elf_loader::get_hhdm_offset()) as *mut u8
+}
+
+impl SharedMemory {
+    pub fn new(size: u64) -> FsResult<Arc<Self>> {
+        let object = Arc::new(Self {
+            ino: NEXT_INO.fetch_add(1, Ordering::Relaxed),
+            pages: Mutex::new(Pages {
+                frames: Vec::new(),
+                retired: Vec::new(),
+                size: 0,
+            }),
+            mappings: AtomicUsize::new(0),
+        });
+        object.resize(size)?;
+        Ok(object)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.pages.lock().size
+    }
+
+    // Grow with zeroed frames, or shrink, zeroing what is left of the last page.
+    // EFBIG past the largest object, ENOSPC (and no growth) when the frames
+    // run out
+    pub fn resize(&self, size: u64) -> FsResult<()> {
+        if size > max_size() {
+            return Err(FsError::FileTooLarge);
+        }
+        let mut pages = self.pages.lock();
+        let count = size.div_ceil(PAGE_SIZE) as usize;
+        let had = pages.frames.len();
+        pages
+            .frames
+            .try_reserve(count.saturating_sub(had))
+            .map_err(|_| FsError::NoSpace)?;
+        while pages.frames.len() < count {
+            let Some(phys) = crate::pmm::allocate_frame() else {
+                let grown = pages.frames.split_off(had);
+                free(grown);
+                return Err(FsError::NoSpace);
+            };
+            let frame = PhysFrame::containing_address(PhysAddr::new(phys));
+            unsafe { core::ptr::write_bytes(frame_ptr(frame), 0, PAGE_SIZE as usize) };
+            pages.frames.push(frame);
+        }
+        let kept = count.min(pages.frames.len());
+        let cut = pages.frames.split_off(kept);
+        if self.mappings.load(Ordering::Relaxed) == 0 {
+            free(cut);
+        } else {
+            pages.retired.extend(cut);
+        }
+        if size < pages.size && !size.is_multiple_of(PAGE_SIZE) {
+            let tail = (size % PAGE_SIZE) as usize;
+            let last = frame_ptr(pages.frames[count - 1]);
+            unsafe { core::ptr::write_bytes(last.add(tail), 0, PAGE_SIZE as usize - tail) };
+        }
+        pages.size = size;
+        Ok(())
+    }
+
+    // Frame holding byte `index * PAGE_SIZE` of the object
+    pub fn frame(&self, index: u64) -> Option<PhysFrame<Size4KiB>> {
+        self.pages.lock().frames.get(index as usize).copied()
+    }
+
+    // Hold the object for an area mapping its frames
+    pub fn map(self: &Arc<Self>) -> Mapping {
+        self.mappings.fetch_add(1, Ordering::Relaxed);
+        Mapping(self.clone())
+    }
+
+    // Copy between the object at `offset` and `buf`, page by page
+    fn copy(&self, offset: u64, len: usize, mut f: impl FnMut(*mut u8, usize, usize)) -> usize {
+        let pages = self.pages.lock();
+        let end = offset.saturating_add(len as u64).min(pages.size);
+        let mut pos = offset;
+        while pos < end {
+            let in_page = pos % PAGE_SIZE;
+            let count = (PAGE_SIZE - in_page).min(end - pos) as usize;
+            let data = frame_ptr(pages.frames[(pos / PAGE_SIZE) as usize]);
+            f(
+                unsafe { data.add(in_page as usize) },
+                (pos - offset) as usize,
+                count,
+            );
+            pos += count as u64;
+        }
+        end.saturating_sub(offset) as usize
+    }
+}
+
+fn max_size() -> u64 {
+    crate::pmm::stats().total as u64 * PAGE_SIZE / MAX_SHARE_OF_MEMORY
+}
+
+fn free(frames: Vec<PhysFrame<Size4KiB>>) {
+    for frame in frames {
+        unsafe { crate::pmm::KernelFrameAllocator.deallocate_frame(frame) };
+    }
+}
+
+// Dropped once unlinked, closed and unmapped: nothing shows the frames anymore
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        let pages = self.pages.get_mut();
+        free(core::mem::take(&mut pages.frames));
+        free(core::mem::take(&mut pages.retired));
+    }
+}
+
+// An object's frames mapped by an area (vma::Vma::object); the area's pieces
+// after a split each hold a clone
+pub struct Mapping(Arc<SharedMemory>);
+
+impl Clone for Mapping {
+    fn clone(&self) -> Self {
+        self.0.map()
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        self.0.mappings.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl PartialEq for Mapping {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Mapping {}
+
+impl fmt::Debug for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Mapping(ino {})", self.0.ino)
+    }
+}
+
+impl Inode for SharedMemory {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino: self.ino,
+            file_type: FileType::Regular,
+            size: self.size(),
+            mode: 0o600,
+            nlink: 1,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        Ok(self.copy(offset, buf.len(), |page, at, count| unsafe {
+            core::ptr::copy_nonoverlapping(page, buf[at..].as_mut_ptr(), count)
+        }))
+    }
+
+    // Writing past the end grows the object, like a tmpfs file
+    fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<usize> {
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(FsError::FileTooLarge)?;
+        if end > self.size() {
+            self.resize(end)?;
+        }
+        Ok(self.copy(offset, buf.len(), |page, at, count| unsafe {
+            core::ptr::copy_nonoverlapping(buf[at..].as_ptr(), page, count)
+        }))
+    }
+
+    fn truncate(&self, size: u64) -> FsResult<()> {
+        self.resize(size)
+    }
+
+    fn shared_memory(self: Arc<Self>) -> Option<Arc<SharedMemory>> {
+        Some(self)
+    }
+}
+
+// The named objects of /dev/shm
+static OBJECTS: Mutex<BTreeMap<String, Arc<SharedMemory>>> = Mutex::new(BTreeMap::new());
+
+fn with_objects<R>(f: impl FnOnce(&mut BTreeMap<String, Arc<SharedMemory>>) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut OBJECTS.lock()))
+}
+
+struct ShmRoot;
+
+impl Inode for ShmRoot {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino: 1,
+            file_type: FileType::Directory,
+            size: 0,
+            mode: 0o1777,
+            nlink: 2,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<InodeRef> {
+        with_objects(|objects| {
+            objects
+                .get(name)
+                .map(|object| object.clone() as InodeRef)
+                .ok_or(FsError::NotFound)
+        })
+    }
+
+    fn readdir(&self) -> FsResult<Vec<DirEntry>> {
+        Ok(with_objects(|objects| {
+            objects
+                .iter()
+                .map(|(name, object)| DirEntry {
+                    name: name.clone(),
+                    ino: object.ino,
+                    file_type: FileType::Regular,
+                })
+                .collect()
+        }))
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> FsResult<InodeRef> {
+        if file_type != FileType::Regular {
+            return Err(FsError::NotSupported);
+        }
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(FsError::InvalidArgument);
+        }
+        let object = SharedMemory::new(0)?;
+        with_objects(|objects| {
+            if objects.contains_key(name) {
+                return Err(FsError::AlreadyExists);
+            }
+            objects.insert(String::from(name), object.clone());
+            Ok(object as InodeRef)
+        })
+    }
+
+    // Existing mappings and open files keep the object
+    fn unlink(&self, name: &str) -> FsResult<()> {
+        with_objects(|objects| objects.remove(name).map(|_| ()).ok_or(FsError::NotFound))
+    }
+}
+
+pub struct ShmFs;
+
+impl FileSystem for ShmFs {
+    fn name(&self) -> &'static str {
+        "shm"
+    }
+
+    fn root(&self) -> InodeRef {
+        Arc::new(ShmRoot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn shm_object_shares_its_frames() {
+        let object = SharedMemory::new(4096 + 10).expect("object");
+        assert!(object.frame(1).is_some() && object.frame(2).is_none());
+
+        // A write through the object lands in the frame a mapping would show
+        assert_eq!(object.write_at(4096, b"ab"), Ok(2));
+        let second = frame_ptr(object.frame(1).unwrap());
+        assert_eq!(unsafe { *second.add(1) }, b'b');
+
+        // Shrinking zeroes the cut-off tail of the last page
+        object.resize(4096 + 1).expect("shrink");
+        object.resize(4096 + 10).expect("grow");
+        let mut buf = [0xFF; 2];
+        assert_eq!(object.read_at(4096, &mut buf), Ok(2));
+        assert_eq!(buf, [b'a', 0]);
+    }
+
+    #[test_case]
+    fn offsets_past_the_largest_object_are_refused() {
+        let object = SharedMemory::new(4096).expect("object");
+        assert_eq!(
+            object.write_at(u64::MAX - 1, b"ab"),
+            Err(FsError::FileTooLarge)
+        );
+        assert_eq!(object.read_at(u64::MAX - 1, &mut [0; 2]), Ok(0));
+        assert_eq!(object.size(), 4096);
+    }
+
+    #[test_case]
+    fn objects_cannot_take_all_memory() {
+        let object = SharedMemory::new(4096).expect("object");
+        let free = crate::pmm::stats().free;
+        assert_eq!(object.resize(max_size() + 1), Err(FsError::FileTooLarge));
+        assert_eq!(object.size(), 4096);
+        assert_eq!(crate::pmm::stats().free, free);
+    }
+
+    #[test_case]
+    fn frames_are_freed_once_unmapped() {
+        let free = || crate::pmm::stats().free;
+        let object = SharedMemory::new(3 * 4096).expect("object");
+        let before = free();
+
+        // Frames cut off under a mapping stay allocated...
+        let mapping = object.map();
+        object.resize(4096).expect("shrink");
+        assert_eq!(free(), before);
+
+        // ...unmapped, shrinking frees at once
+        drop(mapping);
+        object.resize(0).expect("shrink");
+        assert_eq!(free(), before + 1);
+
+        // The last reference gives back the rest
+        drop(object);
+        assert_eq!(free(), before + 3);
+    }
+}
@@ -0,0 +1,304 @@
+// Tar-based initramfs
+// Parses a ustar archive loaded by the bootloader into a read-only in-memory filesystem.
+// File contents are not copied: regular file inodes point directly into the archive,
+// which lives in LOADER_DATA memory that the PMM never hands out.
+//
+// ustar header layout (512 bytes):
+//   0   name[100]      124 size[12] (octal)   156 typeflag
+//   100 mode[8]        257 magic "ustar"      157 linkname[100]
+//   345 prefix[155]    (file data follows, padded to 512 bytes)
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsResult, Inode, InodeRef, Metadata};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const BLOCK_SIZE: usize = 512;
+
+// Directories other filesystems get mounted on
+const MOUNT_POINTS: &[&str] = &["tmp"];
+
+// Type flags
+const TYPE_REGULAR: u8 = b'0';
+const TYPE_REGULAR_OLD: u8 = 0;
+const TYPE_HARDLINK: u8 = b'1';
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_DIRECTORY: u8 = b'5';
+
+enum NodeKind {
+    File(&'static [u8]),
+    Directory(Mutex<BTreeMap<String, Arc<TarNode>>>),
+    Symlink(String),
+}
+
+pub struct TarNode {
+    ino: u64,
+    mode: u32,
+    kind: NodeKind,
+}
+
+impl TarNode {
+    fn new_dir(ino: u64, mode: u32) -> Arc<Self> {
+        Arc::new(Self {
+            ino,
+            mode,
+            kind: NodeKind::Directory(Mutex::new(BTreeMap::new())),
+        })
+    }
+
+    fn children(&self) -> FsResult<&Mutex<BTreeMap<String, Arc<TarNode>>>> {
+        match &self.kind {
+            NodeKind::Directory(children) => Ok(children),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+}
+
+impl Inode for TarNode {
+    fn metadata(&self) -> Metadata {
+        let (file_type, size, nlink) = match &self.kind {
+            NodeKind::File(data) => (FileType::Regular, data.len() as u64, 1),
+            NodeKind::Directory(children) => (FileType::Directory, 0, 2 + children.lock().len()),
+            NodeKind::Symlink(target) => (FileType::Symlink, target.len() as u64, 1),
+        };
+
+        Metadata {
+            ino: self.ino,
+            file_type,
+            size,
+            mode: self.mode,
+            nlink: nlink as u32,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let NodeKind::File(data) = &self.kind else {
+            return Err(FsError::IsADirectory);
+        };
+
+        let start = (offset as usize).min(data.len());
+        let count = buf.len().min(data.len() - start);
+        buf[..count].copy_from_slice(&data[start..start + count]);
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<InodeRef> {
+        let children = self.children()?.lock();
+        children
+            .get(name)
+            .map(|node| node.clone() as InodeRef)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn readdir(&self) -> FsResult<Vec<DirEntry>> {
+        let children = self.children()?.lock();
+        Ok(children
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                ino: node.ino,
+                file_type: node.metadata().file_type,
+            })
+            .collect())
+    }
+
+    fn read_link(&self) -> FsResult<String> {
+        match &self.kind {
+            NodeKind::Symlink(target) => Ok(target.clone()),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+}
+
+pub struct TarFs {
+    root: Arc<TarNode>,
+}
+
+impl FileSystem for TarFs {
+    fn name(&self) -> &'static str {
+        "initramfs"
+    }
+
+    fn root(&self) -> InodeRef {
+        self.root.clone()
+    }
+}
+
+// Parse a NUL/space terminated octal field
+fn parse_octal(field: &[u8]) -> u64 {
+    field
+        .iter()
+        .skip_while(|&&b| b == b' ')
+        .take_while(|&&b| (b'0'..=b'7').contains(&b))
+        .fold(0, |acc, &b| acc * 8 + (b - b'0') as u64)
+}
+
+// Parse a NUL terminated string field
+fn parse_str(field: &[u8]) -> &str {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).unwrap_or("")
+}
+
+impl TarFs {
+    // Build the filesystem tree from an archive
+    pub fn parse(archive: &'static [u8]) -> FsResult<Self> {
+        let mut next_ino = 1;
+        let root = TarNode::new_dir(next_ino, 0o755);
+        let mut offset = 0;
+
+        while offset + BLOCK_SIZE <= archive.len() {
+            let header = &archive[offset..offset + BLOCK_SIZE];
+
+            // Two zero blocks terminate the archive
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+            if &header[257..262] != b"ustar" {
+                return Err(FsError::Io);
+            }
+
+            let size = parse_octal(&header[124..136]) as usize;
+            let mode = parse_octal(&header[100..108]) as u32 & 0o7777;
+            let type_flag = header[156];
+
+            let prefix = parse_str(&header[345..500]);
+            let name = parse_str(&header[0..100]);
+            let mut path = String::from(prefix);
+            if !prefix.is_empty() {
+                path.push('/');
+            }
+            path.push_str(name);
+
+            let data_start = offset + BLOCK_SIZE;
+            let data_end = data_start + size;
+            if data_end > archive.len() {
+                return Err(FsError::Io);
+            }
+
+            // The archive root itself ("./") is our root directory
+            if path.split('/').all(|c| c.is_empty() || c == ".") {
+                offset = data_start + size.next_multiple_of(BLOCK_SIZE);
+                continue;
+            }
+
+            next_ino += 1;
+            let ino = next_ino;
+
+            let kind = match type_flag {
+                TYPE_REGULAR | TYPE_REGULAR_OLD => {
+                    Some(NodeKind::File(&archive[data_start..data_end]))
+                }
+                TYPE_DIRECTORY => None,
+                TYPE_SYMLINK => Some(NodeKind::Symlink(String::from(parse_str(
+                    &header[157..257],
+                )))),
+                TYPE_HARDLINK => {
+                    // Share the data of the link target (must appear earlier in the archive)
+                    let target = find(&root, parse_str(&header[157..257]))?;
+                    match &target.kind {
+                        NodeKind::File(data) => Some(NodeKind::File(data)),
+                        _ => return Err(FsError::Io),
+                    }
+                }
+                _ => {
+                    // Device nodes, FIFOs, GNU extensions: skip
+                    offset = data_start + size.next_multiple_of(BLOCK_SIZE);
+                    continue;
+                }
+            };
+
+            let (parent, file_name) = make_parents(&root, &path, &mut next_ino)?;
+            let mut children = parent.children()?.lock();
+
+            match kind {
+                Some(kind) => {
+                    children.insert(
+                        String::from(file_name),
+                        Arc::new(TarNode { ino, mode, kind }),
+                    );
+                }
+                None => {
+                    // Directories may already exist if a file inside was listed first
+                    children
+                        .entry(String::from(file_name))
+                        .or_insert_with(|| TarNode::new_dir(ino, mode));
+                }
+            }
+
+            offset = data_start + size.next_multiple_of(BLOCK_SIZE);
+        }
+
+        // Make sure the kernel's mount points exist even if the archive lacks them
+        for &dir in MOUNT_POINTS {
+            root.children()?
+                .lock()
+                .entry(String::from(dir))
+                .or_insert_with(|| {
+                    next_ino += 1;
+                    TarNode::new_dir(next_ino, 0o755)
+                });
+        }
+
+        Ok(Self { root })
+    }
+}
+
+// Find a node by archive path
+fn find(root: &Arc<TarNode>, path: &str) -> FsResult<Arc<TarNode>> {
+    let mut node = root.clone();
+    for component in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+        let next = node
+            .children()?
+            .lock()
+            .get(component)
+            .cloned()
+            .ok_or(FsError::NotFound)?;
+        node = next;
+    }
+    Ok(node)
+}
+
+// Create the missing parent directories of `path`
+// Returns the parent directory and the final component
+fn make_parents<'a>(
+    root: &Arc<TarNode>,
+    path: &'a str,
+    next_ino: &mut u64,
+) -> FsResult<(Arc<TarNode>, &'a str)> {
+    let mut components: Vec<&str> = path
+        .split('/')
+        .filter(|c| !c.is_empty() && *c != ".")
+        .collect();
+    let name = components.pop().ok_or(FsError::InvalidArgument)?;
+
+    let mut node = root.clone();
+    for component in components {
+        let next = node
+            .children()?
+            .lock()
+            .entry(String::from(component))
+            .or_insert_with(|| {
+                *next_ino += 1;
+                TarNode::new_dir(*next_ino, 0o755)
+            })
+            .clone();
+        node = next;
+    }
+
+    Ok((node, name))
+}
+
+// Parse the initramfs loaded by the bootloader and mount it at "/"
+pub fn mount_initramfs(phys_addr: u64, size: u64, hhdm_offset: u64) -> FsResult<()> {
+    let archive = unsafe {
+        core::slice::from_raw_parts((phys_addr + hhdm_offset) as *const u8, size as usize)
+    };
+
+    let fs = TarFs::parse(archive)?;
+    let entries = fs.root.readdir().map(|e| e.len()).unwrap_or(0);
+    log::info!("{} bytes, {} top-level entries", size, entries);
+
+    super::mount("/", Arc::new(fs))
+}
@@ -0,0 +1,256 @@
+// timerfd
+// A timer behind a file descriptor, as on Linux: timerfd_settime arms it for one
+// expiration or periodically, read(2) returns the 8-byte number of expirations
+// since the last read (waiting for the first one unless the file is non-blocking,
+// EAGAIN), and poll reports POLLIN while that number is non-zero.
+//
+// Nothing runs when a timer expires: the state is brought up to date against the
+// clock whenever it is looked at, and a waiting reader is woken by the timer
+// interrupt like any other `idle::wait_for` caller. The alarm clocks also arm the
+// RTC alarm for each expiration, so a timer can wake the machine from S3.
+
+use super::{DirEntry, File, FileType, FsError, FsResult, Metadata, POLLIN, SeekFrom};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    // Wall clock (CLOCK_REALTIME)
+    Realtime,
+    // Time since boot, without time suspended (CLOCK_MONOTONIC)
+    Monotonic,
+    // Time since boot, including time suspended (CLOCK_BOOTTIME)
+    Boottime,
+}
+
+impl Clock {
+    pub fn now_ns(self) -> u64 {
+        match self {
+            Clock::Realtime => crate::rtc::realtime_ns(),
+            Clock::Monotonic => crate::interrupts::uptime_ns(),
+            Clock::Boottime => crate::rtc::boottime_ns(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct TimerState {
+    deadline: Option<u64>, // next expiration on the timer's clock (ns)
+    interval: u64,         // period (ns), 0 for a one-shot timer
+    expirations: u64,      // since the last read
+}
+
+impl TimerState {
+    // Count the expirations up to `now`; returns the new deadline if it moved
+    // A next deadline past the end of the clock never comes: the timer disarms
+    fn update(&mut self, now: u64) -> Option<u64> {
+        let deadline = self.deadline.filter(|&deadline| now >= deadline)?;
+        if self.interval == 0 {
+            self.expirations += 1;
+            self.deadline = None;
+            return None;
+        }
+        let count = (now - deadline) / self.interval + 1;
+        self.expirations = self.expirations.saturating_add(count);
+        self.deadline = count
+            .checked_mul(self.interval)
+            .and_then(|ahead| deadline.checked_add(ahead));
+        if self.deadline.is_none() {
+            self.interval = 0;
+        }
+        self.deadline
+    }
+}
+
+pub struct TimerFd {
+    clock: Clock,
+    alarm: bool,
+    state: Mutex<TimerState>,
+    nonblocking: AtomicBool,
+}
+
+impl TimerFd {
+    pub fn new(clock: Clock, alarm: bool, nonblocking: bool) -> Arc<Self> {
+        Arc::new(Self {
+            clock,
+            alarm,
+            state: Mutex::new(TimerState::default()),
+            nonblocking: AtomicBool::new(nonblocking),
+        })
+    }
+
+    // Run `f` on the state brought up to date
+    fn with_state<R>(&self, f: impl FnOnce(&mut TimerState, u64) -> R) -> R {
+        let (result, moved) = interrupts::without_interrupts(|| {
+            let now = self.clock.now_ns();
+            let mut state = self.state.lock();
+            let moved = state.update(now);
+            (f(&mut state, now), moved)
+        });
+        if let Some(deadline) = moved {
+            self.arm_alarm(deadline);
+        }
+        result
+    }
+
+    // Have the RTC ring at `deadline`, at the latest (alarm clocks only)
+    fn arm_alarm(&self, deadline: u64) {
+        if self.alarm {
+            let ahead = deadline.saturating_sub(self.clock.now_ns());
+            let at = crate::rtc::realtime_ns().saturating_add(ahead);
+            crate::rtc::arm_alarm(at.div_ceil(NS_PER_SEC));
+        }
+    }
+
+    // The time left until the next expiration (0 if disarmed) and the interval
+    pub fn get(&self) -> (u64, u64) {
+        self.with_state(|state, now| {
+            let left = state.deadline.map_or(0, |deadline| deadline - now);
+            (left, state.interval)
+        })
+    }
+
+    // Arm the timer for `value` ns from now (or at `value` on its clock if
+    // `absolute`), then every `interval` ns; a zero `value` disarms it.
+    // Returns the previous setting, as `get` does.
+    pub fn set(&self, value: u64, interval: u64, absolute: bool) -> (u64, u64) {
+        let (old, deadline) = self.with_state(|state, now| {
+            let old = (
+                state.deadline.map_or(0, |deadline| deadline - now),
+                state.interval,
+            );
+            let deadline = match value {
+                0 => None,
+                _ if absolute => Some(value),
+                _ => Some(now.saturating_add(value)),
+            };
+            *state = TimerState {
+                deadline,
+                interval: if deadline.is_some() { interval } else { 0 },
+                expirations: 0,
+            };
+            (old, deadline)
+        });
+        if let Some(deadline) = deadline {
+            self.arm_alarm(deadline);
+        }
+        old
+    }
+
+    fn take_expirations(&self) -> Option<u64> {
+        self.with_state(|state, _| match state.expirations {
+            0 => None,
+            _ => Some(core::mem::take(&mut state.expirations)),
+        })
+    }
+}
+
+impl File for TimerFd {
+    fn read(&self, buf: &mut [u8]) -> FsResult<usize> {
+        if buf.len() < 8 {
+            return Err(FsError::InvalidArgument);
+        }
+        let count = if self.nonblocking.load(Ordering::Relaxed) {
+            self.take_expirations().ok_or(FsError::WouldBlock)?
+        } else {
+            crate::idle::wait_for(|| self.take_expirations())
+        };
+        buf[..8].copy_from_slice(&count.to_ne_bytes());
+        Ok(8)
+    }
+
+    fn write(&self, _buf: &[u8]) -> FsResult<usize> {
+        Err(FsError::InvalidArgument)
+    }
+
+    fn seek(&self, _pos: SeekFrom) -> FsResult<u64> {
+        Err(FsError::InvalidArgument)
+    }
+
+    // An anonymous inode, like Linux's
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino: 0,
+            file_type: FileType::Regular,
+            size: 0,
+            mode: 0o600,
+            nlink: 1,
+        }
+    }
+
+    fn read_dir(&self, _max: usize) -> FsResult<Vec<DirEntry>> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn truncate(&self, _size: u64) -> FsResult<()> {
+        Err(FsError::InvalidArgument)
+    }
+
+    fn as_timer(&self) -> Option<&TimerFd> {
+        Some(self)
+    }
+
+    fn poll(&self) -> u16 {
+        if self.with_state(|state, _| state.expirations) > 0 {
+            POLLIN
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn timerfd_one_shot_and_periodic() {
+        let timer = TimerFd::new(Clock::Monotonic, false, true);
+        let mut buf = [0u8; 8];
+        assert_eq!(timer.read(&mut buf), Err(FsError::WouldBlock));
+        assert_eq!(timer.poll(), 0);
+
+        // An absolute deadline already passed expires once
+        timer.set(1, 0, true);
+        assert_eq!(timer.poll(), POLLIN);
+        assert_eq!(timer.read(&mut buf), Ok(8));
+        assert_eq!(u64::from_ne_bytes(buf), 1);
+        assert_eq!(timer.get(), (0, 0));
+        assert_eq!(timer.read(&mut buf), Err(FsError::WouldBlock));
+
+        // Far away: armed, nothing to read; disarming returns the old setting
+        timer.set(1000 * NS_PER_SEC, NS_PER_SEC, false);
+        let (left, interval) = timer.get();
+        assert!(left > 999 * NS_PER_SEC && interval == NS_PER_SEC);
+        assert_eq!(timer.read(&mut buf), Err(FsError::WouldBlock));
+        assert_eq!(timer.set(0, 0, false).1, NS_PER_SEC);
+        assert_eq!(timer.get(), (0, 0));
+
+        // Periodic expirations are counted, not queued
+        let mut state = TimerState {
+            deadline: Some(100),
+            interval: 10,
+            expirations: 0,
+        };
+        assert_eq!(state.update(99), None);
+        assert_eq!(state.update(125), Some(130));
+        assert_eq!(state.expirations, 3);
+
+        // The next period would wrap the clock: one last expiration
+        let mut state = TimerState {
+            deadline: Some(100),
+            interval: u64::MAX - 50,
+            expirations: 0,
+        };
+        assert_eq!(state.update(100), None);
+        assert_eq!(
+            (state.deadline, state.interval, state.expirations),
+            (None, 0, 1)
+        );
+    }
+}
@@ -1,5 +1,7 @@
 // Import necessary modules
-use lazy_static::lazy_static;
+use alloc::boxed::Box;
+use alloc::vec;
+use shared::once::Once;
 use x86_64::VirtAddr;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
@@ -8,42 +10,57 @@ use x86_64::structures::tss::TaskStateSegment;
 // We use index 0
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-// Initialize the TSS (Task State Segment) lazily
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        // Create a new TSS
-        let mut tss = TaskStateSegment::new();
-
-        // RSP0: Stack pointer used when transitioning from Ring 3 to Ring 0
-        // This is CRITICAL for handling interrupts/exceptions from user mode
-        tss.privilege_stack_table[0] = {
-            const STACK_SIZE: usize = 4096 * 5; // 20 KB
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            stack_start + STACK_SIZE as u64
-        };
-
-        // Define the stack for double faults in the Interrupt Stack Table (IST)
-        // This ensures that when a double fault occurs, the CPU switches to a fresh stack.
-        // This prevents a "triple fault" (system reset) if the main stack overflows.
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            // Define the stack size (5 pages)
-            const STACK_SIZE: usize = 4096 * 5;
-            // Create a static array for the stack
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            // Get the virtual address of the stack top
-            // &raw const is used to get a raw pointer safely without creating a reference to mutable static
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-
-            // Return the top address (stacks grow downwards)
-            stack_start + STACK_SIZE as u64
-        };
-        tss
+// Most CPUs the kernel can bring up, each with its own GDT, TSS and stacks
+pub const MAX_CPUS: usize = 64;
+
+const RSP0_STACK_SIZE: usize = 4096 * 5; // 20 KB
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5; // 20 KB
+
+#[repr(C, align(16))]
+struct Stack<const SIZE: usize>([u8; SIZE]);
+
+// The boot CPU builds its tables before the heap exists: its stacks are static
+static mut BSP_RSP0_STACK: Stack<RSP0_STACK_SIZE> = Stack([0; RSP0_STACK_SIZE]);
+static mut BSP_DOUBLE_FAULT_STACK: Stack<DOUBLE_FAULT_STACK_SIZE> =
+    Stack([0; DOUBLE_FAULT_STACK_SIZE]);
+
+// The per-CPU TSS (Task State Segment), built by `init_cpu`
+static TSS: [Once<TaskStateSegment>; MAX_CPUS] = [const { Once::new() }; MAX_CPUS];
+
+// Top of a fresh stack for an application processor, from the heap
+fn heap_stack(size: usize) -> VirtAddr {
+    let stack = Box::leak(vec![0u128; size / 16].into_boxed_slice());
+    VirtAddr::from_ptr(stack.as_ptr()) + size as u64
+}
+
+fn build_tss(cpu: usize) -> TaskStateSegment {
+    // Create a new TSS
+    let mut tss = TaskStateSegment::new();
+
+    let (rsp0, double_fault) = if cpu == 0 {
+        (
+            VirtAddr::from_ptr(&raw const BSP_RSP0_STACK) + RSP0_STACK_SIZE as u64,
+            VirtAddr::from_ptr(&raw const BSP_DOUBLE_FAULT_STACK) + DOUBLE_FAULT_STACK_SIZE as u64,
+        )
+    } else {
+        (
+            heap_stack(RSP0_STACK_SIZE),
+            heap_stack(DOUBLE_FAULT_STACK_SIZE),
+        )
     };
+
+    // RSP0: Stack pointer used when transitioning from Ring 3 to Ring 0
+    // This is CRITICAL for handling interrupts/exceptions from user mode
+    tss.privilege_stack_table[0] = rsp0;
+
+    // Define the stack for double faults in the Interrupt Stack Table (IST)
+    // This ensures that when a double fault occurs, the CPU switches to a fresh stack.
+    // This prevents a "triple fault" (system reset) if the main stack overflows.
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault;
+    tss
 }
 
-// Initialize the GDT (Global Descriptor Table) lazily
+// The per-CPU GDT (Global Descriptor Table), built by `init_cpu` once the TSS exists
 // GDT Layout:
 //   Index 0: Null descriptor
 //   Index 1: Kernel Code Segment (Ring 0) - Selector 0x08
@@ -51,37 +68,41 @@ lazy_static! {
 //   Index 3: User Data Segment (Ring 3)   - Selector 0x18 (with RPL 3 = 0x1B)
 //   Index 4: User Code Segment (Ring 3)   - Selector 0x20 (with RPL 3 = 0x23)
 //   Index 5-6: TSS (takes 2 entries)      - Selector 0x28
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        // Create a new GDT
-        let mut gdt = GlobalDescriptorTable::new();
+static GDT: [Once<(GlobalDescriptorTable, Selectors)>; MAX_CPUS] =
+    [const { Once::new() }; MAX_CPUS];
+
+fn build_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    // Create a new GDT
+    let mut gdt = GlobalDescriptorTable::new();
 
-        // Add a kernel code segment (Ring 0)
-        let code_selector = gdt.append(Descriptor::kernel_code_segment());
+    // Add a kernel code segment (Ring 0)
+    let code_selector = gdt.append(Descriptor::kernel_code_segment());
 
-        // Add a kernel data segment (Ring 0)
-        let data_selector = gdt.append(Descriptor::kernel_data_segment());
+    // Add a kernel data segment (Ring 0)
+    let data_selector = gdt.append(Descriptor::kernel_data_segment());
 
-        // Add user data segment BEFORE user code segment
-        // This ordering is required for syscall/sysret compatibility
-        let user_data_selector = gdt.append(Descriptor::user_data_segment());
+    // Add user data segment BEFORE user code segment
+    // This ordering is required for syscall/sysret compatibility
+    let user_data_selector = gdt.append(Descriptor::user_data_segment());
 
-        // Add user code segment
-        let user_code_selector = gdt.append(Descriptor::user_code_segment());
+    // Add user code segment
+    let user_code_selector = gdt.append(Descriptor::user_code_segment());
 
-        // Add the TSS segment (takes 2 GDT entries in 64-bit mode)
-        // We must load the TSS so the CPU knows about our IST
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
+    // Add the TSS segment (takes 2 GDT entries in 64-bit mode)
+    // We must load the TSS so the CPU knows about our IST
+    let tss_selector = gdt.append(Descriptor::tss_segment(tss));
 
-        // Return the GDT and the selectors
-        (gdt, Selectors {
+    // Return the GDT and the selectors
+    (
+        gdt,
+        Selectors {
             code_selector,
             data_selector,
             user_data_selector,
             user_code_selector,
-            tss_selector
-        })
-    };
+            tss_selector,
+        },
+    )
 }
 
 // Helper struct to store segment selectors
@@ -103,29 +124,73 @@ pub fn get_user_data_selector() -> SegmentSelector {
     SegmentSelector::new(3, x86_64::PrivilegeLevel::Ring3)
 }
 
-// Function to initialize the GDT
+// Top of the RSP0 stack of CPU `cpu`, once `init_cpu` has built its TSS
+// (syscalls run on it too)
+#[cfg(feature = "userspace")]
+pub fn kernel_stack_top(cpu: usize) -> VirtAddr {
+    let tss = TSS[cpu].get().expect("TSS not built");
+    // The TSS is packed: copy the table out before indexing it
+    let stacks = tss.privilege_stack_table;
+    stacks[0]
+}
+
+// Function to initialize the GDT of the boot CPU
 pub fn init() {
+    init_cpu(0);
+}
+
+// Build the GDT and TSS of CPU `cpu` (0 is the boot CPU) and load them
+// Runs on that CPU: at boot for the BSP, from the AP entry code for the others
+// (after the heap is up, which their stacks come from)
+pub fn init_cpu(cpu: usize) {
     // Import segment register instructions
     use x86_64::instructions::segmentation::{CS, DS, ES, SS, Segment};
     use x86_64::instructions::tables::load_tss;
 
+    assert!(cpu < MAX_CPUS, "CPU {} beyond MAX_CPUS", cpu);
+
+    // Build the TSS first: the GDT holds a descriptor pointing at it
+    let tss = TSS[cpu].call_once(|| build_tss(cpu));
+    let (gdt, selectors) = GDT[cpu].call_once(|| build_gdt(tss));
+
     // Load the GDT into the CPU
-    GDT.0.load();
+    gdt.load();
 
     // Reload segment registers
     unsafe {
         // Set the Code Segment register (CS)
         // This effectively switches to our new GDT
-        CS::set_reg(GDT.1.code_selector);
+        CS::set_reg(selectors.code_selector);
 
         // Set Data Segment registers (SS, DS, ES)
         // In 64-bit mode these are mostly ignored but good practice to set
-        SS::set_reg(GDT.1.data_selector);
-        DS::set_reg(GDT.1.data_selector);
-        ES::set_reg(GDT.1.data_selector);
+        SS::set_reg(selectors.data_selector);
+        DS::set_reg(selectors.data_selector);
+        ES::set_reg(selectors.data_selector);
 
         // Load the Task State Segment (TSS)
         // This allows the CPU to find the interrupt stacks
-        load_tss(GDT.1.tss_selector);
+        load_tss(selectors.tss_selector);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn cpus_get_distinct_stacks() {
+        // (RSP0, double fault stack) of each CPU; the TSS is packed, copy them out
+        let stacks = [0, 1, 2].map(|cpu| {
+            let tss = build_tss(cpu);
+            let (rsp0, ist) = (tss.privilege_stack_table, tss.interrupt_stack_table);
+            (rsp0[0], ist[DOUBLE_FAULT_IST_INDEX as usize])
+        });
+        for (i, a) in stacks.iter().enumerate() {
+            for b in &stacks[i + 1..] {
+                assert_ne!(a.0, b.0);
+                assert_ne!(a.1, b.1);
+            }
+        }
     }
 }
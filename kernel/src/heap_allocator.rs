@@ -1,5 +1,14 @@
+// Kernel Heap
+// A linked list allocator over a virtual range at KERNEL_HEAP_START. It starts
+// with KERNEL_HEAP_SIZE mapped; when an allocation does not fit, more pages are
+// mapped from the PMM right after the top and handed to the allocator, up to
+// KERNEL_HEAP_MAX (or less, `heap_max=<MiB>` on the command line). Only at that limit are
+// clean disk blocks dropped from the block caches to make room. The heap never
+// shrinks.
+
 // Import necessary modules
 use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 use linked_list_allocator::Heap;
 use spin::Mutex;
@@ -7,7 +16,8 @@ use x86_64::{
     VirtAddr,
     instructions::interrupts,
     structures::paging::{
-        FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, mapper::MapToError,
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
+        mapper::MapToError,
     },
 };
 
@@ -15,8 +25,22 @@ use x86_64::{
 // We place it in the higher half memory to keep it separate from user space
 pub const KERNEL_HEAP_START: u64 = 0xFFFF_9000_0000_0000;
 
-// Define the size of the heap (100 KB)
-pub const KERNEL_HEAP_SIZE: usize = 100 * 1024;
+// Define the size of the heap (1 MB)
+// tmpfs file contents live on the heap, so it needs some room
+pub const KERNEL_HEAP_SIZE: usize = 1024 * 1024;
+
+// The most the heap grows to; the command line can only lower it
+pub const KERNEL_HEAP_MAX: usize = 64 * 1024 * 1024;
+
+// Smallest amount the heap grows by, so a run of small allocations does not map
+// a page at a time
+const GROW_MIN: usize = 64 * 1024;
+
+static HHDM_OFFSET: AtomicU64 = AtomicU64::new(0);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(KERNEL_HEAP_MAX);
+
+// Number of cached disk blocks each block cache gives up when the heap is exhausted
+const RECLAIM_BLOCKS_PER_CACHE: usize = 64;
 
 // Wrapper around the allocator to make it thread-safe using a spinlock
 pub struct SafeLockedHeap(Mutex<Heap>);
@@ -42,11 +66,27 @@ unsafe impl GlobalAlloc for SafeLockedHeap {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         // Disable interrupts to ensure thread safety
         interrupts::without_interrupts(|| {
-            self.0
-                .lock()
-                .allocate_first_fit(layout)
-                .ok()
-                .map(|ptr| ptr.as_ptr())
+            let try_alloc = || {
+                self.0
+                    .lock()
+                    .allocate_first_fit(layout)
+                    .ok()
+                    .map(|ptr| ptr.as_ptr())
+            };
+
+            // Out of memory: grow the heap, or at its limit drop clean cached
+            // disk blocks, and retry once
+            try_alloc()
+                .or_else(|| {
+                    // The lock is dropped with the statement, before the retry
+                    let grown = grow(&mut self.0.lock(), layout);
+                    grown.then(try_alloc).flatten()
+                })
+                .or_else(|| {
+                    (crate::block::shrink_all(RECLAIM_BLOCKS_PER_CACHE) > 0)
+                        .then(try_alloc)
+                        .flatten()
+                })
                 .unwrap_or_default() // Return null pointer on failure
         })
     }
@@ -62,6 +102,75 @@ unsafe impl GlobalAlloc for SafeLockedHeap {
     }
 }
 
+// Map pages after the top of the heap, enough for `layout` whatever its
+// alignment, and give them to the allocator; false if the heap is at its limit
+// or no frame is left
+fn grow(heap: &mut Heap, layout: Layout) -> bool {
+    let page_size = Size4KiB::SIZE as usize;
+    let wanted = (layout.size() + layout.align())
+        .max(GROW_MIN)
+        .next_multiple_of(page_size);
+    let room = HEAP_LIMIT
+        .load(Ordering::Relaxed)
+        .saturating_sub(heap.size());
+    if room < layout.size() + layout.align() {
+        return false;
+    }
+    let top = heap.top() as u64;
+    let mut mapper = unsafe { crate::pml4::init_mapper(HHDM_OFFSET.load(Ordering::Relaxed)) };
+    let mut frame_allocator = crate::pmm::KernelFrameAllocator;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mut mapped = 0;
+    while mapped < wanted.min(room) {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(top + mapped as u64));
+        let Some(frame) = frame_allocator.allocate_frame() else {
+            break;
+        };
+        match unsafe { mapper.map_to(page, frame, flags, &mut frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                break;
+            }
+        }
+        mapped += page_size;
+    }
+    // What got mapped is the heap's, even if it falls short of the allocation
+    if mapped > 0 {
+        unsafe { heap.extend(mapped) };
+    }
+    mapped >= layout.size() + layout.align()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub used: usize,
+    pub free: usize,
+    // Mapped so far
+    pub size: usize,
+    // What it may grow to
+    pub limit: usize,
+}
+
+fn stats_of(heap: &Heap) -> HeapStats {
+    HeapStats {
+        used: heap.used(),
+        free: heap.free(),
+        size: heap.size(),
+        limit: HEAP_LIMIT.load(Ordering::Relaxed),
+    }
+}
+
+pub fn heap_stats() -> HeapStats {
+    interrupts::without_interrupts(|| stats_of(&ALLOCATOR.0.lock()))
+}
+
+// Same as `heap_stats` for crash paths: None if the heap is locked
+pub fn try_stats() -> Option<HeapStats> {
+    let heap = ALLOCATOR.0.try_lock()?;
+    Some(stats_of(&heap))
+}
+
 // Define the global allocator static variable
 #[global_allocator]
 static ALLOCATOR: SafeLockedHeap = SafeLockedHeap::empty();
@@ -71,7 +180,29 @@ static ALLOCATOR: SafeLockedHeap = SafeLockedHeap::empty();
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    hhdm_offset: u64,
 ) -> Result<(), MapToError<Size4KiB>> {
+    HHDM_OFFSET.store(hhdm_offset, Ordering::Relaxed);
+    if let Some(mib) = shared::cmdline::current().get("heap_max") {
+        match mib.parse::<usize>() {
+            Ok(mib) => {
+                let limit = mib.saturating_mul(1024 * 1024);
+                if limit > KERNEL_HEAP_MAX {
+                    log::warn!(
+                        "heap_max={} above the {} MiB maximum",
+                        mib,
+                        KERNEL_HEAP_MAX >> 20
+                    );
+                }
+                HEAP_LIMIT.store(
+                    limit.clamp(KERNEL_HEAP_SIZE, KERNEL_HEAP_MAX),
+                    Ordering::Relaxed,
+                )
+            }
+            Err(_) => log::warn!("Ignoring heap_max={}: not a number of MiB", mib),
+        }
+    }
+
     // Calculate the range of pages that the heap will cover
     let page_range = {
         let heap_start = VirtAddr::new(KERNEL_HEAP_START);
@@ -81,9 +212,11 @@ pub fn init_heap(
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
 
-    println!(
-        "Initializing Heap at {:#x} (Size: {} Bytes)",
-        KERNEL_HEAP_START, KERNEL_HEAP_SIZE
+    log::info!(
+        "Initializing Heap at {:#x} (Size: {} Bytes, growing up to {} MiB)",
+        KERNEL_HEAP_START,
+        KERNEL_HEAP_SIZE,
+        HEAP_LIMIT.load(Ordering::Relaxed) / (1024 * 1024)
     );
 
     // Map all pages in the heap range
@@ -106,6 +239,52 @@ pub fn init_heap(
         ALLOCATOR.init(KERNEL_HEAP_START as usize, KERNEL_HEAP_SIZE);
     }
 
-    println!("Heap initialized successfully with Interrupt Safety!");
+    log::info!("Heap initialized successfully with Interrupt Safety!");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn box_allocation() {
+        let value = Box::new(41);
+        assert_eq!(*value + 1, 42);
+    }
+
+    #[test_case]
+    fn large_vec() {
+        let n = 1000u64;
+        let vec: Vec<u64> = (0..n).collect();
+        assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+    }
+
+    #[test_case]
+    fn freed_memory_is_reused() {
+        let used = heap_stats().used;
+        for i in 0..KERNEL_HEAP_SIZE / 32 {
+            let value = Box::new([i; 4]);
+            assert_eq!(value[3], i);
+        }
+        assert_eq!(heap_stats().used, used);
+    }
+
+    #[test_case]
+    fn heap_grows_for_large_allocations() {
+        let before = heap_stats();
+        let n = 2 * KERNEL_HEAP_SIZE / 8;
+        let vec: Vec<u64> = (0..n as u64).collect();
+        assert_eq!(vec[n - 1], n as u64 - 1);
+        let grown = heap_stats();
+        assert!(grown.size >= before.size.max(2 * KERNEL_HEAP_SIZE));
+        assert!(grown.size <= grown.limit);
+        drop(vec);
+        assert_eq!(heap_stats().used, before.used);
+        // Past the limit, an allocation fails instead of growing the heap
+        let too_large = Layout::from_size_align(grown.limit + 1, 8).unwrap();
+        assert!(unsafe { alloc::alloc::alloc(too_large) }.is_null());
+    }
+}
@@ -0,0 +1,258 @@
+// CPU Idle & Frequency
+// Code waiting for input or packets idles the CPU through `wait_for` instead of
+// spinning: the condition is checked with interrupts off and the CPU sleeps with
+// `sti; hlt` (or `sti; mwait`), whose interrupt shadow closes the window in which
+// a wakeup could arrive between the check and the sleep. MWAIT is used when CPUID
+// reports MONITOR/MWAIT with interrupt break events; the hint asks for C1 only,
+// deeper C-states need the ACPI _CST tables.
+//
+// Every CPU counts the TSC cycles it spent asleep. The CPU number is kept in
+// IA32_TSC_AUX, so the RDTSCP that stamps the end of a sleep also says whose
+// counter it goes to. `/proc/idle` shows the totals, the status bar the idle
+// share of the last interval; `/proc/cpufreq` reports the TSC, base and maximum
+// frequencies (CPUID leaf 0x16) and the effective one measured with APERF/MPERF.
+
+use alloc::string::String;
+use core::arch::x86_64::{__cpuid, __rdtscp, _rdtsc};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::Msr;
+
+use crate::fs::procfs::{self, ProcEntry};
+use crate::gdt::MAX_CPUS;
+
+// MSRs
+const IA32_MPERF: u32 = 0xE7;
+const IA32_APERF: u32 = 0xE8;
+const IA32_TSC_AUX: u32 = 0xC000_0103;
+
+// CPUID feature bits
+const CPUID_1_ECX_MONITOR: u32 = 1 << 3;
+const CPUID_5_ECX_EXTENSIONS: u32 = 1 << 0;
+const CPUID_5_ECX_INTERRUPT_BREAK: u32 = 1 << 1;
+const CPUID_6_ECX_APERFMPERF: u32 = 1 << 0;
+const CPUID_FREQUENCY: u32 = 0x16;
+
+// MWAIT hint for C1
+const MWAIT_C1: u32 = 0x00;
+
+// APERF/MPERF sampling window
+const FREQUENCY_SAMPLE_US: u64 = 1000;
+
+static MWAIT: AtomicBool = AtomicBool::new(false);
+
+#[repr(C, align(64))]
+struct CpuIdle {
+    // Monitored by MWAIT; nothing writes it, interrupts end the sleep
+    wake: AtomicU64,
+    // TSC cycles asleep / TSC at `init_cpu`
+    idle: AtomicU64,
+    since: AtomicU64,
+    // Counters at the previous status bar sample
+    #[cfg(feature = "graphics")]
+    sampled_idle: AtomicU64,
+    #[cfg(feature = "graphics")]
+    sampled_tsc: AtomicU64,
+}
+
+static CPUS: [CpuIdle; MAX_CPUS] = [const {
+    CpuIdle {
+        wake: AtomicU64::new(0),
+        idle: AtomicU64::new(0),
+        since: AtomicU64::new(0),
+        #[cfg(feature = "graphics")]
+        sampled_idle: AtomicU64::new(0),
+        #[cfg(feature = "graphics")]
+        sampled_tsc: AtomicU64::new(0),
+    }
+}; MAX_CPUS];
+
+static IDLE_ENTRY: ProcEntry = ProcEntry {
+    name: "idle",
+    read: read_idle,
+    write: None,
+};
+
+static CPUFREQ_ENTRY: ProcEntry = ProcEntry {
+    name: "cpufreq",
+    read: read_cpufreq,
+    write: None,
+};
+
+// ============================================================================
+// 1. IDLE
+// ============================================================================
+
+pub fn init() {
+    let mwait = __cpuid(1).ecx & CPUID_1_ECX_MONITOR != 0 && {
+        let leaf = __cpuid(5).ecx;
+        leaf & (CPUID_5_ECX_EXTENSIONS | CPUID_5_ECX_INTERRUPT_BREAK)
+            == CPUID_5_ECX_EXTENSIONS | CPUID_5_ECX_INTERRUPT_BREAK
+    };
+    MWAIT.store(mwait, Ordering::Relaxed);
+    init_cpu(0);
+    log::info!("Idle: {}", method());
+}
+
+// Number the calling CPU for idle accounting
+pub fn init_cpu(cpu: usize) {
+    assert!(cpu < MAX_CPUS, "CPU {} beyond MAX_CPUS", cpu);
+    unsafe { Msr::new(IA32_TSC_AUX).write(cpu as u64) };
+    let now = unsafe { _rdtsc() };
+    CPUS[cpu].since.store(now, Ordering::Relaxed);
+    #[cfg(feature = "graphics")]
+    CPUS[cpu].sampled_tsc.store(now, Ordering::Relaxed);
+}
+
+fn method() -> &'static str {
+    if MWAIT.load(Ordering::Relaxed) {
+        "mwait (C1)"
+    } else {
+        "hlt"
+    }
+}
+
+// TSC and the number of the CPU reading it
+fn rdtscp() -> (u64, usize) {
+    let mut cpu = 0;
+    let tsc = unsafe { __rdtscp(&mut cpu) };
+    (tsc, cpu as usize % MAX_CPUS)
+}
+
+// Sleep until the next interrupt; entered with interrupts disabled, returns with
+// them enabled, after the interrupt ran (its time counts as idle)
+fn sleep() {
+    let (start, cpu) = rdtscp();
+    let local = &CPUS[cpu];
+    unsafe {
+        if MWAIT.load(Ordering::Relaxed) {
+            core::arch::asm!(
+                "monitor",
+                in("rax") local.wake.as_ptr(),
+                in("ecx") 0,
+                in("edx") 0,
+                options(nostack)
+            );
+            core::arch::asm!("sti", "mwait", in("eax") MWAIT_C1, in("ecx") 0, options(nostack));
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+    let (end, _) = rdtscp();
+    local.idle.fetch_add(end - start, Ordering::Relaxed);
+}
+
+// TSC cycles this CPU has spent asleep so far
+#[cfg(feature = "userspace")]
+pub fn slept_cycles() -> u64 {
+    let (_, cpu) = rdtscp();
+    CPUS[cpu].idle.load(Ordering::Relaxed)
+}
+
+// Call `poll` until it yields a value, idling the CPU in between
+// `poll` runs with interrupts disabled; the caller's IF state is restored after
+pub fn wait_for<T>(mut poll: impl FnMut() -> Option<T>) -> T {
+    let enabled = interrupts::are_enabled();
+    let value = loop {
+        interrupts::disable();
+        if let Some(value) = poll() {
+            break value;
+        }
+        sleep();
+    };
+    if enabled {
+        interrupts::enable();
+    }
+    value
+}
+
+// Percent of the time CPU 0 slept since the previous call, for the status bar
+#[cfg(feature = "graphics")]
+pub fn sample_idle_percent() -> u64 {
+    let local = &CPUS[0];
+    let now = unsafe { _rdtsc() };
+    let idle = local.idle.load(Ordering::Relaxed);
+    let elapsed = now - local.sampled_tsc.swap(now, Ordering::Relaxed);
+    let slept = idle - local.sampled_idle.swap(idle, Ordering::Relaxed);
+    (slept * 100).checked_div(elapsed).unwrap_or(0).min(100)
+}
+
+fn read_idle() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "method: {}", method());
+    let now = unsafe { _rdtsc() };
+    let per_us = crate::delay::tsc_per_us();
+    for (cpu, local) in CPUS.iter().enumerate() {
+        let since = local.since.load(Ordering::Relaxed);
+        if since == 0 {
+            continue;
+        }
+        let idle = local.idle.load(Ordering::Relaxed);
+        let total = now - since;
+        let ms = |cycles: u64| (cycles / 1000).checked_div(per_us).unwrap_or(0);
+        let _ = writeln!(
+            out,
+            "cpu{}: idle {} ms of {} ms ({}%)",
+            cpu,
+            ms(idle),
+            ms(total),
+            (idle * 100).checked_div(total).unwrap_or(0)
+        );
+    }
+    out
+}
+
+// ============================================================================
+// 2. FREQUENCY
+// ============================================================================
+
+// Base and maximum frequency in MHz, from CPUID leaf 0x16 (0 if not reported)
+fn nominal_mhz() -> (u32, u32) {
+    if __cpuid(0).eax < CPUID_FREQUENCY {
+        return (0, 0);
+    }
+    let leaf = __cpuid(CPUID_FREQUENCY);
+    (leaf.eax & 0xFFFF, leaf.ebx & 0xFFFF)
+}
+
+// Effective frequency of the calling CPU while busy, in MHz: APERF counts at the
+// actual clock, MPERF at the TSC rate, over a short busy-wait
+fn effective_mhz() -> Option<u64> {
+    if __cpuid(6).ecx & CPUID_6_ECX_APERFMPERF == 0 {
+        return None;
+    }
+    let per_us = crate::delay::tsc_per_us();
+    if per_us == 0 {
+        return None;
+    }
+    let (aperf, mperf) = (Msr::new(IA32_APERF), Msr::new(IA32_MPERF));
+    unsafe {
+        let (a0, m0) = (aperf.read(), mperf.read());
+        crate::delay::udelay(FREQUENCY_SAMPLE_US);
+        let (a1, m1) = (aperf.read(), mperf.read());
+        ((a1 - a0) * per_us).checked_div(m1 - m0)
+    }
+}
+
+fn read_cpufreq() -> String {
+    let mut out = String::new();
+    let mhz = |value: u64| {
+        if value == 0 {
+            String::from("unknown")
+        } else {
+            alloc::format!("{} MHz", value)
+        }
+    };
+    let (base, max) = nominal_mhz();
+    let _ = writeln!(out, "tsc: {}", mhz(crate::delay::tsc_per_us()));
+    let _ = writeln!(out, "base: {}", mhz(base as u64));
+    let _ = writeln!(out, "max: {}", mhz(max as u64));
+    let _ = writeln!(out, "current: {}", mhz(effective_mhz().unwrap_or(0)));
+    out
+}
+
+pub fn init_proc() {
+    procfs::register(&IDLE_ENTRY);
+    procfs::register(&CPUFREQ_ENTRY);
+}
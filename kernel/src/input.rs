@@ -0,0 +1,485 @@
+// Input Event Devices
+// Keyboard and mouse events for user space, as Linux evdev nodes: /dev/input/event0
+// is the keyboard, event1 the PS/2 mouse (when there is one). Reading returns
+// whole `struct input_event`s (timestamp, type, code, value); each group of
+// events that belongs together ends with a SYN_REPORT. Every open file has a
+// queue of its own, so several readers see every event, and the console keeps
+// taking the keyboard's scancodes from its own buffer as before.
+//
+// A reader that falls behind loses its queue: the events are dropped and a
+// SYN_DROPPED takes their place (the reader should then ask for the state again).
+// Events are queued from interrupt handlers, which must not allocate: the queues
+// are allocated when the file is opened and never grow.
+
+use crate::fs::{
+    self, DirEntry, File, FileType, FsError, FsResult, Inode, Metadata, O_NONBLOCK, POLLIN,
+    SeekFrom,
+};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Event types
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+const EV_MAX: u16 = 0x1f;
+
+// EV_SYN codes
+const SYN_REPORT: u16 = 0;
+const SYN_DROPPED: u16 = 3;
+
+// EV_REL codes
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+
+// Mouse buttons (EV_KEY codes)
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+
+// EV_KEY values
+const KEY_RELEASED: i32 = 0;
+const KEY_PRESSED: i32 = 1;
+const KEY_REPEATED: i32 = 2;
+
+// Bus of the i8042 devices, for EVIOCGID
+const BUS_I8042: u16 = 0x11;
+
+// ioctls (_IOR('E', nr, size); the name and bit requests carry the buffer length)
+const EVIOCGVERSION: u64 = 0x8004_4501;
+const EVIOCGID: u64 = 0x8008_4502;
+const EVIOCGNAME: u64 = 0x8000_4506;
+const EVIOCGBIT: u64 = 0x8000_4520;
+const IOC_SIZE_SHIFT: u64 = 16;
+const IOC_SIZE_MASK: u64 = 0x3fff;
+
+const EV_VERSION: i32 = 0x01_0001;
+
+// Events a reader's queue holds before it overflows
+const QUEUE_LEN: usize = 64;
+
+// struct input_event
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub sec: i64,
+    pub usec: i64,
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+const EVENT_SIZE: usize = core::mem::size_of::<InputEvent>();
+
+// struct input_id
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct InputId {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+}
+
+type Queue = Arc<Mutex<VecDeque<InputEvent>>>;
+
+// An input device and the queues of the files open on it
+pub struct InputDevice {
+    name: &'static str,
+    product: u16,
+    keys: RangeInclusive<u16>,
+    rels: &'static [u16],
+    clients: Mutex<Vec<Queue>>,
+}
+
+impl InputDevice {
+    pub const fn new(
+        name: &'static str,
+        product: u16,
+        keys: RangeInclusive<u16>,
+        rels: &'static [u16],
+    ) -> Self {
+        Self {
+            name,
+            product,
+            keys,
+            rels,
+            clients: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Queue `events` (type, code, value) and a SYN_REPORT to every reader
+    // Called from interrupt handlers
+    pub fn report(&self, events: &[(u16, u16, i32)]) {
+        let ns = crate::rtc::realtime_ns();
+        let event = |kind, code, value| InputEvent {
+            sec: (ns / 1_000_000_000) as i64,
+            usec: (ns % 1_000_000_000 / 1000) as i64,
+            kind,
+            code,
+            value,
+        };
+        interrupts::without_interrupts(|| {
+            for queue in self.clients.lock().iter() {
+                let mut queue = queue.lock();
+                if queue.len() + events.len() + 1 > QUEUE_LEN {
+                    queue.clear();
+                    queue.push_back(event(EV_SYN, SYN_DROPPED, 0));
+                }
+                for &(kind, code, value) in events {
+                    queue.push_back(event(kind, code, value));
+                }
+                queue.push_back(event(EV_SYN, SYN_REPORT, 0));
+            }
+        });
+    }
+
+    fn has_type(&self, kind: u16) -> bool {
+        match kind {
+            EV_SYN => true,
+            EV_KEY => !self.keys.is_empty(),
+            EV_REL => !self.rels.is_empty(),
+            _ => false,
+        }
+    }
+
+    fn has_code(&self, kind: u16, code: u16) -> bool {
+        match kind {
+            EV_KEY => self.keys.contains(&code),
+            EV_REL => self.rels.contains(&code),
+            _ => false,
+        }
+    }
+
+    // The EVIOCGBIT bitmap of the event types (`kind` 0) or of the codes of a type
+    fn capabilities(&self, kind: u16, bitmap: &mut [u8]) -> usize {
+        let max = match kind {
+            0 => EV_MAX,
+            EV_KEY => *self.keys.end(),
+            EV_REL => self.rels.iter().copied().max().unwrap_or(0),
+            _ => 0,
+        };
+        let len = bitmap.len().min(max as usize / 8 + 1);
+        for code in 0..(len * 8) as u16 {
+            let set = match kind {
+                0 => self.has_type(code),
+                _ => self.has_code(kind, code),
+            };
+            if set {
+                bitmap[code as usize / 8] |= 1 << (code % 8);
+            }
+        }
+        len
+    }
+}
+
+// An open event device: its own queue of pending events
+struct EventFile {
+    device: &'static InputDevice,
+    queue: Queue,
+    nonblocking: AtomicBool,
+}
+
+impl EventFile {
+    fn open(device: &'static InputDevice, nonblocking: bool) -> Arc<Self> {
+        let queue = Arc::new(Mutex::new(VecDeque::with_capacity(QUEUE_LEN)));
+        interrupts::without_interrupts(|| device.clients.lock().push(queue.clone()));
+        Arc::new(Self {
+            device,
+            queue,
+            nonblocking: AtomicBool::new(nonblocking),
+        })
+    }
+
+    // Take up to `max` events
+    fn take(&self, max: usize) -> Option<Vec<InputEvent>> {
+        interrupts::without_interrupts(|| {
+            let mut queue = self.queue.lock();
+            if queue.is_empty() {
+                return None;
+            }
+            let count = queue.len().min(max);
+            Some(queue.drain(..count).collect())
+        })
+    }
+}
+
+impl Drop for EventFile {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            self.device
+                .clients
+                .lock()
+                .retain(|queue| !Arc::ptr_eq(queue, &self.queue))
+        });
+    }
+}
+
+// Copy to the user address of an ioctl
+fn write_arg(arg: u64, bytes: &[u8]) -> FsResult<()> {
+    if arg == 0 {
+        return Err(FsError::BadAddress);
+    }
+    unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), arg as *mut u8, bytes.len()) };
+    Ok(())
+}
+
+impl File for EventFile {
+    // Whole events only: a buffer too small for one is EINVAL
+    fn read(&self, buf: &mut [u8]) -> FsResult<usize> {
+        let max = buf.len() / EVENT_SIZE;
+        if max == 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        let events = if self.nonblocking.load(Ordering::Relaxed) {
+            self.take(max).ok_or(FsError::WouldBlock)?
+        } else {
+            crate::idle::wait_for(|| self.take(max))
+        };
+        for (chunk, event) in buf.as_chunks_mut::<EVENT_SIZE>().0.iter_mut().zip(&events) {
+            unsafe { (chunk.as_mut_ptr() as *mut InputEvent).write_unaligned(*event) };
+        }
+        Ok(events.len() * EVENT_SIZE)
+    }
+
+    // Injecting events is not supported
+    fn write(&self, _buf: &[u8]) -> FsResult<usize> {
+        Err(FsError::InvalidArgument)
+    }
+
+    fn seek(&self, _pos: SeekFrom) -> FsResult<u64> {
+        Err(FsError::InvalidArgument)
+    }
+
+    fn metadata(&self) -> Metadata {
+        EventNode(self.device).metadata()
+    }
+
+    fn read_dir(&self, _max: usize) -> FsResult<Vec<DirEntry>> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn truncate(&self, _size: u64) -> FsResult<()> {
+        Err(FsError::InvalidArgument)
+    }
+
+    fn ioctl(&self, request: u64, arg: u64) -> FsResult<i64> {
+        let size = ((request >> IOC_SIZE_SHIFT) & IOC_SIZE_MASK) as usize;
+        let base = request & !(IOC_SIZE_MASK << IOC_SIZE_SHIFT);
+        match request {
+            EVIOCGVERSION => write_arg(arg, &EV_VERSION.to_ne_bytes()).map(|_| 0),
+            EVIOCGID => {
+                let id = InputId {
+                    bustype: BUS_I8042,
+                    vendor: 0x0001,
+                    product: self.device.product,
+                    version: 0x0001,
+                };
+                let bytes: [u8; 8] = unsafe { core::mem::transmute(id) };
+                write_arg(arg, &bytes).map(|_| 0)
+            }
+            // The name with its NUL, truncated to the buffer
+            _ if base == EVIOCGNAME => {
+                let mut name = Vec::from(self.device.name.as_bytes());
+                name.push(0);
+                name.truncate(size);
+                write_arg(arg, &name)?;
+                Ok(name.len() as i64)
+            }
+            _ if (EVIOCGBIT..=EVIOCGBIT + EV_MAX as u64).contains(&base) => {
+                let mut bitmap = alloc::vec![0u8; size];
+                let len = self
+                    .device
+                    .capabilities((base - EVIOCGBIT) as u16, &mut bitmap);
+                write_arg(arg, &bitmap[..len])?;
+                Ok(len as i64)
+            }
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn poll(&self) -> u16 {
+        let pending = interrupts::without_interrupts(|| !self.queue.lock().is_empty());
+        if pending { POLLIN } else { 0 }
+    }
+}
+
+// The /dev/input node of a device
+struct EventNode(&'static InputDevice);
+
+impl Inode for EventNode {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino: 0,
+            file_type: FileType::CharDevice,
+            size: 0,
+            mode: 0o640,
+            nlink: 1,
+        }
+    }
+
+    fn open(&self, flags: u32) -> Option<Arc<dyn File>> {
+        Some(EventFile::open(self.0, flags & O_NONBLOCK != 0))
+    }
+}
+
+// Make `device` available at /dev/input/event<index>
+pub fn register(device: &'static InputDevice, index: usize) {
+    let path = alloc::format!("input/event{}", index);
+    fs::devfs::register(&path, Arc::new(EventNode(device)));
+}
+
+// ============================================================================
+// KEYBOARD
+// ============================================================================
+
+// Linux key codes of scancode set 1: the same numbers for the plain keys
+// (KEY_ESC = 1 .. KEY_F12 = 88), a table for the 0xE0-prefixed ones
+const KEY_MAX_PLAIN: u8 = 0x58;
+const EXTENDED_KEYS: &[(u8, u16)] = &[
+    (0x1c, 96),  // KEY_KPENTER
+    (0x1d, 97),  // KEY_RIGHTCTRL
+    (0x35, 98),  // KEY_KPSLASH
+    (0x37, 99),  // KEY_SYSRQ
+    (0x38, 100), // KEY_RIGHTALT
+    (0x47, 102), // KEY_HOME
+    (0x48, 103), // KEY_UP
+    (0x49, 104), // KEY_PAGEUP
+    (0x4b, 105), // KEY_LEFT
+    (0x4d, 106), // KEY_RIGHT
+    (0x4f, 107), // KEY_END
+    (0x50, 108), // KEY_DOWN
+    (0x51, 109), // KEY_PAGEDOWN
+    (0x52, 110), // KEY_INSERT
+    (0x53, 111), // KEY_DELETE
+    (0x5b, 125), // KEY_LEFTMETA
+    (0x5c, 126), // KEY_RIGHTMETA
+    (0x5d, 127), // KEY_COMPOSE
+];
+
+const SCANCODE_EXTENDED: u8 = 0xe0;
+const SCANCODE_PAUSE: u8 = 0xe1;
+const SCANCODE_RELEASE: u8 = 0x80;
+// Bytes following 0xE1 in the Pause key sequence
+const PAUSE_BYTES: u8 = 5;
+
+pub static KEYBOARD: InputDevice =
+    InputDevice::new("AT Translated Set 2 keyboard", 0x0001, 1..=127, &[]);
+
+// Decoder state of the keyboard's scancodes
+struct KeyboardState {
+    extended: bool,
+    skip: u8,
+    down: [u64; 2],
+}
+
+static KEYBOARD_STATE: Mutex<KeyboardState> = Mutex::new(KeyboardState {
+    extended: false,
+    skip: 0,
+    down: [0; 2],
+});
+
+// Linux key code of a make code, None for the ones without a key
+fn key_code(code: u8, extended: bool) -> Option<u16> {
+    if extended {
+        EXTENDED_KEYS
+            .iter()
+            .find(|&&(scancode, _)| scancode == code)
+            .map(|&(_, key)| key)
+    } else {
+        (code != 0 && code <= KEY_MAX_PLAIN).then_some(code as u16)
+    }
+}
+
+// Translate a scancode from the keyboard interrupt into key events
+pub fn keyboard_scancode(scancode: u8) {
+    let mut state = KEYBOARD_STATE.lock();
+    if state.skip > 0 {
+        state.skip -= 1;
+        return;
+    }
+    match scancode {
+        SCANCODE_EXTENDED => {
+            state.extended = true;
+            return;
+        }
+        SCANCODE_PAUSE => {
+            state.skip = PAUSE_BYTES;
+            return;
+        }
+        _ => {}
+    }
+    let extended = core::mem::take(&mut state.extended);
+    // The fake shifts around extended keys have no code and are dropped
+    let Some(key) = key_code(scancode & !SCANCODE_RELEASE, extended) else {
+        return;
+    };
+    let (word, bit) = (key as usize / 64, 1u64 << (key % 64));
+    let value = if scancode & SCANCODE_RELEASE != 0 {
+        state.down[word] &= !bit;
+        KEY_RELEASED
+    } else if state.down[word] & bit != 0 {
+        KEY_REPEATED
+    } else {
+        state.down[word] |= bit;
+        KEY_PRESSED
+    };
+    drop(state);
+    KEYBOARD.report(&[(EV_KEY, key, value)]);
+}
+
+pub fn init() {
+    register(&KEYBOARD, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static TEST_DEVICE: InputDevice =
+        InputDevice::new("test", 0, BTN_LEFT..=BTN_MIDDLE, &[REL_X, REL_Y]);
+
+    #[test_case]
+    fn input_events_queue_and_overflow() {
+        assert_eq!(EVENT_SIZE, 24);
+        let file = EventFile::open(&TEST_DEVICE, true);
+        let mut buf = [0u8; EVENT_SIZE * 4];
+        assert_eq!(file.read(&mut buf), Err(FsError::WouldBlock));
+        assert_eq!(file.poll(), 0);
+
+        TEST_DEVICE.report(&[(EV_REL, REL_X, -3), (EV_KEY, BTN_LEFT, 1)]);
+        assert_eq!(file.poll(), POLLIN);
+        assert_eq!(file.read(&mut buf[..EVENT_SIZE + 1]), Ok(EVENT_SIZE));
+        assert_eq!(file.read(&mut buf), Ok(EVENT_SIZE * 2));
+        let last = unsafe { (buf.as_ptr().add(EVENT_SIZE) as *const InputEvent).read_unaligned() };
+        assert_eq!((last.kind, last.code), (EV_SYN, SYN_REPORT));
+
+        for _ in 0..QUEUE_LEN {
+            TEST_DEVICE.report(&[(EV_REL, REL_Y, 1)]);
+        }
+        assert_eq!(file.read(&mut buf[..EVENT_SIZE]), Ok(EVENT_SIZE));
+        let first = unsafe { (buf.as_ptr() as *const InputEvent).read_unaligned() };
+        assert_eq!((first.kind, first.code), (EV_SYN, SYN_DROPPED));
+
+        drop(file);
+        assert!(TEST_DEVICE.clients.lock().is_empty());
+    }
+
+    #[test_case]
+    fn input_capabilities() {
+        let mut bitmap = [0u8; 4];
+        assert_eq!(TEST_DEVICE.capabilities(0, &mut bitmap), 4);
+        assert_eq!(bitmap[0], 1 << EV_SYN | 1 << EV_KEY | 1 << EV_REL);
+        let mut bitmap = [0u8; 1];
+        assert_eq!(TEST_DEVICE.capabilities(EV_REL, &mut bitmap), 1);
+        assert_eq!(bitmap[0], 0b11);
+        assert_eq!(key_code(0x1e, false), Some(30));
+        assert_eq!(key_code(0x48, true), Some(103));
+        assert_eq!(key_code(0x2a, true), None);
+    }
+}
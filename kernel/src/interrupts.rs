@@ -1,8 +1,11 @@
 use crate::gdt;
+#[cfg(feature = "userspace")]
+use alloc::string::String;
 use core::sync::atomic::{AtomicU64, Ordering};
-use lazy_static::lazy_static;
 use pic8259::ChainedPics;
+use shared::once::Once;
 use shared::serial_println;
+use shared::sync::IrqMutex;
 use spin::Mutex;
 use x86_64::instructions::port::Port;
 use x86_64::registers::control::Cr2;
@@ -20,6 +23,9 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard = PIC_1_OFFSET + 1,
+    Rtc = PIC_2_OFFSET,
+    #[cfg(feature = "userspace")]
+    Mouse = PIC_2_OFFSET + 4,
 }
 
 impl InterruptIndex {
@@ -31,9 +37,37 @@ impl InterruptIndex {
     }
 }
 
+// Linux signal numbers of the faults user code can raise
+const SIGILL: u8 = 4;
+#[cfg(feature = "userspace")]
+pub const SIGTRAP: u8 = 5;
+const SIGBUS: u8 = 7;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
 // Ticks counter (Thread-safe)
 pub static TICKS: AtomicU64 = AtomicU64::new(0);
 
+// Time since the PIT started, in nanoseconds (millisecond resolution)
+pub fn uptime_ns() -> u64 {
+    TICKS.load(Ordering::Relaxed) * 1_000_000
+}
+
+// Start of the unmapped page below the boot stack (0 if unknown): a fault there
+// is a kernel stack overflow
+static STACK_GUARD: AtomicU64 = AtomicU64::new(0);
+
+pub fn init_stack_guard(boot_info: &shared::BootInfo) {
+    if let Some(guard) = boot_info.stack_guard() {
+        STACK_GUARD.store(guard.start, Ordering::Relaxed);
+    }
+}
+
+fn in_stack_guard(addr: u64) -> bool {
+    let guard = STACK_GUARD.load(Ordering::Relaxed);
+    guard != 0 && (guard..guard + 4096).contains(&addr)
+}
+
 // PICS Driver (Thread-safe wrapper)
 pub static PICS: LockedPics = LockedPics::new(PIC_1_OFFSET, PIC_2_OFFSET);
 
@@ -46,60 +80,96 @@ struct KeyboardBuffer {
     tail: usize,
 }
 
-lazy_static! {
-    static ref KEYBOARD_BUFFER: Mutex<KeyboardBuffer> = Mutex::new(KeyboardBuffer {
-        buffer: [0; BUFFER_SIZE],
-        head: 0,
-        tail: 0,
-    });
-}
+static KEYBOARD_BUFFER: Mutex<KeyboardBuffer> = Mutex::new(KeyboardBuffer {
+    buffer: [0; BUFFER_SIZE],
+    head: 0,
+    tail: 0,
+});
 
 // ============================================================================
 // 2. IDT INITIALIZATION (Modern Approach)
 // ============================================================================
 
-lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-
-        // Exceptions
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+// Built and loaded by `init_idt`
+static IDT: Once<InterruptDescriptorTable> = Once::new();
 
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
+fn build_idt() -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
 
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.general_protection_fault.set_handler_fn(general_protection_handler);
+    // Exceptions
+    // With user programs, int3 and single-steps may stop a traced program
+    #[cfg(feature = "userspace")]
+    crate::ptrace::install(&mut idt);
+    #[cfg(not(feature = "userspace"))]
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
 
-        // Hardware Interrupts - SỬA: dùng as_usize() thay vì as_u8()
-        idt[InterruptIndex::Timer.as_u8()].set_handler_fn(timer_handler);
-        idt[InterruptIndex::Keyboard.as_u8()].set_handler_fn(keyboard_handler);
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
 
-        idt
-    };
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_handler);
+
+    // Faults a user program can raise: fatal for the program, not the kernel
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.x87_floating_point
+        .set_handler_fn(x87_floating_point_handler);
+    idt.simd_floating_point
+        .set_handler_fn(simd_floating_point_handler);
+    idt.stack_segment_fault
+        .set_handler_fn(stack_segment_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.device_not_available
+        .set_handler_fn(device_not_available_handler);
+
+    // Hardware Interrupts - SỬA: dùng as_usize() thay vì as_u8()
+    idt[InterruptIndex::Timer.as_u8()].set_handler_fn(timer_handler);
+    idt[InterruptIndex::Keyboard.as_u8()].set_handler_fn(keyboard_handler);
+    idt[InterruptIndex::Rtc.as_u8()].set_handler_fn(rtc_handler);
+    #[cfg(feature = "userspace")]
+    idt[InterruptIndex::Mouse.as_u8()].set_handler_fn(mouse_handler);
+
+    idt
 }
 
 pub fn init_idt() {
-    IDT.load();
+    IDT.call_once(build_idt).load();
 }
 
 // ============================================================================
 // 3. EXCEPTION HANDLERS
 // ============================================================================
 
+#[cfg(not(feature = "userspace"))]
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
-    _error_code: u64,
+    error_code: u64,
 ) -> ! {
     serial_println!("\nPANIC: DOUBLE FAULT EXCEPTION");
     serial_println!("{:#?}", stack_frame);
+    // The page fault of an overflow cannot push its frame on the full stack,
+    // so it arrives here, on the IST stack, with CR2 in the guard page
+    let cr2 = Cr2::read_raw();
+    let name = if in_stack_guard(cr2) {
+        serial_println!(
+            "Kernel stack overflow: {:#x} is in the guard page below the boot stack",
+            cr2
+        );
+        "kernel stack overflow"
+    } else {
+        "double fault"
+    };
+    crate::crashdump::exception(name, &stack_frame, Some(error_code));
     loop {
         x86_64::instructions::hlt();
     }
@@ -111,8 +181,17 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     let cr2 = Cr2::read();
 
+    // First touch of demand-paged user memory: map it and retry the access
+    #[cfg(feature = "userspace")]
+    if crate::vma::handle_fault(Cr2::read_raw(), error_code) {
+        return;
+    }
+
     serial_println!("EXCEPTION: PAGE FAULT");
     serial_println!("Accessed Address: {:?}", cr2);
+    if in_stack_guard(Cr2::read_raw()) {
+        serial_println!("Kernel stack overflow: the address is the boot stack's guard page");
+    }
     serial_println!("Error Code: {:#x} ({:?})", error_code.bits(), error_code);
 
     let bits = error_code.bits();
@@ -150,7 +229,15 @@ extern "x86-interrupt" fn page_fault_handler(
         (bits & PageFaultErrorCode::RMP.bits()) != 0,
     );
 
+    #[cfg(feature = "userspace")]
+    if bits & PageFaultErrorCode::USER_MODE.bits() != 0 {
+        crate::rusage::page_fault();
+    }
+    #[cfg(feature = "userspace")]
+    kill_user_program(&stack_frame, "page fault", SIGSEGV, Some(bits));
+
     serial_println!("{:#?}", stack_frame);
+    crate::crashdump::exception("page fault", &stack_frame, Some(bits));
 
     loop {
         x86_64::instructions::hlt();
@@ -161,20 +248,153 @@ extern "x86-interrupt" fn general_protection_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    #[cfg(feature = "userspace")]
+    kill_user_program(
+        &stack_frame,
+        "general protection fault",
+        SIGSEGV,
+        Some(error_code),
+    );
+
     serial_println!("EXCEPTION: GENERAL PROTECTION FAULT");
     serial_println!("Error Code: {:#x}", error_code);
     serial_println!("{:#?}", stack_frame);
+    crate::crashdump::exception("general protection fault", &stack_frame, Some(error_code));
     loop {
         x86_64::instructions::hlt();
     }
 }
 
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    fatal_fault(&stack_frame, "divide error", SIGFPE, None);
+}
+
+extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    fatal_fault(&stack_frame, "overflow", SIGSEGV, None);
+}
+
+extern "x86-interrupt" fn bound_range_handler(stack_frame: InterruptStackFrame) {
+    fatal_fault(&stack_frame, "bound range exceeded", SIGSEGV, None);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    fatal_fault(&stack_frame, "invalid opcode", SIGILL, None);
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    fatal_fault(&stack_frame, "x87 floating point", SIGFPE, None);
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    fatal_fault(&stack_frame, "SIMD floating point", SIGFPE, None);
+}
+
+extern "x86-interrupt" fn stack_segment_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    fatal_fault(
+        &stack_frame,
+        "stack segment fault",
+        SIGBUS,
+        Some(error_code),
+    );
+}
+
+extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal_fault(&stack_frame, "alignment check", SIGBUS, Some(error_code));
+}
+
+extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    // A stray CR0.TS (nothing saves FPU state lazily): clear it and retry
+    if crate::fpu::clear_task_switched() {
+        return;
+    }
+    fatal_fault(&stack_frame, "device not available", SIGFPE, None);
+}
+
+// A fault without a recovery path: the user program that raised it is killed,
+// a fault in the kernel itself stops the machine with a crash dump
+#[cfg_attr(not(feature = "userspace"), allow(unused_variables))]
+fn fatal_fault(frame: &InterruptStackFrame, name: &str, signal: u8, error_code: Option<u64>) -> ! {
+    #[cfg(feature = "userspace")]
+    kill_user_program(frame, name, signal, error_code);
+
+    serial_println!("EXCEPTION: {}", name);
+    if let Some(code) = error_code {
+        serial_println!("Error Code: {:#x}", code);
+    }
+    serial_println!("{:#?}", frame);
+    crate::crashdump::exception(name, frame, error_code);
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+// Report and end the running program if the fault came from ring 3, the way
+// the default action of `signal` would; returns for kernel faults
+// (No handlers can be installed yet: rt_sigaction is a stub)
+#[cfg(feature = "userspace")]
+fn kill_user_program(frame: &InterruptStackFrame, name: &str, signal: u8, error_code: Option<u64>) {
+    if frame.code_segment.rpl() != x86_64::PrivilegeLevel::Ring3 {
+        return;
+    }
+    kill_user(
+        name,
+        signal,
+        frame.instruction_pointer.as_u64(),
+        frame.stack_pointer.as_u64(),
+        error_code,
+    )
+}
+
+// End the running program for `signal`, raised by `name` at `rip`
+#[cfg(feature = "userspace")]
+pub fn kill_user(name: &str, signal: u8, rip: u64, rsp: u64, error_code: Option<u64>) -> ! {
+    let mut program = String::from("?");
+    crate::shell::with_program(|name, _| program = String::from(name));
+    log::error!(
+        "{}: killed by signal {} ({} at rip={:#x}, rsp={:#x}, error code {:#x})",
+        program,
+        signal,
+        name,
+        rip,
+        rsp,
+        error_code.unwrap_or(0)
+    );
+
+    // Shell convention for a status: 128 + signal
+    crate::syscalls::end_program(128 + signal as u64)
+}
+
 // ============================================================================
 // 4. HARDWARE INTERRUPT HANDLERS
 // ============================================================================
 
 extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
-    TICKS.fetch_add(1, Ordering::Relaxed);
+    #[cfg_attr(
+        not(any(feature = "graphics", feature = "net")),
+        allow(unused_variables)
+    )]
+    let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+
+    // Periodically push the screen back buffer to the framebuffer
+    #[cfg(feature = "graphics")]
+    if ticks.is_multiple_of(crate::screen::FLUSH_INTERVAL_TICKS) {
+        crate::screen::flush_from_interrupt();
+    }
+
+    // Refresh the uptime / memory status bar
+    #[cfg(feature = "graphics")]
+    if ticks.is_multiple_of(crate::screen::STATUS_INTERVAL_TICKS) {
+        crate::screen::update_status_from_interrupt();
+    }
+
+    // Network RX bottom half
+    #[cfg(feature = "net")]
+    if ticks.is_multiple_of(crate::net::POLL_INTERVAL_TICKS) {
+        crate::net::poll_from_interrupt(ticks);
+    }
 
     unsafe {
         PICS.notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
@@ -186,12 +406,31 @@ extern "x86-interrupt" fn keyboard_handler(_stack_frame: InterruptStackFrame) {
     let scancode: u8 = unsafe { port.read() };
 
     add_scancode(scancode);
+    #[cfg(feature = "userspace")]
+    crate::input::keyboard_scancode(scancode);
 
     unsafe {
         PICS.notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
     }
 }
 
+extern "x86-interrupt" fn rtc_handler(_stack_frame: InterruptStackFrame) {
+    crate::rtc::handle_interrupt();
+
+    unsafe {
+        PICS.notify_end_of_interrupt(InterruptIndex::Rtc.as_u8());
+    }
+}
+
+#[cfg(feature = "userspace")]
+extern "x86-interrupt" fn mouse_handler(_stack_frame: InterruptStackFrame) {
+    crate::mouse::handle_interrupt();
+
+    unsafe {
+        PICS.notify_end_of_interrupt(InterruptIndex::Mouse.as_u8());
+    }
+}
+
 // ============================================================================
 // 5. HELPER FUNCTIONS
 // ============================================================================
@@ -242,26 +481,50 @@ pub fn init_timer() {
 // ============================================================================
 
 pub struct LockedPics {
-    inner: Mutex<ChainedPics>,
+    inner: IrqMutex<ChainedPics>,
 }
 
 impl LockedPics {
     pub const fn new(offset1: u8, offset2: u8) -> Self {
         Self {
-            inner: Mutex::new(unsafe { ChainedPics::new(offset1, offset2) }),
+            inner: IrqMutex::new(unsafe { ChainedPics::new(offset1, offset2) }),
         }
     }
 
     pub fn initialize(&self) {
-        // An toàn hơn: disable interrupt khi init PIC
-        x86_64::instructions::interrupts::without_interrupts(|| {
-            unsafe {
-                let mut pics = self.inner.lock();
-                pics.initialize();
-                // Mở IRQ 0 (timer) và IRQ 1 (keyboard): mask = 0b1111_1100 = 0xFC
-                pics.write_masks(0xFC, 0xFF);
-            }
-        });
+        // The IrqMutex keeps interrupts off while the PICs are reprogrammed
+        let mut pics = self.inner.lock();
+        unsafe {
+            pics.initialize();
+            // Mở IRQ 0 (timer) và IRQ 1 (keyboard): mask = 0b1111_1100 = 0xFC
+            pics.write_masks(0xFC, 0xFF);
+        }
+    }
+
+    // Let IRQ `irq` through (a secondary PIC line also needs the cascade, IRQ 2)
+    pub fn unmask(&self, irq: u8) {
+        let mut masks = self.masks();
+        if irq >= 8 {
+            masks[0] &= !(1 << 2);
+            masks[1] &= !(1 << (irq - 8));
+        } else {
+            masks[0] &= !(1 << irq);
+        }
+        self.set_masks(masks);
+    }
+
+    // Mask every IRQ line (before handing the machine to another kernel)
+    pub fn mask_all(&self) {
+        unsafe { self.inner.lock().write_masks(0xFF, 0xFF) }
+    }
+
+    // IRQ masks of the primary and secondary PIC
+    pub fn masks(&self) -> [u8; 2] {
+        unsafe { self.inner.lock().read_masks() }
+    }
+
+    pub fn set_masks(&self, masks: [u8; 2]) {
+        unsafe { self.inner.lock().write_masks(masks[0], masks[1]) }
     }
 
     /// Chỉ dùng trong interrupt handler (interrupt đã bị disable tự động)
@@ -269,3 +532,22 @@ impl LockedPics {
         unsafe { self.inner.lock().notify_end_of_interrupt(id) }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86_64::VirtAddr;
+    use x86_64::structures::paging::Translate;
+
+    #[test_case]
+    fn boot_stack_guard_is_unmapped() {
+        let guard = STACK_GUARD.load(Ordering::Relaxed);
+        if guard == 0 {
+            return;
+        }
+        let mapper = unsafe { crate::pml4::init_mapper(crate::elf_loader::get_hhdm_offset()) };
+        assert!(mapper.translate_addr(VirtAddr::new(guard)).is_none());
+        assert!(mapper.translate_addr(VirtAddr::new(guard + 4096)).is_some());
+        assert!(in_stack_guard(guard) && !in_stack_guard(guard + 4096));
+    }
+}
@@ -0,0 +1,182 @@
+// Integration Tests (feature "integration-tests")
+// A kernel built with the feature runs the user-space test suite instead of the
+// built-in program and the shell: every program in TEST_DIR of the initramfs,
+// one after the other in name order. A program passes if it exits with status 0
+// and, when a `<name>.expected` transcript sits next to it, its stdout/stderr
+// output matches the transcript byte for byte. After the last program the summary
+// goes to serial and the kernel exits QEMU (through isa-debug-exit) with the
+// result, which run-integration-tests.sh turns into its own exit status.
+//
+// There is no scheduler: like the shell's `run`, each program replaces the
+// previous one, and its exit continues the suite on the syscall stack.
+
+use crate::testing::{QemuExitCode, exit_qemu};
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Directory holding the test programs and their transcripts
+const TEST_DIR: &str = "/tests";
+const EXPECTED_SUFFIX: &str = ".expected";
+
+struct Suite {
+    programs: Vec<String>,
+    next: usize,
+    failed: Vec<String>,
+    // Output of the running program
+    output: Vec<u8>,
+}
+
+static SUITE: Mutex<Option<Suite>> = Mutex::new(None);
+
+// Test programs, by name
+fn find_programs() -> Vec<String> {
+    let Ok(entries) = crate::fs::lookup(TEST_DIR).and_then(|dir| dir.readdir()) else {
+        return Vec::new();
+    };
+    let mut programs: Vec<String> = entries
+        .into_iter()
+        .filter(|entry| {
+            entry.file_type == crate::fs::FileType::Regular
+                && !entry.name.ends_with(EXPECTED_SUFFIX)
+        })
+        .map(|entry| entry.name)
+        .collect();
+    programs.sort();
+    programs
+}
+
+// Start the suite, called by `_start` in place of the first user program
+pub fn run() -> ! {
+    let programs = find_programs();
+    shared::serial_println!("running {} integration tests", programs.len());
+    if programs.is_empty() {
+        shared::serial_println!("no test programs in {}", TEST_DIR);
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    interrupts::without_interrupts(|| {
+        *SUITE.lock() = Some(Suite {
+            programs,
+            next: 0,
+            failed: Vec::new(),
+            output: Vec::new(),
+        });
+    });
+    start_next()
+}
+
+// Record console output of the running program (called by write)
+pub fn capture(bytes: &[u8]) {
+    interrupts::without_interrupts(|| {
+        if let Some(suite) = &mut *SUITE.lock() {
+            suite.output.extend_from_slice(bytes);
+        }
+    });
+}
+
+// Called by exit: check the program's result and go on with the next one
+pub fn program_exited(status: u64) -> ! {
+    let (name, output) = interrupts::without_interrupts(|| {
+        let mut suite = SUITE.lock();
+        let suite = suite.as_mut().expect("integration test suite not started");
+        (
+            suite.programs[suite.next - 1].clone(),
+            core::mem::take(&mut suite.output),
+        )
+    });
+
+    let result = check(&name, status, &output);
+    match &result {
+        Ok(()) => shared::serial_println!("test {} ... ok", name),
+        Err(reason) => shared::serial_println!("test {} ... FAILED: {}", name, reason),
+    }
+    if result.is_err() {
+        interrupts::without_interrupts(|| {
+            if let Some(suite) = &mut *SUITE.lock() {
+                suite.failed.push(name);
+            }
+        });
+    }
+    start_next()
+}
+
+fn check(name: &str, status: u64, output: &[u8]) -> Result<(), String> {
+    if status != 0 {
+        return Err(alloc::format!("exit status {}", status));
+    }
+    let Ok(expected) =
+        crate::fs::read_file(&alloc::format!("{}/{}{}", TEST_DIR, name, EXPECTED_SUFFIX))
+    else {
+        return Ok(());
+    };
+    if output == expected.as_slice() {
+        return Ok(());
+    }
+
+    // Report the first differing line
+    let expected = String::from_utf8_lossy(&expected);
+    let output = String::from_utf8_lossy(output);
+    let mismatch = expected
+        .split('\n')
+        .zip(output.split('\n'))
+        .enumerate()
+        .find(|(_, (want, got))| want != got);
+    Err(match mismatch {
+        Some((line, (want, got))) => {
+            alloc::format!("line {}: expected {:?}, got {:?}", line + 1, want, got)
+        }
+        None => alloc::format!(
+            "expected {} bytes of output, got {}",
+            expected.len(),
+            output.len()
+        ),
+    })
+}
+
+// Enter the next program, or finish the suite
+fn start_next() -> ! {
+    loop {
+        let next = interrupts::without_interrupts(|| {
+            let mut suite = SUITE.lock();
+            let suite = suite.as_mut().expect("integration test suite not started");
+            let name = suite.programs.get(suite.next).cloned();
+            suite.next += 1;
+            name
+        });
+        let Some(name) = next else {
+            finish();
+        };
+
+        let Err(err) = crate::shell::exec(&alloc::format!("{}/{}", TEST_DIR, name));
+        shared::serial_println!("test {} ... FAILED: not started: {}", name, err);
+        interrupts::without_interrupts(|| {
+            if let Some(suite) = &mut *SUITE.lock() {
+                suite.failed.push(name);
+            }
+        });
+    }
+}
+
+fn finish() -> ! {
+    let (total, failed) = interrupts::without_interrupts(|| {
+        let suite = SUITE.lock();
+        let suite = suite.as_ref().expect("integration test suite not started");
+        (suite.programs.len(), suite.failed.clone())
+    });
+
+    if failed.is_empty() {
+        shared::serial_println!("integration test result: ok. {} passed", total);
+        exit_qemu(QemuExitCode::Success);
+    }
+    shared::serial_println!(
+        "integration test result: FAILED. {} passed, {} failed",
+        total - failed.len(),
+        failed.len()
+    );
+    for name in &failed {
+        shared::serial_println!("    {}", name);
+    }
+    exit_qemu(QemuExitCode::Failed);
+}
@@ -0,0 +1,430 @@
+// kexec
+// Warm reboot into another kernel without the firmware and the bootloader:
+// `kexec <path>` loads a kernel ELF from the VFS into one block of physically
+// contiguous reserved memory, together with everything the bootloader would have
+// set up for it (page tables mapping its segments, its stack, the HHDM in 1 GiB
+// or 2 MiB pages and the framebuffer; a BootInfo; a memory map), quiesces the devices and
+// jumps to its entry point.
+//
+// The BootInfo is a copy of the one this kernel was started with, so the new
+// kernel finds the same framebuffer, ACPI tables, initramfs and font. Those all
+// live in memory the PMM never hands out (LOADER_DATA, ACPI, MMIO), so they are
+// intact. The new memory map is the original one with the block carved out of
+// conventional memory as LOADER_DATA; the rest of the conventional memory this
+// kernel used is free again for the new one.
+//
+// A kernel linked as a PIE is loaded at a fresh random slide (none with
+// `nokaslr`) and relocated, as the bootloader does (shared::kaslr).
+//
+// With 5-level paging a PML5 goes above the new PML4, as the bootloader does
+// (shared::paging): LA57 stays on across the jump.
+//
+// The jump runs from the HHDM alias of `enter`: both the old and the new page
+// tables map the HHDM, so the code stays mapped when CR3 is switched.
+
+use alloc::vec::Vec;
+use core::convert::Infallible;
+use shared::hhdm;
+use shared::kaslr::{self, KERNEL_BASE};
+use shared::memory::{self, MemoryKind, MemoryRegion, Reservation, ReservedRange};
+use shared::once::Once;
+use shared::{BootInfo, KERNEL_STACK_TOP};
+use x86_64::instructions::random::RdRand;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+use xmas_elf::ElfFile;
+use xmas_elf::header::{Machine, Type};
+use xmas_elf::program;
+
+const PAGE_SIZE: u64 = 4096;
+
+static BOOT_INFO: Once<&'static BootInfo> = Once::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KexecError {
+    // Not an x86_64 kernel ELF linked where kernels are loaded
+    BadImage(&'static str),
+    // No contiguous block of physical memory large enough
+    NoMemory,
+    // The BootInfo of this boot was not recorded
+    NoBootInfo,
+}
+
+// A kernel ready to start
+struct Loaded {
+    root: u64, // PML4, or PML5 with 5-level paging
+    boot_info: u64,
+    entry: u64,
+}
+
+// Keep the BootInfo this kernel was started with, for the next one
+pub fn init(boot_info: &'static BootInfo) {
+    BOOT_INFO.call_once(|| boot_info);
+}
+
+// Page tables mapping `pages` consecutive 4 KiB pages, at most
+fn tables_for(pages: u64) -> u64 {
+    // PTs, PDs and PDPTs, each range possibly straddling one more table
+    pages.div_ceil(512) + 1 + pages.div_ceil(512 * 512) + 1 + 2
+}
+
+// Page tables of the block, handed out bottom-up (already zeroed)
+struct TableAllocator {
+    next: u64,
+    end: u64,
+}
+
+unsafe impl FrameAllocator<Size4KiB> for TableAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        if self.next >= self.end {
+            return None;
+        }
+        let frame = PhysFrame::containing_address(PhysAddr::new(self.next));
+        self.next += PAGE_SIZE;
+        Some(frame)
+    }
+}
+
+// The block, given back to the PMM if loading fails part way
+struct Block {
+    phys: u64,
+    order: usize,
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        crate::pmm::free_contiguous(self.phys, self.order);
+    }
+}
+
+// Page range [first, last] of a segment
+fn segment_pages(ph: &program::ProgramHeader) -> (u64, u64) {
+    let start = ph.virtual_addr() & !(PAGE_SIZE - 1);
+    let end = ph.virtual_addr() + ph.mem_size().max(1);
+    (start, end.div_ceil(PAGE_SIZE) * PAGE_SIZE)
+}
+
+// Page flags of a segment, W^X as the bootloader maps them
+fn segment_flags(elf_flags: program::Flags) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT;
+    if elf_flags.is_write() {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !elf_flags.is_execute() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+// Slide for the next kernel: random bits from RDRAND, else the virtio entropy
+// device, else the TSC
+fn choose_slide() -> u64 {
+    if shared::cmdline::current().has("nokaslr") {
+        return 0;
+    }
+    let random = RdRand::new()
+        .and_then(RdRand::get_u64)
+        .or_else(|| {
+            let mut bytes = [0; 8];
+            crate::virtio_rng::fill(&mut bytes).then(|| u64::from_ne_bytes(bytes))
+        })
+        .unwrap_or_else(|| unsafe { core::arch::x86_64::_rdtsc() });
+    kaslr::slide_from_random(random)
+}
+
+// ============================================================================
+// 1. LOADING
+// ============================================================================
+
+fn load(image: &[u8], current: &BootInfo) -> Result<Loaded, KexecError> {
+    let elf = ElfFile::new(image).map_err(KexecError::BadImage)?;
+    let relocatable = match elf.header.pt2.type_().as_type() {
+        Type::Executable => false,
+        Type::SharedObject => true,
+        _ => return Err(KexecError::BadImage("not an x86_64 executable")),
+    };
+    if elf.header.pt2.machine().as_machine() != Machine::X86_64 {
+        return Err(KexecError::BadImage("not an x86_64 executable"));
+    }
+    let slide = if relocatable { choose_slide() } else { 0 };
+    let segments: Vec<_> = elf
+        .program_iter()
+        .filter(|ph| ph.get_type() == Ok(program::Type::Load))
+        .collect();
+    let mut segment_total = 0;
+    let mut kernel_end = KERNEL_BASE;
+    for ph in &segments {
+        let (start, end) = segment_pages(ph);
+        if start < KERNEL_BASE || ph.offset() + ph.file_size() > image.len() as u64 {
+            return Err(KexecError::BadImage(
+                "segment outside the kernel image area",
+            ));
+        }
+        if ph.file_size() > ph.mem_size() {
+            return Err(KexecError::BadImage("bad segment size"));
+        }
+        segment_total += (end - start) / PAGE_SIZE;
+        kernel_end = kernel_end.max(end);
+    }
+    let entry = elf.header.pt2.entry_point();
+    if !segments.iter().any(|ph| {
+        ph.flags().is_execute()
+            && (ph.virtual_addr()..ph.virtual_addr() + ph.mem_size()).contains(&entry)
+    }) {
+        return Err(KexecError::BadImage("entry point outside the code"));
+    }
+    let entry = entry + slide;
+
+    let hhdm = current.hhdm_offset;
+    let max_phys = current.max_phys_memory;
+    let old_map = unsafe { current.memory_map(hhdm) };
+    // The stack size this kernel was started with (see shared::KERNEL_STACK_TOP)
+    let stack_size = match current.stack_size {
+        0 => shared::DEFAULT_KERNEL_STACK,
+        size => size,
+    };
+    let stack_pages = stack_size.div_ceil(PAGE_SIZE);
+    // Carving the block out of a region makes up to three of one
+    let map_capacity = old_map.len() * 3;
+    let map_pages =
+        ((map_capacity * core::mem::size_of::<MemoryRegion>()) as u64).div_ceil(PAGE_SIZE);
+    let framebuffer = current.framebuffer;
+    let fb_pages = (framebuffer.buffer_size as u64).div_ceil(PAGE_SIZE);
+    let hhdm_page_size = hhdm::page_size();
+    let table_pages = 1
+        + u64::from(crate::pml4::five_level())
+        + hhdm::tables_for(max_phys, hhdm_page_size)
+        + 2
+        + tables_for(fb_pages)
+        + tables_for(stack_pages)
+        + tables_for((kernel_end - KERNEL_BASE) / PAGE_SIZE);
+
+    // Block layout: segments, stack, BootInfo, memory map, page tables
+    let pages = segment_total + stack_pages + 1 + map_pages + table_pages;
+    let order = crate::pmm::order_for(pages as usize);
+    let block = crate::pmm::allocate_contiguous(order).ok_or(KexecError::NoMemory)?;
+    let guard = Block { phys: block, order };
+    let at = |page: u64| block + page * PAGE_SIZE;
+    let stack = at(segment_total);
+    let boot_info = at(segment_total + stack_pages);
+    let map = at(segment_total + stack_pages + 1);
+    let tables = at(segment_total + stack_pages + 1 + map_pages);
+    unsafe { core::ptr::write_bytes((block + hhdm) as *mut u8, 0, (pages * PAGE_SIZE) as usize) };
+
+    let mut allocator = TableAllocator {
+        next: tables,
+        end: at(pages),
+    };
+    let pml4_phys = allocator.allocate_frame().ok_or(KexecError::NoMemory)?;
+    let pml4 = unsafe { &mut *((pml4_phys.start_address().as_u64() + hhdm) as *mut PageTable) };
+    let root = if crate::pml4::five_level() {
+        let pml5_phys = allocator.allocate_frame().ok_or(KexecError::NoMemory)?;
+        let pml5 = unsafe { &mut *((pml5_phys.start_address().as_u64() + hhdm) as *mut PageTable) };
+        shared::paging::wrap_pml4(pml5, pml4_phys.start_address());
+        pml5_phys.start_address().as_u64()
+    } else {
+        pml4_phys.start_address().as_u64()
+    };
+    let mut mapper = unsafe { OffsetPageTable::new(pml4, VirtAddr::new(hhdm)) };
+    let mut map_4k = |virt: u64, phys: u64, flags: PageTableFlags| {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(virt));
+        let frame = PhysFrame::containing_address(PhysAddr::new(phys));
+        unsafe { mapper.map_to(page, frame, flags, &mut allocator) }
+            .map(|flush| flush.ignore())
+            .map_err(|_| KexecError::BadImage("overlapping segments"))
+    };
+
+    // Segments, copied into the block and mapped where they are linked (+ slide)
+    // Link-time page range and physical address of each, for the relocations
+    let mut placed = Vec::with_capacity(segments.len());
+    let mut next = block;
+    for ph in &segments {
+        let (start, end) = segment_pages(ph);
+        placed.push((start..end, next));
+        let in_page = ph.virtual_addr() - start;
+        let data = &image[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize];
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                (next + in_page + hhdm) as *mut u8,
+                data.len(),
+            )
+        };
+        let flags = segment_flags(ph.flags());
+        for virt in (start..end).step_by(PAGE_SIZE as usize) {
+            map_4k(virt + slide, next, flags)?;
+            next += PAGE_SIZE;
+        }
+    }
+
+    if relocatable {
+        let rela = elf
+            .find_section_by_name(".rela.dyn")
+            .map_or(&[][..], |section| section.raw_data(&elf));
+        kaslr::relocate(rela, slide, |place, value| {
+            let Some((range, phys)) = placed
+                .iter()
+                .find(|(range, _)| range.start <= place && place + 8 <= range.end)
+            else {
+                return false;
+            };
+            let target = phys + (place - range.start) + hhdm;
+            unsafe { core::ptr::write_unaligned(target as *mut u64, value) };
+            true
+        })
+        .map_err(|_| KexecError::BadImage("bad relocations"))?;
+    }
+
+    // The stack below the kernel, over an unmapped guard page, as the bootloader
+    // sets it up
+    let stack_bottom = KERNEL_STACK_TOP - stack_pages * PAGE_SIZE;
+    for i in 0..stack_pages {
+        map_4k(
+            stack_bottom + i * PAGE_SIZE,
+            stack + i * PAGE_SIZE,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )?;
+    }
+
+    // The framebuffer at the address the BootInfo gives
+    for i in 0..fb_pages {
+        map_4k(
+            framebuffer.buffer_base + i * PAGE_SIZE,
+            framebuffer.buffer_phys + i * PAGE_SIZE,
+            PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::NO_CACHE
+                | PageTableFlags::NO_EXECUTE,
+        )?;
+    }
+
+    // All physical memory in the HHDM
+    if !hhdm::map(&mut mapper, &mut allocator, max_phys, hhdm, hhdm_page_size) {
+        return Err(KexecError::NoMemory);
+    }
+
+    // The memory map, the block taken out of usable memory
+    let reserved = block..at(pages);
+    let regions =
+        unsafe { core::slice::from_raw_parts_mut((map + hhdm) as *mut MemoryRegion, map_capacity) };
+    let mut count = 0;
+    for region in old_map {
+        let pieces = if region.kind == MemoryKind::Usable
+            && region.start < reserved.end
+            && reserved.start < region.end()
+        {
+            let inner = region.start.max(reserved.start)..region.end().min(reserved.end);
+            [
+                (MemoryKind::Usable, region.start..inner.start),
+                (MemoryKind::Bootloader, inner.clone()),
+                (MemoryKind::Usable, inner.end..region.end()),
+            ]
+        } else {
+            let none = (MemoryKind::Usable, 0..0);
+            [
+                (region.kind, region.start..region.end()),
+                none.clone(),
+                none,
+            ]
+        };
+        for (kind, range) in pieces {
+            regions[count] = MemoryRegion {
+                start: range.start,
+                len: range.end - range.start,
+                kind,
+            };
+            count += 1;
+        }
+    }
+    let count = memory::normalize(&mut regions[..count]) as u64;
+
+    // The BootInfo, ours with the new memory map
+    unsafe {
+        let info = (boot_info + hhdm) as *mut BootInfo;
+        core::ptr::copy_nonoverlapping(current as *const BootInfo, info, 1);
+        (*info).memory_map_addr = map;
+        (*info).memory_map_len = count;
+        (*info).kernel_slide = slide;
+        // The new kernel is not on trial, whatever this one was
+        (*info).boot_trial = 0;
+        (*info).stack_top = KERNEL_STACK_TOP;
+        (*info).stack_size = stack_pages * PAGE_SIZE;
+        // The pieces of the block, in the new kernel's terms
+        (*info).reserved_count = 0;
+        for range in [
+            ReservedRange::new(block, segment_total, Reservation::KernelImage),
+            ReservedRange::new(stack, stack_pages, Reservation::Stack),
+            ReservedRange::new(boot_info, 1, Reservation::BootInfo),
+            ReservedRange::new(map, map_pages, Reservation::MemoryMap),
+            ReservedRange::new(tables, table_pages, Reservation::PageTables),
+        ] {
+            (*info).reserve(range);
+        }
+    }
+
+    log::info!(
+        "kexec: {} KiB reserved at {:#x}, entry {:#x} (slide {:#x})",
+        pages * PAGE_SIZE / 1024,
+        block,
+        entry,
+        slide
+    );
+    // Loaded: the block belongs to the new kernel now
+    core::mem::forget(guard);
+    Ok(Loaded {
+        root,
+        boot_info: boot_info + hhdm,
+        entry,
+    })
+}
+
+// ============================================================================
+// 2. JUMP
+// ============================================================================
+
+// Switch to the new page tables and stack and enter the kernel with its BootInfo
+// Position independent: it runs from its HHDM alias
+#[unsafe(naked)]
+unsafe extern "C" fn enter(root: u64, stack_top: u64, boot_info: u64, entry: u64) -> ! {
+    core::arch::naked_asm!(
+        "mov cr3, rdi",
+        "mov rsp, rsi",
+        "xor ebp, ebp",
+        "mov rdi, rdx",
+        "jmp rcx",
+    )
+}
+
+// Load `image` and start it; returns only if it could not be loaded
+pub fn exec(image: &[u8]) -> Result<Infallible, KexecError> {
+    let current = *BOOT_INFO.get().ok_or(KexecError::NoBootInfo)?;
+    let loaded = load(image, current)?;
+
+    // The kernel image was loaded physically contiguous, so `enter` is too
+    let hhdm = current.hhdm_offset;
+    let mapper = unsafe { crate::pml4::init_mapper(hhdm) };
+    let enter_phys = mapper
+        .translate_addr(VirtAddr::new(enter as *const () as u64))
+        .expect("kexec: kernel code not mapped");
+    let enter_alias: unsafe extern "C" fn(u64, u64, u64, u64) -> ! =
+        unsafe { core::mem::transmute((enter_phys.as_u64() + hhdm) as usize) };
+
+    log::info!("kexec: shutting down devices and starting the new kernel");
+    #[cfg(feature = "graphics")]
+    crate::screen::flush();
+    crate::device::shutdown();
+    x86_64::instructions::interrupts::disable();
+    crate::interrupts::PICS.mask_all();
+
+    unsafe {
+        enter_alias(
+            loaded.root,
+            KERNEL_STACK_TOP,
+            loaded.boot_info,
+            loaded.entry,
+        )
+    }
+}
@@ -0,0 +1,352 @@
+// Kernel Log
+// Backend of the `log` facade: kernel code logs with `log::info!` etc. and the
+// record's target is its module path (shown without the "kernel::" prefix).
+// Every enabled record goes to three sinks:
+//   - serial:  unless turned off
+//   - screen:  only records at or above the console level (default Info)
+//   - ring:    the last KLOG_SIZE bytes, kept in memory for dmesg and /proc/kmsg
+//
+// Levels are adjustable at runtime through /proc/log_level:
+//   `debug`              default level for all targets
+//   `net::arp=trace`     level for a target and its submodules
+//   `console=warn`       screen sink level
+//   `serial=ttyS1,115200n8`  move the serial sink (and console) to another port
+//   `serial=off` / `serial=on`  stop or resume logging to serial
+// Reading it shows the current settings.
+//
+// The kernel command line sets the starting values: `loglevel=<level>`,
+// `console_loglevel=<level>` and `serial=off|ttyS<n>,...`; it is shown in
+// /proc/cmdline.
+//
+// The ring is a static buffer so records logged before the heap exists are kept.
+
+use crate::fs::procfs::{self, ProcEntry};
+use crate::fs::{FsError, FsResult};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(feature = "graphics")]
+use log::Level;
+use log::{LevelFilter, Log, Metadata, Record};
+use shared::{cmdline, serial};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Size of the in-memory log
+const KLOG_SIZE: usize = 64 * 1024;
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+const DEFAULT_CONSOLE_LEVEL: LevelFilter = LevelFilter::Info;
+
+// Byte ring holding the most recent log text
+struct Ring {
+    data: [u8; KLOG_SIZE],
+    head: usize, // Next write position
+    len: usize,
+}
+
+impl Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.data[self.head] = byte;
+            self.head = (self.head + 1) % KLOG_SIZE;
+            self.len = (self.len + 1).min(KLOG_SIZE);
+        }
+        Ok(())
+    }
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring {
+    data: [0; KLOG_SIZE],
+    head: 0,
+    len: 0,
+});
+
+// Levels are stored as LevelFilter discriminants
+static LEVEL: AtomicUsize = AtomicUsize::new(DEFAULT_LEVEL as usize);
+static CONSOLE_LEVEL: AtomicUsize = AtomicUsize::new(DEFAULT_CONSOLE_LEVEL as usize);
+
+// Whether records go to the serial sink
+static SERIAL_LOG: AtomicBool = AtomicBool::new(true);
+
+// Per-target levels (target without the "kernel::" prefix), longest match wins
+static TARGET_LEVELS: Mutex<Vec<(String, LevelFilter)>> = Mutex::new(Vec::new());
+
+static LOGGER: KernelLogger = KernelLogger;
+
+static KMSG_ENTRY: ProcEntry = ProcEntry {
+    name: "kmsg",
+    read,
+    write: None,
+};
+
+static LOG_LEVEL_ENTRY: ProcEntry = ProcEntry {
+    name: "log_level",
+    read: read_levels,
+    write: Some(write_levels),
+};
+
+static CMDLINE_ENTRY: ProcEntry = ProcEntry {
+    name: "cmdline",
+    read: read_cmdline,
+    write: None,
+};
+
+fn level_from_usize(value: usize) -> LevelFilter {
+    LevelFilter::iter()
+        .find(|level| *level as usize == value)
+        .unwrap_or(DEFAULT_LEVEL)
+}
+
+fn short_target(target: &str) -> &str {
+    match target.strip_prefix("kernel::") {
+        Some(rest) => rest,
+        None => target,
+    }
+}
+
+// Level that applies to a target
+fn target_level(target: &str) -> LevelFilter {
+    let target = short_target(target);
+    let default = level_from_usize(LEVEL.load(Ordering::Relaxed));
+
+    interrupts::without_interrupts(|| {
+        let levels = TARGET_LEVELS.lock();
+        levels
+            .iter()
+            .filter(|(prefix, _)| {
+                target == prefix
+                    || (target.starts_with(prefix.as_str())
+                        && target[prefix.len()..].starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(default, |(_, level)| *level)
+    })
+}
+
+// The facade drops records above `log::max_level()` before calling the logger,
+// so it must allow the most verbose of all configured levels
+fn update_max_level() {
+    let default = level_from_usize(LEVEL.load(Ordering::Relaxed));
+    let max = interrupts::without_interrupts(|| {
+        TARGET_LEVELS
+            .lock()
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(default, core::cmp::max)
+    });
+    log::set_max_level(max);
+}
+
+struct KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= target_level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let level = record.level();
+        let target = short_target(record.target());
+        let args = record.args();
+
+        if SERIAL_LOG.load(Ordering::Relaxed) {
+            shared::serial::_print(format_args!("[{:<5} {}] {}\n", level, target, args));
+        }
+
+        interrupts::without_interrupts(|| {
+            let _ = writeln!(RING.lock(), "[{:<5} {}] {}", level, target, args);
+        });
+
+        #[cfg(feature = "graphics")]
+        if level <= level_from_usize(CONSOLE_LEVEL.load(Ordering::Relaxed)) {
+            print_console(level, target, args);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Warnings and errors stand out on screen
+#[cfg(feature = "graphics")]
+fn print_console(level: Level, target: &str, args: &fmt::Arguments) {
+    if let Some(writer) = &mut *crate::screen::WRITER.lock() {
+        let color = match level {
+            Level::Error => "\x1b[91m",
+            Level::Warn => "\x1b[93m",
+            _ => "",
+        };
+        let reset = if color.is_empty() { "" } else { "\x1b[39m" };
+        let _ = writeln!(writer, "{}[{}] {}{}", color, target, args, reset);
+        writer.flush();
+    }
+}
+
+// Install the logger, called first thing at boot
+pub fn init() {
+    let _ = log::set_logger(&LOGGER);
+    apply_cmdline();
+    update_max_level();
+}
+
+// Starting levels and serial sink from the kernel command line; a bad value is
+// reported and otherwise ignored
+fn apply_cmdline() {
+    let cmdline = cmdline::current();
+    if let Some(level) = cmdline.get("loglevel") {
+        match level.parse::<LevelFilter>() {
+            Ok(level) => LEVEL.store(level as usize, Ordering::Relaxed),
+            Err(_) => log::warn!("cmdline: bad loglevel={}", level),
+        }
+    }
+    if let Some(level) = cmdline.get("console_loglevel") {
+        match level.parse::<LevelFilter>() {
+            Ok(level) => set_console_level(level),
+            Err(_) => log::warn!("cmdline: bad console_loglevel={}", level),
+        }
+    }
+    if let Some(spec) = cmdline.get("serial")
+        && let Err(err) = set_serial(spec)
+    {
+        log::warn!("cmdline: serial={}: {:?}", spec, err);
+    }
+}
+
+// Register the /proc entries (needs the heap)
+pub fn init_proc() {
+    procfs::register(&KMSG_ENTRY);
+    procfs::register(&LOG_LEVEL_ENTRY);
+    procfs::register(&CMDLINE_ENTRY);
+}
+
+// Contents of the log ring, oldest first
+pub fn read() -> String {
+    interrupts::without_interrupts(|| {
+        let ring = RING.lock();
+        let start = (ring.head + KLOG_SIZE - ring.len) % KLOG_SIZE;
+        let mut bytes = Vec::with_capacity(ring.len);
+        for i in 0..ring.len {
+            bytes.push(ring.data[(start + i) % KLOG_SIZE]);
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    })
+}
+
+// Copy the end of the log ring into `buf` and return the length copied, for crash
+// dumps: does not allocate and gives up (returning 0) if the ring is locked
+pub fn copy_tail(buf: &mut [u8]) -> usize {
+    let Some(ring) = RING.try_lock() else {
+        return 0;
+    };
+    let len = ring.len.min(buf.len());
+    let start = (ring.head + KLOG_SIZE - len) % KLOG_SIZE;
+    for (i, byte) in buf[..len].iter_mut().enumerate() {
+        *byte = ring.data[(start + i) % KLOG_SIZE];
+    }
+    len
+}
+
+pub fn set_level(level: LevelFilter) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+    update_max_level();
+}
+
+// Level for a target (module path without "kernel::"), None removes the override
+pub fn set_target_level(target: &str, level: Option<LevelFilter>) {
+    interrupts::without_interrupts(|| {
+        let mut levels = TARGET_LEVELS.lock();
+        levels.retain(|(prefix, _)| prefix != target);
+        if let Some(level) = level {
+            levels.push((String::from(target), level));
+        }
+    });
+    update_max_level();
+}
+
+pub fn set_console_level(level: LevelFilter) {
+    CONSOLE_LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+// Route the serial sink to a port given as "ttyS<n>[,<baud><parity><bits>]"
+pub fn set_serial_port(spec: &str) -> FsResult<()> {
+    let (com, config) = serial::parse_console(spec).ok_or(FsError::InvalidArgument)?;
+    serial::select_console(com, config).map_err(|err| match err {
+        serial::SerialError::NotPresent => FsError::NoDevice,
+        serial::SerialError::InvalidConfig => FsError::InvalidArgument,
+    })?;
+    log::info!("Serial log on ttyS{} ({} baud)", com.index(), config.baud);
+    Ok(())
+}
+
+// "off", "on", or a port for set_serial_port
+fn set_serial(spec: &str) -> FsResult<()> {
+    match spec {
+        "off" => SERIAL_LOG.store(false, Ordering::Relaxed),
+        "on" => SERIAL_LOG.store(true, Ordering::Relaxed),
+        _ => {
+            set_serial_port(spec)?;
+            SERIAL_LOG.store(true, Ordering::Relaxed);
+        }
+    }
+    Ok(())
+}
+
+fn read_levels() -> String {
+    let serial = match SERIAL_LOG.load(Ordering::Relaxed) {
+        true => format!("ttyS{}", serial::SERIAL1.lock().com().index()),
+        false => String::from("off"),
+    };
+    let mut out = format!(
+        "{}\nconsole={}\nserial={}\n",
+        level_from_usize(LEVEL.load(Ordering::Relaxed)),
+        level_from_usize(CONSOLE_LEVEL.load(Ordering::Relaxed)),
+        serial
+    );
+    interrupts::without_interrupts(|| {
+        for (target, level) in TARGET_LEVELS.lock().iter() {
+            let _ = writeln!(out, "{}={}", target, level);
+        }
+    });
+    out.to_lowercase()
+}
+
+fn write_levels(command: &str) -> FsResult<()> {
+    let parse = |s: &str| {
+        s.parse::<LevelFilter>()
+            .map_err(|_| FsError::InvalidArgument)
+    };
+
+    for word in command.split_whitespace() {
+        match word.split_once('=') {
+            Some(("console", level)) => set_console_level(parse(level)?),
+            Some(("serial", spec)) => set_serial(spec)?,
+            // "target=default" removes the override
+            Some((target, "default")) => set_target_level(target, None),
+            Some((target, level)) => set_target_level(target, Some(parse(level)?)),
+            None => set_level(parse(word)?),
+        }
+    }
+    Ok(())
+}
+
+// The options on one line, without comments
+fn read_cmdline() -> String {
+    let mut out = String::new();
+    for (key, value) in cmdline::current().options() {
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        out.push_str(key);
+        if let Some(value) = value {
+            let _ = write!(out, "={}", value);
+        }
+    }
+    out.push('\n');
+    out
+}
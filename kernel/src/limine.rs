@@ -0,0 +1,443 @@
+// Limine Boot Protocol
+// The kernel can also be started by Limine (https://github.com/limine-bootloader/limine),
+// e.g. to try it on real hardware from a USB stick. The requests below sit in the
+// kernel's data; Limine finds them by their magic, fills in the responses and
+// jumps to the ELF entry point with nothing in RDI. `_start` asks `boot_info()`
+// first: if Limine answered, the responses are translated into the BootInfo our
+// own bootloader would have passed, and the rest of the kernel never knows the
+// difference. Under uefi_boot the responses stay null and RDI is used as before.
+//
+// Translation:
+//   memory map     sorted and merged MemoryRegions; bootloader-reclaimable memory
+//                  (Limine's page tables, our stack, the responses) and the kernel
+//                  and modules become Bootloader, so the PMM never hands them out
+//   HHDM           offset as given; base revision 2 maps every memory map entry
+//   framebuffer    the first one, through its HHDM address, and its EDID
+//   modules        named by their cmdline, else by the file's basename; the ones
+//                  named "initramfs" and "font" also fill those BootInfo fields
+//   cmdline        the kernel file's cmdline (`cmdline:` in limine.conf)
+//   RSDP, slide    from the RSDP and executable address responses
+//
+// A limine.conf entry matching uefi_boot's ESP layout:
+//
+//   /cat
+//       protocol: limine
+//       path: boot():/kernel
+//       cmdline: loglevel=info
+//       module_path: boot():/initramfs.tar
+//       module_cmdline: initramfs
+//       module_path: boot():/font.psf
+//       module_cmdline: font
+
+use core::cell::UnsafeCell;
+use core::ffi::{CStr, c_char};
+use shared::framebuffer::{FrameBufferInfo, PixelFormat};
+use shared::memory::{self, MAX_RESERVED, MemoryKind, MemoryRegion, ReservedRange};
+use shared::once::Once;
+use shared::{BootInfo, BootModule, MAX_BOOT_MODULES};
+
+const COMMON_MAGIC: [u64; 2] = [0xc7b1dd30df4c8b88, 0x0a82e883a194f07b];
+
+// Limine entry types
+const MEMMAP_USABLE: u64 = 0;
+const MEMMAP_RESERVED: u64 = 1;
+const MEMMAP_ACPI_RECLAIMABLE: u64 = 2;
+const MEMMAP_ACPI_NVS: u64 = 3;
+const MEMMAP_BAD_MEMORY: u64 = 4;
+const MEMMAP_BOOTLOADER_RECLAIMABLE: u64 = 5;
+const MEMMAP_EXECUTABLE_AND_MODULES: u64 = 6;
+const MEMMAP_FRAMEBUFFER: u64 = 7;
+
+// Most Limine memory map entries kept; firmware rarely reports more than ~100
+const MAX_REGIONS: usize = 256;
+
+// A request: its ID, the revision asked for and the pointer Limine fills in
+#[repr(C)]
+struct Request<R> {
+    id: [u64; 4],
+    revision: u64,
+    response: UnsafeCell<*const R>,
+}
+
+// Written only by the bootloader, before the kernel runs
+unsafe impl<R> Sync for Request<R> {}
+
+impl<R> Request<R> {
+    const fn new(id: [u64; 2]) -> Self {
+        Self {
+            id: [COMMON_MAGIC[0], COMMON_MAGIC[1], id[0], id[1]],
+            revision: 0,
+            response: UnsafeCell::new(core::ptr::null()),
+        }
+    }
+
+    // The response, if a Limine bootloader answered the request. Volatile: the
+    // compiler cannot know that anything wrote the field
+    fn response(&self) -> Option<&'static R> {
+        unsafe { core::ptr::read_volatile(self.response.get()).as_ref() }
+    }
+}
+
+#[repr(C)]
+struct HhdmResponse {
+    revision: u64,
+    offset: u64,
+}
+
+#[repr(C)]
+struct MemmapEntry {
+    base: u64,
+    length: u64,
+    type_: u64,
+}
+
+#[repr(C)]
+struct MemmapResponse {
+    revision: u64,
+    entry_count: u64,
+    entries: *const *const MemmapEntry,
+}
+
+#[repr(C)]
+struct Framebuffer {
+    address: u64, // HHDM address
+    width: u64,
+    height: u64,
+    pitch: u64, // Bytes per line
+    bpp: u16,
+    memory_model: u8,
+    red_mask_size: u8,
+    red_mask_shift: u8,
+    green_mask_size: u8,
+    green_mask_shift: u8,
+    blue_mask_size: u8,
+    blue_mask_shift: u8,
+    unused: [u8; 7],
+    edid_size: u64,
+    edid: u64, // HHDM address (0 if none)
+}
+
+#[repr(C)]
+struct FramebufferResponse {
+    revision: u64,
+    framebuffer_count: u64,
+    framebuffers: *const *const Framebuffer,
+}
+
+// struct limine_file, up to the fields used here
+#[repr(C)]
+struct File {
+    revision: u64,
+    address: u64, // HHDM address
+    size: u64,
+    path: *const c_char,
+    cmdline: *const c_char,
+}
+
+#[repr(C)]
+struct ModuleResponse {
+    revision: u64,
+    module_count: u64,
+    modules: *const *const File,
+}
+
+#[repr(C)]
+struct ExecutableFileResponse {
+    revision: u64,
+    file: *const File,
+}
+
+#[repr(C)]
+struct ExecutableAddressResponse {
+    revision: u64,
+    physical_base: u64,
+    virtual_base: u64,
+}
+
+#[repr(C)]
+struct RsdpResponse {
+    revision: u64,
+    address: u64, // HHDM address below base revision 3
+}
+
+// Base revision 2: Limine zeroes the last word if it supports it
+#[repr(C)]
+struct BaseRevision(UnsafeCell<[u64; 3]>);
+
+unsafe impl Sync for BaseRevision {}
+
+#[used]
+#[unsafe(link_section = ".requests")]
+static BASE_REVISION: BaseRevision =
+    BaseRevision(UnsafeCell::new([0xf9562b2d5c95a6c8, 0x6a7b384944536bdc, 2]));
+
+#[used]
+#[unsafe(link_section = ".requests")]
+static HHDM: Request<HhdmResponse> = Request::new([0x48dcf1cb8ad2b852, 0x63984e959a98244b]);
+
+#[used]
+#[unsafe(link_section = ".requests")]
+static MEMMAP: Request<MemmapResponse> = Request::new([0x67cf3d9d378a806f, 0xe304acdfc50c3c62]);
+
+#[used]
+#[unsafe(link_section = ".requests")]
+static FRAMEBUFFER: Request<FramebufferResponse> =
+    Request::new([0x9d5827dcd881dd75, 0xa3148604f6fab11b]);
+
+#[used]
+#[unsafe(link_section = ".requests")]
+static MODULES: Request<ModuleResponse> = Request::new([0x3e7e279702be32af, 0xca1c4f3bd1280cee]);
+
+#[used]
+#[unsafe(link_section = ".requests")]
+static EXECUTABLE_FILE: Request<ExecutableFileResponse> =
+    Request::new([0xad97e90e83f1ed67, 0x31eb5d1c5ff23b69]);
+
+#[used]
+#[unsafe(link_section = ".requests")]
+static EXECUTABLE_ADDRESS: Request<ExecutableAddressResponse> =
+    Request::new([0x71ba76863cc55f63, 0xb2644a48c516a487]);
+
+#[used]
+#[unsafe(link_section = ".requests")]
+static RSDP: Request<RsdpResponse> = Request::new([0xc5e77b6b397e7b43, 0x27637845accdcf3c]);
+
+// The translated BootInfo and the arrays it points to
+static BOOT_INFO: Once<BootInfo> = Once::new();
+static mut REGIONS: [MemoryRegion; MAX_REGIONS] = [MemoryRegion {
+    start: 0,
+    len: 0,
+    kind: MemoryKind::Reserved,
+}; MAX_REGIONS];
+static mut BOOT_MODULES: [BootModule; MAX_BOOT_MODULES] = [BootModule {
+    addr: 0,
+    size: 0,
+    name: [0; shared::BOOT_MODULE_NAME_LEN],
+}; MAX_BOOT_MODULES];
+
+// The BootInfo for a kernel started by Limine, None under uefi_boot. Called once,
+// first thing in `_start`, on the bootloader's stack
+pub fn boot_info() -> Option<&'static BootInfo> {
+    let hhdm = HHDM.response()?;
+    if unsafe { core::ptr::read_volatile(BASE_REVISION.0.get()) }[2] != 0 {
+        panic!("Limine does not support base revision 2");
+    }
+    let memmap = MEMMAP.response().expect("Limine: no memory map response");
+    let address = EXECUTABLE_ADDRESS
+        .response()
+        .expect("Limine: no executable address response");
+    Some(BOOT_INFO.call_once(|| translate(hhdm.offset, memmap, address)))
+}
+
+fn translate(hhdm: u64, memmap: &MemmapResponse, address: &ExecutableAddressResponse) -> BootInfo {
+    // Physical address of a kernel static: the image is loaded contiguously
+    let kernel_phys = |virt: u64| virt - address.virtual_base + address.physical_base;
+
+    let mut info = BootInfo {
+        magic: 0,
+        version: 0,
+        size: 0,
+        memory_map_addr: 0,
+        memory_map_len: 0,
+        hhdm_offset: hhdm,
+        max_phys_memory: 0,
+        framebuffer: FrameBufferInfo {
+            buffer_base: 0,
+            buffer_phys: 0,
+            buffer_size: 0,
+            width: 0,
+            height: 0,
+            stride: 0,
+            format: PixelFormat::BGR,
+        },
+        rsdp_addr: 0,
+        initramfs_addr: 0,
+        initramfs_size: 0,
+        font_addr: 0,
+        font_size: 0,
+        cmdline_addr: 0,
+        cmdline_len: 0,
+        kernel_slide: address.virtual_base - shared::kaslr::KERNEL_BASE,
+        modules_addr: 0,
+        modules_count: 0,
+        efi_system_table: 0,
+        efi_runtime_services: 0,
+        boot_trial: 0,
+        // Without a paging mode request Limine sets up 4-level paging
+        paging_levels: 4,
+        edid_addr: 0,
+        edid_size: 0,
+        // The kernel image, holding this BootInfo and the region array, is
+        // executable-and-modules memory in Limine's map already
+        reserved: [ReservedRange::EMPTY; MAX_RESERVED],
+        reserved_count: 0,
+        tpm_event_log_addr: 0,
+        tpm_event_log_size: 0,
+        // Limine's stack is its own, with no guard page we know of
+        stack_top: 0,
+        stack_size: 0,
+        cpu_features: shared::cpu::detect(),
+    };
+    info.init_header();
+
+    // Memory map
+    let entries = unsafe { array(memmap.entries, memmap.entry_count) };
+    let regions = unsafe {
+        core::slice::from_raw_parts_mut(&raw mut REGIONS as *mut MemoryRegion, MAX_REGIONS)
+    };
+    let count = convert_memory_map(entries, regions);
+    info.memory_map_addr = kernel_phys(regions.as_ptr() as u64);
+    info.memory_map_len = count as u64;
+    // As uefi_boot: the highest address, rounded up to 2 MiB
+    let max_phys = regions[..count].last().map_or(0, MemoryRegion::end);
+    info.max_phys_memory = max_phys.next_multiple_of(2 * 1024 * 1024);
+
+    if let Some(fb) = FRAMEBUFFER.response().and_then(|response| {
+        unsafe { array(response.framebuffers, response.framebuffer_count) }.first()
+    }) {
+        let bytes_per_pixel = (fb.bpp as u64).div_ceil(8).max(1);
+        info.framebuffer = FrameBufferInfo {
+            buffer_base: fb.address,
+            buffer_phys: fb.address - hhdm,
+            buffer_size: (fb.pitch * fb.height) as usize,
+            width: fb.width as usize,
+            height: fb.height as usize,
+            stride: (fb.pitch / bytes_per_pixel) as usize,
+            // Red in the low byte is what UEFI calls RGB
+            format: if fb.red_mask_shift == 0 {
+                PixelFormat::RGB
+            } else {
+                PixelFormat::BGR
+            },
+        };
+        if fb.edid != 0 {
+            info.edid_addr = fb.edid - hhdm;
+            info.edid_size = fb.edid_size;
+        }
+    }
+
+    if let Some(rsdp) = RSDP.response() {
+        info.rsdp_addr = rsdp.address.checked_sub(hhdm).unwrap_or(rsdp.address);
+    }
+
+    if let Some(file) = EXECUTABLE_FILE
+        .response()
+        .and_then(|response| unsafe { response.file.as_ref() })
+        && let Some(cmdline) = unsafe { c_str(file.cmdline) }
+    {
+        info.cmdline_addr = cmdline.as_ptr() as u64 - hhdm;
+        info.cmdline_len = cmdline.len() as u64;
+    }
+
+    if let Some(response) = MODULES.response() {
+        let modules = unsafe {
+            core::slice::from_raw_parts_mut(
+                &raw mut BOOT_MODULES as *mut BootModule,
+                MAX_BOOT_MODULES,
+            )
+        };
+        let files = unsafe { array(response.modules, response.module_count) };
+        let mut count = 0;
+        for file in files.iter().take(MAX_BOOT_MODULES) {
+            let data = unsafe {
+                core::slice::from_raw_parts(file.address as *const u8, file.size as usize)
+            };
+            let name = unsafe { c_str(file.cmdline) }
+                .filter(|cmdline| !cmdline.is_empty())
+                .or_else(|| unsafe { c_str(file.path) }.map(basename))
+                .unwrap_or("module");
+            let mut module = BootModule::new(name, data);
+            module.addr -= hhdm;
+            match module.name() {
+                "initramfs" => {
+                    (info.initramfs_addr, info.initramfs_size) = (module.addr, module.size)
+                }
+                "font" => (info.font_addr, info.font_size) = (module.addr, module.size),
+                _ => {}
+            }
+            modules[count] = module;
+            count += 1;
+        }
+        info.modules_addr = kernel_phys(modules.as_ptr() as u64);
+        info.modules_count = count as u64;
+    }
+    info
+}
+
+// Limine's entries as sorted, merged regions in `out`; returns how many
+fn convert_memory_map(entries: &[&MemmapEntry], out: &mut [MemoryRegion]) -> usize {
+    let mut count = 0;
+    for entry in entries.iter().take(out.len()) {
+        out[count] = MemoryRegion {
+            start: entry.base,
+            len: entry.length,
+            kind: region_kind(entry.type_),
+        };
+        count += 1;
+    }
+    memory::normalize(&mut out[..count])
+}
+
+fn region_kind(type_: u64) -> MemoryKind {
+    match type_ {
+        MEMMAP_USABLE => MemoryKind::Usable,
+        MEMMAP_BOOTLOADER_RECLAIMABLE | MEMMAP_EXECUTABLE_AND_MODULES => MemoryKind::Bootloader,
+        MEMMAP_ACPI_RECLAIMABLE => MemoryKind::AcpiReclaimable,
+        MEMMAP_ACPI_NVS => MemoryKind::Firmware,
+        MEMMAP_FRAMEBUFFER => MemoryKind::Mmio,
+        MEMMAP_RESERVED | MEMMAP_BAD_MEMORY => MemoryKind::Reserved,
+        _ => MemoryKind::Reserved,
+    }
+}
+
+// A Limine array of pointers
+unsafe fn array<'a, T>(pointers: *const *const T, count: u64) -> &'a [&'a T] {
+    if pointers.is_null() {
+        return &[];
+    }
+    unsafe { core::slice::from_raw_parts(pointers as *const &T, count as usize) }
+}
+
+unsafe fn c_str<'a>(text: *const c_char) -> Option<&'a str> {
+    if text.is_null() {
+        return None;
+    }
+    unsafe { CStr::from_ptr(text) }.to_str().ok()
+}
+
+fn basename(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn limine_memory_map_is_normalized() {
+        let entry = |base, length, type_| MemmapEntry {
+            base,
+            length,
+            type_,
+        };
+        let entries = [
+            entry(0x100000, 0x100000, MEMMAP_USABLE),
+            entry(0x0, 0x9f000, MEMMAP_USABLE),
+            entry(0x200000, 0x100000, MEMMAP_USABLE),
+            entry(0x300000, 0x10000, MEMMAP_BOOTLOADER_RECLAIMABLE),
+            entry(0xfd000000, 0x300000, MEMMAP_FRAMEBUFFER),
+        ];
+        let refs: [&MemmapEntry; 5] = core::array::from_fn(|i| &entries[i]);
+        let mut out = [MemoryRegion {
+            start: 0,
+            len: 0,
+            kind: MemoryKind::Reserved,
+        }; 8];
+        let count = convert_memory_map(&refs, &mut out);
+        assert_eq!(count, 4);
+        assert_eq!((out[0].start, out[0].kind), (0, MemoryKind::Usable));
+        assert_eq!((out[1].start, out[1].len), (0x100000, 0x200000));
+        assert_eq!(out[2].kind, MemoryKind::Bootloader);
+        assert_eq!(out[3].kind, MemoryKind::Mmio);
+    }
+}
@@ -1,101 +1,362 @@
 #![no_std] // No standard library
 #![no_main] // No standard main function
 #![feature(abi_x86_interrupt)] // Enable x86-interrupt ABI for IDT handlers
+#![feature(custom_test_frameworks)] // In-kernel #[test_case] tests (see testing.rs)
+#![test_runner(crate::testing::run_tests)]
+#![reexport_test_harness_main = "test_main"]
 
 #[macro_use]
 mod writer;
 
 // Imports
-use elf_loader::{enter_userspace, load_user_elf, setup_user_stack};
-use shared::{BootInfo, panic::panic_handler_impl};
+use shared::{BootInfo, BootInfoError};
 
 // Module Declarations
+mod acpi;
+mod ata;
+mod backtrace;
+mod bench;
+mod block;
+#[cfg(feature = "userspace")]
+mod caps;
+mod crashdump;
+mod delay;
+mod device;
+mod efi;
+#[cfg(feature = "userspace")]
 mod elf_loader;
+#[cfg(all(feature = "graphics", feature = "userspace"))]
+mod fbdev;
+#[cfg(feature = "graphics")]
+mod font;
+mod fpu;
+mod fs;
 mod gdt;
 mod heap_allocator;
+mod idle;
+#[cfg(feature = "userspace")]
+mod input;
 mod interrupts;
+#[cfg(feature = "integration-tests")]
+mod itest;
+mod kexec;
+mod klog;
+mod limine;
+#[cfg(feature = "graphics")]
+mod mmio;
+mod module;
+#[cfg(feature = "userspace")]
+mod mouse;
+mod multiboot;
+#[cfg(feature = "net")]
+mod net;
+mod pci;
+mod perf;
 mod pml4;
 mod pmm;
+#[cfg(feature = "userspace")]
+mod ptrace;
+mod rtc;
+#[cfg(feature = "userspace")]
+mod rusage;
+#[cfg(feature = "graphics")]
 mod screen;
+mod shell;
+mod slab;
+mod suspend;
+mod symbols;
+#[cfg(feature = "userspace")]
 mod syscalls;
+#[cfg(any(test, feature = "integration-tests"))]
+mod testing;
+mod tpm;
+#[cfg(feature = "userspace")]
+mod tty;
+mod virtio_rng;
+#[cfg(feature = "userspace")]
+mod vma;
 
 // External Crate for Heap Allocation
 extern crate alloc;
 
 // The Kernel Entry Point
-// This function is called by the UEFI Bootloader
+// This function is called by the UEFI Bootloader with its BootInfo, or by Limine
+// or the Multiboot2 stub with nothing (see limine.rs, multiboot.rs)
 #[unsafe(no_mangle)] // Ensure the symbol name is unique
-pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
+pub extern "C" fn _start(boot_info_addr: u64) -> ! {
+    // A bootloader built from other sources may lay out BootInfo differently:
+    // stop before any of its fields is trusted
+    let boot_info = match limine::boot_info().or_else(multiboot::boot_info) {
+        Some(boot_info) => boot_info.validate().map(|()| boot_info),
+        None => unsafe { BootInfo::from_addr(boot_info_addr) },
+    }
+    .unwrap_or_else(|err| bad_boot_info(err));
+
+    // Backtraces resolve link-time addresses
+    symbols::init(boot_info.kernel_slide);
+
+    // Where the PML4 is: CR3, or below a PML5 with 5-level paging
+    pml4::init(boot_info.paging_levels);
+
+    // Options from boot.cfg, read by the logger and drivers as they start
+    if boot_info.cmdline_addr != 0 {
+        let text = unsafe {
+            core::slice::from_raw_parts(
+                (boot_info.cmdline_addr + boot_info.hhdm_offset) as *const u8,
+                boot_info.cmdline_len as usize,
+            )
+        };
+        shared::cmdline::set(shared::cmdline::Cmdline::from_bytes(text));
+    }
+
+    #[cfg(feature = "graphics")]
     screen::init(boot_info.framebuffer);
+    // Boot modules and the HHDM for crash dumps
+    crashdump::init(boot_info);
+    // Faults in the page below the boot stack are reported as overflows
+    interrupts::init_stack_guard(boot_info);
+    // Handed on to the next kernel by kexec
+    kexec::init(boot_info);
+    // Firmware runtime services (clock, reset)
+    efi::init(boot_info);
+    // Kernel log (serial, screen and in-memory ring)
+    klog::init();
+    // Measured boot: the TPM event log the bootloader passed on
+    tpm::init(boot_info);
+    log::info!("CPU: {}", shared::cpu::Names(boot_info.cpu_features));
+    log::info!(
+        "Kernel at {:#x} (KASLR slide {:#x})",
+        shared::kaslr::KERNEL_BASE + boot_info.kernel_slide,
+        boot_info.kernel_slide
+    );
+    // The display's native mode, for a display driver to choose its resolution
+    if let Some(timing) =
+        unsafe { boot_info.edid(boot_info.hhdm_offset) }.and_then(shared::edid::preferred_timing)
+    {
+        log::info!(
+            "Display: EDID preferred mode {}x{}, framebuffer {}x{}",
+            timing.width,
+            timing.height,
+            boot_info.framebuffer.width,
+            boot_info.framebuffer.height
+        );
+    }
+    #[cfg(feature = "graphics")]
+    {
+        screen::load_boot_font(
+            boot_info.font_addr,
+            boot_info.font_size,
+            boot_info.hhdm_offset,
+        );
 
-    // Clear screen with the specified color
-    screen::clear_screen(0x0000FF);
+        // Clear screen with the specified color
+        screen::clear_screen(0x0000FF);
 
-    // Set text scale and color
-    screen::set_scale(3);
-    screen::set_text_color(0xFF0000);
+        // Set text scale and color
+        screen::set_scale(2);
+        screen::set_text_color(0xFF0000);
+    }
     println!("WELCOME TO MY OS");
 
+    #[cfg(feature = "graphics")]
     screen::reset_style();
 
+    // x87 / SSE in a known state before user code (or anything else) uses them
+    fpu::init(boot_info.cpu_features);
+
     // Initialize Global Descriptor Table (GDT) and Task State Segment (TSS)
     gdt::init();
-    println!("GDT & TSS initialized.");
+    log::info!("GDT & TSS initialized.");
 
     // Initialize Interrupt Descriptor Table (IDT)
     interrupts::init_idt();
-    println!("IDT initialized.");
+    log::info!("IDT initialized.");
 
     // Initialize Programmable Interrupt Controllers (PICs)
     interrupts::PICS.initialize();
-    println!("PICS initialized.");
+    log::info!("PICS initialized.");
 
     // Initialize Physical Memory Manager (PMM)
     pmm::init(
-        boot_info.memory_map_addr,
-        boot_info.memory_map_len,
-        boot_info.memory_map_desc_size,
+        unsafe { boot_info.memory_map(boot_info.hhdm_offset) },
+        boot_info.reserved(),
         boot_info.hhdm_offset,
         boot_info.max_phys_memory,
     );
+    // Low memory for the S3 wake code, before the heap takes it
+    suspend::init(boot_info.hhdm_offset);
     // Create an instance of our frame allocator
     let mut frame_allocator = pmm::KernelFrameAllocator;
 
     // Initialize the Virtual Memory Mapper using PMM and HHDM offset
     let mut mapper = unsafe { pml4::init_mapper(boot_info.hhdm_offset) };
 
+    // Device memory gets its own mappings (write-combining for the framebuffer)
+    #[cfg(feature = "graphics")]
+    {
+        mmio::init();
+        if let Err(err) = screen::map_framebuffer(&mut mapper, &mut frame_allocator) {
+            panic!("Framebuffer mapping failed: {}", err);
+        }
+    }
+
     // Initialize Programmable Interval Timer (PIT)
     interrupts::init_timer();
-    println!("PIT Timer initialized.");
+    log::info!("PIT Timer initialized.");
+
+    // Calibrate TSC-based busy-wait delays against the PIT
+    delay::calibrate(boot_info.cpu_features);
+
+    // Detect the hardware performance counters
+    perf::init();
+
+    // Choose how the CPU idles (hlt or mwait) and start idle accounting
+    idle::init();
 
     // Initialize the Heap Allocator
     // We pass the mapper and frame allocator so it can map new pages for the heap
-    heap_allocator::init_heap(&mut mapper, &mut frame_allocator)
+    heap_allocator::init_heap(&mut mapper, &mut frame_allocator, boot_info.hhdm_offset)
         .expect("Heap initialization failed");
-    println!("Heap is ready!");
+    log::info!("Heap is ready!");
+    klog::init_proc();
+    crashdump::init_proc();
+    idle::init_proc();
+    tpm::init_proc();
+    slab::init_proc();
+    // Loadable modules (/proc/modules, insmod)
+    module::init(boot_info.hhdm_offset);
+
+    // Switch the screen to a RAM back buffer (double buffering)
+    #[cfg(feature = "graphics")]
+    {
+        screen::init_double_buffering(&mut mapper, &mut frame_allocator)
+            .expect("Back buffer initialization failed");
+        log::info!("Screen double buffering enabled.");
+        screen::enable_glyph_cache();
+        screen::init_text_buffer();
+        screen::enable_status_bar();
+    }
+
+    // Parse ACPI tables (battery / AC adapter status)
+    acpi::init(boot_info.rsdp_addr, boot_info.hhdm_offset);
+    log::info!("Power: {}", acpi::power_status());
+
+    // Wall clock and wakeup alarms (the CMOS RTC)
+    rtc::init();
+
+    // DMA memory of the virtio drivers is reached through the HHDM
+    virtio_rng::init(boot_info.hhdm_offset);
+    // Register built-in drivers and discover devices (platform + PCI)
+    device::init();
+    device::lsdev();
+    // Crash log region of the previous boots, on the disks just found
+    crashdump::init_disk();
+
+    // Network core (packet buffers, RX processing)
+    #[cfg(feature = "net")]
+    net::init(boot_info.hhdm_offset);
 
+    // Mount the initramfs (tar or ISO9660, if the bootloader found one) as the root filesystem
+    if boot_info.initramfs_size != 0 {
+        fs::mount_boot_image(
+            boot_info.initramfs_addr,
+            boot_info.initramfs_size,
+            boot_info.hhdm_offset,
+        )
+        .expect("Failed to mount initramfs");
+        fs::overlay_root().expect("Failed to overlay root filesystem");
+    } else {
+        log::info!("No initramfs provided.");
+    }
+
+    // Writable in-memory filesystem at /tmp
+    fs::init_tmpfs();
+
+    // The files the bootloader loaded, at /boot
+    let modules = unsafe { boot_info.modules(boot_info.hhdm_offset) };
+    if !modules.is_empty()
+        && let Err(err) = fs::bootfs::mount(modules, boot_info.hhdm_offset)
+    {
+        log::warn!("Boot modules not mounted at /boot: {:?}", err);
+    }
+
+    // The screen as /dev/fb0
+    #[cfg(all(feature = "graphics", feature = "userspace"))]
+    fbdev::init();
+    // The keyboard as /dev/input/event0 (the mouse registers itself when probed)
+    #[cfg(feature = "userspace")]
+    input::init();
+
+    #[cfg(feature = "userspace")]
     unsafe {
         syscalls::init(boot_info.hhdm_offset);
     }
 
-    println!("Loading user ELF...");
-    // Gọi loader::load_user_elf
-    let entry_point = load_user_elf(&mut mapper, &mut frame_allocator);
-    println!("Entry point: {:#x}", entry_point.as_u64());
+    // Initialization is done: a trial boot of kernel_new counts as a success
+    efi::confirm_boot(boot_info);
 
-    // Gọi loader::setup_user_stack
-    let user_stack_top = setup_user_stack(&mut mapper, &mut frame_allocator, boot_info.hhdm_offset);
+    // Test builds stop here: run the #[test_case] functions and exit QEMU
+    #[cfg(test)]
+    test_main();
 
-    println!("Entering Ring 3...");
-    // Gọi loader::enter_userspace
-    unsafe {
-        enter_userspace(entry_point, user_stack_top);
+    // Integration test builds run the user-space test suite instead
+    #[cfg(feature = "integration-tests")]
+    itest::run();
+
+    // Without user-mode support the kernel shell is all there is
+    #[cfg(not(feature = "userspace"))]
+    shell::run();
+
+    // Otherwise the initramfs names the first program
+    #[cfg(all(feature = "userspace", not(feature = "integration-tests")))]
+    shell::start_init();
+}
+
+// Nothing is set up and no BootInfo field can be trusted, not even for a crash
+// dump: say so on the serial port and halt
+fn bad_boot_info(err: BootInfoError) -> ! {
+    shared::serial_println!("FATAL: {}", err);
+    shared::serial_println!("Rebuild the bootloader and the kernel together.");
+    loop {
+        x86_64::instructions::interrupts::disable();
+        x86_64::instructions::hlt();
     }
 }
 
 // Panic Handler
-// Called on panic!(), prints error info and halts
+// Called on panic!(), prints error info to the screen and serial and halts
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    let mut frames = [0u64; backtrace::MAX_FRAMES];
+    let depth = backtrace::collect(&mut frames);
+    crashdump::dump(
+        crashdump::Reason::Panic(info),
+        &crashdump::Registers::current(),
+        &frames[..depth],
+    );
+    #[cfg(feature = "graphics")]
+    screen::panic_screen(info, &frames[..depth]);
+    for (i, addr) in frames[..depth].iter().enumerate() {
+        shared::serial::print_panic(format_args!("#{} {}\n", i, symbols::Frame(*addr)));
+    }
+
+    // An integration test run must not hang on a kernel bug
+    #[cfg(feature = "integration-tests")]
+    testing::exit_qemu(testing::QemuExitCode::Failed);
+    #[cfg(not(feature = "integration-tests"))]
+    shared::panic::panic_handler_impl(info);
+}
+
+// Test builds report the panic as a failed test and exit QEMU
+#[cfg(test)]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    panic_handler_impl(info);
+    x86_64::instructions::interrupts::disable();
+
+    let mut frames = [0u64; backtrace::MAX_FRAMES];
+    let depth = backtrace::collect(&mut frames);
+    testing::panic(info, &frames[..depth]);
 }
@@ -0,0 +1,110 @@
+// MMIO Mappings
+// Device memory (the framebuffer) gets mapped write-combining into a kernel-owned
+// window, instead of being reached through the HHDM (cached, and missing for
+// holes above the RAM the bootloader mapped) or the bootloader's own mappings
+// (gone once boot memory is reclaimed or CR3 is rebuilt).
+//
+// The window is handed out bottom-up and never freed: devices are mapped once at
+// boot. The PAT is reprogrammed so entry 1 (selected by PWT alone) means
+// write-combining, as on Linux; the other entries keep their reset values
+// (WB, .., UC-, UC), so existing mappings are unaffected.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, mapper::MapToError,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// Kernel virtual window for MMIO mappings (1 TiB)
+const MMIO_START: u64 = 0xFFFF_C000_0000_0000;
+const MMIO_SIZE: u64 = 1 << 40;
+
+const IA32_PAT: u32 = 0x277;
+
+// PAT memory types
+const PAT_UC: u64 = 0x00;
+const PAT_WC: u64 = 0x01;
+const PAT_WT: u64 = 0x04;
+const PAT_WB: u64 = 0x06;
+const PAT_UC_MINUS: u64 = 0x07;
+
+// Reset layout with entry 1 switched from WT to WC
+const PAT_LAYOUT: [u64; 8] = [
+    PAT_WB,
+    PAT_WC,
+    PAT_UC_MINUS,
+    PAT_UC,
+    PAT_WB,
+    PAT_WT,
+    PAT_UC_MINUS,
+    PAT_UC,
+];
+
+// Next free address in the window
+static NEXT: AtomicU64 = AtomicU64::new(MMIO_START);
+
+#[derive(Debug)]
+pub enum MmioError {
+    // The window is used up
+    NoSpace,
+    Map(MapToError<Size4KiB>),
+}
+
+impl fmt::Display for MmioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MmioError::NoSpace => write!(f, "MMIO window used up"),
+            MmioError::Map(err) => write!(f, "page mapping failed: {:?}", err),
+        }
+    }
+}
+
+// Program the PAT, before the first write-combining mapping
+pub fn init() {
+    let value = PAT_LAYOUT
+        .iter()
+        .enumerate()
+        .fold(0, |pat, (i, kind)| pat | kind << (i * 8));
+    unsafe { Msr::new(IA32_PAT).write(value) };
+}
+
+// Map `size` bytes of device memory at `phys` write-combining (writes are
+// buffered and burst, reads are uncached), returning the virtual address of
+// `phys` itself (the range need not be page aligned)
+pub fn map(
+    phys: u64,
+    size: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<u64, MmioError> {
+    let first = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(phys));
+    let last = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(phys + size.max(1) - 1));
+    let pages = (last.start_address() - first.start_address()) / 4096 + 1;
+
+    let base = NEXT
+        .try_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+            let end = next.checked_add(pages * 4096)?;
+            (end <= MMIO_START + MMIO_SIZE).then_some(end)
+        })
+        .map_err(|_| MmioError::NoSpace)?;
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        // PAT entry 1
+        | PageTableFlags::WRITE_THROUGH;
+    for (i, frame) in PhysFrame::range_inclusive(first, last).enumerate() {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(base + i as u64 * 4096));
+        unsafe {
+            mapper
+                .map_to(page, frame, flags, frame_allocator)
+                .map_err(MmioError::Map)?
+                .flush();
+        }
+    }
+
+    log::debug!("{:#x}..{:#x} mapped at {:#x}", phys, phys + size, base);
+    Ok(base + (phys - first.start_address().as_u64()))
+}
@@ -0,0 +1,602 @@
+// Loadable Kernel Modules
+// A module is a relocatable ELF object (ET_REL) built outside the kernel, with
+// `-mcmodel=kernel -fno-pic` or plain `as`: its allocated sections (code, data,
+// bss) are copied into fresh pages of the module window, its undefined symbols
+// resolved against EXPORTS and the symbols of the modules already loaded, its
+// RELA relocations applied, and `module_init` called (a non-zero return undoes
+// the load). `module_exit`, if defined, runs when the module is unloaded.
+//
+// The window sits right above the kernel image, so the 32-bit PC-relative
+// references of small code models reach kernel functions. The module name comes
+// from its `.modinfo` section ("name=<name>"), as on Linux; `insmod` looks for
+// names without a '/' in /lib/modules/<name>.ko.
+//
+// Every global symbol a module defines is available to the modules loaded after
+// it, and a module cannot be unloaded while others use its symbols. Unloading
+// unmaps its pages and frees their frames; the window is not reused.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+};
+use xmas_elf::ElfFile;
+use xmas_elf::header::{Class, Machine, Type};
+use xmas_elf::sections::{SHF_ALLOC, SHN_ABS, SHN_COMMON, SHN_UNDEF, SectionData, ShType};
+use xmas_elf::symbol_table::{Binding, Entry};
+
+use crate::fs::procfs::{self, ProcEntry};
+
+// Kernel virtual window for modules (1 GiB above the kernel image)
+const MODULE_START: u64 = 0xFFFF_FFFF_A000_0000;
+const MODULE_END: u64 = 0xFFFF_FFFF_E000_0000;
+
+const PAGE_SIZE: u64 = 4096;
+
+// Directory `insmod` searches for names without a '/'
+pub const MODULE_DIR: &str = "/lib/modules";
+
+// x86_64 relocation types
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+const R_X86_64_32: u32 = 10;
+const R_X86_64_32S: u32 = 11;
+const R_X86_64_PC64: u32 = 24;
+
+static HHDM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// Next free address in the window
+static NEXT: AtomicU64 = AtomicU64::new(MODULE_START);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModuleError {
+    // Not an x86_64 relocatable ELF object, or a malformed one
+    BadImage(&'static str),
+    AlreadyLoaded,
+    NotLoaded,
+    // Undefined symbol that neither the kernel nor a loaded module exports
+    UnknownSymbol(String),
+    // Relocation of an unsupported type, or whose value does not fit
+    BadRelocation(u32),
+    // The module window or physical memory is used up
+    OutOfMemory,
+    // The named module uses symbols of the one being unloaded
+    InUse(String),
+    // module_init returned this (a negative errno)
+    InitFailed(i32),
+}
+
+#[cfg(feature = "userspace")]
+impl ModuleError {
+    pub fn errno(&self) -> i64 {
+        match self {
+            ModuleError::BadImage(_) | ModuleError::BadRelocation(_) => -8, // ENOEXEC
+            ModuleError::AlreadyLoaded => -17,                              // EEXIST
+            ModuleError::NotLoaded | ModuleError::UnknownSymbol(_) => -2,   // ENOENT
+            ModuleError::OutOfMemory => -12,                                // ENOMEM
+            ModuleError::InUse(_) => -16,                                   // EBUSY
+            ModuleError::InitFailed(errno) => *errno as i64,
+        }
+    }
+}
+
+struct Module {
+    name: String,
+    base: u64,
+    pages: u64,
+    exit: Option<u64>,
+    // Global symbols it defines
+    exports: Vec<(String, u64)>,
+    // Modules whose symbols it uses
+    uses: Vec<String>,
+}
+
+static MODULES: Mutex<Vec<Module>> = Mutex::new(Vec::new());
+
+fn with_modules<R>(f: impl FnOnce(&mut Vec<Module>) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut MODULES.lock()))
+}
+
+static MODULES_ENTRY: ProcEntry = ProcEntry {
+    name: "modules",
+    read: list,
+    write: None,
+};
+
+pub fn init(hhdm_offset: u64) {
+    HHDM_OFFSET.store(hhdm_offset, Ordering::Relaxed);
+    procfs::register(&MODULES_ENTRY);
+}
+
+// ============================================================================
+// 1. EXPORTS
+// ============================================================================
+// The kernel API of modules: C ABI functions, looked up by name
+
+struct Symbol {
+    name: &'static str,
+    addr: *const (),
+}
+
+// Only function addresses, which never change
+unsafe impl Sync for Symbol {}
+
+macro_rules! export {
+    ($name:ident) => {
+        Symbol {
+            name: stringify!($name),
+            addr: $name as *const (),
+        }
+    };
+}
+
+static EXPORTS: &[Symbol] = &[
+    export!(kprint),
+    export!(kmalloc),
+    export!(kfree),
+    export!(kticks),
+    export!(kudelay),
+    export!(kmdelay),
+];
+
+// Log `len` bytes of UTF-8 text
+unsafe extern "C" fn kprint(text: *const u8, len: usize) {
+    let bytes = unsafe { core::slice::from_raw_parts(text, len) };
+    log::info!("{}", String::from_utf8_lossy(bytes));
+}
+
+// Heap memory, null if it cannot be had; freed with the same size and alignment
+unsafe extern "C" fn kmalloc(size: usize, align: usize) -> *mut u8 {
+    match core::alloc::Layout::from_size_align(size.max(1), align) {
+        Ok(layout) => unsafe { alloc::alloc::alloc(layout) },
+        Err(_) => core::ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn kfree(ptr: *mut u8, size: usize, align: usize) {
+    if let Ok(layout) = core::alloc::Layout::from_size_align(size.max(1), align) {
+        unsafe { alloc::alloc::dealloc(ptr, layout) };
+    }
+}
+
+// Milliseconds since boot
+extern "C" fn kticks() -> u64 {
+    crate::interrupts::TICKS.load(Ordering::Relaxed)
+}
+
+extern "C" fn kudelay(us: u64) {
+    crate::delay::udelay(us);
+}
+
+extern "C" fn kmdelay(ms: u64) {
+    crate::delay::mdelay(ms);
+}
+
+// Address of `name` and the module defining it (None for the kernel)
+fn resolve(modules: &[Module], name: &str) -> Option<(u64, Option<String>)> {
+    if let Some(symbol) = EXPORTS.iter().find(|symbol| symbol.name == name) {
+        return Some((symbol.addr as u64, None));
+    }
+    modules.iter().find_map(|module| {
+        module
+            .exports
+            .iter()
+            .find(|(export, _)| export == name)
+            .map(|&(_, addr)| (addr, Some(module.name.clone())))
+    })
+}
+
+// ============================================================================
+// 2. LOADING
+// ============================================================================
+
+// Value a relocation stores at `place`, and its width in bytes
+fn relocation(
+    r_type: u32,
+    symbol: u64,
+    addend: i64,
+    place: u64,
+) -> Result<(u64, usize), ModuleError> {
+    let value = symbol.wrapping_add(addend as u64);
+    let pc_relative = value.wrapping_sub(place) as i64;
+    let fits_i32 = |value: i64| i32::try_from(value).is_ok();
+    match r_type {
+        R_X86_64_NONE => Ok((0, 0)),
+        R_X86_64_64 => Ok((value, 8)),
+        R_X86_64_PC64 => Ok((pc_relative as u64, 8)),
+        R_X86_64_PC32 | R_X86_64_PLT32 if fits_i32(pc_relative) => Ok((pc_relative as u64, 4)),
+        R_X86_64_32 if u32::try_from(value).is_ok() => Ok((value, 4)),
+        R_X86_64_32S if fits_i32(value as i64) => Ok((value, 4)),
+        _ => Err(ModuleError::BadRelocation(r_type)),
+    }
+}
+
+// "name=<name>" in .modinfo
+fn module_name<'a>(elf: &ElfFile<'a>) -> Result<&'a str, ModuleError> {
+    let modinfo = elf
+        .find_section_by_name(".modinfo")
+        .ok_or(ModuleError::BadImage("no .modinfo section"))?;
+    modinfo
+        .raw_data(elf)
+        .split(|&byte| byte == 0)
+        .find_map(|field| field.strip_prefix(b"name="))
+        .and_then(|name| core::str::from_utf8(name).ok())
+        .filter(|name| !name.is_empty())
+        .ok_or(ModuleError::BadImage("no name in .modinfo"))
+}
+
+// Map `pages` zeroed pages at `base`
+fn map_pages(base: u64, pages: u64) -> Result<(), ModuleError> {
+    let mut mapper = unsafe { crate::pml4::init_mapper(HHDM_OFFSET.load(Ordering::Relaxed)) };
+    let mut frame_allocator = crate::pmm::KernelFrameAllocator;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for i in 0..pages {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(base + i * PAGE_SIZE));
+        let Some(frame) = frame_allocator.allocate_frame() else {
+            unmap_pages(base, i);
+            return Err(ModuleError::OutOfMemory);
+        };
+        match unsafe { mapper.map_to(page, frame, flags, &mut frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                unmap_pages(base, i);
+                return Err(ModuleError::OutOfMemory);
+            }
+        }
+        unsafe {
+            core::ptr::write_bytes(
+                page.start_address().as_mut_ptr::<u8>(),
+                0,
+                PAGE_SIZE as usize,
+            )
+        };
+    }
+    Ok(())
+}
+
+// Unmap `pages` pages at `base` and free their frames
+fn unmap_pages(base: u64, pages: u64) {
+    let mut mapper = unsafe { crate::pml4::init_mapper(HHDM_OFFSET.load(Ordering::Relaxed)) };
+    let mut frame_allocator = crate::pmm::KernelFrameAllocator;
+    for i in 0..pages {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(base + i * PAGE_SIZE));
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+// Place the allocated sections: address of each (by section index) and total size
+fn layout(elf: &ElfFile) -> Result<(Vec<Option<u64>>, u64), ModuleError> {
+    let mut size = 0u64;
+    let mut offsets = Vec::new();
+    for section in elf.section_iter() {
+        let allocated = section.flags() & SHF_ALLOC != 0 && section.size() > 0;
+        if !allocated {
+            offsets.push(None);
+            continue;
+        }
+        let align = section.align().max(1);
+        if !align.is_power_of_two() || align > PAGE_SIZE {
+            return Err(ModuleError::BadImage("bad section alignment"));
+        }
+        let start = size.next_multiple_of(align);
+        offsets.push(Some(start));
+        size = start
+            .checked_add(section.size())
+            .ok_or(ModuleError::BadImage("bad section size"))?;
+    }
+    Ok((offsets, size))
+}
+
+// Load a module image and run its module_init, returning the module name
+pub fn load(image: &[u8]) -> Result<String, ModuleError> {
+    // xmas_elf reads the headers in place, which needs them aligned
+    let mut aligned = alloc::vec![0u64; image.len().div_ceil(8)];
+    let data = unsafe {
+        core::ptr::copy_nonoverlapping(
+            image.as_ptr(),
+            aligned.as_mut_ptr() as *mut u8,
+            image.len(),
+        );
+        core::slice::from_raw_parts(aligned.as_ptr() as *const u8, image.len())
+    };
+    let elf = ElfFile::new(data).map_err(ModuleError::BadImage)?;
+    if elf.header.pt1.class() != Class::SixtyFour
+        || elf.header.pt2.type_().as_type() != Type::Relocatable
+        || elf.header.pt2.machine().as_machine() != Machine::X86_64
+    {
+        return Err(ModuleError::BadImage("not an x86_64 relocatable object"));
+    }
+    let name = String::from(module_name(&elf)?);
+    if with_modules(|modules| modules.iter().any(|module| module.name == name)) {
+        return Err(ModuleError::AlreadyLoaded);
+    }
+
+    let (offsets, size) = layout(&elf)?;
+    let pages = size.div_ceil(PAGE_SIZE).max(1);
+    let base = NEXT
+        .try_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+            let end = next.checked_add(pages * PAGE_SIZE)?;
+            (end <= MODULE_END).then_some(end)
+        })
+        .map_err(|_| ModuleError::OutOfMemory)?;
+    map_pages(base, pages)?;
+
+    let result = link(&elf, &offsets, base).and_then(|linked| {
+        start(
+            Module {
+                name: name.clone(),
+                base,
+                pages,
+                exit: linked.exit,
+                exports: linked.exports,
+                uses: linked.uses,
+            },
+            linked.init,
+        )
+    });
+    if result.is_err() {
+        unmap_pages(base, pages);
+    }
+    result.map(|()| name)
+}
+
+// What linking found in a module
+struct Linked {
+    init: u64,
+    exit: Option<u64>,
+    exports: Vec<(String, u64)>,
+    uses: Vec<String>,
+}
+
+// Copy the sections to `base`, resolve symbols and apply the relocations
+fn link(elf: &ElfFile, offsets: &[Option<u64>], base: u64) -> Result<Linked, ModuleError> {
+    let find_section = |index: usize| {
+        offsets
+            .get(index)
+            .copied()
+            .flatten()
+            .map(|offset| base + offset)
+    };
+
+    for (index, section) in elf.section_iter().enumerate() {
+        if let (Some(addr), Ok(ShType::ProgBits)) = (find_section(index), section.get_type()) {
+            let bytes = section.raw_data(elf);
+            unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), addr as *mut u8, bytes.len()) };
+        }
+    }
+
+    let symbols = match elf
+        .section_iter()
+        .find(|section| section.get_type() == Ok(ShType::SymTab))
+        .map(|section| section.get_data(elf))
+    {
+        Some(Ok(SectionData::SymbolTable64(symbols))) => symbols,
+        _ => return Err(ModuleError::BadImage("no symbol table")),
+    };
+
+    // Symbol values, resolving undefined symbols once
+    let mut uses = Vec::new();
+    let mut values = Vec::with_capacity(symbols.len());
+    with_modules(|modules| {
+        for symbol in symbols {
+            let value = match symbol.shndx() {
+                // Entry 0 is the null symbol
+                SHN_UNDEF if symbol.name() == 0 => 0,
+                SHN_UNDEF => {
+                    let name = symbol.get_name(elf).map_err(ModuleError::BadImage)?;
+                    match resolve(modules, name) {
+                        Some((addr, owner)) => {
+                            if let Some(owner) = owner
+                                && !uses.contains(&owner)
+                            {
+                                uses.push(owner);
+                            }
+                            addr
+                        }
+                        None if symbol.get_binding() == Ok(Binding::Weak) => 0,
+                        None => return Err(ModuleError::UnknownSymbol(String::from(name))),
+                    }
+                }
+                SHN_ABS => symbol.value(),
+                SHN_COMMON => {
+                    return Err(ModuleError::BadImage(
+                        "common symbol (build with -fno-common)",
+                    ));
+                }
+                index => match find_section(index as usize) {
+                    Some(addr) => addr + symbol.value(),
+                    // Symbols of sections that are not loaded are never relocated against
+                    None => 0,
+                },
+            };
+            values.push(value);
+        }
+        Ok(())
+    })?;
+
+    for section in elf.section_iter() {
+        if section.get_type() != Ok(ShType::Rela) {
+            continue;
+        }
+        // Relocations of sections that are not loaded (debug info) are skipped
+        let target = section.info() as usize;
+        let Some(target_addr) = find_section(target) else {
+            continue;
+        };
+        let target_size = elf
+            .section_header(target as u16)
+            .map_err(ModuleError::BadImage)?
+            .size();
+        let Ok(SectionData::Rela64(relas)) = section.get_data(elf) else {
+            return Err(ModuleError::BadImage("bad relocation section"));
+        };
+        for rela in relas {
+            let symbol = *values
+                .get(rela.get_symbol_table_index() as usize)
+                .ok_or(ModuleError::BadImage("bad relocation symbol"))?;
+            let place = target_addr + rela.get_offset();
+            let (value, width) =
+                relocation(rela.get_type(), symbol, rela.get_addend() as i64, place)?;
+            if rela.get_offset() + width as u64 > target_size {
+                return Err(ModuleError::BadImage("relocation outside its section"));
+            }
+            unsafe {
+                match width {
+                    8 => core::ptr::write_unaligned(place as *mut u64, value),
+                    4 => core::ptr::write_unaligned(place as *mut u32, value as u32),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // Defined global symbols: module_init/module_exit and the exports
+    let mut init = None;
+    let mut exit = None;
+    let mut exports = Vec::new();
+    for (symbol, &value) in symbols.iter().zip(&values) {
+        let global = matches!(symbol.get_binding(), Ok(Binding::Global | Binding::Weak));
+        if !global || symbol.shndx() == SHN_UNDEF || value == 0 {
+            continue;
+        }
+        match symbol.get_name(elf).map_err(ModuleError::BadImage)? {
+            "module_init" => init = Some(value),
+            "module_exit" => exit = Some(value),
+            name => exports.push((String::from(name), value)),
+        }
+    }
+    let init = init.ok_or(ModuleError::BadImage("no module_init"))?;
+
+    Ok(Linked {
+        init,
+        exit,
+        exports,
+        uses,
+    })
+}
+
+// Run module_init and register the module
+fn start(module: Module, init: u64) -> Result<(), ModuleError> {
+    let init: extern "C" fn() -> i32 = unsafe { core::mem::transmute(init as usize) };
+    match init() {
+        0 => {}
+        errno => return Err(ModuleError::InitFailed(errno)),
+    }
+    log::info!(
+        "Module {} loaded at {:#x} ({} KiB)",
+        module.name,
+        module.base,
+        module.pages * PAGE_SIZE / 1024
+    );
+    with_modules(|modules| modules.push(module));
+    Ok(())
+}
+
+// ============================================================================
+// 3. UNLOADING
+// ============================================================================
+
+// Run module_exit and unmap the module
+pub fn unload(name: &str) -> Result<(), ModuleError> {
+    let module = with_modules(|modules| {
+        let index = modules
+            .iter()
+            .position(|module| module.name == name)
+            .ok_or(ModuleError::NotLoaded)?;
+        if let Some(user) = modules
+            .iter()
+            .find(|module| module.uses.iter().any(|used| used == name))
+        {
+            return Err(ModuleError::InUse(user.name.clone()));
+        }
+        Ok(modules.remove(index))
+    })?;
+    if let Some(exit) = module.exit {
+        let exit: extern "C" fn() = unsafe { core::mem::transmute(exit as usize) };
+        exit();
+    }
+    unmap_pages(module.base, module.pages);
+    log::info!("Module {} unloaded", module.name);
+    Ok(())
+}
+
+// Loaded modules as in Linux's /proc/modules: name, size, use count, users,
+// state and address
+pub fn list() -> String {
+    let mut out = String::new();
+    with_modules(|modules| {
+        for module in modules.iter() {
+            let users: Vec<&str> = modules
+                .iter()
+                .filter(|user| user.uses.contains(&module.name))
+                .map(|user| user.name.as_str())
+                .collect();
+            let _ = writeln!(
+                out,
+                "{} {} {} {} Live {:#x}",
+                module.name,
+                module.pages * PAGE_SIZE,
+                users.len(),
+                if users.is_empty() {
+                    String::from("-")
+                } else {
+                    users.join(",") + ","
+                },
+                module.base
+            );
+        }
+    });
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn module_relocations_check_their_range() {
+        let place = MODULE_START + 0x10;
+        let kernel = 0xFFFF_FFFF_8000_1000;
+        // A call from the module window back into the kernel image
+        let (value, width) = relocation(R_X86_64_PLT32, kernel, -4, place).unwrap();
+        assert_eq!(width, 4);
+        assert_eq!(value as u32 as i32 as i64, kernel as i64 - 4 - place as i64);
+        // Sign-extended 32-bit absolute addresses cover the top 2 GiB only
+        assert!(relocation(R_X86_64_32S, kernel, 0, place).is_ok());
+        assert_eq!(
+            relocation(R_X86_64_32, kernel, 0, place),
+            Err(ModuleError::BadRelocation(R_X86_64_32))
+        );
+        assert_eq!(
+            relocation(R_X86_64_64, kernel, 8, place),
+            Ok((kernel + 8, 8))
+        );
+        // Out of reach of a 32-bit displacement
+        assert!(relocation(R_X86_64_PC32, 0x1000, 0, place).is_err());
+    }
+
+    #[test_case]
+    fn unmapped_module_pages_free_their_frames() {
+        // The top of the window, past anything loaded
+        let base = MODULE_END - 4 * PAGE_SIZE;
+        let before = crate::pmm::stats().free;
+        map_pages(base, 4).expect("out of memory");
+        let mapped = crate::pmm::stats().free;
+        // The pages, and any page table they needed
+        assert!(mapped <= before - 4);
+        unmap_pages(base, 4);
+        // Page tables stay for the next mapping
+        assert_eq!(crate::pmm::stats().free, mapped + 4);
+    }
+}
@@ -0,0 +1,223 @@
+// PS/2 Mouse
+// The mouse on the auxiliary port of the i8042 controller, reported to user space
+// as /dev/input/event1 (see input.rs).
+//
+// Probe enables the port and its interrupt (IRQ 12) in the controller, then sets
+// the mouse to its defaults (standard 3-byte packets, 100 samples/s) and turns
+// reporting on. Each packet carries the buttons and a 9-bit signed motion; it
+// becomes button events for the buttons that changed and REL_X / REL_Y for the
+// motion (the mouse counts Y upwards, evdev downwards). A machine without an
+// i8042 never answers, and the probe fails after a timeout.
+
+use crate::device::{BusType, Device, Driver, MatchId, ProbeError};
+use crate::input::{BTN_LEFT, BTN_MIDDLE, BTN_RIGHT, EV_KEY, EV_REL, InputDevice, REL_X, REL_Y};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+// i8042 ports
+const DATA_PORT: u16 = 0x60;
+const COMMAND_PORT: u16 = 0x64; // status on read
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+const STATUS_AUX_DATA: u8 = 1 << 5;
+
+// Controller commands
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_ENABLE_AUX: u8 = 0xA8;
+const CMD_WRITE_AUX: u8 = 0xD4;
+
+// Controller configuration byte
+const CONFIG_AUX_INTERRUPT: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+
+// Mouse commands and replies
+const MOUSE_SET_DEFAULTS: u8 = 0xF6;
+const MOUSE_ENABLE_REPORTING: u8 = 0xF4;
+const MOUSE_DISABLE_REPORTING: u8 = 0xF5;
+const MOUSE_ACK: u8 = 0xFA;
+
+// First byte of a packet
+const PACKET_BUTTONS: u8 = 0x07;
+const PACKET_ALWAYS_ONE: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_OVERFLOW: u8 = 0xC0;
+
+// IRQ line of the auxiliary port (on the secondary PIC)
+pub const IRQ: u8 = 12;
+
+// How long the controller and the mouse get to answer
+const TIMEOUT_US: u64 = 50_000;
+const POLL_US: u64 = 10;
+
+// Event codes of the packet's button bits 0, 1 and 2
+const BUTTONS: [u16; 3] = [BTN_LEFT, BTN_RIGHT, BTN_MIDDLE];
+
+pub static MOUSE: InputDevice = InputDevice::new(
+    "PS/2 Generic Mouse",
+    0x0002,
+    BTN_LEFT..=BTN_MIDDLE,
+    &[REL_X, REL_Y],
+);
+
+// The packet being received and the buttons last reported
+struct PacketState {
+    bytes: [u8; 3],
+    len: usize,
+    buttons: u8,
+}
+
+static PACKET: Mutex<PacketState> = Mutex::new(PacketState {
+    bytes: [0; 3],
+    len: 0,
+    buttons: 0,
+});
+
+pub static DRIVER: Driver = Driver {
+    name: "psmouse",
+    bus: BusType::Platform,
+    match_table: &[MatchId::Platform("psaux")],
+    probe,
+    remove,
+    suspend,
+    resume,
+};
+
+fn status() -> u8 {
+    unsafe { Port::<u8>::new(COMMAND_PORT).read() }
+}
+
+// Poll the status register until `ready` holds, or time out
+fn wait_status(ready: impl Fn(u8) -> bool) -> Result<(), ProbeError> {
+    for _ in 0..TIMEOUT_US / POLL_US {
+        if ready(status()) {
+            return Ok(());
+        }
+        crate::delay::udelay(POLL_US);
+    }
+    Err(ProbeError::InitFailed)
+}
+
+fn write_command(command: u8) -> Result<(), ProbeError> {
+    wait_status(|status| status & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::<u8>::new(COMMAND_PORT).write(command) };
+    Ok(())
+}
+
+fn write_data(byte: u8) -> Result<(), ProbeError> {
+    wait_status(|status| status & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::<u8>::new(DATA_PORT).write(byte) };
+    Ok(())
+}
+
+fn read_data() -> Result<u8, ProbeError> {
+    wait_status(|status| status & STATUS_OUTPUT_FULL != 0)?;
+    Ok(unsafe { Port::<u8>::new(DATA_PORT).read() })
+}
+
+// Send a command to the mouse and wait for its acknowledgement
+fn mouse_command(command: u8) -> Result<(), ProbeError> {
+    write_command(CMD_WRITE_AUX)?;
+    write_data(command)?;
+    match read_data()? {
+        MOUSE_ACK => Ok(()),
+        _ => Err(ProbeError::InitFailed),
+    }
+}
+
+// Enable the auxiliary port and its interrupt, and start the mouse reporting
+// Interrupts are off so the keyboard handler cannot take the replies
+fn enable() -> Result<(), ProbeError> {
+    interrupts::without_interrupts(|| {
+        write_command(CMD_ENABLE_AUX)?;
+        write_command(CMD_READ_CONFIG)?;
+        let config = read_data()?;
+        write_command(CMD_WRITE_CONFIG)?;
+        write_data((config | CONFIG_AUX_INTERRUPT) & !CONFIG_AUX_CLOCK_DISABLED)?;
+        mouse_command(MOUSE_SET_DEFAULTS)?;
+        mouse_command(MOUSE_ENABLE_REPORTING)?;
+        *PACKET.lock() = PacketState {
+            bytes: [0; 3],
+            len: 0,
+            buttons: 0,
+        };
+        Ok(())
+    })
+}
+
+fn probe(_device: &Device) -> Result<(), ProbeError> {
+    enable()?;
+    crate::input::register(&MOUSE, 1);
+    crate::interrupts::PICS.unmask(IRQ);
+    Ok(())
+}
+
+// Stop the reports (before another kernel takes over)
+fn remove(_device: &Device) {
+    let _ = interrupts::without_interrupts(|| mouse_command(MOUSE_DISABLE_REPORTING));
+}
+
+fn suspend(_device: &Device) {}
+
+// The controller and the mouse were reset
+fn resume(_device: &Device) {
+    if let Err(err) = enable() {
+        log::warn!("psmouse: resume failed: {:?}", err);
+    }
+}
+
+// A byte from the auxiliary port (IRQ 12)
+pub fn handle_interrupt() {
+    let status = status();
+    if status & (STATUS_OUTPUT_FULL | STATUS_AUX_DATA) != STATUS_OUTPUT_FULL | STATUS_AUX_DATA {
+        return;
+    }
+    let byte = unsafe { Port::<u8>::new(DATA_PORT).read() };
+
+    let mut packet = PACKET.lock();
+    // Resynchronize on a first byte that cannot be one
+    if packet.len == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+        return;
+    }
+    let index = packet.len;
+    packet.bytes[index] = byte;
+    packet.len += 1;
+    if packet.len < packet.bytes.len() {
+        return;
+    }
+    packet.len = 0;
+
+    let [flags, dx, dy] = packet.bytes;
+    // At most the three buttons and both axes; no allocation in an interrupt
+    let mut events = [(0u16, 0u16, 0i32); 5];
+    let mut count = 0;
+    let mut push = |event| {
+        events[count] = event;
+        count += 1;
+    };
+    let changed = (flags ^ packet.buttons) & PACKET_BUTTONS;
+    for (bit, &button) in BUTTONS.iter().enumerate() {
+        if changed & (1 << bit) != 0 {
+            push((EV_KEY, button, (flags >> bit & 1) as i32));
+        }
+    }
+    packet.buttons = flags & PACKET_BUTTONS;
+    drop(packet);
+
+    if flags & PACKET_OVERFLOW == 0 {
+        let dx = dx as i32 - if flags & PACKET_X_SIGN != 0 { 256 } else { 0 };
+        let dy = dy as i32 - if flags & PACKET_Y_SIGN != 0 { 256 } else { 0 };
+        if dx != 0 {
+            push((EV_REL, REL_X, dx));
+        }
+        if dy != 0 {
+            push((EV_REL, REL_Y, -dy));
+        }
+    }
+    if count > 0 {
+        MOUSE.report(&events[..count]);
+    }
+}
@@ -0,0 +1,769 @@
+// Multiboot2 Boot Protocol
+// GRUB (`multiboot2`) can load the kernel ELF directly. The header at the start
+// of the image asks for a linear framebuffer and lets GRUB place the image
+// anywhere below 4 GiB (the relocatable tag); GRUB loads the segments at their
+// physical addresses (`AT()` in linker.ld, or shifted together) and enters
+// `multiboot_entry` in 32-bit protected mode with paging off, EAX = the magic
+// and EBX = the physical address of the boot information (MBI).
+//
+// The stub below does what our bootloader does before the jump, in the least
+// code: it checks for long mode and NX, builds page tables in the kernel's bss
+// (the kernel W^X at KERNEL_BASE with 4 KiB pages, the first 4 GiB at the HHDM
+// with 2 MiB pages, and an identity map of the same to survive turning paging
+// on), enters long mode, applies the static PIE's `.rela.dyn` at slide 0, drops
+// the identity map and calls `_start` on a stack in the bss with nothing in RDI.
+// `_start` asks `boot_info()` after Limine's: the MBI is translated into a
+// BootInfo and the rest of the kernel never knows the difference.
+//
+// Translation:
+//   memory map     the mmap tag, with the kernel image, the MBI and the modules
+//                  carved out as Bootloader (GRUB reports them as available);
+//                  memory above 4 GiB is only kept if 1 GiB pages can extend the
+//                  HHDM to it, up to 512 GiB
+//   framebuffer    the framebuffer tag, if it is 32-bit RGB, through the HHDM
+//   modules        `module2` files named by their arguments; "initramfs" and
+//                  "font" also fill those BootInfo fields
+//   cmdline        the `multiboot2` line after the kernel path
+//   RSDP           the copy GRUB places in the MBI (ACPI 2.0 tag, else 1.0)
+//
+// There are no EFI runtime services (GRUB exits boot services without setting a
+// virtual address map), no TPM log and no guard page under the stack. The kernel
+// file must be the plain ELF, not the LZ4 image. A grub.cfg entry:
+//
+//   menuentry "cat" {
+//       multiboot2 /boot/kernel loglevel=info
+//       module2 /boot/initramfs.tar initramfs
+//       module2 /boot/font.psf font
+//   }
+
+use core::ffi::CStr;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+use shared::framebuffer::{FrameBufferInfo, PixelFormat};
+use shared::kaslr::KERNEL_BASE;
+use shared::memory::{self, MAX_RESERVED, MemoryKind, MemoryRegion, PAGE_SIZE, ReservedRange};
+use shared::once::Once;
+use shared::{BootInfo, BootModule, MAX_BOOT_MODULES, cpu};
+
+const HEADER_MAGIC: u32 = 0xE852_50D6;
+const BOOTLOADER_MAGIC: u32 = 0x36D7_6289;
+
+// Where linker.ld puts the image if GRUB does not move it
+const LOAD_PHYS: u32 = 0x20_0000;
+// Page tables for the kernel image: 32 MiB (checked by linker.ld)
+const KERNEL_PTS: usize = 16;
+const HHDM_OFFSET: u64 = 0xffff_8000_0000_0000;
+// What the stub's 2 MiB pages map
+const LOW_LIMIT: u64 = 4 << 30;
+const GIB: u64 = 1 << 30;
+
+// MBI tag types
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_MODULE: u32 = 3;
+const TAG_MMAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+
+// Memory map entry types
+const MMAP_AVAILABLE: u32 = 1;
+const MMAP_ACPI_RECLAIMABLE: u32 = 3;
+const MMAP_NVS: u32 = 4;
+const MMAP_BAD: u32 = 5;
+
+const FRAMEBUFFER_RGB: u8 = 1;
+
+const MAX_REGIONS: usize = 256;
+
+// Set by the stub: physical addresses of the MBI and of the image
+static INFO_ADDR: AtomicU64 = AtomicU64::new(0);
+static LOAD_ADDR: AtomicU64 = AtomicU64::new(0);
+
+core::arch::global_asm!(
+    ".pushsection .text.multiboot, \"ax\"",
+    ".balign 8",
+    ".global multiboot_header",
+    "multiboot_header:",
+    ".long {header_magic}",
+    ".long 0", // i386 protected mode
+    ".long multiboot_header_end - multiboot_header",
+    ".long 0x100000000 - ({header_magic} + (multiboot_header_end - multiboot_header))",
+    // Entry address: the 32-bit entry below, where linker.ld loads it
+    ".balign 8",
+    ".short 3, 0",
+    ".long 12",
+    ".long multiboot_entry - multiboot_header + {load_phys}",
+    // Relocatable: anywhere from 1 MiB to 4 GiB, page aligned, GRUB's choice
+    ".balign 8",
+    ".short 10, 0",
+    ".long 24",
+    ".long 0x100000",
+    ".long 0xffffffff",
+    ".long 0x1000",
+    ".long 0",
+    // Framebuffer (optional): any size, 32 bits per pixel
+    ".balign 8",
+    ".short 5, 1",
+    ".long 20",
+    ".long 0, 0, 32",
+    ".balign 8",
+    ".short 0, 0",
+    ".long 8",
+    "multiboot_header_end:",
+    // Where the stub's page tables and the section boundaries are, relative to
+    // each entry (X - .)
+    "multiboot_links:",
+    ".long multiboot_pml4 - .",
+    ".long __rodata_start - .",
+    ".long __data_start - .",
+    ".long __kernel_end - .",
+    // Offsets for the memory operands below (one symbol each)
+    ".set multiboot_links_offset, multiboot_links - multiboot_header",
+    ".set multiboot_gdt_offset, multiboot_gdt - multiboot_header",
+    ".set multiboot_long_mode_offset, multiboot_long_mode - multiboot_header",
+    ".set multiboot_no_cpu_offset, multiboot_no_cpu - multiboot_header",
+    ".set multiboot_pdpt_low_offset, multiboot_pdpt_low - multiboot_pml4",
+    ".set multiboot_pdpt_hhdm_offset, multiboot_pdpt_hhdm - multiboot_pml4",
+    ".set multiboot_pd_low_offset, multiboot_pd_low - multiboot_pml4",
+    ".set multiboot_pd_hhdm_offset, multiboot_pd_hhdm - multiboot_pml4",
+    ".set multiboot_pdpt_kernel_offset, multiboot_pdpt_kernel - multiboot_pml4",
+    ".set multiboot_pd_kernel_offset, multiboot_pd_kernel - multiboot_pml4",
+    ".set multiboot_pt_kernel_offset, multiboot_pt_kernel - multiboot_pml4",
+    ".set multiboot_stack_top_offset, multiboot_stack_top - multiboot_pml4",
+    // 32-bit entry, no stack yet: EAX = magic, EBX = MBI
+    ".code32",
+    "multiboot_entry:",
+    "cli",
+    "cld",
+    "cmp eax, {bootloader_magic}",
+    "jne 9f",
+    // EBP = physical address of the image: the load base tag (21) when GRUB
+    // relocated it, else where linker.ld put it
+    "mov ebp, {load_phys}",
+    "lea esi, [ebx + 8]",
+    "1:",
+    "mov eax, [esi]",
+    "test eax, eax",
+    "jz 3f",
+    "cmp eax, 21",
+    "jne 2f",
+    "mov ebp, [esi + 8]",
+    "jmp 3f",
+    "2:",
+    "mov eax, [esi + 4]",
+    "add eax, 7",
+    "and eax, ~7",
+    "add esi, eax",
+    "jmp 1b",
+    // EDI = the page tables, then the stack above them
+    "3:",
+    "lea esi, [ebp + multiboot_links_offset]",
+    "mov edi, [esi]",
+    "add edi, esi",
+    "lea esp, [edi + multiboot_stack_top_offset]",
+    // Long mode and NX, or a note on COM1 and a halt
+    "mov eax, 0x80000000",
+    "cpuid",
+    "cmp eax, 0x80000001",
+    "jb 8f",
+    "mov eax, 0x80000001",
+    "cpuid",
+    "and edx, (1 << 29) | (1 << 20)",
+    "cmp edx, (1 << 29) | (1 << 20)",
+    "jne 8f",
+    // Identity and HHDM: PML4[0] and PML4[256], 4 GiB of 2 MiB pages each, the
+    // HHDM no-execute
+    "xor eax, eax",
+    "2:",
+    "mov edx, eax",
+    "shl edx, 21",
+    "or edx, 0x83", // Present, writable, huge
+    "mov [edi + eax * 8 + multiboot_pd_low_offset], edx",
+    "mov [edi + eax * 8 + multiboot_pd_hhdm_offset], edx",
+    "mov dword ptr [edi + eax * 8 + multiboot_pd_hhdm_offset + 4], 0x80000000",
+    "inc eax",
+    "cmp eax, 2048",
+    "jb 2b",
+    "xor eax, eax",
+    "2:",
+    "mov edx, eax",
+    "shl edx, 12",
+    "lea ecx, [edi + edx + multiboot_pd_low_offset + 0x3]",
+    "mov [edi + eax * 8 + multiboot_pdpt_low_offset], ecx",
+    "lea ecx, [edi + edx + multiboot_pd_hhdm_offset + 0x3]",
+    "mov [edi + eax * 8 + multiboot_pdpt_hhdm_offset], ecx",
+    "inc eax",
+    "cmp eax, 4",
+    "jb 2b",
+    "lea eax, [edi + multiboot_pdpt_low_offset + 0x3]",
+    "mov [edi], eax",
+    "lea eax, [edi + multiboot_pdpt_hhdm_offset + 0x3]",
+    "mov [edi + 256 * 8], eax",
+    // Kernel: PML4[511] -> PDPT[510] -> PD[0..KERNEL_PTS] -> 4 KiB pages, text
+    // read-only, rodata no-execute, data writable and no-execute
+    "lea eax, [edi + multiboot_pdpt_kernel_offset + 0x3]",
+    "mov [edi + 511 * 8], eax",
+    "lea eax, [edi + multiboot_pd_kernel_offset + 0x3]",
+    "mov [edi + multiboot_pdpt_kernel_offset + 510 * 8], eax",
+    "xor eax, eax",
+    "2:",
+    "mov edx, eax",
+    "shl edx, 12",
+    "lea ecx, [edi + edx + multiboot_pt_kernel_offset + 0x3]",
+    "mov [edi + eax * 8 + multiboot_pd_kernel_offset], ecx",
+    "inc eax",
+    "cmp eax, {kernel_pts}",
+    "jb 2b",
+    "push ebx",
+    // [esp] = start of rodata, [esp + 4] of data, [esp + 8] end of the image
+    "mov eax, [esi + 12]",
+    "lea eax, [esi + eax + 12]",
+    "push eax",
+    "mov eax, [esi + 8]",
+    "lea eax, [esi + eax + 8]",
+    "push eax",
+    "mov eax, [esi + 4]",
+    "lea eax, [esi + eax + 4]",
+    "push eax",
+    "lea edx, [edi + multiboot_pt_kernel_offset]",
+    "mov eax, ebp",
+    "2:",
+    "mov ebx, 0x1",
+    "xor ecx, ecx",
+    "cmp eax, [esp]",
+    "jb 3f",
+    "mov ecx, 0x80000000",
+    "cmp eax, [esp + 4]",
+    "jb 3f",
+    "or ebx, 0x2",
+    "3:",
+    "or ebx, eax",
+    "mov [edx], ebx",
+    "mov [edx + 4], ecx",
+    "add edx, 8",
+    "add eax, 4096",
+    "cmp eax, [esp + 8]",
+    "jb 2b",
+    "add esp, 12",
+    "pop ebx",
+    // PAE, EFER.LME and NXE, the tables, paging (CR0.WP stays off until the
+    // relocations are written)
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov ecx, 0xC0000080",
+    "rdmsr",
+    "or eax, (1 << 8) | (1 << 11)",
+    "wrmsr",
+    "mov cr3, edi",
+    "mov eax, cr0",
+    "and eax, ~(1 << 16)",
+    "or eax, (1 << 31) | 1",
+    "mov cr0, eax",
+    // A 64-bit code segment, then a far return into it
+    "lea eax, [ebp + multiboot_gdt_offset]",
+    "push eax",
+    "sub esp, 2",
+    "mov word ptr [esp], 3 * 8 - 1", // GDT limit
+    "lgdt [esp]",
+    "add esp, 6",
+    "push 0x08",
+    "lea eax, [ebp + multiboot_long_mode_offset]",
+    "push eax",
+    "retf",
+    "8:",
+    "lea esi, [ebp + multiboot_no_cpu_offset]",
+    "mov dx, 0x3FD",
+    "2:",
+    "in al, dx",
+    "test al, 0x20", // Transmitter empty
+    "jz 2b",
+    "lodsb",
+    "test al, al",
+    "jz 9f",
+    "mov dx, 0x3F8",
+    "out dx, al",
+    "mov dx, 0x3FD",
+    "jmp 2b",
+    "9:",
+    "hlt",
+    "jmp 9b",
+    // Long mode, still on the identity map. The upper halves of the registers
+    // are undefined: zero-extend the image and MBI addresses
+    ".code64",
+    "multiboot_long_mode:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov ebp, ebp",
+    "mov ebx, ebx",
+    "lea rax, [rip + 5f]",
+    "sub rax, rbp",
+    "mov rcx, {kernel_base}",
+    "add rax, rcx",
+    "jmp rax",
+    // At the link address: R_X86_64_RELATIVE relocations get their addend
+    "5:",
+    "lea rsi, [rip + __rela_start]",
+    "lea rdi, [rip + __rela_end]",
+    "2:",
+    "cmp rsi, rdi",
+    "jae 3f",
+    "cmp dword ptr [rsi + 8], 8",
+    "jne 6f",
+    "mov rax, [rsi]",
+    "mov rdx, [rsi + 16]",
+    "mov [rax], rdx",
+    "6:",
+    "add rsi, 24",
+    "jmp 2b",
+    "3:",
+    "mov rax, cr0",
+    "or rax, 1 << 16",
+    "mov cr0, rax",
+    "mov [rip + {info_addr}], rbx",
+    "mov [rip + {load_addr}], rbp",
+    // The GDT at its high address, then no more identity map
+    "lea rsp, [rip + multiboot_stack_top]",
+    "sub rsp, 16",
+    "mov word ptr [rsp], 3 * 8 - 1", // GDT limit
+    "lea rax, [rip + multiboot_gdt]",
+    "mov [rsp + 2], rax",
+    "lgdt [rsp]",
+    "add rsp, 16",
+    "mov qword ptr [rip + multiboot_pml4], 0",
+    "mov rax, cr3",
+    "mov cr3, rax",
+    "xor ebp, ebp", // Clear RBP for stack tracing
+    "xor edi, edi",
+    "call {start}",
+    "ud2",
+    ".balign 8",
+    "multiboot_gdt:",
+    ".quad 0",
+    ".quad 0x00af9a000000ffff", // 64-bit code
+    ".quad 0x00cf92000000ffff", // Data
+    "multiboot_gdt_end:",
+    "multiboot_no_cpu:",
+    ".asciz \"cat: this CPU has no long mode or no NX, halting\\r\\n\"",
+    ".popsection",
+    // Page tables and the stack
+    ".pushsection .bss.multiboot, \"aw\", @nobits",
+    ".balign 4096",
+    "multiboot_pml4: .skip 4096",
+    "multiboot_pdpt_low: .skip 4096",
+    ".global multiboot_pdpt_hhdm",
+    "multiboot_pdpt_hhdm: .skip 4096",
+    "multiboot_pd_low: .skip 4 * 4096",
+    "multiboot_pd_hhdm: .skip 4 * 4096",
+    "multiboot_pdpt_kernel: .skip 4096",
+    "multiboot_pd_kernel: .skip 4096",
+    "multiboot_pt_kernel: .skip {kernel_pts} * 4096",
+    "multiboot_stack: .skip {stack_size}",
+    "multiboot_stack_top:",
+    ".popsection",
+    header_magic = const HEADER_MAGIC,
+    bootloader_magic = const BOOTLOADER_MAGIC,
+    load_phys = const LOAD_PHYS,
+    kernel_pts = const KERNEL_PTS,
+    kernel_base = const KERNEL_BASE,
+    stack_size = const shared::DEFAULT_KERNEL_STACK,
+    info_addr = sym INFO_ADDR,
+    load_addr = sym LOAD_ADDR,
+    start = sym crate::_start,
+);
+
+unsafe extern "C" {
+    static mut multiboot_pdpt_hhdm: [u64; 512]; // The HHDM's PML4[256] table
+    static __kernel_end: u8;
+}
+
+// The translated BootInfo and the arrays it points to
+static BOOT_INFO: Once<BootInfo> = Once::new();
+static mut REGIONS: [MemoryRegion; MAX_REGIONS] = [MemoryRegion {
+    start: 0,
+    len: 0,
+    kind: MemoryKind::Reserved,
+}; MAX_REGIONS];
+static mut BOOT_MODULES: [BootModule; MAX_BOOT_MODULES] = [BootModule {
+    addr: 0,
+    size: 0,
+    name: [0; shared::BOOT_MODULE_NAME_LEN],
+}; MAX_BOOT_MODULES];
+
+// One entry of the mmap tag
+#[derive(Clone, Copy)]
+struct MmapEntry {
+    base: u64,
+    length: u64,
+    type_: u32,
+}
+
+// A tag of the MBI: its type and everything after the 8-byte header
+struct Tag<'a> {
+    type_: u32,
+    data: &'a [u8],
+}
+
+// The BootInfo for a kernel started by GRUB, None otherwise. Called once, first
+// thing in `_start`
+pub fn boot_info() -> Option<&'static BootInfo> {
+    let mbi_phys = INFO_ADDR.load(Ordering::Relaxed);
+    if mbi_phys == 0 {
+        return None;
+    }
+    let features = cpu::detect();
+    if features & cpu::REQUIRED != cpu::REQUIRED {
+        panic!(
+            "Multiboot: the CPU lacks {}",
+            cpu::Names(cpu::REQUIRED & !features)
+        );
+    }
+    let load_phys = LOAD_ADDR.load(Ordering::Relaxed);
+    Some(BOOT_INFO.call_once(|| {
+        let size = unsafe { core::ptr::read((mbi_phys + HHDM_OFFSET) as *const u32) };
+        let mbi = unsafe {
+            core::slice::from_raw_parts((mbi_phys + HHDM_OFFSET) as *const u8, size as usize)
+        };
+        translate(mbi, mbi_phys, load_phys, features)
+    }))
+}
+
+fn translate(mbi: &[u8], mbi_phys: u64, load_phys: u64, features: u64) -> BootInfo {
+    // Physical address of a kernel static
+    let kernel_phys = |virt: u64| virt - KERNEL_BASE + load_phys;
+    let kernel_end = kernel_phys(&raw const __kernel_end as u64);
+
+    let mut info = BootInfo {
+        magic: 0,
+        version: 0,
+        size: 0,
+        memory_map_addr: 0,
+        memory_map_len: 0,
+        hhdm_offset: HHDM_OFFSET,
+        max_phys_memory: 0,
+        framebuffer: FrameBufferInfo {
+            buffer_base: 0,
+            buffer_phys: 0,
+            buffer_size: 0,
+            width: 0,
+            height: 0,
+            stride: 0,
+            format: PixelFormat::BGR,
+        },
+        rsdp_addr: 0,
+        initramfs_addr: 0,
+        initramfs_size: 0,
+        font_addr: 0,
+        font_size: 0,
+        cmdline_addr: 0,
+        cmdline_len: 0,
+        kernel_slide: 0,
+        modules_addr: 0,
+        modules_count: 0,
+        efi_system_table: 0,
+        efi_runtime_services: 0,
+        boot_trial: 0,
+        paging_levels: 4,
+        edid_addr: 0,
+        edid_size: 0,
+        // The image, holding the page tables, stack and this BootInfo, is
+        // carved out of the memory map instead
+        reserved: [ReservedRange::EMPTY; MAX_RESERVED],
+        reserved_count: 0,
+        tpm_event_log_addr: 0,
+        tpm_event_log_size: 0,
+        stack_top: 0,
+        stack_size: 0,
+        cpu_features: features,
+    };
+    info.init_header();
+
+    // What GRUB loaded, in memory its map calls available
+    let mut taken: [Range<u64>; MAX_BOOT_MODULES + 2] = core::array::from_fn(|_| 0..0);
+    taken[0] = load_phys..kernel_end;
+    taken[1] = mbi_phys..mbi_phys + mbi.len() as u64;
+    let mut taken_count = 2;
+    let mut rsdp = (0, 0); // (tag type, address)
+    let mut mmap = None;
+    let mut framebuffer = None;
+
+    let modules = unsafe {
+        core::slice::from_raw_parts_mut(&raw mut BOOT_MODULES as *mut BootModule, MAX_BOOT_MODULES)
+    };
+    let mut module_count = 0;
+    for tag in tags(mbi) {
+        let tag_phys = mbi_phys + (tag.data.as_ptr() as u64 - mbi.as_ptr() as u64);
+        match tag.type_ {
+            TAG_CMDLINE => {
+                if let Some(cmdline) = c_str(tag.data) {
+                    info.cmdline_addr = tag_phys;
+                    info.cmdline_len = cmdline.len() as u64;
+                }
+            }
+            TAG_MODULE if module_count < MAX_BOOT_MODULES && tag.data.len() >= 8 => {
+                let start = u32_at(tag.data, 0) as u64;
+                let end = u32_at(tag.data, 4) as u64;
+                let name = c_str(&tag.data[8..])
+                    .filter(|name| !name.is_empty())
+                    .unwrap_or("module");
+                let data = unsafe {
+                    core::slice::from_raw_parts(
+                        (start + HHDM_OFFSET) as *const u8,
+                        end.saturating_sub(start) as usize,
+                    )
+                };
+                let mut module = BootModule::new(name, data);
+                module.addr -= HHDM_OFFSET;
+                match module.name() {
+                    "initramfs" => {
+                        (info.initramfs_addr, info.initramfs_size) = (module.addr, module.size)
+                    }
+                    "font" => (info.font_addr, info.font_size) = (module.addr, module.size),
+                    _ => {}
+                }
+                modules[module_count] = module;
+                module_count += 1;
+                taken[taken_count] = start..end;
+                taken_count += 1;
+            }
+            TAG_MMAP => mmap = Some(tag.data),
+            TAG_FRAMEBUFFER => framebuffer = Some(tag.data),
+            // The RSDP itself follows the tag header; ACPI 2.0 wins
+            TAG_ACPI_OLD | TAG_ACPI_NEW if tag.type_ > rsdp.0 => rsdp = (tag.type_, tag_phys),
+            _ => {}
+        }
+    }
+    if module_count != 0 {
+        info.modules_addr = kernel_phys(modules.as_ptr() as u64);
+        info.modules_count = module_count as u64;
+    }
+    info.rsdp_addr = rsdp.1;
+
+    // Memory map, up to what the HHDM can reach
+    let entries = mmap.map(mmap_entries).into_iter().flatten();
+    let ram_end = entries
+        .clone()
+        .filter(|entry| entry.type_ != MMAP_BAD)
+        .map(|entry| entry.base + entry.length)
+        .max()
+        .unwrap_or(0);
+    let framebuffer = framebuffer.and_then(convert_framebuffer);
+    let wanted = framebuffer.as_ref().map_or(ram_end, |fb| {
+        ram_end.max(fb.buffer_phys + fb.buffer_size as u64)
+    });
+    let limit = extend_hhdm(wanted, features);
+    let regions = unsafe {
+        core::slice::from_raw_parts_mut(&raw mut REGIONS as *mut MemoryRegion, MAX_REGIONS)
+    };
+    let count = convert_memory_map(entries, &taken[..taken_count], limit, regions);
+    info.memory_map_addr = kernel_phys(regions.as_ptr() as u64);
+    info.memory_map_len = count as u64;
+    // As uefi_boot: the highest address, rounded up to 2 MiB
+    let max_phys = regions[..count].last().map_or(0, MemoryRegion::end);
+    info.max_phys_memory = max_phys.next_multiple_of(2 * 1024 * 1024);
+
+    if let Some(fb) = framebuffer
+        && fb.buffer_phys + fb.buffer_size as u64 <= limit
+    {
+        info.framebuffer = fb;
+    }
+    info
+}
+
+// Extend the HHDM past the stub's 4 GiB with 1 GiB pages, to cover `end` if the
+// CPU has them; returns the end of the HHDM
+fn extend_hhdm(end: u64, features: u64) -> u64 {
+    if end <= LOW_LIMIT || features & cpu::PDPE1GB == 0 {
+        return LOW_LIMIT;
+    }
+    let end = end.next_multiple_of(GIB).min(512 * GIB);
+    let pdpt = &raw mut multiboot_pdpt_hhdm as *mut u64;
+    for gib in LOW_LIMIT / GIB..end / GIB {
+        // Present, writable, huge, no-execute
+        unsafe { pdpt.add(gib as usize).write((gib * GIB) | 0x83 | (1 << 63)) };
+    }
+    x86_64::instructions::tlb::flush_all();
+    end
+}
+
+// The tags of the MBI, up to the end tag
+fn tags(mbi: &[u8]) -> impl Iterator<Item = Tag<'_>> {
+    let mut offset = 8;
+    core::iter::from_fn(move || {
+        let header = mbi.get(offset..offset + 8)?;
+        let type_ = u32_at(header, 0);
+        let size = u32_at(header, 4) as usize;
+        if type_ == TAG_END || size < 8 {
+            return None;
+        }
+        let data = mbi.get(offset + 8..offset + size)?;
+        offset += size.next_multiple_of(8);
+        Some(Tag { type_, data })
+    })
+}
+
+// The entries of an mmap tag
+fn mmap_entries(data: &[u8]) -> impl Iterator<Item = MmapEntry> + Clone + '_ {
+    let entry_size = data.get(..4).map_or(0, |_| u32_at(data, 0) as usize);
+    let entries = data.get(8..).filter(|_| entry_size >= 24).unwrap_or(&[]);
+    entries
+        .chunks_exact(entry_size.max(24))
+        .map(|entry| MmapEntry {
+            base: u64_at(entry, 0),
+            length: u64_at(entry, 8),
+            type_: u32_at(entry, 16),
+        })
+}
+
+// A framebuffer tag describing 32-bit direct RGB color
+fn convert_framebuffer(data: &[u8]) -> Option<FrameBufferInfo> {
+    if data.len() < 30 {
+        return None;
+    }
+    let phys = u64_at(data, 0);
+    let pitch = u32_at(data, 8) as usize;
+    let width = u32_at(data, 12) as usize;
+    let height = u32_at(data, 16) as usize;
+    let (bpp, type_) = (data[20], data[21]);
+    if type_ != FRAMEBUFFER_RGB || bpp != 32 || phys == 0 {
+        return None;
+    }
+    let red_shift = *data.get(24)?;
+    Some(FrameBufferInfo {
+        buffer_base: phys + HHDM_OFFSET,
+        buffer_phys: phys,
+        buffer_size: pitch * height,
+        width,
+        height,
+        stride: pitch / 4,
+        // Red in the low byte is what UEFI calls RGB
+        format: if red_shift == 0 {
+            PixelFormat::RGB
+        } else {
+            PixelFormat::BGR
+        },
+    })
+}
+
+// The mmap entries as sorted, merged regions in `out`, below `limit`, with the
+// `taken` ranges of available memory made Bootloader; returns how many
+fn convert_memory_map(
+    entries: impl Iterator<Item = MmapEntry>,
+    taken: &[Range<u64>],
+    limit: u64,
+    out: &mut [MemoryRegion],
+) -> usize {
+    let mut count = 0;
+    let mut push = |start: u64, end: u64, kind| {
+        if start < end && count < out.len() {
+            out[count] = MemoryRegion {
+                start,
+                len: end - start,
+                kind,
+            };
+            count += 1;
+        }
+    };
+    for entry in entries {
+        let end = (entry.base + entry.length).min(limit);
+        if entry.type_ != MMAP_AVAILABLE {
+            // Rounded out: whatever shares a page with it is not usable
+            let kind = match entry.type_ {
+                MMAP_ACPI_RECLAIMABLE => MemoryKind::AcpiReclaimable,
+                MMAP_NVS => MemoryKind::Firmware,
+                _ => MemoryKind::Reserved,
+            };
+            push(
+                entry.base / PAGE_SIZE * PAGE_SIZE,
+                end.next_multiple_of(PAGE_SIZE),
+                kind,
+            );
+            continue;
+        }
+        let end = end / PAGE_SIZE * PAGE_SIZE;
+        let mut start = entry.base.next_multiple_of(PAGE_SIZE);
+        while start < end {
+            let next = taken
+                .iter()
+                .map(|range| {
+                    range.start / PAGE_SIZE * PAGE_SIZE..range.end.next_multiple_of(PAGE_SIZE)
+                })
+                .filter(|range| range.start < end && start < range.end)
+                .min_by_key(|range| range.start);
+            match next {
+                Some(range) if range.start <= start => {
+                    let stop = range.end.min(end);
+                    push(start, stop, MemoryKind::Bootloader);
+                    start = stop;
+                }
+                Some(range) => {
+                    push(start, range.start, MemoryKind::Usable);
+                    start = range.start;
+                }
+                None => {
+                    push(start, end, MemoryKind::Usable);
+                    start = end;
+                }
+            }
+        }
+    }
+    memory::normalize(&mut out[..count])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn c_str(data: &[u8]) -> Option<&str> {
+    CStr::from_bytes_until_nul(data).ok()?.to_str().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn multiboot_memory_map_is_carved() {
+        let entry = |base, length, type_| MmapEntry {
+            base,
+            length,
+            type_,
+        };
+        let entries = [
+            entry(0x0, 0x9fc00, MMAP_AVAILABLE),
+            entry(0x9fc00, 0x400, 2),
+            entry(0x100000, 0x7ff00000, MMAP_AVAILABLE),
+            entry(0x7ff00000, 0x100000, MMAP_ACPI_RECLAIMABLE),
+            entry(0x1_0000_0000, 0x4000_0000, MMAP_AVAILABLE),
+        ];
+        // The kernel at 2 MiB, a module ending inside a page
+        let taken = [0x200000..0x500000, 0x600000..0x600800];
+        let mut out = [MemoryRegion {
+            start: 0,
+            len: 0,
+            kind: MemoryKind::Reserved,
+        }; 16];
+        let count = convert_memory_map(entries.into_iter(), &taken, LOW_LIMIT, &mut out);
+        let regions: [_; 8] = core::array::from_fn(|i| (out[i].start, out[i].end(), out[i].kind));
+        assert_eq!(count, 8);
+        assert_eq!(regions[0], (0x0, 0x9f000, MemoryKind::Usable));
+        assert_eq!(regions[1], (0x9f000, 0xa0000, MemoryKind::Reserved));
+        assert_eq!(regions[2], (0x100000, 0x200000, MemoryKind::Usable));
+        assert_eq!(regions[3], (0x200000, 0x500000, MemoryKind::Bootloader));
+        assert_eq!(regions[4], (0x500000, 0x600000, MemoryKind::Usable));
+        assert_eq!(regions[5], (0x600000, 0x601000, MemoryKind::Bootloader));
+        assert_eq!(regions[6], (0x601000, 0x7ff00000, MemoryKind::Usable));
+        // Above 4 GiB is gone
+        assert_eq!(
+            regions[7],
+            (0x7ff00000, 0x80000000, MemoryKind::AcpiReclaimable)
+        );
+    }
+}
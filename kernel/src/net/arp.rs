@@ -0,0 +1,301 @@
+// ARP (Address Resolution Protocol, RFC 826)
+// Maps IPv4 next hops to MAC addresses with a neighbor cache:
+//   - Resolved entries expire REACHABLE_MS after they were learned
+//   - Unresolved entries queue up to MAX_QUEUED packets while a request is outstanding,
+//     the request is retried every RETRY_MS and the queue dropped after MAX_REQUESTS
+//
+// ARP packet (Ethernet / IPv4):
+//   0 htype(2)=1  2 ptype(2)=0x0800  4 hlen=6  5 plen=4  6 oper(2)
+//   8 sender MAC  14 sender IP  18 target MAC  24 target IP
+//
+// /proc/arp lists the resolved entries.
+
+use super::{ETHERTYPE_ARP, ETHERTYPE_IPV4, Ipv4Addr, MacAddr, NetError, PacketBuffer, Protocol};
+use crate::fs::procfs::{self, ProcEntry};
+use crate::interrupts::TICKS;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const ARP_LEN: usize = 28;
+const HTYPE_ETHERNET: u16 = 1;
+const OPER_REQUEST: u16 = 1;
+const OPER_REPLY: u16 = 2;
+
+// Timer ticks are milliseconds (1000 Hz PIT)
+const REACHABLE_MS: u64 = 60_000;
+const RETRY_MS: u64 = 1_000;
+const MAX_REQUESTS: u32 = 3;
+const MAX_QUEUED: usize = 8;
+
+// Ticks between two aging passes over the cache
+pub const AGE_INTERVAL_TICKS: u64 = 1_000;
+
+enum State {
+    Resolved {
+        mac: MacAddr,
+        expires: u64,
+    },
+    Incomplete {
+        queue: Vec<(u16, PacketBuffer)>, // (EtherType, packet) waiting for the MAC
+        requests: u32,
+        last_request: u64,
+    },
+}
+
+static NEIGHBORS: Mutex<BTreeMap<(usize, Ipv4Addr), State>> = Mutex::new(BTreeMap::new());
+
+static ARP_PROTOCOL: Protocol = Protocol {
+    name: "arp",
+    ethertype: ETHERTYPE_ARP,
+    receive,
+};
+
+pub fn init() {
+    super::register_protocol(&ARP_PROTOCOL);
+    procfs::register(&ARP_ENTRY);
+}
+
+fn now() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+fn send_arp(
+    iface: usize,
+    oper: u16,
+    dst_mac: MacAddr,
+    target_mac: MacAddr,
+    target_ip: Ipv4Addr,
+) -> Result<(), NetError> {
+    let device = super::device(iface).ok_or(NetError::NoDevice)?;
+    let config = super::ipv4_config(iface).ok_or(NetError::Unreachable)?;
+    let mut packet = PacketBuffer::alloc().ok_or(NetError::NoBuffers)?;
+
+    let arp = packet.put(ARP_LEN);
+    arp[0..2].copy_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+    arp[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    arp[4] = 6;
+    arp[5] = 4;
+    arp[6..8].copy_from_slice(&oper.to_be_bytes());
+    arp[8..14].copy_from_slice(&device.mac().0);
+    arp[14..18].copy_from_slice(&config.addr.0);
+    arp[18..24].copy_from_slice(&target_mac.0);
+    arp[24..28].copy_from_slice(&target_ip.0);
+
+    super::send_frame(iface, dst_mac, ETHERTYPE_ARP, packet)
+}
+
+fn send_request(iface: usize, target: Ipv4Addr) -> Result<(), NetError> {
+    send_arp(
+        iface,
+        OPER_REQUEST,
+        MacAddr::BROADCAST,
+        MacAddr::ZERO,
+        target,
+    )
+}
+
+// Broadcast our own address (gratuitous ARP) so neighbors update their caches
+pub fn announce(iface: usize) {
+    if let Some(config) = super::ipv4_config(iface) {
+        let _ = send_request(iface, config.addr);
+    }
+}
+
+// Cached MAC address of a neighbor
+pub fn lookup(iface: usize, ip: Ipv4Addr) -> Option<MacAddr> {
+    interrupts::without_interrupts(|| match NEIGHBORS.lock().get(&(iface, ip)) {
+        Some(State::Resolved { mac, expires }) if *expires > now() => Some(*mac),
+        _ => None,
+    })
+}
+
+// Send a packet to a next hop on `iface`, resolving its MAC address first if needed
+// The packet is queued (not an error) while resolution is in progress
+pub fn send_to(
+    iface: usize,
+    next_hop: Ipv4Addr,
+    ethertype: u16,
+    packet: PacketBuffer,
+) -> Result<(), NetError> {
+    if next_hop == Ipv4Addr::BROADCAST || super::device(iface).is_some_and(|d| d.is_loopback()) {
+        return super::send_frame(iface, MacAddr::BROADCAST, ethertype, packet);
+    }
+    if let Some(mac) = lookup(iface, next_hop) {
+        return super::send_frame(iface, mac, ethertype, packet);
+    }
+
+    let now = now();
+    let need_request = interrupts::without_interrupts(|| {
+        let mut neighbors = NEIGHBORS.lock();
+        let entry = neighbors
+            .entry((iface, next_hop))
+            .or_insert(State::Incomplete {
+                queue: Vec::new(),
+                requests: 0,
+                last_request: 0,
+            });
+
+        // An expired entry starts resolving again
+        if matches!(entry, State::Resolved { .. }) {
+            *entry = State::Incomplete {
+                queue: Vec::new(),
+                requests: 0,
+                last_request: 0,
+            };
+        }
+
+        let State::Incomplete {
+            queue,
+            requests,
+            last_request,
+        } = entry
+        else {
+            unreachable!()
+        };
+
+        if queue.len() >= MAX_QUEUED {
+            queue.remove(0); // Keep the most recent packets
+        }
+        queue.push((ethertype, packet));
+
+        if *requests == 0 {
+            *requests = 1;
+            *last_request = now;
+            true
+        } else {
+            false
+        }
+    });
+
+    if need_request {
+        send_request(iface, next_hop)?;
+    }
+    Ok(())
+}
+
+// Record a neighbor's MAC address and return the packets that were waiting for it
+fn learn(iface: usize, ip: Ipv4Addr, mac: MacAddr, create: bool) -> Vec<(u16, PacketBuffer)> {
+    interrupts::without_interrupts(|| {
+        let mut neighbors = NEIGHBORS.lock();
+        if !create && !neighbors.contains_key(&(iface, ip)) {
+            return Vec::new();
+        }
+
+        let resolved = State::Resolved {
+            mac,
+            expires: now() + REACHABLE_MS,
+        };
+        match neighbors.insert((iface, ip), resolved) {
+            Some(State::Incomplete { queue, .. }) => queue,
+            _ => Vec::new(),
+        }
+    })
+}
+
+fn receive(iface: usize, _src: MacAddr, packet: PacketBuffer) {
+    let arp = packet.data();
+    if arp.len() < ARP_LEN
+        || u16::from_be_bytes([arp[0], arp[1]]) != HTYPE_ETHERNET
+        || u16::from_be_bytes([arp[2], arp[3]]) != ETHERTYPE_IPV4
+        || arp[4] != 6
+        || arp[5] != 4
+    {
+        return;
+    }
+
+    let oper = u16::from_be_bytes([arp[6], arp[7]]);
+    let sender_mac = MacAddr([arp[8], arp[9], arp[10], arp[11], arp[12], arp[13]]);
+    let sender_ip = Ipv4Addr([arp[14], arp[15], arp[16], arp[17]]);
+    let target_ip = Ipv4Addr([arp[24], arp[25], arp[26], arp[27]]);
+
+    let Some(config) = super::ipv4_config(iface) else {
+        return;
+    };
+    let for_us = target_ip == config.addr;
+
+    // RFC 826: update an existing entry for the sender, add one only if we are the target
+    if sender_ip != Ipv4Addr::UNSPECIFIED {
+        for (ethertype, queued) in learn(iface, sender_ip, sender_mac, for_us) {
+            let _ = super::send_frame(iface, sender_mac, ethertype, queued);
+        }
+    }
+
+    // Gratuitous ARP (sender == target) needs no reply
+    if for_us && oper == OPER_REQUEST && sender_ip != target_ip {
+        let _ = send_arp(iface, OPER_REPLY, sender_mac, sender_mac, sender_ip);
+    }
+}
+
+// Drop expired entries and retry or give up on pending requests
+fn age(neighbors: &mut BTreeMap<(usize, Ipv4Addr), State>, now: u64) -> Vec<(usize, Ipv4Addr)> {
+    let mut retry = Vec::new();
+
+    neighbors.retain(|&(iface, ip), state| match state {
+        State::Resolved { expires, .. } => *expires > now,
+        State::Incomplete {
+            requests,
+            last_request,
+            ..
+        } => {
+            if now < *last_request + RETRY_MS {
+                return true;
+            }
+            if *requests >= MAX_REQUESTS {
+                return false; // Unreachable: the queued packets are dropped
+            }
+            *requests += 1;
+            *last_request = now;
+            retry.push((iface, ip));
+            true
+        }
+    });
+
+    retry
+}
+
+// Timer interrupt entry: skip if the cache is locked by the interrupted code
+pub fn age_from_interrupt(now: u64) {
+    let retry = match NEIGHBORS.try_lock() {
+        Some(mut neighbors) => age(&mut neighbors, now),
+        None => return,
+    };
+
+    for (iface, ip) in retry {
+        let _ = send_request(iface, ip);
+    }
+}
+
+// Snapshot of resolved entries: (interface, IP, MAC)
+pub fn entries() -> Vec<(usize, Ipv4Addr, MacAddr)> {
+    interrupts::without_interrupts(|| {
+        NEIGHBORS
+            .lock()
+            .iter()
+            .filter_map(|(&(iface, ip), state)| match state {
+                State::Resolved { mac, .. } => Some((iface, ip, *mac)),
+                State::Incomplete { .. } => None,
+            })
+            .collect()
+    })
+}
+
+// /proc/arp: one "<IP> <MAC> <interface>" line per resolved neighbor
+fn read_arp() -> String {
+    let mut out = String::new();
+    for (iface, ip, mac) in entries() {
+        let name = super::device(iface).map(|d| String::from(d.name()));
+        let _ = writeln!(out, "{} {} {}", ip, mac, name.as_deref().unwrap_or("?"));
+    }
+    out
+}
+
+static ARP_ENTRY: ProcEntry = ProcEntry {
+    name: "arp",
+    read: read_arp,
+    write: None,
+};
@@ -0,0 +1,134 @@
+// Packet Buffers
+// An skb-like buffer: one physical 4 KiB frame holding a packet with headroom in front,
+// so each protocol layer can prepend its header (`push`) on transmit and strip it
+// (`pull`) on receive without copying the payload.
+//
+// Frames come from the PMM and are physically contiguous, so a NIC can DMA to and
+// from them. Freed buffers go back to a pool of
+// up to POOL_SIZE frames, the rest to the PMM.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::PhysAddr;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{FrameDeallocator, PhysFrame};
+
+// Size of the backing frame
+pub const BUFFER_SIZE: usize = 4096;
+
+// Space reserved in front of the data for headers added while transmitting
+pub const HEADROOM: usize = 128;
+
+static HHDM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// Free frames kept for reuse
+const POOL_SIZE: usize = 64;
+
+// Physical addresses of frames released by dropped buffers
+static FREE_FRAMES: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+// Frames taken from the PMM and not given back
+static ALLOCATED_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+pub fn init(hhdm_offset: u64) {
+    HHDM_OFFSET.store(hhdm_offset, Ordering::Relaxed);
+}
+
+pub struct PacketBuffer {
+    phys: u64,
+    head: usize, // Offset of the first data byte
+    tail: usize, // Offset after the last data byte
+}
+
+// The buffer exclusively owns its frame
+unsafe impl Send for PacketBuffer {}
+
+impl PacketBuffer {
+    // Allocate an empty buffer with the default headroom
+    pub fn alloc() -> Option<Self> {
+        let phys = interrupts::without_interrupts(|| FREE_FRAMES.lock().pop()).or_else(|| {
+            let frame = crate::pmm::allocate_frame()?;
+            ALLOCATED_FRAMES.fetch_add(1, Ordering::Relaxed);
+            Some(frame)
+        })?;
+
+        Some(Self {
+            phys,
+            head: HEADROOM,
+            tail: HEADROOM,
+        })
+    }
+
+    fn frame(&self) -> *mut u8 {
+        (self.phys + HHDM_OFFSET.load(Ordering::Relaxed)) as *mut u8
+    }
+
+    pub fn len(&self) -> usize {
+        self.tail - self.head
+    }
+
+    pub fn data(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.frame().add(self.head), self.len()) }
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.frame().add(self.head), self.len()) }
+    }
+
+    // Prepend `len` bytes (a header) and return them
+    pub fn push(&mut self, len: usize) -> &mut [u8] {
+        assert!(len <= self.head, "packet buffer headroom exhausted");
+        self.head -= len;
+        &mut self.data_mut()[..len]
+    }
+
+    // Remove `len` bytes (a parsed header) from the front
+    pub fn pull(&mut self, len: usize) -> bool {
+        if len > self.len() {
+            return false;
+        }
+        self.head += len;
+        true
+    }
+
+    // Append `len` bytes and return them
+    pub fn put(&mut self, len: usize) -> &mut [u8] {
+        assert!(self.tail + len <= BUFFER_SIZE, "packet buffer full");
+        let start = self.len();
+        self.tail += len;
+        &mut self.data_mut()[start..]
+    }
+
+    // Cut the data down to `len` bytes (e.g. to drop Ethernet padding)
+    pub fn trim(&mut self, len: usize) {
+        if len < self.len() {
+            self.tail = self.head + len;
+        }
+    }
+}
+
+impl Drop for PacketBuffer {
+    fn drop(&mut self) {
+        let phys = self.phys;
+        let pooled = interrupts::without_interrupts(|| {
+            let mut pool = FREE_FRAMES.lock();
+            let room = pool.len() < POOL_SIZE;
+            if room {
+                pool.push(phys);
+            }
+            room
+        });
+        if !pooled {
+            ALLOCATED_FRAMES.fetch_sub(1, Ordering::Relaxed);
+            let frame = PhysFrame::containing_address(PhysAddr::new(phys));
+            unsafe { crate::pmm::KernelFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+// (frames held from the PMM, frames currently free in the pool)
+pub fn pool_stats() -> (u64, usize) {
+    let free = interrupts::without_interrupts(|| FREE_FRAMES.lock().len());
+    (ALLOCATED_FRAMES.load(Ordering::Relaxed), free)
+}
@@ -0,0 +1,174 @@
+// IPv4
+// Minimal IPv4 layer for the transport protocols: routing over the configured
+// interfaces (directly attached subnet, else the interface's gateway), header
+// construction and validation. Fragments and IP options are not supported.
+//
+// Header (20 bytes):
+//   0 version/IHL  1 TOS  2 total length  4 id  6 flags/fragment offset
+//   8 TTL  9 protocol  10 checksum  12 source  16 destination
+
+use super::{ETHERTYPE_IPV4, Ipv4Addr, MacAddr, NetError, PacketBuffer, Protocol};
+use core::sync::atomic::{AtomicU16, Ordering};
+
+pub const HEADER_LEN: usize = 20;
+pub const PROTO_UDP: u8 = 17;
+
+const DEFAULT_TTL: u8 = 64;
+
+// "More fragments" flag and fragment offset bits
+const FRAGMENT_MASK: u16 = 0x3FFF;
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+static IPV4_PROTOCOL: Protocol = Protocol {
+    name: "ipv4",
+    ethertype: ETHERTYPE_IPV4,
+    receive,
+};
+
+pub fn init() {
+    super::register_protocol(&IPV4_PROTOCOL);
+}
+
+// Internet checksum (RFC 1071) over `data`, continuing from a partial `sum`
+pub fn checksum(data: &[u8], mut sum: u32) -> u16 {
+    let (words, rest) = data.as_chunks::<2>();
+    for &word in words {
+        sum += u16::from_be_bytes(word) as u32;
+    }
+    if let [last] = rest {
+        sum += (*last as u32) << 8;
+    }
+
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// Partial checksum of the pseudo header used by UDP/TCP
+pub fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    let words = [
+        u16::from_be_bytes([src.0[0], src.0[1]]),
+        u16::from_be_bytes([src.0[2], src.0[3]]),
+        u16::from_be_bytes([dst.0[0], dst.0[1]]),
+        u16::from_be_bytes([dst.0[2], dst.0[3]]),
+        protocol as u16,
+        len as u16,
+    ];
+    words.iter().map(|&w| w as u32).sum()
+}
+
+// Where a packet to a destination leaves the machine
+#[derive(Debug, Clone, Copy)]
+pub struct Route {
+    pub iface: usize,
+    pub next_hop: Ipv4Addr,
+    pub src: Ipv4Addr,
+}
+
+pub fn route(dst: Ipv4Addr) -> Result<Route, NetError> {
+    // Traffic to one of our own addresses never leaves the machine
+    if super::iface_for_addr(dst).is_some()
+        && let Some(iface) = super::loopback_iface()
+    {
+        return Ok(Route {
+            iface,
+            next_hop: dst,
+            src: dst,
+        });
+    }
+
+    let configs = super::ipv4_configs();
+
+    // A local address or a directly attached subnet
+    for &(iface, config) in &configs {
+        if config.addr == dst || config.is_local(dst) || dst == Ipv4Addr::BROADCAST {
+            return Ok(Route {
+                iface,
+                next_hop: dst,
+                src: config.addr,
+            });
+        }
+    }
+
+    // Otherwise the first interface with a gateway
+    configs
+        .iter()
+        .find_map(|&(iface, config)| {
+            config.gateway.map(|gateway| Route {
+                iface,
+                next_hop: gateway,
+                src: config.addr,
+            })
+        })
+        .ok_or(NetError::Unreachable)
+}
+
+// Prepend an IPv4 header and send the packet along a route
+pub fn send(
+    route: Route,
+    dst: Ipv4Addr,
+    protocol: u8,
+    mut packet: PacketBuffer,
+) -> Result<(), NetError> {
+    let total_len = HEADER_LEN + packet.len();
+    if total_len > u16::MAX as usize {
+        return Err(NetError::TooLarge);
+    }
+
+    let header = packet.push(HEADER_LEN);
+    header[0] = 0x45; // Version 4, 5 words
+    header[1] = 0;
+    header[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+    header[4..6].copy_from_slice(&NEXT_ID.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    header[6..8].copy_from_slice(&0x4000u16.to_be_bytes()); // Don't fragment
+    header[8] = DEFAULT_TTL;
+    header[9] = protocol;
+    header[10..12].fill(0);
+    header[12..16].copy_from_slice(&route.src.0);
+    header[16..20].copy_from_slice(&dst.0);
+    let sum = checksum(header, 0);
+    header[10..12].copy_from_slice(&sum.to_be_bytes());
+
+    super::arp::send_to(route.iface, route.next_hop, ETHERTYPE_IPV4, packet)
+}
+
+fn receive(iface: usize, _src: MacAddr, mut packet: PacketBuffer) {
+    let header = packet.data();
+    if header.len() < HEADER_LEN || header[0] >> 4 != 4 {
+        return;
+    }
+
+    let header_len = (header[0] & 0x0F) as usize * 4;
+    let total_len = u16::from_be_bytes([header[2], header[3]]) as usize;
+    if header_len < HEADER_LEN || total_len < header_len || total_len > header.len() {
+        return;
+    }
+    if checksum(&header[..header_len], 0) != 0 {
+        return;
+    }
+    if u16::from_be_bytes([header[6], header[7]]) & FRAGMENT_MASK != 0 {
+        return; // Fragments are not reassembled
+    }
+
+    let protocol = header[9];
+    let src = Ipv4Addr([header[12], header[13], header[14], header[15]]);
+    let dst = Ipv4Addr([header[16], header[17], header[18], header[19]]);
+
+    // Accept packets for any of our addresses (packets to local addresses arrive on lo)
+    // and broadcasts on configured interfaces
+    let for_us = super::iface_for_addr(dst).is_some()
+        || (dst == Ipv4Addr::BROADCAST && super::ipv4_config(iface).is_some());
+    if !for_us {
+        return;
+    }
+
+    // Drop Ethernet padding and the IP header
+    packet.trim(total_len);
+    packet.pull(header_len);
+
+    if protocol == PROTO_UDP {
+        super::udp::receive(src, dst, packet);
+    }
+}
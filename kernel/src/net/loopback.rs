@@ -0,0 +1,65 @@
+// Loopback Device
+// The `lo` interface at 127.0.0.1/8: every transmitted frame is handed straight back
+// to the RX path, so sockets can talk to local servers without a NIC.
+// Packets to any of the machine's own addresses are routed here as well.
+
+use super::{Ipv4Addr, Ipv4Config, MacAddr, NetDevice, NetError, PacketBuffer, RxCallback};
+use alloc::sync::Arc;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const LOOPBACK_ADDR: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
+
+pub struct Loopback {
+    rx: Mutex<Option<(usize, RxCallback)>>,
+}
+
+impl Loopback {
+    pub fn new() -> Self {
+        Self {
+            rx: Mutex::new(None),
+        }
+    }
+}
+
+impl NetDevice for Loopback {
+    fn name(&self) -> &str {
+        "lo"
+    }
+
+    fn mac(&self) -> MacAddr {
+        MacAddr::ZERO
+    }
+
+    fn mtu(&self) -> usize {
+        super::buffer::BUFFER_SIZE - super::buffer::HEADROOM - super::ETH_HEADER_LEN
+    }
+
+    fn transmit(&self, packet: PacketBuffer) -> Result<(), NetError> {
+        let rx = interrupts::without_interrupts(|| *self.rx.lock());
+        let (iface, callback) = rx.ok_or(NetError::NoDevice)?;
+        callback(iface, packet);
+        Ok(())
+    }
+
+    fn set_rx_callback(&self, iface: usize, callback: RxCallback) {
+        interrupts::without_interrupts(|| *self.rx.lock() = Some((iface, callback)));
+    }
+
+    fn is_loopback(&self) -> bool {
+        true
+    }
+}
+
+pub fn init() {
+    let iface = super::register_device(Arc::new(Loopback::new()));
+
+    super::set_ipv4(
+        iface,
+        Ipv4Config {
+            addr: LOOPBACK_ADDR,
+            netmask: Ipv4Addr::new(255, 0, 0, 0),
+            gateway: None,
+        },
+    );
+}
@@ -0,0 +1,444 @@
+// Network Stack Core
+// The layer between NIC drivers and protocols:
+//   - NetDevice:    implemented by drivers (transmit, MAC address, MTU), receives an RX callback
+//   - PacketBuffer: frame-backed packet buffers with header room (see buffer.rs)
+//   - RX queue:     drivers queue received frames from their interrupt handler, the
+//                   queue is drained later and each frame handed to the protocol
+//                   registered for its EtherType
+//
+// There is no scheduler yet, so the RX processing "thread" is a bottom half run from
+// the timer interrupt (`poll_from_interrupt`) and from `poll()` in kernel context.
+
+pub mod arp;
+pub mod buffer;
+pub mod ipv4;
+pub mod loopback;
+#[cfg(feature = "smoltcp")]
+pub mod smoltcp_backend;
+pub mod socket;
+pub mod trace;
+pub mod udp;
+
+pub use buffer::PacketBuffer;
+
+use crate::fs::procfs::{self, ProcEntry};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// ============================================================================
+// 1. TYPES
+// ============================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: MacAddr = MacAddr([0xFF; 6]);
+    pub const ZERO: MacAddr = MacAddr([0; 6]);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0; 4]);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([255; 4]);
+
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Self {
+        Ipv4Addr([a, b, c, d])
+    }
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let a = self.0;
+        write!(f, "{}.{}.{}.{}", a[0], a[1], a[2], a[3])
+    }
+}
+
+// IPv4 address and port of a socket endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketAddr {
+    pub ip: Ipv4Addr,
+    pub port: u16,
+}
+
+impl fmt::Display for SocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.ip, self.port)
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let m = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            m[0], m[1], m[2], m[3], m[4], m[5]
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    NoDevice,
+    NoBuffers,
+    Unreachable,
+    TooLarge,
+    AddrInUse,
+    AddrNotAvailable,
+    NotConnected,
+    WouldBlock,
+    InvalidArgument,
+    Io,
+}
+
+impl NetError {
+    // Negative errno returned to user space
+    pub fn errno(self) -> i64 {
+        match self {
+            NetError::NoDevice => -19,         // ENODEV
+            NetError::NoBuffers => -105,       // ENOBUFS
+            NetError::TooLarge => -90,         // EMSGSIZE
+            NetError::Unreachable => -113,     // EHOSTUNREACH
+            NetError::AddrInUse => -98,        // EADDRINUSE
+            NetError::AddrNotAvailable => -99, // EADDRNOTAVAIL
+            NetError::NotConnected => -107,    // ENOTCONN
+            NetError::WouldBlock => -11,       // EAGAIN
+            NetError::InvalidArgument => -22,  // EINVAL
+            NetError::Io => -5,                // EIO
+        }
+    }
+}
+
+// Called by a driver for every received frame (may run in interrupt context)
+pub type RxCallback = fn(iface: usize, packet: PacketBuffer);
+
+// ============================================================================
+// 2. DEVICES
+// ============================================================================
+
+pub trait NetDevice: Send + Sync {
+    fn name(&self) -> &str;
+    fn mac(&self) -> MacAddr;
+    // Largest payload of an Ethernet frame (without the 14-byte header)
+    fn mtu(&self) -> usize;
+    // Send a complete Ethernet frame
+    fn transmit(&self, packet: PacketBuffer) -> Result<(), NetError>;
+    // Called once at registration: frames received afterwards go to `callback`
+    fn set_rx_callback(&self, iface: usize, callback: RxCallback);
+
+    // Loopback devices need no address resolution and carry traffic to local addresses
+    fn is_loopback(&self) -> bool {
+        false
+    }
+}
+
+static DEVICES: Mutex<Vec<Arc<dyn NetDevice>>> = Mutex::new(Vec::new());
+
+// Interface index of the loopback device
+pub fn loopback_iface() -> Option<usize> {
+    interrupts::without_interrupts(|| DEVICES.lock().iter().position(|d| d.is_loopback()))
+}
+
+// Register a NIC and return its interface index
+pub fn register_device(device: Arc<dyn NetDevice>) -> usize {
+    let iface = interrupts::without_interrupts(|| {
+        let mut devices = DEVICES.lock();
+        devices.push(device.clone());
+        devices.len() - 1
+    });
+
+    log::info!(
+        "{}: mac {} mtu {}",
+        device.name(),
+        device.mac(),
+        device.mtu()
+    );
+    device.set_rx_callback(iface, enqueue_rx);
+    iface
+}
+
+pub fn device(iface: usize) -> Option<Arc<dyn NetDevice>> {
+    interrupts::without_interrupts(|| DEVICES.lock().get(iface).cloned())
+}
+
+pub fn devices() -> Vec<Arc<dyn NetDevice>> {
+    interrupts::without_interrupts(|| DEVICES.lock().clone())
+}
+
+// IPv4 configuration of an interface
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv4Config {
+    pub addr: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl Ipv4Config {
+    // Whether `addr` is on the directly attached subnet
+    pub fn is_local(&self, addr: Ipv4Addr) -> bool {
+        let mask = self.netmask.to_u32();
+        addr.to_u32() & mask == self.addr.to_u32() & mask
+    }
+}
+
+static IPV4_CONFIG: Mutex<BTreeMap<usize, Ipv4Config>> = Mutex::new(BTreeMap::new());
+
+// Assign an IPv4 address to an interface and announce it (gratuitous ARP)
+pub fn set_ipv4(iface: usize, config: Ipv4Config) {
+    interrupts::without_interrupts(|| IPV4_CONFIG.lock().insert(iface, config));
+
+    let name = device(iface).map(|d| alloc::string::String::from(d.name()));
+    log::info!(
+        "{}: inet {} netmask {}",
+        name.as_deref().unwrap_or("?"),
+        config.addr,
+        config.netmask
+    );
+    if !device(iface).is_some_and(|d| d.is_loopback()) {
+        arp::announce(iface);
+    }
+}
+
+pub fn ipv4_config(iface: usize) -> Option<Ipv4Config> {
+    interrupts::without_interrupts(|| IPV4_CONFIG.lock().get(&iface).copied())
+}
+
+// Every configured interface
+pub fn ipv4_configs() -> Vec<(usize, Ipv4Config)> {
+    interrupts::without_interrupts(|| {
+        IPV4_CONFIG
+            .lock()
+            .iter()
+            .map(|(&iface, &config)| (iface, config))
+            .collect()
+    })
+}
+
+// Interface owning a local address
+pub fn iface_for_addr(addr: Ipv4Addr) -> Option<usize> {
+    interrupts::without_interrupts(|| {
+        IPV4_CONFIG
+            .lock()
+            .iter()
+            .find(|(_, config)| config.addr == addr)
+            .map(|(&iface, _)| iface)
+    })
+}
+
+// ============================================================================
+// 3. ETHERNET
+// ============================================================================
+
+pub const ETH_HEADER_LEN: usize = 14;
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+// A protocol handler for one EtherType, called with the Ethernet header already pulled
+pub struct Protocol {
+    pub name: &'static str,
+    pub ethertype: u16,
+    pub receive: fn(iface: usize, src: MacAddr, packet: PacketBuffer),
+}
+
+static PROTOCOLS: Mutex<Vec<&'static Protocol>> = Mutex::new(Vec::new());
+
+pub fn register_protocol(protocol: &'static Protocol) {
+    interrupts::without_interrupts(|| PROTOCOLS.lock().push(protocol));
+}
+
+// Prepend an Ethernet header and transmit
+pub fn send_frame(
+    iface: usize,
+    dst: MacAddr,
+    ethertype: u16,
+    mut packet: PacketBuffer,
+) -> Result<(), NetError> {
+    let device = device(iface).ok_or(NetError::NoDevice)?;
+    if packet.len() > device.mtu() {
+        return Err(NetError::TooLarge);
+    }
+
+    let header = packet.push(ETH_HEADER_LEN);
+    header[0..6].copy_from_slice(&dst.0);
+    header[6..12].copy_from_slice(&device.mac().0);
+    header[12..14].copy_from_slice(&ethertype.to_be_bytes());
+
+    TX_PACKETS.fetch_add(1, Ordering::Relaxed);
+    trace::frame(iface, trace::Direction::Tx, packet.data());
+    device.transmit(packet)
+}
+
+// ============================================================================
+// 4. RX PROCESSING
+// ============================================================================
+
+// Frames waiting for processing, beyond this they are dropped
+const RX_QUEUE_LEN: usize = 256;
+
+// Timer ticks between two runs of the RX bottom half
+pub const POLL_INTERVAL_TICKS: u64 = 4;
+
+static RX_QUEUE: Mutex<VecDeque<(usize, PacketBuffer)>> = Mutex::new(VecDeque::new());
+static POLLING: AtomicBool = AtomicBool::new(false);
+
+static RX_PACKETS: AtomicU64 = AtomicU64::new(0);
+static TX_PACKETS: AtomicU64 = AtomicU64::new(0);
+static RX_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+fn enqueue_rx(iface: usize, packet: PacketBuffer) {
+    let queued = interrupts::without_interrupts(|| {
+        let mut queue = RX_QUEUE.lock();
+        if queue.len() >= RX_QUEUE_LEN {
+            return false;
+        }
+        queue.push_back((iface, packet));
+        true
+    });
+
+    if !queued {
+        RX_DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Hand one frame to its protocol
+fn process(iface: usize, packet: PacketBuffer) {
+    RX_PACKETS.fetch_add(1, Ordering::Relaxed);
+    trace::frame(iface, trace::Direction::Rx, packet.data());
+
+    // Interfaces attached to smoltcp bypass the native protocols
+    #[cfg(feature = "smoltcp")]
+    let Some(packet) = smoltcp_backend::divert(iface, packet) else {
+        return;
+    };
+
+    let mut packet = packet;
+    let header = packet.data();
+    if header.len() < ETH_HEADER_LEN {
+        RX_DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let mut src = MacAddr::ZERO;
+    src.0.copy_from_slice(&header[6..12]);
+    let ethertype = u16::from_be_bytes([header[12], header[13]]);
+    packet.pull(ETH_HEADER_LEN);
+
+    let protocol = interrupts::without_interrupts(|| {
+        PROTOCOLS
+            .lock()
+            .iter()
+            .find(|p| p.ethertype == ethertype)
+            .copied()
+    });
+
+    match protocol {
+        Some(protocol) => (protocol.receive)(iface, src, packet),
+        None => {
+            RX_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Process every queued frame
+pub fn poll() {
+    if POLLING.swap(true, Ordering::Acquire) {
+        return; // Already running (e.g. interrupted by the timer)
+    }
+
+    while let Some((iface, packet)) = interrupts::without_interrupts(|| RX_QUEUE.lock().pop_front())
+    {
+        process(iface, packet);
+    }
+
+    #[cfg(feature = "smoltcp")]
+    smoltcp_backend::poll();
+
+    POLLING.store(false, Ordering::Release);
+}
+
+// Timer interrupt entry: skip if the queue is locked by the interrupted code
+pub fn poll_from_interrupt(ticks: u64) {
+    if ticks.is_multiple_of(arp::AGE_INTERVAL_TICKS) {
+        arp::age_from_interrupt(ticks);
+    }
+
+    let pending = match RX_QUEUE.try_lock() {
+        Some(queue) => !queue.is_empty(),
+        None => false,
+    };
+    // smoltcp also needs polling for its own timers (retransmits, ARP)
+    let pending = pending || cfg!(feature = "smoltcp");
+    if pending {
+        poll();
+    }
+}
+
+// (received, transmitted, dropped) frame counters
+pub fn stats() -> (u64, u64, u64) {
+    (
+        RX_PACKETS.load(Ordering::Relaxed),
+        TX_PACKETS.load(Ordering::Relaxed),
+        RX_DROPPED.load(Ordering::Relaxed),
+    )
+}
+
+// /proc/net_dev: interfaces with their address, then the EtherType protocols and
+// the frame and buffer counters
+fn read_net_dev() -> String {
+    let mut out = String::new();
+    for (iface, device) in devices().iter().enumerate() {
+        let _ = write!(
+            out,
+            "{}: mac {} mtu {}",
+            device.name(),
+            device.mac(),
+            device.mtu()
+        );
+        if let Some(config) = ipv4_config(iface) {
+            let _ = write!(out, " inet {} netmask {}", config.addr, config.netmask);
+        }
+        out.push('\n');
+    }
+    let protocols = interrupts::without_interrupts(|| {
+        PROTOCOLS
+            .lock()
+            .iter()
+            .map(|p| alloc::format!(" {} ({:#06x})", p.name, p.ethertype))
+            .collect::<String>()
+    });
+    let _ = writeln!(out, "protocols:{}", protocols);
+    let (rx, tx, dropped) = stats();
+    let (held, pooled) = buffer::pool_stats();
+    let _ = writeln!(out, "frames: rx {} tx {} dropped {}", rx, tx, dropped);
+    let _ = writeln!(out, "buffers: {} frames held, {} free", held, pooled);
+    out
+}
+
+static NET_DEV_ENTRY: ProcEntry = ProcEntry {
+    name: "net_dev",
+    read: read_net_dev,
+    write: None,
+};
+
+pub fn init(hhdm_offset: u64) {
+    procfs::register(&NET_DEV_ENTRY);
+    buffer::init(hhdm_offset);
+    arp::init();
+    ipv4::init();
+    loopback::init();
+    trace::init();
+    log::info!("Core initialized");
+}
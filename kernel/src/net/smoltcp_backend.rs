@@ -0,0 +1,295 @@
+// smoltcp Backend (feature "smoltcp")
+// Hands a registered interface to smoltcp's TCP/IP stack instead of the native
+// ARP/IPv4/UDP layers. The NIC driver is unchanged: its frames are diverted from the
+// RX queue into the interface's smoltcp device, and smoltcp transmits through
+// `NetDevice::transmit`. Interfaces with a native IPv4 configuration (`lo`) are
+// refused. `smoltcp attach <dev> <addr>/<prefix> [gateway]` in the shell attaches
+// one, `smoltcp send <dev> <addr>:<port> <text>` sends a UDP datagram through it.
+//
+// Sockets are smoltcp sockets owned by the interface's socket set, accessed through
+// `add_socket` / `with_socket`. The stack is polled by the RX bottom half.
+
+use super::{Ipv4Addr, Ipv4Config, NetDevice, NetError, PacketBuffer};
+use crate::interrupts::TICKS;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+use smoltcp::iface::{Config, Context, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{self, DeviceCapabilities, Medium};
+use smoltcp::socket::{AnySocket, udp};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, HardwareAddress, IpAddress, IpCidr};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Frames waiting for smoltcp per attached interface, beyond this they are dropped
+const RX_QUEUE_LEN: usize = 64;
+
+// smoltcp's view of a NetDevice
+struct Adapter {
+    iface: usize,
+    device: Arc<dyn NetDevice>,
+}
+
+struct RxToken(PacketBuffer);
+
+struct TxToken(Arc<dyn NetDevice>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(self.0.data())
+    }
+}
+
+impl phy::TxToken for TxToken {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        // smoltcp cannot be told that no buffer is available: build the frame in a
+        // scratch buffer and drop it, as if it was lost on the wire
+        let Some(mut packet) = PacketBuffer::alloc() else {
+            return f(&mut vec![0; len]);
+        };
+        let result = f(packet.put(len));
+        let _ = self.0.transmit(packet);
+        result
+    }
+}
+
+impl phy::Device for Adapter {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(RxToken, TxToken)> {
+        let packet =
+            interrupts::without_interrupts(|| RX_QUEUES.lock().get_mut(&self.iface)?.pop_front())?;
+        Some((RxToken(packet), TxToken(self.device.clone())))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<TxToken> {
+        Some(TxToken(self.device.clone()))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ethernet;
+        // smoltcp's Ethernet MTU includes the Ethernet header
+        caps.max_transmission_unit = self.device.mtu() + super::ETH_HEADER_LEN;
+        caps
+    }
+}
+
+struct Stack {
+    adapter: Adapter,
+    interface: Interface,
+    sockets: SocketSet<'static>,
+}
+
+// Attached interfaces, by interface index
+static STACKS: Mutex<BTreeMap<usize, Stack>> = Mutex::new(BTreeMap::new());
+
+// Diverted frames, separate from STACKS so the RX path never waits on a poll
+static RX_QUEUES: Mutex<BTreeMap<usize, VecDeque<PacketBuffer>>> = Mutex::new(BTreeMap::new());
+
+fn now() -> Instant {
+    // Timer ticks are milliseconds
+    Instant::from_millis(TICKS.load(Ordering::Relaxed) as i64)
+}
+
+// Put an interface under smoltcp with a static IPv4 configuration
+pub fn attach(iface: usize, config: Ipv4Config) -> Result<(), NetError> {
+    let device = super::device(iface).ok_or(NetError::NoDevice)?;
+    if super::ipv4_config(iface).is_some() {
+        return Err(NetError::AddrInUse);
+    }
+    let mut adapter = Adapter {
+        iface,
+        device: device.clone(),
+    };
+
+    let mut iface_config = Config::new(HardwareAddress::Ethernet(EthernetAddress(device.mac().0)));
+    iface_config.random_seed = TICKS.load(Ordering::Relaxed);
+
+    let mut interface = Interface::new(iface_config, &mut adapter, now());
+    let [a, b, c, d] = config.addr.0;
+    let prefix = config.netmask.to_u32().count_ones() as u8;
+    interface.update_ip_addrs(|addrs| {
+        let _ = addrs.push(IpCidr::new(IpAddress::v4(a, b, c, d), prefix));
+    });
+    if let Some(gateway) = config.gateway {
+        let [a, b, c, d] = gateway.0;
+        interface
+            .routes_mut()
+            .add_default_ipv4_route(core::net::Ipv4Addr::new(a, b, c, d))
+            .map_err(|_| NetError::InvalidArgument)?;
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        if stacks.contains_key(&iface) {
+            return Err(NetError::AddrInUse);
+        }
+        stacks.insert(
+            iface,
+            Stack {
+                adapter,
+                interface,
+                sockets: SocketSet::new(Vec::new()),
+            },
+        );
+        RX_QUEUES.lock().insert(iface, VecDeque::new());
+        Ok(())
+    })?;
+
+    log::info!(
+        "{} attached to smoltcp: {}/{}",
+        device.name(),
+        config.addr,
+        prefix
+    );
+    Ok(())
+}
+
+// Called by the RX path: takes frames of attached interfaces, returns the others
+pub fn divert(iface: usize, packet: PacketBuffer) -> Option<PacketBuffer> {
+    interrupts::without_interrupts(|| {
+        let mut queues = RX_QUEUES.lock();
+        let Some(queue) = queues.get_mut(&iface) else {
+            return Some(packet);
+        };
+        if queue.len() < RX_QUEUE_LEN {
+            queue.push_back(packet);
+        }
+        None
+    })
+}
+
+pub fn add_socket<T: AnySocket<'static>>(
+    iface: usize,
+    socket: T,
+) -> Result<SocketHandle, NetError> {
+    interrupts::without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        let stack = stacks.get_mut(&iface).ok_or(NetError::NoDevice)?;
+        Ok(stack.sockets.add(socket))
+    })
+}
+
+pub fn remove_socket(iface: usize, handle: SocketHandle) {
+    interrupts::without_interrupts(|| {
+        if let Some(stack) = STACKS.lock().get_mut(&iface) {
+            stack.sockets.remove(handle);
+        }
+    });
+}
+
+// Run `f` on a socket (the context is needed e.g. by `tcp::Socket::connect`),
+// then poll so its effects go out immediately
+pub fn with_socket<T: AnySocket<'static>, R>(
+    iface: usize,
+    handle: SocketHandle,
+    f: impl FnOnce(&mut T, &mut Context) -> R,
+) -> Result<R, NetError> {
+    interrupts::without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        let stack = stacks.get_mut(&iface).ok_or(NetError::NoDevice)?;
+        let result = f(
+            stack.sockets.get_mut::<T>(handle),
+            stack.interface.context(),
+        );
+        stack
+            .interface
+            .poll(now(), &mut stack.adapter, &mut stack.sockets);
+        Ok(result)
+    })
+}
+
+// How long `send_udp` waits for ARP to resolve the next hop
+const SEND_TIMEOUT_MS: u64 = 1000;
+
+// Send one UDP datagram from an ephemeral port, waiting until it is out
+pub fn send_udp(iface: usize, to: (Ipv4Addr, u16), data: &[u8]) -> Result<(), NetError> {
+    let buffer =
+        || udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 1], vec![0; data.len()]);
+    let handle = add_socket(iface, udp::Socket::new(buffer(), buffer()))?;
+    let [a, b, c, d] = to.0.0;
+    let endpoint = (IpAddress::v4(a, b, c, d), to.1);
+    let port = 49152 + (TICKS.load(Ordering::Relaxed) % 16384) as u16;
+    let sent = with_socket(iface, handle, |socket: &mut udp::Socket, _| {
+        socket.bind(port).map_err(|_| NetError::AddrInUse)?;
+        socket
+            .send_slice(data, endpoint)
+            .map_err(|_| NetError::TooLarge)
+    })
+    .and_then(|result| result);
+
+    let deadline = TICKS.load(Ordering::Relaxed) + SEND_TIMEOUT_MS;
+    let result = sent.and_then(|()| {
+        loop {
+            let queued = with_socket(iface, handle, |socket: &mut udp::Socket, _| {
+                socket.send_queue()
+            })?;
+            if queued == 0 {
+                break Ok(());
+            }
+            if TICKS.load(Ordering::Relaxed) >= deadline {
+                break Err(NetError::Unreachable);
+            }
+            x86_64::instructions::hlt();
+        }
+    });
+    remove_socket(iface, handle);
+    result
+}
+
+// Process received frames and timers of every attached interface
+// Skipped if the interrupted code holds the stacks
+pub fn poll() {
+    let Some(mut stacks) = STACKS.try_lock() else {
+        return;
+    };
+    let now = now();
+    for stack in stacks.values_mut() {
+        stack
+            .interface
+            .poll(now, &mut stack.adapter, &mut stack.sockets);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::loopback::Loopback;
+
+    #[test_case]
+    fn attached_interface_takes_its_frames() {
+        let iface = super::super::register_device(Arc::new(Loopback::new()));
+        let lo = super::super::loopback_iface().unwrap();
+        let config = Ipv4Config {
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            gateway: None,
+        };
+        assert_eq!(attach(iface, config), Ok(()));
+        assert_eq!(attach(iface, config), Err(NetError::AddrInUse));
+        // The native stack keeps interfaces it has an address for
+        assert_eq!(attach(lo, config), Err(NetError::AddrInUse));
+
+        assert!(divert(iface, PacketBuffer::alloc().unwrap()).is_none());
+        assert!(divert(lo, PacketBuffer::alloc().unwrap()).is_some());
+
+        let buffer = || udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 1], vec![0; 64]);
+        let handle = add_socket(iface, udp::Socket::new(buffer(), buffer())).unwrap();
+        let bound = with_socket(iface, handle, |socket: &mut udp::Socket, _| {
+            socket.bind(7).is_ok()
+        });
+        assert_eq!(bound, Ok(true));
+        remove_socket(iface, handle);
+    }
+}
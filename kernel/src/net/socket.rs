@@ -0,0 +1,115 @@
+// Socket Files
+// Wraps a socket as an open file so it lives in the file descriptor table:
+// read/write work on connected sockets, the socket syscalls reach the socket
+// itself through `File::as_socket`.
+//
+// Blocking receives idle with interrupts enabled between polls (the syscall entry
+// clears IF) so the timer keeps running the RX bottom half that fills the queue.
+
+use super::udp::{Datagram, UdpSocket};
+use super::{NetError, SocketAddr};
+use crate::fs::{DirEntry, File, FileType, FsError, FsResult, Metadata, SeekFrom};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+impl From<NetError> for FsError {
+    fn from(err: NetError) -> Self {
+        match err {
+            NetError::WouldBlock => FsError::WouldBlock,
+            NetError::InvalidArgument => FsError::InvalidArgument,
+            _ => FsError::Io,
+        }
+    }
+}
+
+pub struct SocketFile {
+    socket: Arc<UdpSocket>,
+    nonblocking: AtomicBool,
+}
+
+impl SocketFile {
+    pub fn new_udp(nonblocking: bool) -> Self {
+        Self {
+            socket: UdpSocket::new(),
+            nonblocking: AtomicBool::new(nonblocking),
+        }
+    }
+
+    pub fn udp(&self) -> &Arc<UdpSocket> {
+        &self.socket
+    }
+
+    // Receive one datagram, waiting for it unless non-blocking
+    pub fn recv(&self, dont_wait: bool) -> Result<Datagram, NetError> {
+        crate::idle::wait_for(|| {
+            super::poll();
+            if let Some(datagram) = self.socket.try_recv() {
+                return Some(Ok(datagram));
+            }
+            if dont_wait || self.nonblocking.load(Ordering::Relaxed) {
+                return Some(Err(NetError::WouldBlock));
+            }
+            None
+        })
+    }
+
+    pub fn send(&self, data: &[u8], dst: Option<SocketAddr>) -> Result<usize, NetError> {
+        self.socket.send_to(data, dst)
+    }
+}
+
+impl File for SocketFile {
+    fn read(&self, buf: &mut [u8]) -> FsResult<usize> {
+        // Datagram semantics: the rest of a datagram that does not fit is discarded
+        let datagram = self.recv(false)?;
+        let count = buf.len().min(datagram.data.len());
+        buf[..count].copy_from_slice(&datagram.data[..count]);
+        Ok(count)
+    }
+
+    #[cfg(feature = "userspace")]
+    fn write(&self, buf: &[u8]) -> FsResult<usize> {
+        Ok(self.send(buf, None)?)
+    }
+
+    fn seek(&self, _pos: SeekFrom) -> FsResult<u64> {
+        Err(FsError::InvalidArgument)
+    }
+
+    #[cfg(feature = "userspace")]
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            ino: 0,
+            file_type: FileType::Socket,
+            size: 0,
+            mode: 0o777,
+            nlink: 1,
+        }
+    }
+
+    #[cfg(feature = "userspace")]
+    fn read_dir(&self, _max: usize) -> FsResult<Vec<DirEntry>> {
+        Err(FsError::NotADirectory)
+    }
+
+    #[cfg(feature = "userspace")]
+    fn truncate(&self, _size: u64) -> FsResult<()> {
+        Err(FsError::InvalidArgument)
+    }
+
+    fn as_socket(&self) -> Option<&SocketFile> {
+        Some(self)
+    }
+
+    // Sends never wait; drain the RX path first, as recv does
+    #[cfg(feature = "userspace")]
+    fn poll(&self) -> u16 {
+        super::poll();
+        if self.socket.has_datagram() {
+            crate::fs::POLLIN | crate::fs::POLLOUT
+        } else {
+            crate::fs::POLLOUT
+        }
+    }
+}
@@ -0,0 +1,195 @@
+// Packet Tracing
+// Decodes the headers of every frame going through the net core (Ethernet, ARP,
+// IPv4, UDP) into one line per frame, optionally followed by a hex dump, and sends
+// it to the serial log and/or a ring buffer readable at /proc/net_trace.
+//
+// Controlled by writing words to /proc/net_trace (or calling `set_flags`):
+//   off | serial | ring | payload   e.g. `echo "ring payload" > /proc/net_trace`
+//   clear                           empties the ring buffer
+// Nothing is formatted while tracing is off.
+
+use super::{ETH_HEADER_LEN, ETHERTYPE_ARP, ETHERTYPE_IPV4, Ipv4Addr, MacAddr};
+use crate::fs::procfs::{self, ProcEntry};
+use crate::fs::{FsError, FsResult};
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU8, Ordering};
+use shared::serial_println;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const TRACE_SERIAL: u8 = 1 << 0;
+pub const TRACE_RING: u8 = 1 << 1;
+pub const TRACE_PAYLOAD: u8 = 1 << 2;
+
+// Lines kept in the ring buffer, the oldest are dropped first
+const RING_LINES: usize = 256;
+
+// Bytes of each frame included in hex dumps
+const MAX_DUMP: usize = 256;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+static FLAGS: AtomicU8 = AtomicU8::new(0);
+static RING: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+static PROC_ENTRY: ProcEntry = ProcEntry {
+    name: "net_trace",
+    read: read_ring,
+    write: Some(write_control),
+};
+
+pub fn init() {
+    procfs::register(&PROC_ENTRY);
+}
+
+pub fn set_flags(flags: u8) {
+    FLAGS.store(flags, Ordering::Relaxed);
+}
+
+pub fn flags() -> u8 {
+    FLAGS.load(Ordering::Relaxed)
+}
+
+// Hook called by the net core with the complete Ethernet frame
+pub fn frame(iface: usize, direction: Direction, frame: &[u8]) {
+    let flags = flags();
+    if flags & (TRACE_SERIAL | TRACE_RING) == 0 {
+        return;
+    }
+
+    let mut line = describe(iface, direction, frame);
+    if flags & TRACE_PAYLOAD != 0 {
+        hex_dump(&mut line, &frame[..frame.len().min(MAX_DUMP)]);
+    }
+
+    if flags & TRACE_SERIAL != 0 {
+        serial_println!("{}", line);
+    }
+    if flags & TRACE_RING != 0 {
+        interrupts::without_interrupts(|| {
+            let mut ring = RING.lock();
+            if ring.len() >= RING_LINES {
+                ring.pop_front();
+            }
+            ring.push_back(line);
+        });
+    }
+}
+
+fn mac(bytes: &[u8]) -> MacAddr {
+    let mut mac = MacAddr::ZERO;
+    mac.0.copy_from_slice(&bytes[..6]);
+    mac
+}
+
+fn ip(bytes: &[u8]) -> Ipv4Addr {
+    Ipv4Addr([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn describe(iface: usize, direction: Direction, frame: &[u8]) -> String {
+    let name = super::device(iface).map_or(String::from("?"), |d| String::from(d.name()));
+    let dir = match direction {
+        Direction::Rx => "RX",
+        Direction::Tx => "TX",
+    };
+    let mut line = format!("[NET] {} {} len {}", name, dir, frame.len());
+
+    if frame.len() < ETH_HEADER_LEN {
+        line.push_str(" truncated");
+        return line;
+    }
+    let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+    let _ = write!(line, " {} > {}", mac(&frame[6..]), mac(&frame[0..]));
+
+    let payload = &frame[ETH_HEADER_LEN..];
+    match ethertype {
+        ETHERTYPE_ARP if payload.len() >= 28 => {
+            let oper = u16::from_be_bytes([payload[6], payload[7]]);
+            let (sender, target) = (ip(&payload[14..]), ip(&payload[24..]));
+            let _ = match oper {
+                1 => write!(line, " ARP who-has {} tell {}", target, sender),
+                2 => write!(line, " ARP {} is-at {}", sender, mac(&payload[8..])),
+                _ => write!(line, " ARP oper {}", oper),
+            };
+        }
+        ETHERTYPE_IPV4 if payload.len() >= 20 => {
+            let header_len = (payload[0] & 0x0F) as usize * 4;
+            let protocol = payload[9];
+            let _ = write!(
+                line,
+                " IPv4 {} > {} ttl {}",
+                ip(&payload[12..]),
+                ip(&payload[16..]),
+                payload[8]
+            );
+            let transport = payload.get(header_len..).unwrap_or(&[]);
+            if protocol == super::ipv4::PROTO_UDP && transport.len() >= 8 {
+                let _ = write!(
+                    line,
+                    " UDP {} > {} len {}",
+                    u16::from_be_bytes([transport[0], transport[1]]),
+                    u16::from_be_bytes([transport[2], transport[3]]),
+                    u16::from_be_bytes([transport[4], transport[5]])
+                );
+            } else {
+                let _ = write!(line, " proto {}", protocol);
+            }
+        }
+        _ => {
+            let _ = write!(line, " type {:#06x}", ethertype);
+        }
+    }
+    line
+}
+
+// Append `data` as lines of 16 hex bytes
+fn hex_dump(line: &mut String, data: &[u8]) {
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let _ = write!(line, "\n  {:04x}:", i * 16);
+        for byte in chunk {
+            let _ = write!(line, " {:02x}", byte);
+        }
+    }
+}
+
+fn read_ring() -> String {
+    interrupts::without_interrupts(|| {
+        let ring = RING.lock();
+        let mut out = String::new();
+        for line in ring.iter() {
+            out.push_str(line);
+            out.push('\n');
+        }
+        out
+    })
+}
+
+fn write_control(command: &str) -> FsResult<()> {
+    let mut flags = 0;
+    for word in command.split_whitespace() {
+        flags |= match word {
+            "off" => 0,
+            "serial" => TRACE_SERIAL,
+            "ring" => TRACE_RING,
+            "payload" => TRACE_PAYLOAD,
+            "clear" => {
+                interrupts::without_interrupts(|| RING.lock().clear());
+                continue;
+            }
+            _ => return Err(FsError::InvalidArgument),
+        };
+    }
+    // "clear" alone keeps the current mode
+    if command.split_whitespace().all(|w| w == "clear") {
+        return Ok(());
+    }
+    set_flags(flags);
+    Ok(())
+}
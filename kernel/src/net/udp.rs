@@ -0,0 +1,240 @@
+// UDP (RFC 768)
+// Sockets bind a local port (explicitly or an ephemeral one on first use) and
+// receive datagrams into a bounded per-socket queue. Checksums are generated on
+// transmit and verified on receive (a zero checksum means "none").
+//
+// Header (8 bytes): 0 source port  2 destination port  4 length  6 checksum
+
+use super::ipv4::{self, PROTO_UDP, Route};
+use super::{Ipv4Addr, NetError, PacketBuffer, SocketAddr};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const HEADER_LEN: usize = 8;
+
+// Datagrams queued per socket before new ones are dropped
+const MAX_QUEUED: usize = 64;
+
+// Ephemeral port range (IANA)
+const EPHEMERAL_START: u16 = 49152;
+
+pub struct Datagram {
+    pub src: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+struct Endpoints {
+    local: Option<SocketAddr>,
+    remote: Option<SocketAddr>,
+}
+
+pub struct UdpSocket {
+    endpoints: Mutex<Endpoints>,
+    queue: Mutex<VecDeque<Datagram>>,
+}
+
+// Bound sockets by local port
+static PORTS: Mutex<BTreeMap<u16, Weak<UdpSocket>>> = Mutex::new(BTreeMap::new());
+
+impl UdpSocket {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            endpoints: Mutex::new(Endpoints {
+                local: None,
+                remote: None,
+            }),
+            queue: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        interrupts::without_interrupts(|| self.endpoints.lock().local)
+    }
+
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        interrupts::without_interrupts(|| self.endpoints.lock().remote)
+    }
+
+    // Bind to a local address, port 0 picks an ephemeral port
+    pub fn bind(self: &Arc<Self>, addr: SocketAddr) -> Result<(), NetError> {
+        if addr.ip != Ipv4Addr::UNSPECIFIED && super::iface_for_addr(addr.ip).is_none() {
+            return Err(NetError::AddrNotAvailable);
+        }
+
+        interrupts::without_interrupts(|| {
+            let mut endpoints = self.endpoints.lock();
+            if endpoints.local.is_some() {
+                return Err(NetError::InvalidArgument);
+            }
+
+            let mut ports = PORTS.lock();
+            ports.retain(|_, socket| socket.strong_count() > 0);
+
+            let port = if addr.port != 0 {
+                if ports.contains_key(&addr.port) {
+                    return Err(NetError::AddrInUse);
+                }
+                addr.port
+            } else {
+                (EPHEMERAL_START..=u16::MAX)
+                    .find(|port| !ports.contains_key(port))
+                    .ok_or(NetError::AddrInUse)?
+            };
+
+            ports.insert(port, Arc::downgrade(self));
+            endpoints.local = Some(SocketAddr { ip: addr.ip, port });
+            Ok(())
+        })
+    }
+
+    fn ensure_bound(self: &Arc<Self>) -> Result<SocketAddr, NetError> {
+        if let Some(local) = self.local_addr() {
+            return Ok(local);
+        }
+        self.bind(SocketAddr {
+            ip: Ipv4Addr::UNSPECIFIED,
+            port: 0,
+        })?;
+        self.local_addr().ok_or(NetError::Io)
+    }
+
+    // Set the default destination and only accept datagrams from it
+    pub fn connect(self: &Arc<Self>, remote: SocketAddr) -> Result<(), NetError> {
+        self.ensure_bound()?;
+        interrupts::without_interrupts(|| self.endpoints.lock().remote = Some(remote));
+        Ok(())
+    }
+
+    // Send one datagram to `dst` (or the connected peer)
+    pub fn send_to(
+        self: &Arc<Self>,
+        data: &[u8],
+        dst: Option<SocketAddr>,
+    ) -> Result<usize, NetError> {
+        let dst = dst
+            .or_else(|| self.remote_addr())
+            .ok_or(NetError::NotConnected)?;
+        let local = self.ensure_bound()?;
+        let route = ipv4::route(dst.ip)?;
+
+        let len = HEADER_LEN + data.len();
+        let mtu = super::device(route.iface).ok_or(NetError::NoDevice)?.mtu();
+        if len + ipv4::HEADER_LEN > mtu {
+            return Err(NetError::TooLarge);
+        }
+
+        let src = if local.ip == Ipv4Addr::UNSPECIFIED {
+            route.src
+        } else {
+            local.ip
+        };
+
+        let mut packet = PacketBuffer::alloc().ok_or(NetError::NoBuffers)?;
+        let udp = packet.put(len);
+        udp[0..2].copy_from_slice(&local.port.to_be_bytes());
+        udp[2..4].copy_from_slice(&dst.port.to_be_bytes());
+        udp[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+        udp[6..8].fill(0);
+        udp[8..].copy_from_slice(data);
+
+        let sum = ipv4::checksum(udp, ipv4::pseudo_header_sum(src, dst.ip, PROTO_UDP, len));
+        // A computed zero is sent as all ones, zero means "no checksum"
+        let sum = if sum == 0 { 0xFFFF } else { sum };
+        udp[6..8].copy_from_slice(&sum.to_be_bytes());
+
+        ipv4::send(Route { src, ..route }, dst.ip, PROTO_UDP, packet)?;
+        Ok(data.len())
+    }
+
+    // Whether a datagram is waiting, for poll
+    #[cfg(feature = "userspace")]
+    pub fn has_datagram(&self) -> bool {
+        interrupts::without_interrupts(|| !self.queue.lock().is_empty())
+    }
+
+    // Pop the oldest queued datagram
+    pub fn try_recv(&self) -> Option<Datagram> {
+        interrupts::without_interrupts(|| self.queue.lock().pop_front())
+    }
+
+    fn deliver(&self, datagram: Datagram) {
+        interrupts::without_interrupts(|| {
+            let mut queue = self.queue.lock();
+            if queue.len() < MAX_QUEUED {
+                queue.push_back(datagram);
+            }
+        });
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        if let Some(local) = self.endpoints.get_mut().local {
+            interrupts::without_interrupts(|| {
+                let mut ports = PORTS.lock();
+                if ports
+                    .get(&local.port)
+                    .is_some_and(|s| s.strong_count() == 0)
+                {
+                    ports.remove(&local.port);
+                }
+            });
+        }
+    }
+}
+
+// Called by the IPv4 layer with the IP header removed
+pub fn receive(src_ip: Ipv4Addr, dst_ip: Ipv4Addr, packet: PacketBuffer) {
+    let udp = packet.data();
+    if udp.len() < HEADER_LEN {
+        return;
+    }
+
+    let src_port = u16::from_be_bytes([udp[0], udp[1]]);
+    let dst_port = u16::from_be_bytes([udp[2], udp[3]]);
+    let len = u16::from_be_bytes([udp[4], udp[5]]) as usize;
+    let sum = u16::from_be_bytes([udp[6], udp[7]]);
+    if len < HEADER_LEN || len > udp.len() {
+        return;
+    }
+    let udp = &udp[..len];
+
+    if sum != 0 && ipv4::checksum(udp, ipv4::pseudo_header_sum(src_ip, dst_ip, PROTO_UDP, len)) != 0
+    {
+        return;
+    }
+
+    let socket = interrupts::without_interrupts(|| {
+        PORTS
+            .lock()
+            .get(&dst_port)
+            .and_then(|socket| socket.upgrade())
+    });
+    let Some(socket) = socket else {
+        return;
+    };
+
+    let src = SocketAddr {
+        ip: src_ip,
+        port: src_port,
+    };
+    let (local, remote) = interrupts::without_interrupts(|| {
+        let endpoints = socket.endpoints.lock();
+        (endpoints.local, endpoints.remote)
+    });
+
+    // Bound to a specific address, or connected to a specific peer
+    if local.is_some_and(|l| l.ip != Ipv4Addr::UNSPECIFIED && l.ip != dst_ip)
+        || remote.is_some_and(|r| r != src)
+    {
+        return;
+    }
+
+    socket.deliver(Datagram {
+        src,
+        data: Vec::from(&udp[HEADER_LEN..]),
+    });
+}
@@ -0,0 +1,157 @@
+// PCI Bus Module
+// Enumerates PCI devices through the legacy configuration mechanism (I/O ports 0xCF8/0xCFC)
+// and registers every function found on the `pci` bus of the device model.
+
+use crate::device::{self, BusType, DeviceIdent};
+use alloc::format;
+use x86_64::instructions::port::Port;
+
+// Configuration space access ports
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+// Command register (low half of offset 0x04) and its Bus Master Enable bit
+const COMMAND_REGISTER: u8 = 0x04;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+// Vendor ID of an empty slot
+const INVALID_VENDOR: u16 = 0xFFFF;
+
+// Red Hat / Qumranet vendor ID used by all virtio devices
+pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+
+// Read a 32-bit register from PCI configuration space
+pub fn config_read_u32(bus: u8, slot: u8, function: u8, offset: u8) -> u32 {
+    let address = 0x8000_0000u32
+        | (bus as u32) << 16
+        | (slot as u32) << 11
+        | (function as u32) << 8
+        | (offset as u32 & 0xFC);
+
+    let mut address_port = Port::<u32>::new(CONFIG_ADDRESS);
+    let mut data_port = Port::<u32>::new(CONFIG_DATA);
+
+    unsafe {
+        address_port.write(address);
+        data_port.read()
+    }
+}
+
+// Write a 32-bit register of PCI configuration space
+pub fn config_write_u32(bus: u8, slot: u8, function: u8, offset: u8, value: u32) {
+    let address = 0x8000_0000u32
+        | (bus as u32) << 16
+        | (slot as u32) << 11
+        | (function as u32) << 8
+        | (offset as u32 & 0xFC);
+
+    let mut address_port = Port::<u32>::new(CONFIG_ADDRESS);
+    let mut data_port = Port::<u32>::new(CONFIG_DATA);
+
+    unsafe {
+        address_port.write(address);
+        data_port.write(value);
+    }
+}
+
+// Stop a function from starting DMA: clear Bus Master Enable in its command
+// register (the status half of the register is write-1-to-clear, so it is
+// written back as zero)
+pub fn disable_bus_master(bus: u8, slot: u8, function: u8) {
+    let command = config_read_u32(bus, slot, function, COMMAND_REGISTER) as u16;
+    config_write_u32(
+        bus,
+        slot,
+        function,
+        COMMAND_REGISTER,
+        (command & !COMMAND_BUS_MASTER) as u32,
+    );
+}
+
+// Size of the standard configuration header, in dwords
+pub const HEADER_DWORDS: usize = 16;
+
+// The configuration header of a function, for `restore_header`
+pub fn save_header(bus: u8, slot: u8, function: u8) -> [u32; HEADER_DWORDS] {
+    core::array::from_fn(|i| config_read_u32(bus, slot, function, (i * 4) as u8))
+}
+
+// Write a saved header back after the function was reset (suspend-to-RAM):
+// BARs and interrupt line first, the command register last so that decoding and
+// bus mastering come back with valid BARs. IDs and status are left alone.
+pub fn restore_header(bus: u8, slot: u8, function: u8, header: &[u32; HEADER_DWORDS]) {
+    for (i, &value) in header.iter().enumerate().skip(3) {
+        config_write_u32(bus, slot, function, (i * 4) as u8, value);
+    }
+    config_write_u32(
+        bus,
+        slot,
+        function,
+        COMMAND_REGISTER,
+        header[COMMAND_REGISTER as usize / 4] & 0xFFFF,
+    );
+}
+
+// Map a virtio PCI device ID to the virtio device type
+// Transitional devices use 0x1000-0x103F, modern devices use 0x1040 + type
+pub fn virtio_device_type(device_id: u16) -> Option<u16> {
+    match device_id {
+        0x1000 => Some(1), // Network card
+        0x1001 => Some(2), // Block device
+        0x1002 => Some(5), // Memory balloon
+        0x1003 => Some(3), // Console
+        0x1004 => Some(8), // SCSI host
+        0x1005 => Some(4), // Entropy source
+        0x1009 => Some(9), // 9P transport
+        0x1040..=0x107F => Some(device_id - 0x1040),
+        _ => None,
+    }
+}
+
+// Scan all buses/slots/functions and register each present function
+pub fn enumerate() {
+    for bus in 0..=255u8 {
+        for slot in 0..32u8 {
+            let id = config_read_u32(bus, slot, 0, 0x00);
+            if id as u16 == INVALID_VENDOR {
+                continue;
+            }
+
+            // Bit 7 of the header type marks a multi-function device
+            let header_type = (config_read_u32(bus, slot, 0, 0x0C) >> 16) as u8;
+            let functions = if header_type & 0x80 != 0 { 8 } else { 1 };
+
+            for function in 0..functions {
+                register_function(bus, slot, function);
+            }
+        }
+    }
+}
+
+fn register_function(bus: u8, slot: u8, function: u8) {
+    let id = config_read_u32(bus, slot, function, 0x00);
+    let vendor_id = id as u16;
+    if vendor_id == INVALID_VENDOR {
+        return;
+    }
+    let device_id = (id >> 16) as u16;
+
+    let class_reg = config_read_u32(bus, slot, function, 0x08);
+    let class = (class_reg >> 24) as u8;
+    let subclass = (class_reg >> 16) as u8;
+
+    device::register_device(
+        format!("pci{:02x}:{:02x}.{}", bus, slot, function),
+        BusType::Pci,
+        DeviceIdent::Pci {
+            bus,
+            slot,
+            function,
+            vendor_id,
+            device_id,
+            class,
+            subclass,
+        },
+        None,
+    );
+}
@@ -0,0 +1,353 @@
+// Performance Counters
+// Counts hardware events (instructions retired, core cycles, last-level cache
+// references and misses, mispredicted branches) with the architectural
+// performance monitoring unit of Intel CPUs, described by CPUID leaf 0xA.
+//
+// Instructions and cycles use the fixed-function counters when the PMU has them
+// (version 2 and later), everything else a general purpose counter programmed
+// through its IA32_PERFEVTSELx MSR. Counters run in both rings on the CPU that
+// started the session, and there is one session at a time: `perf <command>` in
+// the shell wraps a shell command in one and prints the counts.
+//
+// CPUs without an Intel-style PMU (AMD, QEMU without KVM) report Unsupported.
+
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, Ordering};
+use shared::once::Once;
+use x86_64::registers::model_specific::Msr;
+
+// MSRs
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_FIXED_CTR0: u32 = 0x309;
+const IA32_FIXED_CTR_CTRL: u32 = 0x38D;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+
+// IA32_PERFEVTSELx bits
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_EN: u64 = 1 << 22;
+
+// IA32_FIXED_CTR_CTRL field of one fixed counter: count in ring 0 and ring 3
+const FIXED_CTRL_OS_USR: u64 = 0b11;
+// IA32_PERF_GLOBAL_CTRL bit of fixed counter 0
+const GLOBAL_CTRL_FIXED_SHIFT: u32 = 32;
+
+const CPUID_ARCH_PERFMON: u32 = 0xA;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Instructions,
+    Cycles,
+    CacheReferences,
+    CacheMisses,
+    BranchMisses,
+}
+
+pub const EVENTS: &[Event] = &[
+    Event::Instructions,
+    Event::Cycles,
+    Event::CacheReferences,
+    Event::CacheMisses,
+    Event::BranchMisses,
+];
+
+impl Event {
+    // The name Linux perf uses
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::Instructions => "instructions",
+            Event::Cycles => "cycles",
+            Event::CacheReferences => "cache-references",
+            Event::CacheMisses => "cache-misses",
+            Event::BranchMisses => "branch-misses",
+        }
+    }
+
+    // Bit of the event in CPUID.0AH:EBX (set if the event is not available)
+    fn cpuid_bit(self) -> u32 {
+        match self {
+            Event::Cycles => 0,
+            Event::Instructions => 1,
+            Event::CacheReferences => 3,
+            Event::CacheMisses => 4,
+            Event::BranchMisses => 6,
+        }
+    }
+
+    // Architectural event select and unit mask
+    fn encoding(self) -> (u8, u8) {
+        match self {
+            Event::Cycles => (0x3C, 0x00),
+            Event::Instructions => (0xC0, 0x00),
+            Event::CacheReferences => (0x2E, 0x4F),
+            Event::CacheMisses => (0x2E, 0x41),
+            Event::BranchMisses => (0xC5, 0x00),
+        }
+    }
+
+    // Fixed-function counter counting the event, if any
+    fn fixed_counter(self) -> Option<u8> {
+        match self {
+            Event::Instructions => Some(0),
+            Event::Cycles => Some(1),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PerfError {
+    // No architectural PMU
+    Unsupported,
+    // The PMU does not count this event
+    EventUnavailable(Event),
+    // More events than counters
+    NoFreeCounter,
+    // Another session is running
+    Busy,
+}
+
+// What CPUID leaf 0xA reports
+#[derive(Debug, Clone, Copy)]
+struct Pmu {
+    version: u8,
+    general: u8,
+    general_width: u8,
+    fixed: u8,
+    fixed_width: u8,
+    // Events described by `unavailable`, and the "not available" bits
+    known_events: u8,
+    unavailable: u32,
+}
+
+impl Pmu {
+    fn counts(&self, event: Event) -> bool {
+        let bit = event.cpuid_bit();
+        bit < self.known_events as u32 && self.unavailable & (1 << bit) == 0
+    }
+}
+
+static PMU: Once<Option<Pmu>> = Once::new();
+
+// One session at a time
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy)]
+enum Counter {
+    Fixed(u8),
+    General(u8),
+}
+
+// Counters running for a set of events, stopped when dropped
+pub struct Session {
+    pmu: Pmu,
+    counters: Vec<(Event, Counter)>,
+}
+
+// ============================================================================
+// 1. DETECTION
+// ============================================================================
+
+fn detect() -> Option<Pmu> {
+    if __cpuid(0).eax < CPUID_ARCH_PERFMON {
+        return None;
+    }
+    let leaf = __cpuid(CPUID_ARCH_PERFMON);
+    let version = leaf.eax as u8;
+    if version == 0 {
+        return None;
+    }
+    // Fixed counters are only enumerated from version 2 on
+    let (fixed, fixed_width) = if version >= 2 {
+        ((leaf.edx & 0x1F) as u8, (leaf.edx >> 5) as u8)
+    } else {
+        (0, 0)
+    };
+    Some(Pmu {
+        version,
+        general: (leaf.eax >> 8) as u8,
+        general_width: (leaf.eax >> 16) as u8,
+        fixed,
+        fixed_width,
+        known_events: (leaf.eax >> 24) as u8,
+        unavailable: leaf.ebx,
+    })
+}
+
+pub fn init() {
+    match PMU.call_once(detect) {
+        Some(pmu) => log::info!(
+            "PMU: architectural v{}, {} x {}-bit general, {} x {}-bit fixed counters",
+            pmu.version,
+            pmu.general,
+            pmu.general_width,
+            pmu.fixed,
+            pmu.fixed_width
+        ),
+        None => log::info!("PMU: no architectural performance counters"),
+    }
+}
+
+fn pmu() -> Result<Pmu, PerfError> {
+    PMU.get().copied().flatten().ok_or(PerfError::Unsupported)
+}
+
+// Assign each event a counter: its fixed counter if there is one, else the next
+// free general purpose counter
+fn allocate(pmu: &Pmu, events: &[Event]) -> Result<Vec<(Event, Counter)>, PerfError> {
+    let mut next_general = 0;
+    events
+        .iter()
+        .map(|&event| {
+            if !pmu.counts(event) {
+                return Err(PerfError::EventUnavailable(event));
+            }
+            let counter = match event.fixed_counter() {
+                Some(index) if index < pmu.fixed => Counter::Fixed(index),
+                _ if next_general < pmu.general => {
+                    next_general += 1;
+                    Counter::General(next_general - 1)
+                }
+                _ => return Err(PerfError::NoFreeCounter),
+            };
+            Ok((event, counter))
+        })
+        .collect()
+}
+
+// The events this CPU can count, as many as fit in one session
+pub fn available() -> Vec<Event> {
+    let Ok(pmu) = pmu() else {
+        return Vec::new();
+    };
+    let mut events = Vec::new();
+    for &event in EVENTS {
+        events.push(event);
+        if allocate(&pmu, &events).is_err() {
+            events.pop();
+        }
+    }
+    events
+}
+
+// ============================================================================
+// 2. SESSIONS
+// ============================================================================
+
+// Zero counters for `events` and start them
+pub fn start(events: &[Event]) -> Result<Session, PerfError> {
+    let pmu = pmu()?;
+    let counters = allocate(&pmu, events)?;
+    if ACTIVE.swap(true, Ordering::Acquire) {
+        return Err(PerfError::Busy);
+    }
+
+    let mut fixed_ctrl = 0;
+    let mut global_ctrl = 0;
+    unsafe {
+        for &(event, counter) in &counters {
+            match counter {
+                Counter::Fixed(index) => {
+                    Msr::new(IA32_FIXED_CTR0 + index as u32).write(0);
+                    fixed_ctrl |= FIXED_CTRL_OS_USR << (index * 4);
+                    global_ctrl |= 1 << (GLOBAL_CTRL_FIXED_SHIFT + index as u32);
+                }
+                Counter::General(index) => {
+                    let (select, umask) = event.encoding();
+                    Msr::new(IA32_PMC0 + index as u32).write(0);
+                    Msr::new(IA32_PERFEVTSEL0 + index as u32).write(
+                        select as u64 | (umask as u64) << 8 | EVTSEL_USR | EVTSEL_OS | EVTSEL_EN,
+                    );
+                    global_ctrl |= 1 << index;
+                }
+            }
+        }
+        if pmu.fixed > 0 {
+            Msr::new(IA32_FIXED_CTR_CTRL).write(fixed_ctrl);
+        }
+        // Version 1 has no global control: EVTSEL_EN alone starts a counter
+        if pmu.version >= 2 {
+            Msr::new(IA32_PERF_GLOBAL_CTRL).write(global_ctrl);
+        }
+    }
+    Ok(Session { pmu, counters })
+}
+
+impl Session {
+    // Counts so far, in the order the events were given
+    pub fn read(&self) -> Vec<(Event, u64)> {
+        self.counters
+            .iter()
+            .map(|&(event, counter)| {
+                let (msr, width) = match counter {
+                    Counter::Fixed(index) => (IA32_FIXED_CTR0 + index as u32, self.pmu.fixed_width),
+                    Counter::General(index) => (IA32_PMC0 + index as u32, self.pmu.general_width),
+                };
+                let value = unsafe { Msr::new(msr).read() };
+                (event, value & mask(width))
+            })
+            .collect()
+    }
+
+    // Stop the counters and return their final counts
+    pub fn stop(self) -> Vec<(Event, u64)> {
+        if self.pmu.version >= 2 {
+            unsafe { Msr::new(IA32_PERF_GLOBAL_CTRL).write(0) };
+        }
+        self.read()
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        unsafe {
+            if self.pmu.version >= 2 {
+                Msr::new(IA32_PERF_GLOBAL_CTRL).write(0);
+            }
+            if self.pmu.fixed > 0 {
+                Msr::new(IA32_FIXED_CTR_CTRL).write(0);
+            }
+            for &(_, counter) in &self.counters {
+                if let Counter::General(index) = counter {
+                    Msr::new(IA32_PERFEVTSEL0 + index as u32).write(0);
+                }
+            }
+        }
+        ACTIVE.store(false, Ordering::Release);
+    }
+}
+
+fn mask(width: u8) -> u64 {
+    if width >= 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn perf_counts_instructions() {
+        let session = match start(&[Event::Instructions]) {
+            Ok(session) => session,
+            // Nothing to count with (e.g. QEMU without KVM)
+            Err(PerfError::Unsupported | PerfError::EventUnavailable(_)) => return,
+            Err(err) => panic!("perf: {:?}", err),
+        };
+        for i in 0..1000u64 {
+            core::hint::black_box(i);
+        }
+        assert!(matches!(
+            start(&[Event::Instructions]),
+            Err(PerfError::Busy)
+        ));
+        let counts = session.stop();
+        assert_eq!(counts[0].0, Event::Instructions);
+        assert!(counts[0].1 >= 1000);
+    }
+}
@@ -1,15 +1,41 @@
 // Import necessary types for paging
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PhysFrame;
 use x86_64::{VirtAddr, structures::paging::OffsetPageTable, structures::paging::PageTable};
 
+// 5-level paging: CR3 holds a PML5 above the PML4 (see shared::paging)
+static FIVE_LEVEL: AtomicBool = AtomicBool::new(false);
+
+// Record the paging depth the bootloader left (BootInfo::paging_levels)
+pub fn init(paging_levels: u64) {
+    FIVE_LEVEL.store(paging_levels == 5, Ordering::Relaxed);
+    if paging_levels == 5 {
+        log::info!("Paging: 5-level, the PML4 sits below a PML5");
+    }
+}
+
+pub fn five_level() -> bool {
+    FIVE_LEVEL.load(Ordering::Relaxed)
+}
+
+// The PML4 in use: the CR3 frame, or the one behind PML5[0] with 5-level paging
+pub fn active_pml4(hhdm_offset: u64) -> PhysFrame {
+    let (root, _) = Cr3::read();
+    if !five_level() {
+        return root;
+    }
+    let pml5 = unsafe { &*((root.start_address().as_u64() + hhdm_offset) as *const PageTable) };
+    pml5[0].frame().expect("PML5 without a PML4")
+}
+
 // Function to initialize the memory mapper
 // This uses "Offset Page Table" (also known as Higher Half Direct Mapping or HHDM)
 // This technique maps all physical memory to a virtual address range starting at 'hhdm_offset'
 pub unsafe fn init_mapper(hhdm_offset: u64) -> OffsetPageTable<'static> {
-    // Import CR3 register (holds the physical address of the level 4 page table)
-    use x86_64::registers::control::Cr3;
-
-    // Read the current level 4 page table frame from CR3
-    let (level_4_table_frame, _) = Cr3::read();
+    // Read the current level 4 page table frame from CR3 (through the PML5 with
+    // 5-level paging)
+    let level_4_table_frame = active_pml4(hhdm_offset);
 
     // Get the physical address of the page table
     let phys = level_4_table_frame.start_address();
@@ -28,3 +54,17 @@ pub unsafe fn init_mapper(hhdm_offset: u64) -> OffsetPageTable<'static> {
     // phys_addr + offset = virt_addr for the entire physical memory
     unsafe { OffsetPageTable::new(l4_table, VirtAddr::new(hhdm_offset)) }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86_64::structures::paging::Translate;
+
+    #[test_case]
+    fn active_pml4_maps_the_kernel() {
+        // Whatever the depth, the table found translates kernel code
+        let mapper = unsafe { init_mapper(crate::elf_loader::get_hhdm_offset()) };
+        let code = VirtAddr::new(active_pml4 as *const () as u64);
+        assert!(mapper.translate_addr(code).is_some());
+    }
+}
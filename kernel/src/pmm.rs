@@ -1,30 +1,41 @@
+// Physical Memory Manager
+// A buddy allocator over a bitmap. The bitmap has a bit per frame (1 = used) and
+// is what the rest of the PMM checks against: double frees, the free count, what
+// the bootloader reserved. Free memory is also kept as blocks of 2^order frames,
+// aligned to their size, on one list per order: an allocation takes a block of
+// the smallest order that fits and splits it, handing the upper halves back to
+// the lists below; a free merges the block with its buddy (the block it was split
+// from) for as long as the buddy is free as a whole.
+//
+// The lists are threaded through the free blocks themselves (next and previous
+// block at the start of the first frame, through the HHDM); a bitmap per order
+// records which blocks head a free block of that order, so finding a free buddy
+// takes no list walk.
+//
+// A last bitmap records the frames the PMM manages: usable RAM, less what the
+// bootloader reserved, the bitmaps and frame 0. Only those can be freed; the
+// used bits alone also cover holes, firmware memory and the kernel.
+
 // Import necessary modules
+use shared::memory::{MemoryKind, MemoryRegion, ReservedRange};
 use spin::Mutex;
 use x86_64::PhysAddr;
 use x86_64::instructions::interrupts;
-use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
 
 // Page size is 4KB
 pub const PAGE_SIZE: u64 = 4096;
 
-// UEFI Memory Descriptor structure
-// Matches the UEFI specification for EFI_MEMORY_DESCRIPTOR
-#[repr(C)]
-#[derive(Debug, Copy, Clone)]
-pub struct MemoryDescriptor {
-    pub type_: u32,      // Memory type
-    pub pad: u32,        // Padding
-    pub phys_start: u64, // Physical start address
-    pub virt_start: u64, // Virtual start address
-    pub page_count: u64, // Number of 4KiB pages
-    pub attribute: u64,  // Attributes (permissions, cacheability)
-}
+// Largest block: 2^MAX_ORDER frames (32 MiB); order 9 is a 2 MiB huge page
+pub const MAX_ORDER: usize = 13;
+const ORDERS: usize = MAX_ORDER + 1;
 
-unsafe impl Send for BitmapPmm {}
+// End of a free list; frame 0 is never free
+const NONE: u64 = 0;
 
-// Bitmap Physical Memory Manager
-// Uses a bitmap to track free/used frames
-struct BitmapPmm {
+unsafe impl Send for BuddyPmm {}
+
+struct BuddyPmm {
     // Pointer to the start of the bitmap logic
     bitmap: *mut u64,
     // Total number of physical frames managed
@@ -33,48 +44,73 @@ struct BitmapPmm {
     bitmap_size_u64: usize,
     // Physical address where the bitmap is stored
     bitmap_start_addr: u64,
+    // Per order, a bit per block: set if it heads a free block of that order
+    heads: [*mut u64; ORDERS],
+    // A bit per frame: set if the frame is usable RAM the PMM hands out
+    managed: *mut u64,
+    // Per order, physical address of the first free block (NONE if empty)
+    free_lists: [u64; ORDERS],
+    hhdm_offset: u64,
 }
 
 // Global PMM instance protected by a Mutex
-static PMM: Mutex<BitmapPmm> = Mutex::new(BitmapPmm {
+static PMM: Mutex<BuddyPmm> = Mutex::new(BuddyPmm {
     bitmap: core::ptr::null_mut(),
     total_frames: 0,
     bitmap_size_u64: 0,
     bitmap_start_addr: 0,
+    heads: [core::ptr::null_mut(); ORDERS],
+    managed: core::ptr::null_mut(),
+    free_lists: [NONE; ORDERS],
+    hhdm_offset: 0,
 });
 
-impl BitmapPmm {
+// The links at the start of a free block
+#[repr(C)]
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+// Smallest order whose blocks hold `pages` frames
+pub fn order_for(pages: usize) -> usize {
+    pages.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+impl BuddyPmm {
     // Internal initialization function
     unsafe fn init_internal(
         &mut self,
-        mmap_addr_phys: u64,
-        mmap_len: u64,
-        desc_size: u64,
+        memory_map: &[MemoryRegion],
+        reserved: &[ReservedRange],
         hhdm_offset: u64,
         max_phys_addr: u64,
     ) {
         unsafe {
-            // Calculate virtual address of memory map
-            let mmap_addr_virt = mmap_addr_phys + hhdm_offset;
-
+            self.hhdm_offset = hhdm_offset;
             // Calculate total frames needed to cover max physical address
             self.total_frames = (max_phys_addr / PAGE_SIZE) as usize;
             // Calculate bitmap size in u64 words (64 bits per word)
             self.bitmap_size_u64 = self.total_frames.div_ceil(64);
-            let bitmap_size_bytes = self.bitmap_size_u64 * 8;
+            // The head bitmaps of every order follow it
+            let head_words = |order: usize| (self.total_frames >> order).div_ceil(64);
+            let metadata_bytes =
+                (2 * self.bitmap_size_u64 + (0..ORDERS).map(head_words).sum::<usize>()) * 8;
 
-            // Find a large enough free region to store the bitmap itself for us
+            // Find a large enough free region to store the bitmaps for us, clear
+            // of anything the bootloader reserved
             let mut bitmap_phys_addr = u64::MAX;
-            for i in 0..mmap_len {
-                let addr = mmap_addr_virt + (i * desc_size);
-                let desc = &*(addr as *const MemoryDescriptor);
-                // Type 7 is Conventional Memory (Usable RAM)
-                if desc.type_ == 7 && desc.phys_start != 0 {
-                    let region_size = desc.page_count * PAGE_SIZE;
-                    if region_size >= bitmap_size_bytes as u64 {
-                        bitmap_phys_addr = desc.phys_start;
-                        break;
-                    }
+            for region in memory_map {
+                let bitmap = region.start..region.start + metadata_bytes as u64;
+                if region.kind == MemoryKind::Usable
+                    && region.start != 0
+                    && region.len >= metadata_bytes as u64
+                    && !reserved.iter().any(|range| {
+                        range.start < bitmap.end && bitmap.start < range.start + range.len
+                    })
+                {
+                    bitmap_phys_addr = region.start;
+                    break;
                 }
             }
 
@@ -87,50 +123,202 @@ impl BitmapPmm {
             self.bitmap = (bitmap_phys_addr + hhdm_offset) as *mut u64;
 
             // Initialize bitmap to all 1s (all used) initially
-            core::ptr::write_bytes(self.bitmap, 0xFF, bitmap_size_bytes);
+            core::ptr::write_bytes(self.bitmap, 0xFF, self.bitmap_size_u64 * 8);
+            let mut next = self.bitmap.add(self.bitmap_size_u64);
+            for (order, head) in self.heads.iter_mut().enumerate() {
+                *head = next;
+                core::ptr::write_bytes(next, 0, head_words(order) * 8);
+                next = next.add(head_words(order));
+            }
+            self.managed = next;
+            self.free_lists = [NONE; ORDERS];
 
             // Iterate memory map again and mark usable regions as free (0)
-            for i in 0..mmap_len {
-                let addr = mmap_addr_virt + (i * desc_size);
-                let desc = &*(addr as *const MemoryDescriptor);
-                if desc.type_ == 7 {
-                    self.mark_region_free(desc.phys_start, desc.page_count as usize);
+            for region in memory_map {
+                if region.kind == MemoryKind::Usable {
+                    self.mark_region_free(region.start, region.pages() as usize);
                 }
             }
 
-            // Mark the memory occupied by the bitmap itself as used
-            let bitmap_pages = bitmap_size_bytes.div_ceil(PAGE_SIZE as usize);
+            // What the bootloader set up for us stays ours, even if the memory
+            // map were to call it usable
+            for range in reserved {
+                self.mark_region_used(range.start, (range.len / PAGE_SIZE) as usize);
+            }
+
+            // Mark the memory occupied by the bitmaps themselves as used
+            let bitmap_pages = metadata_bytes.div_ceil(PAGE_SIZE as usize);
             self.mark_region_used(bitmap_phys_addr, bitmap_pages);
 
             // Mark frame 0 as used (null pointer protection)
             self.mark_used(0);
+
+            // What is free now is all the PMM will ever hand out
+            for word in 0..self.bitmap_size_u64 {
+                *self.managed.add(word) = !*self.bitmap.add(word);
+            }
+
+            // Every run of free frames onto the lists, from the top so that the
+            // lowest blocks end up first
+            let mut end = self.total_frames;
+            while end > 0 {
+                while end > 0 && self.is_used(end - 1) {
+                    end -= 1;
+                }
+                let mut start = end;
+                while start > 0 && !self.is_used(start - 1) {
+                    start -= 1;
+                }
+                self.add_run(start, end);
+                end = start;
+            }
+        }
+    }
+
+    // Put the free frames start..end on the lists as the largest aligned blocks
+    // that fit, highest first
+    fn add_run(&mut self, start: usize, mut end: usize) {
+        while end > start {
+            let order = (end.trailing_zeros() as usize)
+                .min((end - start).ilog2() as usize)
+                .min(MAX_ORDER);
+            end -= 1 << order;
+            self.push(end, order);
+        }
+    }
+
+    fn block(&self, frame_idx: usize) -> *mut FreeBlock {
+        (frame_idx as u64 * PAGE_SIZE + self.hhdm_offset) as *mut FreeBlock
+    }
+
+    // Whether `frame_idx` heads a free block of `order`
+    fn is_head(&self, frame_idx: usize, order: usize) -> bool {
+        let bit = frame_idx >> order;
+        unsafe { *self.heads[order].add(bit / 64) & (1 << (bit % 64)) != 0 }
+    }
+
+    fn set_head(&mut self, frame_idx: usize, order: usize, head: bool) {
+        let bit = frame_idx >> order;
+        let word = unsafe { &mut *self.heads[order].add(bit / 64) };
+        if head {
+            *word |= 1 << (bit % 64);
+        } else {
+            *word &= !(1 << (bit % 64));
         }
     }
 
-    // Allocation Logic: Find first 0 bit in bitmap
-    fn allocate_frame_internal(&mut self) -> Option<u64> {
+    // Add a free block at the front of its list
+    fn push(&mut self, frame_idx: usize, order: usize) {
+        let addr = frame_idx as u64 * PAGE_SIZE;
+        let next = self.free_lists[order];
         unsafe {
-            for idx in 0..self.bitmap_size_u64 {
-                let entry = *self.bitmap.add(idx);
-                if entry != !0 {
-                    // If not all bits are 1
-                    // Find first zero bit (inverted entry trailing zeros)
-                    let inverted = !entry;
-                    let bit_idx = inverted.trailing_zeros();
-                    let frame_idx = (idx * 64) + bit_idx as usize;
-
-                    if frame_idx >= self.total_frames {
-                        return None; // Out of bounds
-                    }
-
-                    // Mark as used
-                    self.mark_used(frame_idx);
-                    // Return physical address
-                    return Some(frame_idx as u64 * PAGE_SIZE);
+            self.block(frame_idx).write(FreeBlock { next, prev: NONE });
+            if next != NONE {
+                (*self.block((next / PAGE_SIZE) as usize)).prev = addr;
+            }
+        }
+        self.free_lists[order] = addr;
+        self.set_head(frame_idx, order, true);
+    }
+
+    // Take a free block off its list
+    fn unlink(&mut self, frame_idx: usize, order: usize) {
+        let FreeBlock { next, prev } = unsafe { self.block(frame_idx).read() };
+        unsafe {
+            match prev {
+                NONE => self.free_lists[order] = next,
+                prev => (*self.block((prev / PAGE_SIZE) as usize)).next = next,
+            }
+            if next != NONE {
+                (*self.block((next / PAGE_SIZE) as usize)).prev = prev;
+            }
+        }
+        self.set_head(frame_idx, order, false);
+    }
+
+    // A block of 2^order frames ending at or below `limit`: the first on the
+    // smallest list that has one, split down to size
+    fn allocate_order(&mut self, order: usize, limit: u64) -> Option<u64> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let size = (1u64 << order) * PAGE_SIZE;
+        let (frame_idx, mut current) = (order..ORDERS).find_map(|current| {
+            let mut addr = self.free_lists[current];
+            while addr != NONE {
+                if addr + size <= limit {
+                    return Some(((addr / PAGE_SIZE) as usize, current));
                 }
+                addr = unsafe { (*self.block((addr / PAGE_SIZE) as usize)).next };
+            }
+            None
+        })?;
+        self.unlink(frame_idx, current);
+        // Keep the lower half, give back the upper
+        while current > order {
+            current -= 1;
+            self.push(frame_idx + (1 << current), current);
+        }
+        self.mark_region_used(frame_idx as u64 * PAGE_SIZE, 1 << order);
+        Some(frame_idx as u64 * PAGE_SIZE)
+    }
+
+    // Give back a block of 2^order frames; false if it was not allocated as a
+    // whole (a double free, or memory the PMM never managed: holes, firmware or
+    // reserved memory, the kernel, the bitmaps)
+    fn free_order(&mut self, phys: u64, order: usize) -> bool {
+        let mut frame_idx = (phys / PAGE_SIZE) as usize;
+        let count = 1usize << order.min(MAX_ORDER);
+        // Frame 0 is never handed out
+        if order > MAX_ORDER
+            || !phys.is_multiple_of(PAGE_SIZE * count as u64)
+            || frame_idx == 0
+            || frame_idx + count > self.total_frames
+            || (frame_idx..frame_idx + count)
+                .any(|frame| !self.is_managed(frame) || !unsafe { self.is_used(frame) })
+        {
+            return false;
+        }
+        self.mark_region_free(phys, count);
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = frame_idx ^ (1 << order);
+            if buddy + (1 << order) > self.total_frames || !self.is_head(buddy, order) {
+                break;
             }
+            self.unlink(buddy, order);
+            frame_idx = frame_idx.min(buddy);
+            order += 1;
         }
-        None // OOM
+        self.push(frame_idx, order);
+        true
+    }
+
+    fn stats(&self) -> FrameStats {
+        let free = self.free_frames();
+        FrameStats {
+            total: self.total_frames,
+            used: self.total_frames - free,
+            free,
+        }
+    }
+
+    // Number of free frames (0 bits)
+    fn free_frames(&self) -> usize {
+        let mut free = 0;
+        for idx in 0..self.bitmap_size_u64 {
+            free += unsafe { *self.bitmap.add(idx) }.count_zeros() as usize;
+        }
+        // Bits past the last frame stay set, they are never counted
+        free
+    }
+
+    fn is_managed(&self, frame_idx: usize) -> bool {
+        unsafe { *self.managed.add(frame_idx / 64) & (1 << (frame_idx % 64)) != 0 }
+    }
+
+    unsafe fn is_used(&self, frame_idx: usize) -> bool {
+        unsafe { *self.bitmap.add(frame_idx / 64) & (1 << (frame_idx % 64)) != 0 }
     }
 
     // Helper to mark a specific frame as used (set bit to 1)
@@ -151,8 +339,10 @@ impl BitmapPmm {
     fn mark_region_used(&mut self, start_addr: u64, page_count: usize) {
         let start_frame = (start_addr / PAGE_SIZE) as usize;
         for i in 0..page_count {
-            unsafe {
-                self.mark_used(start_frame + i);
+            if start_frame + i < self.total_frames {
+                unsafe {
+                    self.mark_used(start_frame + i);
+                }
             }
         }
     }
@@ -172,30 +362,81 @@ impl BitmapPmm {
 
 // Public initialization function called by main
 pub fn init(
-    mmap_addr_phys: u64,
-    mmap_len: u64,
-    desc_size: u64,
+    memory_map: &[MemoryRegion],
+    reserved: &[ReservedRange],
     hhdm_offset: u64,
     max_phys_addr: u64,
 ) {
-    println!("[PMM] Init started...");
+    log::debug!("Init started...");
 
     unsafe {
-        PMM.lock().init_internal(
-            mmap_addr_phys,
-            mmap_len,
-            desc_size,
-            hhdm_offset,
-            max_phys_addr,
-        )
+        PMM.lock()
+            .init_internal(memory_map, reserved, hhdm_offset, max_phys_addr)
     };
+    for range in reserved {
+        log::debug!(
+            "Reserved {:#x}..{:#x} ({:?})",
+            range.start,
+            range.start + range.len,
+            range.what
+        );
+    }
 
-    println!("[PMM] Init finished!");
+    log::info!("Init finished!");
 }
 
 // Public allocation function
 pub fn allocate_frame() -> Option<u64> {
-    interrupts::without_interrupts(|| PMM.lock().allocate_frame_internal())
+    interrupts::without_interrupts(|| PMM.lock().allocate_order(0, u64::MAX))
+}
+
+// 2^order physically contiguous frames, aligned to their size (DMA buffers and
+// rings, huge pages, memory that must survive a kexec)
+pub fn allocate_contiguous(order: usize) -> Option<u64> {
+    interrupts::without_interrupts(|| PMM.lock().allocate_order(order, u64::MAX))
+}
+
+// Same as `allocate_contiguous`, ending at or below physical address `limit`
+// (real-mode code, devices that cannot address all memory)
+pub fn allocate_contiguous_below(order: usize, limit: u64) -> Option<u64> {
+    interrupts::without_interrupts(|| PMM.lock().allocate_order(order, limit))
+}
+
+// Give a block from `allocate_contiguous` back, with the order it was asked for
+pub fn free_contiguous(phys: u64, order: usize) {
+    if !interrupts::without_interrupts(|| PMM.lock().free_order(phys, order)) {
+        log::error!(
+            "PMM: block {:#x} of order {} freed but not allocated",
+            phys,
+            order
+        );
+    }
+}
+
+// Give a frame from `allocate_frame` back. Freeing one twice is a bug in the
+// caller: it is reported and ignored, the frame may already be someone else's
+pub fn free_frame(phys: u64) {
+    if !interrupts::without_interrupts(|| PMM.lock().free_order(phys, 0)) {
+        log::error!("PMM: frame {:#x} freed but not allocated", phys);
+    }
+}
+
+// Frame counts; `used` includes what the PMM never manages (holes in the memory
+// map, firmware and reserved memory), so a leak shows as `free` shrinking
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+pub fn stats() -> FrameStats {
+    interrupts::without_interrupts(|| PMM.lock().stats())
+}
+
+// Same as `stats` for interrupt handlers: None if the PMM is locked
+pub fn try_stats() -> Option<FrameStats> {
+    Some(PMM.try_lock()?.stats())
 }
 
 // Implement the FrameAllocator trait from x86_64 crate
@@ -206,3 +447,99 @@ unsafe impl FrameAllocator<Size4KiB> for KernelFrameAllocator {
         crate::pmm::allocate_frame().map(|phys| PhysFrame::containing_address(PhysAddr::new(phys)))
     }
 }
+
+impl FrameDeallocator<Size4KiB> for KernelFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        free_frame(frame.start_address().as_u64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn allocated_frames_are_aligned_and_distinct() {
+        let first = allocate_frame().expect("out of frames");
+        let second = allocate_frame().expect("out of frames");
+        assert!(first.is_multiple_of(PAGE_SIZE));
+        assert!(second.is_multiple_of(PAGE_SIZE));
+        assert_ne!(first, second);
+    }
+
+    #[test_case]
+    fn allocation_updates_free_count() {
+        let before = stats();
+        assert_eq!(before.used + before.free, before.total);
+        allocate_frame().expect("out of frames");
+        assert_eq!(stats().free, before.free - 1);
+        assert_eq!(stats().used, before.used + 1);
+    }
+
+    #[test_case]
+    fn freed_frames_are_reused_and_counted() {
+        let before = stats();
+        let frame = allocate_frame().expect("out of frames");
+        unsafe {
+            KernelFrameAllocator
+                .deallocate_frame(PhysFrame::containing_address(PhysAddr::new(frame)))
+        };
+        assert_eq!(stats(), before);
+        let frame = allocate_frame().expect("out of frames");
+        free_frame(frame);
+        // A second free changes nothing
+        free_frame(frame);
+        assert_eq!(stats(), before);
+    }
+
+    #[test_case]
+    fn contiguous_blocks_are_aligned_and_merge_back() {
+        assert_eq!(order_for(1), 0);
+        assert_eq!(order_for(5), 3);
+        assert_eq!(order_for(512), 9);
+        let before = stats();
+        // A 2 MiB huge page
+        let huge = allocate_contiguous(9).expect("no 2 MiB block");
+        assert!(huge.is_multiple_of(512 * PAGE_SIZE));
+        assert_eq!(stats().free, before.free - 512);
+        let small = allocate_contiguous(3).expect("no 8-frame block");
+        assert!(small.is_multiple_of(8 * PAGE_SIZE));
+        assert!(small + 8 * PAGE_SIZE <= huge || huge + 512 * PAGE_SIZE <= small);
+        free_contiguous(huge, 9);
+        free_contiguous(small, 3);
+        assert_eq!(stats(), before);
+        // Freed as a whole, the block can be had again in one piece
+        let again = allocate_contiguous(9).expect("blocks did not merge");
+        free_contiguous(again, 9);
+        // Only what was allocated, in the order asked for, can be freed
+        free_contiguous(again + PAGE_SIZE, 0);
+        assert_eq!(stats(), before);
+    }
+
+    #[test_case]
+    fn contiguous_blocks_respect_a_limit() {
+        let limit = 1 << 30;
+        if let Some(low) = allocate_contiguous_below(2, limit) {
+            assert!(low + 4 * PAGE_SIZE <= limit);
+            free_contiguous(low, 2);
+        }
+        assert_eq!(allocate_contiguous_below(0, PAGE_SIZE), None);
+    }
+
+    #[test_case]
+    fn kernel_image_is_never_free() {
+        use x86_64::VirtAddr;
+        use x86_64::structures::paging::Translate;
+        let mapper = unsafe { crate::pml4::init_mapper(crate::elf_loader::get_hhdm_offset()) };
+        let code = mapper
+            .translate_addr(VirtAddr::new(allocate_frame as *const () as u64))
+            .expect("kernel code not mapped");
+        let frame = (code.as_u64() / PAGE_SIZE) as usize;
+        assert!(unsafe { PMM.lock().is_used(frame) });
+        // Nor can it be freed
+        let before = stats();
+        free_frame(frame as u64 * PAGE_SIZE);
+        assert_eq!(stats(), before);
+        assert!(!PMM.lock().is_managed(frame));
+    }
+}
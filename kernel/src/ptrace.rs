@@ -0,0 +1,499 @@
+// Debugging Support
+// A traced program stops on int3 and on debug exceptions (single-step) instead of
+// being killed: its registers are saved and the kernel shell takes over, where
+// `dbg` reads and writes its memory and registers, single-steps it or lets it
+// continue. The same operations back a ptrace(2)-style syscall with the Linux
+// request numbers and `user_regs_struct` layout.
+//
+// There is one program at a time, so "the tracee" is simply the program last
+// started. A program is traced if started with `dbg run` (it stops at its entry
+// point, before the first instruction) or after PTRACE_TRACEME; an untraced one
+// gets the default action of SIGTRAP. A debugger running as a separate user
+// program needs a scheduler: until then only PTRACE_TRACEME is of use from user
+// space (the other requests find no stopped tracee) and the shell is the front end.
+//
+// #BP and #DB enter through naked stubs that save every general purpose register,
+// since the x86-interrupt ABI does not expose them and resuming must restore them.
+
+use core::arch::naked_asm;
+use spin::Mutex;
+use x86_64::registers::model_specific::{FsBase, GsBase};
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::structures::paging::{PageTableFlags, Translate, mapper::TranslateResult};
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+// Linux ptrace requests
+pub const PTRACE_TRACEME: u64 = 0;
+pub const PTRACE_PEEKTEXT: u64 = 1;
+pub const PTRACE_PEEKDATA: u64 = 2;
+pub const PTRACE_POKETEXT: u64 = 4;
+pub const PTRACE_POKEDATA: u64 = 5;
+pub const PTRACE_CONT: u64 = 7;
+pub const PTRACE_SINGLESTEP: u64 = 9;
+pub const PTRACE_GETREGS: u64 = 12;
+pub const PTRACE_SETREGS: u64 = 13;
+
+// The program's pid, as shown by `ps`
+pub const TRACEE_PID: u64 = 1;
+
+// RFLAGS.TF: trap after the next instruction
+const RFLAGS_TF: u64 = 1 << 8;
+// DR6.BS: the debug exception was a single-step
+const DR6_BS: u64 = 1 << 14;
+
+// User selectors and RFLAGS of a program about to run its first instruction
+const USER_CODE_SEL: u64 = 0x23;
+const USER_DATA_SEL: u64 = 0x1B;
+const RFLAGS_USER: u64 = 0x202;
+
+const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+// General purpose registers pushed by the entry stubs, then the CPU's frame
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct TrapFrame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+/// Linux `struct user_regs_struct` (x86-64), for GETREGS / SETREGS
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UserRegs {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub orig_rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub eflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+    pub fs_base: u64,
+    pub gs_base: u64,
+    pub ds: u64,
+    pub es: u64,
+    pub fs: u64,
+    pub gs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PtraceError {
+    // No stopped tracee (ESRCH)
+    NotStopped,
+    // Address not mapped in the tracee (EIO)
+    BadAddress,
+}
+
+struct Tracee {
+    traced: bool,
+    // Registers of the stopped program, None while it runs
+    stopped: Option<UserRegs>,
+}
+
+static TRACEE: Mutex<Tracee> = Mutex::new(Tracee {
+    traced: false,
+    stopped: None,
+});
+
+// Frame `resume` returns to user space from; outlives the shell's stack frames
+static mut RESUME_FRAME: TrapFrame = TrapFrame {
+    r15: 0,
+    r14: 0,
+    r13: 0,
+    r12: 0,
+    r11: 0,
+    r10: 0,
+    r9: 0,
+    r8: 0,
+    rbp: 0,
+    rdi: 0,
+    rsi: 0,
+    rdx: 0,
+    rcx: 0,
+    rbx: 0,
+    rax: 0,
+    rip: 0,
+    cs: 0,
+    rflags: 0,
+    rsp: 0,
+    ss: 0,
+};
+
+// ============================================================================
+// 1. ENTRY STUBS
+// ============================================================================
+
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        // int3 is executed by user code: the gate must be reachable from ring 3
+        idt.breakpoint
+            .set_handler_addr(VirtAddr::new(breakpoint_entry as *const () as u64))
+            .set_privilege_level(PrivilegeLevel::Ring3);
+        idt.debug
+            .set_handler_addr(VirtAddr::new(debug_entry as *const () as u64));
+    }
+}
+
+macro_rules! trap_entry {
+    ($name:ident, $handler:ident) => {
+        #[unsafe(naked)]
+        unsafe extern "C" fn $name() {
+            naked_asm!(
+                // Complete the CPU's frame into a TrapFrame (RSP is 16-byte
+                // aligned again after the 15 pushes, as the call needs)
+                "push rax",
+                "push rbx",
+                "push rcx",
+                "push rdx",
+                "push rsi",
+                "push rdi",
+                "push rbp",
+                "push r8",
+                "push r9",
+                "push r10",
+                "push r11",
+                "push r12",
+                "push r13",
+                "push r14",
+                "push r15",
+                "mov rdi, rsp",
+                "call {handler}",
+                "pop r15",
+                "pop r14",
+                "pop r13",
+                "pop r12",
+                "pop r11",
+                "pop r10",
+                "pop r9",
+                "pop r8",
+                "pop rbp",
+                "pop rdi",
+                "pop rsi",
+                "pop rdx",
+                "pop rcx",
+                "pop rbx",
+                "pop rax",
+                "iretq",
+                handler = sym $handler,
+            );
+        }
+    };
+}
+
+trap_entry!(breakpoint_entry, breakpoint_trap);
+trap_entry!(debug_entry, debug_trap);
+
+extern "C" fn breakpoint_trap(frame: &mut TrapFrame) {
+    if from_user(frame) {
+        user_trap(frame, "breakpoint");
+    }
+    shared::serial_println!("EXCEPTION: BREAKPOINT\n{:#x?}", frame);
+}
+
+extern "C" fn debug_trap(frame: &mut TrapFrame) {
+    let dr6: u64;
+    unsafe {
+        core::arch::asm!("mov {}, dr6", out(reg) dr6, options(nomem, nostack));
+        core::arch::asm!("mov dr6, {}", in(reg) 0u64, options(nomem, nostack));
+    }
+    // One step at a time: the next resume decides whether to step again
+    frame.rflags &= !RFLAGS_TF;
+
+    if from_user(frame) {
+        let reason = if dr6 & DR6_BS != 0 {
+            "single-step"
+        } else {
+            "debug exception"
+        };
+        user_trap(frame, reason);
+    }
+    shared::serial_println!("EXCEPTION: DEBUG (dr6={:#x})\n{:#x?}", dr6, frame);
+}
+
+fn from_user(frame: &TrapFrame) -> bool {
+    frame.cs & 3 == 3
+}
+
+// Stop the program if traced, otherwise SIGTRAP's default action (terminate)
+fn user_trap(frame: &TrapFrame, reason: &str) -> ! {
+    if !TRACEE.lock().traced {
+        crate::interrupts::kill_user(
+            reason,
+            crate::interrupts::SIGTRAP,
+            frame.rip,
+            frame.rsp,
+            None,
+        );
+    }
+    // Entered from ring 3: GS still holds the user's base
+    let regs = user_regs(frame, FsBase::read().as_u64(), GsBase::read().as_u64());
+    stop(regs, reason)
+}
+
+// ============================================================================
+// 2. STOP / RESUME
+// ============================================================================
+
+fn user_regs(frame: &TrapFrame, fs_base: u64, gs_base: u64) -> UserRegs {
+    UserRegs {
+        r15: frame.r15,
+        r14: frame.r14,
+        r13: frame.r13,
+        r12: frame.r12,
+        rbp: frame.rbp,
+        rbx: frame.rbx,
+        r11: frame.r11,
+        r10: frame.r10,
+        r9: frame.r9,
+        r8: frame.r8,
+        rax: frame.rax,
+        rcx: frame.rcx,
+        rdx: frame.rdx,
+        rsi: frame.rsi,
+        rdi: frame.rdi,
+        orig_rax: u64::MAX,
+        rip: frame.rip,
+        cs: frame.cs,
+        eflags: frame.rflags,
+        rsp: frame.rsp,
+        ss: frame.ss,
+        fs_base,
+        gs_base,
+        ds: frame.ss,
+        es: frame.ss,
+        fs: 0,
+        gs: 0,
+    }
+}
+
+fn stop(regs: UserRegs, reason: &str) -> ! {
+    log::info!("Program stopped ({}) at {:#x}", reason, regs.rip);
+    TRACEE.lock().stopped = Some(regs);
+    crate::shell::run()
+}
+
+// A new program replaces the previous one, traced or not
+pub fn reset(traced: bool) {
+    *TRACEE.lock() = Tracee {
+        traced,
+        stopped: None,
+    };
+}
+
+// Stop a program just loaded by `shell::exec_traced` before its first instruction
+pub fn stop_at_entry(entry: VirtAddr, stack: VirtAddr) -> ! {
+    let frame = TrapFrame {
+        rip: entry.as_u64(),
+        cs: USER_CODE_SEL,
+        rflags: RFLAGS_USER,
+        rsp: stack.as_u64(),
+        ss: USER_DATA_SEL,
+        ..TrapFrame::default()
+    };
+    stop(user_regs(&frame, FsBase::read().as_u64(), 0), "entry point")
+}
+
+pub fn is_stopped() -> bool {
+    TRACEE.lock().stopped.is_some()
+}
+
+// Let the stopped program run again, for one instruction if `step`
+pub fn resume(step: bool) -> Result<core::convert::Infallible, PtraceError> {
+    let regs = TRACEE
+        .lock()
+        .stopped
+        .take()
+        .ok_or(PtraceError::NotStopped)?;
+    x86_64::instructions::interrupts::disable();
+
+    FsBase::write(VirtAddr::new(regs.fs_base));
+    crate::syscalls::prepare_user_gs(VirtAddr::new(regs.gs_base));
+
+    // Only the arithmetic flags, DF, TF and IF are the program's to choose; the
+    // selectors stay the user's whatever SETREGS said (iretq must not reach ring 0)
+    const USER_FLAGS: u64 = 0xCD5 | (1 << 10) | RFLAGS_TF;
+    let mut rflags = RFLAGS_USER | (regs.eflags & USER_FLAGS);
+    if step {
+        rflags |= RFLAGS_TF;
+    }
+    unsafe {
+        RESUME_FRAME = TrapFrame {
+            r15: regs.r15,
+            r14: regs.r14,
+            r13: regs.r13,
+            r12: regs.r12,
+            r11: regs.r11,
+            r10: regs.r10,
+            r9: regs.r9,
+            r8: regs.r8,
+            rbp: regs.rbp,
+            rdi: regs.rdi,
+            rsi: regs.rsi,
+            rdx: regs.rdx,
+            rcx: regs.rcx,
+            rbx: regs.rbx,
+            rax: regs.rax,
+            rip: regs.rip,
+            cs: USER_CODE_SEL,
+            rflags,
+            rsp: regs.rsp,
+            ss: USER_DATA_SEL,
+        };
+        resume_frame(&raw const RESUME_FRAME);
+    }
+}
+
+// Load every register from `frame` and return to it
+#[unsafe(naked)]
+unsafe extern "C" fn resume_frame(frame: *const TrapFrame) -> ! {
+    naked_asm!(
+        "mov rsp, rdi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "iretq",
+    );
+}
+
+// ============================================================================
+// 3. REGISTERS & MEMORY
+// ============================================================================
+
+pub fn regs() -> Result<UserRegs, PtraceError> {
+    TRACEE.lock().stopped.ok_or(PtraceError::NotStopped)
+}
+
+pub fn set_regs(regs: UserRegs) -> Result<(), PtraceError> {
+    // A kernel or non-canonical RIP/RSP would fault on the IRETQ back, in ring 0
+    let addresses = [regs.rip, regs.rsp, regs.fs_base, regs.gs_base];
+    if addresses.iter().any(|&addr| addr >= USER_SPACE_END) {
+        return Err(PtraceError::BadAddress);
+    }
+    let mut tracee = TRACEE.lock();
+    let stopped = tracee.stopped.as_mut().ok_or(PtraceError::NotStopped)?;
+    *stopped = regs;
+    Ok(())
+}
+
+// Physical address (through the HHDM) of user byte `addr` of the tracee
+fn user_byte(addr: u64) -> Result<*mut u8, PtraceError> {
+    if addr >= USER_SPACE_END {
+        return Err(PtraceError::BadAddress);
+    }
+    // Demand-paged memory the tracee has not touched yet is faulted in
+    crate::vma::populate(addr);
+    let hhdm = crate::elf_loader::get_hhdm_offset();
+    let mapper = unsafe { crate::pml4::init_mapper(hhdm) };
+    match mapper.translate(VirtAddr::new(addr)) {
+        TranslateResult::Mapped {
+            frame,
+            offset,
+            flags,
+        } if flags.contains(PageTableFlags::USER_ACCESSIBLE) => {
+            Ok((frame.start_address().as_u64() + offset + hhdm) as *mut u8)
+        }
+        _ => Err(PtraceError::BadAddress),
+    }
+}
+
+// Read a word of the stopped program's memory
+pub fn peek(addr: u64) -> Result<u64, PtraceError> {
+    if !is_stopped() {
+        return Err(PtraceError::NotStopped);
+    }
+    let mut bytes = [0u8; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = unsafe { *user_byte(addr.wrapping_add(i as u64))? };
+    }
+    Ok(u64::from_le_bytes(bytes))
+}
+
+// Write a word of the stopped program's memory, read-only (code) pages included,
+// so breakpoints can be planted
+pub fn poke(addr: u64, value: u64) -> Result<(), PtraceError> {
+    if !is_stopped() {
+        return Err(PtraceError::NotStopped);
+    }
+    // Check the whole word first: nothing is written if part of it is unmapped
+    let mut targets = [core::ptr::null_mut(); 8];
+    for (i, target) in targets.iter_mut().enumerate() {
+        *target = user_byte(addr.wrapping_add(i as u64))?;
+    }
+    for (target, byte) in targets.iter().zip(value.to_le_bytes()) {
+        unsafe { target.write(byte) };
+    }
+    Ok(())
+}
+
+// Mark the calling program traced (PTRACE_TRACEME)
+pub fn trace_me() {
+    TRACEE.lock().traced = true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn registers_outside_user_space_are_refused() {
+        for regs in [
+            UserRegs {
+                rip: 0xFFFF_8000_0000_0000,
+                ..UserRegs::default()
+            },
+            UserRegs {
+                rsp: 0x0000_8000_0000_0000,
+                ..UserRegs::default()
+            },
+            UserRegs {
+                fs_base: u64::MAX,
+                ..UserRegs::default()
+            },
+        ] {
+            assert_eq!(set_regs(regs), Err(PtraceError::BadAddress));
+        }
+    }
+}
@@ -0,0 +1,356 @@
+// RTC Module
+// The battery-backed CMOS real-time clock: the wall clock and a wakeup alarm.
+//
+// At boot the date and time are read once, through the firmware's GetTime when
+// it has runtime services (efi.rs), else from the CMOS (kept in UTC); after that the wall
+// clock is that reading plus the PIT uptime, plus the time spent in S3, which the
+// PIT does not see and which is measured again against the RTC on resume.
+//
+// The RTC alarm raises IRQ 8 at a time of day (hour, minute, second). Callers arm
+// alarms at absolute wall-clock seconds; the earliest pending one is programmed, and
+// when it fires the next one is. An alarm more than a day away first rings on an
+// earlier day at the same time and is kept, so it is simply programmed again. The
+// interrupt itself is the wakeup: the CPU leaves `hlt` and whoever waits re-checks
+// its deadline. In S3, ACPI's RTC_EN lets the same alarm wake the machine.
+
+use alloc::collections::BTreeSet;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+// CMOS index and data ports
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+// CMOS registers
+const REG_SECONDS: u8 = 0x00;
+const REG_SECONDS_ALARM: u8 = 0x01;
+const REG_MINUTES: u8 = 0x02;
+const REG_MINUTES_ALARM: u8 = 0x03;
+const REG_HOURS: u8 = 0x04;
+const REG_HOURS_ALARM: u8 = 0x05;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_A: u8 = 0x0A;
+const REG_B: u8 = 0x0B;
+const REG_C: u8 = 0x0C;
+
+const A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const B_24_HOUR: u8 = 1 << 1;
+const B_BINARY: u8 = 1 << 2;
+const B_ALARM_INTERRUPT: u8 = 1 << 5;
+const C_ALARM_FLAG: u8 = 1 << 5;
+const HOUR_PM: u8 = 1 << 7;
+
+// IRQ line of the RTC (on the secondary PIC)
+pub const IRQ: u8 = 8;
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+const SECS_PER_DAY: u64 = 86_400;
+
+// One register access is an index write followed by the data access
+static CMOS: Mutex<()> = Mutex::new(());
+
+// Wall-clock seconds read at boot, and the uptime (ns) they were read at
+static BOOT_EPOCH: AtomicU64 = AtomicU64::new(0);
+static BOOT_UPTIME_NS: AtomicU64 = AtomicU64::new(0);
+
+// Time spent in S3, added to the boot-based clocks
+static SUSPENDED_NS: AtomicU64 = AtomicU64::new(0);
+
+// Pending alarms, as wall-clock seconds
+static ALARMS: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+
+// Most pending alarms kept; later ones past this many are dropped
+const MAX_ALARMS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DateTime {
+    year: u64,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+}
+
+// ============================================================================
+// 1. CMOS ACCESS
+// ============================================================================
+
+fn with_cmos<R>(f: impl FnOnce() -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let _guard = CMOS.lock();
+        f()
+    })
+}
+
+// Callers hold the CMOS lock
+fn read_reg(reg: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_INDEX).write(reg);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+fn write_reg(reg: u8, value: u8) {
+    unsafe {
+        Port::<u8>::new(CMOS_INDEX).write(reg);
+        Port::<u8>::new(CMOS_DATA).write(value);
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+// Decode a clock register in the format register B selects
+fn decode(value: u8, format: u8) -> u8 {
+    if format & B_BINARY != 0 {
+        value
+    } else {
+        from_bcd(value)
+    }
+}
+
+fn encode(value: u8, format: u8) -> u8 {
+    if format & B_BINARY != 0 {
+        value
+    } else {
+        to_bcd(value)
+    }
+}
+
+// 12-hour mode keeps 1..=12 with the PM bit on top
+fn decode_hour(value: u8, format: u8) -> u8 {
+    if format & B_24_HOUR != 0 {
+        return decode(value, format);
+    }
+    let hour = decode(value & !HOUR_PM, format) % 12;
+    if value & HOUR_PM != 0 {
+        hour + 12
+    } else {
+        hour
+    }
+}
+
+fn encode_hour(hour: u8, format: u8) -> u8 {
+    if format & B_24_HOUR != 0 {
+        return encode(hour, format);
+    }
+    let twelve = match hour % 12 {
+        0 => 12,
+        h => h,
+    };
+    encode(twelve, format) | if hour >= 12 { HOUR_PM } else { 0 }
+}
+
+fn read_raw() -> [u8; 6] {
+    // An update takes under 2 ms once UIP is set
+    while read_reg(REG_A) & A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    [
+        REG_SECONDS,
+        REG_MINUTES,
+        REG_HOURS,
+        REG_DAY,
+        REG_MONTH,
+        REG_YEAR,
+    ]
+    .map(read_reg)
+}
+
+// Read the date and time; two equal reads in a row cannot straddle an update
+fn read_time() -> DateTime {
+    with_cmos(|| {
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        let format = read_reg(REG_B);
+        // Two-digit years: the century register is not standard
+        let year = decode(raw[5], format) as u64;
+        DateTime {
+            year: if year < 70 { 2000 + year } else { 1900 + year },
+            month: decode(raw[4], format),
+            day: decode(raw[3], format),
+            hour: decode_hour(raw[2], format),
+            minute: decode(raw[1], format),
+            second: decode(raw[0], format),
+        }
+    })
+}
+
+// ============================================================================
+// 2. WALL CLOCK
+// ============================================================================
+
+// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: u64, month: u8, day: u8) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month = month as u64;
+    let day_of_year =
+        (153 * if month > 2 { month - 3 } else { month + 9 } + 2) / 5 + day as u64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn epoch_seconds(time: DateTime) -> u64 {
+    days_from_civil(time.year, time.month, time.day) * SECS_PER_DAY
+        + time.hour as u64 * 3600
+        + time.minute as u64 * 60
+        + time.second as u64
+}
+
+// The firmware's reading of the clock (four-digit year, time zone) and the
+// seconds to add for UTC
+fn read_efi_time() -> Option<(DateTime, i64)> {
+    let time = crate::efi::get_time()?;
+    let date = DateTime {
+        year: time.year as u64,
+        month: time.month,
+        day: time.day,
+        hour: time.hour,
+        minute: time.minute,
+        second: time.second,
+    };
+    Some((date, time.utc_offset_minutes() * 60))
+}
+
+// Read the clock and take over the RTC interrupt for alarms
+pub fn init() {
+    let (time, offset, source) = match read_efi_time() {
+        Some((time, offset)) => (time, offset, "EFI"),
+        None => (read_time(), 0, "CMOS"),
+    };
+    BOOT_EPOCH.store(
+        epoch_seconds(time).saturating_add_signed(offset),
+        Ordering::Relaxed,
+    );
+    BOOT_UPTIME_NS.store(crate::interrupts::uptime_ns(), Ordering::Relaxed);
+    log::info!(
+        "RTC: {:04}-{:02}-{:02} {:02}:{:02}:{:02} (UTC{:+} min, from {})",
+        time.year,
+        time.month,
+        time.day,
+        time.hour,
+        time.minute,
+        time.second,
+        offset / 60,
+        source
+    );
+
+    // Discard a flag left from before boot, then let alarms through
+    with_cmos(|| read_reg(REG_C));
+    crate::interrupts::PICS.unmask(IRQ);
+}
+
+// Nanoseconds since boot, including time spent suspended (CLOCK_BOOTTIME)
+pub fn boottime_ns() -> u64 {
+    crate::interrupts::uptime_ns() + SUSPENDED_NS.load(Ordering::Relaxed)
+}
+
+// Nanoseconds since the Unix epoch (CLOCK_REALTIME)
+pub fn realtime_ns() -> u64 {
+    BOOT_EPOCH.load(Ordering::Relaxed) * NS_PER_SEC + boottime_ns()
+        - BOOT_UPTIME_NS.load(Ordering::Relaxed)
+}
+
+// After S3: account for the sleep and program the alarm again
+pub fn resume() {
+    let now = epoch_seconds(read_time()) * NS_PER_SEC;
+    let estimate = realtime_ns();
+    if now > estimate {
+        SUSPENDED_NS.fetch_add(now - estimate, Ordering::Relaxed);
+    }
+    with_cmos(|| read_reg(REG_C));
+    program_next();
+}
+
+// ============================================================================
+// 3. ALARMS
+// ============================================================================
+
+// Ring the alarm at wall-clock second `at` (an earlier alarm stays first)
+pub fn arm_alarm(at: u64) {
+    interrupts::without_interrupts(|| {
+        let mut alarms = ALARMS.lock();
+        if alarms.len() < MAX_ALARMS {
+            alarms.insert(at);
+        }
+    });
+    program_next();
+}
+
+// Program the earliest pending alarm still ahead, or turn the alarm off
+fn program_next() {
+    let now = realtime_ns() / NS_PER_SEC;
+    let next = interrupts::without_interrupts(|| {
+        let mut alarms = ALARMS.lock();
+        // Passed alarms would only ring tomorrow
+        alarms.retain(|&at| at > now);
+        alarms.first().copied()
+    });
+
+    with_cmos(|| {
+        let format = read_reg(REG_B);
+        let Some(at) = next else {
+            write_reg(REG_B, format & !B_ALARM_INTERRUPT);
+            return;
+        };
+        let of_day = at % SECS_PER_DAY;
+        write_reg(REG_SECONDS_ALARM, encode((of_day % 60) as u8, format));
+        write_reg(REG_MINUTES_ALARM, encode((of_day / 60 % 60) as u8, format));
+        write_reg(REG_HOURS_ALARM, encode_hour((of_day / 3600) as u8, format));
+        write_reg(REG_B, format | B_ALARM_INTERRUPT);
+    });
+}
+
+// IRQ 8: reading register C acknowledges the interrupt
+pub fn handle_interrupt() {
+    let flags = with_cmos(|| read_reg(REG_C));
+    if flags & C_ALARM_FLAG != 0 {
+        program_next();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn rtc_date_and_register_formats() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        let time = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 23,
+            minute: 59,
+            second: 59,
+        };
+        assert_eq!(epoch_seconds(time), 1_709_251_199);
+
+        // BCD, 12-hour: 11 PM is 0x11 with the PM bit, midnight is 12 AM
+        assert_eq!(encode_hour(23, 0), 0x91);
+        assert_eq!(decode_hour(0x91, 0), 23);
+        assert_eq!(encode_hour(0, 0), 0x12);
+        assert_eq!(decode_hour(0x12, 0), 0);
+        assert_eq!(decode_hour(0x23, B_24_HOUR), 23);
+        assert_eq!(encode(59, B_BINARY | B_24_HOUR), 59);
+    }
+}
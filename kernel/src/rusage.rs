@@ -0,0 +1,117 @@
+// Resource Usage
+// CPU time, memory and page fault accounting of the running program, reported by
+// times(2) and getrusage(2).
+//
+// CPU time is measured with the TSC: each syscall adds the cycles it ran to the
+// system time, less those the CPU slept in `idle::wait_for` (blocking is neither
+// user nor system time), and the user time is what remains of the cycles since the
+// program started. Interrupts are charged to whatever they interrupted, as a
+// tick-based kernel would; time stopped under the debugger counts as user time.
+//
+// The resident set is counted in the page tables (user pages of the lower half).
+// Nothing is unmapped while a program runs, so the current size is also the
+// largest and sampling it when it is asked for is enough.
+
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::PhysAddr;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+
+// Page table entries of the user half of the address space
+const USER_PML4_ENTRIES: usize = 256;
+
+// TSC when the program started
+static START_TSC: AtomicU64 = AtomicU64::new(0);
+// Cycles spent in syscalls, and asleep in them
+static SYSTEM_CYCLES: AtomicU64 = AtomicU64::new(0);
+static SLEPT_CYCLES: AtomicU64 = AtomicU64::new(0);
+// Largest resident set seen, in pages
+static MAX_RSS_PAGES: AtomicU64 = AtomicU64::new(0);
+// Page faults taken in user mode
+static MINOR_FAULTS: AtomicU64 = AtomicU64::new(0);
+
+// Usage of the running program
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub user_us: u64,
+    pub system_us: u64,
+    pub max_rss_kib: u64,
+    pub minor_faults: u64,
+}
+
+// A new program starts from zero
+pub fn start() {
+    START_TSC.store(unsafe { _rdtsc() }, Ordering::Relaxed);
+    SYSTEM_CYCLES.store(0, Ordering::Relaxed);
+    SLEPT_CYCLES.store(0, Ordering::Relaxed);
+    MAX_RSS_PAGES.store(0, Ordering::Relaxed);
+    MINOR_FAULTS.store(0, Ordering::Relaxed);
+}
+
+// Stamp the start of a syscall, for `syscall_exit`
+pub fn syscall_enter() -> (u64, u64) {
+    (unsafe { _rdtsc() }, crate::idle::slept_cycles())
+}
+
+pub fn syscall_exit((start, slept_before): (u64, u64)) {
+    let elapsed = unsafe { _rdtsc() }.saturating_sub(start);
+    let slept = crate::idle::slept_cycles()
+        .saturating_sub(slept_before)
+        .min(elapsed);
+    SYSTEM_CYCLES.fetch_add(elapsed - slept, Ordering::Relaxed);
+    SLEPT_CYCLES.fetch_add(slept, Ordering::Relaxed);
+}
+
+// Count a page fault of the program
+pub fn page_fault() {
+    MINOR_FAULTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn usage() -> Usage {
+    let elapsed = unsafe { _rdtsc() }.saturating_sub(START_TSC.load(Ordering::Relaxed));
+    let system = SYSTEM_CYCLES.load(Ordering::Relaxed);
+    let user = elapsed
+        .saturating_sub(system)
+        .saturating_sub(SLEPT_CYCLES.load(Ordering::Relaxed));
+    let per_us = crate::delay::tsc_per_us();
+    let rss = resident_pages();
+    let max_rss = MAX_RSS_PAGES.fetch_max(rss, Ordering::Relaxed).max(rss);
+    Usage {
+        user_us: user.checked_div(per_us).unwrap_or(0),
+        system_us: system.checked_div(per_us).unwrap_or(0),
+        max_rss_kib: max_rss * 4,
+        minor_faults: MINOR_FAULTS.load(Ordering::Relaxed),
+    }
+}
+
+// User pages mapped in the current address space
+fn resident_pages() -> u64 {
+    let pml4 = crate::pml4::active_pml4(crate::elf_loader::get_hhdm_offset());
+    let table = table_at(pml4.start_address());
+    table
+        .iter()
+        .take(USER_PML4_ENTRIES)
+        .map(|entry| count_entry(entry, 4))
+        .sum()
+}
+
+fn table_at(addr: PhysAddr) -> &'static PageTable {
+    let hhdm = crate::elf_loader::get_hhdm_offset();
+    unsafe { &*((addr.as_u64() + hhdm) as *const PageTable) }
+}
+
+// Pages mapped under an entry of a level `level` table (4 = PML4)
+fn count_entry(entry: &PageTableEntry, level: u32) -> u64 {
+    let user = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if !entry.flags().contains(user) {
+        return 0;
+    }
+    if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return 512u64.pow(level - 1);
+    }
+    table_at(entry.addr())
+        .iter()
+        .map(|entry| count_entry(entry, level - 1))
+        .sum()
+}
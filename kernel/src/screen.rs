@@ -1,33 +1,361 @@
+use crate::font::{FontSize, PsfFont};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use core::fmt;
 use core::ptr;
-use font8x8::{BASIC_FONTS, UnicodeFonts};
-use lazy_static::lazy_static;
 use shared::framebuffer::{FrameBufferInfo, PixelFormat};
-use spin::Mutex;
+use shared::sync::IrqMutex;
+use x86_64::{
+    VirtAddr,
+    structures::paging::{
+        FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, mapper::MapToError,
+    },
+};
 
-const FONT_WIDTH: usize = 8;
-const FONT_HEIGHT: usize = 8;
+// Screens at least this wide get the large built-in font
+const LARGE_FONT_MIN_WIDTH: usize = 2560;
+
+// Virtual address of the RAM back buffer (double buffering)
+pub const BACK_BUFFER_START: u64 = 0xFFFF_B000_0000_0000;
+
+// Flush the back buffer every N timer ticks (1 tick = 1ms, ~60 Hz)
+pub const FLUSH_INTERVAL_TICKS: u64 = 16;
+
+// Redraw the status bar every second
+pub const STATUS_INTERVAL_TICKS: u64 = 1000;
+
+// Longest status bar text
+const STATUS_LINE_LEN: usize = 128;
+
+// There is a single console for now
+const CURRENT_VT: usize = 1;
+
+// Default console colors (white on blue), restored by `reset_style` and SGR 0
+pub const DEFAULT_TEXT_COLOR: u32 = 0xFFFFFF;
+pub const DEFAULT_BG_COLOR: u32 = 0x0000FF;
+
+// Standard ANSI colors (0-7) and their bright variants (8-15) as 0xRRGGBB
+pub const ANSI_PALETTE: [u32; 16] = [
+    0x000000, 0xAA0000, 0x00AA00, 0xAA5500, 0x0000AA, 0xAA00AA, 0x00AAAA, 0xAAAAAA, // 0-7
+    0x555555, 0xFF5555, 0x55FF55, 0xFFFF55, 0x5555FF, 0xFF55FF, 0x55FFFF, 0xFFFFFF, // 8-15
+];
+
+// Cell color: the theme's default, an entry of its 16-color palette, or fixed RGB
+// (SGR 38;2 and `set_text_color`). Palette and default colors follow theme changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Default,
+    Palette(u8),
+    Rgb(u32),
+}
+
+// Console colors as 0xRRGGBB
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Theme {
+    pub palette: [u32; 16],
+    pub foreground: u32,
+    pub background: u32,
+}
+
+pub const DEFAULT_THEME: Theme = Theme {
+    palette: ANSI_PALETTE,
+    foreground: DEFAULT_TEXT_COLOR,
+    background: DEFAULT_BG_COLOR,
+};
+
+// Bright red on black, for fatal errors
+pub const PANIC_THEME: Theme = Theme {
+    palette: ANSI_PALETTE,
+    foreground: 0xFF5555,
+    background: 0x000000,
+};
+
+// One character of the text buffer
+#[derive(Debug, Clone, Copy)]
+struct Cell {
+    c: char,
+    fg: Color,
+    bg: Color,
+}
+
+const BLANK_CELL: Cell = Cell {
+    c: ' ',
+    fg: Color::Default,
+    bg: Color::Default,
+};
+
+// Columns between tab stops
+const TAB_WIDTH: usize = 8;
+
+// Memory the glyph cache may use before it is emptied
+const GLYPH_CACHE_BYTES: usize = 64 * 1024;
+
+// Parameters kept per CSI sequence, extra ones are ignored
+const MAX_CSI_PARAMS: usize = 16;
+
+// Escape sequence parser state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Esc, // After ESC, waiting for '['
+    Csi, // Inside "ESC [", collecting parameters until the final byte
+}
+
+// Rectangle of the back buffer that changed since the last flush
+// (x1, y1 are exclusive)
+#[derive(Debug, Clone, Copy)]
+struct DirtyRect {
+    x0: usize,
+    y0: usize,
+    x1: usize,
+    y1: usize,
+}
+
+// Glyphs pre-rendered at the current scale, one pixel row after another, keyed by
+// character and (converted) text / background color. Needs the heap, so it is
+// enabled by `enable_glyph_cache` once the heap is up.
+struct GlyphCache {
+    glyphs: BTreeMap<(char, u32, u32), Vec<u32>>,
+    bytes: usize,
+}
+
+impl GlyphCache {
+    fn clear(&mut self) {
+        self.glyphs.clear();
+        self.bytes = 0;
+    }
+}
 
 pub struct FrameBufferWriter {
     info: FrameBufferInfo,
     x_pos: usize,
     y_pos: usize,
+    font: PsfFont,
     scale: usize,
+    theme: Theme,
+    fg: Color,
+    bg: Color,
+    // fg / bg resolved against the theme, in framebuffer pixel format
     text_color: u32,
     bg_color: u32,
+    // Text buffer: the character and colors of every cell at scale 1, row by row
+    // (empty until `init_text_buffer`). Text drawn at other scales is not recorded.
+    cells: Vec<Cell>,
+    cell_cols: usize,
+    // Pixel rows at the bottom taken by the status bar (0 = no status bar)
+    status_height: usize,
+    // Address of the RAM back buffer (0 = draw directly into the framebuffer)
+    back_buffer: u64,
+    dirty: Option<DirtyRect>,
+    // The back buffer is mapped into user space (/dev/fb0), whose stores mark
+    // nothing dirty: every flush copies the whole screen from then on
+    user_mapped: bool,
+    // ANSI escape sequence state (see `write_byte`)
+    escape: Escape,
+    params: [u16; MAX_CSI_PARAMS],
+    param_count: usize,
+    bold: bool,
+    saved_pos: (usize, usize),
+    // Partial UTF-8 sequence received by `write_byte`
+    utf8: [u8; 4],
+    utf8_len: usize,
+    utf8_need: usize,
+    glyph_cache: Option<GlyphCache>,
 }
 
 impl FrameBufferWriter {
+    // Buffer that all drawing goes to (back buffer if enabled, otherwise MMIO)
+    fn target(&self) -> *mut u32 {
+        if self.back_buffer != 0 {
+            self.back_buffer as *mut u32
+        } else {
+            self.info.buffer_base as *mut u32
+        }
+    }
+
+    // Grow the dirty rectangle to include the given region
+    fn mark_dirty(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        if self.back_buffer == 0 {
+            return;
+        }
+
+        let x1 = (x + width).min(self.info.width);
+        let y1 = (y + height).min(self.info.height);
+
+        self.dirty = Some(match self.dirty {
+            Some(rect) => DirtyRect {
+                x0: rect.x0.min(x),
+                y0: rect.y0.min(y),
+                x1: rect.x1.max(x1),
+                y1: rect.y1.max(y1),
+            },
+            None => DirtyRect {
+                x0: x,
+                y0: y,
+                x1,
+                y1,
+            },
+        });
+    }
+
+    // Switch to double buffering using the given (already mapped) RAM buffer
+    // The current framebuffer content is copied so nothing on screen is lost
+    unsafe fn attach_back_buffer(&mut self, addr: u64) {
+        let len = self.info.height * self.info.stride;
+        unsafe {
+            ptr::copy_nonoverlapping(self.info.buffer_base as *const u32, addr as *mut u32, len);
+        }
+        self.back_buffer = addr;
+        self.dirty = None;
+    }
+
+    // Copy the dirty region from the back buffer to the physical framebuffer
+    pub fn flush(&mut self) {
+        let full = DirtyRect {
+            x0: 0,
+            y0: 0,
+            x1: self.info.width,
+            y1: self.info.height,
+        };
+        let Some(rect) = self.dirty.take().or(self.user_mapped.then_some(full)) else {
+            return;
+        };
+
+        let back = self.back_buffer as *const u32;
+        let front = self.info.buffer_base as *mut u32;
+        let stride = self.info.stride;
+        let width = rect.x1.saturating_sub(rect.x0);
+
+        for y in rect.y0..rect.y1 {
+            let offset = y * stride + rect.x0;
+            unsafe {
+                ptr::copy_nonoverlapping(back.add(offset), front.add(offset), width);
+            }
+        }
+    }
+
     pub fn set_scale(&mut self, scale: usize) {
         self.scale = scale;
+        self.clear_glyph_cache();
+    }
+
+    pub fn set_font(&mut self, font: PsfFont) {
+        self.font = font;
+        self.clear_glyph_cache();
+        // The cell grid changed
+        if !self.cells.is_empty() {
+            self.alloc_cells();
+        }
+    }
+
+    fn clear_glyph_cache(&mut self) {
+        if let Some(cache) = &mut self.glyph_cache {
+            cache.clear();
+        }
     }
 
     pub fn set_text_color(&mut self, color: u32) {
-        self.text_color = self.convert_color(color);
+        self.set_colors(Color::Rgb(color), self.bg);
+    }
+
+    fn set_colors(&mut self, fg: Color, bg: Color) {
+        self.fg = fg;
+        self.bg = bg;
+        self.text_color = self.resolve(fg, self.theme.foreground);
+        self.bg_color = self.resolve(bg, self.theme.background);
     }
 
-    pub fn set_background_color(&mut self, color: u32) {
-        self.bg_color = self.convert_color(color);
+    // Pixel value of a color, `default` is the theme's color for Color::Default
+    fn resolve(&self, color: Color, default: u32) -> u32 {
+        self.convert_color(match color {
+            Color::Default => default,
+            Color::Palette(index) => self.theme.palette[index as usize & 0xF],
+            Color::Rgb(rgb) => rgb,
+        })
+    }
+
+    // Switch to a theme: the current style is reset to its default colors and
+    // the text buffer (if any) is redrawn with them
+    pub fn set_theme(&mut self, theme: Theme) {
+        self.theme = theme;
+        self.bold = false;
+        self.set_colors(Color::Default, Color::Default);
+        self.redraw_cells();
+    }
+
+    fn alloc_cells(&mut self) {
+        let (cols, rows) = (
+            (self.info.width / self.font.width).max(1),
+            (self.info.height / self.font.height).max(1),
+        );
+        let mut cells = Vec::new();
+        if cells.try_reserve_exact(cols * rows).is_err() {
+            return;
+        }
+        cells.resize(cols * rows, BLANK_CELL);
+        self.cells = cells;
+        self.cell_cols = cols;
+    }
+
+    // Index of the text buffer cell at a pixel position, if it starts a scale 1 cell
+    fn cell_index(&self, x: usize, y: usize) -> Option<usize> {
+        if self.cells.is_empty()
+            || self.scale != 1
+            || !x.is_multiple_of(self.font.width)
+            || !y.is_multiple_of(self.font.height)
+        {
+            return None;
+        }
+        let index = (y / self.font.height) * self.cell_cols + x / self.font.width;
+        (x / self.font.width < self.cell_cols && index < self.cells.len()).then_some(index)
+    }
+
+    fn put_cell(&mut self, x: usize, y: usize, c: char) {
+        if let Some(index) = self.cell_index(x, y) {
+            self.cells[index] = Cell {
+                c,
+                fg: self.fg,
+                bg: self.bg,
+            };
+        }
+    }
+
+    // Record a cleared pixel rectangle (in whole cells) in the text buffer
+    fn clear_cells(&mut self, x: usize, y: usize, x1: usize, y1: usize, bg: Color) {
+        if self.cells.is_empty() {
+            return;
+        }
+        let (cell_w, cell_h) = (self.font.width, self.font.height);
+        let rows = self.cells.len() / self.cell_cols;
+        let blank = Cell {
+            c: ' ',
+            fg: self.fg,
+            bg,
+        };
+        for row in y.div_ceil(cell_h)..(y1 / cell_h).min(rows) {
+            for col in x.div_ceil(cell_w)..(x1 / cell_w).min(self.cell_cols) {
+                self.cells[row * self.cell_cols + col] = blank;
+            }
+        }
+    }
+
+    // Draw every cell of the text buffer again (after a theme change)
+    fn redraw_cells(&mut self) {
+        if self.cells.is_empty() {
+            return;
+        }
+        let (fg, bg, scale) = (self.fg, self.bg, self.scale);
+        self.scale = 1;
+        for index in 0..self.cells.len() {
+            let cell = self.cells[index];
+            self.text_color = self.resolve(cell.fg, self.theme.foreground);
+            self.bg_color = self.resolve(cell.bg, self.theme.background);
+            let x = (index % self.cell_cols) * self.font.width;
+            let y = (index / self.cell_cols) * self.font.height;
+            self.draw_char(x, y, cell.c);
+        }
+        self.scale = scale;
+        self.set_colors(fg, bg);
     }
 
     fn convert_color(&self, color: u32) -> u32 {
@@ -50,7 +378,7 @@ impl FrameBufferWriter {
     }
 
     pub fn clear(&mut self, color: u32) {
-        let buffer = self.info.buffer_base as *mut u32;
+        let buffer = self.target();
         unsafe {
             for y in 0..self.info.height {
                 let row_start = buffer.add(y * self.info.stride);
@@ -59,41 +387,353 @@ impl FrameBufferWriter {
                 }
             }
         }
+        self.mark_dirty(0, 0, self.info.width, self.info.height);
+        self.clear_cells(0, 0, self.info.width, self.info.height, Color::Rgb(color));
         self.x_pos = 0;
         self.y_pos = 0;
     }
 
+    // Write one byte of UTF-8 text; sequences may be split across calls
+    // Malformed sequences are shown as the replacement character
     pub fn write_byte(&mut self, byte: u8) {
-        let scaled_width = FONT_WIDTH * self.scale;
-        match byte {
-            b'\n' => self.fill_remainder(),
+        if self.utf8_need > 0 {
+            if byte & 0xC0 == 0x80 {
+                self.utf8[self.utf8_len] = byte;
+                self.utf8_len += 1;
+                if self.utf8_len == self.utf8_need {
+                    // from_utf8 also rejects overlong encodings and surrogates
+                    let c = core::str::from_utf8(&self.utf8[..self.utf8_len])
+                        .ok()
+                        .and_then(|s| s.chars().next())
+                        .unwrap_or(char::REPLACEMENT_CHARACTER);
+                    self.utf8_need = 0;
+                    self.write_char(c);
+                }
+                return;
+            }
+            // Truncated sequence: the new byte starts over
+            self.utf8_need = 0;
+            self.write_char(char::REPLACEMENT_CHARACTER);
+        }
+
+        let need = match byte {
+            0x00..=0x7F => return self.write_char(byte as char),
+            0xC2..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF4 => 4,
+            _ => return self.write_char(char::REPLACEMENT_CHARACTER),
+        };
+        self.utf8[0] = byte;
+        self.utf8_len = 1;
+        self.utf8_need = need;
+    }
+
+    // Write one character, interpreting ANSI escape sequences:
+    //   ESC [ n A/B/C/D   cursor up/down/forward/back     ESC [ r;c H (or f)  cursor position
+    //   ESC [ n J         clear screen (0 below, 1 above, 2 all)
+    //   ESC [ n K         clear line (0 right, 1 left, 2 all)
+    //   ESC [ ... m       SGR colors: 0 reset, 1 bold, 30-37/90-97 text, 40-47/100-107
+    //                     background, 39/49 default, 38/48;5;n 256 colors, 38/48;2;r;g;b
+    //   ESC [ s / u       save / restore cursor
+    // Unsupported sequences are consumed and ignored, a non-ASCII character ends them.
+    // Control characters: \n new line, \r start of line, \x08 erase the previous cell,
+    // \t next tab stop (every TAB_WIDTH columns); BEL and the others are not drawn.
+    pub fn write_char(&mut self, c: char) {
+        match self.escape {
+            Escape::None => {}
+            _ if !c.is_ascii() => self.escape = Escape::None,
+            Escape::Esc => {
+                self.escape = if c == '[' {
+                    self.params = [0; MAX_CSI_PARAMS];
+                    self.param_count = 1;
+                    Escape::Csi
+                } else {
+                    Escape::None
+                };
+                return;
+            }
+            Escape::Csi => {
+                self.csi_byte(c as u8);
+                return;
+            }
+        }
+
+        let (scaled_width, _) = self.cell_size();
+        match c {
+            '\x1B' => self.escape = Escape::Esc,
+
+            '\n' => self.fill_remainder(),
+
+            '\r' => self.x_pos = 0,
 
-            byte => {
+            '\x08' => {
+                if self.x_pos >= scaled_width {
+                    self.x_pos -= scaled_width;
+                    let (_, cell_h) = self.cell_size();
+                    self.fill_rect(self.x_pos, self.y_pos, scaled_width, cell_h);
+                }
+            }
+
+            '\t' => {
+                let tab = scaled_width * TAB_WIDTH;
+                let next = (self.x_pos / tab + 1) * tab;
+                if next + scaled_width > self.info.width {
+                    self.fill_remainder();
+                } else {
+                    self.x_pos = next;
+                }
+            }
+
+            // BEL (there is no speaker) and the other control characters
+            c if c.is_control() => {}
+
+            c => {
                 if self.x_pos + scaled_width > self.info.width {
                     self.new_line();
                 }
-                self.draw_char(self.x_pos, self.y_pos, byte as char);
+                self.draw_char(self.x_pos, self.y_pos, c);
+                self.put_cell(self.x_pos, self.y_pos, c);
                 self.x_pos += scaled_width;
             }
         }
     }
 
+    fn csi_byte(&mut self, byte: u8) {
+        match byte {
+            b'0'..=b'9' => {
+                let param = &mut self.params[self.param_count - 1];
+                *param = param
+                    .saturating_mul(10)
+                    .saturating_add((byte - b'0') as u16);
+            }
+            b';' => self.param_count = (self.param_count + 1).min(MAX_CSI_PARAMS),
+            // Final byte
+            0x40..=0x7E => {
+                self.escape = Escape::None;
+                self.execute_csi(byte);
+            }
+            // Private markers ('?') and intermediate bytes
+            _ => {}
+        }
+    }
+
+    // Parameter `index`, with 0 or a missing parameter replaced by `default`
+    fn param(&self, index: usize, default: u16) -> usize {
+        match self.params[index] {
+            0 => default as usize,
+            value => value as usize,
+        }
+    }
+
+    // Height of the text area: the screen without the status bar
+    fn text_height(&self) -> usize {
+        self.info.height - self.status_height
+    }
+
+    // Reserve the bottom row for the status bar, scrolling the text out of it
+    fn enable_status_bar(&mut self) {
+        let (_, cell_h) = self.cell_size();
+        self.status_height = self.font.height;
+        while self.y_pos > 0 && self.y_pos + cell_h > self.text_height() {
+            self.scroll_up();
+            self.y_pos -= cell_h;
+        }
+    }
+
+    // Draw the status bar text in reverse video without moving the cursor
+    fn draw_status(&mut self, text: &str) {
+        if self.status_height == 0 {
+            return;
+        }
+        let saved = (self.x_pos, self.y_pos, self.scale, self.fg, self.bg);
+        self.scale = 1;
+        self.set_colors(
+            Color::Rgb(self.theme.background),
+            Color::Rgb(self.theme.foreground),
+        );
+
+        let y = self.text_height();
+        let mut chars = text.chars();
+        let mut x = 0;
+        while x + self.font.width <= self.info.width {
+            self.draw_char(x, y, chars.next().unwrap_or(' '));
+            x += self.font.width;
+        }
+
+        let (x_pos, y_pos, scale, fg, bg) = saved;
+        (self.x_pos, self.y_pos, self.scale) = (x_pos, y_pos, scale);
+        self.set_colors(fg, bg);
+    }
+
+    fn cell_size(&self) -> (usize, usize) {
+        (self.font.width * self.scale, self.font.height * self.scale)
+    }
+
+    // Text columns and rows of the screen
+    fn text_size(&self) -> (usize, usize) {
+        let (cell_w, cell_h) = self.cell_size();
+        (
+            (self.info.width / cell_w).max(1),
+            (self.text_height() / cell_h).max(1),
+        )
+    }
+
+    // Move the cursor to a (0-based, clamped) text cell
+    fn move_to(&mut self, col: usize, row: usize) {
+        let (cols, rows) = self.text_size();
+        let (cell_w, cell_h) = self.cell_size();
+        self.x_pos = col.min(cols - 1) * cell_w;
+        self.y_pos = row.min(rows - 1) * cell_h;
+    }
+
+    fn execute_csi(&mut self, command: u8) {
+        let (cell_w, cell_h) = self.cell_size();
+        let col = self.x_pos / cell_w;
+        let row = self.y_pos / cell_h;
+        let n = self.param(0, 1);
+
+        match command {
+            b'A' => self.move_to(col, row.saturating_sub(n)),
+            b'B' => self.move_to(col, row + n),
+            b'C' => self.move_to(col + n, row),
+            b'D' => self.move_to(col.saturating_sub(n), row),
+            b'H' | b'f' => self.move_to(self.param(1, 1) - 1, self.param(0, 1) - 1),
+            b'J' => {
+                let (width, height) = (self.info.width, self.text_height());
+                let line_end = self.y_pos + cell_h;
+                match self.params[0] {
+                    0 => {
+                        self.fill_rect(self.x_pos, self.y_pos, width - self.x_pos, cell_h);
+                        self.fill_rect(0, line_end, width, height.saturating_sub(line_end));
+                    }
+                    1 => {
+                        self.fill_rect(0, 0, width, self.y_pos);
+                        self.fill_rect(0, self.y_pos, self.x_pos + cell_w, cell_h);
+                    }
+                    _ => self.fill_rect(0, 0, width, height),
+                }
+            }
+            b'K' => {
+                let width = self.info.width;
+                match self.params[0] {
+                    0 => self.fill_rect(self.x_pos, self.y_pos, width - self.x_pos, cell_h),
+                    1 => self.fill_rect(0, self.y_pos, self.x_pos + cell_w, cell_h),
+                    _ => self.fill_rect(0, self.y_pos, width, cell_h),
+                }
+            }
+            b'm' => self.select_graphic_rendition(),
+            b's' => self.saved_pos = (self.x_pos, self.y_pos),
+            b'u' => (self.x_pos, self.y_pos) = self.saved_pos,
+            _ => {}
+        }
+    }
+
+    fn select_graphic_rendition(&mut self) {
+        let mut i = 0;
+        while i < self.param_count {
+            match self.params[i] {
+                0 => {
+                    self.bold = false;
+                    self.set_colors(Color::Default, Color::Default);
+                }
+                1 => self.bold = true,
+                22 => self.bold = false,
+                code @ 30..=37 => {
+                    let index = (code - 30) as u8 + if self.bold { 8 } else { 0 };
+                    self.set_colors(Color::Palette(index), self.bg);
+                }
+                39 => self.set_colors(Color::Default, self.bg),
+                code @ 40..=47 => self.set_colors(self.fg, Color::Palette((code - 40) as u8)),
+                49 => self.set_colors(self.fg, Color::Default),
+                code @ 90..=97 => self.set_colors(Color::Palette((code - 90) as u8 + 8), self.bg),
+                code @ 100..=107 => {
+                    self.set_colors(self.fg, Color::Palette((code - 100) as u8 + 8))
+                }
+                code @ (38 | 48) => {
+                    let (color, used) = self.extended_color(i + 1);
+                    if let Some(color) = color {
+                        if code == 38 {
+                            self.set_colors(color, self.bg);
+                        } else {
+                            self.set_colors(self.fg, color);
+                        }
+                    }
+                    i += used;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+
+    // Color of "38;5;n" / "38;2;r;g;b" starting after the 38/48, and the parameters used
+    fn extended_color(&self, start: usize) -> (Option<Color>, usize) {
+        let arg = |i: usize| self.params.get(start + i).copied().unwrap_or(0) as u32;
+        match arg(0) {
+            5 => {
+                let n = arg(1).min(255);
+                let rgb = match n {
+                    0..=15 => return (Some(Color::Palette(n as u8)), 2),
+                    // 6x6x6 color cube
+                    16..=231 => {
+                        let level = |v: u32| if v == 0 { 0 } else { 55 + v * 40 };
+                        let v = n - 16;
+                        (level(v / 36) << 16) | (level(v / 6 % 6) << 8) | level(v % 6)
+                    }
+                    // Grayscale ramp
+                    _ => {
+                        let gray = 8 + (n - 232) * 10;
+                        (gray << 16) | (gray << 8) | gray
+                    }
+                };
+                (Some(Color::Rgb(rgb)), 2)
+            }
+            2 => {
+                let rgb = (arg(1).min(255) << 16) | (arg(2).min(255) << 8) | arg(3).min(255);
+                (Some(Color::Rgb(rgb)), 4)
+            }
+            _ => (None, 0),
+        }
+    }
+
+    // Fill a pixel rectangle (clipped to the screen) with the background color
+    fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        let x1 = (x + width).min(self.info.width);
+        let y1 = (y + height).min(self.text_height());
+        if x >= x1 || y >= y1 {
+            return;
+        }
+
+        let buffer = self.target();
+        let stride = self.info.stride;
+        unsafe {
+            for dy in y..y1 {
+                let row_start = buffer.add(dy * stride);
+                for dx in x..x1 {
+                    *row_start.add(dx) = self.bg_color;
+                }
+            }
+        }
+        self.mark_dirty(x, y, x1 - x, y1 - y);
+        self.clear_cells(x, y, x1, y1, self.bg);
+    }
+
     fn new_line(&mut self) {
-        let scaled_height = FONT_HEIGHT * self.scale;
+        let (_, scaled_height) = self.cell_size();
         self.x_pos = 0;
         self.y_pos += scaled_height;
 
-        if self.y_pos + scaled_height > self.info.height {
+        if self.y_pos + scaled_height > self.text_height() {
             self.scroll_up();
             self.y_pos -= scaled_height;
         }
     }
 
     fn scroll_up(&mut self) {
-        let scaled_height = FONT_HEIGHT * self.scale;
+        let (_, scaled_height) = self.cell_size();
         let stride = self.info.stride;
-        let height = self.info.height;
-        let buffer = self.info.buffer_base as *mut u32;
+        let height = self.text_height();
+        let buffer = self.target();
 
         unsafe {
             // Copy the entire screen up
@@ -110,20 +750,115 @@ impl FrameBufferWriter {
                 *last_line_start.add(i) = self.bg_color;
             }
         }
+
+        // Scrolling moves every pixel
+        self.mark_dirty(0, 0, self.info.width, height);
+
+        if !self.cells.is_empty() {
+            let shift = (scaled_height / self.font.height * self.cell_cols).min(self.cells.len());
+            let len = self.cells.len();
+            self.cells.copy_within(shift.., 0);
+            let blank = Cell {
+                c: ' ',
+                fg: self.fg,
+                bg: self.bg,
+            };
+            self.cells[len - shift..].fill(blank);
+        }
     }
 
     fn draw_char(&mut self, x: usize, y: usize, c: char) {
-        let bitmap = match BASIC_FONTS.get(c) {
-            Some(glyph) => glyph,
-            None => return,
+        let (cell_w, cell_h) = self.cell_size();
+        let fits = x + cell_w <= self.info.width && y + cell_h <= self.info.height;
+        if !(fits && self.draw_cached(x, y, c)) {
+            self.draw_char_pixels(x, y, c);
+        }
+        self.mark_dirty(x, y, cell_w, cell_h);
+    }
+
+    // Render a glyph at the current scale and colors into a pixel block
+    // None if the font has no glyph or the heap is out of memory
+    fn render_glyph(&self, c: char) -> Option<Vec<u32>> {
+        let bitmap = self.font.glyph_or_replacement(c)?;
+        let (cell_w, cell_h) = self.cell_size();
+        let mut pixels = Vec::new();
+        pixels.try_reserve_exact(cell_w * cell_h).ok()?;
+
+        for row in bitmap.chunks_exact(self.font.bytes_per_row()) {
+            let start = pixels.len();
+            for col_idx in 0..self.font.width {
+                // Bits are stored MSB first (leftmost pixel)
+                let color = if row[col_idx / 8] & (0x80 >> (col_idx % 8)) != 0 {
+                    self.text_color
+                } else {
+                    self.bg_color
+                };
+                for _ in 0..self.scale {
+                    pixels.push(color);
+                }
+            }
+            // Repeat the scaled row for the other scale lines
+            for _ in 1..self.scale {
+                pixels.extend_from_within(start..start + cell_w);
+            }
+        }
+        Some(pixels)
+    }
+
+    // Copy a glyph from the cache (rendering it first if needed), one row at a time
+    // Returns false if the cache is disabled or the glyph could not be cached
+    fn draw_cached(&mut self, x: usize, y: usize, c: char) -> bool {
+        let Some(cache) = &self.glyph_cache else {
+            return false;
         };
+        let key = (c, self.text_color, self.bg_color);
+        if !cache.glyphs.contains_key(&key) {
+            let Some(pixels) = self.render_glyph(c) else {
+                return false;
+            };
+            let Some(cache) = &mut self.glyph_cache else {
+                return false;
+            };
+            let size = pixels.len() * core::mem::size_of::<u32>();
+            if cache.bytes + size > GLYPH_CACHE_BYTES {
+                cache.clear();
+            }
+            cache.bytes += size;
+            cache.glyphs.insert(key, pixels);
+        }
 
-        let buffer = self.info.buffer_base as *mut u32;
+        let Some(pixels) = self.glyph_cache.as_ref().and_then(|c| c.glyphs.get(&key)) else {
+            return false;
+        };
+        let (cell_w, _) = self.cell_size();
+        let buffer = self.target();
         let stride = self.info.stride;
+        for (row_idx, row) in pixels.chunks_exact(cell_w).enumerate() {
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    row.as_ptr(),
+                    buffer.add((y + row_idx) * stride + x),
+                    cell_w,
+                );
+            }
+        }
+        true
+    }
 
-        for (row_idx, &row_byte) in bitmap.iter().enumerate() {
-            for col_idx in 0..8 {
-                let bit_is_set = (row_byte & (1 << col_idx)) != 0;
+    // Draw a glyph pixel by pixel, clipped to the screen
+    fn draw_char_pixels(&mut self, x: usize, y: usize, c: char) {
+        let Some(bitmap) = self.font.glyph_or_replacement(c) else {
+            return;
+        };
+
+        let buffer = self.target();
+        let stride = self.info.stride;
+        let bytes_per_row = self.font.bytes_per_row();
+
+        for (row_idx, row) in bitmap.chunks_exact(bytes_per_row).enumerate() {
+            for col_idx in 0..self.font.width {
+                // Bits are stored MSB first (leftmost pixel)
+                let bit_is_set = row[col_idx / 8] & (0x80 >> (col_idx % 8)) != 0;
 
                 let pixel_color = if bit_is_set {
                     self.text_color
@@ -148,25 +883,14 @@ impl FrameBufferWriter {
     }
 
     fn fill_remainder(&mut self) {
-        let scaled_height = FONT_HEIGHT * self.scale;
-        let buffer = self.info.buffer_base as *mut u32;
-        let stride = self.info.stride;
-
+        let (_, scaled_height) = self.cell_size();
         if self.x_pos < self.info.width {
-            unsafe {
-                for y_offset in 0..scaled_height {
-                    let dy = self.y_pos + y_offset;
-                    if dy >= self.info.height {
-                        break;
-                    }
-
-                    let row_start = buffer.add(dy * stride);
-
-                    for x in self.x_pos..self.info.width {
-                        *row_start.add(x) = self.bg_color;
-                    }
-                }
-            }
+            self.fill_rect(
+                self.x_pos,
+                self.y_pos,
+                self.info.width - self.x_pos,
+                scaled_height,
+            );
         }
         self.new_line();
     }
@@ -175,26 +899,397 @@ impl FrameBufferWriter {
 impl fmt::Write for FrameBufferWriter {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         for c in s.chars() {
-            self.write_byte(c as u8);
+            self.write_char(c);
         }
         Ok(())
     }
 }
 
-lazy_static! {
-    pub static ref WRITER: Mutex<Option<FrameBufferWriter>> = Mutex::new(None);
-}
+// Filled in by `init`
+pub static WRITER: IrqMutex<Option<FrameBufferWriter>> = IrqMutex::new(None);
 
 pub fn init(info: FrameBufferInfo) {
+    let font_size = if info.width >= LARGE_FONT_MIN_WIDTH {
+        FontSize::Large
+    } else {
+        FontSize::Normal
+    };
+
     let mut writer = WRITER.lock();
     *writer = Some(FrameBufferWriter {
         info,
         x_pos: 0,
         y_pos: 0,
-        scale: 2,
-        text_color: 0xFFFFFF,
-        bg_color: 0x0000FF,
+        font: PsfFont::builtin(font_size),
+        scale: 1,
+        theme: DEFAULT_THEME,
+        fg: Color::Default,
+        bg: Color::Default,
+        text_color: 0,
+        bg_color: 0,
+        cells: Vec::new(),
+        cell_cols: 0,
+        status_height: 0,
+        back_buffer: 0,
+        dirty: None,
+        user_mapped: false,
+        escape: Escape::None,
+        params: [0; MAX_CSI_PARAMS],
+        param_count: 0,
+        bold: false,
+        saved_pos: (0, 0),
+        utf8: [0; 4],
+        utf8_len: 0,
+        utf8_need: 0,
+        glyph_cache: None,
     });
+    if let Some(writer) = writer.as_mut() {
+        writer.set_colors(Color::Default, Color::Default);
+    }
+}
+
+// Move the writer from the bootloader's framebuffer mapping to one owned by the
+// kernel (write-combining), so it survives the boot page tables going away
+// Must be called after the PMM and the page mapper are ready
+pub fn map_framebuffer(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), crate::mmio::MmioError> {
+    let mut lock = WRITER.lock();
+    let Some(writer) = lock.as_mut() else {
+        return Ok(());
+    };
+
+    writer.info.buffer_base = crate::mmio::map(
+        writer.info.buffer_phys,
+        writer.info.buffer_size as u64,
+        mapper,
+        frame_allocator,
+    )?;
+    Ok(())
+}
+
+// Allocate a RAM back buffer and switch the writer to double buffering
+// Must be called after the PMM and the page mapper are ready
+pub fn init_double_buffering(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let mut lock = WRITER.lock();
+    let Some(writer) = lock.as_mut() else {
+        return Ok(());
+    };
+
+    let size = (writer.info.height * writer.info.stride * 4) as u64;
+    let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(BACK_BUFFER_START));
+    let end_page =
+        Page::<Size4KiB>::containing_address(VirtAddr::new(BACK_BUFFER_START + size - 1));
+
+    // Map all pages of the back buffer (normal cacheable RAM)
+    for page in Page::range_inclusive(start_page, end_page) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+        }
+    }
+
+    unsafe { writer.attach_back_buffer(BACK_BUFFER_START) };
+    Ok(())
+}
+
+// Keep a text buffer of the screen (needed for `set_theme` to recolor existing text),
+// must be called after the heap is ready
+pub fn init_text_buffer() {
+    if let Some(writer) = &mut *WRITER.lock() {
+        writer.alloc_cells();
+    }
+}
+
+// Start caching pre-rendered glyphs, must be called after the heap is ready
+pub fn enable_glyph_cache() {
+    if let Some(writer) = &mut *WRITER.lock() {
+        writer.glyph_cache = Some(GlyphCache {
+            glyphs: BTreeMap::new(),
+            bytes: 0,
+        });
+    }
+}
+
+// Flush the dirty region of the back buffer to the screen
+pub fn flush() {
+    if let Some(writer) = &mut *WRITER.lock() {
+        writer.flush();
+    }
+}
+
+// Geometry of the framebuffer, for /dev/fb0
+#[cfg(feature = "userspace")]
+pub fn info() -> Option<FrameBufferInfo> {
+    WRITER.lock().as_ref().map(|writer| writer.info)
+}
+
+// Bytes of the screen, `height` rows of `stride` 32-bit pixels
+#[cfg(feature = "userspace")]
+pub fn size() -> usize {
+    info().map_or(0, |info| info.height * info.stride * 4)
+}
+
+// Text columns and rows of the console, for TIOCGWINSZ
+#[cfg(feature = "userspace")]
+pub fn text_size() -> Option<(usize, usize)> {
+    WRITER.lock().as_ref().map(|writer| writer.text_size())
+}
+
+// Copy from byte `offset` of the screen (the back buffer if there is one) to
+// `buf`; returns the bytes copied
+#[cfg(feature = "userspace")]
+pub fn read_pixels(offset: usize, buf: &mut [u8]) -> usize {
+    let lock = WRITER.lock();
+    let Some(writer) = lock.as_ref() else {
+        return 0;
+    };
+    let size = writer.info.height * writer.info.stride * 4;
+    let count = buf.len().min(size.saturating_sub(offset));
+    unsafe {
+        let source = (writer.target() as *const u8).add(offset);
+        ptr::copy_nonoverlapping(source, buf.as_mut_ptr(), count);
+    }
+    count
+}
+
+// Copy `buf` to byte `offset` of the screen, marking the rows it covers dirty
+#[cfg(feature = "userspace")]
+pub fn write_pixels(offset: usize, buf: &[u8]) -> usize {
+    let mut lock = WRITER.lock();
+    let Some(writer) = lock.as_mut() else {
+        return 0;
+    };
+    let row = writer.info.stride * 4;
+    let count = buf
+        .len()
+        .min((writer.info.height * row).saturating_sub(offset));
+    if count == 0 {
+        return 0;
+    }
+    unsafe {
+        let target = (writer.target() as *mut u8).add(offset);
+        ptr::copy_nonoverlapping(buf.as_ptr(), target, count);
+    }
+    let (y0, y1) = (offset / row, (offset + count - 1) / row + 1);
+    writer.mark_dirty(0, y0, writer.info.width, y1 - y0);
+    count
+}
+
+// Physical frame of page `index` of the back buffer, to map it into user space
+// None without double buffering: the framebuffer itself needs write-combining
+#[cfg(feature = "userspace")]
+pub fn map_back_buffer_page(index: u64) -> Option<x86_64::structures::paging::PhysFrame> {
+    use x86_64::structures::paging::{PhysFrame, Translate};
+
+    let mut lock = WRITER.lock();
+    let writer = lock.as_mut()?;
+    let size = (writer.info.height * writer.info.stride * 4) as u64;
+    if writer.back_buffer == 0 || index >= size.div_ceil(4096) {
+        return None;
+    }
+    let mapper = unsafe { crate::pml4::init_mapper(crate::elf_loader::get_hhdm_offset()) };
+    let phys = mapper.translate_addr(VirtAddr::new(writer.back_buffer + index * 4096))?;
+    writer.user_mapped = true;
+    Some(PhysFrame::containing_address(phys))
+}
+
+// Show the status bar on the bottom text row
+pub fn enable_status_bar() {
+    if let Some(writer) = &mut *WRITER.lock() {
+        writer.enable_status_bar();
+    }
+    update_status_from_interrupt();
+}
+
+// Fixed buffer for the status line: it is formatted in the timer interrupt
+struct StatusLine {
+    buf: [u8; STATUS_LINE_LEN],
+    len: usize,
+}
+
+impl fmt::Write for StatusLine {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Text past the end is dropped, the bar is one row anyway
+        let count = s.len().min(STATUS_LINE_LEN - self.len);
+        self.buf[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+// Redraw the status bar (uptime, free memory, idle time, console), called by the timer
+// Skipped if the writer or the PMM is busy, the next refresh catches up
+pub fn update_status_from_interrupt() {
+    use core::fmt::Write;
+
+    let Some(mut lock) = WRITER.try_lock() else {
+        return;
+    };
+    let Some(writer) = lock.as_mut() else {
+        return;
+    };
+    if writer.status_height == 0 {
+        return;
+    }
+    let Some(frames) = crate::pmm::try_stats() else {
+        return;
+    };
+
+    let seconds = crate::interrupts::TICKS.load(core::sync::atomic::Ordering::Relaxed) / 1000;
+    let mib = |frames: usize| frames * crate::pmm::PAGE_SIZE as usize / (1024 * 1024);
+    let mut line = StatusLine {
+        buf: [0; STATUS_LINE_LEN],
+        len: 0,
+    };
+    let _ = write!(
+        line,
+        " up {}:{:02}:{:02} | mem {} / {} MiB free | idle {}% | vt{}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        mib(frames.free),
+        mib(frames.total),
+        crate::idle::sample_idle_percent(),
+        CURRENT_VT
+    );
+    // Only ASCII is written, so any prefix is valid UTF-8
+    let text = core::str::from_utf8(&line.buf[..line.len]).unwrap_or("");
+    writer.draw_status(text);
+}
+
+// Box around panic output: text rows of `width` columns between two borders
+struct PanicBox<'a> {
+    writer: &'a mut FrameBufferWriter,
+    width: usize,
+    col: usize,
+}
+
+impl PanicBox<'_> {
+    // Horizontal border, with an optional title after the corner
+    fn border(&mut self, left: char, right: char, title: &str) {
+        self.writer.write_char(left);
+        let mut count = 0;
+        for c in title.chars().take(self.width + 2) {
+            self.writer.write_char(c);
+            count += 1;
+        }
+        for _ in count..self.width + 2 {
+            self.writer.write_char('─');
+        }
+        self.writer.write_char(right);
+        self.writer.write_char('\n');
+    }
+
+    fn start_row(&mut self) {
+        self.writer.write_char('│');
+        self.writer.write_char(' ');
+        self.col = 0;
+    }
+
+    fn end_row(&mut self) {
+        for _ in self.col..self.width {
+            self.writer.write_char(' ');
+        }
+        self.writer.write_char(' ');
+        self.writer.write_char('│');
+        self.writer.write_char('\n');
+    }
+}
+
+impl fmt::Write for PanicBox<'_> {
+    // Writes into the current row, wrapping long lines; '\n' starts a new row
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if c == '\n' || self.col == self.width {
+                self.end_row();
+                self.start_row();
+            }
+            if c != '\n' {
+                self.writer.write_char(c);
+                self.col += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Show a panic on screen: the console switches to PANIC_THEME and the message,
+// location and backtrace are drawn in a box below the last output.
+// Runs in the panic handler: takes the writer even if the panicking code held it,
+// and must not allocate (the heap may be what failed).
+pub fn panic_screen(info: &core::panic::PanicInfo, backtrace: &[u64]) {
+    use core::fmt::Write;
+
+    let mut lock = match WRITER.try_lock() {
+        Some(lock) => lock,
+        None => unsafe {
+            WRITER.force_unlock();
+            WRITER.lock()
+        },
+    };
+    let Some(writer) = lock.as_mut() else {
+        return;
+    };
+
+    // Drawing through the glyph cache would allocate; leak it instead of freeing
+    core::mem::forget(writer.glyph_cache.take());
+    writer.escape = Escape::None;
+    writer.utf8_need = 0;
+    writer.scale = 1;
+    writer.set_theme(PANIC_THEME);
+    if writer.x_pos != 0 {
+        writer.write_char('\n');
+    }
+
+    let (cols, _) = writer.text_size();
+    let width = cols.min(80).saturating_sub(4).max(1);
+    let mut panic_box = PanicBox {
+        writer,
+        width,
+        col: 0,
+    };
+
+    panic_box.border('┌', '┐', " KERNEL PANIC ");
+    panic_box.start_row();
+    let _ = write!(panic_box, "{}", info.message());
+    if let Some(location) = info.location() {
+        let _ = write!(
+            panic_box,
+            "\nat {}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        );
+    }
+    if !backtrace.is_empty() {
+        let _ = write!(panic_box, "\n\nbacktrace:");
+        for (i, addr) in backtrace.iter().enumerate() {
+            let _ = write!(panic_box, "\n  #{:<2} {}", i, crate::symbols::Frame(*addr));
+        }
+    }
+    panic_box.end_row();
+    panic_box.border('└', '┘', "");
+
+    writer.flush();
+}
+
+// Flush called from the timer interrupt
+// Uses try_lock: if the writer is busy, the next tick will flush instead
+pub fn flush_from_interrupt() {
+    if let Some(mut lock) = WRITER.try_lock()
+        && let Some(writer) = lock.as_mut()
+    {
+        writer.flush();
+    }
 }
 
 // ==========================================
@@ -209,10 +1304,26 @@ pub fn set_text_color(color: u32) {
     }
 }
 
-// Set background color for the next print
-pub fn set_background_color(color: u32) {
+// Replace the console font (e.g. one loaded by the bootloader)
+pub fn set_font(font: PsfFont) {
     if let Some(writer) = &mut *WRITER.lock() {
-        writer.set_background_color(color);
+        writer.set_font(font);
+    }
+}
+
+// Switch to the PSF font loaded by the bootloader, if any
+// The file stays in LOADER_DATA pages, which the PMM keeps reserved
+pub fn load_boot_font(phys_addr: u64, size: u64, hhdm_offset: u64) {
+    if phys_addr == 0 || size == 0 {
+        return;
+    }
+
+    let data = unsafe {
+        core::slice::from_raw_parts((phys_addr + hhdm_offset) as *const u8, size as usize)
+    };
+    match PsfFont::parse(data) {
+        Ok(font) => set_font(font),
+        Err(err) => log::warn!("Invalid font.psf: {:?}", err),
     }
 }
 
@@ -230,18 +1341,19 @@ pub fn clear_screen(color: u32) {
     }
 }
 
-// Reset to default (White text, Scale 2, Blue background)
+// Reset to default (White text, Scale 1, Blue background)
 pub fn reset_style() {
     if let Some(writer) = &mut *WRITER.lock() {
-        writer.set_text_color(0xFFFFFF);
-        writer.set_scale(2);
-        writer.set_background_color(0x0000FF);
+        writer.set_scale(1);
+        writer.set_colors(Color::Default, Color::Default);
     }
 }
 
-// Print a single character
-pub fn print_char(c: char) {
+// Print raw UTF-8 bytes (e.g. from a user write)
+pub fn print_bytes(bytes: &[u8]) {
     if let Some(writer) = &mut *WRITER.lock() {
-        writer.write_byte(c as u8);
+        for &byte in bytes {
+            writer.write_byte(byte);
+        }
     }
 }
@@ -0,0 +1,1069 @@
+// Kernel Shell
+// A command line on the console, entered when the user program exits (or stops
+// under `dbg`, see ptrace.rs). Input comes
+// from the PS/2 keyboard and from serial (so it works over `-serial stdio`), output
+// goes to both through `println!`.
+//
+// Line editing: Left/Right, Home/End (and Ctrl-A / Ctrl-E), Backspace, Delete,
+// Ctrl-U (clear line), Ctrl-C (discard line), Up/Down to walk the history.
+// The line is redrawn with ANSI escapes, understood by the screen and serial terminals.
+//
+// There is no scheduler: the shell runs on the kernel stack it was entered from, and
+// `run` replaces it with the program until that program exits.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "userspace")]
+use core::convert::Infallible;
+use core::sync::atomic::Ordering;
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1, layouts};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const PROMPT: &str = "> ";
+
+// Lines kept for Up/Down
+const HISTORY_LEN: usize = 32;
+
+// Directory searched by `run` for names without a '/'
+#[cfg(feature = "userspace")]
+const PROGRAM_DIR: &str = "/bin";
+
+// Path of the first program, written by build-initramfs.sh
+#[cfg(feature = "userspace")]
+const INIT_MANIFEST: &str = "/etc/init";
+
+// PS/2 controller command port and its "pulse reset line" command
+const PS2_COMMAND_PORT: u16 = 0x64;
+const PS2_RESET_CPU: u8 = 0xFE;
+const PS2_INPUT_FULL: u8 = 0x02;
+
+// ASCII control characters produced by the keyboard decoder and serial terminals
+const CTRL_A: char = '\x01';
+const CTRL_C: char = '\x03';
+const CTRL_E: char = '\x05';
+const CTRL_U: char = '\x15';
+const BACKSPACE: char = '\x08';
+const DELETE: char = '\x7F';
+const ESCAPE: char = '\x1B';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    ClearLine,
+    Cancel,
+}
+
+// Decoder state for VT100 sequences arriving on serial (ESC [ A etc.)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SerialEscape {
+    None,
+    Esc,
+    Csi,
+    // ESC [ <digit>, waiting for the final '~'
+    Tilde(u8),
+}
+
+struct Input {
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+    serial_escape: SerialEscape,
+    // The terminal sends "\r\n" or just "\r" for Enter
+    last_was_cr: bool,
+}
+
+// Last program started by `run` (or the boot program) and its exit status
+struct Program {
+    name: String,
+    status: Option<u64>,
+}
+
+static INPUT: Mutex<Input> = Mutex::new(Input {
+    keyboard: Keyboard::new(
+        ScancodeSet1::new(),
+        layouts::Us104Key,
+        HandleControl::MapLettersToUnicode,
+    ),
+    serial_escape: SerialEscape::None,
+    last_was_cr: false,
+});
+
+static HISTORY: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+static PROGRAM: Mutex<Option<Program>> = Mutex::new(None);
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    run: fn(&[&str]),
+}
+
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help                list commands",
+        run: cmd_help,
+    },
+    Command {
+        name: "mem",
+        usage: "mem                 physical memory and heap usage",
+        run: cmd_mem,
+    },
+    Command {
+        name: "ps",
+        usage: "ps                  tasks",
+        run: cmd_ps,
+    },
+    Command {
+        name: "lsdev",
+        usage: "lsdev               device tree",
+        run: cmd_lsdev,
+    },
+    Command {
+        name: "dmesg",
+        usage: "dmesg [--crash]     kernel log (or the dumps of the crash log)",
+        run: cmd_dmesg,
+    },
+    #[cfg(feature = "userspace")]
+    Command {
+        name: "run",
+        usage: "run [--caps <list>] <program>  start a program (name in /bin or a path), with only the listed capabilities",
+        run: cmd_run,
+    },
+    #[cfg(feature = "userspace")]
+    Command {
+        name: "dbg",
+        usage: "dbg <command>       debug a program (`dbg` alone for commands)",
+        run: cmd_dbg,
+    },
+    Command {
+        name: "insmod",
+        usage: "insmod <module>     load a kernel module (name in /lib/modules or a path)",
+        run: cmd_insmod,
+    },
+    Command {
+        name: "rmmod",
+        usage: "rmmod <name>        unload a kernel module",
+        run: cmd_rmmod,
+    },
+    Command {
+        name: "lsmod",
+        usage: "lsmod               loaded kernel modules",
+        run: cmd_lsmod,
+    },
+    Command {
+        name: "bench",
+        usage: "bench [name]        run microbenchmarks (all, or the one named)",
+        run: cmd_bench,
+    },
+    Command {
+        name: "perf",
+        usage: "perf <command>      count CPU events (instructions, cycles, ..) of a command",
+        run: cmd_perf,
+    },
+    Command {
+        name: "sync",
+        usage: "sync                write cached disk blocks back",
+        run: cmd_sync,
+    },
+    #[cfg(feature = "smoltcp")]
+    Command {
+        name: "smoltcp",
+        usage: "smoltcp <command>   attach a network device to smoltcp, send UDP through it",
+        run: cmd_smoltcp,
+    },
+    Command {
+        name: "clear",
+        usage: "clear               clear the screen",
+        run: cmd_clear,
+    },
+    Command {
+        name: "reboot",
+        usage: "reboot              restart the machine",
+        run: cmd_reboot,
+    },
+    Command {
+        name: "poweroff",
+        usage: "poweroff            turn the machine off (UEFI runtime services)",
+        run: cmd_poweroff,
+    },
+    Command {
+        name: "suspend",
+        usage: "suspend [seconds]   suspend to RAM (ACPI S3) until a wake event or the RTC alarm",
+        run: cmd_suspend,
+    },
+    Command {
+        name: "kexec",
+        usage: "kexec <kernel>      start another kernel without going through the firmware",
+        run: cmd_kexec,
+    },
+];
+
+// ============================================================================
+// 1. INPUT
+// ============================================================================
+
+fn key_from_char(c: char) -> Option<Key> {
+    Some(match c {
+        '\n' | '\r' => Key::Enter,
+        BACKSPACE => Key::Backspace,
+        DELETE => Key::Delete,
+        CTRL_A => Key::Home,
+        CTRL_E => Key::End,
+        CTRL_U => Key::ClearLine,
+        CTRL_C => Key::Cancel,
+        c if c.is_control() => return None,
+        c => Key::Char(c),
+    })
+}
+
+impl Input {
+    fn keyboard_key(&mut self, scancode: u8) -> Option<Key> {
+        let event = self.keyboard.add_byte(scancode).ok()??;
+        match self.keyboard.process_keyevent(event)? {
+            DecodedKey::Unicode(c) => key_from_char(c),
+            DecodedKey::RawKey(code) => match code {
+                KeyCode::ArrowLeft => Some(Key::Left),
+                KeyCode::ArrowRight => Some(Key::Right),
+                KeyCode::ArrowUp => Some(Key::Up),
+                KeyCode::ArrowDown => Some(Key::Down),
+                KeyCode::Home => Some(Key::Home),
+                KeyCode::End => Some(Key::End),
+                _ => None,
+            },
+        }
+    }
+
+    #[cfg(feature = "userspace")]
+    fn keyboard_bytes(&mut self, scancode: u8, out: &mut Vec<u8>) {
+        let Some(Some(event)) = self.keyboard.add_byte(scancode).ok() else {
+            return;
+        };
+        let sequence: &[u8] = match self.keyboard.process_keyevent(event) {
+            Some(DecodedKey::Unicode('\n')) => b"\r",
+            Some(DecodedKey::Unicode(BACKSPACE)) => b"\x7f",
+            Some(DecodedKey::Unicode(DELETE)) => b"\x1b[3~",
+            Some(DecodedKey::Unicode(c)) => {
+                out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                return;
+            }
+            Some(DecodedKey::RawKey(code)) => match code {
+                KeyCode::ArrowUp => b"\x1b[A",
+                KeyCode::ArrowDown => b"\x1b[B",
+                KeyCode::ArrowRight => b"\x1b[C",
+                KeyCode::ArrowLeft => b"\x1b[D",
+                KeyCode::Home => b"\x1b[H",
+                KeyCode::End => b"\x1b[F",
+                _ => return,
+            },
+            None => return,
+        };
+        out.extend_from_slice(sequence);
+    }
+
+    fn serial_key(&mut self, byte: u8) -> Option<Key> {
+        let c = byte as char;
+        let was_cr = core::mem::replace(&mut self.last_was_cr, c == '\r');
+
+        match self.serial_escape {
+            SerialEscape::None => match c {
+                ESCAPE => self.serial_escape = SerialEscape::Esc,
+                '\n' if was_cr => {}
+                // Terminals send DEL for Backspace
+                DELETE => return Some(Key::Backspace),
+                // Only ASCII is decoded from serial
+                c if c.is_ascii() => return key_from_char(c),
+                _ => {}
+            },
+            SerialEscape::Esc => {
+                self.serial_escape = if c == '[' || c == 'O' {
+                    SerialEscape::Csi
+                } else {
+                    SerialEscape::None
+                };
+            }
+            SerialEscape::Csi => {
+                self.serial_escape = SerialEscape::None;
+                return match c {
+                    'A' => Some(Key::Up),
+                    'B' => Some(Key::Down),
+                    'C' => Some(Key::Right),
+                    'D' => Some(Key::Left),
+                    'H' => Some(Key::Home),
+                    'F' => Some(Key::End),
+                    '0'..='9' => {
+                        self.serial_escape = SerialEscape::Tilde(byte - b'0');
+                        None
+                    }
+                    _ => None,
+                };
+            }
+            SerialEscape::Tilde(code) => {
+                self.serial_escape = SerialEscape::None;
+                if c == '~' {
+                    return match code {
+                        1 | 7 => Some(Key::Home),
+                        3 => Some(Key::Delete),
+                        4 | 8 => Some(Key::End),
+                        _ => None,
+                    };
+                }
+            }
+        }
+        None
+    }
+}
+
+fn poll_key() -> Option<Key> {
+    interrupts::without_interrupts(|| {
+        let mut input = INPUT.lock();
+        while let Some(scancode) = crate::interrupts::pop_scancode() {
+            if let Some(key) = input.keyboard_key(scancode) {
+                return Some(key);
+            }
+        }
+        while let Some(byte) = shared::serial::SERIAL1.lock().try_receive() {
+            if let Some(key) = input.serial_key(byte) {
+                return Some(key);
+            }
+        }
+        None
+    })
+}
+
+// Console input as a terminal sends it, for the program's TTY (see tty.rs):
+// characters in UTF-8, Enter as CR, Backspace as DEL and the cursor keys as VT100
+// sequences; serial bytes are passed on as they come
+#[cfg(feature = "userspace")]
+pub fn poll_console_bytes(out: &mut Vec<u8>) {
+    interrupts::without_interrupts(|| {
+        let mut input = INPUT.lock();
+        while let Some(scancode) = crate::interrupts::pop_scancode() {
+            input.keyboard_bytes(scancode, out);
+        }
+        let mut serial = shared::serial::SERIAL1.lock();
+        while let Some(byte) = serial.try_receive() {
+            out.push(byte);
+        }
+    })
+}
+
+// Wait for the next key, idling between interrupts
+fn read_key() -> Key {
+    crate::idle::wait_for(poll_key)
+}
+
+// ============================================================================
+// 2. LINE EDITING
+// ============================================================================
+
+struct LineEditor {
+    line: Vec<char>,
+    cursor: usize,
+    // Position in the history while walking it, None for the line being typed
+    history_pos: Option<usize>,
+    // The line being typed, kept while walking the history
+    draft: Vec<char>,
+}
+
+fn move_left(count: usize) {
+    if count > 0 {
+        print!("\x1b[{}D", count);
+    }
+}
+
+impl LineEditor {
+    fn new() -> Self {
+        Self {
+            line: Vec::new(),
+            cursor: 0,
+            history_pos: None,
+            draft: Vec::new(),
+        }
+    }
+
+    // Rewrite the line from `from` to its end and put the cursor back
+    fn redraw_from(&self, from: usize) {
+        let tail: String = self.line[from..].iter().collect();
+        print!("{}\x1b[K", tail);
+        move_left(self.line.len() - self.cursor);
+    }
+
+    // Replace the whole line (history navigation)
+    fn replace(&mut self, line: Vec<char>) {
+        move_left(self.cursor);
+        self.line = line;
+        self.cursor = self.line.len();
+        print!("{}\x1b[K", self.line.iter().collect::<String>());
+    }
+
+    fn history_step(&mut self, older: bool) {
+        let history = interrupts::without_interrupts(|| HISTORY.lock().clone());
+        let pos = match (self.history_pos, older) {
+            (None, true) if !history.is_empty() => Some(history.len() - 1),
+            (Some(pos), true) => Some(pos.saturating_sub(1)),
+            (Some(pos), false) if pos + 1 < history.len() => Some(pos + 1),
+            (Some(_), false) => None,
+            _ => return,
+        };
+
+        if self.history_pos.is_none() {
+            self.draft = self.line.clone();
+        }
+        self.history_pos = pos;
+        let line = match pos {
+            Some(pos) => history[pos].chars().collect(),
+            None => core::mem::take(&mut self.draft),
+        };
+        self.replace(line);
+    }
+
+    // Read a line, returns None if it was cancelled with Ctrl-C
+    fn read_line(&mut self) -> Option<String> {
+        loop {
+            match read_key() {
+                Key::Enter => {
+                    println!();
+                    return Some(self.line.iter().collect());
+                }
+                Key::Cancel => {
+                    println!("^C");
+                    return None;
+                }
+                Key::Char(c) => {
+                    self.line.insert(self.cursor, c);
+                    self.cursor += 1;
+                    print!("{}", c);
+                    self.redraw_from(self.cursor);
+                }
+                Key::Backspace if self.cursor > 0 => {
+                    self.cursor -= 1;
+                    self.line.remove(self.cursor);
+                    move_left(1);
+                    self.redraw_from(self.cursor);
+                }
+                Key::Delete if self.cursor < self.line.len() => {
+                    self.line.remove(self.cursor);
+                    self.redraw_from(self.cursor);
+                }
+                Key::Left if self.cursor > 0 => {
+                    self.cursor -= 1;
+                    move_left(1);
+                }
+                Key::Right if self.cursor < self.line.len() => {
+                    self.cursor += 1;
+                    print!("\x1b[C");
+                }
+                Key::Home => {
+                    move_left(self.cursor);
+                    self.cursor = 0;
+                }
+                Key::End => {
+                    let tail: String = self.line[self.cursor..].iter().collect();
+                    print!("{}", tail);
+                    self.cursor = self.line.len();
+                }
+                Key::ClearLine => self.replace(Vec::new()),
+                Key::Up => self.history_step(true),
+                Key::Down => self.history_step(false),
+                _ => {}
+            }
+            #[cfg(feature = "graphics")]
+            #[cfg(feature = "graphics")]
+            crate::screen::flush();
+        }
+    }
+}
+
+fn add_history(line: &str) {
+    interrupts::without_interrupts(|| {
+        let mut history = HISTORY.lock();
+        if history.back().is_some_and(|last| last == line) {
+            return;
+        }
+        if history.len() >= HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back(String::from(line));
+    });
+}
+
+// ============================================================================
+// 3. COMMANDS
+// ============================================================================
+
+fn cmd_help(_args: &[&str]) {
+    for command in COMMANDS {
+        println!("  {}", command.usage);
+    }
+}
+
+fn cmd_mem(_args: &[&str]) {
+    let frames = crate::pmm::stats();
+    let page_kib = crate::pmm::PAGE_SIZE as usize / 1024;
+    println!(
+        "Physical: {} KiB used, {} KiB free, {} KiB total",
+        frames.used * page_kib,
+        frames.free * page_kib,
+        frames.total * page_kib
+    );
+
+    let heap = crate::heap_allocator::heap_stats();
+    println!(
+        "Kernel heap: {} KiB used, {} KiB free, {} KiB total (grows to {} KiB)",
+        heap.used / 1024,
+        heap.free / 1024,
+        heap.size / 1024,
+        heap.limit / 1024
+    );
+
+    let classes = crate::slab::class_stats();
+    let slabs: usize = classes.iter().map(|class| class.slabs).sum();
+    println!(
+        "Slabs: {} KiB in {} slabs, {} objects in use",
+        slabs * crate::slab::SLAB_SIZE / 1024,
+        slabs,
+        classes.iter().map(|class| class.in_use).sum::<usize>()
+    );
+
+    let blocks = crate::block::stats_all();
+    println!(
+        "Block cache: {} blocks ({} dirty), {} hits, {} misses",
+        blocks.cached, blocks.dirty, blocks.hits, blocks.misses
+    );
+}
+
+fn cmd_sync(_args: &[&str]) {
+    crate::block::sync_all();
+}
+
+#[cfg(feature = "smoltcp")]
+fn cmd_smoltcp(args: &[&str]) {
+    use crate::net::{Ipv4Addr, Ipv4Config, smoltcp_backend};
+
+    let parse = |addr: &str| {
+        addr.parse::<core::net::Ipv4Addr>()
+            .ok()
+            .map(|a| Ipv4Addr(a.octets()))
+    };
+    let iface = |name: &str| {
+        let iface = crate::net::devices().iter().position(|d| d.name() == name);
+        if iface.is_none() {
+            println!("smoltcp: {}: no such device", name);
+        }
+        iface
+    };
+    match args {
+        ["attach", name, cidr, gateway @ ..] if gateway.len() <= 1 => {
+            let config = cidr.split_once('/').and_then(|(addr, prefix)| {
+                let prefix = prefix.parse::<u32>().ok().filter(|&prefix| prefix <= 32)?;
+                Some(Ipv4Config {
+                    addr: parse(addr)?,
+                    netmask: Ipv4Addr(u32::MAX.checked_shl(32 - prefix).unwrap_or(0).to_be_bytes()),
+                    gateway: match gateway.first() {
+                        Some(gateway) => Some(parse(gateway)?),
+                        None => None,
+                    },
+                })
+            });
+            let Some(config) = config else {
+                println!("smoltcp: bad address");
+                return;
+            };
+            let Some(iface) = iface(name) else {
+                return;
+            };
+            if let Err(err) = smoltcp_backend::attach(iface, config) {
+                println!("smoltcp: {}: {:?}", name, err);
+            }
+        }
+        ["send", name, to, words @ ..] => {
+            let to = to
+                .split_once(':')
+                .and_then(|(addr, port)| Some((parse(addr)?, port.parse::<u16>().ok()?)));
+            let Some(to) = to else {
+                println!("smoltcp: bad address");
+                return;
+            };
+            let Some(iface) = iface(name) else {
+                return;
+            };
+            let text = words.join(" ");
+            if let Err(err) = smoltcp_backend::send_udp(iface, to, text.as_bytes()) {
+                println!("smoltcp: {}: {:?}", name, err);
+            }
+        }
+        _ => {
+            println!("usage: smoltcp attach <dev> <addr>/<prefix> [gateway]");
+            println!("       smoltcp send <dev> <addr>:<port> <text>");
+        }
+    }
+}
+
+fn cmd_ps(_args: &[&str]) {
+    let uptime = crate::interrupts::TICKS.load(Ordering::Relaxed);
+    println!("Uptime: {}.{:03} s", uptime / 1000, uptime % 1000);
+    println!("PID  STATE       NAME");
+    println!("0    running     kernel shell");
+    interrupts::without_interrupts(|| {
+        if let Some(program) = &*PROGRAM.lock() {
+            match program.status {
+                Some(status) => println!("1    exited({:<3}) {}", status, program.name),
+                #[cfg(feature = "userspace")]
+                None if crate::ptrace::is_stopped() => {
+                    println!("1    stopped     {}", program.name)
+                }
+                None => println!("1    running     {}", program.name),
+            }
+        }
+    });
+}
+
+fn cmd_lsdev(_args: &[&str]) {
+    crate::device::lsdev();
+}
+
+fn cmd_dmesg(args: &[&str]) {
+    match args.first() {
+        Some(&"--crash") => match crate::crashdump::stored() {
+            Ok(dumps) if dumps.is_empty() => println!("dmesg: no crash dumps"),
+            Ok(dumps) => print!("{}", dumps),
+            Err(err) => println!("dmesg: crash log: {:?}", err),
+        },
+        _ => print!("{}", crate::klog::read()),
+    }
+}
+
+// A program name as given to `run`: looked up in PROGRAM_DIR without a '/'
+#[cfg(feature = "userspace")]
+fn program_path(name: &str) -> String {
+    if name.contains('/') {
+        String::from(name)
+    } else {
+        alloc::format!("{}/{}", PROGRAM_DIR, name)
+    }
+}
+
+#[cfg(feature = "userspace")]
+fn cmd_run(args: &[&str]) {
+    let (caps, args) = match args {
+        ["--caps", list, rest @ ..] => match crate::caps::parse(list) {
+            Some(caps) => (caps, rest),
+            None => {
+                println!("run: unknown capability in {}", list);
+                return;
+            }
+        },
+        _ => (crate::caps::ALL, args),
+    };
+    let Some(&name) = args.first() else {
+        println!("usage: run [--caps <list>] <program>");
+        return;
+    };
+    let path = program_path(name);
+
+    let Err(err) = start_program(&path, false, caps);
+    println!("run: {}: {}", path, err);
+}
+
+#[cfg(feature = "userspace")]
+const DBG_USAGE: &str = "usage: dbg run <program> | regs | peek <addr> | poke <addr> <value>
+           | set <register> <value> | step | cont";
+
+// Numbers for `dbg`: hex with a 0x prefix, decimal otherwise
+#[cfg(feature = "userspace")]
+fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[cfg(feature = "userspace")]
+fn register<'a>(regs: &'a mut crate::ptrace::UserRegs, name: &str) -> Option<&'a mut u64> {
+    Some(match name {
+        "rax" => &mut regs.rax,
+        "rbx" => &mut regs.rbx,
+        "rcx" => &mut regs.rcx,
+        "rdx" => &mut regs.rdx,
+        "rsi" => &mut regs.rsi,
+        "rdi" => &mut regs.rdi,
+        "rbp" => &mut regs.rbp,
+        "rsp" => &mut regs.rsp,
+        "r8" => &mut regs.r8,
+        "r9" => &mut regs.r9,
+        "r10" => &mut regs.r10,
+        "r11" => &mut regs.r11,
+        "r12" => &mut regs.r12,
+        "r13" => &mut regs.r13,
+        "r14" => &mut regs.r14,
+        "r15" => &mut regs.r15,
+        "rip" => &mut regs.rip,
+        "rflags" => &mut regs.eflags,
+        "fs_base" => &mut regs.fs_base,
+        "gs_base" => &mut regs.gs_base,
+        _ => return None,
+    })
+}
+
+#[cfg(feature = "userspace")]
+fn print_regs(regs: &crate::ptrace::UserRegs) {
+    println!(
+        "rax {:#018x}  rbx {:#018x}  rcx {:#018x}",
+        regs.rax, regs.rbx, regs.rcx
+    );
+    println!(
+        "rdx {:#018x}  rsi {:#018x}  rdi {:#018x}",
+        regs.rdx, regs.rsi, regs.rdi
+    );
+    println!(
+        "rbp {:#018x}  rsp {:#018x}  r8  {:#018x}",
+        regs.rbp, regs.rsp, regs.r8
+    );
+    println!(
+        "r9  {:#018x}  r10 {:#018x}  r11 {:#018x}",
+        regs.r9, regs.r10, regs.r11
+    );
+    println!(
+        "r12 {:#018x}  r13 {:#018x}  r14 {:#018x}",
+        regs.r12, regs.r13, regs.r14
+    );
+    println!(
+        "r15 {:#018x}  rip {:#018x}  rflags {:#x}",
+        regs.r15, regs.rip, regs.eflags
+    );
+    println!("fs_base {:#x}  gs_base {:#x}", regs.fs_base, regs.gs_base);
+}
+
+#[cfg(feature = "userspace")]
+fn cmd_dbg(args: &[&str]) {
+    use crate::ptrace::{self, PtraceError};
+
+    let result = match args {
+        ["run", name] => {
+            let path = program_path(name);
+            let Err(err) = exec_traced(&path);
+            println!("dbg: {}: {}", path, err);
+            return;
+        }
+        ["regs"] => ptrace::regs().map(|regs| print_regs(&regs)),
+        ["peek", addr] => match parse_number(addr) {
+            Some(addr) => ptrace::peek(addr).map(|value| println!("{:#x}: {:#018x}", addr, value)),
+            None => Err(PtraceError::BadAddress),
+        },
+        ["poke", addr, value] => match (parse_number(addr), parse_number(value)) {
+            (Some(addr), Some(value)) => ptrace::poke(addr, value),
+            _ => Err(PtraceError::BadAddress),
+        },
+        ["set", name, value] => {
+            let Some(value) = parse_number(value) else {
+                println!("{}", DBG_USAGE);
+                return;
+            };
+            ptrace::regs().and_then(|mut regs| match register(&mut regs, name) {
+                Some(slot) => {
+                    *slot = value;
+                    ptrace::set_regs(regs)
+                }
+                None => {
+                    println!("dbg: unknown register {}", name);
+                    Ok(())
+                }
+            })
+        }
+        ["step"] => ptrace::resume(true).map(|never| match never {}),
+        ["cont"] => ptrace::resume(false).map(|never| match never {}),
+        _ => {
+            println!("{}", DBG_USAGE);
+            return;
+        }
+    };
+    match result {
+        Ok(()) => {}
+        Err(PtraceError::NotStopped) => {
+            println!("dbg: no stopped program (start one with `dbg run`)")
+        }
+        Err(PtraceError::BadAddress) => println!("dbg: bad or unmapped address"),
+    }
+}
+
+fn cmd_insmod(args: &[&str]) {
+    let Some(&name) = args.first() else {
+        println!("usage: insmod <module>");
+        return;
+    };
+    let path = if name.contains('/') {
+        String::from(name)
+    } else {
+        alloc::format!("{}/{}.ko", crate::module::MODULE_DIR, name)
+    };
+    let result = crate::fs::read_file(&path)
+        .map_err(|err| alloc::format!("{:?}", err))
+        .and_then(|image| crate::module::load(&image).map_err(|err| alloc::format!("{:?}", err)));
+    if let Err(err) = result {
+        println!("insmod: {}: {}", path, err);
+    }
+}
+
+fn cmd_rmmod(args: &[&str]) {
+    let Some(&name) = args.first() else {
+        println!("usage: rmmod <name>");
+        return;
+    };
+    if let Err(err) = crate::module::unload(name) {
+        println!("rmmod: {}: {:?}", name, err);
+    }
+}
+
+fn cmd_lsmod(_args: &[&str]) {
+    // The /proc/modules format: name, size, use count, users, state, address
+    print!("{}", crate::module::list());
+}
+
+fn cmd_bench(args: &[&str]) {
+    if !crate::bench::run(args.first().copied()) {
+        let names: Vec<&str> = crate::bench::names().collect();
+        println!("bench: unknown benchmark (one of: {})", names.join(", "));
+    }
+}
+
+// Commands that start a program do not come back here: only shell commands are
+// measured to the end
+fn cmd_perf(args: &[&str]) {
+    if args.is_empty() {
+        println!("usage: perf <command> [args]");
+        return;
+    }
+    let session = match crate::perf::start(&crate::perf::available()) {
+        Ok(session) => session,
+        Err(err) => {
+            println!("perf: {:?}", err);
+            return;
+        }
+    };
+    let start = crate::interrupts::TICKS.load(Ordering::Relaxed);
+    execute(&args.join(" "));
+    let counts = session.stop();
+    let elapsed = crate::interrupts::TICKS.load(Ordering::Relaxed) - start;
+
+    println!();
+    for &(event, count) in &counts {
+        println!("{:>16}  {}", count, event.name());
+    }
+    let count = |event| counts.iter().find(|(e, _)| *e == event).map(|&(_, n)| n);
+    if let (Some(instructions), Some(cycles)) = (
+        count(crate::perf::Event::Instructions),
+        count(crate::perf::Event::Cycles),
+    ) && let Some(ipc) = (instructions * 100).checked_div(cycles)
+    {
+        println!("{:>13}.{:02}  instructions per cycle", ipc / 100, ipc % 100);
+    }
+    println!("{:>12}.{:03} s elapsed", elapsed / 1000, elapsed % 1000);
+}
+
+fn cmd_clear(_args: &[&str]) {
+    print!("\x1b[2J\x1b[H");
+}
+
+fn cmd_suspend(args: &[&str]) {
+    if let Some(arg) = args.first() {
+        let Ok(seconds) = arg.parse::<u64>() else {
+            println!("usage: suspend [seconds]");
+            return;
+        };
+        crate::rtc::arm_alarm(crate::rtc::realtime_ns() / 1_000_000_000 + seconds);
+    }
+    println!("Suspending...");
+    match crate::suspend::suspend() {
+        Ok(()) => println!("Resumed"),
+        Err(err) => println!("suspend: {:?}", err),
+    }
+}
+
+fn cmd_kexec(args: &[&str]) {
+    let Some(&path) = args.first() else {
+        println!("usage: kexec <kernel>");
+        return;
+    };
+    let result = crate::fs::read_file(path)
+        .map_err(|err| alloc::format!("{:?}", err))
+        .and_then(|image| crate::kexec::exec(&image).map_err(|err| alloc::format!("{:?}", err)));
+    // Only returns on failure
+    let Err(err) = result;
+    println!("kexec: {}: {}", path, err);
+}
+
+fn cmd_reboot(_args: &[&str]) {
+    println!("Rebooting...");
+    // The firmware knows how to reset this machine
+    crate::efi::reset(crate::efi::ResetType::Cold);
+    interrupts::disable();
+
+    // Ask the keyboard controller to pulse the CPU reset line
+    let mut command: Port<u8> = Port::new(PS2_COMMAND_PORT);
+    unsafe {
+        while command.read() & PS2_INPUT_FULL != 0 {}
+        command.write(PS2_RESET_CPU);
+    }
+
+    // No controller: triple fault with an empty IDT
+    let empty = x86_64::structures::DescriptorTablePointer {
+        limit: 0,
+        base: x86_64::VirtAddr::zero(),
+    };
+    unsafe {
+        x86_64::instructions::tables::lidt(&empty);
+    }
+    x86_64::instructions::interrupts::int3();
+}
+
+fn cmd_poweroff(_args: &[&str]) {
+    if !crate::efi::available() {
+        println!("poweroff: no UEFI runtime services");
+        return;
+    }
+    println!("Powering off...");
+    crate::efi::reset(crate::efi::ResetType::Shutdown);
+    println!("poweroff: the firmware refused");
+}
+
+fn execute(line: &str) {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let Some((&name, args)) = args.split_first() else {
+        return;
+    };
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.run)(args),
+        None => println!("{}: command not found (try `help`)", name),
+    }
+}
+
+// ============================================================================
+// 4. ENTRY
+// ============================================================================
+
+// Record a program about to enter user space (shown by `ps`)
+#[cfg(feature = "userspace")]
+pub fn set_program(name: &str) {
+    interrupts::without_interrupts(|| {
+        *PROGRAM.lock() = Some(Program {
+            name: String::from(name),
+            status: None,
+        });
+    });
+}
+
+// Load a program over the previous one and enter user space
+// Only returns if the program could not be started
+#[cfg(feature = "userspace")]
+pub fn exec(path: &str) -> Result<Infallible, String> {
+    start_program(path, false, crate::caps::ALL)
+}
+
+// Like `exec`, but the program is traced and stops before its first instruction
+#[cfg(feature = "userspace")]
+pub fn exec_traced(path: &str) -> Result<Infallible, String> {
+    start_program(path, true, crate::caps::ALL)
+}
+
+#[cfg(feature = "userspace")]
+fn start_program(path: &str, traced: bool, caps: u32) -> Result<Infallible, String> {
+    let data = crate::fs::read_file(path).map_err(|err| alloc::format!("{:?}", err))?;
+
+    let hhdm = crate::elf_loader::get_hhdm_offset();
+    let mut frame_allocator = crate::pmm::KernelFrameAllocator;
+    // The boot mapper is gone for good: programs are only started this way after
+    // the kernel entered user space once, or in place of the first program
+    let mut mapper = unsafe { crate::pml4::init_mapper(hhdm) };
+
+    // Pages of the previous program are reused in place
+    let entry_point = crate::elf_loader::load_elf(&data, &mut mapper, &mut frame_allocator)
+        .map_err(String::from)?;
+    let stack_top = crate::elf_loader::setup_user_stack(&data, &mut mapper, &mut frame_allocator);
+    drop(data);
+    // The initial thread's TLS; FS must not keep the previous program's TP
+    let tp = crate::elf_loader::setup_tls(0, &mut mapper, &mut frame_allocator)?;
+    x86_64::registers::model_specific::FsBase::write(tp.unwrap_or(x86_64::VirtAddr::zero()));
+
+    set_program(path);
+    crate::rusage::start();
+    crate::tty::reset();
+    crate::caps::reset(caps);
+    crate::ptrace::reset(traced);
+    if traced {
+        crate::ptrace::stop_at_entry(entry_point, stack_top);
+    }
+    unsafe {
+        crate::elf_loader::enter_userspace(entry_point, stack_top);
+    }
+}
+
+// Start the program named by the initramfs manifest, or the shell without one
+#[cfg(feature = "userspace")]
+pub fn start_init() -> ! {
+    match crate::fs::read_file(INIT_MANIFEST) {
+        Ok(data) => {
+            let path = String::from_utf8_lossy(&data);
+            let path = path.trim();
+            log::info!("Starting init: {}", path);
+            let Err(err) = exec(path);
+            log::warn!("init {}: {}", path, err);
+        }
+        Err(err) => log::warn!("{}: {:?}, no init program", INIT_MANIFEST, err),
+    }
+    run()
+}
+
+// Name and exit status of the last program, for crash dumps: skipped if locked
+pub fn with_program(f: impl FnOnce(&str, Option<u64>)) {
+    if let Some(program) = PROGRAM
+        .try_lock()
+        .as_ref()
+        .and_then(|program| program.as_ref())
+    {
+        f(&program.name, program.status);
+    }
+}
+
+// Called by exit: the program is gone, the shell takes over
+#[cfg(feature = "userspace")]
+pub fn program_exited(status: u64) -> ! {
+    interrupts::without_interrupts(|| {
+        if let Some(program) = &mut *PROGRAM.lock() {
+            program.status = Some(status);
+        }
+    });
+    run()
+}
+
+pub fn run() -> ! {
+    // Entered from a syscall, which runs with interrupts off
+    interrupts::enable();
+    println!("Kernel shell, type `help` for commands");
+
+    loop {
+        print!("{}", PROMPT);
+        #[cfg(feature = "graphics")]
+        crate::screen::flush();
+
+        let Some(line) = LineEditor::new().read_line() else {
+            continue;
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        add_history(line);
+        execute(line);
+    }
+}
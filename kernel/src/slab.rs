@@ -0,0 +1,460 @@
+// Slab Allocator
+// Caches of small fixed-size objects on top of the kernel heap, for kernel
+// objects that come and go all the time (the program's memory areas): the heap's
+// linked list allocator only sees whole slabs come and go, so thousands of small
+// blocks cannot fragment it, and an allocation is a pop off a free list.
+//
+// Objects are grouped by size class (powers of two, 16 to 1024 bytes). A slab is
+// one 4 KiB heap block, aligned to its size: a header, then objects of a single
+// class, the free ones chained through their first word. A slab with a free
+// object is on its class's partial list; a slab whose last object is freed goes
+// back to the heap, except one per class kept so a single object coming and
+// going does not hit the heap every time. An object finds its slab by masking
+// its address.
+//
+// `SlabCache<T>` is the typed front end: a static per object type handing out
+// `SlabBox<T>`s and counting them. /proc/slabinfo lists the caches and classes.
+
+use crate::fs::procfs::{self, ProcEntry};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::fmt::Write;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const SLAB_SIZE: usize = 4096;
+
+// Object sizes; anything larger belongs on the heap
+const SIZE_CLASSES: [usize; 7] = [16, 32, 64, 128, 256, 512, 1024];
+
+// Empty slabs each class holds on to
+const KEEP_EMPTY: usize = 1;
+
+// At the start of every slab
+#[repr(C)]
+struct Slab {
+    // Partial list of the class
+    next: *mut Slab,
+    prev: *mut Slab,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+struct Class {
+    size: usize,
+    // Slabs with at least one free object
+    partial: *mut Slab,
+    slabs: usize,
+    empty: usize,
+    in_use: usize,
+}
+
+// Only touched under the class lock
+unsafe impl Send for Class {}
+
+static CLASSES: [Mutex<Class>; SIZE_CLASSES.len()] = [
+    Mutex::new(Class::new(SIZE_CLASSES[0])),
+    Mutex::new(Class::new(SIZE_CLASSES[1])),
+    Mutex::new(Class::new(SIZE_CLASSES[2])),
+    Mutex::new(Class::new(SIZE_CLASSES[3])),
+    Mutex::new(Class::new(SIZE_CLASSES[4])),
+    Mutex::new(Class::new(SIZE_CLASSES[5])),
+    Mutex::new(Class::new(SIZE_CLASSES[6])),
+];
+
+fn slab_layout() -> Layout {
+    Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+}
+
+// Index of the smallest class that holds `layout`; objects are aligned to their
+// class size
+const fn class_of(layout: Layout) -> Option<usize> {
+    let needed = if layout.size() > layout.align() {
+        layout.size()
+    } else {
+        layout.align()
+    };
+    let mut class = 0;
+    while class < SIZE_CLASSES.len() {
+        if SIZE_CLASSES[class] >= needed {
+            return Some(class);
+        }
+        class += 1;
+    }
+    None
+}
+
+impl Class {
+    const fn new(size: usize) -> Self {
+        Self {
+            size,
+            partial: ptr::null_mut(),
+            slabs: 0,
+            empty: 0,
+            in_use: 0,
+        }
+    }
+
+    // Offset of the first object, past the header
+    fn first_object(&self) -> usize {
+        size_of::<Slab>().next_multiple_of(self.size)
+    }
+
+    fn capacity(&self) -> usize {
+        (SLAB_SIZE - self.first_object()) / self.size
+    }
+
+    fn push(&mut self, slab: *mut Slab) {
+        unsafe {
+            (*slab).prev = ptr::null_mut();
+            (*slab).next = self.partial;
+            if !self.partial.is_null() {
+                (*self.partial).prev = slab;
+            }
+        }
+        self.partial = slab;
+    }
+
+    fn unlink(&mut self, slab: *mut Slab) {
+        unsafe {
+            let Slab { next, prev, .. } = *slab;
+            if prev.is_null() {
+                self.partial = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+    }
+
+    // A new slab from the heap, all objects free
+    fn grow(&mut self) -> Option<()> {
+        let base = unsafe { alloc::alloc::alloc(slab_layout()) };
+        if base.is_null() {
+            return None;
+        }
+        let mut free = ptr::null_mut();
+        for offset in (self.first_object()..SLAB_SIZE).step_by(self.size).rev() {
+            let object = unsafe { base.add(offset) } as *mut FreeObject;
+            unsafe { object.write(FreeObject { next: free }) };
+            free = object;
+        }
+        let slab = base as *mut Slab;
+        unsafe {
+            slab.write(Slab {
+                next: ptr::null_mut(),
+                prev: ptr::null_mut(),
+                free,
+                in_use: 0,
+            })
+        };
+        self.push(slab);
+        self.slabs += 1;
+        self.empty += 1;
+        Some(())
+    }
+
+    fn alloc(&mut self) -> Option<NonNull<u8>> {
+        if self.partial.is_null() {
+            self.grow()?;
+        }
+        let slab = self.partial;
+        unsafe {
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            if (*slab).in_use == 0 {
+                self.empty -= 1;
+            }
+            (*slab).in_use += 1;
+            if (*slab).free.is_null() {
+                self.unlink(slab);
+            }
+            self.in_use += 1;
+            NonNull::new(object as *mut u8)
+        }
+    }
+
+    unsafe fn free(&mut self, ptr: NonNull<u8>) {
+        let slab = (ptr.as_ptr() as usize & !(SLAB_SIZE - 1)) as *mut Slab;
+        let object = ptr.as_ptr() as *mut FreeObject;
+        unsafe {
+            // A full slab is on no list
+            if (*slab).free.is_null() {
+                self.push(slab);
+            }
+            object.write(FreeObject { next: (*slab).free });
+            (*slab).free = object;
+            (*slab).in_use -= 1;
+            self.in_use -= 1;
+            if (*slab).in_use == 0 {
+                if self.empty < KEEP_EMPTY {
+                    self.empty += 1;
+                } else {
+                    self.unlink(slab);
+                    self.slabs -= 1;
+                    alloc::alloc::dealloc(slab as *mut u8, slab_layout());
+                }
+            }
+        }
+    }
+}
+
+// An object for `layout` from its size class; None if it is too large for the
+// slabs or the heap is full
+pub fn alloc(layout: Layout) -> Option<NonNull<u8>> {
+    let class = class_of(layout)?;
+    interrupts::without_interrupts(|| CLASSES[class].lock().alloc())
+}
+
+/// Give an object from `alloc` back
+///
+/// # Safety
+/// `ptr` must come from `alloc` with the same `layout` and not be used again
+pub unsafe fn free(ptr: NonNull<u8>, layout: Layout) {
+    let class = class_of(layout).expect("slab: object not from a size class");
+    interrupts::without_interrupts(|| unsafe { CLASSES[class].lock().free(ptr) });
+}
+
+// ============================================================================
+// 1. TYPED CACHES
+// ============================================================================
+
+// What /proc/slabinfo shows of a cache
+struct CacheInfo {
+    name: &'static str,
+    object_size: usize,
+    in_use: AtomicUsize,
+    // Allocations since boot
+    allocs: AtomicUsize,
+    registered: AtomicBool,
+}
+
+// Caches that have allocated something, for /proc/slabinfo
+static CACHES: Mutex<Vec<&'static CacheInfo>> = Mutex::new(Vec::new());
+
+// Objects of one type, e.g. `static TIMERS: SlabCache<Timer> = SlabCache::new("timer");`
+pub struct SlabCache<T> {
+    info: CacheInfo,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T> SlabCache<T> {
+    pub const fn new(name: &'static str) -> Self {
+        const {
+            assert!(
+                class_of(Layout::new::<T>()).is_some(),
+                "type too large for a slab cache"
+            )
+        };
+        Self {
+            info: CacheInfo {
+                name,
+                object_size: size_of::<T>(),
+                in_use: AtomicUsize::new(0),
+                allocs: AtomicUsize::new(0),
+                registered: AtomicBool::new(false),
+            },
+            _type: PhantomData,
+        }
+    }
+
+    // Move `value` into an object of the cache; None if the heap is full
+    pub fn alloc(&'static self, value: T) -> Option<SlabBox<T>> {
+        if !self.info.registered.swap(true, Ordering::Relaxed) {
+            interrupts::without_interrupts(|| CACHES.lock().push(&self.info));
+        }
+        let ptr = alloc(Layout::new::<T>())?.cast::<T>();
+        unsafe { ptr.write(value) };
+        self.info.in_use.fetch_add(1, Ordering::Relaxed);
+        self.info.allocs.fetch_add(1, Ordering::Relaxed);
+        Some(SlabBox {
+            ptr,
+            cache: &self.info,
+            _type: PhantomData,
+        })
+    }
+}
+
+// An object owned like a Box, going back to its cache when dropped
+pub struct SlabBox<T> {
+    ptr: NonNull<T>,
+    cache: &'static CacheInfo,
+    _type: PhantomData<T>,
+}
+
+#[cfg(feature = "userspace")]
+impl<T> SlabBox<T> {
+    // Move the value out, giving the object back
+    pub fn into_inner(this: Self) -> T {
+        let this = core::mem::ManuallyDrop::new(this);
+        let value = unsafe { this.ptr.read() };
+        unsafe { free(this.ptr.cast(), Layout::new::<T>()) };
+        this.cache.in_use.fetch_sub(1, Ordering::Relaxed);
+        value
+    }
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            free(self.ptr.cast(), Layout::new::<T>());
+        }
+        self.cache.in_use.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// ============================================================================
+// 2. STATISTICS
+// ============================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassStats {
+    pub size: usize,
+    pub slabs: usize,
+    pub in_use: usize,
+    // Objects the slabs have room for
+    pub capacity: usize,
+}
+
+pub fn class_stats() -> [ClassStats; SIZE_CLASSES.len()] {
+    core::array::from_fn(|class| {
+        interrupts::without_interrupts(|| {
+            let class = CLASSES[class].lock();
+            ClassStats {
+                size: class.size,
+                slabs: class.slabs,
+                in_use: class.in_use,
+                capacity: class.slabs * class.capacity(),
+            }
+        })
+    })
+}
+
+fn slabinfo() -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{:<16} {:>8} {:>8} {:>10}",
+        "cache", "size", "in use", "allocs"
+    );
+    let caches = interrupts::without_interrupts(|| CACHES.lock().clone());
+    for cache in caches {
+        let _ = writeln!(
+            out,
+            "{:<16} {:>8} {:>8} {:>10}",
+            cache.name,
+            cache.object_size,
+            cache.in_use.load(Ordering::Relaxed),
+            cache.allocs.load(Ordering::Relaxed)
+        );
+    }
+    let _ = writeln!(out);
+    let _ = writeln!(
+        out,
+        "{:<16} {:>8} {:>8} {:>10}",
+        "class", "slabs", "in use", "capacity"
+    );
+    for class in class_stats() {
+        let _ = writeln!(
+            out,
+            "{:<16} {:>8} {:>8} {:>10}",
+            class.size, class.slabs, class.in_use, class.capacity
+        );
+    }
+    out
+}
+
+static SLABINFO_ENTRY: ProcEntry = ProcEntry {
+    name: "slabinfo",
+    read: slabinfo,
+    write: None,
+};
+
+pub fn init_proc() {
+    procfs::register(&SLABINFO_ENTRY);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static VALUES: SlabCache<[u64; 5]> = SlabCache::new("test-values");
+
+    #[test_case]
+    fn objects_are_aligned_to_their_class() {
+        assert_eq!(class_of(Layout::new::<u8>()), Some(0));
+        assert_eq!(class_of(Layout::new::<[u64; 5]>()), Some(2));
+        assert_eq!(class_of(Layout::from_size_align(8, 256).unwrap()), Some(4));
+        assert_eq!(class_of(Layout::new::<[u8; 2048]>()), None);
+        let value = VALUES.alloc([7; 5]).expect("out of memory");
+        assert_eq!(value.as_ptr() as usize % 64, 0);
+        assert_eq!(value[4], 7);
+    }
+
+    #[test_case]
+    fn freed_objects_are_handed_out_first() {
+        let first = VALUES.alloc([1; 5]).expect("out of memory");
+        let addr = first.as_ptr() as usize;
+        drop(first);
+        let second = VALUES.alloc([2; 5]).expect("out of memory");
+        assert_eq!(second.as_ptr() as usize, addr);
+        assert_eq!(second[0], 2);
+    }
+
+    #[test_case]
+    fn full_slabs_grow_and_empty_ones_go_back() {
+        let before = class_stats()[2];
+        let in_use = VALUES.info.in_use.load(Ordering::Relaxed);
+        let per_slab = Class::new(SIZE_CLASSES[2]).capacity();
+        // Fill the room left, then three whole slabs
+        let room = before.capacity - before.in_use;
+        let values: Vec<_> = (0..room + 3 * per_slab)
+            .map(|i| VALUES.alloc([i as u64; 5]).expect("out of memory"))
+            .collect();
+        let full = class_stats()[2];
+        assert_eq!(full.slabs, before.slabs + 3);
+        assert_eq!(full.in_use, full.capacity);
+        assert_eq!(
+            VALUES.info.in_use.load(Ordering::Relaxed),
+            in_use + values.len()
+        );
+        // Objects of one new slab share its base
+        let base = |value: &SlabBox<[u64; 5]>| value.as_ptr() as usize & !(SLAB_SIZE - 1);
+        let last = &values[values.len() - per_slab..];
+        assert!(last.iter().all(|value| base(value) == base(&last[0])));
+        assert_ne!(base(&values[room]), base(&last[0]));
+        drop(values);
+        let after = class_stats()[2];
+        assert_eq!(after.in_use, before.in_use);
+        assert!(after.slabs <= before.slabs + KEEP_EMPTY);
+    }
+}
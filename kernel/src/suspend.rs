@@ -0,0 +1,392 @@
+// Suspend-to-RAM (ACPI S3)
+// `suspend` saves the CPU state the firmware does not keep (control registers,
+// descriptor tables, MSRs, FPU), lets the drivers save their devices, writes the
+// waking vector and enters S3 through the FADT (see acpi.rs). Memory keeps its
+// contents while the machine sleeps; everything else is reset.
+//
+// On wake the firmware jumps to the waking vector in real mode. The wake code,
+// copied into a page below 1 MiB at boot, goes straight to long mode on page
+// tables that map itself 1:1 and the kernel half as usual, and jumps to
+// `resume_from_sleep`. That restores the CPU state and returns from
+// `sleep_saving_registers` a second time, now with 0. `suspend` then brings the
+// interrupt controller, the timer and the consoles back and resumes the drivers.
+// The RTC measures how long we slept (for the wall clock and CLOCK_BOOTTIME), and
+// its alarm, if one is armed, is what wakes the machine (see rtc.rs).
+//
+// Low memory (5 pages):
+//   +0x0000  wake code
+//   +0x1000  PML4 (a copy of the kernel one, with entry 0 mapping the first 2 MiB)
+//   +0x2000  PDPT
+//   +0x3000  PD
+//   +0x4000  PML5 above the PML4, with 5-level paging (see shared::paging)
+//
+// Virtio devices get their PCI configuration back, not their queues: the
+// network and block drivers do not resume yet.
+
+use crate::acpi::SleepError;
+use shared::once::Once;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::segmentation::{CS, DS, ES, FS, GS, SS, Segment};
+use x86_64::instructions::tables::{self, DescriptorTablePointer};
+use x86_64::registers::control::{Cr0, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
+use x86_64::registers::xcontrol::XCr0;
+use x86_64::structures::gdt::SegmentSelector;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::{PhysAddr, PrivilegeLevel};
+
+const PAGE_SIZE: u64 = 4096;
+const WAKE_PAGES: usize = 5;
+const CR4_PAE: u32 = 1 << 5;
+const CR4_LA57: u32 = 1 << 12;
+// The waking vector is a real mode address
+const WAKE_LIMIT: u64 = 0x10_0000;
+
+// MSRs restored on resume (EFER, STAR, LSTAR, SFMASK, FS/GS/kernel GS base, PAT)
+const SAVED_MSRS: [u32; 8] = [
+    0xC000_0080,
+    0xC000_0081,
+    0xC000_0082,
+    0xC000_0084,
+    0xC000_0100,
+    0xC000_0101,
+    0xC000_0102,
+    0x277,
+];
+const EFER_LMA: u64 = 1 << 10;
+
+// GDT layout of gdt.rs
+const KERNEL_CODE: u16 = 1;
+const KERNEL_DATA: u16 = 2;
+const TSS_ENTRY: u16 = 5;
+const TSS_BUSY: u64 = 1 << 41;
+
+// Physical address of the low pages
+static WAKE_MEMORY: Once<u64> = Once::new();
+static HHDM_OFFSET: Once<u64> = Once::new();
+
+#[repr(C, align(16))]
+struct FxArea([u8; 512]);
+
+struct CpuState {
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    xcr0: u64,
+    gdt: DescriptorTablePointer,
+    idt: DescriptorTablePointer,
+    msrs: [u64; SAVED_MSRS.len()],
+    fx: FxArea,
+}
+
+// Only touched with interrupts off, by the CPU going to sleep and waking up
+static mut CPU_STATE: Option<CpuState> = None;
+static mut SAVED_RSP: u64 = 0;
+
+// Take the low pages for the wake code, before the heap and anything else
+// allocates the low frames
+pub fn init(hhdm_offset: u64) {
+    HHDM_OFFSET.call_once(|| hhdm_offset);
+    let order = crate::pmm::order_for(WAKE_PAGES);
+    match crate::pmm::allocate_contiguous_below(order, WAKE_LIMIT) {
+        Some(phys) => {
+            WAKE_MEMORY.call_once(|| phys);
+        }
+        None => log::warn!("Suspend: no memory below 1 MiB for the wake code"),
+    }
+}
+
+// ============================================================================
+// 1. WAKE CODE
+// ============================================================================
+
+// Entered in real mode at offset 0 with CS = page >> 4; the fields at the end are
+// filled in by `prepare_wake_code`
+core::arch::global_asm!(
+    ".section .text.s3_wake, \"ax\"",
+    ".global s3_wake_start",
+    ".global s3_wake_gdtr",
+    ".global s3_wake_far",
+    ".global s3_wake_cr3",
+    ".global s3_wake_cr4",
+    ".global s3_wake_efer",
+    ".global s3_wake_resume",
+    ".global s3_wake_long",
+    ".global s3_wake_gdt",
+    ".global s3_wake_end",
+    ".code16",
+    "s3_wake_start:",
+    "    cli",
+    "    cld",
+    "    mov %cs, %ax",
+    "    mov %ax, %ds",
+    "    lgdtl (s3_wake_gdtr - s3_wake_start)",
+    // PAE (and LA57 if the kernel used it), the page tables, EFER.LME (and NXE
+    // if the kernel used it)
+    "    mov %cr4, %eax",
+    "    orl (s3_wake_cr4 - s3_wake_start), %eax",
+    "    mov %eax, %cr4",
+    "    movl (s3_wake_cr3 - s3_wake_start), %eax",
+    "    mov %eax, %cr3",
+    "    mov $0xC0000080, %ecx",
+    "    movl (s3_wake_efer - s3_wake_start), %eax",
+    "    xor %edx, %edx",
+    "    wrmsr",
+    // Protection and paging at once: long mode
+    "    mov %cr0, %eax",
+    "    or $0x80000001, %eax",
+    "    mov %eax, %cr0",
+    "    ljmpl *(s3_wake_far - s3_wake_start)",
+    ".code64",
+    "s3_wake_long:",
+    "    mov $0x10, %ax",
+    "    mov %ax, %ds",
+    "    mov %ax, %es",
+    "    mov %ax, %ss",
+    "    jmp *s3_wake_resume(%rip)",
+    ".balign 8",
+    "s3_wake_gdt:",
+    "    .quad 0",
+    "    .quad 0x00AF9A000000FFFF", // 64-bit code
+    "    .quad 0x00CF92000000FFFF", // data
+    "s3_wake_gdtr:",
+    "    .word 23",
+    "    .long 0", // base
+    "s3_wake_far:",
+    "    .long 0", // address of s3_wake_long
+    "    .word 0x08",
+    "s3_wake_cr3:",
+    "    .long 0",
+    "s3_wake_cr4:",
+    "    .long 0", // bits to set
+    "s3_wake_efer:",
+    "    .long 0",
+    ".balign 8",
+    "s3_wake_resume:",
+    "    .quad 0", // resume_from_sleep
+    "s3_wake_end:",
+    ".text",
+    options(att_syntax)
+);
+
+unsafe extern "C" {
+    static s3_wake_start: u8;
+    static s3_wake_gdtr: u8;
+    static s3_wake_far: u8;
+    static s3_wake_cr3: u8;
+    static s3_wake_cr4: u8;
+    static s3_wake_efer: u8;
+    static s3_wake_resume: u8;
+    static s3_wake_long: u8;
+    static s3_wake_gdt: u8;
+    static s3_wake_end: u8;
+}
+
+// Offset of a wake code symbol from its start
+fn wake_offset(symbol: *const u8) -> u64 {
+    symbol as u64 - (&raw const s3_wake_start) as u64
+}
+
+// Copy the wake code and build its page tables against the current ones
+fn prepare_wake_code(wake: u64, hhdm: u64, efer: u64) {
+    let virt = wake + hhdm;
+    let length = wake_offset(&raw const s3_wake_end);
+    assert!(length <= PAGE_SIZE, "wake code larger than a page");
+
+    let field = |symbol: *const u8| virt + wake_offset(symbol);
+    unsafe {
+        core::ptr::copy_nonoverlapping(&raw const s3_wake_start, virt as *mut u8, length as usize);
+        let gdt = wake + wake_offset(&raw const s3_wake_gdt);
+        let long = wake + wake_offset(&raw const s3_wake_long);
+        // Unaligned: the GDT pointer base sits after its 16-bit limit
+        core::ptr::write_unaligned((field(&raw const s3_wake_gdtr) + 2) as *mut u32, gdt as u32);
+        core::ptr::write_unaligned(field(&raw const s3_wake_far) as *mut u32, long as u32);
+        let (root, cr4) = if crate::pml4::five_level() {
+            (wake + 4 * PAGE_SIZE, CR4_PAE | CR4_LA57)
+        } else {
+            (wake + PAGE_SIZE, CR4_PAE)
+        };
+        core::ptr::write_unaligned(field(&raw const s3_wake_cr3) as *mut u32, root as u32);
+        core::ptr::write_unaligned(field(&raw const s3_wake_cr4) as *mut u32, cr4);
+        core::ptr::write_unaligned(
+            field(&raw const s3_wake_efer) as *mut u32,
+            (efer & !EFER_LMA) as u32,
+        );
+        core::ptr::write_unaligned(
+            field(&raw const s3_wake_resume) as *mut u64,
+            resume_from_sleep as *const () as u64,
+        );
+    }
+
+    let table = |page: u64| unsafe { &mut *((wake + page * PAGE_SIZE + hhdm) as *mut PageTable) };
+    let current = crate::pml4::active_pml4(hhdm);
+    let kernel = unsafe { &*((current.start_address().as_u64() + hhdm) as *const PageTable) };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let pml4 = table(1);
+    pml4.clone_from(kernel);
+    pml4[0].set_addr(PhysAddr::new(wake + 2 * PAGE_SIZE), flags);
+    let pdpt = table(2);
+    pdpt.zero();
+    pdpt[0].set_addr(PhysAddr::new(wake + 3 * PAGE_SIZE), flags);
+    let pd = table(3);
+    pd.zero();
+    pd[0].set_addr(PhysAddr::new(0), flags | PageTableFlags::HUGE_PAGE);
+    shared::paging::wrap_pml4(table(4), PhysAddr::new(wake + PAGE_SIZE));
+}
+
+// ============================================================================
+// 2. CPU STATE
+// ============================================================================
+
+fn save_cpu() -> CpuState {
+    let (pml4, pcid) = Cr3::read_raw();
+    let mut state = CpuState {
+        cr0: Cr0::read_raw(),
+        cr3: pml4.start_address().as_u64() | pcid as u64,
+        cr4: Cr4::read_raw(),
+        xcr0: 0,
+        gdt: tables::sgdt(),
+        idt: tables::sidt(),
+        msrs: SAVED_MSRS.map(|msr| unsafe { Msr::new(msr).read() }),
+        fx: FxArea([0; 512]),
+    };
+    if Cr4::read().contains(Cr4Flags::OSXSAVE) {
+        state.xcr0 = XCr0::read_raw();
+    }
+    unsafe { core::arch::asm!("fxsave64 [{}]", in(reg) &raw mut state.fx, options(nostack)) };
+    state
+}
+
+// Called by `resume_from_sleep` on the saved stack, still on the wake page tables
+extern "C" fn restore_cpu() {
+    #[allow(static_mut_refs)]
+    let state = unsafe { CPU_STATE.as_ref() }.expect("resume without saved CPU state");
+    unsafe {
+        // The kernel GDT, then every segment register from it
+        tables::lgdt(&state.gdt);
+        CS::set_reg(SegmentSelector::new(KERNEL_CODE, PrivilegeLevel::Ring0));
+        let data = SegmentSelector::new(KERNEL_DATA, PrivilegeLevel::Ring0);
+        DS::set_reg(data);
+        ES::set_reg(data);
+        SS::set_reg(data);
+        FS::set_reg(SegmentSelector(0));
+        GS::set_reg(SegmentSelector(0));
+        // The TSS descriptor is still marked busy from before the sleep
+        let tss = (state.gdt.base.as_u64() + TSS_ENTRY as u64 * 8) as *mut u64;
+        *tss &= !TSS_BUSY;
+        tables::load_tss(SegmentSelector::new(TSS_ENTRY, PrivilegeLevel::Ring0));
+        tables::lidt(&state.idt);
+
+        Cr4::write_raw(state.cr4);
+        if state.xcr0 != 0 {
+            XCr0::write_raw(state.xcr0);
+        }
+        Cr0::write_raw(state.cr0);
+        core::arch::asm!("mov cr3, {}", in(reg) state.cr3, options(nostack));
+        for (&msr, &value) in SAVED_MSRS.iter().zip(&state.msrs) {
+            Msr::new(msr).write(value);
+        }
+        core::arch::asm!("fxrstor64 [{}]", in(reg) &state.fx, options(nostack));
+    }
+}
+
+// Save the callee-saved registers and the stack pointer, then call `sleep`
+// Returns 1 if `sleep` returned (still awake), 0 through `resume_from_sleep`
+#[unsafe(naked)]
+unsafe extern "C" fn sleep_saving_registers(sleep: extern "C" fn()) -> u64 {
+    core::arch::naked_asm!(
+        "push rbx",
+        "push rbp",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "sub rsp, 8",
+        "mov [rip + {saved_rsp}], rsp",
+        "call rdi",
+        "mov eax, 1",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "pop rbx",
+        "ret",
+        saved_rsp = sym SAVED_RSP,
+    )
+}
+
+// Jumped to by the wake code: back on the stack of `sleep_saving_registers`
+#[unsafe(naked)]
+unsafe extern "C" fn resume_from_sleep() -> ! {
+    core::arch::naked_asm!(
+        "mov rsp, [rip + {saved_rsp}]",
+        "call {restore}",
+        "xor eax, eax",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "pop rbx",
+        "ret",
+        saved_rsp = sym SAVED_RSP,
+        restore = sym restore_cpu,
+    )
+}
+
+extern "C" fn enter_sleep() {
+    crate::acpi::enter_s3();
+}
+
+// ============================================================================
+// 3. SUSPEND
+// ============================================================================
+
+// Suspend to RAM; returns after the machine woke up, or with the reason it could not sleep
+pub fn suspend() -> Result<(), SleepError> {
+    let wake = *WAKE_MEMORY
+        .get()
+        .ok_or(SleepError::Unsupported("no wake memory"))?;
+    let hhdm = *HHDM_OFFSET.get().unwrap();
+
+    log::info!("Suspend: entering S3");
+    #[cfg(feature = "graphics")]
+    crate::screen::flush();
+
+    let result = interrupts::without_interrupts(|| {
+        let masks = crate::interrupts::PICS.masks();
+        let state = save_cpu();
+        prepare_wake_code(wake, hhdm, state.msrs[0]);
+        crate::acpi::prepare_s3(wake as u32)?;
+        crate::device::suspend();
+        unsafe { CPU_STATE = Some(state) };
+
+        let awake = unsafe { sleep_saving_registers(enter_sleep) } == 1;
+
+        // The interrupt controller, the timer and the consoles lost their setup
+        // (the CPU too, unless the sleep failed)
+        crate::interrupts::PICS.initialize();
+        crate::interrupts::PICS.set_masks(masks);
+        crate::interrupts::init_timer();
+        crate::idle::init_cpu(0);
+        let _ = shared::serial::SERIAL1.lock().reinit();
+        crate::rtc::resume();
+        crate::device::resume();
+        #[cfg(feature = "graphics")]
+        crate::screen::flush();
+        if awake {
+            Err(SleepError::NotEntered)
+        } else {
+            Ok(())
+        }
+    });
+    match result {
+        Ok(()) => log::info!("Suspend: resumed"),
+        Err(err) => log::warn!("Suspend: {:?}", err),
+    }
+    result
+}
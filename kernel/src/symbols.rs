@@ -0,0 +1,137 @@
+// Kernel Symbols
+// Function names for backtraces. The kernel reserves a zeroed `.ksyms` section and
+// tools/ksyms fills it after linking with the functions of the ELF symbol table,
+// so addresses can be resolved without loading anything at boot.
+//
+// Section layout (little endian):
+//   header (8 bytes):   magic "KSYM", symbol count
+//   symbols:            count * (address u64, size u32, name offset u32), by address
+//   names:              NUL-terminated demangled names, offsets are from the start
+//                       of this table
+//
+// An unpatched kernel (built without running tools/ksyms) has an all-zero section
+// and resolves nothing.
+//
+// The table holds link-time addresses; with KASLR the kernel runs `slide` bytes
+// higher (shared::kaslr), which is taken off before a lookup.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const KSYMS_MAGIC: [u8; 4] = *b"KSYM";
+const HEADER_LEN: usize = 8;
+const ENTRY_LEN: usize = 16;
+
+// Space reserved for the table, tools/ksyms fails if the symbols do not fit
+const KSYMS_SIZE: usize = 128 * 1024;
+
+#[used]
+#[unsafe(link_section = ".ksyms")]
+static KSYMS: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+// Load address minus link address, from BootInfo
+static SLIDE: AtomicU64 = AtomicU64::new(0);
+
+// Record the KASLR slide, called first thing at boot
+pub fn init(slide: u64) {
+    SLIDE.store(slide, Ordering::Relaxed);
+}
+
+pub fn slide() -> u64 {
+    SLIDE.load(Ordering::Relaxed)
+}
+
+struct Symbol {
+    addr: u64,
+    size: u32,
+    name_offset: u32,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    read_u32(data, offset) as u64 | (read_u32(data, offset + 4) as u64) << 32
+}
+
+struct Table {
+    data: &'static [u8],
+    count: usize,
+}
+
+impl Table {
+    fn get() -> Option<Self> {
+        // The contents are written after compilation, the compiler must not assume
+        // they are still zero
+        let data: &'static [u8] = core::hint::black_box(&KSYMS);
+        if data[0..4] != KSYMS_MAGIC {
+            return None;
+        }
+        let count = read_u32(data, 4) as usize;
+        if HEADER_LEN + count * ENTRY_LEN > data.len() {
+            return None;
+        }
+        Some(Self { data, count })
+    }
+
+    fn symbol(&self, index: usize) -> Symbol {
+        let offset = HEADER_LEN + index * ENTRY_LEN;
+        Symbol {
+            addr: read_u64(self.data, offset),
+            size: read_u32(self.data, offset + 8),
+            name_offset: read_u32(self.data, offset + 12),
+        }
+    }
+
+    fn name(&self, symbol: &Symbol) -> &'static str {
+        let names = &self.data[HEADER_LEN + self.count * ENTRY_LEN..];
+        let Some(name) = names.get(symbol.name_offset as usize..) else {
+            return "?";
+        };
+        let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        core::str::from_utf8(&name[..len]).unwrap_or("?")
+    }
+}
+
+// Name of the function containing `addr` and the offset of `addr` into it
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    let table = Table::get()?;
+    let addr = addr.checked_sub(slide())?;
+
+    // Last symbol starting at or before addr
+    let (mut low, mut high) = (0, table.count);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if table.symbol(mid).addr <= addr {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let symbol = table.symbol(low.checked_sub(1)?);
+
+    // A call to a noreturn function can be the last instruction, its return
+    // address is then the end of the function
+    let offset = addr - symbol.addr;
+    if offset > symbol.size as u64 {
+        return None;
+    }
+    Some((table.name(&symbol), offset))
+}
+
+// Backtrace line for a return address: "name+0xoff", or the raw address
+pub struct Frame(pub u64);
+
+impl core::fmt::Display for Frame {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match resolve(self.0) {
+            Some((name, offset)) => write!(f, "{:#018x} {}+{:#x}", self.0, name, offset),
+            None => write!(f, "{:#018x}", self.0),
+        }
+    }
+}
@@ -2,17 +2,37 @@
 // This module implements system call handling for user space programs
 // It uses the SYSCALL/SYSRET mechanism on x86_64
 
+use crate::caps;
+use crate::fs::eventfd::EventFd;
+use crate::fs::timerfd::{Clock, TimerFd};
+use crate::fs::{self, FileType, FsError, Metadata, SeekFrom, fd};
+#[cfg(feature = "net")]
+use crate::net::socket::SocketFile;
+#[cfg(feature = "net")]
+use crate::net::{Ipv4Addr, SocketAddr};
+use crate::vma::{PROT_EXEC, PROT_READ, PROT_WRITE, Vma, VmaKind};
+use alloc::string::String;
+#[cfg(feature = "net")]
+use alloc::sync::Arc;
 use core::arch::naked_asm;
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicU64, Ordering};
 use x86_64::VirtAddr;
-use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::model_specific::{
+    Efer, EferFlags, GsBase, KernelGsBase, LStar, SFMask, Star,
+};
 use x86_64::registers::rflags::RFlags;
 
 // Syscall numbers (Linux x86_64 ABI)
 const SYS_READ: u64 = 0;
 const SYS_WRITE: u64 = 1;
+const SYS_OPEN: u64 = 2;
 const SYS_CLOSE: u64 = 3;
+const SYS_STAT: u64 = 4;
 const SYS_FSTAT: u64 = 5;
+const SYS_LSTAT: u64 = 6;
 const SYS_POLL: u64 = 7; // Fixed: was 23
+const SYS_LSEEK: u64 = 8;
 const SYS_MMAP: u64 = 9;
 const SYS_MPROTECT: u64 = 10;
 const SYS_MUNMAP: u64 = 11;
@@ -23,15 +43,57 @@ const SYS_IOCTL: u64 = 16;
 const SYS_PREAD64: u64 = 17;
 const SYS_PWRITE64: u64 = 18;
 const SYS_WRITEV: u64 = 20;
+const SYS_MINCORE: u64 = 27;
 const SYS_MADVISE: u64 = 28;
+#[cfg(feature = "net")]
+const SYS_SOCKET: u64 = 41;
+#[cfg(feature = "net")]
+const SYS_CONNECT: u64 = 42;
+#[cfg(feature = "net")]
+const SYS_SENDTO: u64 = 44;
+#[cfg(feature = "net")]
+const SYS_RECVFROM: u64 = 45;
+#[cfg(feature = "net")]
+const SYS_BIND: u64 = 49;
+#[cfg(feature = "net")]
+const SYS_GETSOCKNAME: u64 = 51;
+#[cfg(feature = "net")]
+const SYS_GETPEERNAME: u64 = 52;
+const SYS_FTRUNCATE: u64 = 77;
+const SYS_GETCWD: u64 = 79;
+const SYS_CHDIR: u64 = 80;
+const SYS_MKDIR: u64 = 83;
+const SYS_RMDIR: u64 = 84;
+const SYS_UNLINK: u64 = 87;
+const SYS_READLINK: u64 = 89;
+const SYS_MOUNT: u64 = 165;
+const SYS_UMOUNT2: u64 = 166;
+const SYS_SYNC: u64 = 162;
 const SYS_FUTEX: u64 = 202;
 const SYS_CLOCK_GETTIME: u64 = 228;
 const SYS_EXIT: u64 = 60;
 const SYS_EXIT_GROUP: u64 = 231;
 const SYS_ARCH_PRCTL: u64 = 158;
+const SYS_PRCTL: u64 = 157;
 const SYS_SET_TID_ADDRESS: u64 = 218;
+const SYS_GETDENTS64: u64 = 217;
+const SYS_OPENAT: u64 = 257;
+const SYS_MKDIRAT: u64 = 258;
+const SYS_UNLINKAT: u64 = 263;
+const SYS_NEWFSTATAT: u64 = 262;
+const SYS_READLINKAT: u64 = 267;
 const SYS_SIGALTSTACK: u64 = 131;
 const SYS_GETRANDOM: u64 = 318;
+const SYS_PTRACE: u64 = 101;
+const SYS_INIT_MODULE: u64 = 175;
+const SYS_DELETE_MODULE: u64 = 176;
+const SYS_EVENTFD: u64 = 284;
+const SYS_EVENTFD2: u64 = 290;
+const SYS_TIMERFD_CREATE: u64 = 283;
+const SYS_TIMERFD_SETTIME: u64 = 286;
+const SYS_TIMERFD_GETTIME: u64 = 287;
+const SYS_TIMES: u64 = 100;
+const SYS_GETRUSAGE: u64 = 98;
 
 // ARCH_PRCTL sub-functions
 const ARCH_SET_FS: u64 = 0x1002;
@@ -39,12 +101,110 @@ const ARCH_GET_FS: u64 = 0x1003;
 const ARCH_SET_GS: u64 = 0x1001;
 const ARCH_GET_GS: u64 = 0x1004;
 
+// prctl options (the capability bounding set, here the capabilities of caps.rs)
+const PR_CAPBSET_READ: u64 = 23;
+const PR_CAPBSET_DROP: u64 = 24;
+
 // File descriptors
 const STDOUT: u64 = 1;
 const STDERR: u64 = 2;
 
-// Simple brk implementation - current break address
-static mut CURRENT_BRK: u64 = 0x800_0000; // 128 MB initial break
+// openat/fstatat "current directory" pseudo descriptor
+const AT_FDCWD: i64 = -100;
+
+// unlinkat flag: remove a directory instead of a file
+const AT_REMOVEDIR: u64 = 0x200;
+
+// fstatat flag: do not follow a final symlink
+const AT_SYMLINK_NOFOLLOW: u64 = 0x100;
+
+// mmap flags (the protection bits are vma.rs's)
+const MAP_SHARED: u64 = 0x01;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+const MAP_POPULATE: u64 = 0x8000;
+const MAP_FIXED_NOREPLACE: u64 = 0x100000;
+
+// madvise advice
+const MADV_NORMAL: u64 = 0;
+const MADV_RANDOM: u64 = 1;
+const MADV_SEQUENTIAL: u64 = 2;
+const MADV_WILLNEED: u64 = 3;
+const MADV_DONTNEED: u64 = 4;
+const MADV_FREE: u64 = 8;
+
+// poll events reported for descriptors that are not open
+const POLLNVAL: u16 = 0x20;
+
+// eventfd2 flags
+const EFD_SEMAPHORE: u64 = 1;
+const EFD_NONBLOCK: u64 = 0o4000;
+const EFD_CLOEXEC: u64 = 0o2000000;
+
+// Clock ids (clock_gettime, timerfd_create)
+const CLOCK_REALTIME: u64 = 0;
+const CLOCK_MONOTONIC: u64 = 1;
+const CLOCK_BOOTTIME: u64 = 7;
+const CLOCK_REALTIME_ALARM: u64 = 8;
+const CLOCK_BOOTTIME_ALARM: u64 = 9;
+
+// timerfd_create / timerfd_settime flags
+const TFD_NONBLOCK: u64 = 0o4000;
+const TFD_CLOEXEC: u64 = 0o2000000;
+const TFD_TIMER_ABSTIME: u64 = 1;
+const TFD_TIMER_CANCEL_ON_SET: u64 = 2;
+
+// getrusage targets
+const RUSAGE_SELF: i64 = 0;
+const RUSAGE_CHILDREN: i64 = -1;
+const RUSAGE_THREAD: i64 = 1;
+
+// clock_t ticks per second of times(2) (USER_HZ)
+const USER_HZ: u64 = 100;
+
+// mount flags that change an existing mount (not supported)
+const MS_REMOUNT: u64 = 0x20;
+const MS_BIND: u64 = 0x1000;
+const MS_MOVE: u64 = 0x2000;
+
+// Socket constants
+#[cfg(feature = "net")]
+const AF_INET: u64 = 2;
+#[cfg(feature = "net")]
+const SOCK_DGRAM: u64 = 2;
+#[cfg(feature = "net")]
+const SOCK_TYPE_MASK: u64 = 0xF;
+#[cfg(feature = "net")]
+const SOCK_NONBLOCK: u64 = 0o4000;
+#[cfg(feature = "net")]
+const MSG_DONTWAIT: u64 = 0x40;
+#[cfg(feature = "net")]
+const SOCKADDR_IN_LEN: usize = 16;
+
+// Error numbers
+const EPERM: i64 = -1;
+const ENOENT: i64 = -2;
+const ESRCH: i64 = -3;
+const EIO: i64 = -5;
+const EINVAL: i64 = -22;
+const ENXIO: i64 = -6;
+const ENOMEM: i64 = -12;
+const ENODEV: i64 = -19;
+const ERANGE: i64 = -34;
+const EFAULT: i64 = -14;
+const ENAMETOOLONG: i64 = -36;
+#[cfg(feature = "net")]
+const ENOTSOCK: i64 = -88;
+#[cfg(feature = "net")]
+const ESOCKTNOSUPPORT: i64 = -94;
+#[cfg(feature = "net")]
+const EAFNOSUPPORT: i64 = -97;
+
+// End of the lower canonical half: FS/GS bases user space may set stay below
+const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+// Maximum length of a path passed from user space
+const PATH_MAX: usize = 4096;
 
 /// Initialize the syscall mechanism
 /// This sets up SYSCALL/SYSRET for handling system calls from user space
@@ -52,8 +212,19 @@ pub unsafe fn init(hhdm_offset: u64) {
     // Initialize ELF loader with HHDM offset
     crate::elf_loader::init_hhdm(hhdm_offset);
 
-    // Initialize kernel syscall stack
-    init_syscall_stack();
+    unsafe { init_cpu(0) };
+    log::info!("Handler initialized");
+}
+
+/// Set up SYSCALL/SYSRET and the per-CPU syscall state on CPU `cpu`
+/// Runs on that CPU, after `gdt::init_cpu` (the kernel stack is its RSP0 stack)
+pub unsafe fn init_cpu(cpu: usize) {
+    let local = &CPU_LOCAL[cpu];
+    set_kernel_stack(local, crate::gdt::kernel_stack_top(cpu));
+
+    // Outside the syscall path GS belongs to user space
+    GsBase::write(VirtAddr::zero());
+    KernelGsBase::write(VirtAddr::from_ptr(local));
 
     // Enable System Call Extensions (SCE) in EFER
     unsafe {
@@ -88,8 +259,67 @@ pub unsafe fn init(hhdm_offset: u64) {
         // Clear IF (interrupt flag) and TF (trap flag) on syscall entry
         SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG);
     }
+}
 
-    println!("[SYSCALL] Handler initialized");
+// ============================================================================
+// PER-CPU SYSCALL STATE
+// ============================================================================
+//
+// SYSCALL leaves RSP untouched, so the entry needs somewhere to stash the user
+// stack pointer and to find the kernel stack without a free register. Each CPU
+// has a CpuLocal block for that, reached through GS: the entry swaps it in with
+// SWAPGS and swaps the user's GS back before SYSRET. Nothing else in the kernel
+// uses GS, so interrupt and exception handlers need no SWAPGS of their own:
+//
+//   user mode, kernel outside the syscall path   GS = user, KERNEL_GS_BASE = CpuLocal
+//   syscall path (entry to SYSRET, and anything   GS = CpuLocal, KERNEL_GS_BASE = user
+//   it calls, such as the shell after exit)
+//
+// User GS values are always user addresses (arch_prctl checks them), which tells
+// the two states apart.
+
+#[repr(C)]
+pub struct CpuLocal {
+    // User RSP between SYSCALL and the first push on the kernel stack
+    user_rsp: AtomicU64,
+    // Top of the kernel stack syscalls run on: the CPU's, or a task's
+    kernel_rsp: AtomicU64,
+}
+
+const USER_RSP_OFFSET: usize = offset_of!(CpuLocal, user_rsp);
+const KERNEL_RSP_OFFSET: usize = offset_of!(CpuLocal, kernel_rsp);
+
+static CPU_LOCAL: [CpuLocal; crate::gdt::MAX_CPUS] = [const {
+    CpuLocal {
+        user_rsp: AtomicU64::new(0),
+        kernel_rsp: AtomicU64::new(0),
+    }
+}; crate::gdt::MAX_CPUS];
+
+/// The CpuLocal block of the running CPU, from whichever GS base holds it
+pub fn current_cpu() -> &'static CpuLocal {
+    let gs = GsBase::read();
+    let base = if gs.as_u64() >= USER_SPACE_END {
+        gs
+    } else {
+        KernelGsBase::read()
+    };
+    unsafe { &*base.as_ptr::<CpuLocal>() }
+}
+
+/// Make syscalls on this CPU run on the stack ending at `top` (16-byte aligned)
+/// A scheduler sets this, with the TSS RSP0, to the next task's kernel stack
+pub fn set_kernel_stack(local: &CpuLocal, top: VirtAddr) {
+    local.kernel_rsp.store(top.as_u64(), Ordering::Relaxed);
+}
+
+/// Put GS in its user-mode state before entering a program: the program's GS
+/// base (zero for a fresh one), this CPU's block in KERNEL_GS_BASE (entered from
+/// the syscall path or not)
+pub fn prepare_user_gs(user_gs: VirtAddr) {
+    let local = current_cpu();
+    GsBase::write(user_gs);
+    KernelGsBase::write(VirtAddr::from_ptr(local));
 }
 
 /// Syscall entry point (naked function)
@@ -108,16 +338,18 @@ pub unsafe fn init(hhdm_offset: u64) {
 #[unsafe(naked)]
 extern "C" fn syscall_entry() {
     naked_asm!(
-        // Save user stack pointer to a temporary location
+        // Switch GS to this CPU's block
+        "swapgs",
+
+        // Save user stack pointer in the per-CPU block
         // We cannot use a register like R12 because we must preserve it for the user
-        "mov [rip + TEMP_RSP], rsp",
+        "mov gs:[{user_rsp}], rsp",
 
-        // Switch to kernel stack
-        "lea rsp, [rip + KERNEL_SYSCALL_STACK_TOP]",
-        "mov rsp, [rsp]",
+        // Switch to this CPU's (or task's) kernel stack
+        "mov rsp, gs:[{kernel_rsp}]",
 
         // Push saved User Stack Pointer
-        "push qword ptr [rip + TEMP_RSP]",
+        "push qword ptr gs:[{user_rsp}]",
 
         // Save User RIP (RCX) and RFLAGS (R11)
         "push rcx",
@@ -201,34 +433,16 @@ extern "C" fn syscall_entry() {
         "pop rcx",       // User RIP
         "pop rsp",       // User RSP
 
-        // Return to userspace
+        // Give user space its GS back and return
+        "swapgs",
         "sysretq",
 
         handler = sym syscall_handler_inner,
+        user_rsp = const USER_RSP_OFFSET,
+        kernel_rsp = const KERNEL_RSP_OFFSET,
     );
 }
 
-#[unsafe(no_mangle)]
-static mut TEMP_RSP: u64 = 0;
-
-// Kernel syscall stack (16 KB)
-#[repr(C, align(16))]
-struct KernelSyscallStack([u8; 16384]);
-
-#[unsafe(no_mangle)]
-static mut KERNEL_SYSCALL_STACK: KernelSyscallStack = KernelSyscallStack([0; 16384]);
-
-#[unsafe(no_mangle)]
-static mut KERNEL_SYSCALL_STACK_TOP: u64 = 0;
-
-/// Initialize kernel syscall stack
-fn init_syscall_stack() {
-    unsafe {
-        let stack_ptr = core::ptr::addr_of!(KERNEL_SYSCALL_STACK) as u64;
-        KERNEL_SYSCALL_STACK_TOP = stack_ptr + 16384 - 8; // Align and leave space
-    }
-}
-
 /// Main syscall handler (called from assembly)
 #[unsafe(no_mangle)]
 extern "C" fn syscall_handler_inner(
@@ -250,47 +464,121 @@ fn handle_syscall(
     arg2: u64,
     arg3: u64,
     arg4: u64,
-    _arg5: u64,
-    _arg6: u64,
+    arg5: u64,
+    arg6: u64,
 ) -> i64 {
-    // Debug: log all syscalls
-    shared::serial::_print(format_args!(
-        "[SC] nr={} a1={:#x} a2={:#x} a3={:#x}\n",
-        nr, arg1, arg2, arg3
-    ));
+    // Every syscall and its result, with `syscall=trace` in /proc/log_level
+    log::trace!(
+        target: "syscall",
+        "nr={} a1={:#x} a2={:#x} a3={:#x}",
+        nr,
+        arg1,
+        arg2,
+        arg3
+    );
 
-    let result = match nr {
+    // The program's startup relocations are done by now
+    crate::elf_loader::apply_pending_relro();
+
+    let stamp = crate::rusage::syscall_enter();
+    let result = dispatch(nr, arg1, arg2, arg3, arg4, arg5, arg6);
+    crate::rusage::syscall_exit(stamp);
+
+    log::trace!(target: "syscall", "-> {}", result);
+    result
+}
+
+/// Run syscall `nr`, without the debug trace (also used by `bench`)
+// Only the socket syscalls take five or six arguments
+#[cfg_attr(not(feature = "net"), allow(unused_variables))]
+pub fn dispatch(nr: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64, arg6: u64) -> i64 {
+    match nr {
         SYS_WRITE => sys_write(arg1, arg2, arg3),
         SYS_READ => sys_read(arg1, arg2, arg3),
         SYS_EXIT => sys_exit(arg1),
         SYS_EXIT_GROUP => sys_exit_group(arg1),
         SYS_BRK => sys_brk(arg1),
-        SYS_MMAP => sys_mmap(arg1, arg2, arg3, arg4),
+        SYS_MMAP => sys_mmap(arg1, arg2, arg3, arg4, arg5, arg6),
         SYS_MPROTECT => sys_mprotect(arg1, arg2, arg3),
         SYS_MUNMAP => sys_munmap(arg1, arg2),
         SYS_ARCH_PRCTL => sys_arch_prctl(arg1, arg2),
+        SYS_PRCTL => sys_prctl(arg1, arg2),
         SYS_SET_TID_ADDRESS => sys_set_tid_address(arg1),
         SYS_POLL => sys_poll(arg1, arg2, arg3),
+        SYS_INIT_MODULE => sys_init_module(arg1, arg2, arg3),
+        SYS_DELETE_MODULE => sys_delete_module(arg1, arg2),
+        SYS_EVENTFD => sys_eventfd2(arg1, 0),
+        SYS_EVENTFD2 => sys_eventfd2(arg1, arg2),
+        SYS_CLOCK_GETTIME => sys_clock_gettime(arg1, arg2),
+        SYS_TIMERFD_CREATE => sys_timerfd_create(arg1, arg2),
+        SYS_TIMERFD_SETTIME => sys_timerfd_settime(arg1, arg2, arg3, arg4),
+        SYS_TIMERFD_GETTIME => sys_timerfd_gettime(arg1, arg2),
+        SYS_TIMES => sys_times(arg1),
+        SYS_GETRUSAGE => sys_getrusage(arg1, arg2),
         SYS_RT_SIGACTION => sys_rt_sigaction(arg1, arg2, arg3),
         SYS_RT_SIGPROCMASK => sys_rt_sigprocmask(arg1, arg2, arg3, arg4),
         SYS_SIGALTSTACK => sys_sigaltstack(arg1, arg2),
         SYS_GETRANDOM => sys_getrandom(arg1, arg2, arg3),
+        SYS_PTRACE => sys_ptrace(arg1, arg2, arg3, arg4),
         SYS_FSTAT => sys_fstat(arg1, arg2),
+        SYS_OPEN => sys_open(arg1, arg2),
+        SYS_OPENAT => sys_openat(arg1, arg2, arg3),
+        SYS_CLOSE => sys_close(arg1),
+        SYS_LSEEK => sys_lseek(arg1, arg2, arg3),
+        SYS_STAT => sys_stat(arg1, arg2, true),
+        SYS_LSTAT => sys_stat(arg1, arg2, false),
+        SYS_NEWFSTATAT => sys_newfstatat(arg1, arg2, arg3, arg4),
+        SYS_GETDENTS64 => sys_getdents64(arg1, arg2, arg3),
+        SYS_MKDIR => sys_mkdir(arg1),
+        SYS_MKDIRAT => sys_mkdir(arg2),
+        SYS_RMDIR => sys_rmdir(arg1),
+        SYS_UNLINK => sys_unlink(arg1),
+        SYS_UNLINKAT => sys_unlinkat(arg2, arg3),
+        SYS_FTRUNCATE => sys_ftruncate(arg1, arg2),
+        SYS_GETCWD => sys_getcwd(arg1, arg2),
+        SYS_CHDIR => sys_chdir(arg1),
+        SYS_READLINK => sys_readlink(arg1, arg2, arg3),
+        SYS_READLINKAT => sys_readlink(arg2, arg3, arg4),
+        #[cfg(feature = "net")]
+        SYS_SOCKET => sys_socket(arg1, arg2, arg3),
+        #[cfg(feature = "net")]
+        SYS_BIND => sys_bind(arg1, arg2, arg3),
+        #[cfg(feature = "net")]
+        SYS_CONNECT => sys_connect(arg1, arg2, arg3),
+        #[cfg(feature = "net")]
+        SYS_SENDTO => sys_sendto(arg1, arg2, arg3, arg4, arg5, arg6),
+        #[cfg(feature = "net")]
+        SYS_RECVFROM => sys_recvfrom(arg1, arg2, arg3, arg4, arg5, arg6),
+        #[cfg(feature = "net")]
+        SYS_GETSOCKNAME => sys_getsockname(arg1, arg2, arg3, false),
+        #[cfg(feature = "net")]
+        SYS_GETPEERNAME => sys_getsockname(arg1, arg2, arg3, true),
+        SYS_MOUNT => sys_mount(arg1, arg2, arg3, arg4),
+        SYS_UMOUNT2 => sys_umount2(arg1, arg2),
+        SYS_SYNC => sys_sync(),
         SYS_IOCTL => sys_ioctl(arg1, arg2, arg3),
         SYS_WRITEV => sys_writev(arg1, arg2, arg3),
-        SYS_MADVISE => 0, // Ignore madvise
+        SYS_MINCORE => sys_mincore(arg1, arg2, arg3),
+        SYS_MADVISE => sys_madvise(arg1, arg2, arg3),
         _ => {
-            println!("[SYSCALL] Unhandled syscall: {}", nr);
+            log::warn!("Unhandled syscall: {}", nr);
             -38 // ENOSYS
         }
-    };
-
-    shared::serial::_print(format_args!("[SC] -> {}\n", result));
-    result
+    }
 }
 
 /// SYS_WRITE - Write to file descriptor
 fn sys_write(fd: u64, buf: u64, count: u64) -> i64 {
+    // Descriptors above stderr are VFS files
+    if fd > STDERR {
+        let slice = unsafe { core::slice::from_raw_parts(buf as *const u8, count as usize) };
+        return fs_result(
+            fd::get(fd)
+                .and_then(|file| file.write(slice))
+                .map(|n| n as i64),
+        );
+    }
+
     // Only handle stdout (1) and stderr (2)
     if fd != STDOUT && fd != STDERR {
         return -9; // EBADF
@@ -299,39 +587,42 @@ fn sys_write(fd: u64, buf: u64, count: u64) -> i64 {
     // Safety: we trust the user pointer for now
     // In a real kernel, we would validate this
     let slice = unsafe { core::slice::from_raw_parts(buf as *const u8, count as usize) };
-
-    // Convert to string and print
-    if let Ok(s) = core::str::from_utf8(slice) {
-        // Print without adding newline
-        for c in s.chars() {
-            crate::screen::print_char(c);
-        }
-        // Also print to serial
-        shared::serial::_print(format_args!("{}", s));
-    } else {
-        // Print raw bytes as characters
-        for &byte in slice {
-            crate::screen::print_char(byte as char);
-        }
-    }
+    crate::tty::write(slice);
 
     count as i64
 }
 
 /// SYS_READ - Read from file descriptor
-fn sys_read(_fd: u64, _buf: u64, _count: u64) -> i64 {
-    // Not implemented - return 0 (EOF)
-    0
+fn sys_read(fd: u64, buf: u64, count: u64) -> i64 {
+    // Descriptors above stderr are VFS files
+    if fd > STDERR {
+        let slice = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, count as usize) };
+        return fs_result(
+            fd::get(fd)
+                .and_then(|file| file.read(slice))
+                .map(|n| n as i64),
+        );
+    }
+
+    // Standard descriptors are the console
+    let slice = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, count as usize) };
+    fs_result(crate::tty::read(slice).map(|n| n as i64))
 }
 
 /// SYS_EXIT - Exit process
 fn sys_exit(status: u64) -> i64 {
-    println!("\n[KERNEL] User process exited with status: {}", status);
+    log::info!("User process exited with status: {}", status);
+    end_program(status)
+}
 
-    // Halt the system (for now, we just loop)
-    loop {
-        x86_64::instructions::hlt();
-    }
+/// The running program is gone (exit, or killed by a fault)
+/// No other process to return to: drop into the kernel shell, or go on with the
+/// integration test suite
+pub fn end_program(status: u64) -> ! {
+    #[cfg(feature = "integration-tests")]
+    crate::itest::program_exited(status);
+    #[cfg(not(feature = "integration-tests"))]
+    crate::shell::program_exited(status)
 }
 
 /// SYS_EXIT_GROUP - Exit all threads
@@ -340,85 +631,297 @@ fn sys_exit_group(status: u64) -> i64 {
 }
 
 /// SYS_BRK - Change data segment size
+/// The heap is an area of the address space from the end of the program to the
+/// break (see vma.rs); an address the break cannot move to (below the start, or
+/// into another mapping) leaves it where it is, which the caller sees as failure
 fn sys_brk(addr: u64) -> i64 {
-    unsafe {
-        if addr == 0 {
-            // Return current break
-            return CURRENT_BRK as i64;
-        }
-
-        // Simple implementation: just update the break
-        // In a real kernel, we would allocate/deallocate pages
-        if addr >= 0x800_0000 && addr < 0x1_0000_0000 {
-            // Allow brk within reasonable range (128MB to 4GB)
-            CURRENT_BRK = addr;
-            addr as i64
-        } else if addr < CURRENT_BRK {
-            // Allow shrinking
-            CURRENT_BRK = addr;
-            addr as i64
-        } else {
-            // For larger allocations, just accept them for now
-            CURRENT_BRK = addr;
-            addr as i64
-        }
-    }
+    crate::vma::brk(addr) as i64
 }
 
 /// SYS_MMAP - Map memory
-/// NOTE: This is a simple implementation that returns addresses from a pre-allocated pool.
-/// For musl static PIE, we use addresses that should be in the already-loaded ELF's BSS
-/// or we return addresses from a range we'll pre-map.
-fn sys_mmap(addr: u64, length: u64, _prot: u64, flags: u64) -> i64 {
-    // Flags bit 0x20 = MAP_ANONYMOUS
-    let _is_anon = (flags & 0x20) != 0;
-
-    // Use memory starting after the ELF's data segment
-    // ELF loads at 0x400000, data ends around 0x470320,
-    // we use region starting at 0x480000 (which is mapped as part of user space)
-    static mut MMAP_NEXT: u64 = 0x480000;
-    static mut MMAP_END: u64 = 0x500000; // Must match MMAP_POOL_END in elf_loader
+/// Private mappings are anonymous memory, demand paged (see vma.rs): the pages
+/// are faulted in on first access, or mapped zeroed here with MAP_POPULATE.
+/// MAP_FIXED replaces whatever is mapped at `addr`, MAP_FIXED_NOREPLACE fails
+/// with EEXIST instead; otherwise the hint is taken if nothing is mapped there,
+/// or the lowest free range of the mmap window is
+fn sys_mmap(addr: u64, length: u64, prot: u64, flags: u64, fd: u64, offset: u64) -> i64 {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return EINVAL;
+    }
+    if flags & MAP_SHARED != 0 {
+        return mmap_shared(length, prot, flags, fd, offset);
+    }
+    let fixed = flags & (MAP_FIXED | MAP_FIXED_NOREPLACE) != 0;
+    if length == 0 || (fixed && addr & 0xFFF != 0) {
+        return EINVAL;
+    }
+    // Private file mappings would need their pages read in
+    if flags & MAP_ANONYMOUS == 0 {
+        return ENODEV;
+    }
+    let Some(length) = length.checked_next_multiple_of(4096) else {
+        return ENOMEM;
+    };
+    let vma = Vma::new(0..0, prot, VmaKind::Anonymous);
+    let at = |start: u64| {
+        let end = start
+            .checked_add(length)
+            .ok_or(crate::vma::VmaError::NoMemory)?;
+        let vma = Vma {
+            range: start..end,
+            ..vma.clone()
+        };
+        if flags & MAP_FIXED != 0 {
+            crate::vma::unmap(vma.range.clone())?;
+        }
+        crate::vma::map(vma).map(|()| start)
+    };
 
-    unsafe {
-        let aligned_len = (length + 0xFFF) & !0xFFF; // Page align
+    let result = if fixed {
+        at(addr)
+    } else if addr != 0 && addr & 0xFFF == 0 && at(addr).is_ok() {
+        Ok(addr)
+    } else {
+        let window = crate::elf_loader::MMAP_START..crate::elf_loader::MMAP_END;
+        crate::vma::map_anywhere(length, window, vma.clone())
+    };
+    let start = match result {
+        Ok(start) => start,
+        Err(err) => return err.errno(),
+    };
 
-        let result = if addr != 0 && addr >= 0x1000 {
-            // Fixed mapping requested
-            addr
-        } else {
-            // Allocate from our pool
-            if MMAP_NEXT + aligned_len > MMAP_END {
-                // Out of memory
-                return -12; // ENOMEM
-            }
-            let alloc_addr = MMAP_NEXT;
-            MMAP_NEXT += aligned_len;
-            alloc_addr
-        };
+    let range = start..start + length;
+    if flags & MAP_POPULATE != 0 && !range.clone().step_by(4096).all(crate::vma::populate) {
+        let _ = crate::vma::unmap(range);
+        return ENOMEM;
+    }
+    start as i64
+}
 
-        // Note: Ideally we should map these pages here, but we don't have access
-        // to the page mapper in syscall context. This works if the pages are
-        // pre-mapped by the ELF loader for the BSS/heap region.
+/// MAP_SHARED: map the frames of a shared memory object, a new one for anonymous
+/// memory or the one of a /dev/shm file, so every mapping sees the same memory;
+/// a device node (/dev/fb0) maps its own memory
+fn mmap_shared(length: u64, prot: u64, flags: u64, fd: u64, offset: u64) -> i64 {
+    use crate::fs::shmfs::SharedMemory;
+    use x86_64::structures::paging::{Mapper, Page, Size4KiB};
 
-        result as i64
+    if length == 0 || offset & 0xFFF != 0 || flags & MAP_FIXED != 0 {
+        return EINVAL;
     }
+    let pages = length.div_ceil(4096);
+    let (object, file) = if flags & MAP_ANONYMOUS != 0 {
+        match SharedMemory::new(pages * 4096) {
+            Ok(object) => (Some(object), None),
+            Err(_) => return ENOMEM,
+        }
+    } else {
+        match fd::get(fd) {
+            Ok(file) => (file.shared_memory(), Some(file)),
+            Err(err) => return err.errno(),
+        }
+    };
+    let frame = |index: u64| match (&object, &file) {
+        (Some(object), _) => object.frame(index),
+        (None, Some(file)) => file.mmap_frame(index),
+        (None, None) => None,
+    };
+    if object.is_none() && frame(0).is_none() {
+        return ENODEV;
+    }
+    // Pages past the end of the object cannot be backed (Linux maps them and
+    // raises SIGBUS on access)
+    let first = offset / 4096;
+    if frame(first + pages - 1).is_none() {
+        return ENXIO;
+    }
+
+    // The frames stay the object's when the area goes away, and the object
+    // stays until no area maps it
+    let window = crate::elf_loader::SHARED_MAP_START..crate::elf_loader::SHARED_MAP_END;
+    let vma = Vma {
+        shared: true,
+        object: object.as_ref().map(SharedMemory::map),
+        ..Vma::new(0..0, prot, VmaKind::File)
+    };
+    let start = match crate::vma::map_anywhere(pages * 4096, window, vma) {
+        Ok(start) => start,
+        Err(err) => return err.errno(),
+    };
+
+    let page_flags = crate::vma::page_flags(prot);
+    let mut mapper = unsafe { crate::pml4::init_mapper(crate::elf_loader::get_hhdm_offset()) };
+    let mut frame_allocator = crate::pmm::KernelFrameAllocator;
+    for i in 0..pages {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(start + i * 4096));
+        let mapped = frame(first + i).is_some_and(|frame| {
+            unsafe { mapper.map_to(page, frame, page_flags, &mut frame_allocator) }
+                .map(|flush| flush.flush())
+                .is_ok()
+        });
+        if !mapped {
+            let _ = crate::vma::unmap(start..start + pages * 4096);
+            return ENOMEM;
+        }
+    }
+    start as i64
 }
 
 /// SYS_MPROTECT - Change memory protection
-fn sys_mprotect(_addr: u64, _len: u64, _prot: u64) -> i64 {
-    // Stub: pretend it worked
-    0
+/// The whole range must be mapped, in user space (ENOMEM otherwise); the PTEs of
+/// the pages already mapped change with the areas, so a write to a page made
+/// read-only, or a jump into one made NX, faults from then on
+fn sys_mprotect(addr: u64, len: u64, prot: u64) -> i64 {
+    if addr & 0xFFF != 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return EINVAL;
+    }
+    if len == 0 {
+        return 0;
+    }
+    let Some(end) = addr
+        .checked_add(len)
+        .and_then(|end| end.checked_next_multiple_of(4096))
+        .filter(|&end| end <= USER_SPACE_END && addr != 0)
+    else {
+        return ENOMEM;
+    };
+    match crate::vma::protect(addr..end, prot) {
+        Ok(()) => 0,
+        Err(err) => err.errno(),
+    }
 }
 
 /// SYS_MUNMAP - Unmap memory
-fn sys_munmap(_addr: u64, _len: u64) -> i64 {
-    // Stub: pretend it worked
+/// Parts of the range that are not mapped are skipped
+fn sys_munmap(addr: u64, len: u64) -> i64 {
+    let Some(end) = addr
+        .checked_add(len)
+        .and_then(|end| end.checked_next_multiple_of(4096))
+    else {
+        return EINVAL;
+    };
+    if addr & 0xFFF != 0 || len == 0 {
+        return EINVAL;
+    }
+    match crate::vma::unmap(addr..end) {
+        Ok(()) => 0,
+        Err(err) => err.errno(),
+    }
+}
+
+/// Kernel (HHDM) address of the user page at `addr`, None if it is not mapped
+/// Memory is never paged out, so a mapped page is a resident one; demand-paged
+/// memory is not mapped before its first access
+fn user_page(addr: u64) -> Option<*mut u8> {
+    use x86_64::structures::paging::{PageTableFlags, Translate, mapper::TranslateResult};
+
+    if addr >= USER_SPACE_END {
+        return None;
+    }
+    let hhdm = crate::elf_loader::get_hhdm_offset();
+    let mapper = unsafe { crate::pml4::init_mapper(hhdm) };
+    match mapper.translate(VirtAddr::new(addr)) {
+        TranslateResult::Mapped {
+            frame,
+            offset,
+            flags,
+        } if flags.contains(PageTableFlags::USER_ACCESSIBLE) => {
+            Some((frame.start_address().as_u64() + offset + hhdm) as *mut u8)
+        }
+        _ => None,
+    }
+}
+
+/// Pages of [addr, addr + len): EINVAL if `addr` is not page aligned, ENOMEM if
+/// the range leaves user space
+fn user_pages(addr: u64, len: u64) -> Result<core::ops::Range<u64>, i64> {
+    if addr & 0xFFF != 0 {
+        return Err(EINVAL);
+    }
+    let end = addr
+        .checked_add(len)
+        .and_then(|end| end.checked_add(0xFFF))
+        .map(|end| end & !0xFFF)
+        .filter(|&end| end <= USER_SPACE_END)
+        .ok_or(ENOMEM)?;
+    Ok(addr / 4096..end / 4096)
+}
+
+/// SYS_MADVISE - Advise on the use of memory
+/// The anonymous memory is private mmap memory, the brk heap and the stack,
+/// demand paged (see vma.rs). MADV_DONTNEED gives their pages back, the next access faults in a
+/// zeroed one; MADV_FREE may leave them as they are, and does, since nothing
+/// reclaims memory
+fn sys_madvise(addr: u64, len: u64, advice: u64) -> i64 {
+    let pages = match user_pages(addr, len) {
+        Ok(pages) => pages,
+        Err(err) => return err,
+    };
+    let valid = |page: u64| user_page(page * 4096).is_some() || crate::vma::contains(page * 4096);
+    if !pages.clone().all(valid) {
+        return ENOMEM;
+    }
+    match advice {
+        MADV_NORMAL | MADV_RANDOM | MADV_SEQUENTIAL | MADV_WILLNEED | MADV_FREE => 0,
+        MADV_DONTNEED => {
+            // File-backed pages would have to be read back from their file
+            let anonymous =
+                |page: u64| crate::vma::find(page * 4096).is_some_and(|vma| vma.demand_paged());
+            if !pages.clone().all(anonymous) {
+                return EINVAL;
+            }
+            for page in pages {
+                crate::vma::discard(page * 4096);
+            }
+            0
+        }
+        _ => EINVAL,
+    }
+}
+
+/// SYS_MINCORE - Report which pages of a range are resident
+/// ENOMEM if part of the range is not mapped, EFAULT if `vec` cannot take a byte
+/// per page
+fn sys_mincore(addr: u64, len: u64, vec: u64) -> i64 {
+    let pages = match user_pages(addr, len) {
+        Ok(pages) => pages,
+        Err(err) => return err,
+    };
+    let resident = |page: u64| user_page(page * 4096).is_some();
+    let valid = |page: u64| resident(page) || crate::vma::contains(page * 4096);
+    if !pages.clone().all(valid) {
+        return ENOMEM;
+    }
+    if !user_writable(vec, pages.end - pages.start) {
+        return EFAULT;
+    }
+    // One byte per page, written as it is looked up
+    for (i, page) in pages.enumerate() {
+        unsafe { *((vec + i as u64) as *mut u8) = resident(page) as u8 };
+    }
     0
 }
 
+/// Whether [addr, addr + len) lies in areas the program may write, so the
+/// kernel can write there directly (untouched pages fault in like the program's)
+fn user_writable(addr: u64, len: u64) -> bool {
+    let Some(end) = addr.checked_add(len) else {
+        return false;
+    };
+    let mut next = addr;
+    while next < end {
+        match crate::vma::find(next) {
+            Some(vma) if vma.prot & crate::vma::PROT_WRITE != 0 => next = vma.range.end,
+            _ => return false,
+        }
+    }
+    true
+}
+
 /// SYS_ARCH_PRCTL - Architecture-specific thread control
+/// The user GS base sits in KERNEL_GS_BASE while a syscall runs (see CpuLocal)
 fn sys_arch_prctl(code: u64, addr: u64) -> i64 {
     match code {
+        ARCH_SET_FS | ARCH_SET_GS if addr >= USER_SPACE_END => EPERM,
         ARCH_SET_FS => {
             // Set FS base for TLS
             x86_64::registers::model_specific::FsBase::write(VirtAddr::new(addr));
@@ -433,11 +936,11 @@ fn sys_arch_prctl(code: u64, addr: u64) -> i64 {
             0
         }
         ARCH_SET_GS => {
-            x86_64::registers::model_specific::GsBase::write(VirtAddr::new(addr));
+            KernelGsBase::write(VirtAddr::new(addr));
             0
         }
         ARCH_GET_GS => {
-            let gs = x86_64::registers::model_specific::GsBase::read();
+            let gs = KernelGsBase::read();
             unsafe {
                 *(addr as *mut u64) = gs.as_u64();
             }
@@ -447,16 +950,79 @@ fn sys_arch_prctl(code: u64, addr: u64) -> i64 {
     }
 }
 
+/// SYS_PRCTL - Process control
+/// Only the capability options: read one, or drop it for good
+fn sys_prctl(option: u64, arg: u64) -> i64 {
+    let Ok(cap) = u32::try_from(arg) else {
+        return EINVAL;
+    };
+    match option {
+        PR_CAPBSET_READ if cap < caps::COUNT => caps::has(cap) as i64,
+        PR_CAPBSET_DROP if caps::drop(cap) => 0,
+        _ => EINVAL,
+    }
+}
+
+// EPERM unless the program holds `cap`
+fn require(cap: u32) -> Result<(), i64> {
+    if caps::has(cap) { Ok(()) } else { Err(EPERM) }
+}
+
 /// SYS_SET_TID_ADDRESS - Set pointer to thread ID
 fn sys_set_tid_address(_tidptr: u64) -> i64 {
     // Return a fake TID
     1
 }
 
-/// SYS_POLL - Poll file descriptors
-fn sys_poll(_fds: u64, _nfds: u64, _timeout: u64) -> i64 {
-    // Return 0 (timeout with no events)
-    0
+/// struct pollfd
+#[repr(C)]
+struct PollFd {
+    fd: i32,
+    events: i16,
+    revents: i16,
+}
+
+/// Readiness of one descriptor, as POLL* bits
+fn poll_fd(fd: i32) -> u16 {
+    match fd as u64 {
+        0 => crate::tty::poll(),
+        STDOUT | STDERR => fs::POLLOUT,
+        _ => fd::get(fd as u64).map_or(POLLNVAL, |file| file.poll()),
+    }
+}
+
+/// SYS_POLL - Wait for file descriptors to become ready
+/// Timeout in milliseconds (timer ticks), negative to wait forever
+fn sys_poll(fds: u64, nfds: u64, timeout: u64) -> i64 {
+    if nfds > fd::MAX_FDS as u64 {
+        return EINVAL;
+    }
+    if fds == 0 && nfds != 0 {
+        return EFAULT;
+    }
+    let entries = unsafe { core::slice::from_raw_parts_mut(fds as *mut PollFd, nfds as usize) };
+    let timeout = timeout as i32;
+    let deadline = crate::interrupts::TICKS
+        .load(Ordering::Relaxed)
+        .saturating_add(timeout as u64);
+
+    crate::idle::wait_for(|| {
+        let mut ready = 0;
+        for entry in entries.iter_mut() {
+            // Negative descriptors are skipped
+            entry.revents = 0;
+            if entry.fd < 0 {
+                continue;
+            }
+            let revents = poll_fd(entry.fd) & (entry.events as u16 | POLLNVAL);
+            entry.revents = revents as i16;
+            if revents != 0 {
+                ready += 1;
+            }
+        }
+        let expired = timeout >= 0 && crate::interrupts::TICKS.load(Ordering::Relaxed) >= deadline;
+        (ready > 0 || expired).then_some(ready)
+    })
 }
 
 /// SYS_RT_SIGACTION - Set signal action
@@ -509,13 +1075,314 @@ fn sys_sigaltstack(_ss: u64, old_ss: u64) -> i64 {
     0
 }
 
+/// SYS_PTRACE - Trace a program (see ptrace.rs)
+/// The raw syscall, as glibc issues it: PEEK* store the word at `data`
+/// The caller is the only program, so it is never the stopped tracee the
+/// other requests need: they fail with ESRCH until there is a scheduler
+fn sys_ptrace(request: u64, pid: u64, addr: u64, data: u64) -> i64 {
+    use crate::ptrace::{self, PtraceError};
+
+    if request == ptrace::PTRACE_TRACEME {
+        ptrace::trace_me();
+        return 0;
+    }
+    if pid != ptrace::TRACEE_PID {
+        return ESRCH;
+    }
+    let result = match request {
+        ptrace::PTRACE_PEEKTEXT | ptrace::PTRACE_PEEKDATA => {
+            ptrace::peek(addr).map(|word| unsafe { *(data as *mut u64) = word })
+        }
+        ptrace::PTRACE_POKETEXT | ptrace::PTRACE_POKEDATA => ptrace::poke(addr, data),
+        ptrace::PTRACE_GETREGS => {
+            ptrace::regs().map(|regs| unsafe { *(data as *mut ptrace::UserRegs) = regs })
+        }
+        ptrace::PTRACE_SETREGS => ptrace::set_regs(unsafe { *(data as *const ptrace::UserRegs) }),
+        ptrace::PTRACE_CONT => ptrace::resume(false).map(|never| match never {}),
+        ptrace::PTRACE_SINGLESTEP => ptrace::resume(true).map(|never| match never {}),
+        _ => return EINVAL,
+    };
+    match result {
+        Ok(()) => 0,
+        Err(PtraceError::NotStopped) => ESRCH,
+        Err(PtraceError::BadAddress) => EIO,
+    }
+}
+
+/// SYS_EVENTFD2 - Create an eventfd counter (eventfd is eventfd2 without flags)
+fn sys_eventfd2(initval: u64, flags: u64) -> i64 {
+    // There is no exec from user space, so close-on-exec has nothing to do
+    if flags & !(EFD_SEMAPHORE | EFD_NONBLOCK | EFD_CLOEXEC) != 0 {
+        return EINVAL;
+    }
+    let event = EventFd::new(
+        initval as u32 as u64,
+        flags & EFD_SEMAPHORE != 0,
+        flags & EFD_NONBLOCK != 0,
+    );
+    fs_result(fd::install(event).map(|fd| fd as i64))
+}
+
+/// struct timespec
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Timespec {
+    tv_sec: i64,
+    tv_nsec: i64,
+}
+
+impl Timespec {
+    const NS_PER_SEC: u64 = 1_000_000_000;
+
+    fn from_ns(ns: u64) -> Self {
+        Self {
+            tv_sec: (ns / Self::NS_PER_SEC) as i64,
+            tv_nsec: (ns % Self::NS_PER_SEC) as i64,
+        }
+    }
+
+    fn to_ns(self) -> Option<u64> {
+        if self.tv_sec < 0 || !(0..Self::NS_PER_SEC as i64).contains(&self.tv_nsec) {
+            return None;
+        }
+        (self.tv_sec as u64)
+            .checked_mul(Self::NS_PER_SEC)?
+            .checked_add(self.tv_nsec as u64)
+    }
+}
+
+/// struct itimerspec
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Itimerspec {
+    it_interval: Timespec,
+    it_value: Timespec,
+}
+
+impl Itimerspec {
+    fn from_ns((value, interval): (u64, u64)) -> Self {
+        Self {
+            it_interval: Timespec::from_ns(interval),
+            it_value: Timespec::from_ns(value),
+        }
+    }
+}
+
+/// A clock id, and whether it is one of the alarm clocks that wake from suspend
+fn clock_from_id(id: u64) -> Option<(Clock, bool)> {
+    match id {
+        CLOCK_REALTIME => Some((Clock::Realtime, false)),
+        CLOCK_MONOTONIC => Some((Clock::Monotonic, false)),
+        CLOCK_BOOTTIME => Some((Clock::Boottime, false)),
+        CLOCK_REALTIME_ALARM => Some((Clock::Realtime, true)),
+        CLOCK_BOOTTIME_ALARM => Some((Clock::Boottime, true)),
+        _ => None,
+    }
+}
+
+/// SYS_CLOCK_GETTIME - Read a clock
+fn sys_clock_gettime(clock_id: u64, tp: u64) -> i64 {
+    let Some((clock, _)) = clock_from_id(clock_id) else {
+        return EINVAL;
+    };
+    if tp == 0 {
+        return EFAULT;
+    }
+    unsafe { (tp as *mut Timespec).write_unaligned(Timespec::from_ns(clock.now_ns())) };
+    0
+}
+
+/// SYS_TIMERFD_CREATE - Create a timer behind a descriptor
+fn sys_timerfd_create(clock_id: u64, flags: u64) -> i64 {
+    let Some((clock, alarm)) = clock_from_id(clock_id) else {
+        return EINVAL;
+    };
+    // There is no exec from user space, so close-on-exec has nothing to do
+    if flags & !(TFD_NONBLOCK | TFD_CLOEXEC) != 0 {
+        return EINVAL;
+    }
+    let timer = TimerFd::new(clock, alarm, flags & TFD_NONBLOCK != 0);
+    fs_result(fd::install(timer).map(|fd| fd as i64))
+}
+
+/// SYS_TIMERFD_SETTIME - Arm or disarm a timerfd, returning the old setting
+/// The clock is never set, so TFD_TIMER_CANCEL_ON_SET has nothing to cancel
+fn sys_timerfd_settime(fd: u64, flags: u64, new_value: u64, old_value: u64) -> i64 {
+    if flags & !(TFD_TIMER_ABSTIME | TFD_TIMER_CANCEL_ON_SET) != 0 {
+        return EINVAL;
+    }
+    let file = match fd::get(fd) {
+        Ok(file) => file,
+        Err(err) => return err.errno(),
+    };
+    let Some(timer) = file.as_timer() else {
+        return EINVAL;
+    };
+    if new_value == 0 {
+        return EFAULT;
+    }
+    let new = unsafe { (new_value as *const Itimerspec).read_unaligned() };
+    let (Some(value), Some(interval)) = (new.it_value.to_ns(), new.it_interval.to_ns()) else {
+        return EINVAL;
+    };
+
+    let old = timer.set(value, interval, flags & TFD_TIMER_ABSTIME != 0);
+    if old_value != 0 {
+        unsafe { (old_value as *mut Itimerspec).write_unaligned(Itimerspec::from_ns(old)) };
+    }
+    0
+}
+
+/// SYS_TIMERFD_GETTIME - Time left until a timerfd expires, and its interval
+fn sys_timerfd_gettime(fd: u64, curr_value: u64) -> i64 {
+    let file = match fd::get(fd) {
+        Ok(file) => file,
+        Err(err) => return err.errno(),
+    };
+    let Some(timer) = file.as_timer() else {
+        return EINVAL;
+    };
+    if curr_value == 0 {
+        return EFAULT;
+    }
+    unsafe { (curr_value as *mut Itimerspec).write_unaligned(Itimerspec::from_ns(timer.get())) };
+    0
+}
+
+/// struct tms
+#[repr(C)]
+struct Tms {
+    tms_utime: i64,
+    tms_stime: i64,
+    tms_cutime: i64,
+    tms_cstime: i64,
+}
+
+/// SYS_TIMES - CPU time of the program, in USER_HZ ticks
+/// Returns the ticks since boot; there are no child processes to add up
+fn sys_times(buf: u64) -> i64 {
+    let usage = crate::rusage::usage();
+    let ticks = |us: u64| (us / (1_000_000 / USER_HZ)) as i64;
+    if buf != 0 {
+        let tms = Tms {
+            tms_utime: ticks(usage.user_us),
+            tms_stime: ticks(usage.system_us),
+            tms_cutime: 0,
+            tms_cstime: 0,
+        };
+        unsafe { (buf as *mut Tms).write_unaligned(tms) };
+    }
+    ticks(crate::interrupts::uptime_ns() / 1000)
+}
+
+/// struct timeval
+#[repr(C)]
+#[derive(Default)]
+struct Timeval {
+    tv_sec: i64,
+    tv_usec: i64,
+}
+
+impl Timeval {
+    fn from_us(us: u64) -> Self {
+        Self {
+            tv_sec: (us / 1_000_000) as i64,
+            tv_usec: (us % 1_000_000) as i64,
+        }
+    }
+}
+
+/// struct rusage (the fields after ru_majflt are never counted)
+#[repr(C)]
+#[derive(Default)]
+struct Rusage {
+    ru_utime: Timeval,
+    ru_stime: Timeval,
+    ru_maxrss: i64, // KiB
+    ru_ixrss: i64,
+    ru_idrss: i64,
+    ru_isrss: i64,
+    ru_minflt: i64,
+    ru_majflt: i64,
+    ru_nswap: i64,
+    ru_inblock: i64,
+    ru_oublock: i64,
+    ru_msgsnd: i64,
+    ru_msgrcv: i64,
+    ru_nsignals: i64,
+    ru_nvcsw: i64,
+    ru_nivcsw: i64,
+}
+
+/// SYS_GETRUSAGE - Resource usage of the program (its only thread), or of its
+/// children, which are always none; nothing is read from disk, so no major faults
+fn sys_getrusage(who: u64, usage: u64) -> i64 {
+    let rusage = match who as i64 {
+        RUSAGE_SELF | RUSAGE_THREAD => {
+            let usage = crate::rusage::usage();
+            Rusage {
+                ru_utime: Timeval::from_us(usage.user_us),
+                ru_stime: Timeval::from_us(usage.system_us),
+                ru_maxrss: usage.max_rss_kib as i64,
+                ru_minflt: usage.minor_faults as i64,
+                ..Rusage::default()
+            }
+        }
+        RUSAGE_CHILDREN => Rusage::default(),
+        _ => return EINVAL,
+    };
+    if usage == 0 {
+        return EFAULT;
+    }
+    unsafe { (usage as *mut Rusage).write_unaligned(rusage) };
+    0
+}
+
+/// SYS_INIT_MODULE - Load a kernel module from a user buffer
+/// Module parameters are not supported
+fn sys_init_module(image: u64, len: u64, params: u64) -> i64 {
+    if let Err(errno) = require(caps::CAP_MODULE) {
+        return errno;
+    }
+    if image == 0 {
+        return EFAULT;
+    }
+    match user_str(params) {
+        Ok(params) if params.is_empty() => {}
+        Ok(_) => return EINVAL,
+        Err(errno) => return errno,
+    }
+    let image = unsafe { core::slice::from_raw_parts(image as *const u8, len as usize) };
+    match crate::module::load(image) {
+        Ok(_) => 0,
+        Err(err) => err.errno(),
+    }
+}
+
+/// SYS_DELETE_MODULE - Unload a kernel module (flags are ignored)
+fn sys_delete_module(name: u64, _flags: u64) -> i64 {
+    if let Err(errno) = require(caps::CAP_MODULE) {
+        return errno;
+    }
+    let name = match user_str(name) {
+        Ok(name) => name,
+        Err(errno) => return errno,
+    };
+    match crate::module::unload(&name) {
+        Ok(()) => 0,
+        Err(err) => err.errno(),
+    }
+}
+
 /// SYS_GETRANDOM - Get random bytes
 fn sys_getrandom(buf: u64, buflen: u64, _flags: u64) -> i64 {
-    // Simple pseudo-random implementation
-    // In a real kernel, use a proper RNG
     let slice = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, buflen as usize) };
+    // The virtio entropy device if there is one
+    if crate::virtio_rng::fill(slice) {
+        return buflen as i64;
+    }
 
-    // Use a simple LFSR or just timestamp-based pseudo-random
+    // Otherwise a simple pseudo-random generator, not fit for keys
     static mut SEED: u64 = 0x12345678DEADBEEF;
 
     for byte in slice.iter_mut() {
@@ -530,6 +1397,14 @@ fn sys_getrandom(buf: u64, buflen: u64, _flags: u64) -> i64 {
 
 /// SYS_FSTAT - Get file status
 fn sys_fstat(fd: u64, statbuf: u64) -> i64 {
+    // Descriptors above stderr are VFS files
+    if fd > STDERR {
+        return match fd::get(fd) {
+            Ok(file) => write_stat(statbuf, &file.metadata()),
+            Err(err) => err.errno(),
+        };
+    }
+
     // Fill in a minimal stat structure for stdin/stdout/stderr
     if fd <= STDERR {
         // Zero out the stat buffer (144 bytes on Linux x86_64)
@@ -548,9 +1423,12 @@ fn sys_fstat(fd: u64, statbuf: u64) -> i64 {
 }
 
 /// SYS_IOCTL - I/O control
-fn sys_ioctl(_fd: u64, _request: u64, _arg: u64) -> i64 {
-    // Stub: return ENOTTY for most ioctls
-    -25 // ENOTTY
+/// Device nodes handle their own requests, the standard descriptors the console's (termios)
+fn sys_ioctl(fd: u64, request: u64, arg: u64) -> i64 {
+    if fd <= STDERR {
+        return fs_result(crate::tty::ioctl(request, arg));
+    }
+    fs_result(fd::get(fd).and_then(|file| file.ioctl(request, arg)))
 }
 
 /// SYS_WRITEV - Write vector
@@ -574,3 +1452,476 @@ fn sys_writev(fd: u64, iov: u64, iovcnt: u64) -> i64 {
 
     total
 }
+
+// ============================================================================
+// FILESYSTEM SYSCALLS
+// ============================================================================
+
+/// Convert a VFS result into a syscall return value
+fn fs_result(result: Result<i64, FsError>) -> i64 {
+    result.unwrap_or_else(|err| err.errno())
+}
+
+/// Copy a NUL-terminated string from user space
+fn user_str(ptr: u64) -> Result<String, i64> {
+    if ptr == 0 {
+        return Err(EFAULT);
+    }
+
+    let mut len = 0;
+    unsafe {
+        while *((ptr + len as u64) as *const u8) != 0 {
+            len += 1;
+            if len >= PATH_MAX {
+                return Err(ENAMETOOLONG);
+            }
+        }
+    }
+
+    let bytes = unsafe { core::slice::from_raw_parts(ptr as *const u8, len) };
+    core::str::from_utf8(bytes)
+        .map(String::from)
+        .map_err(|_| EINVAL)
+}
+
+/// Copy a NUL-terminated path from user space
+/// Relative paths are resolved by the VFS against the working directory
+fn user_path(ptr: u64) -> Result<String, i64> {
+    let path = user_str(ptr)?;
+    if path.is_empty() {
+        return Err(ENOENT);
+    }
+    Ok(path)
+}
+
+/// Fill a Linux x86_64 `struct stat` (144 bytes)
+fn write_stat(statbuf: u64, meta: &Metadata) -> i64 {
+    if statbuf == 0 {
+        return EFAULT;
+    }
+
+    let buf = statbuf as *mut u8;
+    unsafe {
+        core::ptr::write_bytes(buf, 0, 144);
+        *(buf.add(8) as *mut u64) = meta.ino; // st_ino
+        *(buf.add(16) as *mut u64) = meta.nlink as u64; // st_nlink
+        *(buf.add(24) as *mut u32) = meta.file_type.mode_bits() | meta.mode; // st_mode
+        *(buf.add(48) as *mut i64) = meta.size as i64; // st_size
+        *(buf.add(56) as *mut i64) = 4096; // st_blksize
+        *(buf.add(64) as *mut i64) = meta.size.div_ceil(512) as i64; // st_blocks
+    }
+    0
+}
+
+/// SYS_OPEN - Open a file
+fn sys_open(path: u64, flags: u64) -> i64 {
+    let path = match user_path(path) {
+        Ok(path) => path,
+        Err(errno) => return errno,
+    };
+
+    let file = match fs::open(&path, flags as u32) {
+        Ok(file) => file,
+        Err(err) => return err.errno(),
+    };
+    // Device nodes reach the hardware
+    if file.metadata().file_type == FileType::CharDevice
+        && let Err(errno) = require(caps::CAP_RAW_IO)
+    {
+        return errno;
+    }
+    fs_result(fd::install(file).map(|fd| fd as i64))
+}
+
+/// SYS_OPENAT - Open a file relative to a directory descriptor
+fn sys_openat(dirfd: u64, path: u64, flags: u64) -> i64 {
+    // Only AT_FDCWD (or absolute paths) are supported
+    let absolute = path != 0 && unsafe { *(path as *const u8) } == b'/';
+    if dirfd as i64 != AT_FDCWD && !absolute {
+        return -9; // EBADF
+    }
+    sys_open(path, flags)
+}
+
+/// SYS_CLOSE - Close a file descriptor
+fn sys_close(fd: u64) -> i64 {
+    // Closing the console is a no-op
+    if fd <= STDERR {
+        return 0;
+    }
+    fs_result(fd::close(fd).map(|_| 0))
+}
+
+/// SYS_LSEEK - Reposition the file offset
+fn sys_lseek(fd: u64, offset: u64, whence: u64) -> i64 {
+    let pos = match whence {
+        0 => SeekFrom::Start(offset),          // SEEK_SET
+        1 => SeekFrom::Current(offset as i64), // SEEK_CUR
+        2 => SeekFrom::End(offset as i64),     // SEEK_END
+        _ => return -22,                       // EINVAL
+    };
+
+    fs_result(
+        fd::get(fd)
+            .and_then(|file| file.seek(pos))
+            .map(|p| p as i64),
+    )
+}
+
+/// SYS_STAT / SYS_LSTAT - Get file status by path
+fn sys_stat(path: u64, statbuf: u64, follow: bool) -> i64 {
+    match user_path(path) {
+        Ok(path) => {
+            let meta = if follow {
+                fs::stat(&path)
+            } else {
+                fs::lstat(&path)
+            };
+            match meta {
+                Ok(meta) => write_stat(statbuf, &meta),
+                Err(err) => err.errno(),
+            }
+        }
+        Err(errno) => errno,
+    }
+}
+
+/// SYS_NEWFSTATAT - Get file status relative to a directory descriptor
+fn sys_newfstatat(dirfd: u64, path: u64, statbuf: u64, flags: u64) -> i64 {
+    // Empty path with AT_EMPTY_PATH means fstat(dirfd)
+    if path != 0 && unsafe { *(path as *const u8) } == 0 {
+        return sys_fstat(dirfd, statbuf);
+    }
+    sys_stat(path, statbuf, flags & AT_SYMLINK_NOFOLLOW == 0)
+}
+
+/// SYS_GETDENTS64 - Read directory entries
+/// Each record is a `struct linux_dirent64 { u64 d_ino; i64 d_off; u16 d_reclen; u8 d_type; char d_name[]; }`
+fn sys_getdents64(fd: u64, dirp: u64, count: u64) -> i64 {
+    let file = match fd::get(fd) {
+        Ok(file) => file,
+        Err(err) => return err.errno(),
+    };
+
+    let mut written = 0u64;
+
+    loop {
+        let entries = match file.read_dir(1) {
+            Ok(entries) => entries,
+            Err(err) => return err.errno(),
+        };
+        let Some(entry) = entries.first() else {
+            break;
+        };
+
+        // Header (19 bytes) + name + NUL, padded to 8 bytes
+        let reclen = (19 + entry.name.len() as u64 + 1).next_multiple_of(8);
+        if written + reclen > count {
+            // Does not fit: rewind so the entry is returned by the next call
+            let _ = file.seek(SeekFrom::Current(-1));
+            if written == 0 {
+                return -22; // EINVAL: buffer too small
+            }
+            break;
+        }
+
+        let rec = (dirp + written) as *mut u8;
+        unsafe {
+            core::ptr::write_bytes(rec, 0, reclen as usize);
+            *(rec as *mut u64) = entry.ino;
+            *(rec.add(8) as *mut i64) = (written + reclen) as i64;
+            *(rec.add(16) as *mut u16) = reclen as u16;
+            *rec.add(18) = entry.file_type.dirent_type();
+            core::ptr::copy_nonoverlapping(entry.name.as_ptr(), rec.add(19), entry.name.len());
+        }
+
+        written += reclen;
+    }
+
+    written as i64
+}
+
+/// SYS_MKDIR / SYS_MKDIRAT - Create a directory
+fn sys_mkdir(path: u64) -> i64 {
+    match user_path(path) {
+        Ok(path) => fs_result(fs::mkdir(&path).map(|_| 0)),
+        Err(errno) => errno,
+    }
+}
+
+/// SYS_RMDIR - Remove an empty directory
+fn sys_rmdir(path: u64) -> i64 {
+    match user_path(path) {
+        Ok(path) => fs_result(fs::rmdir(&path).map(|_| 0)),
+        Err(errno) => errno,
+    }
+}
+
+/// SYS_UNLINK - Remove a file
+fn sys_unlink(path: u64) -> i64 {
+    match user_path(path) {
+        Ok(path) => fs_result(fs::unlink(&path).map(|_| 0)),
+        Err(errno) => errno,
+    }
+}
+
+/// SYS_UNLINKAT - Remove a file or (with AT_REMOVEDIR) a directory
+fn sys_unlinkat(path: u64, flags: u64) -> i64 {
+    if flags & AT_REMOVEDIR != 0 {
+        sys_rmdir(path)
+    } else {
+        sys_unlink(path)
+    }
+}
+
+/// SYS_FTRUNCATE - Resize an open file
+fn sys_ftruncate(fd: u64, length: u64) -> i64 {
+    fs_result(
+        fd::get(fd)
+            .and_then(|file| file.truncate(length))
+            .map(|_| 0),
+    )
+}
+
+/// SYS_GETCWD - Copy the working directory into a buffer
+/// Returns the length including the terminating NUL
+fn sys_getcwd(buf: u64, size: u64) -> i64 {
+    if buf == 0 {
+        return EFAULT;
+    }
+
+    let cwd = fs::cwd();
+    let len = cwd.len() + 1;
+    if len as u64 > size {
+        return ERANGE;
+    }
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(cwd.as_ptr(), buf as *mut u8, cwd.len());
+        *((buf + cwd.len() as u64) as *mut u8) = 0;
+    }
+    len as i64
+}
+
+/// SYS_CHDIR - Change the working directory
+fn sys_chdir(path: u64) -> i64 {
+    match user_path(path) {
+        Ok(path) => fs_result(fs::chdir(&path).map(|_| 0)),
+        Err(errno) => errno,
+    }
+}
+
+/// SYS_READLINK / SYS_READLINKAT - Read the target of a symlink (not NUL-terminated)
+fn sys_readlink(path: u64, buf: u64, size: u64) -> i64 {
+    let target = match user_path(path).map(|path| fs::readlink(&path)) {
+        Ok(Ok(target)) => target,
+        Ok(Err(err)) => return err.errno(),
+        Err(errno) => return errno,
+    };
+
+    let count = target.len().min(size as usize);
+    unsafe {
+        core::ptr::copy_nonoverlapping(target.as_ptr(), buf as *mut u8, count);
+    }
+    count as i64
+}
+
+/// SYS_MOUNT - Attach a filesystem
+/// Signature: mount(source, target, filesystemtype, mountflags, data)
+fn sys_mount(source: u64, target: u64, fs_type: u64, flags: u64) -> i64 {
+    if let Err(errno) = require(caps::CAP_MOUNT) {
+        return errno;
+    }
+    if flags & (MS_REMOUNT | MS_BIND | MS_MOVE) != 0 {
+        return EINVAL;
+    }
+
+    // Memory filesystems have no source, accept NULL
+    let source = if source == 0 {
+        Ok(String::from("none"))
+    } else {
+        user_str(source)
+    };
+
+    match (source, user_path(target), user_str(fs_type)) {
+        (Ok(source), Ok(target), Ok(fs_type)) => {
+            fs_result(fs::mount_type(&source, &target, &fs_type).map(|_| 0))
+        }
+        (Err(errno), _, _) | (_, Err(errno), _) | (_, _, Err(errno)) => errno,
+    }
+}
+
+/// SYS_SYNC - Write every dirty cached block back to its device
+fn sys_sync() -> i64 {
+    crate::block::sync_all();
+    0
+}
+
+/// SYS_UMOUNT2 - Detach a filesystem
+fn sys_umount2(target: u64, flags: u64) -> i64 {
+    if let Err(errno) = require(caps::CAP_MOUNT) {
+        return errno;
+    }
+    match user_path(target) {
+        Ok(target) => fs_result(fs::umount(&target, flags as u32).map(|_| 0)),
+        Err(errno) => errno,
+    }
+}
+
+// ============================================================================
+// SOCKET SYSCALLS (feature "net")
+// ============================================================================
+
+/// Parse a user `struct sockaddr_in { u16 family; u16 port (BE); u8 addr[4]; u8 zero[8]; }`
+#[cfg(feature = "net")]
+fn read_sockaddr(addr: u64, len: u64) -> Result<SocketAddr, i64> {
+    if addr == 0 {
+        return Err(EFAULT);
+    }
+    if (len as usize) < SOCKADDR_IN_LEN {
+        return Err(EINVAL);
+    }
+
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, SOCKADDR_IN_LEN) };
+    if u16::from_le_bytes([bytes[0], bytes[1]]) as u64 != AF_INET {
+        return Err(EAFNOSUPPORT);
+    }
+
+    Ok(SocketAddr {
+        port: u16::from_be_bytes([bytes[2], bytes[3]]),
+        ip: Ipv4Addr([bytes[4], bytes[5], bytes[6], bytes[7]]),
+    })
+}
+
+/// Write a `struct sockaddr_in`, `len_ptr` holds the buffer size and receives the real size
+#[cfg(feature = "net")]
+fn write_sockaddr(addr: u64, len_ptr: u64, value: SocketAddr) {
+    if addr == 0 || len_ptr == 0 {
+        return;
+    }
+
+    let mut bytes = [0u8; SOCKADDR_IN_LEN];
+    bytes[0..2].copy_from_slice(&(AF_INET as u16).to_le_bytes());
+    bytes[2..4].copy_from_slice(&value.port.to_be_bytes());
+    bytes[4..8].copy_from_slice(&value.ip.0);
+
+    unsafe {
+        let len = *(len_ptr as *const u32) as usize;
+        let count = len.min(SOCKADDR_IN_LEN);
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), addr as *mut u8, count);
+        *(len_ptr as *mut u32) = SOCKADDR_IN_LEN as u32;
+    }
+}
+
+/// Run `f` on the socket behind a descriptor
+#[cfg(feature = "net")]
+fn with_socket(fd: u64, f: impl FnOnce(&SocketFile) -> i64) -> i64 {
+    match fd::get(fd) {
+        Ok(file) => match file.as_socket() {
+            Some(socket) => f(socket),
+            None => ENOTSOCK,
+        },
+        Err(err) => err.errno(),
+    }
+}
+
+/// SYS_SOCKET - Create a socket (AF_INET / SOCK_DGRAM only)
+#[cfg(feature = "net")]
+fn sys_socket(domain: u64, sock_type: u64, _protocol: u64) -> i64 {
+    if let Err(errno) = require(caps::CAP_NET) {
+        return errno;
+    }
+    if domain != AF_INET {
+        return EAFNOSUPPORT;
+    }
+    if sock_type & SOCK_TYPE_MASK != SOCK_DGRAM {
+        return ESOCKTNOSUPPORT;
+    }
+
+    let socket = SocketFile::new_udp(sock_type & SOCK_NONBLOCK != 0);
+    fs_result(fd::install(Arc::new(socket)).map(|fd| fd as i64))
+}
+
+/// SYS_BIND - Bind a socket to a local address
+#[cfg(feature = "net")]
+fn sys_bind(fd: u64, addr: u64, len: u64) -> i64 {
+    let addr = match read_sockaddr(addr, len) {
+        Ok(addr) => addr,
+        Err(errno) => return errno,
+    };
+    with_socket(fd, |socket| match socket.udp().bind(addr) {
+        Ok(()) => 0,
+        Err(err) => err.errno(),
+    })
+}
+
+/// SYS_CONNECT - Set the default peer of a datagram socket
+#[cfg(feature = "net")]
+fn sys_connect(fd: u64, addr: u64, len: u64) -> i64 {
+    let addr = match read_sockaddr(addr, len) {
+        Ok(addr) => addr,
+        Err(errno) => return errno,
+    };
+    with_socket(fd, |socket| match socket.udp().connect(addr) {
+        Ok(()) => 0,
+        Err(err) => err.errno(),
+    })
+}
+
+/// SYS_SENDTO - Send a datagram
+/// Signature: sendto(fd, buf, len, flags, dest_addr, addrlen)
+#[cfg(feature = "net")]
+fn sys_sendto(fd: u64, buf: u64, len: u64, _flags: u64, dest: u64, dest_len: u64) -> i64 {
+    let dst = if dest == 0 {
+        None
+    } else {
+        match read_sockaddr(dest, dest_len) {
+            Ok(addr) => Some(addr),
+            Err(errno) => return errno,
+        }
+    };
+
+    let data = unsafe { core::slice::from_raw_parts(buf as *const u8, len as usize) };
+    with_socket(fd, |socket| match socket.send(data, dst) {
+        Ok(count) => count as i64,
+        Err(err) => err.errno(),
+    })
+}
+
+/// SYS_RECVFROM - Receive a datagram
+/// Signature: recvfrom(fd, buf, len, flags, src_addr, addrlen)
+#[cfg(feature = "net")]
+fn sys_recvfrom(fd: u64, buf: u64, len: u64, flags: u64, src: u64, src_len: u64) -> i64 {
+    with_socket(fd, |socket| match socket.recv(flags & MSG_DONTWAIT != 0) {
+        Ok(datagram) => {
+            let count = (len as usize).min(datagram.data.len());
+            unsafe {
+                core::ptr::copy_nonoverlapping(datagram.data.as_ptr(), buf as *mut u8, count);
+            }
+            write_sockaddr(src, src_len, datagram.src);
+            count as i64
+        }
+        Err(err) => err.errno(),
+    })
+}
+
+/// SYS_GETSOCKNAME / SYS_GETPEERNAME - Local or remote address of a socket
+#[cfg(feature = "net")]
+fn sys_getsockname(fd: u64, addr: u64, len_ptr: u64, peer: bool) -> i64 {
+    with_socket(fd, |socket| {
+        let udp = socket.udp();
+        let value = if peer {
+            match udp.remote_addr() {
+                Some(remote) => remote,
+                None => return crate::net::NetError::NotConnected.errno(),
+            }
+        } else {
+            udp.local_addr().unwrap_or(SocketAddr {
+                ip: Ipv4Addr::UNSPECIFIED,
+                port: 0,
+            })
+        };
+        write_sockaddr(addr, len_ptr, value);
+        0
+    })
+}
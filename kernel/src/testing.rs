@@ -0,0 +1,143 @@
+// Kernel Tests
+// `cargo test -p kernel --target x86_64-unknown-none` builds the kernel with its
+// `#[test_case]` functions and boots it under QEMU through qemu-runner.sh. The
+// kernel initializes as usual, then `_start` runs the tests instead of user space:
+// each one reports on serial, a panic fails the run. The result leaves QEMU through
+// the isa-debug-exit device, whose exit status the runner turns into cargo's.
+// The integration test suite (itest.rs) exits QEMU the same way.
+
+use x86_64::instructions::port::Port;
+
+// I/O port of the isa-debug-exit device (-device isa-debug-exit,iobase=0xf4)
+const DEBUG_EXIT_PORT: u16 = 0xF4;
+
+// QEMU exits with status (code << 1) | 1: 33 for Success, 35 for Failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+// Leave QEMU, or halt if the device is missing (e.g. on real hardware)
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    unsafe {
+        Port::<u32>::new(DEBUG_EXIT_PORT).write(code as u32);
+    }
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[cfg(test)]
+pub trait Testable {
+    fn run(&self);
+}
+
+#[cfg(test)]
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        shared::serial_print!("{} ... ", core::any::type_name::<T>());
+        self();
+        shared::serial_println!("[ok]");
+    }
+}
+
+#[cfg(test)]
+pub fn run_tests(tests: &[&dyn Testable]) {
+    shared::serial_println!("running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    shared::serial_println!("test result: ok. {} passed", tests.len());
+    exit_qemu(QemuExitCode::Success);
+}
+
+// Panic handler of test builds: the running test failed
+#[cfg(test)]
+pub fn panic(info: &core::panic::PanicInfo, backtrace: &[u64]) -> ! {
+    shared::serial_println!("[failed]\n");
+    shared::serial_println!("Error: {}", info);
+    for (i, addr) in backtrace.iter().enumerate() {
+        shared::serial_println!("#{} {}", i, crate::symbols::Frame(*addr));
+    }
+    exit_qemu(QemuExitCode::Failed);
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::once::{LazyInit, Once};
+    use shared::spinlock::Spinlock;
+    use shared::sync::IrqMutex;
+    use x86_64::instructions::interrupts;
+
+    #[test_case]
+    fn spinlock_excludes_second_locker() {
+        let lock = Spinlock::new(0);
+        let guard = lock.lock();
+        assert!(lock.is_locked());
+        assert!(lock.try_lock().is_none());
+        drop(guard);
+        *lock.try_lock().expect("lock is free") += 1;
+        assert_eq!(*lock.lock(), 1);
+        assert!(!lock.is_locked());
+    }
+
+    #[test_case]
+    fn spinlock_force_unlock_frees_lock() {
+        let lock = Spinlock::new(());
+        core::mem::forget(lock.lock());
+        unsafe { lock.force_unlock() };
+        assert!(lock.try_lock().is_some());
+        // Unlocking a free lock leaves it free
+        unsafe { lock.force_unlock() };
+        assert!(lock.try_lock().is_some());
+    }
+
+    #[test_case]
+    fn without_interrupts_restores_state() {
+        let enabled = interrupts::are_enabled();
+        interrupts::without_interrupts(|| assert!(!interrupts::are_enabled()));
+        assert_eq!(interrupts::are_enabled(), enabled);
+    }
+
+    #[test_case]
+    fn irq_mutex_restores_interrupt_state() {
+        let initial = interrupts::are_enabled();
+        let lock = IrqMutex::new(0);
+        for enabled in [false, true] {
+            if enabled {
+                interrupts::enable();
+            } else {
+                interrupts::disable();
+            }
+            {
+                let mut guard = lock.lock();
+                assert!(!interrupts::are_enabled());
+                assert!(lock.try_lock().is_none());
+                *guard += 1;
+            }
+            assert_eq!(interrupts::are_enabled(), enabled);
+        }
+        assert_eq!(*lock.lock(), 2);
+        if !initial {
+            interrupts::disable();
+        }
+    }
+
+    #[test_case]
+    fn once_runs_initializer_once() {
+        let once = Once::new();
+        assert!(once.get().is_none());
+        assert_eq!(*once.call_once(|| 1), 1);
+        assert_eq!(*once.call_once(|| 2), 1);
+        assert_eq!(once.get(), Some(&1));
+    }
+
+    #[test_case]
+    fn lazy_init_runs_on_first_access() {
+        static LAZY: LazyInit<u32> = LazyInit::new(|| 42);
+        assert_eq!(*LAZY, 42);
+        assert_eq!(*LAZY.force(), 42);
+    }
+}
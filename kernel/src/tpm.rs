@@ -0,0 +1,166 @@
+// TPM Event Log
+// The bootloader measures the kernel file and the initramfs into PCR 9 and hands
+// over a copy of the firmware's event log (see shared::tpm). It is listed, one
+// event per line, at /proc/tpm_events: PCR, event type, the SHA-256 digest (or
+// the first bank's) and the event data when it is text, so a quote of the PCRs
+// can be checked by replaying it. Nothing talks to the TPM itself.
+
+use alloc::string::String;
+use core::fmt::Write;
+use shared::BootInfo;
+use shared::once::Once;
+use shared::tpm::{self, EventLog};
+
+use crate::fs::procfs::{self, ProcEntry};
+
+static LOG: Once<Option<&'static [u8]>> = Once::new();
+
+static EVENTS_ENTRY: ProcEntry = ProcEntry {
+    name: "tpm_events",
+    read,
+    write: None,
+};
+
+// Pick up the event log of the BootInfo, if the bootloader measured the boot
+pub fn init(boot_info: &BootInfo) {
+    let log = *LOG.call_once(|| unsafe { boot_info.tpm_event_log(boot_info.hhdm_offset) });
+    match log.and_then(EventLog::parse) {
+        Some(log) => {
+            let banks = log.algorithms().len();
+            let boot_files = log
+                .events()
+                .filter(|event| event.pcr == tpm::PCR_BOOT_FILES && event.event_type == tpm::EV_IPL)
+                .count();
+            log::info!(
+                "TPM: event log with {} events in {} banks, {} boot files measured into PCR {}",
+                log.events().count(),
+                banks,
+                boot_files,
+                tpm::PCR_BOOT_FILES
+            );
+        }
+        None if log.is_some() => log::warn!("TPM: the event log does not parse"),
+        None => log::info!("TPM: no measured boot"),
+    }
+}
+
+// Register /proc/tpm_events (needs the heap)
+pub fn init_proc() {
+    procfs::register(&EVENTS_ENTRY);
+}
+
+fn event_type_name(event_type: u32) -> Option<&'static str> {
+    match event_type {
+        tpm::EV_NO_ACTION => Some("NO_ACTION"),
+        tpm::EV_IPL => Some("IPL"),
+        0x0000_0001 => Some("POST_CODE"),
+        0x0000_0004 => Some("SEPARATOR"),
+        0x0000_0008 => Some("S_CRTM_VERSION"),
+        0x8000_0001 => Some("EFI_VARIABLE_DRIVER_CONFIG"),
+        0x8000_0002 => Some("EFI_VARIABLE_BOOT"),
+        0x8000_0003 => Some("EFI_BOOT_SERVICES_APPLICATION"),
+        0x8000_0007 => Some("EFI_ACTION"),
+        0x8000_000B => Some("EFI_HANDOFF_TABLES2"),
+        0x8000_00E0 => Some("EFI_VARIABLE_AUTHORITY"),
+        _ => None,
+    }
+}
+
+// One line per event of `log`
+fn format_events(log: &EventLog) -> String {
+    let mut out = String::new();
+    let bank = log
+        .algorithms()
+        .iter()
+        .map(|(alg, _)| *alg)
+        .find(|alg| *alg == tpm::ALG_SHA256)
+        .or_else(|| log.algorithms().first().map(|(alg, _)| *alg));
+    for event in log.events() {
+        let _ = write!(out, "{:2} ", event.pcr);
+        match event_type_name(event.event_type) {
+            Some(name) => out.push_str(name),
+            None => {
+                let _ = write!(out, "{:#x}", event.event_type);
+            }
+        }
+        if let Some(alg) = bank
+            && let Some(digest) = event.digest(alg)
+        {
+            let _ = write!(out, " {}:", tpm::algorithm_name(alg));
+            for byte in digest {
+                let _ = write!(out, "{:02x}", byte);
+            }
+        }
+        let text = core::str::from_utf8(event.data)
+            .ok()
+            .map(|text| text.trim_end_matches('\0'))
+            .filter(|text| !text.is_empty() && !text.contains(|c: char| c.is_control()));
+        if let Some(text) = text {
+            let _ = write!(out, " {}", text);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn read() -> String {
+    match LOG.get().copied().flatten().and_then(EventLog::parse) {
+        Some(log) => format_events(&log),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    // A log with a SHA-256 bank and one EV_IPL event for "kernel"
+    fn sample_log() -> Vec<u8> {
+        let mut spec = Vec::new();
+        spec.extend_from_slice(b"Spec ID Event03\0");
+        spec.extend_from_slice(&0u32.to_le_bytes()); // Platform class
+        spec.extend_from_slice(&[0, 2, 0, 2]); // Version, uintn size
+        spec.extend_from_slice(&1u32.to_le_bytes());
+        spec.extend_from_slice(&tpm::ALG_SHA256.to_le_bytes());
+        spec.extend_from_slice(&32u16.to_le_bytes());
+        spec.push(0); // No vendor info
+
+        let mut log = Vec::new();
+        log.extend_from_slice(&0u32.to_le_bytes());
+        log.extend_from_slice(&tpm::EV_NO_ACTION.to_le_bytes());
+        log.extend_from_slice(&[0; 20]);
+        log.extend_from_slice(&(spec.len() as u32).to_le_bytes());
+        log.extend_from_slice(&spec);
+
+        log.extend_from_slice(&tpm::PCR_BOOT_FILES.to_le_bytes());
+        log.extend_from_slice(&tpm::EV_IPL.to_le_bytes());
+        log.extend_from_slice(&1u32.to_le_bytes());
+        log.extend_from_slice(&tpm::ALG_SHA256.to_le_bytes());
+        log.extend_from_slice(&[0xAB; 32]);
+        log.extend_from_slice(&6u32.to_le_bytes());
+        log.extend_from_slice(b"kernel");
+        log
+    }
+
+    #[test_case]
+    fn event_log_is_parsed_and_listed() {
+        let data = sample_log();
+        let log = EventLog::parse(&data).expect("log parses");
+        assert_eq!(log.algorithms(), &[(tpm::ALG_SHA256, 32)]);
+        let event = log.events().next().expect("one event");
+        assert_eq!(log.events().count(), 1);
+        assert_eq!(event.pcr, tpm::PCR_BOOT_FILES);
+        assert_eq!(event.digest(tpm::ALG_SHA256), Some(&[0xAB; 32][..]));
+        assert_eq!(event.data, b"kernel");
+
+        let listing = format_events(&log);
+        assert!(listing.starts_with(" 9 IPL sha256:abab"));
+        assert!(listing.ends_with(" kernel\n"));
+
+        // The length the bootloader computes from GetEventLog's two pointers
+        let last_entry = data.len() - (12 + 2 + 32 + 4 + 6);
+        let len = unsafe { tpm::log_len(data.as_ptr(), data.as_ptr().add(last_entry)) };
+        assert_eq!(len, Some(data.len()));
+    }
+}
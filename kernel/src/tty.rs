@@ -0,0 +1,478 @@
+// Console TTY
+// The terminal behind descriptors 0-2 of the running program: input comes from the
+// keyboard and serial (decoded by the shell's input code, see
+// `shell::poll_console_bytes`), output goes to the screen and serial.
+//
+// Input goes through a line discipline configured with termios (TCGETS /
+// TCSETS*). In canonical mode (ICANON) a line is edited before the program sees
+// it: VERASE deletes a character, VKILL the line, VEOF ends it without a newline
+// (on an empty line, read returns 0), and read(0) waits for a whole line. In raw
+// mode bytes are available as they arrive, and VMIN / VTIME decide when a read
+// returns: VMIN bytes, or VTIME tenths of a second without input. ISIG turns
+// VINTR (Ctrl-C) and VQUIT into SIGINT / SIGQUIT, which end the program (no
+// handlers can be installed), and ECHO writes the input back.
+//
+// Input is only taken from the console while the program reads or polls it; keys
+// typed before that wait in the keyboard and serial buffers. Every program starts
+// with the default settings (canonical, echo, signals).
+
+use crate::fs::{FsError, FsResult, POLLIN, POLLOUT};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// ioctls
+const TCGETS: u64 = 0x5401;
+const TCSETS: u64 = 0x5402;
+const TCSETSW: u64 = 0x5403;
+const TCSETSF: u64 = 0x5404;
+const TIOCGWINSZ: u64 = 0x5413;
+const FIONREAD: u64 = 0x541B;
+
+// c_iflag
+const ICRNL: u32 = 0o400;
+// c_oflag
+const OPOST: u32 = 0o1;
+const ONLCR: u32 = 0o4;
+// c_cflag
+const B38400: u32 = 0o17;
+const CS8: u32 = 0o60;
+const CREAD: u32 = 0o200;
+// c_lflag
+const ISIG: u32 = 0o1;
+const ICANON: u32 = 0o2;
+const ECHO: u32 = 0o10;
+const ECHOE: u32 = 0o20;
+const ECHOK: u32 = 0o40;
+const ECHOCTL: u32 = 0o1000;
+const IEXTEN: u32 = 0o100000;
+
+// c_cc indices
+const VINTR: usize = 0;
+const VQUIT: usize = 1;
+const VERASE: usize = 2;
+const VKILL: usize = 3;
+const VEOF: usize = 4;
+const VTIME: usize = 5;
+const VMIN: usize = 6;
+const NCCS: usize = 19;
+
+const SIGINT: u8 = 2;
+const SIGQUIT: u8 = 3;
+
+// Text size reported without a screen
+const DEFAULT_WINSIZE: (usize, usize) = (80, 24);
+
+// Milliseconds per VTIME unit
+const VTIME_MS: u64 = 100;
+
+// struct termios (the kernel's, without the speeds)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Termios {
+    iflag: u32,
+    oflag: u32,
+    cflag: u32,
+    lflag: u32,
+    line: u8,
+    cc: [u8; NCCS],
+}
+
+impl Termios {
+    const DEFAULT: Self = {
+        let mut cc = [0; NCCS];
+        cc[VINTR] = 0x03; // ^C
+        cc[VQUIT] = 0x1C; // ^\
+        cc[VERASE] = 0x7F;
+        cc[VKILL] = 0x15; // ^U
+        cc[VEOF] = 0x04; // ^D
+        cc[VMIN] = 1;
+        Termios {
+            iflag: ICRNL,
+            oflag: OPOST | ONLCR,
+            cflag: B38400 | CS8 | CREAD,
+            lflag: ISIG | ICANON | ECHO | ECHOE | ECHOK | ECHOCTL | IEXTEN,
+            line: 0,
+            cc,
+        }
+    };
+
+    fn has(&self, lflag: u32) -> bool {
+        self.lflag & lflag != 0
+    }
+
+    // A control character is disabled by setting it to 0
+    fn is(&self, index: usize, byte: u8) -> bool {
+        self.cc[index] != 0 && self.cc[index] == byte
+    }
+}
+
+// struct winsize
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Winsize {
+    row: u16,
+    col: u16,
+    xpixel: u16,
+    ypixel: u16,
+}
+
+struct Tty {
+    termios: Termios,
+    // The line being edited (canonical mode)
+    line: Vec<u8>,
+    // Input the program can read
+    ready: VecDeque<u8>,
+    // VEOF on an empty line: the next read returns 0
+    eof: bool,
+    // Tick of the last byte received, for VTIME
+    last_input: u64,
+}
+
+static TTY: Mutex<Tty> = Mutex::new(Tty {
+    termios: Termios::DEFAULT,
+    line: Vec::new(),
+    ready: VecDeque::new(),
+    eof: false,
+    last_input: 0,
+});
+
+fn with_tty<R>(f: impl FnOnce(&mut Tty) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut TTY.lock()))
+}
+
+// Bytes to the screen and serial, as the program's standard output
+pub fn write(bytes: &[u8]) {
+    // The console decodes UTF-8 itself, so a character split across two writes
+    // still renders correctly; serial gets the raw bytes
+    #[cfg(feature = "graphics")]
+    crate::screen::print_bytes(bytes);
+    #[cfg(feature = "integration-tests")]
+    crate::itest::capture(bytes);
+    let mut serial = shared::serial::SERIAL1.lock();
+    for &byte in bytes {
+        serial.send_raw(byte);
+    }
+    drop(serial);
+
+    #[cfg(feature = "graphics")]
+    crate::screen::flush();
+}
+
+impl Tty {
+    fn flush_input(&mut self) {
+        self.line.clear();
+        self.ready.clear();
+        self.eof = false;
+    }
+
+    // Echo a character of the input (control characters as ^X with ECHOCTL)
+    fn echo_byte(&self, byte: u8, echo: &mut Vec<u8>) {
+        if !self.termios.has(ECHO) {
+            return;
+        }
+        let control = byte < 0x20 && byte != b'\n' && byte != b'\t' || byte == 0x7F;
+        if control && self.termios.has(ECHOCTL) {
+            echo.extend_from_slice(&[b'^', byte ^ 0x40]);
+        } else {
+            echo.push(byte);
+        }
+    }
+
+    // Remove the last character (all bytes of it) from the line being edited
+    fn erase_char(&mut self, echo: &mut Vec<u8>) {
+        while let Some(byte) = self.line.pop() {
+            // UTF-8 continuation bytes belong to the character before
+            if byte & 0xC0 != 0x80 {
+                if self.termios.has(ECHO) && self.termios.has(ECHOE) {
+                    echo.extend_from_slice(b"\x08 \x08");
+                }
+                break;
+            }
+        }
+    }
+
+    // Feed a byte of input through the line discipline
+    // Returns the signal it raises, if any
+    fn receive(&mut self, mut byte: u8, echo: &mut Vec<u8>) -> Option<u8> {
+        let termios = self.termios;
+        if byte == b'\r' && termios.iflag & ICRNL != 0 {
+            byte = b'\n';
+        }
+        if termios.has(ISIG) {
+            let signal = if termios.is(VINTR, byte) {
+                Some(SIGINT)
+            } else if termios.is(VQUIT, byte) {
+                Some(SIGQUIT)
+            } else {
+                None
+            };
+            if signal.is_some() {
+                self.flush_input();
+                self.echo_byte(byte, echo);
+                echo.push(b'\n');
+                return signal;
+            }
+        }
+        self.last_input = crate::interrupts::TICKS.load(Ordering::Relaxed);
+
+        if !termios.has(ICANON) {
+            self.ready.push_back(byte);
+            self.echo_byte(byte, echo);
+            return None;
+        }
+        if termios.is(VERASE, byte) {
+            self.erase_char(echo);
+        } else if termios.is(VKILL, byte) {
+            while !self.line.is_empty() {
+                self.erase_char(echo);
+            }
+        } else if termios.is(VEOF, byte) {
+            if self.line.is_empty() {
+                self.eof = true;
+            }
+            self.ready.extend(self.line.drain(..));
+        } else {
+            self.line.push(byte);
+            self.echo_byte(byte, echo);
+            if byte == b'\n' {
+                self.ready.extend(self.line.drain(..));
+            }
+        }
+        None
+    }
+
+    // Take what the console received; returns a signal to raise
+    fn pump(&mut self) -> Option<u8> {
+        let mut input = Vec::new();
+        crate::shell::poll_console_bytes(&mut input);
+        let mut echo = Vec::new();
+        let mut signal = None;
+        for byte in input {
+            signal = signal.or(self.receive(byte, &mut echo));
+        }
+        if !echo.is_empty() {
+            write(&echo);
+        }
+        signal
+    }
+
+    // Something for read to return (possibly end of file)
+    fn readable(&self) -> bool {
+        !self.ready.is_empty() || self.eof
+    }
+
+    // Take up to `max` bytes of input; in canonical mode at most one line
+    fn take(&mut self, max: usize) -> Vec<u8> {
+        let canonical = self.termios.has(ICANON);
+        let mut bytes = Vec::new();
+        while bytes.len() < max {
+            let Some(byte) = self.ready.pop_front() else {
+                break;
+            };
+            bytes.push(byte);
+            if canonical && byte == b'\n' {
+                break;
+            }
+        }
+        if bytes.is_empty() {
+            self.eof = false;
+        }
+        bytes
+    }
+}
+
+// End the program for a signal typed on the console
+fn raise(signal: u8) -> ! {
+    log::info!("console: signal {} from the keyboard", signal);
+    // Shell convention for a status: 128 + signal
+    crate::syscalls::end_program(128 + signal as u64)
+}
+
+// read(2) on the console
+pub fn read(buf: &mut [u8]) -> FsResult<usize> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    let (canonical, vmin, vtime) = with_tty(|tty| {
+        let termios = tty.termios;
+        (
+            termios.has(ICANON),
+            termios.cc[VMIN] as usize,
+            termios.cc[VTIME] as u64 * VTIME_MS,
+        )
+    });
+    let start = crate::interrupts::TICKS.load(Ordering::Relaxed);
+    let wanted = vmin.min(buf.len());
+
+    let result = crate::idle::wait_for(|| {
+        let mut tty = TTY.lock();
+        if let Some(signal) = tty.pump() {
+            return Some(Err(signal));
+        }
+        let now = crate::interrupts::TICKS.load(Ordering::Relaxed);
+        let done = if canonical {
+            tty.readable()
+        } else if vmin == 0 {
+            // A read with a timeout, or a poll
+            !tty.ready.is_empty() || now >= start + vtime
+        } else if vtime == 0 {
+            tty.ready.len() >= wanted
+        } else {
+            // VTIME between bytes, once the first one came
+            tty.ready.len() >= wanted || (!tty.ready.is_empty() && now >= tty.last_input + vtime)
+        };
+        done.then(|| Ok(tty.take(buf.len())))
+    });
+
+    match result {
+        Ok(bytes) => {
+            buf[..bytes.len()].copy_from_slice(&bytes);
+            Ok(bytes.len())
+        }
+        Err(signal) => raise(signal),
+    }
+}
+
+// Readiness of the console for poll(2)
+pub fn poll() -> u16 {
+    let (signal, readable) = with_tty(|tty| (tty.pump(), tty.readable()));
+    if let Some(signal) = signal {
+        raise(signal);
+    }
+    if readable { POLLIN | POLLOUT } else { POLLOUT }
+}
+
+// Copy to / from the user address of an ioctl
+fn write_arg<T>(arg: u64, value: T) -> FsResult<i64> {
+    if arg == 0 {
+        return Err(FsError::BadAddress);
+    }
+    unsafe { (arg as *mut T).write_unaligned(value) };
+    Ok(0)
+}
+
+fn read_arg<T: Copy>(arg: u64) -> FsResult<T> {
+    if arg == 0 {
+        return Err(FsError::BadAddress);
+    }
+    Ok(unsafe { (arg as *const T).read_unaligned() })
+}
+
+// Text columns and rows of the console
+fn text_size() -> (usize, usize) {
+    #[cfg(feature = "graphics")]
+    if let Some(size) = crate::screen::text_size() {
+        return size;
+    }
+    DEFAULT_WINSIZE
+}
+
+// ioctl(2) on the console
+pub fn ioctl(request: u64, arg: u64) -> FsResult<i64> {
+    match request {
+        TCGETS => write_arg(arg, with_tty(|tty| tty.termios)),
+        // Output is written synchronously, so there is nothing to drain first
+        TCSETS | TCSETSW | TCSETSF => {
+            let termios: Termios = read_arg(arg)?;
+            with_tty(|tty| {
+                if request == TCSETSF {
+                    tty.flush_input();
+                }
+                // Leaving canonical mode makes the line typed so far readable
+                if !termios.has(ICANON) {
+                    let line = core::mem::take(&mut tty.line);
+                    tty.ready.extend(line);
+                }
+                tty.termios = termios;
+            });
+            Ok(0)
+        }
+        TIOCGWINSZ => {
+            let (cols, rows) = text_size();
+            write_arg(
+                arg,
+                Winsize {
+                    row: rows as u16,
+                    col: cols as u16,
+                    xpixel: 0,
+                    ypixel: 0,
+                },
+            )
+        }
+        FIONREAD => write_arg(arg, with_tty(|tty| tty.ready.len() as i32)),
+        _ => Err(FsError::NotATty),
+    }
+}
+
+// A new program gets the default settings and no input left by the previous one
+pub fn reset() {
+    with_tty(|tty| {
+        tty.termios = Termios::DEFAULT;
+        tty.flush_input();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tty() -> Tty {
+        Tty {
+            termios: Termios::DEFAULT,
+            line: Vec::new(),
+            ready: VecDeque::new(),
+            eof: false,
+            last_input: 0,
+        }
+    }
+
+    fn feed(tty: &mut Tty, input: &[u8]) -> (Option<u8>, Vec<u8>) {
+        let mut echo = Vec::new();
+        let mut signal = None;
+        for &byte in input {
+            signal = signal.or(tty.receive(byte, &mut echo));
+        }
+        (signal, echo)
+    }
+
+    #[test_case]
+    fn tty_canonical_line_editing() {
+        assert_eq!(core::mem::size_of::<Termios>(), 36);
+        let mut tty = tty();
+        let (signal, echo) = feed(&mut tty, "ab\u{e9}\x7f\x7fc".as_bytes());
+        assert_eq!(signal, None);
+        assert_eq!(echo, "ab\u{e9}\x08 \x08\x08 \x08c".as_bytes());
+        assert!(!tty.readable());
+
+        feed(&mut tty, b"\rxy\n");
+        assert_eq!(tty.take(64), b"ac\n");
+        assert_eq!(tty.take(1), b"x");
+        assert_eq!(tty.take(64), b"y\n");
+
+        // ^U kills the line, ^D on an empty line is end of file
+        feed(&mut tty, b"gone\x15\x04");
+        assert!(tty.readable());
+        assert_eq!(tty.take(64), b"");
+        assert!(!tty.readable());
+
+        assert_eq!(
+            feed(&mut tty, b"x\x03"),
+            (Some(SIGINT), Vec::from(*b"x^C\n"))
+        );
+        assert!(tty.line.is_empty());
+    }
+
+    #[test_case]
+    fn tty_raw_mode() {
+        let mut tty = tty();
+        tty.termios.lflag &= !(ICANON | ECHO | ISIG);
+        tty.termios.iflag = 0;
+        let (signal, echo) = feed(&mut tty, b"\r\x03\x7f");
+        assert_eq!((signal, echo.len()), (None, 0));
+        assert_eq!(tty.take(2), b"\r\x03");
+        assert_eq!(tty.take(64), b"\x7f");
+    }
+}
@@ -0,0 +1,305 @@
+// Virtio Entropy Source
+// The virtio-rng device (virtio type 4, `-device virtio-rng-pci` in QEMU) on the
+// virtio bus, driven through the legacy PCI transport: its registers are in I/O
+// BAR 0 of the PCI function, and its single virtqueue lives in physically
+// contiguous frames whose page number is handed to the device.
+//
+// A read posts one device-writable buffer and polls the used ring until the
+// device gives it back filled with random bytes; a device that never does makes
+// the read fail after a timeout. getrandom and the KASLR slide of kexec take
+// their bytes from here when the device is present. The device forgets the
+// queue across suspend-to-RAM and is given it again on resume; remove resets
+// the device, which stops it touching the queue, and frees the queue.
+
+use crate::device::{self, BusType, Device, DeviceIdent, Driver, MatchId, ProbeError};
+use core::ptr;
+use core::sync::atomic::{self, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const PAGE_SIZE: usize = 4096;
+
+// Virtio device type of an entropy source
+const DEVICE_TYPE_ENTROPY: u16 = 4;
+
+// Legacy transport registers, offsets into I/O BAR 0
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_PFN: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0C;
+const REG_QUEUE_SELECT: u16 = 0x0E;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_STATUS: u16 = 0x12;
+
+// Device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+const STATUS_DRIVER: u8 = 1 << 1;
+const STATUS_DRIVER_OK: u8 = 1 << 2;
+const STATUS_FAILED: u8 = 1 << 7;
+
+// The device writes into the buffer of the descriptor
+const DESC_F_WRITE: u16 = 1 << 1;
+
+// PCI command register: decode I/O ports, master DMA
+const PCI_COMMAND: u8 = 0x04;
+const PCI_COMMAND_IO: u32 = 1 << 0;
+const PCI_COMMAND_BUS_MASTER: u32 = 1 << 2;
+const PCI_BAR0: u8 = 0x10;
+const PCI_BAR_IO: u32 = 1 << 0;
+
+// How long a read waits for the device
+const TIMEOUT_US: u64 = 100_000;
+const POLL_US: u64 = 10;
+
+static HHDM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+pub static DRIVER: Driver = Driver {
+    name: "virtio-rng",
+    bus: BusType::Virtio,
+    match_table: &[MatchId::Virtio(DEVICE_TYPE_ENTROPY)],
+    probe,
+    remove,
+    suspend,
+    resume,
+};
+
+// The bound device; reads are serialized on its one descriptor
+struct Rng {
+    io: u16,
+    // Queue frames: descriptors, available ring, then the used ring on the
+    // next page boundary
+    queue: u64,
+    order: usize,
+    size: u16,
+    used_offset: usize,
+    // The frame the device fills
+    buffer: u64,
+    // Next index of the available ring, last one seen in the used ring
+    avail_idx: u16,
+    used_idx: u16,
+}
+
+static RNG: Mutex<Option<Rng>> = Mutex::new(None);
+
+// Bytes of the legacy queue layout for `size` descriptors, and where its used
+// ring starts
+fn queue_layout(size: usize) -> (usize, usize) {
+    let used_offset = (16 * size + 6 + 2 * size).next_multiple_of(PAGE_SIZE);
+    (
+        used_offset + (6 + 8 * size).next_multiple_of(PAGE_SIZE),
+        used_offset,
+    )
+}
+
+fn virt(phys: u64) -> *mut u8 {
+    (phys + HHDM_OFFSET.load(Ordering::Relaxed)) as *mut u8
+}
+
+impl Rng {
+    fn write_u8(&self, reg: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io + reg).write(value) };
+    }
+
+    fn write_u16(&self, reg: u16, value: u16) {
+        unsafe { Port::<u16>::new(self.io + reg).write(value) };
+    }
+
+    fn write_u32(&self, reg: u16, value: u32) {
+        unsafe { Port::<u32>::new(self.io + reg).write(value) };
+    }
+
+    // Reset the device and give it the queue, emptied
+    fn start(&mut self) {
+        self.write_u8(REG_STATUS, 0);
+        self.write_u8(REG_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        // No features wanted
+        self.write_u32(REG_GUEST_FEATURES, 0);
+        self.write_u16(REG_QUEUE_SELECT, 0);
+        unsafe { ptr::write_bytes(virt(self.queue), 0, PAGE_SIZE << self.order) };
+        self.avail_idx = 0;
+        self.used_idx = 0;
+        self.write_u32(REG_QUEUE_PFN, (self.queue / PAGE_SIZE as u64) as u32);
+        self.write_u8(
+            REG_STATUS,
+            STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK,
+        );
+    }
+
+    // Fill the start of `buf` from the device; the bytes written, 0 if the
+    // device did not answer in time
+    fn request(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(PAGE_SIZE);
+        let queue = virt(self.queue);
+        let size = self.size as usize;
+        unsafe {
+            // Descriptor 0: { addr: u64, len: u32, flags: u16, next: u16 }
+            let desc = queue;
+            ptr::write_volatile(desc as *mut u64, self.buffer);
+            ptr::write_volatile(desc.add(8) as *mut u32, len as u32);
+            ptr::write_volatile(desc.add(12) as *mut u16, DESC_F_WRITE);
+            // Available ring: { flags: u16, idx: u16, ring: [u16; size] }
+            let avail = queue.add(16 * size);
+            let slot = self.avail_idx as usize % size;
+            ptr::write_volatile(avail.add(4 + 2 * slot) as *mut u16, 0);
+            atomic::fence(Ordering::SeqCst);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            ptr::write_volatile(avail.add(2) as *mut u16, self.avail_idx);
+            atomic::fence(Ordering::SeqCst);
+        }
+        self.write_u16(REG_QUEUE_NOTIFY, 0);
+
+        // Used ring: { flags: u16, idx: u16, ring: [{ id: u32, len: u32 }; size] }
+        let used = unsafe { queue.add(self.used_offset) };
+        for _ in 0..TIMEOUT_US / POLL_US {
+            let idx = unsafe { ptr::read_volatile(used.add(2) as *const u16) };
+            if idx != self.used_idx {
+                atomic::fence(Ordering::SeqCst);
+                let slot = self.used_idx as usize % size;
+                self.used_idx = idx;
+                let written =
+                    unsafe { ptr::read_volatile(used.add(4 + 8 * slot + 4) as *const u32) };
+                let written = (written as usize).min(len);
+                let data = unsafe { core::slice::from_raw_parts(virt(self.buffer), written) };
+                buf[..written].copy_from_slice(data);
+                return written;
+            }
+            crate::delay::udelay(POLL_US);
+        }
+        log::warn!("virtio-rng: no answer from the device");
+        0
+    }
+
+    // Stop the device and give its memory back
+    fn release(self) {
+        self.write_u8(REG_STATUS, 0);
+        crate::pmm::free_contiguous(self.queue, self.order);
+        crate::pmm::free_frame(self.buffer);
+    }
+}
+
+// Called before the devices are probed
+pub fn init(hhdm_offset: u64) {
+    HHDM_OFFSET.store(hhdm_offset, Ordering::Relaxed);
+}
+
+// The PCI function the virtio-pci transport found the device on
+fn pci_location(device: &Device) -> Option<(u8, u8, u8)> {
+    device::devices()
+        .into_iter()
+        .find(|dev| Some(dev.id) == device.parent)
+        .and_then(|dev| match dev.ident {
+            DeviceIdent::Pci {
+                bus,
+                slot,
+                function,
+                ..
+            } => Some((bus, slot, function)),
+            _ => None,
+        })
+}
+
+fn probe(device: &Device) -> Result<(), ProbeError> {
+    let (bus, slot, function) = pci_location(device).ok_or(ProbeError::Unsupported)?;
+    // Modern-only devices have no I/O BAR
+    let bar = crate::pci::config_read_u32(bus, slot, function, PCI_BAR0);
+    if bar & PCI_BAR_IO == 0 {
+        return Err(ProbeError::Unsupported);
+    }
+    let command = crate::pci::config_read_u32(bus, slot, function, PCI_COMMAND) & 0xFFFF;
+    crate::pci::config_write_u32(
+        bus,
+        slot,
+        function,
+        PCI_COMMAND,
+        command | PCI_COMMAND_IO | PCI_COMMAND_BUS_MASTER,
+    );
+
+    let io = (bar & !0x3) as u16;
+    let mut status = Port::<u8>::new(io + REG_STATUS);
+    // Reset, then find the size of the queue
+    unsafe {
+        status.write(0);
+        status.write(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        Port::<u16>::new(io + REG_QUEUE_SELECT).write(0);
+    }
+    let size = unsafe { Port::<u16>::new(io + REG_QUEUE_SIZE).read() };
+    let (bytes, used_offset) = queue_layout(size as usize);
+    let order = crate::pmm::order_for(bytes / PAGE_SIZE);
+    let queue = (size != 0)
+        .then(|| crate::pmm::allocate_contiguous(order))
+        .flatten();
+    let buffer = queue.and_then(|_| crate::pmm::allocate_frame());
+    let (Some(queue), Some(buffer)) = (queue, buffer) else {
+        unsafe { status.write(STATUS_FAILED) };
+        if let Some(queue) = queue {
+            crate::pmm::free_contiguous(queue, order);
+        }
+        return Err(ProbeError::InitFailed);
+    };
+    let mut rng = Rng {
+        io,
+        queue,
+        order,
+        size,
+        used_offset,
+        buffer,
+        avail_idx: 0,
+        used_idx: 0,
+    };
+    rng.start();
+
+    log::info!("virtio-rng: {} queue entries", rng.size);
+    if let Some(old) = interrupts::without_interrupts(|| RNG.lock().replace(rng)) {
+        old.release();
+    }
+    Ok(())
+}
+
+fn remove(_device: &Device) {
+    if let Some(rng) = interrupts::without_interrupts(|| RNG.lock().take()) {
+        rng.release();
+    }
+}
+
+fn suspend(_device: &Device) {}
+
+// The PCI header is back (the transport resumed first), the device was reset
+fn resume(_device: &Device) {
+    interrupts::without_interrupts(|| {
+        if let Some(rng) = RNG.lock().as_mut() {
+            rng.start();
+        }
+    });
+}
+
+// Fill `buf` with random bytes from the device; false if there is no device or
+// it stopped answering
+pub fn fill(buf: &mut [u8]) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut rng = RNG.lock();
+        let Some(rng) = rng.as_mut() else {
+            return false;
+        };
+        let mut done = 0;
+        while done < buf.len() {
+            match rng.request(&mut buf[done..]) {
+                0 => return false,
+                count => done += count,
+            }
+        }
+        true
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn used_ring_starts_on_a_page_boundary() {
+        // 8 descriptors and the available ring fit a page, as does the used ring
+        assert_eq!(queue_layout(8), (2 * PAGE_SIZE, PAGE_SIZE));
+        // 256 descriptors fill a page on their own
+        assert_eq!(queue_layout(256), (3 * PAGE_SIZE, 2 * PAGE_SIZE));
+    }
+}
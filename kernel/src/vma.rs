@@ -0,0 +1,780 @@
+// Virtual Memory Areas
+// The program's address space as a set of areas ordered by address, each with
+// its range, protection (PROT_*) and what backs it: anonymous memory (mmap, the
+// brk heap, TLS), a file or device (program segments, MAP_SHARED objects) or the
+// stack. mmap, munmap and mprotect work on the areas, so overlaps are caught and
+// a range that is not mapped is an error, and the page tables follow them.
+//
+// The brk heap is an anonymous area from the end of the program to the
+// program break, grown and shrunk by brk like the others by mmap and munmap.
+//
+// Anonymous and stack areas are demand paged: the first access to one of their
+// pages, from the program or from the kernel handling a syscall, faults, and the
+// page fault handler maps a fresh zeroed frame with the area's protection and
+// resumes. The others are mapped when they are created. A fault outside every
+// area, or one the area's protection does not allow (a write to read-only
+// memory, executing NX memory), still ends the program.
+//
+// Areas are only created where nothing is mapped yet: every page in one is the
+// program's, never a mapping the kernel made for itself (the boot trampoline's
+// identity page, say), so faulting in and unmapping cannot hand out or free
+// memory the kernel still uses.
+//
+// There is one address space, the running program's. munmap, and loading the
+// next program for every area, unmap the pages and free the frames that were
+// the program's (not those of shared memory objects and devices) and the page
+// tables that no longer map anything, so the next program starts from zeroed
+// memory and nothing leaks.
+
+use crate::slab::{SlabBox, SlabCache};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTable, PageTableFlags, Size4KiB,
+    mapper::CleanUp,
+};
+
+const PAGE_SIZE: u64 = 4096;
+
+// User space, above the null page
+const USER_START: u64 = 0x1000;
+const USER_END: u64 = 0x0000_8000_0000_0000;
+
+// Protection bits, as in mmap(2)
+pub const PROT_NONE: u64 = 0x0;
+pub const PROT_READ: u64 = 0x1;
+pub const PROT_WRITE: u64 = 0x2;
+pub const PROT_EXEC: u64 = 0x4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    // Zero-filled private memory, faulted in page by page
+    Anonymous,
+    // Mapped from a file or device when created
+    File,
+    // The user stack, faulted in like anonymous memory
+    Stack,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vma {
+    pub range: Range<u64>,
+    pub prot: u64,
+    pub kind: VmaKind,
+    // The frames belong to a shared memory object or a device, not to the
+    // area: unmapping leaves them alone
+    pub shared: bool,
+    // The shared memory object mapped, kept alive while the area maps its frames
+    pub object: Option<crate::fs::shmfs::Mapping>,
+}
+
+impl Vma {
+    pub fn new(range: Range<u64>, prot: u64, kind: VmaKind) -> Self {
+        Self {
+            range,
+            prot,
+            kind,
+            shared: false,
+            object: None,
+        }
+    }
+
+    // Whether its pages come from the page fault handler
+    pub fn demand_paged(&self) -> bool {
+        matches!(self.kind, VmaKind::Anonymous | VmaKind::Stack) && !self.shared
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    // Empty, not page aligned or outside user space
+    Invalid,
+    // Part of the range is already mapped
+    Overlap,
+    // No room left, or part of the range is not mapped
+    NoMemory,
+}
+
+impl VmaError {
+    pub fn errno(self) -> i64 {
+        match self {
+            VmaError::Invalid => -22,  // EINVAL
+            VmaError::Overlap => -17,  // EEXIST
+            VmaError::NoMemory => -12, // ENOMEM
+        }
+    }
+}
+
+// Page table flags for a page with protection `prot`. x86 cannot make a page
+// writable or executable but not readable, so any access implies read; a
+// PROT_NONE page stays present for the kernel, out of the program's reach
+pub fn page_flags(prot: u64) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT;
+    if prot != PROT_NONE {
+        flags |= PageTableFlags::USER_ACCESSIBLE;
+    }
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+fn check_range(range: &Range<u64>) -> Result<(), VmaError> {
+    if range.start >= range.end
+        || !range.start.is_multiple_of(PAGE_SIZE)
+        || !range.end.is_multiple_of(PAGE_SIZE)
+        || range.start < USER_START
+        || range.end > USER_END
+    {
+        return Err(VmaError::Invalid);
+    }
+    Ok(())
+}
+
+// Every mmap, munmap, mprotect and brk makes and drops areas
+static VMAS: SlabCache<Vma> = SlabCache::new("vma");
+
+// Areas by start address; they never overlap
+pub struct AddressSpace {
+    areas: BTreeMap<u64, SlabBox<Vma>>,
+    // Start of the brk heap (page aligned) to the program break
+    brk: Range<u64>,
+}
+
+impl AddressSpace {
+    pub const fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+            brk: 0..0,
+        }
+    }
+
+    pub fn find(&self, addr: u64) -> Option<&Vma> {
+        self.areas
+            .range(..=addr)
+            .next_back()
+            .map(|(_, vma)| &**vma)
+            .filter(|vma| vma.range.contains(&addr))
+    }
+
+    // Areas with a byte in `range`, in order
+    fn overlapping(&self, range: &Range<u64>) -> impl Iterator<Item = &Vma> {
+        let before = self.areas.range(..range.start).next_back();
+        let from = before.map_or(range.start, |(&start, _)| start);
+        let start = range.start;
+        self.areas
+            .range(from..range.end)
+            .map(|(_, vma)| &**vma)
+            .filter(move |vma| vma.range.end > start)
+    }
+
+    // Out of heap is fatal here, as it is for the tree's own nodes
+    fn store(&mut self, vma: Vma) {
+        let vma = VMAS.alloc(vma).expect("vma: out of memory");
+        self.areas.insert(vma.range.start, vma);
+    }
+
+    pub fn insert(&mut self, vma: Vma) -> Result<(), VmaError> {
+        check_range(&vma.range)?;
+        if self.overlapping(&vma.range).next().is_some() {
+            return Err(VmaError::Overlap);
+        }
+        self.store(vma);
+        Ok(())
+    }
+
+    // Take `range` out of the areas, splitting those it cuts through; returns
+    // the pieces taken
+    pub fn remove(&mut self, range: Range<u64>) -> Vec<Vma> {
+        let starts: Vec<u64> = self
+            .overlapping(&range)
+            .map(|vma| vma.range.start)
+            .collect();
+        let mut removed = Vec::new();
+        for start in starts {
+            let vma = SlabBox::into_inner(self.areas.remove(&start).unwrap());
+            if vma.range.start < range.start {
+                self.store(Vma {
+                    range: vma.range.start..range.start,
+                    ..vma.clone()
+                });
+            }
+            if range.end < vma.range.end {
+                self.store(Vma {
+                    range: range.end..vma.range.end,
+                    ..vma.clone()
+                });
+            }
+            let taken = vma.range.start.max(range.start)..vma.range.end.min(range.end);
+            removed.push(Vma {
+                range: taken,
+                ..vma
+            });
+        }
+        removed
+    }
+
+    // Whether every byte of `range` is in an area
+    fn covers(&self, range: &Range<u64>) -> bool {
+        let mut next = range.start;
+        for vma in self.overlapping(range) {
+            if vma.range.start > next {
+                return false;
+            }
+            next = vma.range.end;
+        }
+        next >= range.end
+    }
+
+    // Give `range`, mapped throughout, the protection `prot`; returns the pieces
+    // changed. Areas are split, not merged back
+    pub fn protect(&mut self, range: Range<u64>, prot: u64) -> Result<Vec<Vma>, VmaError> {
+        check_range(&range)?;
+        if !self.covers(&range) {
+            return Err(VmaError::NoMemory);
+        }
+        let changed = self.remove(range);
+        for vma in &changed {
+            self.store(Vma {
+                prot,
+                ..vma.clone()
+            });
+        }
+        Ok(changed)
+    }
+
+    // Start of the lowest free range of `len` bytes inside `window`
+    pub fn find_free(&self, len: u64, window: Range<u64>) -> Option<u64> {
+        let mut start = window.start;
+        for vma in self.overlapping(&window) {
+            if vma.range.start >= start.checked_add(len)? {
+                break;
+            }
+            start = start.max(vma.range.end);
+        }
+        (start.checked_add(len)? <= window.end).then_some(start)
+    }
+
+    // Move the program break to `addr`, growing or shrinking the heap area;
+    // returns the break, unchanged if the heap cannot reach `addr` (below its
+    // start, or into another area), and the pieces taken out of the heap
+    pub fn set_brk(&mut self, addr: u64) -> (u64, Vec<Vma>) {
+        let Range { start, end } = self.brk.clone();
+        let (Some(old_top), Some(new_top)) = (
+            end.checked_next_multiple_of(PAGE_SIZE),
+            addr.checked_next_multiple_of(PAGE_SIZE),
+        ) else {
+            return (end, Vec::new());
+        };
+        if addr < start {
+            return (end, Vec::new());
+        }
+        let mut removed = Vec::new();
+        if new_top > old_top {
+            let grown = old_top..new_top;
+            if check_range(&grown).is_err() || self.overlapping(&grown).next().is_some() {
+                return (end, Vec::new());
+            }
+            // Extend the heap's last area, unless mprotect made it another kind
+            let last = self
+                .areas
+                .range(start..old_top)
+                .next_back()
+                .filter(|(_, vma)| {
+                    vma.range.end == old_top
+                        && vma.prot == PROT_READ | PROT_WRITE
+                        && vma.demand_paged()
+                })
+                .map(|(&key, _)| key);
+            match last {
+                Some(key) => self.areas.get_mut(&key).unwrap().range.end = new_top,
+                None => {
+                    self.store(Vma::new(grown, PROT_READ | PROT_WRITE, VmaKind::Anonymous));
+                }
+            }
+        } else if new_top < old_top {
+            removed = self.remove(new_top..old_top);
+        }
+        self.brk.end = addr;
+        (addr, removed)
+    }
+}
+
+static SPACE: Mutex<AddressSpace> = Mutex::new(AddressSpace::new());
+
+// The fault handler takes the lock too: never touch user memory while holding it
+fn with_space<R>(f: impl FnOnce(&mut AddressSpace) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut SPACE.lock()))
+}
+
+fn hhdm() -> u64 {
+    crate::elf_loader::get_hhdm_offset()
+}
+
+fn pages(range: &Range<u64>) -> impl Iterator<Item = Page<Size4KiB>> {
+    range
+        .clone()
+        .step_by(PAGE_SIZE as usize)
+        .map(|addr| Page::containing_address(VirtAddr::new(addr)))
+}
+
+// First address in `range` (in user space) with a page mapped, looking only
+// into the page tables that exist
+fn first_mapped(range: &Range<u64>) -> Option<u64> {
+    fn walk(table: &PageTable, level: u32, range: Range<u64>) -> Option<u64> {
+        let shift = 12 + 9 * (level - 1);
+        let mut addr = range.start;
+        while addr < range.end {
+            let entry = &table[((addr >> shift) & 0x1FF) as usize];
+            let entry_end = ((addr >> shift) + 1) << shift;
+            let flags = entry.flags();
+            if flags.contains(PageTableFlags::PRESENT) {
+                if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+                    return Some(addr);
+                }
+                let next = unsafe { &*((entry.addr().as_u64() + hhdm()) as *const PageTable) };
+                if let Some(found) = walk(next, level - 1, addr..range.end.min(entry_end)) {
+                    return Some(found);
+                }
+            }
+            addr = entry_end;
+        }
+        None
+    }
+
+    let mapper = unsafe { crate::pml4::init_mapper(hhdm()) };
+    walk(mapper.level_4_table(), 4, range.clone())
+}
+
+// Unmap the pages of an area taken out of the address space, freeing the
+// frames that were its own and the page tables left empty
+fn release(vma: &Vma) {
+    let mut mapper = unsafe { crate::pml4::init_mapper(hhdm()) };
+    for page in pages(&vma.range) {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            if !vma.shared {
+                unsafe { crate::pmm::KernelFrameAllocator.deallocate_frame(frame) };
+            }
+        }
+    }
+    let first = Page::containing_address(VirtAddr::new(vma.range.start));
+    let last = Page::containing_address(VirtAddr::new(vma.range.end - 1));
+    unsafe {
+        mapper.clean_up_addr_range(
+            Page::range_inclusive(first, last),
+            &mut crate::pmm::KernelFrameAllocator,
+        )
+    };
+    // The freed tables may still be in the paging-structure caches
+    tlb::flush_all();
+}
+
+// Add an area where nothing is mapped, by an area or by the kernel; its pages
+// are faulted in or mapped by the caller
+pub fn map(vma: Vma) -> Result<(), VmaError> {
+    check_range(&vma.range)?;
+    with_space(|space| {
+        if first_mapped(&vma.range).is_some() {
+            return Err(VmaError::Overlap);
+        }
+        space.insert(vma)
+    })
+}
+
+// Add an area of `len` bytes at the lowest free address in `window`, past
+// pages the kernel mapped there; returns its start
+pub fn map_anywhere(len: u64, mut window: Range<u64>, vma: Vma) -> Result<u64, VmaError> {
+    with_space(|space| {
+        loop {
+            let start = space
+                .find_free(len, window.clone())
+                .ok_or(VmaError::NoMemory)?;
+            let range = start..start + len;
+            match first_mapped(&range) {
+                Some(taken) => window.start = (taken & !(PAGE_SIZE - 1)) + PAGE_SIZE,
+                None => {
+                    space.insert(Vma { range, ..vma })?;
+                    return Ok(start);
+                }
+            }
+        }
+    })
+}
+
+// Start the brk heap, empty, at `start` (page aligned), past the program
+pub fn init_brk(start: u64) {
+    with_space(|space| space.brk = start..start);
+}
+
+// brk: move the program break to `addr`; returns the break. The pages above
+// a lower break are unmapped, those below a higher one faulted in on access
+pub fn brk(addr: u64) -> u64 {
+    let (brk, removed) = with_space(|space| {
+        // Not over pages the kernel mapped
+        let old_top = space.brk.end.next_multiple_of(PAGE_SIZE);
+        let new_top = addr.min(USER_END).next_multiple_of(PAGE_SIZE);
+        if old_top < new_top && first_mapped(&(old_top..new_top)).is_some() {
+            return (space.brk.end, Vec::new());
+        }
+        space.set_brk(addr)
+    });
+    for vma in removed {
+        release(&vma);
+    }
+    brk
+}
+
+// Remove `range` from the address space, whatever is mapped there
+pub fn unmap(range: Range<u64>) -> Result<(), VmaError> {
+    check_range(&range)?;
+    for vma in with_space(|space| space.remove(range)) {
+        release(&vma);
+    }
+    Ok(())
+}
+
+// Change the protection of `range`, pages already mapped included
+pub fn protect(range: Range<u64>, prot: u64) -> Result<(), VmaError> {
+    let changed = with_space(|space| space.protect(range, prot))?;
+    let mut mapper = unsafe { crate::pml4::init_mapper(hhdm()) };
+    for vma in changed {
+        for page in pages(&vma.range) {
+            if let Ok(flush) = unsafe { mapper.update_flags(page, page_flags(prot)) } {
+                flush.flush();
+            }
+        }
+    }
+    Ok(())
+}
+
+// The area holding `addr`
+pub fn find(addr: u64) -> Option<Vma> {
+    with_space(|space| space.find(addr).cloned())
+}
+
+// Whether `addr` lies in an area
+pub fn contains(addr: u64) -> bool {
+    find(addr).is_some()
+}
+
+// Map a zeroed frame at the page holding `addr` if it is in a demand-paged area
+// and not mapped yet; true if the page is mapped afterwards
+pub fn populate(addr: u64) -> bool {
+    let Some(vma) = find(addr).filter(Vma::demand_paged) else {
+        return false;
+    };
+    let mut mapper = unsafe { crate::pml4::init_mapper(hhdm()) };
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+    if mapper.translate_page(page).is_ok() {
+        return true;
+    }
+    let mut frame_allocator = crate::pmm::KernelFrameAllocator;
+    let Some(frame) = frame_allocator.allocate_frame() else {
+        log::warn!("VMA: out of memory faulting in {:#x}", addr);
+        return false;
+    };
+    unsafe {
+        core::ptr::write_bytes(
+            (frame.start_address().as_u64() + hhdm()) as *mut u8,
+            0,
+            PAGE_SIZE as usize,
+        )
+    };
+    match unsafe { mapper.map_to(page, frame, page_flags(vma.prot), &mut frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            false
+        }
+    }
+}
+
+// Resolve a page fault at `addr`: true if it was the first touch of a page of
+// a demand-paged area, now mapped, and the faulting instruction can be restarted
+pub fn handle_fault(addr: u64, error_code: PageFaultErrorCode) -> bool {
+    // A present page the access was not allowed on is not ours to fix
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    let Some(vma) = find(addr) else {
+        return false;
+    };
+    let allowed = if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        vma.prot & PROT_WRITE != 0
+    } else if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        vma.prot & PROT_EXEC != 0
+    } else {
+        vma.prot != PROT_NONE
+    };
+    if !allowed || !populate(addr) {
+        return false;
+    }
+    crate::rusage::page_fault();
+    true
+}
+
+// Give the page holding `addr` back if it is anonymous memory that was faulted
+// in; the next access faults in a zeroed one (MADV_DONTNEED)
+pub fn discard(addr: u64) {
+    if !find(addr).is_some_and(|vma| vma.demand_paged()) {
+        return;
+    }
+    let mut mapper = unsafe { crate::pml4::init_mapper(hhdm()) };
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+    if let Ok((frame, flush)) = mapper.unmap(page) {
+        flush.flush();
+        unsafe { crate::pmm::KernelFrameAllocator.deallocate_frame(frame) };
+    }
+}
+
+// Unmap every area, before loading another program
+pub fn reset() {
+    let space = with_space(|space| core::mem::replace(space, AddressSpace::new()));
+    for vma in space.areas.values() {
+        release(vma);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86_64::structures::paging::{Translate, mapper::TranslateResult};
+
+    // Out of the way of the loader's regions
+    const TEST_AREA: Range<u64> = 0x6000_0000..0x6000_2000;
+
+    fn mapped(addr: u64) -> bool {
+        let mapper = unsafe { crate::pml4::init_mapper(hhdm()) };
+        mapper.translate_addr(VirtAddr::new(addr)).is_some()
+    }
+
+    fn anonymous(range: Range<u64>) -> Vma {
+        Vma::new(range, PROT_READ | PROT_WRITE, VmaKind::Anonymous)
+    }
+
+    #[test_case]
+    fn areas_do_not_overlap_and_split_on_removal() {
+        let mut space = AddressSpace::new();
+        assert_eq!(
+            space.insert(anonymous(0x1000..0x1000)),
+            Err(VmaError::Invalid)
+        );
+        assert_eq!(
+            space.insert(anonymous(0x1800..0x3000)),
+            Err(VmaError::Invalid)
+        );
+        assert_eq!(space.insert(anonymous(0x4000..0x8000)), Ok(()));
+        assert_eq!(
+            space.insert(anonymous(0x7000..0x9000)),
+            Err(VmaError::Overlap)
+        );
+        assert_eq!(space.insert(anonymous(0x2000..0x4000)), Ok(()));
+        assert_eq!(
+            space.find(0x3fff).map(|vma| vma.range.clone()),
+            Some(0x2000..0x4000)
+        );
+        assert!(space.find(0x8000).is_none());
+
+        // Free room: below the first area, then only past the last
+        assert_eq!(space.find_free(0x1000, 0x1000..0x10000), Some(0x1000));
+        assert_eq!(space.find_free(0x2000, 0x1000..0x10000), Some(0x8000));
+        assert_eq!(space.find_free(0x9000, 0x1000..0x10000), None);
+
+        // A hole punched through the middle of an area
+        let removed = space.remove(0x5000..0x6000);
+        assert_eq!(removed, [anonymous(0x5000..0x6000)]);
+        assert_eq!(
+            space.find(0x4000).map(|vma| vma.range.clone()),
+            Some(0x4000..0x5000)
+        );
+        assert_eq!(
+            space.find(0x6000).map(|vma| vma.range.clone()),
+            Some(0x6000..0x8000)
+        );
+        assert!(space.find(0x5000).is_none());
+    }
+
+    #[test_case]
+    fn the_break_grows_and_shrinks_the_heap_area() {
+        let mut space = AddressSpace::new();
+        space.brk = 0x10000..0x10000;
+        let heap = |space: &AddressSpace| space.find(0x10000).map(|vma| vma.range.clone());
+        assert_eq!(space.set_brk(0x10010).0, 0x10010);
+        assert_eq!(heap(&space), Some(0x10000..0x11000));
+        // One area, extended
+        assert_eq!(space.set_brk(0x13000).0, 0x13000);
+        assert_eq!(heap(&space), Some(0x10000..0x13000));
+
+        // Not into another area, nor below the start
+        space.insert(anonymous(0x14000..0x15000)).unwrap();
+        assert_eq!(space.set_brk(0x14800).0, 0x13000);
+        assert_eq!(space.set_brk(0x8000).0, 0x13000);
+
+        let (brk, removed) = space.set_brk(0x10800);
+        assert_eq!(brk, 0x10800);
+        assert_eq!(removed, [anonymous(0x11000..0x13000)]);
+        assert_eq!(heap(&space), Some(0x10000..0x11000));
+        assert_eq!(space.set_brk(0x10000).1, [anonymous(0x10000..0x11000)]);
+        assert!(heap(&space).is_none());
+    }
+
+    #[test_case]
+    fn protection_changes_need_a_mapped_range() {
+        let mut space = AddressSpace::new();
+        space.insert(anonymous(0x2000..0x4000)).unwrap();
+        space.insert(anonymous(0x5000..0x6000)).unwrap();
+        assert_eq!(
+            space.protect(0x3000..0x6000, PROT_READ),
+            Err(VmaError::NoMemory)
+        );
+        assert_eq!(space.find(0x3000).unwrap().prot, PROT_READ | PROT_WRITE);
+        assert!(space.protect(0x3000..0x4000, PROT_READ).is_ok());
+        assert_eq!(space.find(0x2000).unwrap().prot, PROT_READ | PROT_WRITE);
+        assert_eq!(space.find(0x3000).unwrap().prot, PROT_READ);
+        assert_eq!(
+            page_flags(PROT_READ),
+            PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE
+        );
+        assert!(!page_flags(PROT_NONE).contains(PageTableFlags::USER_ACCESSIBLE));
+    }
+
+    #[test_case]
+    fn areas_stay_clear_of_kernel_mappings() {
+        // A page the kernel mapped for itself, known to no area
+        let kernel_page = TEST_AREA.start + PAGE_SIZE;
+        let mut mapper = unsafe { crate::pml4::init_mapper(hhdm()) };
+        let mut frames = crate::pmm::KernelFrameAllocator;
+        let frame = frames.allocate_frame().unwrap();
+        let page = Page::containing_address(VirtAddr::new(kernel_page));
+        unsafe { mapper.map_to(page, frame, PageTableFlags::PRESENT, &mut frames) }
+            .unwrap()
+            .flush();
+        assert_eq!(first_mapped(&TEST_AREA), Some(kernel_page));
+
+        assert_eq!(map(anonymous(TEST_AREA)), Err(VmaError::Overlap));
+        let start = map_anywhere(
+            PAGE_SIZE * 2,
+            TEST_AREA.start..TEST_AREA.end + PAGE_SIZE * 3,
+            anonymous(0..0),
+        );
+        assert_eq!(start, Ok(kernel_page + PAGE_SIZE));
+        unmap(kernel_page + PAGE_SIZE..kernel_page + PAGE_SIZE * 3).unwrap();
+
+        // Still the kernel's
+        assert!(mapped(kernel_page));
+        let (frame, flush) = mapper.unmap(page).unwrap();
+        flush.flush();
+        unsafe { frames.deallocate_frame(frame) };
+        assert_eq!(first_mapped(&TEST_AREA), None);
+    }
+
+    #[test_case]
+    fn first_touch_maps_a_zeroed_page() {
+        map(anonymous(TEST_AREA)).unwrap();
+        let addr = TEST_AREA.start + 8;
+        assert!(!mapped(addr));
+        // A kernel access faults the page in like a user one
+        let value = addr as *mut u64;
+        assert_eq!(unsafe { value.read_volatile() }, 0);
+        unsafe { value.write_volatile(42) };
+        assert_eq!(unsafe { value.read_volatile() }, 42);
+        assert!(mapped(addr));
+        assert!(!mapped(TEST_AREA.start + PAGE_SIZE));
+
+        // Discarded, it comes back zeroed
+        discard(addr);
+        assert!(!mapped(addr));
+        assert_eq!(unsafe { value.read_volatile() }, 0);
+
+        let used = crate::pmm::stats().used;
+        unmap(TEST_AREA).unwrap();
+        assert!(find(addr).is_none());
+        assert!(!mapped(addr));
+        assert!(crate::pmm::stats().used < used);
+    }
+
+    #[test_case]
+    fn unmapping_frees_the_page_tables_left_empty() {
+        // Alone in its PML4 entry: the fault needs a PDPT, a PD and a PT too
+        let area = 0x5000_0000_0000..0x5000_0000_2000;
+        map(anonymous(area.clone())).unwrap();
+        let used = crate::pmm::stats().used;
+        unsafe { (area.start as *mut u8).write_volatile(1) };
+        assert_eq!(crate::pmm::stats().used, used + 4);
+
+        // Unmapping half the area keeps the tables the other half needs
+        unsafe { ((area.start + PAGE_SIZE) as *mut u8).write_volatile(1) };
+        unmap(area.start..area.start + PAGE_SIZE).unwrap();
+        assert_eq!(crate::pmm::stats().used, used + 4);
+        assert!(mapped(area.start + PAGE_SIZE));
+
+        unmap(area).unwrap();
+        assert_eq!(crate::pmm::stats().used, used);
+    }
+
+    #[test_case]
+    fn faults_the_protection_does_not_allow_are_refused() {
+        map(Vma::new(TEST_AREA, PROT_READ, VmaKind::Anonymous)).unwrap();
+        let addr = TEST_AREA.start;
+        assert!(!handle_fault(addr, PageFaultErrorCode::CAUSED_BY_WRITE));
+        assert!(!handle_fault(addr, PageFaultErrorCode::INSTRUCTION_FETCH));
+        assert!(!handle_fault(TEST_AREA.end, PageFaultErrorCode::empty()));
+        assert!(!mapped(addr));
+        assert!(handle_fault(addr, PageFaultErrorCode::empty()));
+        assert!(!handle_fault(
+            addr,
+            PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE
+        ));
+
+        // Made writable, the mapped page is too
+        protect(TEST_AREA, PROT_READ | PROT_WRITE).unwrap();
+        unsafe { (addr as *mut u8).write_volatile(1) };
+        unmap(TEST_AREA).unwrap();
+        assert!(!mapped(addr));
+    }
+
+    #[test_case]
+    fn protection_changes_reach_mapped_pages() {
+        let flags = |addr: u64| {
+            let mapper = unsafe { crate::pml4::init_mapper(hhdm()) };
+            match mapper.translate(VirtAddr::new(addr)) {
+                TranslateResult::Mapped { flags, .. } => flags,
+                _ => panic!("{:#x} not mapped", addr),
+            }
+        };
+        map(anonymous(TEST_AREA)).unwrap();
+        let addr = TEST_AREA.start;
+        unsafe { (addr as *mut u8).write_volatile(1) };
+        assert!(flags(addr).contains(PageTableFlags::WRITABLE));
+
+        protect(TEST_AREA, PROT_READ).unwrap();
+        assert!(!flags(addr).contains(PageTableFlags::WRITABLE));
+        assert!(flags(addr).contains(PageTableFlags::NO_EXECUTE));
+        protect(TEST_AREA, PROT_READ | PROT_EXEC).unwrap();
+        assert!(!flags(addr).contains(PageTableFlags::NO_EXECUTE));
+        // PROT_NONE keeps the page, out of the program's reach
+        protect(TEST_AREA, PROT_NONE).unwrap();
+        assert!(!flags(addr).contains(PageTableFlags::USER_ACCESSIBLE));
+        assert_eq!(find(addr).map(|vma| vma.prot), Some(PROT_NONE));
+
+        // Not covered by an area: nothing changes
+        assert_eq!(
+            protect(TEST_AREA.start..TEST_AREA.end + PAGE_SIZE, PROT_READ),
+            Err(VmaError::NoMemory)
+        );
+        assert_eq!(find(addr).map(|vma| vma.prot), Some(PROT_NONE));
+        unmap(TEST_AREA).unwrap();
+    }
+}
@@ -14,17 +14,16 @@ macro_rules! println {
 
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
-    use core::fmt::Write;
-    use x86_64::instructions::interrupts;
+    // Both locks are IrqMutexes: interrupt handlers that print cannot deadlock on them
 
-    // Lock interrupts to avoid deadlock when both interrupt handler and kernel want to print
-    interrupts::without_interrupts(|| {
-        // 1. Print to Serial (always prioritize because it is the most stable for debugging)
-        shared::serial::_print(args);
+    // 1. Print to Serial (always prioritize because it is the most stable for debugging)
+    shared::serial::_print(args);
 
-        // 2. Print to Screen (GOP)
-        if let Some(writer) = &mut *crate::screen::WRITER.lock() {
-            let _ = writer.write_fmt(args);
-        }
-    });
+    // 2. Print to Screen (GOP)
+    #[cfg(feature = "graphics")]
+    if let Some(writer) = &mut *crate::screen::WRITER.lock() {
+        use core::fmt::Write;
+        let _ = writer.write_fmt(args);
+        writer.flush();
+    }
 }
@@ -0,0 +1,116 @@
+#!/usr/bin/env bash
+# Cargo runner for the kernel (see .cargo/config.toml): boots the given kernel ELF
+# under QEMU with the UEFI bootloader.
+#
+#   cargo run  -p kernel --target x86_64-unknown-none   interactive, like run-uefi.sh
+#   cargo test -p kernel --target x86_64-unknown-none   headless, the kernel runs its
+#                                                       #[test_case] tests and exits
+#                                                       through isa-debug-exit
+#
+# Environment overrides (used by run-integration-tests.sh):
+#   QEMU_RUNNER_MODE=run|test   instead of guessing from the kernel path
+#   INITRAMFS=<file>            initramfs.tar to boot with
+#   COMPRESS_KERNEL=1           store the kernel LZ4-compressed on the ESP (needs lz4)
+set -e
+
+KERNEL="$1"
+ROOT="$(cd "$(dirname "$0")" && pwd)"
+
+OVMF_CODE=/usr/share/OVMF/OVMF_CODE_4M.fd
+OVMF_VARS=/usr/share/OVMF/OVMF_VARS_4M.fd
+
+# Seconds before a hanging test run is killed
+TEST_TIMEOUT=300
+
+# isa-debug-exit: the kernel writes 0x10 (success) or 0x11 (failure),
+# QEMU exits with (code << 1) | 1
+QEMU_EXIT_SUCCESS=33
+
+if [ ! -f "$KERNEL" ]; then
+    echo "[!] Kernel not found: $KERNEL"
+    exit 1
+fi
+
+# Test binaries are built into target/<triple>/<profile>/deps
+case "$KERNEL" in
+    */deps/*) MODE=test ;;
+    *) MODE=run ;;
+esac
+MODE="${QEMU_RUNNER_MODE:-$MODE}"
+INITRAMFS="${INITRAMFS:-$ROOT/initramfs.tar}"
+
+# ==========================
+# BUILD UEFI BOOTLOADER
+# ==========================
+(cd "$ROOT" && cargo uefi_boot --release -q)
+EFI_PATH="$ROOT/target/x86_64-unknown-uefi/release/uefi_boot.efi"
+
+# ==========================
+# PREPARE ESP
+# ==========================
+# One ESP per mode so a test run does not replace the kernel of esp/
+ESP="$ROOT/target/qemu-runner/esp-$MODE"
+mkdir -p "$ESP/efi/boot"
+cp "$EFI_PATH" "$ESP/efi/boot/bootx64.efi"
+cp "$KERNEL" "$ESP/kernel"
+
+# Symbolized backtraces, optional: debug kernels may not fit in .ksyms
+if ! (cd / && cargo build --release -q --manifest-path "$ROOT/tools/ksyms/Cargo.toml") ||
+    ! "$ROOT/tools/ksyms/target/release/ksyms" "$ESP/kernel"; then
+    echo "[!] Continuing without kernel symbols"
+fi
+if [ "${COMPRESS_KERNEL:-0}" = 1 ]; then
+    lz4 -q -f -9 --content-size "$ESP/kernel" "$ESP/kernel.lz4"
+    mv "$ESP/kernel.lz4" "$ESP/kernel"
+fi
+# Checked by the bootloader before it starts the kernel (after ksyms patched it)
+(cd "$ESP" && sha256sum kernel > kernel.sha256)
+
+rm -f "$ESP/initramfs.tar" "$ESP/rootfs.iso"
+if [ -f "$INITRAMFS" ]; then
+    cp "$INITRAMFS" "$ESP/initramfs.tar"
+elif [ -f "$ROOT/rootfs.iso" ]; then
+    cp "$ROOT/rootfs.iso" "$ESP/rootfs.iso"
+fi
+if [ -f "$ROOT/font.psf" ]; then
+    cp "$ROOT/font.psf" "$ESP/font.psf"
+fi
+rm -f "$ESP/boot.cfg"
+if [ -f "$ROOT/boot.cfg" ]; then
+    cp "$ROOT/boot.cfg" "$ESP/boot.cfg"
+fi
+
+# Firmware variables are writable, use a copy
+cp "$OVMF_VARS" "$ROOT/target/qemu-runner/OVMF_VARS_4M.fd"
+
+QEMU_ARGS=(
+    -m 512M
+    -drive if=pflash,format=raw,readonly=on,file="$OVMF_CODE"
+    -drive if=pflash,format=raw,file="$ROOT/target/qemu-runner/OVMF_VARS_4M.fd"
+    -drive format=raw,file=fat:rw:"$ESP"
+    -serial stdio
+)
+if [ -w /dev/kvm ]; then
+    QEMU_ARGS+=(-enable-kvm)
+fi
+
+# ==========================
+# RUN
+# ==========================
+if [ "$MODE" = run ]; then
+    exec qemu-system-x86_64 "${QEMU_ARGS[@]}"
+fi
+
+set +e
+timeout "$TEST_TIMEOUT" qemu-system-x86_64 "${QEMU_ARGS[@]}" \
+    -device isa-debug-exit,iobase=0xf4,iosize=0x04 \
+    -display none \
+    -no-reboot
+STATUS=$?
+set -e
+
+case "$STATUS" in
+    "$QEMU_EXIT_SUCCESS") exit 0 ;;
+    124) echo "[!] Tests timed out after ${TEST_TIMEOUT}s"; exit 1 ;;
+    *) echo "[!] Tests failed (QEMU exit status $STATUS)"; exit 1 ;;
+esac
@@ -0,0 +1,38 @@
+#!/usr/bin/env bash
+# Integration tests: boots a kernel built with the "integration-tests" feature under
+# QEMU with an initramfs holding the test programs of user_space/tests. The kernel
+# runs each of them, compares its output with <name>.expected and exits QEMU with
+# the result (see kernel/src/itest.rs). Exit status 0 means every test passed.
+set -e
+
+ROOT="$(cd "$(dirname "$0")" && pwd)"
+OUT="$ROOT/target/itest"
+
+# ==========================
+# BUILD TEST PROGRAMS
+# ==========================
+echo "[*] Building test programs..."
+rm -rf "$OUT"
+mkdir -p "$OUT/tests"
+for src in "$ROOT"/user_space/tests/*.S; do
+    name="$(basename "$src" .S)"
+    as -I "$ROOT/user_space/tests" "$src" -o "$OUT/$name.o"
+    ld -static "$OUT/$name.o" -o "$OUT/tests/$name"
+    if [ -f "$ROOT/user_space/tests/$name.expected" ]; then
+        cp "$ROOT/user_space/tests/$name.expected" "$OUT/tests/"
+    fi
+done
+tar --format=ustar -cf "$OUT/initramfs.tar" -C "$OUT" tests
+
+# ==========================
+# BUILD KERNEL
+# ==========================
+echo "[*] Building kernel..."
+cd "$ROOT"
+cargo +nightly kernel --release --features integration-tests
+
+# ==========================
+# RUN
+# ==========================
+QEMU_RUNNER_MODE=test INITRAMFS="$OUT/initramfs.tar" \
+    "$ROOT/qemu-runner.sh" target/x86_64-unknown-none/release/kernel
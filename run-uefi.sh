@@ -11,6 +11,13 @@ ESP_IMG=esp.img
 ESP_SIZE=64   # MB
 ESP_DIR=esp
 
+# 1: store the kernel LZ4-compressed on the ESP (needs lz4), unpacked by the bootloader
+COMPRESS_KERNEL=${COMPRESS_KERNEL:-0}
+
+# 1: no boot disk, the firmware fetches the bootloader and the bootloader every
+# other file from QEMU's built-in TFTP server (serving esp/) over PXE
+PXE=${PXE:-0}
+
 OVMF_CODE=/usr/share/OVMF/OVMF_CODE_4M.fd
 OVMF_VARS=/usr/share/OVMF/OVMF_VARS_4M.fd
 
@@ -26,6 +33,27 @@ cargo +nightly uefi_boot --release
 echo "[*] Building kernel..."
 cargo +nightly kernel --release
 
+# ==========================
+# BUILD INITRAMFS
+# ==========================
+# User programs of user_space/initramfs.manifest, packed into initramfs.tar;
+# ROOTFS=<file> boots with another ustar archive or ISO9660 image instead
+if [ -z "$ROOTFS" ]; then
+    echo "[*] Building initramfs..."
+    ./build-initramfs.sh
+    ROOTFS=initramfs.tar
+fi
+
+# ==========================
+# EMBED KERNEL SYMBOLS
+# ==========================
+# ksyms is a host tool: build it from outside the repo so the build-std settings
+# of .cargo/config.toml do not apply to it
+echo "[*] Embedding kernel symbols..."
+ROOT=$(pwd)
+(cd / && cargo +nightly build --release -q --manifest-path "$ROOT/tools/ksyms/Cargo.toml")
+tools/ksyms/target/release/ksyms target/x86_64-unknown-none/release/kernel
+
 EFI_PATH=target/x86_64-unknown-uefi/release/uefi_boot.efi
 
 if [ ! -f "$EFI_PATH" ]; then
@@ -40,11 +68,48 @@ cp $OVMF_CODE .
 mkdir -p esp/efi/boot
 cp target/x86_64-unknown-uefi/release/uefi_boot.efi esp/efi/boot/bootx64.efi
 cp target/x86_64-unknown-none/release/kernel esp/kernel
+if [ "$COMPRESS_KERNEL" = 1 ]; then
+    lz4 -q -f -9 --content-size esp/kernel esp/kernel.lz4
+    mv esp/kernel.lz4 esp/kernel
+fi
+# Checked by the bootloader before it starts the kernel
+(cd esp && sha256sum kernel > kernel.sha256)
+
+# Initramfs (ustar archive or ISO9660 image mounted at / by the kernel)
+rm -f esp/initramfs.tar esp/rootfs.iso
+case "$ROOTFS" in
+    *.iso) cp "$ROOTFS" esp/rootfs.iso ;;
+    *) cp "$ROOTFS" esp/initramfs.tar ;;
+esac
+
+# Optional PSF (1 or 2) console font
+if [ -f font.psf ]; then
+    cp font.psf esp/font.psf
+fi
+
+# Optional kernel command line, e.g. "loglevel=debug serial=off"
+rm -f esp/boot.cfg
+if [ -f boot.cfg ]; then
+    cp boot.cfg esp/boot.cfg
+fi
+
+# Raw disk for the persistent crash log (hdb, the ESP is hda); formatted once with
+# `echo "hdb 0 2048" > /proc/crashdump`, found again at every boot after that
+if [ ! -f crash.img ]; then
+    truncate -s 1M crash.img
+fi
+
+if [ "$PXE" = 1 ]; then
+    BOOT_MEDIA=(-nic user,model=virtio-net-pci,tftp=esp,bootfile=efi/boot/bootx64.efi)
+else
+    BOOT_MEDIA=(-drive format=raw,file=fat:rw:esp)
+fi
 
 qemu-system-x86_64 \
     -enable-kvm \
     -m 512M \
     -drive if=pflash,format=raw,readonly=on,file=OVMF_CODE_4M.fd \
     -drive if=pflash,format=raw,readonly=on,file=OVMF_VARS_4M.fd \
-    -drive format=raw,file=fat:rw:esp \
+    "${BOOT_MEDIA[@]}" \
+    -drive format=raw,file=crash.img,if=ide,index=1 \
     -serial stdio
\ No newline at end of file
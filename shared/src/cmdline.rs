@@ -0,0 +1,99 @@
+// Kernel Command Line
+// Options for the kernel, read by the bootloader from `boot.cfg` (or
+// `cmdline.txt`) next to the kernel on the boot volume and handed over as text
+// through BootInfo, so the kernel can be configured without rebuilding it.
+//
+// Options are separated by whitespace, so the file may hold one per line or all
+// on one line; each is `key=value` or a bare `key`, and `#` starts a comment that
+// runs to the end of the line. Values cannot contain spaces. When a key is given
+// more than once the last one wins.
+//
+// The kernel records the text once at boot (`set`) and reads it anywhere with
+// `current`.
+//
+// The bootloader reads it too: `kernel=[<volume>:]<path>` names the kernel file,
+// on another volume (a label, or `fsN` as numbered by the UEFI shell) if given,
+// `initrd=<path>` names the initramfs file, each `module=<path>` loads one
+// more file for the kernel (see BootModule), and `kernel_stack=<KiB>` sizes the
+// kernel stack (see KERNEL_STACK_TOP).
+
+use crate::once::Once;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Cmdline<'a> {
+    text: &'a str,
+}
+
+impl<'a> Cmdline<'a> {
+    pub const EMPTY: Cmdline<'static> = Cmdline { text: "" };
+
+    pub fn new(text: &'a str) -> Self {
+        Self { text }
+    }
+
+    // Text from a file: only the part up to the first invalid UTF-8 is used
+    pub fn from_bytes(bytes: &'a [u8]) -> Self {
+        let text = match core::str::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(err) => core::str::from_utf8(&bytes[..err.valid_up_to()]).unwrap_or(""),
+        };
+        Self::new(text)
+    }
+
+    pub fn as_str(&self) -> &'a str {
+        self.text
+    }
+
+    // Every option as (key, value), None for a bare key, in order
+    pub fn options(&self) -> impl Iterator<Item = (&'a str, Option<&'a str>)> {
+        self.text
+            .lines()
+            .flat_map(|line| line.split('#').next().unwrap_or("").split_whitespace())
+            .map(|word| match word.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (word, None),
+            })
+    }
+
+    // Value of the last `key=value`
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.values(key).last()
+    }
+
+    // Values of every `key=value`, in order (for repeatable options)
+    pub fn values(&self, key: &str) -> impl Iterator<Item = &'a str> {
+        self.options()
+            .filter(move |&(name, _)| name == key)
+            .filter_map(|(_, value)| value)
+    }
+
+    // Whether `key` was given at all
+    pub fn has(&self, key: &str) -> bool {
+        self.options().any(|(name, _)| name == key)
+    }
+
+    // A switch: a bare key or on/yes/1/true turns it on, off/no/0/false off;
+    // None if it was not given or the value is something else
+    pub fn flag(&self, key: &str) -> Option<bool> {
+        let (_, value) = self.options().filter(|&(name, _)| name == key).last()?;
+        match value {
+            None | Some("on" | "yes" | "1" | "true") => Some(true),
+            Some("off" | "no" | "0" | "false") => Some(false),
+            Some(_) => None,
+        }
+    }
+}
+
+static CMDLINE: Once<&'static str> = Once::new();
+
+// Record the command line of this boot (only the first call counts)
+pub fn set(cmdline: Cmdline<'static>) {
+    CMDLINE.call_once(|| cmdline.text);
+}
+
+// The command line of this boot, empty if there was none
+pub fn current() -> Cmdline<'static> {
+    CMDLINE
+        .get()
+        .map_or(Cmdline::EMPTY, |&text| Cmdline::new(text))
+}
@@ -0,0 +1,149 @@
+// CPU Feature Baseline
+// The bootloader runs CPUID before loading anything, refuses a CPU without the
+// features the kernel is built to assume (REQUIRED), and passes what it found as
+// a bitmap in BootInfo, so the kernel does not crash obscurely on an old or
+// oddly virtualized CPU and does not have to probe again. Optional features are
+// only reported; the kernel turns on what it uses.
+//
+// The x87/SSE control bits are set the same way by the bootloader, before the
+// jump, and again by the kernel at boot (`enable_fpu`).
+
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+
+// Feature bits of the BootInfo bitmap
+pub const LONG_MODE: u64 = 1 << 0;
+pub const NX: u64 = 1 << 1;
+pub const PAE: u64 = 1 << 2;
+pub const FXSR: u64 = 1 << 3;
+pub const SSE: u64 = 1 << 4;
+pub const SSE2: u64 = 1 << 5;
+pub const XSAVE: u64 = 1 << 6;
+pub const AVX: u64 = 1 << 7;
+pub const FSGSBASE: u64 = 1 << 8;
+pub const PDPE1GB: u64 = 1 << 9;
+pub const LA57: u64 = 1 << 10;
+pub const RDRAND: u64 = 1 << 11;
+pub const INVARIANT_TSC: u64 = 1 << 12;
+
+// What the kernel cannot run without
+pub const REQUIRED: u64 = LONG_MODE | NX | PAE | FXSR | SSE | SSE2;
+
+const NAMES: [(u64, &str); 13] = [
+    (LONG_MODE, "lm"),
+    (NX, "nx"),
+    (PAE, "pae"),
+    (FXSR, "fxsr"),
+    (SSE, "sse"),
+    (SSE2, "sse2"),
+    (XSAVE, "xsave"),
+    (AVX, "avx"),
+    (FSGSBASE, "fsgsbase"),
+    (PDPE1GB, "pdpe1gb"),
+    (LA57, "la57"),
+    (RDRAND, "rdrand"),
+    (INVARIANT_TSC, "invtsc"),
+];
+
+// CPUID bits
+const LEAF1_EDX_PAE: u32 = 1 << 6;
+const LEAF1_EDX_FXSR: u32 = 1 << 24;
+const LEAF1_EDX_SSE: u32 = 1 << 25;
+const LEAF1_EDX_SSE2: u32 = 1 << 26;
+const LEAF1_ECX_XSAVE: u32 = 1 << 26;
+const LEAF1_ECX_AVX: u32 = 1 << 28;
+const LEAF1_ECX_RDRAND: u32 = 1 << 30;
+const LEAF7_EBX_FSGSBASE: u32 = 1 << 0;
+const LEAF7_ECX_LA57: u32 = 1 << 16;
+const EXT1_EDX_NX: u32 = 1 << 20;
+const EXT1_EDX_PDPE1GB: u32 = 1 << 26;
+const EXT1_EDX_LONG_MODE: u32 = 1 << 29;
+const EXT7_EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+// The running CPU's features
+pub fn detect() -> u64 {
+    let mut features = 0;
+    let mut set = |feature: u64, present: bool| {
+        if present {
+            features |= feature;
+        }
+    };
+
+    let max_leaf = __cpuid(0).eax;
+    let leaf1 = __cpuid(1);
+    set(PAE, leaf1.edx & LEAF1_EDX_PAE != 0);
+    set(FXSR, leaf1.edx & LEAF1_EDX_FXSR != 0);
+    set(SSE, leaf1.edx & LEAF1_EDX_SSE != 0);
+    set(SSE2, leaf1.edx & LEAF1_EDX_SSE2 != 0);
+    set(XSAVE, leaf1.ecx & LEAF1_ECX_XSAVE != 0);
+    set(AVX, leaf1.ecx & LEAF1_ECX_AVX != 0);
+    set(RDRAND, leaf1.ecx & LEAF1_ECX_RDRAND != 0);
+    if max_leaf >= 7 {
+        let leaf7 = __cpuid(7);
+        set(FSGSBASE, leaf7.ebx & LEAF7_EBX_FSGSBASE != 0);
+        set(LA57, leaf7.ecx & LEAF7_ECX_LA57 != 0);
+    }
+
+    let max_extended = __cpuid(0x8000_0000).eax;
+    if max_extended >= 0x8000_0001 {
+        let ext1 = __cpuid(0x8000_0001);
+        set(NX, ext1.edx & EXT1_EDX_NX != 0);
+        set(PDPE1GB, ext1.edx & EXT1_EDX_PDPE1GB != 0);
+        set(LONG_MODE, ext1.edx & EXT1_EDX_LONG_MODE != 0);
+    }
+    if max_extended >= 0x8000_0007 {
+        set(
+            INVARIANT_TSC,
+            __cpuid(0x8000_0007).edx & EXT7_EDX_INVARIANT_TSC != 0,
+        );
+    }
+    features
+}
+
+// The features of a bitmap by name, space separated, e.g. "lm nx pae"
+pub struct Names(pub u64);
+
+impl fmt::Display for Names {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names = NAMES.iter().filter(|(bit, _)| self.0 & bit != 0);
+        if let Some((_, first)) = names.next() {
+            f.write_str(first)?;
+        }
+        for (_, name) in names {
+            write!(f, " {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+// Put the x87 FPU and SSE in a known state: CR0.EM off and CR0.MP on (FPU
+// present, WAIT honours TS), CR0.NE for native x87 error reporting, CR4.OSFXSR /
+// OSXMMEXCPT so SSE works and reports unmasked SIMD exceptions through #XM, and
+// with XSAVE, CR4.OSXSAVE plus XCR0 enabling the x87, SSE and (if present) AVX
+// state components. FSGSBASE is left off: user code must not move GS.
+pub fn enable_fpu(features: u64) {
+    let xsave = features & XSAVE != 0;
+    unsafe {
+        let mut cr0 = Cr0::read();
+        cr0.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+        cr0.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        Cr0::write(cr0);
+
+        let mut cr4 = Cr4::read();
+        cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+        if xsave {
+            cr4.insert(Cr4Flags::OSXSAVE);
+        }
+        Cr4::write(cr4);
+    }
+
+    if xsave {
+        let mut xcr0 = XCr0Flags::X87 | XCr0Flags::SSE;
+        if features & AVX != 0 {
+            xcr0 |= XCr0Flags::AVX;
+        }
+        unsafe { XCr0::write(xcr0) };
+    }
+}
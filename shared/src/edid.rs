@@ -0,0 +1,65 @@
+// EDID (Extended Display Identification Data)
+// The monitor's description of itself, as read by the firmware: the bootloader
+// passes the 128-byte base block (and any extension blocks) on unchanged. The
+// first detailed timing descriptor is the preferred, usually native, mode.
+
+pub const BLOCK_LEN: usize = 128;
+
+const HEADER: [u8; 8] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+// First of the four 18-byte descriptors of the base block
+const DESCRIPTORS: usize = 54;
+const DESCRIPTOR_LEN: usize = 18;
+
+// A display mode from a detailed timing descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    pub pixel_clock_khz: u32,
+    pub width: u32,
+    pub height: u32,
+    pub h_blank: u32,
+    pub v_blank: u32,
+}
+
+impl Timing {
+    // Vertical refresh in millihertz
+    pub fn refresh_mhz(&self) -> u32 {
+        let total = (self.width + self.h_blank) as u64 * (self.height + self.v_blank) as u64;
+        (self.pixel_clock_khz as u64 * 1_000_000)
+            .checked_div(total)
+            .unwrap_or(0) as u32
+    }
+}
+
+// Whether `edid` starts with a base block with the EDID header and a valid
+// checksum
+pub fn is_valid(edid: &[u8]) -> bool {
+    edid.len() >= BLOCK_LEN
+        && edid[..HEADER.len()] == HEADER
+        && edid[..BLOCK_LEN]
+            .iter()
+            .fold(0u8, |sum, &b| sum.wrapping_add(b))
+            == 0
+}
+
+// The preferred mode: the first detailed timing descriptor of the base block
+// (a pixel clock of 0 marks a display descriptor instead)
+pub fn preferred_timing(edid: &[u8]) -> Option<Timing> {
+    if !is_valid(edid) {
+        return None;
+    }
+    let d = &edid[DESCRIPTORS..DESCRIPTORS + DESCRIPTOR_LEN];
+    let clock = u16::from_le_bytes([d[0], d[1]]) as u32;
+    if clock == 0 {
+        return None;
+    }
+    // 12-bit fields: low byte, high nibble shared with the companion field
+    let high =
+        |low: u8, shared: u8, shift: u8| low as u32 | (((shared >> shift) & 0x0F) as u32) << 8;
+    Some(Timing {
+        pixel_clock_khz: clock * 10,
+        width: high(d[2], d[4], 4),
+        h_blank: high(d[3], d[4], 0),
+        height: high(d[5], d[7], 4),
+        v_blank: high(d[6], d[7], 0),
+    })
+}
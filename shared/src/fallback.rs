@@ -0,0 +1,18 @@
+// A/B Kernel Fallback
+// A new kernel is installed as `kernel_new` next to the known good `kernel_good`.
+// Before booting `kernel_new` the bootloader stores its SHA-256 in a non-volatile
+// UEFI variable; the kernel deletes the variable once it has come up (efi.rs).
+// If the variable still holds the hash of `kernel_new` on the next boot, that
+// kernel never got that far, and `kernel_good` is booted instead.
+
+pub const NEW_KERNEL: &str = "kernel_new";
+pub const GOOD_KERNEL: &str = "kernel_good";
+
+// Name of the variable holding the hash of the kernel_new being tried
+pub const PENDING_VARIABLE: &str = "CatBootPending";
+
+// Vendor GUID of the variable, 5f0c2b1e-8d4a-4c7e-9b36-0a71c3e5d942, in the
+// EFI_GUID byte order (first three fields little endian)
+pub const VENDOR_GUID: [u8; 16] = [
+    0x1e, 0x2b, 0x0c, 0x5f, 0x4a, 0x8d, 0x7e, 0x4c, 0x9b, 0x36, 0x0a, 0x71, 0xc3, 0xe5, 0xd9, 0x42,
+];
@@ -9,7 +9,8 @@ pub enum PixelFormat {
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct FrameBufferInfo {
-    pub buffer_base: u64,
+    pub buffer_base: u64, // Virtual address, mapped by the bootloader
+    pub buffer_phys: u64, // Physical address, for the kernel's own mapping
     pub buffer_size: usize,
     pub width: usize,
     pub height: usize,
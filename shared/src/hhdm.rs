@@ -0,0 +1,79 @@
+// Higher Half Direct Map
+// All physical memory mapped at a fixed offset, built by the bootloader and by
+// kexec for the next kernel. With 1 GiB pages (CPUID PDPE1GB) the map needs one
+// PDPT per 512 GiB and nothing below it; otherwise it takes 2 MiB pages, one PD
+// per GiB.
+
+use core::arch::x86_64::__cpuid;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+const CPUID_EXTENDED_FEATURES: u32 = 0x8000_0001;
+const CPUID_EDX_PDPE1GB: u32 = 1 << 26;
+
+// Whether the CPU supports 1 GiB pages
+pub fn has_1gib_pages() -> bool {
+    let max_extended = __cpuid(0x8000_0000).eax;
+    max_extended >= CPUID_EXTENDED_FEATURES
+        && __cpuid(CPUID_EXTENDED_FEATURES).edx & CPUID_EDX_PDPE1GB != 0
+}
+
+// Page size used for the HHDM on this CPU
+pub fn page_size() -> u64 {
+    if has_1gib_pages() {
+        Size1GiB::SIZE
+    } else {
+        Size2MiB::SIZE
+    }
+}
+
+// Page tables needed below the PML4 to map `max_phys` bytes with `page_size` pages
+pub fn tables_for(max_phys: u64, page_size: u64) -> u64 {
+    let pdpts = max_phys.div_ceil(512 * Size1GiB::SIZE);
+    if page_size == Size1GiB::SIZE {
+        pdpts
+    } else {
+        pdpts + max_phys.div_ceil(Size1GiB::SIZE)
+    }
+}
+
+// Map physical 0..max_phys (rounded up to `page_size`) at `offset` into page
+// tables that are not active yet, so nothing is flushed. Returns false when the
+// allocator runs out of frames.
+pub fn map<M, A>(
+    mapper: &mut M,
+    allocator: &mut A,
+    max_phys: u64,
+    offset: u64,
+    page_size: u64,
+) -> bool
+where
+    M: Mapper<Size1GiB> + Mapper<Size2MiB>,
+    A: FrameAllocator<Size4KiB>,
+{
+    if page_size == Size1GiB::SIZE {
+        map_with::<Size1GiB, _, _>(mapper, allocator, max_phys, offset)
+    } else {
+        map_with::<Size2MiB, _, _>(mapper, allocator, max_phys, offset)
+    }
+}
+
+fn map_with<S, M, A>(mapper: &mut M, allocator: &mut A, max_phys: u64, offset: u64) -> bool
+where
+    S: PageSize,
+    M: Mapper<S>,
+    A: FrameAllocator<Size4KiB>,
+{
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for phys in (0..max_phys).step_by(S::SIZE as usize) {
+        let page = Page::<S>::containing_address(VirtAddr::new(phys + offset));
+        let frame = PhysFrame::<S>::containing_address(PhysAddr::new(phys));
+        match unsafe { mapper.map_to(page, frame, flags, allocator) } {
+            Ok(flush) => flush.ignore(),
+            Err(_) => return false,
+        }
+    }
+    true
+}
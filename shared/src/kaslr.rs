@@ -0,0 +1,84 @@
+// Kernel Address Space Layout Randomization
+// The kernel is linked as a static PIE at KERNEL_BASE and loaded `slide` bytes
+// higher: the loader (uefi_boot, or kexec for the next kernel) maps every segment
+// at its linked address + slide, applies the R_X86_64_RELATIVE relocations of
+// `.rela.dyn` and passes the slide in BootInfo. The kernel subtracts it again to
+// find link-time addresses (symbols for backtraces).
+//
+// The slide is a multiple of 2 MiB below MAX_SLIDE, so the image stays under the
+// module window (module.rs) and within reach of 32-bit displacements from it.
+// `nokaslr` on the command line loads the kernel at slide 0; a kernel linked at a
+// fixed address (ET_EXEC) is always loaded there.
+
+// Where kernels are linked (kernel/linker.ld)
+pub const KERNEL_BASE: u64 = 0xffff_ffff_8000_0000;
+
+pub const SLIDE_ALIGN: u64 = 2 * 1024 * 1024;
+pub const MAX_SLIDE: u64 = 256 * 1024 * 1024;
+
+// x86_64 relocation types
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+// Elf64_Rela: offset, info (type in the low 32 bits), addend
+const RELA_SIZE: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocError {
+    // The table is not a whole number of entries
+    Malformed,
+    // Only RELATIVE relocations are expected in a static PIE
+    Unsupported(u32),
+    // The place (a link-time address) is not inside a loaded segment
+    OutOfImage(u64),
+}
+
+// A slide from random bits
+pub fn slide_from_random(random: u64) -> u64 {
+    random % (MAX_SLIDE / SLIDE_ALIGN) * SLIDE_ALIGN
+}
+
+// Round `slide` down to a multiple of `align`, the largest p_align of the image,
+// so segments aligned beyond SLIDE_ALIGN keep their alignment (0 if it exceeds
+// MAX_SLIDE)
+pub fn align_slide(slide: u64, align: u64) -> u64 {
+    if align > SLIDE_ALIGN && align.is_power_of_two() {
+        slide - slide % align
+    } else {
+        slide
+    }
+}
+
+// Apply the relocations of a `.rela.dyn` section for `slide`. `write(place,
+// value)` stores a u64 at the link-time address `place` and returns false if it
+// is outside the image. Returns the number of relocations applied.
+pub fn relocate(
+    rela: &[u8],
+    slide: u64,
+    mut write: impl FnMut(u64, u64) -> bool,
+) -> Result<usize, RelocError> {
+    let (entries, rest) = rela.as_chunks::<RELA_SIZE>();
+    if !rest.is_empty() {
+        return Err(RelocError::Malformed);
+    }
+    let field = |entry: &[u8; RELA_SIZE], index: usize| {
+        let bytes: [u8; 8] = entry[index * 8..index * 8 + 8].try_into().unwrap();
+        u64::from_le_bytes(bytes)
+    };
+
+    let mut count = 0;
+    for entry in entries {
+        let (place, info, addend) = (field(entry, 0), field(entry, 1), field(entry, 2));
+        match info as u32 {
+            R_X86_64_NONE => {}
+            R_X86_64_RELATIVE => {
+                if !write(place, addend.wrapping_add(slide)) {
+                    return Err(RelocError::OutOfImage(place));
+                }
+                count += 1;
+            }
+            other => return Err(RelocError::Unsupported(other)),
+        }
+    }
+    Ok(count)
+}
@@ -3,6 +3,15 @@
 // Module for serial port communication
 pub mod serial;
 
+// One-time initialization (Once, LazyInit)
+pub mod once;
+
+// Fair ticket spinlock
+pub mod spinlock;
+
+// Interrupt-safe mutex
+pub mod sync;
+
 // Module for panic handling
 pub mod panic;
 
@@ -11,12 +20,301 @@ pub mod helpers;
 
 pub mod framebuffer;
 
+// Display identification (EDID) and its preferred timing
+pub mod edid;
+
+// Physical memory map (sorted, merged regions)
+pub mod memory;
+
+// CPUID feature baseline passed in BootInfo, x87/SSE control bits
+pub mod cpu;
+
+// Higher half direct map of physical memory (1 GiB or 2 MiB pages)
+pub mod hhdm;
+
+// 5-level paging (LA57): a PML5 above the usual PML4
+pub mod paging;
+
+// Kernel command line (boot.cfg) parsing
+pub mod cmdline;
+
+// Kernel load address randomization and relocation
+pub mod kaslr;
+
+// SHA-256, for the bootloader's kernel image check
+pub mod sha256;
+
+// LZ4 frame decompression, for a compressed kernel image
+pub mod lz4;
+
+// A/B kernel fallback (kernel_new / kernel_good) through a UEFI variable
+pub mod fallback;
+
+// TPM 2.0 measurements of the boot files and their event log
+pub mod tpm;
+
+// "CATBOOT\0": identifies a BootInfo written by our bootloader
+pub const BOOT_INFO_MAGIC: u64 = u64::from_le_bytes(*b"CATBOOT\0");
+
+// Bump on every change to the BootInfo layout or the meaning of a field
+pub const BOOT_INFO_VERSION: u32 = 14;
+
 #[repr(C)]
 pub struct BootInfo {
-    pub memory_map_addr: u64,
-    pub memory_map_len: u64,
-    pub memory_map_desc_size: u64,
+    // Header checked by the kernel before anything else is read
+    pub magic: u64,
+    pub version: u32,
+    pub size: u32,            // size_of::<BootInfo>() as seen by the bootloader
+    pub memory_map_addr: u64, // Physical address of the memory::MemoryRegion array
+    pub memory_map_len: u64,  // Number of regions
     pub hhdm_offset: u64,
     pub max_phys_memory: u64,
     pub framebuffer: framebuffer::FrameBufferInfo,
+    pub rsdp_addr: u64,      // Physical address of the ACPI RSDP (0 if not found)
+    pub initramfs_addr: u64, // Physical address of the initramfs archive (0 if none)
+    pub initramfs_size: u64,
+    pub font_addr: u64, // Physical address of a PSF console font (0 if none)
+    pub font_size: u64,
+    pub cmdline_addr: u64, // Physical address of the command line text (0 if none)
+    pub cmdline_len: u64,
+    pub kernel_slide: u64, // Kernel loaded this many bytes above its link address (KASLR)
+    pub modules_addr: u64, // Physical address of the BootModule array (0 if none)
+    pub modules_count: u64,
+    // Physical addresses of the EFI system table and runtime services table (0 if
+    // unavailable). The firmware is in virtual mode: its runtime code and data are
+    // at their HHDM addresses, as are the function pointers in the table
+    pub efi_system_table: u64,
+    pub efi_runtime_services: u64,
+    // 1 when this is a trial boot of `kernel_new`, to be confirmed by the kernel
+    // once it is up (see fallback)
+    pub boot_trial: u64,
+    // 4, or 5 with LA57 on: CR3 then holds a PML5 whose first and last entries
+    // lead to the PML4 (see paging)
+    pub paging_levels: u64,
+    pub edid_addr: u64, // Physical address of the display's EDID blob (0 if none)
+    pub edid_size: u64,
+    // What the bootloader set up for the kernel (segments, page tables, this
+    // BootInfo, the memory map): the first `reserved_count` entries
+    pub reserved: [memory::ReservedRange; memory::MAX_RESERVED],
+    pub reserved_count: u64,
+    // Physical address of a copy of the firmware's TPM 2.0 event log, crypto
+    // agile format, with the measurements of the kernel and initramfs (0 if no
+    // TPM; see tpm)
+    pub tpm_event_log_addr: u64,
+    pub tpm_event_log_size: u64,
+    // The kernel stack the kernel was started on: `stack_size` bytes mapped
+    // below `stack_top`, and the page under them left unmapped as a guard, so an
+    // overflow faults (0 if unknown)
+    pub stack_top: u64,
+    pub stack_size: u64,
+    // CPU features found by the bootloader's CPUID, a cpu::REQUIRED superset
+    pub cpu_features: u64,
+}
+
+// The kernel stack the bootloader and kexec map right below the kernel image;
+// `kernel_stack=<KiB>` in boot.cfg sizes it (rounded to pages, clamped)
+pub const KERNEL_STACK_TOP: u64 = kaslr::KERNEL_BASE - 0x1000;
+pub const DEFAULT_KERNEL_STACK: u64 = 64 * 1024;
+pub const MIN_KERNEL_STACK: u64 = 16 * 1024;
+pub const MAX_KERNEL_STACK: u64 = 1024 * 1024;
+
+// Most files the bootloader passes on as modules
+pub const MAX_BOOT_MODULES: usize = 32;
+pub const BOOT_MODULE_NAME_LEN: usize = 48;
+
+// A file the bootloader loaded for the kernel: the initramfs ("initramfs"), the
+// font ("font") and every `module=<path>` of boot.cfg (named after the file)
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BootModule {
+    pub addr: u64, // Physical address
+    pub size: u64,
+    pub name: [u8; BOOT_MODULE_NAME_LEN], // UTF-8, NUL padded
+}
+
+impl BootModule {
+    // A module for `data`, its name cut to BOOT_MODULE_NAME_LEN bytes
+    pub fn new(name: &str, data: &[u8]) -> Self {
+        let mut len = name.len().min(BOOT_MODULE_NAME_LEN);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut bytes = [0; BOOT_MODULE_NAME_LEN];
+        bytes[..len].copy_from_slice(&name.as_bytes()[..len]);
+        Self {
+            addr: data.as_ptr() as u64,
+            size: data.len() as u64,
+            name: bytes,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..len]).unwrap_or("?")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootInfoError {
+    // Null or misaligned: nothing was passed, or not a BootInfo pointer
+    BadAddress(u64),
+    BadMagic(u64),
+    VersionMismatch { found: u32, expected: u32 },
+    SizeMismatch { found: u32, expected: u32 },
+}
+
+impl core::fmt::Display for BootInfoError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            BootInfoError::BadAddress(addr) => write!(f, "no BootInfo (address {:#x})", addr),
+            BootInfoError::BadMagic(magic) => {
+                write!(
+                    f,
+                    "not a BootInfo (magic {:#018x}, expected {:#018x})",
+                    magic, BOOT_INFO_MAGIC
+                )
+            }
+            BootInfoError::VersionMismatch { found, expected } => write!(
+                f,
+                "BootInfo version {} from the bootloader, the kernel expects {}",
+                found, expected
+            ),
+            BootInfoError::SizeMismatch { found, expected } => write!(
+                f,
+                "BootInfo of {} bytes from the bootloader, the kernel expects {}",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl BootInfo {
+    // Fill in the header, called by the bootloader
+    pub fn init_header(&mut self) {
+        self.magic = BOOT_INFO_MAGIC;
+        self.version = BOOT_INFO_VERSION;
+        self.size = core::mem::size_of::<BootInfo>() as u32;
+    }
+
+    /// The BootInfo at `addr`, once its header checks out
+    ///
+    /// # Safety
+    /// A non-null, aligned `addr` must point to readable memory of BootInfo size
+    pub unsafe fn from_addr(addr: u64) -> Result<&'static BootInfo, BootInfoError> {
+        if addr == 0 || !addr.is_multiple_of(core::mem::align_of::<BootInfo>() as u64) {
+            return Err(BootInfoError::BadAddress(addr));
+        }
+        let boot_info = unsafe { &*(addr as *const BootInfo) };
+        boot_info.validate().map(|()| boot_info)
+    }
+
+    // Check that the bootloader and the kernel agree on the BootInfo layout
+    pub fn validate(&self) -> Result<(), BootInfoError> {
+        if self.magic != BOOT_INFO_MAGIC {
+            return Err(BootInfoError::BadMagic(self.magic));
+        }
+        if self.version != BOOT_INFO_VERSION {
+            return Err(BootInfoError::VersionMismatch {
+                found: self.version,
+                expected: BOOT_INFO_VERSION,
+            });
+        }
+        let expected = core::mem::size_of::<BootInfo>() as u32;
+        if self.size != expected {
+            return Err(BootInfoError::SizeMismatch {
+                found: self.size,
+                expected,
+            });
+        }
+        Ok(())
+    }
+
+    /// The memory map, read through the HHDM
+    ///
+    /// # Safety
+    /// The BootInfo must be valid and `hhdm_offset` must map all physical memory
+    pub unsafe fn memory_map(&self, hhdm_offset: u64) -> &'static [memory::MemoryRegion] {
+        unsafe {
+            core::slice::from_raw_parts(
+                (self.memory_map_addr + hhdm_offset) as *const memory::MemoryRegion,
+                self.memory_map_len as usize,
+            )
+        }
+    }
+
+    /// The module array, read through the HHDM
+    ///
+    /// # Safety
+    /// The BootInfo must be valid and `hhdm_offset` must map all physical memory
+    pub unsafe fn modules(&self, hhdm_offset: u64) -> &'static [BootModule] {
+        if self.modules_addr == 0 {
+            return &[];
+        }
+        unsafe {
+            core::slice::from_raw_parts(
+                (self.modules_addr + hhdm_offset) as *const BootModule,
+                self.modules_count as usize,
+            )
+        }
+    }
+
+    // The ranges the bootloader reserved for the kernel
+    pub fn reserved(&self) -> &[memory::ReservedRange] {
+        &self.reserved[..(self.reserved_count as usize).min(memory::MAX_RESERVED)]
+    }
+
+    // Record a reserved range; false if the list is full
+    pub fn reserve(&mut self, range: memory::ReservedRange) -> bool {
+        let Some(slot) = self.reserved.get_mut(self.reserved_count as usize) else {
+            return false;
+        };
+        *slot = range;
+        self.reserved_count += 1;
+        true
+    }
+
+    /// The display's EDID blob, read through the HHDM
+    ///
+    /// # Safety
+    /// The BootInfo must be valid and `hhdm_offset` must map all physical memory
+    pub unsafe fn edid(&self, hhdm_offset: u64) -> Option<&'static [u8]> {
+        if self.edid_addr == 0 {
+            return None;
+        }
+        Some(unsafe {
+            core::slice::from_raw_parts(
+                (self.edid_addr + hhdm_offset) as *const u8,
+                self.edid_size as usize,
+            )
+        })
+    }
+
+    // The unmapped page below the kernel stack, if the stack is known
+    pub fn stack_guard(&self) -> Option<core::ops::Range<u64>> {
+        if self.stack_size == 0 {
+            return None;
+        }
+        let bottom = self.stack_top - self.stack_size;
+        Some(bottom - memory::PAGE_SIZE..bottom)
+    }
+
+    /// The TPM event log, read through the HHDM
+    ///
+    /// # Safety
+    /// The BootInfo must be valid and `hhdm_offset` must map all physical memory
+    pub unsafe fn tpm_event_log(&self, hhdm_offset: u64) -> Option<&'static [u8]> {
+        if self.tpm_event_log_addr == 0 {
+            return None;
+        }
+        Some(unsafe {
+            core::slice::from_raw_parts(
+                (self.tpm_event_log_addr + hhdm_offset) as *const u8,
+                self.tpm_event_log_size as usize,
+            )
+        })
+    }
 }
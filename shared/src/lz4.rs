@@ -0,0 +1,205 @@
+// LZ4 Frame Decompression
+// Decoder for files written by the `lz4` tool (frame format v1.6): the
+// bootloader uses it for a compressed kernel image. The frame is walked twice:
+// `decompressed_len` sizes the output from the block headers and the sequence
+// lengths without writing anything, then `decompress` fills the buffer.
+//
+// Blocks are decoded into one contiguous buffer, so linked blocks (matches
+// reaching back into earlier blocks) work like independent ones. Checksums are
+// skipped, not verified; dictionaries and the legacy format are not supported.
+
+// Little endian magic at the start of a frame
+pub const MAGIC: u32 = 0x184D_2204;
+
+// Frame descriptor flags
+const FLG_VERSION_MASK: u8 = 0b1100_0000;
+const FLG_VERSION_1: u8 = 0b0100_0000;
+const FLG_BLOCK_CHECKSUM: u8 = 1 << 4;
+const FLG_CONTENT_SIZE: u8 = 1 << 3;
+const FLG_CONTENT_CHECKSUM: u8 = 1 << 2;
+const FLG_DICT_ID: u8 = 1 << 0;
+
+// Block size word: the high bit marks a block stored uncompressed, zero ends the frame
+const BLOCK_UNCOMPRESSED: u32 = 1 << 31;
+
+const MIN_MATCH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lz4Error {
+    // Not an LZ4 frame, or one using an unsupported feature
+    BadHeader,
+    // The data ends in the middle of a block or a sequence
+    Truncated,
+    // A match reaches back before the start of the output
+    BadOffset,
+    // The output buffer is smaller than the data
+    OutputTooSmall,
+}
+
+pub fn is_frame(data: &[u8]) -> bool {
+    data.len() >= 4 && u32::from_le_bytes([data[0], data[1], data[2], data[3]]) == MAGIC
+}
+
+// Size of the decompressed data
+pub fn decompressed_len(src: &[u8]) -> Result<usize, Lz4Error> {
+    decode_frame(src, &mut Sink::Count(0))
+}
+
+// Decompress into `out`, returning the number of bytes written
+pub fn decompress(src: &[u8], out: &mut [u8]) -> Result<usize, Lz4Error> {
+    decode_frame(src, &mut Sink::Buffer { out, len: 0 })
+}
+
+// Where decoded bytes go: counted only, or written
+enum Sink<'a> {
+    Count(usize),
+    Buffer { out: &'a mut [u8], len: usize },
+}
+
+impl Sink<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Sink::Count(len) => *len,
+            Sink::Buffer { len, .. } => *len,
+        }
+    }
+
+    fn literals(&mut self, bytes: &[u8]) -> Result<(), Lz4Error> {
+        match self {
+            Sink::Count(len) => *len += bytes.len(),
+            Sink::Buffer { out, len } => {
+                let dest = out
+                    .get_mut(*len..*len + bytes.len())
+                    .ok_or(Lz4Error::OutputTooSmall)?;
+                dest.copy_from_slice(bytes);
+                *len += bytes.len();
+            }
+        }
+        Ok(())
+    }
+
+    // Copy `count` bytes from `offset` back; byte by byte, as they may overlap
+    fn copy_match(&mut self, offset: usize, count: usize) -> Result<(), Lz4Error> {
+        if offset == 0 || offset > self.len() {
+            return Err(Lz4Error::BadOffset);
+        }
+        match self {
+            Sink::Count(len) => *len += count,
+            Sink::Buffer { out, len } => {
+                if *len + count > out.len() {
+                    return Err(Lz4Error::OutputTooSmall);
+                }
+                for i in *len..*len + count {
+                    out[i] = out[i - offset];
+                }
+                *len += count;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Byte reader over the compressed data
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], Lz4Error> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + count)
+            .ok_or(Lz4Error::Truncated)?;
+        self.pos += count;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Lz4Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, Lz4Error> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    // A length continued by extra bytes while they are 255
+    fn length(&mut self, mut length: usize) -> Result<usize, Lz4Error> {
+        loop {
+            let byte = self.u8()?;
+            length += byte as usize;
+            if byte != 255 {
+                return Ok(length);
+            }
+        }
+    }
+}
+
+fn decode_frame(src: &[u8], sink: &mut Sink) -> Result<usize, Lz4Error> {
+    let mut reader = Reader { data: src, pos: 0 };
+    if reader.u32()? != MAGIC {
+        return Err(Lz4Error::BadHeader);
+    }
+    let flags = reader.u8()?;
+    let _block_descriptor = reader.u8()?;
+    if flags & FLG_VERSION_MASK != FLG_VERSION_1 || flags & FLG_DICT_ID != 0 {
+        return Err(Lz4Error::BadHeader);
+    }
+    if flags & FLG_CONTENT_SIZE != 0 {
+        reader.bytes(8)?;
+    }
+    let _header_checksum = reader.u8()?;
+
+    loop {
+        let size = reader.u32()?;
+        if size == 0 {
+            break;
+        }
+        let block = reader.bytes((size & !BLOCK_UNCOMPRESSED) as usize)?;
+        if size & BLOCK_UNCOMPRESSED != 0 {
+            sink.literals(block)?;
+        } else {
+            decode_block(block, sink)?;
+        }
+        if flags & FLG_BLOCK_CHECKSUM != 0 {
+            reader.u32()?;
+        }
+    }
+    if flags & FLG_CONTENT_CHECKSUM != 0 {
+        reader.u32()?;
+    }
+    Ok(sink.len())
+}
+
+// Sequences of a compressed block: token, literals, then a match except in the
+// last sequence
+fn decode_block(block: &[u8], sink: &mut Sink) -> Result<(), Lz4Error> {
+    let mut reader = Reader {
+        data: block,
+        pos: 0,
+    };
+    loop {
+        let token = reader.u8()?;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals = reader.length(literals)?;
+        }
+        sink.literals(reader.bytes(literals)?)?;
+        if reader.at_end() {
+            return Ok(());
+        }
+
+        let offset = reader.bytes(2)?;
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        let mut length = (token & 0x0F) as usize;
+        if length == 15 {
+            length = reader.length(length)?;
+        }
+        sink.copy_match(offset, length + MIN_MATCH)?;
+    }
+}
@@ -0,0 +1,126 @@
+// Physical memory map as handed over in BootInfo
+// The firmware's map (EFI_MEMORY_DESCRIPTORs, with a firmware-chosen stride and
+// UEFI type numbers) never reaches the kernel: the bootloader converts it into an
+// array of MemoryRegion, sorted by address with adjacent regions of the same kind
+// merged, and the kernel (pmm, kexec) only reads that.
+
+pub const PAGE_SIZE: u64 = 4096;
+
+// What a region of physical memory is
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    // Free RAM
+    Usable = 1,
+    // Loaded by the bootloader: kernel image, page tables, stack, BootInfo, boot
+    // modules. Never handed out, these stay valid for the life of the kernel
+    Bootloader = 2,
+    // Used by the firmware's boot services, no longer needed but kept
+    BootServices = 3,
+    // ACPI tables
+    AcpiReclaimable = 4,
+    // Firmware storage that must be preserved (ACPI NVS, runtime services)
+    Firmware = 5,
+    // Memory-mapped I/O
+    Mmio = 6,
+    // Anything else, including memory reported as faulty
+    Reserved = 7,
+}
+
+impl MemoryKind {
+    // Kind of an EFI_MEMORY_TYPE
+    pub fn from_uefi(type_: u32) -> Self {
+        match type_ {
+            1 | 2 => MemoryKind::Bootloader,   // LoaderCode, LoaderData
+            3 | 4 => MemoryKind::BootServices, // BootServicesCode, BootServicesData
+            5 | 6 => MemoryKind::Firmware,     // RuntimeServicesCode, RuntimeServicesData
+            7 => MemoryKind::Usable,           // ConventionalMemory
+            9 => MemoryKind::AcpiReclaimable,  // ACPIReclaimMemory
+            10 => MemoryKind::Firmware,        // ACPIMemoryNVS
+            11 | 12 => MemoryKind::Mmio,       // MemoryMappedIO, MemoryMappedIOPortSpace
+            _ => MemoryKind::Reserved,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: u64, // Physical address, page aligned
+    pub len: u64,   // Bytes, a multiple of PAGE_SIZE
+    pub kind: MemoryKind,
+}
+
+impl MemoryRegion {
+    pub fn end(&self) -> u64 {
+        self.start + self.len
+    }
+
+    pub fn pages(&self) -> u64 {
+        self.len / PAGE_SIZE
+    }
+}
+
+// What a range the bootloader reserved for the kernel holds
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reservation {
+    None = 0,
+    KernelImage = 1, // One loaded kernel segment
+    PageTables = 2,  // The page table pool
+    BootInfo = 3,
+    MemoryMap = 4, // The MemoryRegion array
+    Stack = 5,     // The kernel stack
+}
+
+// Most ranges a BootInfo lists
+pub const MAX_RESERVED: usize = 16;
+
+// Physical memory the kernel must never hand out, whatever the memory map says
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReservedRange {
+    pub start: u64, // Physical address, page aligned
+    pub len: u64,   // Bytes, a multiple of PAGE_SIZE
+    pub what: Reservation,
+}
+
+impl ReservedRange {
+    pub const EMPTY: Self = Self {
+        start: 0,
+        len: 0,
+        what: Reservation::None,
+    };
+
+    pub fn new(start: u64, pages: u64, what: Reservation) -> Self {
+        Self {
+            start,
+            len: pages * PAGE_SIZE,
+            what,
+        }
+    }
+}
+
+// Sort `regions` by address and merge neighbours of the same kind, in place;
+// the result is the returned number of regions at the front. Empty regions are
+// dropped; overlapping ones (which firmware should not report) are kept as they are.
+pub fn normalize(regions: &mut [MemoryRegion]) -> usize {
+    regions.sort_unstable_by_key(|region| region.start);
+    let mut len: usize = 0;
+    for index in 0..regions.len() {
+        let region = regions[index];
+        if region.len == 0 {
+            continue;
+        }
+        match len.checked_sub(1).map(|last| &mut regions[last]) {
+            Some(last) if last.kind == region.kind && last.end() == region.start => {
+                last.len += region.len;
+            }
+            _ => {
+                regions[len] = region;
+                len += 1;
+            }
+        }
+    }
+    len
+}
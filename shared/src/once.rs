@@ -0,0 +1,119 @@
+// One-time initialization
+// `Once<T>` is a cell written exactly once, by whoever calls `call_once` first;
+// the kernel fills its descriptor tables through it from the init functions, so
+// the order in which they come to life is the order of calls in `_start`.
+// `LazyInit<T>` pairs a Once with its initializer and runs it on first access, for
+// the few statics that may be needed before anyone could initialize them (the
+// serial console, used by panics from the very first instruction).
+//
+// A second caller arriving while the initializer runs spins until it is done; an
+// initializer that reaches its own cell again panics instead of deadlocking.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const UNINIT: u8 = 0;
+const RUNNING: u8 = 1;
+const DONE: u8 = 2;
+
+pub struct Once<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// The value is shared once written, and written by a single caller
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(UNINIT),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    // Run `init` if the cell is empty, return the value either way
+    pub fn call_once(&self, init: impl FnOnce() -> T) -> &T {
+        match self
+            .state
+            .compare_exchange(UNINIT, RUNNING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                unsafe { (*self.value.get()).write(init()) };
+                self.state.store(DONE, Ordering::Release);
+            }
+            Err(_) => self.wait(),
+        }
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+
+    // None until `call_once` has finished
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == DONE {
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.state.load(Ordering::Acquire) == DONE
+    }
+
+    fn wait(&self) {
+        // Without threads a RUNNING cell can only be our own initializer again
+        // (or an interrupt handler that preempted it), which would spin forever
+        if self.state.load(Ordering::Acquire) == RUNNING {
+            panic!("Once: initializer reentered");
+        }
+        while self.state.load(Ordering::Acquire) != DONE {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl<T> Default for Once<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == DONE {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+pub struct LazyInit<T, F = fn() -> T> {
+    once: Once<T>,
+    init: F,
+}
+
+unsafe impl<T: Send + Sync, F: Sync> Sync for LazyInit<T, F> {}
+
+impl<T, F: Fn() -> T> LazyInit<T, F> {
+    pub const fn new(init: F) -> Self {
+        Self {
+            once: Once::new(),
+            init,
+        }
+    }
+
+    // Initialize now rather than on first access
+    pub fn force(&self) -> &T {
+        self.once.call_once(&self.init)
+    }
+}
+
+impl<T, F: Fn() -> T> Deref for LazyInit<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.force()
+    }
+}
@@ -0,0 +1,29 @@
+// Paging Depth
+// Firmware on a CPU with LA57 may start us with 5-level paging on, and CR4.LA57
+// cannot be changed without leaving long mode. The page tables stay 4-level all
+// the same: a PML5 is put above the PML4 with its first and last entries both
+// pointing at it, so every address canonical in 48 bits translates exactly as
+// under 4-level paging (the lower half through PML5[0], the higher half through
+// PML5[511]). CR3 then holds the PML5, and the PML4 is the one behind entry 0.
+
+use x86_64::PhysAddr;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+
+// Whether 5-level paging is on
+pub fn five_level() -> bool {
+    Cr4::read().contains(Cr4Flags::L5_PAGING)
+}
+
+// Paging depth, as passed in BootInfo
+pub fn levels() -> u64 {
+    if five_level() { 5 } else { 4 }
+}
+
+// Make `pml5` the root above the PML4 at `pml4`
+pub fn wrap_pml4(pml5: &mut PageTable, pml4: PhysAddr) {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    pml5.zero();
+    pml5[0].set_addr(pml4, flags);
+    pml5[511].set_addr(pml4, flags);
+}
@@ -1,28 +1,320 @@
 // Import fmt for formatting strings
 use core::fmt;
-// Import lazy_static to initialize static variables lazily
-use lazy_static::lazy_static;
-// Import Mutex for thread-safe access to the serial port
-use spin::Mutex;
-// Import SerialPort driver
-use uart_16550::SerialPort;
-// Import interrupt instructions
-use x86_64::instructions::interrupts;
-
-// Define a lazy static wrapper for the serial port
-// This ensures the serial port is initialized only when first accessed
-lazy_static! {
-    // The main serial port interface, protected by a Mutex (spinlock)
-    // 0x3F8 is the standard I/O port address for COM1
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        // Create a new SerialPort instance for COM1
-        // unsafe is needed because we are constructing a raw port address
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        // Initialize the serial port
-        serial_port.init();
-        // Wrap it in a Mutex so it can be safely shared across the kernel
-        Mutex::new(serial_port)
+// One-time initialization of the console port
+use crate::once::LazyInit;
+// Import IrqMutex for access to the serial port from normal and interrupt code
+use crate::sync::IrqMutex;
+// Import Port for the UART registers
+use x86_64::instructions::port::Port;
+
+// ============================================================================
+// 1. PORTS & CONFIGURATION
+// ============================================================================
+
+// The four legacy PC serial ports (8250/16550 UARTs)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Com {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl Com {
+    pub const ALL: [Com; 4] = [Com::Com1, Com::Com2, Com::Com3, Com::Com4];
+
+    // Standard I/O base address
+    pub fn base(self) -> u16 {
+        match self {
+            Com::Com1 => 0x3F8,
+            Com::Com2 => 0x2F8,
+            Com::Com3 => 0x3E8,
+            Com::Com4 => 0x2E8,
+        }
+    }
+
+    // Index in the Linux naming (COM1 = ttyS0)
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn from_index(index: usize) -> Option<Com> {
+        Com::ALL.get(index).copied()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+// Line settings of a port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialConfig {
+    pub baud: u32,
+    pub data_bits: u8, // 5 to 8
+    pub parity: Parity,
+    pub stop_bits: u8, // 1 or 2
+}
+
+impl SerialConfig {
+    // 38400 8N1, what the kernel always used
+    pub const DEFAULT: SerialConfig = SerialConfig {
+        baud: 38400,
+        data_bits: 8,
+        parity: Parity::None,
+        stop_bits: 1,
+    };
+
+    // Parse Linux console= style options: "<baud>[<parity>[<bits>]]",
+    // e.g. "115200", "115200n8", "9600e7"
+    pub fn parse(options: &str) -> Option<SerialConfig> {
+        let digits = options
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(options.len());
+        let (baud, rest) = options.split_at(digits);
+        let mut config = SerialConfig::DEFAULT;
+        config.baud = baud.parse().ok()?;
+
+        let mut rest = rest.chars();
+        if let Some(parity) = rest.next() {
+            config.parity = match parity {
+                'n' => Parity::None,
+                'o' => Parity::Odd,
+                'e' => Parity::Even,
+                _ => return None,
+            };
+        }
+        if let Some(bits) = rest.next() {
+            config.data_bits = bits.to_digit(10)? as u8;
+        }
+        (rest.next().is_none() && config.is_valid()).then_some(config)
+    }
+
+    // The baud rate must be reachable with the 115200 / divisor clock
+    pub fn is_valid(&self) -> bool {
+        self.baud != 0
+            && UART_CLOCK_DIVISOR_BASE.is_multiple_of(self.baud)
+            && (5..=8).contains(&self.data_bits)
+            && (1..=2).contains(&self.stop_bits)
+    }
+}
+
+// Parse a console name with optional settings: "ttyS1", "ttyS1,115200n8"
+pub fn parse_console(spec: &str) -> Option<(Com, SerialConfig)> {
+    let (name, options) = match spec.split_once(',') {
+        Some((name, options)) => (name, Some(options)),
+        None => (spec, None),
+    };
+    let index = name.strip_prefix("ttyS")?.parse().ok()?;
+    let com = Com::from_index(index)?;
+    let config = match options {
+        Some(options) => SerialConfig::parse(options)?,
+        None => SerialConfig::DEFAULT,
     };
+    Some((com, config))
+}
+
+// ============================================================================
+// 2. UART DRIVER
+// ============================================================================
+
+// Register offsets from the base port
+const REG_DATA: u16 = 0; // RBR / THR, divisor low byte with DLAB
+const REG_INT_ENABLE: u16 = 1; // Divisor high byte with DLAB
+const REG_FIFO_CTRL: u16 = 2;
+const REG_LINE_CTRL: u16 = 3;
+const REG_MODEM_CTRL: u16 = 4;
+const REG_LINE_STATUS: u16 = 5;
+const REG_SCRATCH: u16 = 7;
+
+const LCR_DLAB: u8 = 0x80;
+const LCR_STOP_2: u8 = 0x04;
+const LCR_PARITY_ODD: u8 = 0x08;
+const LCR_PARITY_EVEN: u8 = 0x18;
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THR_EMPTY: u8 = 0x20;
+
+// The divisor latch divides 115200
+const UART_CLOCK_DIVISOR_BASE: u32 = 115200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    NotPresent,
+    InvalidConfig,
+}
+
+pub struct SerialPort {
+    com: Com,
+    // Writes to a missing port are dropped instead of polling floating registers
+    present: bool,
+    // Line settings of the last successful `init`
+    config: SerialConfig,
+}
+
+impl SerialPort {
+    // An uninitialized handle, use `init` before sending
+    pub const fn new(com: Com) -> Self {
+        Self {
+            com,
+            present: false,
+            config: SerialConfig::DEFAULT,
+        }
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.com.base() + reg).read() }
+    }
+
+    fn write(&self, reg: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.com.base() + reg).write(value) }
+    }
+
+    // Detect a UART through its scratch register, without disturbing the line
+    // (a missing port reads back 0xFF)
+    pub fn probe(com: Com) -> bool {
+        let port = SerialPort::new(com);
+        let saved = port.read(REG_SCRATCH);
+        let present = [0x5A, 0xA5].iter().all(|&value| {
+            port.write(REG_SCRATCH, value);
+            port.read(REG_SCRATCH) == value
+        });
+        port.write(REG_SCRATCH, saved);
+        present
+    }
+
+    // Probe and program the line settings
+    pub fn init(&mut self, config: SerialConfig) -> Result<(), SerialError> {
+        if !config.is_valid() {
+            return Err(SerialError::InvalidConfig);
+        }
+        self.present = SerialPort::probe(self.com);
+        if !self.present {
+            return Err(SerialError::NotPresent);
+        }
+
+        let divisor = (UART_CLOCK_DIVISOR_BASE / config.baud) as u16;
+        let mut line = config.data_bits - 5;
+        if config.stop_bits == 2 {
+            line |= LCR_STOP_2;
+        }
+        line |= match config.parity {
+            Parity::None => 0,
+            Parity::Odd => LCR_PARITY_ODD,
+            Parity::Even => LCR_PARITY_EVEN,
+        };
+
+        // Interrupts off while the divisor is changed
+        self.write(REG_INT_ENABLE, 0x00);
+        self.write(REG_LINE_CTRL, LCR_DLAB);
+        self.write(REG_DATA, divisor as u8);
+        self.write(REG_INT_ENABLE, (divisor >> 8) as u8);
+        self.write(REG_LINE_CTRL, line);
+        // Enable and clear the FIFOs, 14 byte threshold
+        self.write(REG_FIFO_CTRL, 0xC7);
+        // DTR, RTS and OUT2 (the IRQ line)
+        self.write(REG_MODEM_CTRL, 0x0B);
+        // Received data available interrupt
+        self.write(REG_INT_ENABLE, 0x01);
+        self.config = config;
+        Ok(())
+    }
+
+    // Program the line settings again, after the UART lost them (suspend-to-RAM)
+    pub fn reinit(&mut self) -> Result<(), SerialError> {
+        self.init(self.config)
+    }
+
+    pub fn com(&self) -> Com {
+        self.com
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    // Send a byte as is
+    pub fn send_raw(&mut self, byte: u8) {
+        if !self.present {
+            return;
+        }
+        while self.read(REG_LINE_STATUS) & LSR_THR_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write(REG_DATA, byte);
+    }
+
+    // Send a byte for a terminal: "\n" becomes "\r\n", backspace/delete erase
+    pub fn send(&mut self, byte: u8) {
+        match byte {
+            0x08 | 0x7F => {
+                self.send_raw(0x08);
+                self.send_raw(b' ');
+                self.send_raw(0x08);
+            }
+            b'\n' => {
+                self.send_raw(b'\r');
+                self.send_raw(b'\n');
+            }
+            byte => self.send_raw(byte),
+        }
+    }
+
+    // A received byte, if one is waiting
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if self.present && self.read(REG_LINE_STATUS) & LSR_DATA_READY != 0 {
+            Some(self.read(REG_DATA))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+// ============================================================================
+// 3. CONSOLE PORT
+// ============================================================================
+
+// The serial console, protected by an IrqMutex (spinlock): COM1 if present,
+// otherwise the first UART found, until `select_console` picks another one.
+// Kernel log output goes here. Probed on first use rather than from an init
+// function, since a panic may need it before anything else has run.
+pub static SERIAL1: LazyInit<IrqMutex<SerialPort>> = LazyInit::new(open_console);
+
+fn open_console() -> IrqMutex<SerialPort> {
+    let com = Com::ALL
+        .into_iter()
+        .find(|&com| SerialPort::probe(com))
+        .unwrap_or(Com::Com1);
+    let mut serial_port = SerialPort::new(com);
+    // No UART at all: output is dropped
+    let _ = serial_port.init(SerialConfig::DEFAULT);
+    // Wrap it in an IrqMutex so it can be safely shared across the kernel
+    IrqMutex::new(serial_port)
+}
+
+// Move the serial console (and the kernel log) to another port
+// The current console is kept if the port is missing or the settings are invalid
+pub fn select_console(com: Com, config: SerialConfig) -> Result<(), SerialError> {
+    let mut port = SerialPort::new(com);
+    port.init(config)?;
+    *SERIAL1.lock() = port;
+    Ok(())
+}
+
+// Ports with a UART, probed on every call
+pub fn present_ports() -> impl Iterator<Item = Com> {
+    Com::ALL.into_iter().filter(|&com| SerialPort::probe(com))
 }
 
 // Hidden function used by the printing macros
@@ -32,42 +324,35 @@ pub fn _print(args: ::core::fmt::Arguments) {
     // Import the Write trait to use write_fmt
     use core::fmt::Write;
 
-    // Disable interrupts while printing
-    // This is CRITICAL to prevent deadlocks:
-    // If an interrupt occurs while we hold the lock, and the interrupt handler
-    // tries to print, it would try to acquire the same lock, causing a deadlock.
-    interrupts::without_interrupts(|| {
-        // Acquire the lock on the serial port
-        let mut serial = SERIAL1.lock();
+    // Acquire the lock on the serial port
+    // The IrqMutex keeps interrupts off meanwhile, so a handler that prints cannot
+    // deadlock on a lock held by the code it interrupted
+    let mut serial = SERIAL1.lock();
 
-        // Write the formatted string to the serial port
-        serial.write_fmt(args).expect("Printing to serial failed");
-    });
+    // Write the formatted string to the serial port
+    serial.write_fmt(args).expect("Printing to serial failed");
 }
 
 // Special print function for panics
 // It tries to force-unlock the serial port if it's locked, to ensure the message gets out
 pub fn print_panic(args: fmt::Arguments) {
     use core::fmt::Write;
-    // Disable interrupts to ensure atomicity
-    interrupts::without_interrupts(|| {
-        // Try to lock normally first
-        let mut port = match SERIAL1.try_lock() {
-            Some(lock) => lock,
-            None => unsafe {
-                // If locked, force unlock it!
-                // This is unsafe but necessary during a panic to ensure the message is seen.
-                // The previous owner of the lock is dead/panicking anyway.
-                SERIAL1.force_unlock();
-                SERIAL1.lock()
-            },
-        };
+    // Try to lock normally first
+    let mut port = match SERIAL1.try_lock() {
+        Some(lock) => lock,
+        None => unsafe {
+            // If locked, force unlock it!
+            // This is unsafe but necessary during a panic to ensure the message is seen.
+            // The previous owner of the lock is dead/panicking anyway.
+            SERIAL1.force_unlock();
+            SERIAL1.lock()
+        },
+    };
 
-        // Print a prefix indicating we forced it (optional, but good for debugging context)
-        let _ = port.write_fmt(format_args!("\n[FORCE_PRINT] "));
-        // Print the actual panic message
-        let _ = port.write_fmt(args);
-    });
+    // Print a prefix indicating we forced it (optional, but good for debugging context)
+    let _ = port.write_fmt(format_args!("\n[FORCE_PRINT] "));
+    // Print the actual panic message
+    let _ = port.write_fmt(args);
 }
 
 // Macro for printing to the serial port (like print!)
@@ -0,0 +1,148 @@
+// Ticket spinlock
+// Every locker takes a ticket and waits until it is served, so the lock is handed
+// out in arrival order and no waiter can be starved by a luckier one.
+//
+// With the "lock-debug" feature each lock remembers where its current holder took
+// it, and a locker that spins for more than SPIN_LIMIT_CYCLES TSC cycles panics,
+// naming that holder: a deadlock becomes a report instead of a silent hang.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+// About a few seconds at common TSC frequencies, far beyond any legitimate wait
+#[cfg(feature = "lock-debug")]
+const SPIN_LIMIT_CYCLES: u64 = 10_000_000_000;
+
+pub struct Spinlock<T> {
+    next_ticket: AtomicU32,
+    now_serving: AtomicU32,
+    // Call site of the holder's `lock` / `try_lock`
+    #[cfg(feature = "lock-debug")]
+    owner: core::sync::atomic::AtomicPtr<core::panic::Location<'static>>,
+    data: UnsafeCell<T>,
+}
+
+// The lock serializes all access to the data
+unsafe impl<T: Send> Sync for Spinlock<T> {}
+unsafe impl<T: Send> Send for Spinlock<T> {}
+
+pub struct SpinlockGuard<'a, T> {
+    lock: &'a Spinlock<T>,
+}
+
+impl<T> Spinlock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            #[cfg(feature = "lock-debug")]
+            owner: core::sync::atomic::AtomicPtr::new(core::ptr::null_mut()),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    #[cfg_attr(feature = "lock-debug", track_caller)]
+    pub fn lock(&self) -> SpinlockGuard<'_, T> {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "lock-debug")]
+        let start = unsafe { core::arch::x86_64::_rdtsc() };
+
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            #[cfg(feature = "lock-debug")]
+            if unsafe { core::arch::x86_64::_rdtsc() } - start > SPIN_LIMIT_CYCLES {
+                self.report_deadlock();
+            }
+            core::hint::spin_loop();
+        }
+
+        self.set_owner();
+        SpinlockGuard { lock: self }
+    }
+
+    // None if the lock is held or has waiters
+    #[cfg_attr(feature = "lock-debug", track_caller)]
+    pub fn try_lock(&self) -> Option<SpinlockGuard<'_, T>> {
+        let serving = self.now_serving.load(Ordering::Acquire);
+        self.next_ticket
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .ok()?;
+
+        self.set_owner();
+        Some(SpinlockGuard { lock: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+
+    /// # Safety
+    /// The current holder (and any waiter) must never touch the data again, e.g.
+    /// because it panicked. Only meant for panic paths.
+    pub unsafe fn force_unlock(&self) {
+        // Serving the next free ticket leaves the lock free, held or not
+        self.now_serving
+            .store(self.next_ticket.load(Ordering::Relaxed), Ordering::Release);
+    }
+
+    fn unlock(&self) {
+        #[cfg(feature = "lock-debug")]
+        self.owner.store(core::ptr::null_mut(), Ordering::Relaxed);
+        // Only the holder advances now_serving
+        let serving = self.now_serving.load(Ordering::Relaxed);
+        self.now_serving
+            .store(serving.wrapping_add(1), Ordering::Release);
+    }
+
+    #[cfg_attr(feature = "lock-debug", track_caller)]
+    fn set_owner(&self) {
+        #[cfg(feature = "lock-debug")]
+        self.owner.store(
+            core::panic::Location::caller() as *const _ as *mut _,
+            Ordering::Relaxed,
+        );
+    }
+
+    #[cfg(feature = "lock-debug")]
+    #[cold]
+    #[track_caller]
+    fn report_deadlock(&self) -> ! {
+        let owner = self.owner.load(Ordering::Relaxed);
+        match unsafe { owner.as_ref() } {
+            Some(owner) => panic!(
+                "spinlock: waited over {} cycles, held since {}",
+                SPIN_LIMIT_CYCLES, owner
+            ),
+            None => panic!(
+                "spinlock: waited over {} cycles, holder unknown",
+                SPIN_LIMIT_CYCLES
+            ),
+        }
+    }
+}
+
+impl<T> Deref for SpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
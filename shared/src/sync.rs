@@ -0,0 +1,93 @@
+// Interrupt-safe locking
+// A spinlock taken both by normal code and by interrupt handlers deadlocks if the
+// interrupt arrives while normal code holds it: the handler spins on a lock its own
+// CPU will never release. `IrqMutex` closes that hole by disabling interrupts for
+// as long as the lock is held, and restoring the previous IF state on unlock.
+//
+// Interrupt handlers run with IF cleared, so taking an IrqMutex there costs nothing
+// extra; they should still use `try_lock` for locks normal code may hold, since
+// the lock holder cannot run again until the handler returns.
+
+use crate::spinlock::{Spinlock, SpinlockGuard};
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use x86_64::instructions::interrupts;
+
+pub struct IrqMutex<T> {
+    inner: Spinlock<T>,
+}
+
+// Unlocks, then re-enables interrupts if they were enabled before locking
+// Guards of nested IrqMutexes must be dropped in reverse order, or interrupts come
+// back on while the inner lock is still held
+pub struct IrqMutexGuard<'a, T> {
+    guard: ManuallyDrop<SpinlockGuard<'a, T>>,
+    restore: bool,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: Spinlock::new(value),
+        }
+    }
+
+    #[cfg_attr(feature = "lock-debug", track_caller)]
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let restore = interrupts::are_enabled();
+        interrupts::disable();
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            restore,
+        }
+    }
+
+    // None if the lock is held, interrupts are left as they were
+    #[cfg_attr(feature = "lock-debug", track_caller)]
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let restore = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                restore,
+            }),
+            None => {
+                if restore {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// # Safety
+    /// The current holder must never touch the data again, e.g. because it
+    /// panicked. Only meant for panic paths.
+    pub unsafe fn force_unlock(&self) {
+        unsafe { self.inner.force_unlock() }
+    }
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.restore {
+            interrupts::enable();
+        }
+    }
+}
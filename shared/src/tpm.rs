@@ -0,0 +1,186 @@
+// TPM 2.0 Measured Boot
+// Before exiting boot services the bootloader hashes the kernel file and the
+// initramfs into PCR 9 through the firmware's TCG2 protocol, which extends every
+// active PCR bank and appends an event to the firmware's log. The log (TCG PC
+// Client "crypto agile" format) is copied for the kernel and handed over in
+// BootInfo: a quote of the PCRs can then be checked against it.
+//
+// Layout: one TCG_PCR_EVENT in the old SHA-1 format whose data is the "Spec ID
+// Event03" structure listing the digest size of each algorithm, then
+// TCG_PCR_EVENT2 records: PCR, type, a count of (algorithm, digest) pairs, and
+// the event data with its size.
+
+// PCR the bootloader measures into (the Linux EFI stub's for its initrd)
+pub const PCR_BOOT_FILES: u32 = 9;
+
+// EV_IPL: code or data loaded by the boot loader; the event data names the file
+pub const EV_IPL: u32 = 0x0000_000D;
+// EV_NO_ACTION: not extended into any PCR (the log header is one)
+pub const EV_NO_ACTION: u32 = 0x0000_0003;
+
+// TPM_ALG_ID values of the common banks
+pub const ALG_SHA1: u16 = 0x0004;
+pub const ALG_SHA256: u16 = 0x000B;
+pub const ALG_SHA384: u16 = 0x000C;
+pub const ALG_SHA512: u16 = 0x000D;
+
+const SPEC_ID_SIGNATURE: &[u8; 16] = b"Spec ID Event03\0";
+// Header event: pcr, type, SHA-1 digest, event size
+const HEADER_EVENT_FIXED: usize = 4 + 4 + 20 + 4;
+// Spec ID event: signature, platform class, version (4 bytes), algorithm count
+const SPEC_ID_FIXED: usize = 16 + 4 + 4 + 4;
+const MAX_ALGORITHMS: usize = 8;
+
+pub fn algorithm_name(alg: u16) -> &'static str {
+    match alg {
+        ALG_SHA1 => "sha1",
+        ALG_SHA256 => "sha256",
+        ALG_SHA384 => "sha384",
+        ALG_SHA512 => "sha512",
+        _ => "unknown",
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn digest_size(algorithms: &[(u16, u16)], alg: u16) -> Option<usize> {
+    algorithms
+        .iter()
+        .find(|(id, _)| *id == alg)
+        .map(|(_, size)| *size as usize)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// One measurement of the log
+#[derive(Debug, Clone, Copy)]
+pub struct Event<'a> {
+    pub pcr: u32,
+    pub event_type: u32,
+    digests: &'a [u8], // (algorithm, digest) pairs
+    pub data: &'a [u8],
+    algorithms: &'a [(u16, u16)],
+}
+
+impl<'a> Event<'a> {
+    // The event's digest for algorithm `alg`, if its bank is in the log
+    pub fn digest(&self, alg: u16) -> Option<&'a [u8]> {
+        let mut offset = 0;
+        while offset < self.digests.len() {
+            let id = u16_at(self.digests, offset)?;
+            let size = digest_size(self.algorithms, id)?;
+            let digest = self.digests.get(offset + 2..offset + 2 + size)?;
+            if id == alg {
+                return Some(digest);
+            }
+            offset += 2 + size;
+        }
+        None
+    }
+}
+
+// A crypto-agile event log
+pub struct EventLog<'a> {
+    data: &'a [u8],
+    first_event: usize,                       // Offset of the first TCG_PCR_EVENT2
+    algorithms: [(u16, u16); MAX_ALGORITHMS], // (algorithm, digest size)
+    algorithm_count: usize,
+}
+
+impl<'a> EventLog<'a> {
+    // The log in `data`, if it starts with a Spec ID event
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if u32_at(data, 4)? != EV_NO_ACTION {
+            return None;
+        }
+        let header_size = u32_at(data, HEADER_EVENT_FIXED - 4)? as usize;
+        let spec = data.get(HEADER_EVENT_FIXED..HEADER_EVENT_FIXED.checked_add(header_size)?)?;
+        if spec.len() < SPEC_ID_FIXED || &spec[..16] != SPEC_ID_SIGNATURE {
+            return None;
+        }
+        let count = u32_at(spec, SPEC_ID_FIXED - 4)? as usize;
+        if count == 0 || count > MAX_ALGORITHMS {
+            return None;
+        }
+        let mut algorithms = [(0, 0); MAX_ALGORITHMS];
+        for (index, slot) in algorithms.iter_mut().take(count).enumerate() {
+            let offset = SPEC_ID_FIXED + index * 4;
+            *slot = (u16_at(spec, offset)?, u16_at(spec, offset + 2)?);
+        }
+        Some(Self {
+            data,
+            first_event: HEADER_EVENT_FIXED + header_size,
+            algorithms,
+            algorithm_count: count,
+        })
+    }
+
+    // The (algorithm, digest size) pairs of the banks the log records
+    pub fn algorithms(&self) -> &[(u16, u16)] {
+        &self.algorithms[..self.algorithm_count]
+    }
+
+    // The event at `offset` and the offset of the next one
+    fn event_at(&self, offset: usize) -> Option<(Event<'_>, usize)> {
+        let pcr = u32_at(self.data, offset)?;
+        let event_type = u32_at(self.data, offset + 4)?;
+        let count = u32_at(self.data, offset + 8)?;
+        let digests_start = offset + 12;
+        let mut at = digests_start;
+        for _ in 0..count {
+            at += 2 + digest_size(self.algorithms(), u16_at(self.data, at)?)?;
+        }
+        let digests = self.data.get(digests_start..at)?;
+        let size = u32_at(self.data, at)? as usize;
+        let data = self.data.get(at + 4..(at + 4).checked_add(size)?)?;
+        let event = Event {
+            pcr,
+            event_type,
+            digests,
+            data,
+            algorithms: self.algorithms(),
+        };
+        Some((event, at + 4 + size))
+    }
+
+    // The events after the header, up to the first one that does not parse
+    pub fn events(&self) -> impl Iterator<Item = Event<'_>> {
+        let mut offset = self.first_event;
+        core::iter::from_fn(move || {
+            let (event, next) = self.event_at(offset)?;
+            offset = next;
+            Some(event)
+        })
+    }
+}
+
+/// Length of the firmware's log at `location` whose last record starts at
+/// `last_entry` (both from GetEventLog), reading no further than that record
+///
+/// # Safety
+/// Both pointers must come from the firmware's GetEventLog, the log readable
+pub unsafe fn log_len(location: *const u8, last_entry: *const u8) -> Option<usize> {
+    let read = |offset: usize, len: usize| unsafe {
+        core::slice::from_raw_parts(last_entry.add(offset), len)
+    };
+    let before = (last_entry as usize).checked_sub(location as usize)?;
+    if before == 0 {
+        // Only the header event
+        let size = u32_at(read(0, HEADER_EVENT_FIXED), HEADER_EVENT_FIXED - 4)? as usize;
+        return Some(HEADER_EVENT_FIXED + size);
+    }
+    let log = EventLog::parse(unsafe { core::slice::from_raw_parts(location, before) })?;
+    let count = u32_at(read(0, 12), 8)?;
+    let mut len = 12;
+    for _ in 0..count {
+        len += 2 + digest_size(log.algorithms(), u16_at(read(len, 2), 0)?)?;
+    }
+    len += 4 + u32_at(read(len, 4), 0)? as usize;
+    Some(before + len)
+}
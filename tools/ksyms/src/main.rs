@@ -0,0 +1,114 @@
+// ksyms
+// Fills the kernel's `.ksyms` section with its function symbols, in place, after
+// linking. See kernel/src/symbols.rs for the layout.
+//
+// Usage: ksyms <kernel ELF>
+
+use std::process::exit;
+use xmas_elf::ElfFile;
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::{Entry, Type};
+
+const KSYMS_MAGIC: [u8; 4] = *b"KSYM";
+const HEADER_LEN: usize = 8;
+const ENTRY_LEN: usize = 16;
+
+struct Symbol {
+    addr: u64,
+    size: u64,
+    name: String,
+}
+
+fn collect_symbols(elf: &ElfFile) -> Result<Vec<Symbol>, String> {
+    let symtab = elf
+        .find_section_by_name(".symtab")
+        .ok_or("no symbol table (is the kernel stripped?)")?;
+    let SectionData::SymbolTable64(entries) = symtab.get_data(elf)? else {
+        return Err("unexpected .symtab format".into());
+    };
+
+    let mut symbols = Vec::new();
+    for entry in entries {
+        if entry.get_type() != Ok(Type::Func) || entry.size() == 0 || entry.value() == 0 {
+            continue;
+        }
+        let name = entry.get_name(elf)?;
+        symbols.push(Symbol {
+            addr: entry.value(),
+            size: entry.size(),
+            // "{:#}" drops the hash suffix
+            name: format!("{:#}", rustc_demangle::demangle(name)),
+        });
+    }
+
+    symbols.sort_by_key(|symbol| symbol.addr);
+    // Identical code folding gives several names to one address, keep the first
+    symbols.dedup_by_key(|symbol| symbol.addr);
+    Ok(symbols)
+}
+
+fn build_table(symbols: &[Symbol]) -> Vec<u8> {
+    let mut header = Vec::new();
+    let mut names = Vec::new();
+
+    header.extend_from_slice(&KSYMS_MAGIC);
+    header.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    for symbol in symbols {
+        header.extend_from_slice(&symbol.addr.to_le_bytes());
+        header.extend_from_slice(&(symbol.size.min(u32::MAX as u64) as u32).to_le_bytes());
+        header.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        names.extend_from_slice(symbol.name.as_bytes());
+        names.push(0);
+    }
+    debug_assert_eq!(header.len(), HEADER_LEN + symbols.len() * ENTRY_LEN);
+
+    header.extend_from_slice(&names);
+    header
+}
+
+fn run(path: &str) -> Result<(), String> {
+    let mut image = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+
+    let (table, offset, capacity) = {
+        let elf = ElfFile::new(&image)?;
+        let section = elf
+            .find_section_by_name(".ksyms")
+            .ok_or("no .ksyms section")?;
+        let symbols = collect_symbols(&elf)?;
+        let table = build_table(&symbols);
+        println!(
+            "[*] ksyms: {} symbols, {} / {} bytes",
+            symbols.len(),
+            table.len(),
+            section.size()
+        );
+        (table, section.offset() as usize, section.size() as usize)
+    };
+
+    if table.len() > capacity {
+        return Err(format!(
+            "symbol table needs {} bytes, .ksyms has {} (raise KSYMS_SIZE)",
+            table.len(),
+            capacity
+        ));
+    }
+
+    // Clear it first so patching an already patched kernel leaves no stale names
+    let section = &mut image[offset..offset + capacity];
+    section.fill(0);
+    section[..table.len()].copy_from_slice(&table);
+
+    std::fs::write(path, &image).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 2 {
+        eprintln!("usage: {} <kernel ELF>", args[0]);
+        exit(2);
+    }
+    if let Err(e) = run(&args[1]) {
+        eprintln!("[!] ksyms: {}", e);
+        exit(1);
+    }
+}
@@ -0,0 +1,75 @@
+// Display EDID
+// The firmware exposes the monitor's EDID on the GOP handle: EDID Active when a
+// platform override applies, else EDID Discovered as read from the display. The
+// blob is copied into LOADER_DATA pages, since the firmware's copy lives in boot
+// services memory, and handed to the kernel in BootInfo (see shared::edid).
+
+use core::slice;
+use log::{info, warn};
+use shared::edid;
+use uefi::Handle;
+use uefi::boot::{self, AllocateType, MemoryType, OpenProtocolAttributes, OpenProtocolParams};
+use uefi::proto::{ProtocolPointer, unsafe_protocol};
+
+// EFI_EDID_ACTIVE_PROTOCOL and EFI_EDID_DISCOVERED_PROTOCOL share a layout
+#[repr(C)]
+#[unsafe_protocol("bd8c1056-9f36-44ec-92a8-a6337f817986")]
+struct EdidActive {
+    size: u32,
+    edid: *const u8,
+}
+
+#[repr(C)]
+#[unsafe_protocol("1c0c34f6-d380-41fa-a049-8ad06c1a66aa")]
+struct EdidDiscovered {
+    size: u32,
+    edid: *const u8,
+}
+
+// The firmware's blob for protocol P on `handle`, if it has a non-empty one
+fn blob<P: ProtocolPointer + ?Sized>(
+    handle: Handle,
+    fields: impl Fn(&P) -> (u32, *const u8),
+) -> Option<&'static [u8]> {
+    let params = OpenProtocolParams {
+        handle,
+        agent: boot::image_handle(),
+        controller: None,
+    };
+    // Read-only access that leaves the display drivers alone
+    let protocol =
+        unsafe { boot::open_protocol::<P>(params, OpenProtocolAttributes::GetProtocol) }.ok()?;
+    let (size, data) = fields(&protocol);
+    if size == 0 || data.is_null() {
+        return None;
+    }
+    Some(unsafe { slice::from_raw_parts(data, size as usize) })
+}
+
+// A copy of the display's EDID, or None if the firmware has no valid one
+pub fn read(gop_handle: Handle) -> Option<&'static [u8]> {
+    let source = blob::<EdidActive>(gop_handle, |p| (p.size, p.edid))
+        .or_else(|| blob::<EdidDiscovered>(gop_handle, |p| (p.size, p.edid)))?;
+    if !edid::is_valid(source) {
+        warn!("EDID: the firmware's blob is not valid, ignoring it");
+        return None;
+    }
+
+    let pages = source.len().div_ceil(0x1000);
+    let addr = boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages).ok()?;
+    let copy = unsafe { slice::from_raw_parts_mut(addr.as_ptr(), source.len()) };
+    copy.copy_from_slice(source);
+
+    match edid::preferred_timing(copy) {
+        Some(timing) => info!(
+            "EDID: {} bytes, preferred mode {}x{} at {}.{:03} Hz",
+            copy.len(),
+            timing.width,
+            timing.height,
+            timing.refresh_mhz() / 1000,
+            timing.refresh_mhz() % 1000
+        ),
+        None => info!("EDID: {} bytes, no preferred mode", copy.len()),
+    }
+    Some(copy)
+}
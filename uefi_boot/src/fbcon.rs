@@ -0,0 +1,161 @@
+// Fatal Error Screen
+// A panic in the bootloader (a missing file, a kernel that does not parse) is
+// painted on the GOP framebuffer as well as sent to the serial port, so a machine
+// without a serial console still shows why it stopped. The framebuffer is the one
+// recorded when the GOP was set up, or, for an earlier panic, the GOP's current
+// mode if boot services are still running. Text uses the built-in Spleen 8x16.
+
+use core::fmt::{self, Write};
+use shared::framebuffer::PixelFormat;
+use shared::once::Once;
+use spleen_font::{FONT_8X16, PSF2Font};
+use uefi::boot;
+use uefi::proto::console::gop::{GraphicsOutput, PixelFormat as UefiPixelFormat};
+
+const GLYPH_WIDTH: usize = 8;
+const GLYPH_HEIGHT: usize = 16;
+const MARGIN: usize = 16;
+
+// (red, green, blue)
+const BACKGROUND: (u8, u8, u8) = (0x80, 0x00, 0x00);
+const FOREGROUND: (u8, u8, u8) = (0xFF, 0xFF, 0xFF);
+
+#[derive(Clone, Copy)]
+pub struct Screen {
+    pub base: u64, // Physical address, identity mapped while the bootloader runs
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize, // Pixels per line
+    pub format: PixelFormat,
+}
+
+static SCREEN: Once<Screen> = Once::new();
+
+// The framebuffer to paint on should the bootloader panic from now on
+pub fn init(screen: Screen) {
+    SCREEN.call_once(|| screen);
+}
+
+// Before `init`: the GOP's framebuffer, as long as boot services are there
+fn current_screen() -> Option<Screen> {
+    if let Some(screen) = SCREEN.get() {
+        return Some(*screen);
+    }
+    let table = uefi::table::system_table_raw()?;
+    if unsafe { table.as_ref() }.boot_services.is_null() {
+        return None;
+    }
+    let handle = boot::get_handle_for_protocol::<GraphicsOutput>().ok()?;
+    let mut gop = boot::open_protocol_exclusive::<GraphicsOutput>(handle).ok()?;
+    let mode = gop.current_mode_info();
+    let format = match mode.pixel_format() {
+        UefiPixelFormat::Rgb => PixelFormat::RGB,
+        UefiPixelFormat::Bgr => PixelFormat::BGR,
+        _ => return None,
+    };
+    Some(Screen {
+        base: gop.frame_buffer().as_mut_ptr() as u64,
+        width: mode.resolution().0,
+        height: mode.resolution().1,
+        stride: mode.stride(),
+        format,
+    })
+}
+
+struct Painter {
+    screen: Screen,
+    font: PSF2Font<'static>,
+    x: usize,
+    y: usize,
+}
+
+impl Painter {
+    fn pixel(&self, (r, g, b): (u8, u8, u8)) -> u32 {
+        match self.screen.format {
+            PixelFormat::RGB => u32::from_le_bytes([r, g, b, 0]),
+            _ => u32::from_le_bytes([b, g, r, 0]),
+        }
+    }
+
+    fn put(&self, x: usize, y: usize, color: u32) {
+        if x < self.screen.width && y < self.screen.height {
+            let offset = (y * self.screen.stride + x) * 4;
+            unsafe { ((self.screen.base as usize + offset) as *mut u32).write_volatile(color) };
+        }
+    }
+
+    fn clear(&self) {
+        let background = self.pixel(BACKGROUND);
+        for y in 0..self.screen.height {
+            for x in 0..self.screen.width {
+                self.put(x, y, background);
+            }
+        }
+    }
+
+    fn newline(&mut self) {
+        self.x = MARGIN;
+        self.y += GLYPH_HEIGHT;
+    }
+
+    fn draw_char(&mut self, c: char) {
+        if c == '\n' {
+            self.newline();
+            return;
+        }
+        if self.x + GLYPH_WIDTH > self.screen.width - MARGIN {
+            self.newline();
+        }
+        if self.y + GLYPH_HEIGHT > self.screen.height {
+            return;
+        }
+        let (foreground, background) = (self.pixel(FOREGROUND), self.pixel(BACKGROUND));
+        let mut utf8 = [0; 4];
+        let glyph = self
+            .font
+            .glyph_for_utf8(c.encode_utf8(&mut utf8).as_bytes())
+            .or_else(|| self.font.glyph_for_utf8(b"?"));
+        if let Some(glyph) = glyph {
+            for (row_y, row) in glyph.enumerate() {
+                for (col_x, on) in row.enumerate() {
+                    let color = if on { foreground } else { background };
+                    self.put(self.x + col_x, self.y + row_y, color);
+                }
+            }
+        }
+        self.x += GLYPH_WIDTH;
+    }
+}
+
+impl Write for Painter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.chars().for_each(|c| self.draw_char(c));
+        Ok(())
+    }
+}
+
+// Paint the panic on the screen, if there is one to paint on
+pub fn show_panic(info: &core::panic::PanicInfo) {
+    let Some(screen) = current_screen() else {
+        return;
+    };
+    if screen.width < 2 * MARGIN + GLYPH_WIDTH {
+        return;
+    }
+    let Ok(font) = PSF2Font::new(FONT_8X16) else {
+        return;
+    };
+    let mut painter = Painter {
+        screen,
+        font,
+        x: MARGIN,
+        y: MARGIN,
+    };
+    painter.clear();
+    let _ = writeln!(painter, "BOOTLOADER ERROR\n");
+    let _ = writeln!(painter, "{}", info.message());
+    if let Some(location) = info.location() {
+        let _ = writeln!(painter, "\nat {}:{}", location.file(), location.line());
+    }
+    let _ = write!(painter, "\nThe machine is halted: power it off to retry.");
+}
@@ -1,23 +1,51 @@
 #![no_std] // No Standard Library
 #![no_main] // No Main Entry Point
 
+mod edid;
+mod fbcon;
+mod pxe;
+mod tpm;
+mod trampoline;
+
 // Imports
 use core::slice;
-use log::info;
+use core::time::Duration;
+use log::{error, info, warn};
+use shared::cmdline::Cmdline;
+use shared::cpu;
+use shared::fallback;
 use shared::framebuffer::{FrameBufferInfo, PixelFormat};
+use shared::hhdm;
+use shared::kaslr;
+use shared::lz4;
+use shared::memory::{self, MemoryKind, MemoryRegion, Reservation, ReservedRange};
+use shared::paging;
 use shared::panic::panic_handler_impl;
-use uefi::boot::{AllocateType, MemoryType};
-use uefi::mem::memory_map::MemoryMap;
+use shared::sha256;
+use shared::{BootModule, MAX_BOOT_MODULES};
+use uefi::boot::{AllocateType, MemoryType, SearchType};
+use uefi::mem::memory_map::{MemoryAttribute, MemoryDescriptor, MemoryMap};
 use uefi::prelude::*;
 use uefi::proto::console::gop::{GraphicsOutput, PixelFormat as UefiPixelFormat};
-use uefi::proto::media::file::{File, FileAttribute, FileInfo, FileMode};
+use uefi::proto::media::file::{
+    Directory, File, FileAttribute, FileInfo, FileMode, FileSystemVolumeLabel,
+};
+use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::proto::rng::Rng;
+use uefi::runtime::{self, VariableAttributes, VariableVendor};
+use uefi::table::cfg::ConfigTableEntry;
+use x86_64::instructions::random::RdRand;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
 use x86_64::structures::paging::{
-    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size2MiB,
-    Size4KiB,
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    Translate,
 };
 use x86_64::{PhysAddr, VirtAddr};
 use xmas_elf::ElfFile;
-use xmas_elf::program::Type;
+use xmas_elf::header::Type as ElfType;
+use xmas_elf::program::{Flags, Type};
 
 // A simple Bump Allocator for the Bootloader
 // Since the bootloader is short-lived, we don't need a complex allocator with deallocation.
@@ -53,6 +81,226 @@ unsafe impl FrameAllocator<Size4KiB> for BumpAllocator {
     }
 }
 
+// Read a whole file from the boot volume into LOADER_DATA pages
+// Returns None if the file does not exist
+fn load_optional_file(root: &mut Directory, name: &uefi::CStr16) -> Option<&'static [u8]> {
+    let mut file = root
+        .open(name, FileMode::Read, FileAttribute::empty())
+        .ok()?
+        .into_regular_file()?;
+
+    let mut info_buf = [0u8; 128];
+    let file_size = file
+        .get_info::<FileInfo>(&mut info_buf)
+        .expect("Failed to get file info")
+        .file_size() as usize;
+
+    if file_size == 0 {
+        return None;
+    }
+
+    // LOADER_DATA pages are kept reserved by the kernel PMM
+    let pages = file_size.div_ceil(0x1000);
+    let addr = boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)
+        .expect("Failed to allocate pages for file");
+
+    let buffer = unsafe { slice::from_raw_parts_mut(addr.as_ptr(), pages * 0x1000) };
+    let len = file.read(buffer).expect("Failed to read file");
+
+    Some(&buffer[..len])
+}
+
+// A path on the boot volume from a command line value, with '/' separators
+// turned into the '\\' of UEFI; None if it does not fit in `buf`
+fn volume_path<'a>(path: &str, buf: &'a mut [u16; 128]) -> Option<&'a uefi::CStr16> {
+    let mut len = 0;
+    for unit in path.trim_start_matches('/').encode_utf16() {
+        if len + 1 >= buf.len() {
+            return None;
+        }
+        buf[len] = if unit == u16::from(b'/') {
+            u16::from(b'\\')
+        } else {
+            unit
+        };
+        len += 1;
+    }
+    buf[len] = 0;
+    uefi::CStr16::from_u16_with_nul(&buf[..=len]).ok()
+}
+
+// A volume named by a `kernel=` option: `fsN` is the N-th file system handle (as
+// numbered by the UEFI shell), anything else a volume label
+fn open_volume(name: &str) -> Option<Directory> {
+    let handles = boot::locate_handle_buffer(SearchType::from_proto::<SimpleFileSystem>()).ok()?;
+    let index = name
+        .strip_prefix("fs")
+        .and_then(|n| n.parse::<usize>().ok());
+    handles.iter().enumerate().find_map(|(i, &handle)| {
+        if index.is_some_and(|index| index != i) {
+            return None;
+        }
+        let mut root = boot::open_protocol_exclusive::<SimpleFileSystem>(handle)
+            .ok()?
+            .open_volume()
+            .ok()?;
+        let mut info_buf = [0u8; 256];
+        let found = index.is_some()
+            || root
+                .get_info::<FileSystemVolumeLabel>(&mut info_buf)
+                .is_ok_and(|info| {
+                    info.volume_label()
+                        .iter()
+                        .map(|&c| u16::from(c))
+                        .eq(name.encode_utf16())
+                });
+        found.then_some(root)
+    })
+}
+
+// Where the bootloader's files come from: the volume it was started from, or the
+// TFTP server of a network boot (see pxe.rs)
+enum Source {
+    Volume(Directory),
+    Network(pxe::Tftp),
+}
+
+impl Source {
+    // A whole file by its '/' separated path; None if it does not exist
+    fn load(&mut self, path: &str) -> Option<&'static [u8]> {
+        match self {
+            Source::Volume(root) => {
+                let mut path_buf = [0u16; 128];
+                volume_path(path, &mut path_buf).and_then(|name| load_optional_file(root, name))
+            }
+            Source::Network(tftp) => tftp.load(path),
+        }
+    }
+}
+
+// Expected SHA-256 of the kernel, built in with `KERNEL_SHA256=<hex> cargo uefi_boot`
+const BUILTIN_KERNEL_SHA256: Option<&str> = option_env!("KERNEL_SHA256");
+
+// How long a refusal to boot stays on screen before returning to the firmware
+const REFUSAL_DELAY: Duration = Duration::from_secs(10);
+
+// Page flags of a kernel segment from its ELF flags: W^X, code read-only
+fn segment_flags(elf_flags: Flags) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT;
+    if elf_flags.is_write() {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !elf_flags.is_execute() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+// Digest bytes as hex
+struct Hex<'a>(&'a [u8]);
+
+impl core::fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+// Compare the SHA-256 of the kernel file with the built-in hash, else the one
+// in `<name>.sha256` (sha256sum output) next to it. Without either the kernel
+// boots unverified; a mismatch or an unreadable hash stops the boot.
+fn verify_kernel(files: &mut Source, name: &str, kernel: &[u8]) -> Result<(), Status> {
+    let mut path_buf = [0u8; 128];
+    let path_len = (name.len() + ".sha256".len()).min(path_buf.len());
+    let name_len = path_len - ".sha256".len();
+    path_buf[..name_len].copy_from_slice(&name.as_bytes()[..name_len]);
+    path_buf[name_len..path_len].copy_from_slice(b".sha256");
+    let hash_file = core::str::from_utf8(&path_buf[..path_len]).unwrap_or("kernel.sha256");
+
+    let (text, source) = match BUILTIN_KERNEL_SHA256 {
+        Some(hex) => (hex, "built-in hash"),
+        None => {
+            let Some(data) = files.load(hash_file) else {
+                warn!("No kernel hash: booting an unverified kernel");
+                return Ok(());
+            };
+            let text = core::str::from_utf8(data).unwrap_or("");
+            (text, hash_file)
+        }
+    };
+
+    let actual = sha256::digest(kernel);
+    match sha256::parse_hex(text) {
+        Some(expected) if expected == actual => {
+            info!("Kernel SHA-256 {} matches the {}", Hex(&actual), source);
+            Ok(())
+        }
+        expected => {
+            error!("KERNEL VERIFICATION FAILED, refusing to boot");
+            match expected {
+                Some(expected) => error!("  expected {} ({})", Hex(&expected), source),
+                None => error!("  {} is not a SHA-256 hash", source),
+            }
+            error!("  actual   {}", Hex(&actual));
+            boot::stall(REFUSAL_DELAY);
+            Err(Status::SECURITY_VIOLATION)
+        }
+    }
+}
+
+// A kernel file by name, with its contents and whether it is a trial boot of
+// kernel_new (see shared::fallback): kernel_new unless its last boot was never
+// confirmed, else kernel_good, else kernel. None if the source has none of them.
+fn choose_kernel(files: &mut Source) -> Option<(&'static str, &'static [u8], bool)> {
+    if let Some(data) = files.load(fallback::NEW_KERNEL) {
+        let mut name_buf = [0u16; 32];
+        let name = uefi::CStr16::from_str_with_buf(fallback::PENDING_VARIABLE, &mut name_buf)
+            .expect("Bad variable name");
+        let vendor = VariableVendor(uefi::Guid::from_bytes(fallback::VENDOR_GUID));
+        let hash = sha256::digest(data);
+
+        // The variable still names this kernel: it was tried and never came up
+        let mut pending = [0u8; sha256::DIGEST_LEN];
+        let failed = matches!(
+            runtime::get_variable(name, &vendor, &mut pending),
+            Ok((value, _)) if *value == hash
+        );
+        if !failed {
+            let attributes = VariableAttributes::NON_VOLATILE
+                | VariableAttributes::BOOTSERVICE_ACCESS
+                | VariableAttributes::RUNTIME_ACCESS;
+            if runtime::set_variable(name, &vendor, attributes, &hash).is_err() {
+                warn!("Cannot record the boot attempt: no fallback if kernel_new fails");
+                return Some((fallback::NEW_KERNEL, data, false));
+            }
+            info!("Trying kernel_new, kernel_good is booted if it does not come up");
+            return Some((fallback::NEW_KERNEL, data, true));
+        }
+
+        warn!("kernel_new never confirmed its last boot, falling back to kernel_good");
+        let pages = data.len().div_ceil(0x1000);
+        let _ = unsafe { boot::free_pages(core::ptr::NonNull::from(data).cast::<u8>(), pages) };
+    }
+    [fallback::GOOD_KERNEL, "kernel"]
+        .into_iter()
+        .find_map(|name| files.load(name).map(|data| (name, data, false)))
+}
+
+// Random bits for KASLR: the firmware's RNG protocol, else RDRAND, else the TSC
+fn random_u64() -> (u64, &'static str) {
+    if let Ok(handle) = boot::get_handle_for_protocol::<Rng>()
+        && let Ok(mut rng) = boot::open_protocol_exclusive::<Rng>(handle)
+    {
+        let mut bytes = [0u8; 8];
+        if rng.get_rng(None, &mut bytes).is_ok() {
+            return (u64::from_le_bytes(bytes), "EFI_RNG_PROTOCOL");
+        }
+    }
+    if let Some(random) = RdRand::new().and_then(RdRand::get_u64) {
+        return (random, "RDRAND");
+    }
+    (unsafe { core::arch::x86_64::_rdtsc() }, "TSC")
+}
+
 // UEFI Entry Point
 #[entry]
 fn main() -> Status {
@@ -60,57 +308,195 @@ fn main() -> Status {
     uefi::helpers::init().unwrap();
     info!("Hello from UEFI Bootloader!");
 
-    // Get the file system protocol to access files
-    let image_handle = boot::image_handle();
-    let mut fs =
-        boot::get_image_file_system(image_handle).expect("Failed to get image file system");
-    let mut root = fs.open_volume().expect("Failed to open root volume");
-
-    // Open the 'kernel' file
-    let mut kernel_file = root
-        .open(
-            uefi::cstr16!("kernel"),
-            FileMode::Read,
-            FileAttribute::empty(),
-        )
-        .expect("Failed to open 'kernel' file")
-        .into_regular_file()
-        .expect("Kernel file is not a regular file");
+    // Refuse a CPU the kernel cannot run on before loading anything
+    let cpu_features = cpu::detect();
+    info!("CPU features: {}", cpu::Names(cpu_features));
+    let missing = cpu::REQUIRED & !cpu_features;
+    if missing != 0 {
+        panic!(
+            "This CPU lacks {}, which the kernel requires",
+            cpu::Names(missing)
+        );
+    }
 
-    info!("Found kernel file");
+    // Files come from the boot volume; without a kernel there (or without a
+    // volume, when the bootloader itself came over the network) from TFTP
+    let volume = boot::get_image_file_system(boot::image_handle())
+        .ok()
+        .and_then(|mut fs| fs.open_volume().ok());
+    let mut files = volume.map_or_else(
+        || {
+            info!("No boot volume, trying a network boot");
+            Source::Network(pxe::Tftp::open().expect("No boot volume and no PXE"))
+        },
+        Source::Volume,
+    );
+    // Optional kernel command line (see shared::cmdline), read first: it may say
+    // where the kernel is
+    let mut cmdline = files.load("boot.cfg").or_else(|| files.load("cmdline.txt"));
+
+    // `kernel=[<volume>:]<path>` names the kernel file, on the boot source or on
+    // another volume; otherwise kernel_new, kernel_good or kernel (and TFTP when
+    // the boot volume has none of them)
+    let mut kernel_volume = None;
+    let kernel_option = cmdline
+        .map_or(Cmdline::EMPTY, Cmdline::from_bytes)
+        .get("kernel");
+    let (kernel_name, kernel_data, boot_trial) = match kernel_option {
+        Some(spec) => {
+            let (volume, path) = spec
+                .split_once(':')
+                .map_or((None, spec), |(volume, path)| (Some(volume), path));
+            if let Some(volume) = volume {
+                let root = open_volume(volume)
+                    .unwrap_or_else(|| panic!("kernel={}: no volume '{}'", spec, volume));
+                kernel_volume = Some(Source::Volume(root));
+            }
+            let data = kernel_volume
+                .as_mut()
+                .unwrap_or(&mut files)
+                .load(path)
+                .unwrap_or_else(|| panic!("kernel={}: file not found", spec));
+            (path, data, false)
+        }
+        None => match choose_kernel(&mut files) {
+            Some(kernel) => kernel,
+            None if matches!(files, Source::Volume(_)) => {
+                info!("No kernel file on the boot volume, trying a network boot");
+                files = Source::Network(pxe::Tftp::open().expect("No kernel file and no PXE"));
+                cmdline = files.load("boot.cfg").or_else(|| files.load("cmdline.txt"));
+                choose_kernel(&mut files).expect("No kernel file on the TFTP server")
+            }
+            None => panic!("No kernel file on the TFTP server"),
+        },
+    };
+    info!("Kernel file '{}': {} bytes", kernel_name, kernel_data.len());
 
-    // Get file size
-    let mut info_buf = [0u8; 128];
-    let file_info = kernel_file
-        .get_info::<FileInfo>(&mut info_buf)
-        .expect("Failed to get file info");
-    let file_size = file_info.file_size();
+    // Refuse a kernel that does not match its expected hash
+    let kernel_files = kernel_volume.as_mut().unwrap_or(&mut files);
+    if let Err(status) = verify_kernel(kernel_files, kernel_name, kernel_data) {
+        return status;
+    }
 
-    info!("Kernel file size: {} bytes", file_size);
+    // Measured boot: the kernel file as verified, before it is unpacked
+    let mut tpm = tpm::Tpm::open();
+    if let Some(tpm) = tpm.as_mut() {
+        tpm.measure(kernel_name, kernel_data);
+    }
 
-    // Allocate memory to read the kernel file
-    let pages_needed = (file_size as usize).div_ceil(0x1000);
+    // A compressed kernel (LZ4 frame, `lz4 kernel`) is unpacked before ELF parsing;
+    // the hash above is of the file as stored
+    let kernel_data = if lz4::is_frame(kernel_data) {
+        let size = lz4::decompressed_len(kernel_data).expect("Corrupt compressed kernel");
+        let pages = size.div_ceil(0x1000);
+        let addr = boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)
+            .expect("Failed to allocate pages for the decompressed kernel");
+        let out = unsafe { slice::from_raw_parts_mut(addr.as_ptr(), pages * 0x1000) };
+        let len = lz4::decompress(kernel_data, out).expect("Corrupt compressed kernel");
+        info!(
+            "Decompressed kernel: {} -> {} bytes",
+            kernel_data.len(),
+            len
+        );
+        // The compressed copy is not needed any more
+        let compressed = core::ptr::NonNull::from(kernel_data).cast::<u8>();
+        let _ = unsafe { boot::free_pages(compressed, kernel_data.len().div_ceil(0x1000)) };
+        &out[..len]
+    } else {
+        kernel_data
+    };
 
-    let file_buffer_addr = boot::allocate_pages(
-        AllocateType::AnyPages,
-        MemoryType::LOADER_DATA,
-        pages_needed,
-    )
-    .expect("Failed to allocate pages for kernel file");
+    if let Some(data) = cmdline {
+        info!(
+            "Kernel command line: {}",
+            Cmdline::from_bytes(data).as_str().trim()
+        );
+    }
 
-    let file_buffer =
-        unsafe { slice::from_raw_parts_mut(file_buffer_addr.as_ptr(), pages_needed * 0x1000) };
+    // Load the optional initramfs (ustar archive, or an ISO9660 image) from the same volume:
+    // the file named by `initrd=` on the command line, else the first of the default names
+    let options = cmdline.map_or(Cmdline::EMPTY, Cmdline::from_bytes);
+    let named = options.get("initrd").and_then(|path| {
+        let file = files.load(path);
+        if file.is_none() {
+            warn!("initrd={} not found, trying the default names", path);
+        }
+        file
+    });
+    let initramfs = named
+        .or_else(|| files.load("initramfs.tar"))
+        .or_else(|| files.load("rootfs.iso"))
+        .or_else(|| files.load("initrd"));
+    match initramfs {
+        Some(data) => info!(
+            "Loaded initramfs: {} bytes at {:#x}",
+            data.len(),
+            data.as_ptr() as u64
+        ),
+        None => info!("No initramfs.tar, rootfs.iso or initrd found"),
+    }
+    // The log goes to the kernel with both measurements in it
+    let tpm_event_log = tpm.and_then(|mut tpm| {
+        if let Some(data) = initramfs {
+            tpm.measure("initramfs", data);
+        }
+        tpm.event_log()
+    });
 
-    // Read kernel content into memory
-    let len = kernel_file
-        .read(file_buffer)
-        .expect("Failed to read kernel file");
+    // Optional PSF console font replacing the kernel's built-in one
+    let font = files.load("font.psf");
+    if let Some(data) = font {
+        info!("Loaded font.psf: {} bytes", data.len());
+    }
 
-    let kernel_data = &file_buffer[..len];
+    // Every file for the kernel as a module: the two above, then each
+    // `module=<path>` of the command line, named after the file
+    let mut modules = [BootModule::new("", &[]); MAX_BOOT_MODULES];
+    let mut module_count = 0;
+    for (name, data) in [("initramfs", initramfs), ("font", font)] {
+        if let Some(data) = data {
+            modules[module_count] = BootModule::new(name, data);
+            module_count += 1;
+        }
+    }
+    for path in options.values("module") {
+        if module_count == MAX_BOOT_MODULES {
+            warn!(
+                "Too many modules: module={} and the ones after it skipped",
+                path
+            );
+            break;
+        }
+        let Some(data) = files.load(path) else {
+            warn!("module={} not found", path);
+            continue;
+        };
+        let name = path.rsplit('/').next().unwrap_or(path);
+        info!("Loaded module {}: {} bytes", name, data.len());
+        modules[module_count] = BootModule::new(name, data);
+        module_count += 1;
+    }
 
     // Parse ELF Header
     let elf = ElfFile::new(kernel_data).expect("Failed to parse ELF");
-    let entry_point = elf.header.pt2.entry_point();
+
+    // A kernel linked as a PIE is loaded at a random slide (see shared::kaslr)
+    let relocatable = elf.header.pt2.type_().as_type() == ElfType::SharedObject;
+    let slide = if relocatable && !options.has("nokaslr") {
+        let (random, source) = random_u64();
+        let align = elf
+            .program_iter()
+            .filter(|ph| ph.get_type() == Ok(Type::Load))
+            .map(|ph| ph.align())
+            .max()
+            .unwrap_or(0);
+        let slide = kaslr::align_slide(kaslr::slide_from_random(random), align);
+        info!("KASLR: slide {:#x} (entropy from {})", slide, source);
+        slide
+    } else {
+        0
+    };
+    let entry_point = elf.header.pt2.entry_point() + slide;
     info!("ELF Entry point: {:#x}", entry_point);
 
     // Setup Page Tables
@@ -142,15 +528,21 @@ fn main() -> Status {
     let pml4 = unsafe { &mut *(pml4_phys.as_u64() as *mut PageTable) };
     pml4.zero(); // Clear it
 
+    if paging::five_level() {
+        info!("5-level paging is on: the PML4 goes below a PML5");
+    }
+
     // Create a Mapper using OffsetPageTable (initially offset 0 for identity mapping)
     let mut mapper = unsafe { OffsetPageTable::new(pml4, VirtAddr::new(0)) };
 
-    // Load segments from ELF
+    // Load segments from ELF, recording where each went for BootInfo::reserved
+    let mut segment_ranges = [ReservedRange::EMPTY; memory::MAX_RESERVED - 3];
+    let mut segment_count = 0;
     for ph in elf.program_iter() {
         if let Ok(Type::Load) = ph.get_type() {
             let mem_size = ph.mem_size();
             let file_size = ph.file_size();
-            let virt_addr = ph.virtual_addr();
+            let virt_addr = ph.virtual_addr() + slide;
             let offset = ph.offset();
 
             info!(
@@ -158,104 +550,197 @@ fn main() -> Status {
                 virt_addr, mem_size, file_size
             );
 
-            // Allocate physical memory for the segment
-            let pages = (mem_size as usize).div_ceil(0x1000);
-            let phys_addr =
-                boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)
-                    .expect("Failed to allocate pages for segment");
-
-            let segment_slice =
-                unsafe { slice::from_raw_parts_mut(phys_addr.as_ptr(), pages * 0x1000) };
-
-            // Copy data from file
-            let start = offset as usize;
-            let end = start + file_size as usize;
-            segment_slice[..file_size as usize].copy_from_slice(&kernel_data[start..end]);
-
-            // Zero out remaining memory (BSS section)
-            if mem_size > file_size {
-                segment_slice[file_size as usize..mem_size as usize].fill(0);
+            if mem_size == 0 {
+                continue;
             }
 
-            // Map the segment in page tables
+            // Every page the segment touches: it may start and end inside a page,
+            // and share those pages with its neighbours
             let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(virt_addr));
             let end_page =
                 Page::<Size4KiB>::containing_address(VirtAddr::new(virt_addr + mem_size - 1));
+            let pages = (end_page - start_page + 1) as usize;
+            let phys_addr =
+                boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)
+                    .expect("Failed to allocate pages for segment");
 
-            let mut flags = PageTableFlags::PRESENT;
-            if ph.flags().is_write() {
-                flags |= PageTableFlags::WRITABLE;
+            let flags = segment_flags(ph.flags());
+            let data = &kernel_data[offset as usize..(offset + file_size) as usize];
+            let mut first_shared = false;
+
+            for (index, page) in Page::range_inclusive(start_page, end_page).enumerate() {
+                let fresh = phys_addr.as_ptr() as u64 + index as u64 * 0x1000;
+                let frame = match mapper.translate(page.start_address()) {
+                    // Shared with a segment loaded before: keep its frame and
+                    // contents, allow what either segment needs
+                    TranslateResult::Mapped {
+                        frame: MappedFrame::Size4KiB(frame),
+                        flags: mapped,
+                        ..
+                    } => {
+                        first_shared |= index == 0;
+                        let mut merged = mapped | flags;
+                        if !(mapped & flags).contains(PageTableFlags::NO_EXECUTE) {
+                            merged.remove(PageTableFlags::NO_EXECUTE);
+                        }
+                        unsafe {
+                            mapper
+                                .update_flags(page, merged)
+                                .expect("Failed to update kernel page")
+                                .flush();
+                            let _ = boot::free_pages(
+                                core::ptr::NonNull::new_unchecked(fresh as *mut u8),
+                                1,
+                            );
+                        }
+                        frame
+                    }
+                    _ => {
+                        let frame = PhysFrame::containing_address(PhysAddr::new(fresh));
+                        unsafe {
+                            core::ptr::write_bytes(fresh as *mut u8, 0, 0x1000);
+                            mapper
+                                .map_to(page, frame, flags, &mut frame_allocator)
+                                .expect("Failed to map kernel page")
+                                .flush();
+                        }
+                        frame
+                    }
+                };
+
+                // This page's part of the segment: file bytes, then zeros (BSS)
+                let page_virt = page.start_address().as_u64();
+                let from = page_virt.max(virt_addr) - virt_addr;
+                let to = (page_virt + 0x1000).min(virt_addr + mem_size) - virt_addr;
+                let dest = unsafe {
+                    slice::from_raw_parts_mut(
+                        (frame.start_address().as_u64() + (virt_addr + from - page_virt))
+                            as *mut u8,
+                        (to - from) as usize,
+                    )
+                };
+                let file_end = to.min(file_size).max(from);
+                let (file_part, bss_part) = dest.split_at_mut((file_end - from) as usize);
+                file_part.copy_from_slice(&data[from as usize..file_end as usize]);
+                bss_part.fill(0);
             }
 
-            let mut frame_addr = phys_addr.as_ptr() as u64;
-
-            for page in Page::range_inclusive(start_page, end_page) {
-                let frame = PhysFrame::containing_address(PhysAddr::new(frame_addr));
-                unsafe {
-                    mapper
-                        .map_to(page, frame, flags, &mut frame_allocator)
-                        .expect("Failed to map kernel page")
-                        .flush();
-                }
-                frame_addr += 4096;
-            }
+            // The first page may have been given back, being a neighbour's
+            let skip = u64::from(first_shared);
+            assert!(
+                segment_count < segment_ranges.len(),
+                "Too many kernel segments"
+            );
+            segment_ranges[segment_count] = ReservedRange::new(
+                phys_addr.as_ptr() as u64 + skip * 0x1000,
+                pages as u64 - skip,
+                Reservation::KernelImage,
+            );
+            segment_count += 1;
 
             info!("Segment mapped.");
         }
     }
 
-    // Set up Stack in Higher Half
-    const KERNEL_BASE: u64 = 0xffffffff80000000;
-    const STACK_TOP: u64 = KERNEL_BASE - 0x1000;
+    // A PIE's pointers are only filled in by its relocations, also at slide 0
+    if relocatable {
+        let rela = elf
+            .find_section_by_name(".rela.dyn")
+            .map_or(&[][..], |section| section.raw_data(&elf));
+        let count = kaslr::relocate(rela, slide, |place, value| {
+            // Segments are identity mapped by the firmware: write through the
+            // physical address
+            let Some(phys) = mapper.translate_addr(VirtAddr::new(place + slide)) else {
+                return false;
+            };
+            unsafe { core::ptr::write_unaligned(phys.as_u64() as *mut u64, value) };
+            true
+        })
+        .expect("Failed to relocate the kernel");
+        info!("Applied {} relocations", count);
+    }
 
-    let stack_start = VirtAddr::new(STACK_TOP);
-    let stack_size = 20 * 1024; // 20 KB stack
+    // Set up Stack in Higher Half: `kernel_stack=<KiB>` pages of its own below
+    // the kernel image, and nothing mapped in the page under them (the guard), so
+    // an overflow is a page fault instead of silent corruption
+    let stack_size = options
+        .get("kernel_stack")
+        .and_then(|kib| kib.parse::<u64>().ok())
+        .map_or(shared::DEFAULT_KERNEL_STACK, |kib| {
+            (kib * 1024).clamp(shared::MIN_KERNEL_STACK, shared::MAX_KERNEL_STACK)
+        })
+        .next_multiple_of(0x1000);
+    let stack_pages = (stack_size / 0x1000) as usize;
+    let stack_start = VirtAddr::new(shared::KERNEL_STACK_TOP);
     let stack_bottom = stack_start - stack_size;
-    let stack_bottom_page = Page::<Size4KiB>::containing_address(stack_bottom);
-    let stack_top_page = Page::<Size4KiB>::containing_address(stack_start - 1u64);
+    let stack_phys =
+        boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, stack_pages)
+            .expect("Failed to allocate the kernel stack")
+            .as_ptr() as u64;
 
-    info!("Mapping stack at {:#x}", stack_start.as_u64());
+    info!(
+        "Mapping a {} KiB stack at {:#x}, guard page at {:#x}",
+        stack_size / 1024,
+        stack_start.as_u64(),
+        (stack_bottom - 0x1000u64).as_u64()
+    );
 
-    for page in Page::range_inclusive(stack_bottom_page, stack_top_page) {
-        let frame = frame_allocator
-            .allocate_frame()
-            .expect("Failed to allocate stack frame");
+    for index in 0..stack_pages as u64 {
+        let page = Page::<Size4KiB>::containing_address(stack_bottom + index * 0x1000);
+        let frame = PhysFrame::containing_address(PhysAddr::new(stack_phys + index * 0x1000));
         unsafe {
             mapper
                 .map_to(
                     page,
                     frame,
-                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
                     &mut frame_allocator,
                 )
                 .expect("Failed to map stack")
                 .flush();
         }
     }
+    assert!(
+        mapper.translate_addr(stack_bottom - 0x1000u64).is_none(),
+        "The kernel stack's guard page is mapped"
+    );
 
-    // Prepare Memory Map arguments for Kernel
-    let desc_size = {
-        let mmap = boot::memory_map(MemoryType::LOADER_DATA).expect("Failed to get temp mmap");
-        let mut entries = mmap.entries();
-        let e1 = entries.next().unwrap();
-        let e2 = entries.next().unwrap();
-        (e2 as *const _ as u64) - (e1 as *const _ as u64)
+    // Detect maximum physical memory, and size the kernel's memory map: the final
+    // map is only known after ExitBootServices, when nothing can be allocated
+    let (mut max_phys_addr, region_capacity) = {
+        let mmap = boot::memory_map(MemoryType::LOADER_DATA).expect("Failed to get memory map");
+        let max = mmap
+            .entries()
+            .map(|desc| desc.phys_start + desc.page_count * 0x1000)
+            .max()
+            .unwrap_or(0);
+        // Later allocations split a few more descriptors
+        (max, mmap.entries().len() + 64)
+    };
+    let region_pages = (region_capacity * core::mem::size_of::<MemoryRegion>()).div_ceil(0x1000);
+    let regions_addr = boot::allocate_pages(
+        AllocateType::AnyPages,
+        MemoryType::LOADER_DATA,
+        region_pages,
+    )
+    .expect("Failed to allocate the memory map");
+    let regions = unsafe {
+        slice::from_raw_parts_mut(regions_addr.as_ptr() as *mut MemoryRegion, region_capacity)
+    };
+    // Room for the runtime services' part of the final map, handed back to the
+    // firmware with their new addresses
+    let runtime_addr = boot::allocate_pages(
+        AllocateType::AnyPages,
+        MemoryType::LOADER_DATA,
+        (region_capacity * core::mem::size_of::<MemoryDescriptor>()).div_ceil(0x1000),
+    )
+    .expect("Failed to allocate the runtime memory map");
+    let runtime_map = unsafe {
+        slice::from_raw_parts_mut(
+            runtime_addr.as_ptr() as *mut MemoryDescriptor,
+            region_capacity,
+        )
     };
-
-    let mmap_storage = boot::memory_map(MemoryType::LOADER_DATA).expect("Failed to get memory map");
-
-    // Detect maximum physical memory
-    let mut max_phys_addr = 0;
-
-    for descriptor in mmap_storage.entries() {
-        let start = descriptor.phys_start;
-        let size = descriptor.page_count * 4096;
-        let end = start + size;
-
-        if end > max_phys_addr {
-            max_phys_addr = end;
-        }
-    }
 
     // Align to 2MB
     max_phys_addr = (max_phys_addr + 0x1fffff) & !0x1fffff;
@@ -269,58 +754,29 @@ fn main() -> Status {
     // Map all physical memory to Higher Half (HHDM)
     const HHDM_OFFSET: u64 = 0xffff_8000_0000_0000;
 
-    let start_frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(0));
-    let end_frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(max_phys_addr - 1));
-
-    info!("Mapping HHDM from 0 to {:#x}...", max_phys_addr);
-
-    for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
-        let phys_addr = frame.start_address().as_u64();
-        let virt_addr = phys_addr + HHDM_OFFSET;
-
-        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(virt_addr));
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-
-        unsafe {
-            let _ = mapper
-                .map_to(page, frame, flags, &mut frame_allocator)
-                .ok()
-                .map(|f| f.flush());
-        }
-    }
-
-    info!("HHDM mapped successfully!");
-
-    // Identity Map the current running code
-    // This is needed because when we switch cr3, the CPU needs to still be able to fetch the next instruction
-    // before we jump to higher half.
-    let current_rip: u64;
-    unsafe { core::arch::asm!("lea {}, [rip]", out(reg) current_rip) };
-
-    let start_rip = current_rip & !0xfff;
-
+    // 1 GiB pages where the CPU has them: a handful of entries instead of one
+    // PD (and 512 entries) per GiB of RAM
+    let hhdm_page_size = hhdm::page_size();
     info!(
-        "Identity mapping current execution code at {:#x}",
-        start_rip
+        "Mapping HHDM from 0 to {:#x} with {} KiB pages...",
+        max_phys_addr,
+        hhdm_page_size / 1024
     );
+    if !hhdm::map(
+        &mut mapper,
+        &mut frame_allocator,
+        max_phys_addr,
+        HHDM_OFFSET,
+        hhdm_page_size,
+    ) {
+        error!("Out of page table frames while mapping the HHDM");
+    }
 
-    for offset in 0..512 {
-        let addr = start_rip + (offset * 0x1000);
-        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
-        let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(addr));
+    info!("HHDM mapped successfully!");
 
-        unsafe {
-            let _ = mapper
-                .map_to(
-                    page,
-                    frame,
-                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                    &mut frame_allocator,
-                )
-                .ok()
-                .map(|f| f.flush());
-        }
-    }
+    // The code that switches CR3 must be mapped the same in the kernel's tables
+    let trampoline = trampoline::Trampoline::install(&mut mapper, &mut frame_allocator);
+    info!("Handover trampoline at {:#x}", trampoline.addr());
 
     // ========================================================================
     // 1. SETUP GRAPHICS OUTPUT PROTOCOL (GOP)
@@ -331,6 +787,9 @@ fn main() -> Status {
     let mut gop = boot::open_protocol_exclusive::<GraphicsOutput>(gop_handle)
         .expect("Failed to open GOP protocol");
 
+    // The display's own description, for the kernel to pick a native mode
+    let edid = edid::read(gop_handle);
+
     let mode_info = gop.current_mode_info();
     let mut frame_buffer = gop.frame_buffer();
 
@@ -354,6 +813,14 @@ fn main() -> Status {
         "GOP Found: {}x{}, Stride {}, Addr {:#x}",
         fb_width, fb_height, fb_stride, fb_phys_addr
     );
+    // Fatal errors from here on are painted on this framebuffer
+    fbcon::init(fbcon::Screen {
+        base: fb_phys_addr,
+        width: fb_width,
+        height: fb_height,
+        stride: fb_stride,
+        format: fb_format,
+    });
 
     const FRAMEBUFFER_VIRT_BASE: u64 = 0xFFFF_A000_0000_0000;
     let fb_virt_addr = FRAMEBUFFER_VIRT_BASE;
@@ -373,7 +840,10 @@ fn main() -> Status {
 
         let page = Page::<Size4KiB>::containing_address(VirtAddr::new(fb_virt_addr + offset));
 
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::NO_EXECUTE;
 
         unsafe {
             mapper
@@ -416,10 +886,12 @@ fn main() -> Status {
     let boot_info = unsafe { &mut *(boot_info_addr.as_ptr() as *mut shared::BootInfo) };
 
     // Fill in static information (except Memory Map because we're about to exit services)
+    boot_info.init_header();
     boot_info.hhdm_offset = HHDM_OFFSET;
     boot_info.max_phys_memory = max_phys_addr;
     boot_info.framebuffer = FrameBufferInfo {
         buffer_base: fb_virt_addr, // Virtual address of mapped framebuffer
+        buffer_phys: fb_phys_addr,
         buffer_size: fb_size,
         width: fb_width,
         height: fb_height,
@@ -432,20 +904,144 @@ fn main() -> Status {
         boot_info_addr.as_ptr() as u64
     );
 
+    // Everything set up for the kernel, for its PMM to keep (see shared::memory)
+    boot_info.reserved_count = 0;
+    let reserved = segment_ranges[..segment_count].iter().copied().chain([
+        ReservedRange::new(
+            pool_addr.as_ptr() as u64,
+            PAGE_TABLE_POOL_SIZE as u64,
+            Reservation::PageTables,
+        ),
+        ReservedRange::new(stack_phys, stack_pages as u64, Reservation::Stack),
+        ReservedRange::new(boot_info_addr.as_ptr() as u64, 1, Reservation::BootInfo),
+        ReservedRange::new(
+            regions_addr.as_ptr() as u64,
+            region_pages as u64,
+            Reservation::MemoryMap,
+        ),
+    ]);
+    for range in reserved {
+        assert!(boot_info.reserve(range), "Too many reserved ranges");
+    }
+
+    // Locate the ACPI RSDP in the UEFI configuration table
+    // Prefer the ACPI 2.0 entry (XSDT), fall back to ACPI 1.0 (RSDT)
+    boot_info.rsdp_addr = uefi::system::with_config_table(|tables| {
+        let find = |guid| {
+            tables
+                .iter()
+                .find(|entry| entry.guid == guid)
+                .map(|entry| entry.address as u64)
+        };
+        find(ConfigTableEntry::ACPI2_GUID)
+            .or_else(|| find(ConfigTableEntry::ACPI_GUID))
+            .unwrap_or(0)
+    });
+
+    info!("ACPI RSDP at {:#x}", boot_info.rsdp_addr);
+
+    // Physical location of the initramfs (identity mapped by UEFI)
+    (boot_info.initramfs_addr, boot_info.initramfs_size) = initramfs
+        .map(|data| (data.as_ptr() as u64, data.len() as u64))
+        .unwrap_or((0, 0));
+    (boot_info.font_addr, boot_info.font_size) = font
+        .map(|data| (data.as_ptr() as u64, data.len() as u64))
+        .unwrap_or((0, 0));
+    (boot_info.cmdline_addr, boot_info.cmdline_len) = cmdline
+        .map(|data| (data.as_ptr() as u64, data.len() as u64))
+        .unwrap_or((0, 0));
+    boot_info.kernel_slide = slide;
+    boot_info.boot_trial = u64::from(boot_trial);
+    boot_info.paging_levels = paging::levels();
+    boot_info.stack_top = stack_start.as_u64();
+    boot_info.stack_size = stack_size;
+    boot_info.cpu_features = cpu_features;
+    (boot_info.edid_addr, boot_info.edid_size) = edid
+        .map(|data| (data.as_ptr() as u64, data.len() as u64))
+        .unwrap_or((0, 0));
+    (boot_info.tpm_event_log_addr, boot_info.tpm_event_log_size) = tpm_event_log
+        .map(|data| (data.as_ptr() as u64, data.len() as u64))
+        .unwrap_or((0, 0));
+    (boot_info.modules_addr, boot_info.modules_count) = if module_count == 0 {
+        (0, 0)
+    } else {
+        let size = module_count * core::mem::size_of::<BootModule>();
+        let addr = boot::allocate_pages(
+            AllocateType::AnyPages,
+            MemoryType::LOADER_DATA,
+            size.div_ceil(0x1000),
+        )
+        .expect("Failed to allocate the module list");
+        let list = addr.as_ptr() as *mut BootModule;
+        unsafe { core::ptr::copy_nonoverlapping(modules.as_ptr(), list, module_count) };
+        (list as u64, module_count as u64)
+    };
+
     // EXIT BOOT SERVICES
     // After this point, we cannot use UEFI functions anymore!
     let mmap = unsafe { boot::exit_boot_services(Some(MemoryType::LOADER_DATA)) };
 
-    // Get final memory map info
-    boot_info.memory_map_addr = mmap
-        .entries()
-        .next()
-        .map(|d| d as *const _ as u64)
-        .unwrap_or(0);
-    boot_info.memory_map_len = mmap.entries().len() as u64;
-    boot_info.memory_map_desc_size = desc_size;
+    // The final map, sorted and merged into regions (see shared::memory)
+    let mut region_count = 0;
+    for desc in mmap.entries() {
+        if region_count == regions.len() {
+            region_count = memory::normalize(&mut regions[..region_count]);
+            assert!(region_count < regions.len(), "Memory map too large");
+        }
+        regions[region_count] = MemoryRegion {
+            start: desc.phys_start,
+            len: desc.page_count * memory::PAGE_SIZE,
+            kind: MemoryKind::from_uefi(desc.ty.0),
+        };
+        region_count += 1;
+    }
+    boot_info.memory_map_addr = regions.as_ptr() as u64;
+    boot_info.memory_map_len = memory::normalize(&mut regions[..region_count]) as u64;
+
+    // Runtime services move to their HHDM addresses, which every address space of
+    // the kernel maps; they must not be called again before the jump
+    let system_table = uefi::table::system_table_raw().expect("No system table");
+    let runtime_services = unsafe { system_table.as_ref() }.runtime_services as u64;
+    let mut runtime_count = 0;
+    for desc in mmap.entries() {
+        if desc.att.contains(MemoryAttribute::RUNTIME) && runtime_count < runtime_map.len() {
+            runtime_map[runtime_count] = MemoryDescriptor {
+                virt_start: desc.phys_start + HHDM_OFFSET,
+                ..*desc
+            };
+            runtime_count += 1;
+        }
+    }
+    let virtual_system_table = unsafe { system_table.as_ptr().byte_add(HHDM_OFFSET as usize) };
+    match unsafe {
+        uefi::runtime::set_virtual_address_map(
+            &mut runtime_map[..runtime_count],
+            virtual_system_table,
+        )
+    } {
+        Ok(()) => {
+            boot_info.efi_system_table = system_table.as_ptr() as u64;
+            boot_info.efi_runtime_services = runtime_services;
+        }
+        // The kernel goes without runtime services
+        Err(_) => {
+            boot_info.efi_system_table = 0;
+            boot_info.efi_runtime_services = 0;
+        }
+    }
 
-    let pml4_phys = pml4_frame.start_address().as_u64();
+    // With 5-level paging on (it cannot be turned off from here) CR3 takes a PML5
+    // above our PML4, see shared::paging
+    let root_phys = if paging::five_level() {
+        let pml5_frame = frame_allocator
+            .allocate_frame()
+            .expect("Failed to allocate PML5 (Pool empty?)");
+        let pml5 = unsafe { &mut *(pml5_frame.start_address().as_u64() as *mut PageTable) };
+        paging::wrap_pml4(pml5, pml4_frame.start_address());
+        pml5_frame.start_address().as_u64()
+    } else {
+        pml4_frame.start_address().as_u64()
+    };
     let stack_top = stack_start.as_u64();
     let boot_info_phys = boot_info_addr.as_ptr() as u64;
     let boot_info_virt = boot_info_phys + HHDM_OFFSET;
@@ -455,29 +1051,22 @@ fn main() -> Status {
         // Disable interrupts (just in case)
         x86_64::instructions::interrupts::disable();
 
-        // Switch to our new Page Table (CR3)
-        // Switch Stack Pointer (RSP)
-        // Jump to Kernel Entry Point
-        core::arch::asm!(
-            "mov cr3, {pml4}",
-            "mov rsp, {stack}",
-            "xor rbp, rbp", // Clear RBP for stack tracing
-            "jmp {entry}",
-
-            pml4 = in(reg) pml4_phys,
-            stack = in(reg) stack_top,
-            entry = in(reg) entry_point,
+        // The kernel's page tables use NO_EXECUTE and read-only pages: enable the
+        // NX bit and make read-only pages binding on ring 0 too
+        Efer::update(|efer| efer.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|cr0| cr0.insert(Cr0Flags::WRITE_PROTECT));
+        // x87 and SSE as the kernel expects them (see shared::cpu)
+        cpu::enable_fpu(cpu_features);
 
-            // Pass arguments to kernel (System V AMD64 ABI: RDI, RSI, RDX, RCX, R8, R9)
-            in("rdi") boot_info_virt,
-
-            options(noreturn)
-        );
+        // Switch to our new Page Table (CR3) and Stack Pointer (RSP) and jump to
+        // the Kernel Entry Point, with the BootInfo as its argument (RDI)
+        trampoline.jump(boot_info_virt, root_phys, stack_top, entry_point)
     }
 }
 
 // Panic Handler for Bootloader
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    fbcon::show_panic(info);
     panic_handler_impl(info)
 }
@@ -0,0 +1,121 @@
+// Network Boot
+// When the boot volume has no kernel, or there is no volume at all because the
+// bootloader was itself fetched over PXE, the kernel and every other file are
+// read from the TFTP server of the DHCP offer through the firmware's PXE Base
+// Code protocol. Paths are relative to the directory of the DHCP boot file: with
+// `efi/boot/bootx64.efi` handed out, `kernel` is fetched as `efi/boot/kernel`.
+// HTTP boot would need the firmware's HTTP protocol and a heap; only TFTP is
+// supported.
+
+use core::net::{IpAddr, Ipv4Addr};
+use core::slice;
+use log::{info, warn};
+use uefi::CStr8;
+use uefi::boot::{self, AllocateType, MemoryType, ScopedProtocol};
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::proto::network::pxe::{BaseCode, DhcpV4Packet};
+
+// Longest path sent to the server, with the boot file's directory
+const PATH_LEN: usize = 256;
+
+pub struct Tftp {
+    pxe: ScopedProtocol<BaseCode>,
+    server: IpAddr,
+    // Directory of the DHCP boot file, '/' terminated (or empty)
+    prefix: [u8; 128],
+    prefix_len: usize,
+}
+
+impl Tftp {
+    // PXE on the NIC the bootloader was loaded from, else on the first NIC that
+    // has it; DHCP is run unless the firmware already did it to fetch us
+    pub fn open() -> Option<Self> {
+        let device = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())
+            .ok()
+            .and_then(|image| image.device());
+        let mut pxe = device
+            .and_then(|handle| boot::open_protocol_exclusive::<BaseCode>(handle).ok())
+            .or_else(|| {
+                let handle = boot::get_handle_for_protocol::<BaseCode>().ok()?;
+                boot::open_protocol_exclusive::<BaseCode>(handle).ok()
+            })?;
+
+        if !pxe.mode().started() && pxe.start(false).is_err() {
+            warn!("PXE: failed to start the base code");
+            return None;
+        }
+        if !pxe.mode().dhcp_ack_received() {
+            info!("PXE: running DHCP...");
+            if let Err(err) = pxe.dhcp(true) {
+                warn!("PXE: DHCP failed: {:?}", err.status());
+                return None;
+            }
+        }
+
+        // The boot server is named by the DHCP server or by a proxy DHCP server
+        let mode = pxe.mode();
+        let ack: &DhcpV4Packet = mode.dhcp_ack().as_ref();
+        let reply: &DhcpV4Packet = if ack.bootp_si_addr == [0; 4] && mode.proxy_offer_received() {
+            mode.proxy_offer().as_ref()
+        } else {
+            ack
+        };
+        let server = IpAddr::V4(Ipv4Addr::from(reply.bootp_si_addr));
+        let boot_file = &reply.bootp_boot_file;
+        let boot_file = &boot_file[..boot_file.iter().position(|&b| b == 0).unwrap_or(0)];
+        let prefix_len = boot_file
+            .iter()
+            .rposition(|&b| b == b'/')
+            .map_or(0, |i| i + 1);
+        let mut prefix = [0; 128];
+        prefix[..prefix_len].copy_from_slice(&boot_file[..prefix_len]);
+        info!(
+            "PXE: station {}, TFTP server {}, directory '{}'",
+            mode.station_ip(),
+            server,
+            core::str::from_utf8(&prefix[..prefix_len]).unwrap_or("?")
+        );
+
+        Some(Self {
+            pxe,
+            server,
+            prefix,
+            prefix_len,
+        })
+    }
+
+    // Fetch a whole file into LOADER_DATA pages; None if the server does not
+    // have it
+    pub fn load(&mut self, path: &str) -> Option<&'static [u8]> {
+        let mut buf = [0u8; PATH_LEN];
+        let path = path.trim_start_matches('/').as_bytes();
+        let len = self.prefix_len + path.len();
+        if len + 1 > buf.len() {
+            return None;
+        }
+        buf[..self.prefix_len].copy_from_slice(&self.prefix[..self.prefix_len]);
+        buf[self.prefix_len..len].copy_from_slice(path);
+        let name = CStr8::from_bytes_with_nul(&buf[..=len]).ok()?;
+
+        let size = self.pxe.tftp_get_file_size(&self.server, name).ok()? as usize;
+        if size == 0 {
+            return None;
+        }
+        // LOADER_DATA pages are kept reserved by the kernel PMM
+        let pages = size.div_ceil(0x1000);
+        let addr = boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)
+            .expect("Failed to allocate pages for file");
+        let buffer = unsafe { slice::from_raw_parts_mut(addr.as_ptr(), pages * 0x1000) };
+        match self
+            .pxe
+            .tftp_read_file(&self.server, name, Some(&mut buffer[..size]))
+        {
+            Ok(len) => Some(&buffer[..len as usize]),
+            Err(err) => {
+                warn!("PXE: reading {} failed: {:?}", name, err.status());
+                let _ = unsafe { boot::free_pages(addr, pages) };
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,147 @@
+// TPM 2.0 Measurements
+// The kernel file (as stored, before any decompression) and the initramfs are
+// measured into PCR 9 through the firmware's EFI_TCG2_PROTOCOL, each as an
+// EV_IPL event naming the file. Once everything is measured the firmware's event
+// log is copied into LOADER_DATA pages, since the original lives in boot services
+// memory, and passed on in BootInfo (see shared::tpm).
+
+use core::slice;
+use log::{info, warn};
+use shared::tpm;
+use uefi::boot::{
+    self, AllocateType, MemoryType, OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol,
+};
+use uefi::proto::unsafe_protocol;
+
+// EFI_TCG2_EVENT_LOG_FORMAT_TCG_2: the crypto agile log
+const LOG_FORMAT_TCG_2: u32 = 0x0000_0002;
+
+// EFI_TCG2_EVENT header: size, then header size, version, PCR and event type
+const EVENT_HEADER_LEN: usize = 4 + 4 + 2 + 4 + 4;
+
+// EFI_TCG2_PROTOCOL up to HashLogExtendEvent
+#[repr(C)]
+#[unsafe_protocol("607f766c-7455-42be-930b-e4d76db2720f")]
+struct Tcg2 {
+    get_capability: usize,
+    get_event_log: unsafe extern "efiapi" fn(
+        this: *mut Tcg2,
+        format: u32,
+        location: *mut u64,
+        last_entry: *mut u64,
+        truncated: *mut u8,
+    ) -> uefi::Status,
+    hash_log_extend_event: unsafe extern "efiapi" fn(
+        this: *mut Tcg2,
+        flags: u64,
+        data: u64,
+        data_len: u64,
+        event: *const u8,
+    ) -> uefi::Status,
+}
+
+pub struct Tpm(ScopedProtocol<Tcg2>);
+
+impl Tpm {
+    // The TCG2 protocol, if the firmware has one with a TPM behind it
+    pub fn open() -> Option<Self> {
+        let handle = boot::get_handle_for_protocol::<Tcg2>().ok()?;
+        let params = OpenProtocolParams {
+            handle,
+            agent: boot::image_handle(),
+            controller: None,
+        };
+        let mut tpm = Self(
+            unsafe { boot::open_protocol::<Tcg2>(params, OpenProtocolAttributes::GetProtocol) }
+                .ok()?,
+        );
+        // Without a TPM the firmware answers with an empty log
+        match tpm.log_location() {
+            Some(_) => Some(tpm),
+            None => {
+                info!("TPM: TCG2 protocol present but no TPM");
+                None
+            }
+        }
+    }
+
+    // Start of the log and of its last record
+    fn log_location(&mut self) -> Option<(u64, u64)> {
+        let (mut location, mut last_entry, mut truncated) = (0, 0, 0);
+        let protocol = &mut *self.0 as *mut Tcg2;
+        let status = unsafe {
+            ((*protocol).get_event_log)(
+                protocol,
+                LOG_FORMAT_TCG_2,
+                &mut location,
+                &mut last_entry,
+                &mut truncated,
+            )
+        };
+        if status.is_error() || location == 0 {
+            return None;
+        }
+        if truncated != 0 {
+            warn!("TPM: the firmware's event log is truncated");
+        }
+        Some((location, last_entry))
+    }
+
+    // Extend PCR 9 with the hash of `data` and log an EV_IPL event named `name`
+    pub fn measure(&mut self, name: &str, data: &[u8]) {
+        let mut event = [0u8; EVENT_HEADER_LEN + 64];
+        let name = &name.as_bytes()[..name.len().min(64)];
+        let size = EVENT_HEADER_LEN + name.len();
+        event[0..4].copy_from_slice(&(size as u32).to_le_bytes());
+        event[4..8].copy_from_slice(&((EVENT_HEADER_LEN - 4) as u32).to_le_bytes());
+        event[8..10].copy_from_slice(&1u16.to_le_bytes());
+        event[10..14].copy_from_slice(&tpm::PCR_BOOT_FILES.to_le_bytes());
+        event[14..18].copy_from_slice(&tpm::EV_IPL.to_le_bytes());
+        event[EVENT_HEADER_LEN..size].copy_from_slice(name);
+
+        let protocol = &mut *self.0 as *mut Tcg2;
+        let status = unsafe {
+            ((*protocol).hash_log_extend_event)(
+                protocol,
+                0,
+                data.as_ptr() as u64,
+                data.len() as u64,
+                event.as_ptr(),
+            )
+        };
+        if status.is_error() {
+            warn!(
+                "TPM: measuring {} failed: {:?}",
+                core::str::from_utf8(name).unwrap_or("?"),
+                status
+            );
+        } else {
+            info!(
+                "TPM: measured {} ({} bytes) into PCR {}",
+                core::str::from_utf8(name).unwrap_or("?"),
+                data.len(),
+                tpm::PCR_BOOT_FILES
+            );
+        }
+    }
+
+    // A copy of the event log with everything measured so far
+    pub fn event_log(mut self) -> Option<&'static [u8]> {
+        let (location, last_entry) = self.log_location()?;
+        let len = unsafe { tpm::log_len(location as *const u8, last_entry as *const u8) };
+        let Some(len) = len else {
+            warn!("TPM: the firmware's event log does not parse, not passing it on");
+            return None;
+        };
+        let source = unsafe { slice::from_raw_parts(location as *const u8, len) };
+
+        let pages = len.div_ceil(0x1000);
+        let addr =
+            boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages).ok()?;
+        let copy = unsafe { slice::from_raw_parts_mut(addr.as_ptr(), len) };
+        copy.copy_from_slice(source);
+        let events = tpm::EventLog::parse(copy).map_or(0, |log| log.events().count());
+        info!("TPM: event log of {} bytes, {} events", len, events);
+        Some(copy)
+    }
+}
@@ -0,0 +1,80 @@
+// Handover Trampoline
+// The jump to the kernel switches CR3 to tables that do not map the bootloader,
+// so the instructions after `mov cr3` must be mapped at the same address in both.
+// A few position-independent instructions (switch CR3 and the stack, enter the
+// kernel) are copied into a page of their own, LOADER_CODE so the firmware keeps
+// it executable, and that page alone is identity mapped in the kernel's tables.
+// Nothing else of the bootloader, its stack included, is used after the switch.
+
+use uefi::boot::{self, AllocateType, MemoryType};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// boot_trampoline(boot_info: rdi, root: rsi, stack_top: rdx, entry: rcx)
+// The BootInfo stays in RDI, the kernel's first argument
+core::arch::global_asm!(
+    ".pushsection .text",
+    ".global boot_trampoline",
+    "boot_trampoline:",
+    "mov cr3, rsi",
+    "mov rsp, rdx",
+    "xor ebp, ebp", // Clear RBP for stack tracing
+    "jmp rcx",
+    ".global boot_trampoline_end",
+    "boot_trampoline_end:",
+    ".popsection",
+);
+
+unsafe extern "C" {
+    static boot_trampoline: u8;
+    static boot_trampoline_end: u8;
+}
+
+pub struct Trampoline {
+    addr: u64, // Physical address, identity mapped in both page tables
+}
+
+impl Trampoline {
+    // Copy the trampoline into a fresh page and identity map it in the kernel's
+    // page tables
+    pub fn install<M, A>(mapper: &mut M, allocator: &mut A) -> Self
+    where
+        M: Mapper<Size4KiB>,
+        A: FrameAllocator<Size4KiB>,
+    {
+        let start = &raw const boot_trampoline;
+        let len = (&raw const boot_trampoline_end) as usize - start as usize;
+        let addr = boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_CODE, 1)
+            .expect("Failed to allocate the trampoline page")
+            .as_ptr() as u64;
+        unsafe { core::ptr::copy_nonoverlapping(start, addr as *mut u8, len) };
+
+        // Read-only and executable, like any kernel text
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+        let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(addr));
+        unsafe {
+            mapper
+                .map_to(page, frame, PageTableFlags::PRESENT, allocator)
+                .expect("Failed to map the trampoline")
+                .flush();
+        }
+        Self { addr }
+    }
+
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// Switch to `root` and `stack_top` and jump to `entry` with `boot_info`
+    ///
+    /// # Safety
+    /// `root` must map the trampoline page, the stack and the kernel; interrupts
+    /// must be off and boot services gone
+    pub unsafe fn jump(self, boot_info: u64, root: u64, stack_top: u64, entry: u64) -> ! {
+        let enter: unsafe extern "C" fn(u64, u64, u64, u64) -> ! =
+            unsafe { core::mem::transmute(self.addr as usize) };
+        unsafe { enter(boot_info, root, stack_top, entry) }
+    }
+}